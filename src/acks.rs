@@ -0,0 +1,128 @@
+use anyhow::{anyhow, Result};
+use rusqlite::params;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use crate::database::Database;
+use crate::similarities::FileGroup;
+use crate::tombstones::now;
+
+/// Which of the groups a browser session has already seen to show, see `filter_seen`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SeenFilter {
+    /// Groups the session never saw
+    New,
+    /// Groups that gained or lost files since the session saw them
+    Changed,
+}
+
+impl FromStr for SeenFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<SeenFilter> {
+        match s {
+            "new" => Ok(SeenFilter::New),
+            "changed" => Ok(SeenFilter::Changed),
+            _ => Err(anyhow!("only must be new or changed, not {}", s)),
+        }
+    }
+}
+
+/// Tells apart the memberships of a group by the number and total size of its files.
+/// The group id only depends on the content, so it stays the same when copies are
+/// added or removed.
+pub fn membership(group: &FileGroup) -> String {
+    let size = group.files.first().map_or(0, |f| f.size);
+    format!(
+        "{}:{}",
+        group.member_count,
+        group.member_count as u64 * size
+    )
+}
+
+/// Keeps the groups that are new or changed for a session, going by its `acks`.
+pub fn filter_seen(
+    groups: Vec<FileGroup>,
+    acks: &HashMap<String, String>,
+    only: SeenFilter,
+) -> Vec<FileGroup> {
+    groups
+        .into_iter()
+        .filter(|g| match (only, acks.get(&g.group_id)) {
+            (SeenFilter::New, seen) => seen.is_none(),
+            (SeenFilter::Changed, Some(seen)) => *seen != membership(g),
+            (SeenFilter::Changed, None) => false,
+        })
+        .collect()
+}
+
+impl Database {
+    /// The membership of each group the session saw last, by group id.
+    pub fn get_group_acks(&self, session: &str) -> Result<HashMap<String, String>> {
+        let mut stmt = self
+            .db
+            .prepare("SELECT group_id, membership FROM group_acks WHERE session = ?1")?;
+        let acks: Result<HashMap<String, String>, _> = stmt
+            .query_map(params![session], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect();
+        Ok(acks?)
+    }
+
+    /// Remembers that the session saw the groups with their current members.
+    pub fn ack_groups(&self, session: &str, groups: &[FileGroup]) -> Result<()> {
+        let acked_at = now()?;
+        let tx = self.db.unchecked_transaction()?;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT OR REPLACE INTO group_acks (session, group_id, membership, acked_at) \
+                 VALUES (?1, ?2, ?3, ?4)",
+            )?;
+            for group in groups {
+                stmt.execute(params![
+                    session,
+                    group.group_id,
+                    membership(group),
+                    acked_at
+                ])?;
+            }
+        }
+        Ok(tx.commit()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::FileDigest;
+    use crate::similarities::get_list_of_similar_files;
+
+    fn group_ids(groups: &[FileGroup]) -> Vec<&str> {
+        groups.iter().map(|g| g.group_id.as_str()).collect()
+    }
+
+    #[test]
+    fn test_filter_seen() -> Result<()> {
+        let db = Database::new("test_filter_seen.sqlite", true)?;
+        for (path, digest) in [("/tmp/a", 1), ("/tmp/b", 1), ("/tmp/c", 2), ("/tmp/d", 2)].iter() {
+            db.insert_filedigest(&FileDigest::new(0, path, vec![*digest; 8], 10))?;
+        }
+        db.ack_groups("one", &get_list_of_similar_files(&db)?)?;
+        db.insert_filedigest(&FileDigest::new(0, "/tmp/e", vec![2; 8], 10))?;
+        db.insert_filedigest(&FileDigest::new(0, "/tmp/f", vec![3; 8], 10))?;
+        db.insert_filedigest(&FileDigest::new(0, "/tmp/g", vec![3; 8], 10))?;
+
+        let acks = db.get_group_acks("one")?;
+        let new = filter_seen(get_list_of_similar_files(&db)?, &acks, SeenFilter::New);
+        assert_eq!(group_ids(&new), ["0303030303030303"]);
+        let changed = filter_seen(get_list_of_similar_files(&db)?, &acks, SeenFilter::Changed);
+        assert_eq!(group_ids(&changed), ["0202020202020202"]);
+        // other sessions saw nothing yet
+        let acks = db.get_group_acks("two")?;
+        let new = filter_seen(get_list_of_similar_files(&db)?, &acks, SeenFilter::New);
+        assert_eq!(new.len(), 3);
+        let changed = filter_seen(get_list_of_similar_files(&db)?, &acks, SeenFilter::Changed);
+        assert!(changed.is_empty());
+        assert!("old".parse::<SeenFilter>().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,387 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::database::Database;
+use crate::dedupe;
+use crate::interface;
+use crate::similarities::{FileGroup, KeepPolicy};
+use crate::tombstones;
+
+/// What --auto-delete did, or would do with --dry-run, with one file.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Keep,
+    Delete,
+    Skip,
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Action::Keep => "keep",
+            Action::Delete => "delete",
+            Action::Skip => "skip",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PlannedFile {
+    pub group_id: String,
+    pub path: PathBuf,
+    pub action: Action,
+    /// Why a file is skipped
+    pub reason: Option<String>,
+}
+
+impl fmt::Display for PlannedFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}",
+            self.action,
+            self.group_id,
+            self.path.to_string_lossy()
+        )?;
+        if let Some(reason) = &self.reason {
+            write!(f, "\t{}", reason)?;
+        }
+        Ok(())
+    }
+}
+
+/// What an --auto-delete run did, or planned to do with --dry-run.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct AutoDeleteReport {
+    pub files: Vec<PlannedFile>,
+    pub deleted: usize,
+    /// Bytes freed by deleting them
+    pub freed: u64,
+    pub skipped: usize,
+    pub dry_run: bool,
+}
+
+impl fmt::Display for AutoDeleteReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let verb = if self.dry_run {
+            "Would delete"
+        } else {
+            "Deleted"
+        };
+        write!(
+            f,
+            "{} {} duplicates, freeing {} bytes, skipped {}",
+            verb, self.deleted, self.freed, self.skipped
+        )
+    }
+}
+
+impl AutoDeleteReport {
+    /// Records what happened to a file, and appends it to `deletion_log` right away, so the log is
+    /// complete even if the run is interrupted.
+    fn record(
+        &mut self,
+        deletion_log: &mut Option<&mut dyn Write>,
+        group: &FileGroup,
+        path: &Path,
+        action: Action,
+        reason: Option<String>,
+    ) -> Result<()> {
+        let file = PlannedFile {
+            group_id: group.group_id.clone(),
+            path: path.to_path_buf(),
+            action,
+            reason,
+        };
+        if let Some(deletion_log) = deletion_log {
+            writeln!(deletion_log, "{}\t{}", tombstones::now()?, file)?;
+        }
+        if action == Action::Skip {
+            self.skipped += 1;
+        }
+        self.files.push(file);
+        Ok(())
+    }
+}
+
+/// The canonical path of the file of `group` that is kept, or why no file of the group
+/// can be deleted.
+fn checked_keeper(
+    group: &FileGroup,
+    keeper_index: usize,
+    keep_policy: &KeepPolicy,
+) -> Result<PathBuf, String> {
+    if !keep_policy.finds_keeper(&group.files) {
+        return Err("no file matches the keep policy".into());
+    }
+    let keeper = &group.files[keeper_index];
+    let unreadable = |e| format!("the kept file can't be read: {}", e);
+    let size = fs::metadata(&keeper.path).map_err(unreadable)?.len();
+    if size != keeper.size {
+        return Err(format!(
+            "the kept file changed, it has {} bytes instead of {}",
+            size, keeper.size
+        ));
+    }
+    fs::canonicalize(&keeper.path).map_err(unreadable)
+}
+
+/// Deletes all duplicates of each group but the file kept by `keep_policy`. Nothing of a
+/// group is deleted if its kept file is gone or changed size, and each duplicate is
+/// compared byte by byte with the kept file first, like --dedupe does. Deleted files are
+/// removed from the database and remembered as tombstones, and every decision is
+/// appended to `deletion_log`. With `dry_run`, nothing is deleted or logged.
+pub fn auto_delete(
+    db: &Database,
+    groups: &[FileGroup],
+    keep_policy: &KeepPolicy,
+    dry_run: bool,
+    mut deletion_log: Option<&mut dyn Write>,
+) -> Result<AutoDeleteReport> {
+    let mut report = AutoDeleteReport {
+        dry_run,
+        ..Default::default()
+    };
+    if dry_run {
+        deletion_log = None;
+    }
+    for group in groups.iter().filter(|g| g.files.len() > 1) {
+        let keeper_index = keep_policy.keeper(&group.files);
+        let keeper = &group.files[keeper_index];
+        let canonical_keeper = match checked_keeper(group, keeper_index, keep_policy) {
+            Ok(path) => path,
+            Err(reason) => {
+                log::warn!("Not deleting from group {}, {}", group.group_id, reason);
+                for file in group.files.iter() {
+                    let reason = Some(reason.clone());
+                    report.record(&mut deletion_log, group, &file.path, Action::Skip, reason)?;
+                }
+                continue;
+            }
+        };
+        report.record(&mut deletion_log, group, &keeper.path, Action::Keep, None)?;
+        for (i, duplicate) in group.files.iter().enumerate() {
+            if i == keeper_index {
+                continue;
+            }
+            if let Some(reason) = dedupe::refuse_reason(&canonical_keeper, duplicate) {
+                log::warn!(
+                    "Not deleting {}, {}",
+                    duplicate.path.to_string_lossy(),
+                    reason
+                );
+                report.record(
+                    &mut deletion_log,
+                    group,
+                    &duplicate.path,
+                    Action::Skip,
+                    Some(reason),
+                )?;
+                continue;
+            }
+            if !dry_run {
+                if let Err(e) = interface::delete_file(db, duplicate.id) {
+                    log::warn!(
+                        "Unable to delete {}: {}",
+                        duplicate.path.to_string_lossy(),
+                        e
+                    );
+                    let reason = Some(e.to_string());
+                    report.record(
+                        &mut deletion_log,
+                        group,
+                        &duplicate.path,
+                        Action::Skip,
+                        reason,
+                    )?;
+                    continue;
+                }
+                log::info!("Deleted {}", duplicate.path.to_string_lossy());
+            }
+            report.record(
+                &mut deletion_log,
+                group,
+                &duplicate.path,
+                Action::Delete,
+                None,
+            )?;
+            report.deleted += 1;
+            report.freed += duplicate.size;
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filehashing::create_filedigest;
+    use crate::similarities;
+    use rusqlite::params;
+    use tempfile::{tempdir, TempDir};
+
+    const CONTENT: &str = "the same content";
+
+    /// Indexes a group of three copies, the kept file is decided by the policy alone.
+    fn index_copies(name: &str) -> Result<(TempDir, Database, Vec<PathBuf>)> {
+        let dir = tempdir()?;
+        let db = Database::new(format!("test_auto_delete_{}.sqlite", name), true)?;
+        fs::create_dir(dir.path().join("b"))?;
+        fs::create_dir(dir.path().join("archive"))?;
+        let paths: Vec<PathBuf> = ["b/copy.bin", "c.bin", "archive/a-much-longer-name.bin"]
+            .iter()
+            .map(|n| dir.path().join(n))
+            .collect();
+        for path in paths.iter() {
+            fs::write(path, CONTENT)?;
+            db.insert_filedigest(&create_filedigest(path)?)?;
+        }
+        Ok((dir, db, paths))
+    }
+
+    fn run(
+        db: &Database,
+        policy: &KeepPolicy,
+        dry_run: bool,
+    ) -> Result<(AutoDeleteReport, String)> {
+        let groups = similarities::get_list_of_similar_files(db)?;
+        let mut log = Vec::new();
+        let report = auto_delete(db, &groups, policy, dry_run, Some(&mut log))?;
+        Ok((report, String::from_utf8(log)?))
+    }
+
+    fn remaining(paths: &[PathBuf]) -> Vec<&PathBuf> {
+        paths.iter().filter(|p| p.exists()).collect()
+    }
+
+    #[test]
+    fn test_auto_delete_keep_policies() -> Result<()> {
+        type PolicyIn = fn(&Path) -> KeepPolicy;
+        let in_archive = |dir: &Path| KeepPolicy::InDir(dir.join("archive"));
+        let cases: [(&str, PolicyIn, usize); 4] = [
+            ("first_alphabetical", |_| KeepPolicy::FirstAlphabetical, 2),
+            ("shortest_path", |_| KeepPolicy::ShortestPath, 1),
+            ("longest_path", |_| KeepPolicy::LongestPath, 2),
+            ("in_dir", in_archive, 2),
+        ];
+        for (name, policy, kept) in cases.iter() {
+            let (dir, db, paths) = index_copies(name)?;
+            let (report, log) = run(&db, &policy(dir.path()), false)?;
+            assert_eq!(remaining(&paths), [&paths[*kept]], "{}", name);
+            assert_eq!((report.deleted, report.freed, report.skipped), (2, 32, 0));
+            assert_eq!(db.get_all_filedigests()?.len(), 1);
+            assert_eq!(db.get_tombstoned_paths()?.len(), 2);
+            let actions: Vec<&str> = log.lines().map(|l| l.split('\t').nth(1).unwrap()).collect();
+            assert_eq!(actions, ["keep", "delete", "delete"]);
+            assert!(log.contains(&*paths[*kept].to_string_lossy()));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_auto_delete_by_creation_time() -> Result<()> {
+        for (name, policy, kept) in [
+            ("oldest", KeepPolicy::Oldest, 1),
+            ("newest", KeepPolicy::Newest, 0),
+        ]
+        .iter()
+        {
+            let (_dir, db, paths) = index_copies(name)?;
+            db.db.execute(
+                "INSERT INTO file_meta (id, created) VALUES (1, 200), (2, 100), (3, NULL)",
+                params![],
+            )?;
+            run(&db, policy, false)?;
+            assert_eq!(remaining(&paths), [&paths[*kept]], "{}", name);
+        }
+
+        // without collected metadata there is nothing to go by
+        let (_dir, db, paths) = index_copies("no_creation_time")?;
+        let (report, _) = run(&db, &KeepPolicy::Oldest, false)?;
+        assert_eq!((report.deleted, report.skipped), (0, 3));
+        assert_eq!(remaining(&paths).len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_auto_delete_keeps_the_kept_inode() -> Result<()> {
+        let (_dir, db, paths) = index_copies("kept_inode")?;
+        // another name of the kept file, as a bind mount would give it
+        fs::remove_file(&paths[0])?;
+        fs::hard_link(&paths[1], &paths[0])?;
+        let (report, log) = run(&db, &KeepPolicy::ShortestPath, false)?;
+        assert_eq!(remaining(&paths), [&paths[0], &paths[1]]);
+        assert_eq!((report.deleted, report.skipped), (1, 1));
+        assert_eq!(fs::read_to_string(&paths[1])?, CONTENT);
+        assert!(log.contains("it is the kept file"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_auto_delete_keeps_groups_without_keeper() -> Result<()> {
+        let (dir, db, paths) = index_copies("missing_keeper")?;
+        fs::remove_file(&paths[1])?;
+        let (report, log) = run(&db, &KeepPolicy::ShortestPath, false)?;
+        assert_eq!(remaining(&paths), [&paths[0], &paths[2]]);
+        assert_eq!((report.deleted, report.skipped), (0, 3));
+        assert_eq!(db.get_all_filedigests()?.len(), 3);
+        assert!(log.lines().all(|l| l.contains("\tskip\t")));
+
+        // changed since it was indexed
+        fs::write(&paths[1], "other content")?;
+        let (report, _) = run(&db, &KeepPolicy::ShortestPath, false)?;
+        assert_eq!(report.deleted, 0);
+        assert!(report.files[0]
+            .reason
+            .as_ref()
+            .is_some_and(|r| r.contains("13 bytes instead of 16")));
+
+        // no copy below the directory
+        let policy = KeepPolicy::InDir(dir.path().join("elsewhere"));
+        let (report, _) = run(&db, &policy, false)?;
+        assert_eq!(report.deleted, 0);
+        assert_eq!(remaining(&paths).len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_auto_delete_skips_changed_duplicates() -> Result<()> {
+        let (_dir, db, paths) = index_copies("changed_duplicate")?;
+        fs::write(&paths[0], "the same CONTENT")?;
+        let (report, _) = run(&db, &KeepPolicy::ShortestPath, false)?;
+        assert_eq!((report.deleted, report.skipped), (1, 1));
+        assert_eq!(remaining(&paths), [&paths[0], &paths[1]]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_auto_delete_dry_run() -> Result<()> {
+        let (_dir, db, paths) = index_copies("dry_run")?;
+        let (report, log) = run(&db, &KeepPolicy::ShortestPath, true)?;
+        assert_eq!(remaining(&paths).len(), 3);
+        assert_eq!(db.get_all_filedigests()?.len(), 3);
+        assert!(log.is_empty());
+        let planned: Vec<(Action, &PathBuf)> =
+            report.files.iter().map(|f| (f.action, &f.path)).collect();
+        assert_eq!(
+            planned,
+            [
+                (Action::Keep, &paths[1]),
+                (Action::Delete, &paths[0]),
+                (Action::Delete, &paths[2])
+            ]
+        );
+        assert_eq!(
+            report.to_string(),
+            "Would delete 2 duplicates, freeing 32 bytes, skipped 0"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,173 @@
+use anyhow::{anyhow, Result};
+use rusqlite::backup::{Backup, StepResult};
+use rusqlite::Connection;
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::database::Database;
+
+/// Number of backups kept per database, unless --keep-backups says otherwise
+pub const DEFAULT_KEEP_BACKUPS: usize = 5;
+
+/// Safety copies of the database that are taken before operations that remove many rows
+/// at once: --reset-database, purging missing files and forced cleanups of more files
+/// than the --clean-unfound-max-* limits allow.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BackupPolicy {
+    /// False with --no-backup
+    pub enabled: bool,
+    /// Next to the database if None
+    pub dir: Option<PathBuf>,
+    /// Older backups are removed after each new one
+    pub keep: usize,
+}
+
+impl BackupPolicy {
+    pub fn disabled() -> BackupPolicy {
+        BackupPolicy {
+            enabled: false,
+            ..BackupPolicy::default()
+        }
+    }
+}
+
+impl Default for BackupPolicy {
+    fn default() -> BackupPolicy {
+        BackupPolicy {
+            enabled: true,
+            dir: None,
+            keep: DEFAULT_KEEP_BACKUPS,
+        }
+    }
+}
+
+/// Backups are named `<database file name>.bak-<unix milliseconds>`, so that sorting
+/// them by name sorts them by age.
+fn backup_prefix(db_path: &Path) -> String {
+    let name = db_path
+        .file_name()
+        .map_or_else(|| "digests.sqlite".into(), |n| n.to_string_lossy());
+    format!("{}.bak-", name)
+}
+
+/// Removes all but the `keep` newest backups of the database in `dir`. Returns the
+/// removed files.
+fn prune_backups(dir: &Path, db_path: &Path, keep: usize) -> Result<Vec<PathBuf>> {
+    let prefix = backup_prefix(db_path);
+    let mut backups: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| {
+            p.file_name()
+                .is_some_and(|n| n.to_string_lossy().starts_with(&prefix))
+        })
+        .collect();
+    backups.sort();
+    let num_removed = backups.len().saturating_sub(keep);
+    let removed: Vec<PathBuf> = backups.drain(..num_removed).collect();
+    for path in removed.iter() {
+        fs::remove_file(path)?;
+    }
+    Ok(removed)
+}
+
+impl Database {
+    /// The file the database is stored in, None for in-memory databases.
+    fn file_path(&self) -> Result<Option<PathBuf>> {
+        let file: String = self.db.query_row(
+            "SELECT file FROM pragma_database_list WHERE name = 'main'",
+            [],
+            |row| row.get(0),
+        )?;
+        Ok(Some(PathBuf::from(file)).filter(|f| !f.as_os_str().is_empty()))
+    }
+
+    /// Copies the database before `operation`, following `policy`, and prunes older
+    /// backups. Returns the new backup, None if backups are disabled or the database
+    /// only lives in memory.
+    pub fn backup(&self, policy: &BackupPolicy, operation: &str) -> Result<Option<PathBuf>> {
+        if !policy.enabled {
+            return Ok(None);
+        }
+        let db_path = match self.file_path()? {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let dir = match &policy.dir {
+            Some(dir) => dir.clone(),
+            None => db_path
+                .parent()
+                .map_or_else(|| PathBuf::from("."), Path::to_path_buf),
+        };
+        fs::create_dir_all(&dir)?;
+        let mut millis = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+        let mut target = dir.join(format!("{}{:013}", backup_prefix(&db_path), millis));
+        while target.exists() {
+            millis += 1;
+            target = dir.join(format!("{}{:013}", backup_prefix(&db_path), millis));
+        }
+        let mut copy = Connection::open(&target)?;
+        {
+            // copying all pages in a single step reads them in one transaction, so the
+            // copy is a consistent snapshot even if another process writes to the WAL
+            let backup = Backup::new(&self.db, &mut copy)?;
+            match backup.step(-1)? {
+                StepResult::Done => {}
+                other => return Err(anyhow!("Backup didn't finish: {:?}", other)),
+            }
+        }
+        log::info!(
+            "Backed up the database to {} before {}",
+            target.to_string_lossy(),
+            operation
+        );
+        for removed in prune_backups(&dir, &db_path, policy.keep.max(1))? {
+            log::debug!("Removed old backup {}", removed.to_string_lossy());
+        }
+        Ok(Some(target))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::FileDigest;
+
+    #[test]
+    fn test_backup_and_prune() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = Database::new(dir.path().join("digests.sqlite"), true)?;
+        db.insert_filedigest(&FileDigest::new(0, "/tmp/a", vec![1; 8], 10))?;
+        let policy = BackupPolicy {
+            enabled: true,
+            dir: Some(dir.path().join("backups")),
+            keep: 2,
+        };
+
+        let first = db.backup(&policy, "testing")?.unwrap();
+        assert!(first.starts_with(dir.path().join("backups")));
+        {
+            let copy = Database::new(&first, false)?;
+            assert_eq!(copy.get_all_paths()?, [PathBuf::from("/tmp/a")]);
+        }
+
+        let mut backups = vec![first];
+        for _ in 0..3 {
+            backups.push(db.backup(&policy, "testing")?.unwrap());
+        }
+        let mut left: Vec<PathBuf> = fs::read_dir(dir.path().join("backups"))?
+            .map(|e| e.unwrap().path())
+            .collect();
+        left.sort();
+        assert_eq!(left, backups[2..]);
+
+        assert_eq!(db.backup(&BackupPolicy::disabled(), "testing")?, None);
+        assert_eq!(
+            Database::in_memory()?.backup(&BackupPolicy::default(), "testing")?,
+            None
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,373 @@
+use anyhow::{anyhow, Result};
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SendError, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::database::FileDigest;
+use crate::videohash::VideoHash;
+
+/// Batches are committed at least this often, so a crash loses little work even when
+/// files are slow to hash.
+pub const DEFAULT_COMMIT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Hashed files that may wait for the database writer before hashing blocks.
+pub const DEFAULT_QUEUE_SIZE: usize = 256;
+
+/// Batch sizes outside of this range are allowed, but warned about at startup.
+const FEW_ITEMS_WARNING: usize = 16;
+const MANY_ITEMS_WARNING: usize = 100_000;
+
+/// When a batch of hashed files is committed: as soon as any of the limits is reached.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BatchLimits {
+    pub max_items: usize,
+    /// Estimated memory of the batched rows, see `BatchItem::approx_bytes`
+    pub max_bytes: usize,
+    pub max_interval: Duration,
+    /// Capacity of the queue between the hashing threads and the database writer
+    pub queue_size: usize,
+}
+
+impl BatchLimits {
+    pub fn new(max_items: usize, max_bytes: usize) -> Result<BatchLimits> {
+        if max_items == 0 || max_bytes == 0 {
+            return Err(anyhow!("Batches must hold at least one file and one byte"));
+        }
+        Ok(BatchLimits {
+            max_items,
+            max_bytes,
+            max_interval: DEFAULT_COMMIT_INTERVAL,
+            queue_size: DEFAULT_QUEUE_SIZE,
+        })
+    }
+
+    pub fn with_queue_size(self, queue_size: usize) -> Result<BatchLimits> {
+        if queue_size == 0 {
+            return Err(anyhow!("The hash queue must hold at least one file"));
+        }
+        Ok(BatchLimits { queue_size, ..self })
+    }
+
+    /// Limits by number of items only, mostly for tests.
+    pub fn with_items(max_items: usize) -> BatchLimits {
+        BatchLimits {
+            max_items: max_items.max(1),
+            max_bytes: usize::MAX,
+            max_interval: DEFAULT_COMMIT_INTERVAL,
+            queue_size: DEFAULT_QUEUE_SIZE,
+        }
+    }
+
+    /// Explains limits that are likely to be a mistake.
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        if self.max_items < FEW_ITEMS_WARNING {
+            warnings.push(format!(
+                "Committing every {} files makes hashing slow, consider a larger --commit-batchsize",
+                self.max_items
+            ));
+        }
+        if self.max_items > MANY_ITEMS_WARNING {
+            warnings.push(format!(
+                "Batches of {} files are kept in memory until they are committed, and an \
+                 interrupted scan loses all of them. --commit-batch-bytes still limits their size",
+                self.max_items
+            ));
+        }
+        if self.queue_size > self.max_items {
+            warnings.push(format!(
+                "A hash queue of {} files is larger than a batch of {}, so the queue may hold \
+                 more files in memory than the batch while the database is busy",
+                self.queue_size, self.max_items
+            ));
+        }
+        warnings
+    }
+}
+
+/// Something that is collected in a batch, with an estimate of its memory use.
+pub trait BatchItem {
+    fn approx_bytes(&self) -> usize;
+}
+
+impl BatchItem for FileDigest {
+    fn approx_bytes(&self) -> usize {
+        let exif = &self.exif;
+        mem::size_of::<FileDigest>()
+            + self.path.as_os_str().len()
+            + self.digest.len()
+            + [&exif.capture_date, &exif.camera_make, &exif.camera_model]
+                .iter()
+                .map(|s| s.as_ref().map_or(0, |s| s.len()))
+                .sum::<usize>()
+    }
+}
+
+impl BatchItem for VideoHash {
+    fn approx_bytes(&self) -> usize {
+        mem::size_of::<VideoHash>() + self.path.len() + self.histogram.len() + self.digest.len()
+    }
+}
+
+/// The sending end of `queue`, one clone per hashing thread.
+pub struct QueueSender<T> {
+    tx: SyncSender<T>,
+    depth: Arc<AtomicUsize>,
+}
+
+impl<T> Clone for QueueSender<T> {
+    fn clone(&self) -> Self {
+        QueueSender {
+            tx: self.tx.clone(),
+            depth: self.depth.clone(),
+        }
+    }
+}
+
+impl<T> QueueSender<T> {
+    /// Blocks while the queue is full, which holds back hashing until the writer caught up.
+    pub fn send(&self, item: T) -> Result<(), SendError<T>> {
+        self.depth.fetch_add(1, Ordering::Relaxed);
+        let result = match self.tx.try_send(item) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(item)) => {
+                log::debug!("Hash queue is full, waiting for the database writer");
+                self.tx.send(item)
+            }
+            Err(TrySendError::Disconnected(item)) => Err(SendError(item)),
+        };
+        if result.is_err() {
+            self.depth.fetch_sub(1, Ordering::Relaxed);
+        }
+        result
+    }
+}
+
+/// The receiving end of `queue`, read by the database writer.
+pub struct QueueReceiver<T> {
+    rx: Receiver<T>,
+    depth: Arc<AtomicUsize>,
+}
+
+impl<T> QueueReceiver<T> {
+    /// Items that were handed to the queue and not received yet, including the ones of
+    /// senders that are blocked.
+    pub fn depth(&self) -> usize {
+        self.depth.load(Ordering::Relaxed)
+    }
+
+    /// Waits for the next item, None once all senders are gone.
+    pub fn recv(&self) -> Option<T> {
+        let item = self.rx.recv().ok()?;
+        self.depth.fetch_sub(1, Ordering::Relaxed);
+        Some(item)
+    }
+
+    /// Waits at most `timeout` for the next item, fails like `Receiver::recv_timeout`.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        let item = self.rx.recv_timeout(timeout)?;
+        self.depth.fetch_sub(1, Ordering::Relaxed);
+        Ok(item)
+    }
+}
+
+/// The bounded channel between the hashing threads and the database writer. Without a
+/// bound, hashed files pile up in memory whenever a commit takes long.
+pub fn queue<T>(limits: &BatchLimits) -> (QueueSender<T>, QueueReceiver<T>) {
+    let (tx, rx) = mpsc::sync_channel(limits.queue_size);
+    let depth = Arc::new(AtomicUsize::new(0));
+    (
+        QueueSender {
+            tx,
+            depth: depth.clone(),
+        },
+        QueueReceiver { rx, depth },
+    )
+}
+
+/// Collects items until a batch is full by count, estimated bytes or age.
+pub struct Batcher<T> {
+    limits: BatchLimits,
+    items: Vec<T>,
+    bytes: usize,
+    started: Instant,
+}
+
+impl<T: BatchItem> Batcher<T> {
+    pub fn new(limits: BatchLimits) -> Batcher<T> {
+        Batcher {
+            limits,
+            items: Vec::new(),
+            bytes: 0,
+            started: Instant::now(),
+        }
+    }
+
+    pub fn push(&mut self, item: T) {
+        if self.items.is_empty() {
+            self.started = Instant::now();
+        }
+        self.bytes += item.approx_bytes();
+        self.items.push(item);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Whether the batch should be committed now.
+    pub fn is_full(&self) -> bool {
+        !self.items.is_empty()
+            && (self.items.len() >= self.limits.max_items
+                || self.bytes >= self.limits.max_bytes
+                || self.started.elapsed() >= self.limits.max_interval)
+    }
+
+    /// How long until the batch is full by age, the whole interval while it is empty.
+    pub fn time_left(&self) -> Duration {
+        if self.items.is_empty() {
+            return self.limits.max_interval;
+        }
+        self.limits
+            .max_interval
+            .saturating_sub(self.started.elapsed())
+    }
+
+    pub fn items(&self) -> &Vec<T> {
+        &self.items
+    }
+
+    /// Starts the next batch.
+    pub fn clear(&mut self) {
+        self.items.clear();
+        self.bytes = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Item(usize);
+
+    impl BatchItem for Item {
+        fn approx_bytes(&self) -> usize {
+            self.0
+        }
+    }
+
+    fn limits(max_items: usize, max_bytes: usize, max_interval: Duration) -> BatchLimits {
+        BatchLimits {
+            max_items,
+            max_bytes,
+            max_interval,
+            queue_size: DEFAULT_QUEUE_SIZE,
+        }
+    }
+
+    #[test]
+    fn test_batch_full_by_count() {
+        let mut batcher = Batcher::new(limits(2, 1000, DEFAULT_COMMIT_INTERVAL));
+        assert!(!batcher.is_full());
+        batcher.push(Item(1));
+        assert!(!batcher.is_full());
+        batcher.push(Item(1));
+        assert!(batcher.is_full());
+        batcher.clear();
+        assert!(batcher.is_empty() && !batcher.is_full());
+    }
+
+    #[test]
+    fn test_batch_full_by_bytes() {
+        let mut batcher = Batcher::new(limits(100, 1000, DEFAULT_COMMIT_INTERVAL));
+        batcher.push(Item(600));
+        assert!(!batcher.is_full());
+        batcher.push(Item(400));
+        assert!(batcher.is_full());
+        batcher.clear();
+        // a single item larger than the limit is committed alone
+        batcher.push(Item(5000));
+        assert!(batcher.is_full());
+        assert_eq!(batcher.items().len(), 1);
+    }
+
+    #[test]
+    fn test_batch_full_by_age() {
+        let mut batcher = Batcher::new(limits(100, 1000, Duration::from_millis(20)));
+        std::thread::sleep(Duration::from_millis(30));
+        // an empty batch never needs a commit, and its age starts with the first item
+        assert!(!batcher.is_full());
+        batcher.push(Item(1));
+        assert!(!batcher.is_full());
+        assert!(batcher.time_left() > Duration::from_millis(0));
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(batcher.is_full());
+        assert_eq!(batcher.time_left(), Duration::from_millis(0));
+    }
+
+    #[test]
+    fn test_batch_limits() {
+        assert!(BatchLimits::new(0, 1).is_err());
+        assert!(BatchLimits::new(1024, 1 << 20)
+            .unwrap()
+            .warnings()
+            .is_empty());
+        // tiny batches are also smaller than the default queue
+        assert_eq!(BatchLimits::new(1, 1 << 20).unwrap().warnings().len(), 2);
+        assert_eq!(
+            BatchLimits::new(1_000_000, 1 << 20)
+                .unwrap()
+                .warnings()
+                .len(),
+            1
+        );
+        assert_eq!(BatchLimits::with_items(0).max_items, 1);
+        let limits = BatchLimits::new(1024, 1 << 20).unwrap();
+        assert!(limits.with_queue_size(0).is_err());
+        assert_eq!(limits.with_queue_size(4096).unwrap().warnings().len(), 1);
+    }
+
+    #[test]
+    fn test_queue_blocks_producers() {
+        let limits = BatchLimits::with_items(16).with_queue_size(4).unwrap();
+        let (tx, rx) = queue(&limits);
+        let sent = Arc::new(AtomicUsize::new(0));
+        let producer = {
+            let sent = sent.clone();
+            std::thread::spawn(move || {
+                for i in 0..100 {
+                    tx.send(i).unwrap();
+                    sent.fetch_add(1, Ordering::Relaxed);
+                }
+            })
+        };
+        // a consumer that doesn't keep up
+        std::thread::sleep(Duration::from_millis(50));
+        let mut received = Vec::new();
+        for _ in 0..10 {
+            // the queue holds 4, the producer waits with the fifth
+            assert!(sent.load(Ordering::Relaxed) <= received.len() + 4);
+            assert!(rx.depth() <= 5);
+            received.push(rx.recv().unwrap());
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        while let Some(i) = rx.recv() {
+            received.push(i);
+        }
+        producer.join().unwrap();
+        assert_eq!(received, (0..100).collect::<Vec<_>>());
+        assert_eq!(rx.depth(), 0);
+    }
+
+    #[test]
+    fn test_file_digest_bytes() {
+        let mut file = FileDigest::new(1, "/tmp/a", vec![0; 64], 1 << 30);
+        let bytes = file.approx_bytes();
+        // the file size doesn't matter, only what is kept in memory
+        assert_eq!(bytes, mem::size_of::<FileDigest>() + 6 + 64);
+        file.exif.camera_make = Some("Canon".to_string());
+        assert_eq!(file.approx_bytes(), bytes + 5);
+    }
+}
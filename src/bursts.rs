@@ -0,0 +1,301 @@
+use anyhow::{anyhow, Result};
+use rayon::prelude::*;
+use rusqlite::params;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::database::Database;
+use crate::similarities;
+use crate::unionfind::UnionFind;
+use crate::videohash;
+
+/// A dHash is computed from a grayscale image of this size. Rows have one pixel more
+/// than bits, since each bit compares two neighboring pixels.
+const DHASH_WIDTH: u32 = 9;
+const DHASH_HEIGHT: u32 = 8;
+
+/// Photos taken at most this many seconds apart can belong to the same burst.
+pub const DEFAULT_BURST_WINDOW: i64 = 10;
+/// Photos whose hashes differ in fewer bits are considered near identical.
+pub const DEFAULT_BURST_THRESHOLD: u32 = 8;
+
+/// Difference hash of DHASH_WIDTH x DHASH_HEIGHT RGB24 pixels. Each bit tells whether a
+/// pixel is brighter than its right neighbor, which survives recompression and small
+/// changes in exposure.
+pub fn dhash(rgb: &[u8]) -> u64 {
+    let luma: Vec<u32> = rgb
+        .chunks(3)
+        .map(|p| (299 * p[0] as u32 + 587 * p[1] as u32 + 114 * p[2] as u32) / 1000)
+        .collect();
+    let mut hash = 0u64;
+    for row in luma
+        .chunks(DHASH_WIDTH as usize)
+        .take(DHASH_HEIGHT as usize)
+    {
+        for pair in row.windows(2) {
+            hash = (hash << 1) | (pair[0] > pair[1]) as u64;
+        }
+    }
+    hash
+}
+
+pub fn hamming_distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+fn image_dhash(path: &Path) -> Result<u64> {
+    Ok(dhash(&videohash::decode_still_scaled(
+        path,
+        DHASH_WIDTH,
+        DHASH_HEIGHT,
+    )?))
+}
+
+/// Seconds since 1970 of an EXIF capture date like "2021-06-13 14:15:16". The time zone
+/// is unknown, which doesn't matter for comparing photos of the same camera.
+pub fn parse_capture_time(s: &str) -> Option<i64> {
+    let mut parts = s.split(' ');
+    let date = similarities::parse_date(parts.next()?).ok()?;
+    let time: Vec<i64> = parts
+        .next()?
+        .split(':')
+        .map(|t| t.parse().ok())
+        .collect::<Option<_>>()?;
+    match time[..] {
+        [h, m, s] if h < 24 && m < 60 && s < 61 => Some(date + h * 3600 + m * 60 + s),
+        _ => None,
+    }
+}
+
+/// A photo with a capture date and an image hash.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BurstPhoto {
+    pub id: i64,
+    pub path: PathBuf,
+    pub size: u64,
+    pub capture_date: String,
+    #[serde(skip)]
+    pub taken_at: i64,
+    #[serde(skip)]
+    pub dhash: u64,
+}
+
+/// Near identical photos taken shortly after each other, ordered by capture date.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Burst<'a> {
+    pub photos: Vec<&'a BurstPhoto>,
+}
+
+/// Clusters photos that were taken at most `window` seconds apart and whose hashes
+/// differ in fewer than `threshold` bits. Both have to apply to link two photos.
+pub fn find_bursts(photos: &[BurstPhoto], window: i64, threshold: u32) -> Vec<Burst<'_>> {
+    let mut order: Vec<usize> = (0..photos.len()).collect();
+    order.sort_by_key(|&i| (photos[i].taken_at, photos[i].id));
+    let mut sets = UnionFind::new(photos.len());
+    for (n, &i) in order.iter().enumerate() {
+        for &j in order[n + 1..].iter() {
+            if photos[j].taken_at - photos[i].taken_at > window {
+                break;
+            }
+            if hamming_distance(photos[i].dhash, photos[j].dhash) < threshold {
+                sets.union(i, j);
+            }
+        }
+    }
+    let mut bursts: Vec<Burst> = sets
+        .clusters()
+        .into_iter()
+        .map(|cluster| {
+            let mut photos: Vec<&BurstPhoto> = cluster.into_iter().map(|i| &photos[i]).collect();
+            photos.sort_by_key(|p| (p.taken_at, p.id));
+            Burst { photos }
+        })
+        .collect();
+    bursts.sort_by_key(|b| (b.photos[0].taken_at, b.photos[0].id));
+    bursts
+}
+
+impl Database {
+    /// Photos with a capture date that weren't hashed yet.
+    fn get_photos_without_image_hash(&self) -> Result<Vec<(i64, String)>> {
+        let mut stmt = self.db.prepare(
+            "SELECT id, path FROM file_digests WHERE exif_capture_date IS NOT NULL \
+             AND id NOT IN (SELECT id FROM image_hashes)",
+        )?;
+        let rows: Result<Vec<_>, _> = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect();
+        Ok(rows?)
+    }
+
+    fn insert_image_hashes(&mut self, hashes: &[(i64, Option<u64>)]) -> Result<()> {
+        let tx = self.db.transaction()?;
+        {
+            let mut stmt =
+                tx.prepare("INSERT OR REPLACE INTO image_hashes (id, dhash) VALUES (?1, ?2)")?;
+            for (id, hash) in hashes {
+                stmt.execute(params![id, hash.map(|h| h as i64)])?;
+            }
+        }
+        Ok(tx.commit()?)
+    }
+
+    pub fn get_burst_photos(&self) -> Result<Vec<BurstPhoto>> {
+        let mut stmt = self.db.prepare(
+            "SELECT f.id, f.path, f.size, f.exif_capture_date, h.dhash \
+             FROM file_digests f JOIN image_hashes h ON h.id = f.id \
+             WHERE h.dhash IS NOT NULL AND f.exif_capture_date IS NOT NULL",
+        )?;
+        let rows: Result<Vec<_>, _> = stmt
+            .query_map([], |row| {
+                let path: String = row.get(1)?;
+                let dhash: i64 = row.get(4)?;
+                Ok(BurstPhoto {
+                    id: row.get(0)?,
+                    path: PathBuf::from(path),
+                    size: row.get(2)?,
+                    capture_date: row.get(3)?,
+                    taken_at: 0,
+                    dhash: dhash as u64,
+                })
+            })?
+            .collect();
+        // photos with unparseable dates can't be placed in time
+        Ok(rows?
+            .into_iter()
+            .filter_map(|mut p| {
+                p.taken_at = parse_capture_time(&p.capture_date)?;
+                Some(p)
+            })
+            .collect())
+    }
+}
+
+/// Computes the image hashes of all photos with a capture date that don't have one yet.
+/// Returns the number of hashed photos.
+pub fn update_image_hashes(db_mutex: &Mutex<Database>, commit_batchsize: usize) -> Result<usize> {
+    let photos = if let Ok(db) = db_mutex.lock() {
+        db.get_photos_without_image_hash()?
+    } else {
+        return Err(anyhow!("Unable to lock DB"));
+    };
+    log::info!("Photos to hash for burst detection: {}", photos.len());
+    for batch in photos.chunks(commit_batchsize.max(1)) {
+        let hashes: Vec<(i64, Option<u64>)> = batch
+            .par_iter()
+            .map(|(id, path)| match image_dhash(Path::new(path)) {
+                Ok(hash) => (*id, Some(hash)),
+                Err(e) => {
+                    log::debug!("Unable to hash {}: {}", path, e);
+                    (*id, None)
+                }
+            })
+            .collect();
+        if let Ok(mut db) = db_mutex.lock() {
+            db.insert_image_hashes(&hashes)?;
+        } else {
+            return Err(anyhow!("Unable to lock DB"));
+        }
+    }
+    Ok(photos.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::FileDigest;
+
+    fn photo(id: i64, taken_at: i64, dhash: u64) -> BurstPhoto {
+        BurstPhoto {
+            id,
+            path: PathBuf::from(format!("/photos/{}.jpg", id)),
+            size: 1,
+            capture_date: String::new(),
+            taken_at,
+            dhash,
+        }
+    }
+
+    fn ids(bursts: &[Burst]) -> Vec<Vec<i64>> {
+        bursts
+            .iter()
+            .map(|b| b.photos.iter().map(|p| p.id).collect())
+            .collect()
+    }
+
+    #[test]
+    fn test_find_bursts() {
+        let base = 0xf0f0_f0f0_f0f0_f0f0u64;
+        let photos = vec![
+            photo(1, 100, base),
+            photo(2, 102, base ^ 0b11),
+            // close in time, but a different picture
+            photo(3, 103, !base),
+            photo(4, 105, base ^ 0b111),
+            // the same picture, but much later
+            photo(5, 500, base),
+            photo(6, 1000, !base),
+            photo(7, 1001, !base ^ 1),
+        ];
+        assert_eq!(
+            ids(&find_bursts(&photos, 10, 8)),
+            [vec![1, 2, 4], vec![6, 7]]
+        );
+        // the window is in seconds between neighbors
+        assert_eq!(ids(&find_bursts(&photos, 2, 8)), [vec![1, 2], vec![6, 7]]);
+        // the threshold is exclusive
+        assert_eq!(ids(&find_bursts(&photos, 10, 2)), [vec![2, 4], vec![6, 7]]);
+        assert!(find_bursts(&photos, 10, 1).is_empty());
+    }
+
+    #[test]
+    fn test_dhash() {
+        // brightness falling from left to right sets all bits
+        let falling: Vec<u8> = (0..DHASH_HEIGHT)
+            .flat_map(|_| (0..DHASH_WIDTH).rev())
+            .flat_map(|x| vec![(x * 20) as u8; 3])
+            .collect();
+        assert_eq!(dhash(&falling), u64::MAX);
+        let flat = vec![128u8; (DHASH_WIDTH * DHASH_HEIGHT * 3) as usize];
+        assert_eq!(dhash(&flat), 0);
+        assert_eq!(hamming_distance(dhash(&falling), dhash(&flat)), 64);
+    }
+
+    #[test]
+    fn test_parse_capture_time() {
+        assert_eq!(
+            parse_capture_time("1970-01-02 01:02:03"),
+            Some(86400 + 3723)
+        );
+        assert_eq!(parse_capture_time("1970-01-02"), None);
+        assert_eq!(parse_capture_time("1970-01-02 25:00:00"), None);
+        assert_eq!(parse_capture_time("1970-01-02 1:x:3"), None);
+    }
+
+    #[test]
+    fn test_burst_photos() -> Result<()> {
+        let mut db = Database::new("test_burst_photos.sqlite", true)?;
+        for id in 1..=3 {
+            let mut file = FileDigest::new(id, &format!("/photos/{}.jpg", id), vec![id as u8], 1);
+            file.exif.capture_date = Some(format!("2021-06-13 14:15:1{}", id));
+            db.insert_filedigest(&file)?;
+        }
+        db.insert_filedigest(&FileDigest::new(4, "/photos/undated.jpg", vec![4], 1))?;
+        let pending: Vec<i64> = db
+            .get_photos_without_image_hash()?
+            .iter()
+            .map(|p| p.0)
+            .collect();
+        assert_eq!(pending, [1, 2, 3]);
+        db.insert_image_hashes(&[(1, Some(u64::MAX)), (2, Some(u64::MAX - 1)), (3, None)])?;
+        assert!(db.get_photos_without_image_hash()?.is_empty());
+        let photos = db.get_burst_photos()?;
+        assert_eq!(photos.len(), 2);
+        assert_eq!(photos[1].dhash, u64::MAX - 1);
+        assert_eq!(ids(&find_bursts(&photos, 10, 8)), [vec![1, 2]]);
+        db.delete_filedigest(1)?;
+        assert_eq!(db.get_burst_photos()?.len(), 1);
+        Ok(())
+    }
+}
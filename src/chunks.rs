@@ -0,0 +1,302 @@
+use anyhow::{anyhow, Result};
+use blake2::{Blake2b, Digest};
+use rayon::prelude::*;
+use rusqlite::params;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::database::Database;
+use crate::verify::read_chunk;
+
+/// Files are split into chunks of this size. Chunks only match at the same offset
+/// modulo the chunk size, which suits disk images and other block based files.
+pub const CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+/// Chunk digests are shortened to this many bytes, since there is a row for every chunk.
+const CHUNK_DIGEST_BYTES: usize = 16;
+
+/// Two files that share some of their chunks, but not all of them.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PartialDuplicate {
+    pub a: PathBuf,
+    pub b: PathBuf,
+    /// Chunks of the larger file that are also in the other one
+    pub shared_chunks: usize,
+    /// Chunks of the larger file
+    pub chunks: usize,
+    /// Roughly, the last chunk of a file is usually shorter
+    pub shared_bytes: u64,
+    pub percent: f64,
+}
+
+impl fmt::Display for PartialDuplicate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:.1}% shared ({} of {} chunks, {} bytes): {} and {}",
+            self.percent,
+            self.shared_chunks,
+            self.chunks,
+            self.shared_bytes,
+            self.a.to_string_lossy(),
+            self.b.to_string_lossy()
+        )
+    }
+}
+
+/// The shortened digest of each chunk of a file.
+fn chunk_digests(path: &Path, chunk_size: usize) -> io::Result<Vec<Vec<u8>>> {
+    let mut file = File::open(path)?;
+    let mut buffer = vec![0u8; chunk_size];
+    let mut digests = Vec::new();
+    loop {
+        let n = read_chunk(&mut file, &mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        digests.push(Blake2b::digest(&buffer[..n])[..CHUNK_DIGEST_BYTES].to_vec());
+        if n < chunk_size {
+            break;
+        }
+    }
+    Ok(digests)
+}
+
+/// A file that is split into chunks, with the digest of its content and of its chunks.
+type ChunkedFile = (i64, Vec<u8>, Vec<Vec<u8>>);
+
+impl Database {
+    /// Files of at least `min_size` bytes that have no chunks yet, or whose content or
+    /// chunk size changed since, with their digest.
+    fn get_files_to_chunk(
+        &self,
+        min_size: u64,
+        chunk_size: usize,
+    ) -> Result<Vec<(i64, PathBuf, Vec<u8>)>> {
+        let mut stmt = self.db.prepare(
+            "SELECT f.id, f.path, f.digest FROM file_digests f \
+             LEFT JOIN chunked_files c \
+             ON c.id = f.id AND c.digest = f.digest AND c.chunk_size = ?2 \
+             WHERE f.size >= ?1 AND c.id IS NULL ORDER BY f.id",
+        )?;
+        let files: Result<Vec<_>, _> = stmt
+            .query_map(params![min_size as i64, chunk_size as i64], |row| {
+                let path: String = row.get(1)?;
+                Ok((row.get(0)?, PathBuf::from(path), row.get(2)?))
+            })?
+            .collect();
+        Ok(files?)
+    }
+
+    /// Replaces the chunks of each file in a single transaction.
+    fn insert_chunks(&mut self, files: &[ChunkedFile], chunk_size: usize) -> Result<()> {
+        let tx = self.db.transaction()?;
+        {
+            let mut delete_stmt = tx.prepare("DELETE FROM chunks WHERE id = ?1")?;
+            let mut chunk_stmt =
+                tx.prepare("INSERT INTO chunks (id, chunk_index, digest) VALUES (?1, ?2, ?3)")?;
+            let mut file_stmt = tx.prepare(
+                "INSERT OR REPLACE INTO chunked_files (id, digest, chunk_size) VALUES (?1, ?2, ?3)",
+            )?;
+            for (id, digest, chunks) in files {
+                delete_stmt.execute(params![id])?;
+                for (i, chunk) in chunks.iter().enumerate() {
+                    chunk_stmt.execute(params![id, i as i64, chunk])?;
+                }
+                file_stmt.execute(params![id, digest, chunk_size as i64])?;
+            }
+        }
+        Ok(tx.commit()?)
+    }
+
+    /// Removes the chunks of files that changed since they were split, are no longer in
+    /// the DB, or are smaller than `min_size`. Returns the number of removed chunks.
+    pub fn prune_chunk_index(&self, min_size: u64) -> Result<usize> {
+        self.db.execute(
+            "DELETE FROM chunked_files WHERE NOT EXISTS \
+             (SELECT 1 FROM file_digests f WHERE f.id = chunked_files.id \
+              AND f.digest = chunked_files.digest AND f.size >= ?1)",
+            params![min_size as i64],
+        )?;
+        Ok(self.db.execute(
+            "DELETE FROM chunks WHERE id NOT IN (SELECT id FROM chunked_files)",
+            params![],
+        )?)
+    }
+
+    /// Pairs of files that share more than `min_percent` of the chunks of the larger one,
+    /// the most similar first. Files with the same content are left out, they are
+    /// reported as duplicates anyway.
+    pub fn partial_duplicates(&self, min_percent: f64) -> Result<Vec<PartialDuplicate>> {
+        let mut stmt = self.db.prepare(
+            "SELECT c.id, c.chunk_size, f.path, f.digest, f.size, \
+             (SELECT COUNT(*) FROM chunks WHERE chunks.id = c.id) \
+             FROM chunked_files c JOIN file_digests f ON f.id = c.id AND f.digest = c.digest",
+        )?;
+        let files: HashMap<i64, (u64, PathBuf, Vec<u8>, u64, usize)> = stmt
+            .query_map([], |row| {
+                let path: String = row.get(2)?;
+                let chunk_size: i64 = row.get(1)?;
+                let size: i64 = row.get(4)?;
+                let num_chunks: i64 = row.get(5)?;
+                Ok((
+                    row.get(0)?,
+                    (
+                        chunk_size as u64,
+                        PathBuf::from(path),
+                        row.get(3)?,
+                        size as u64,
+                        num_chunks as usize,
+                    ),
+                ))
+            })?
+            .collect::<Result<_, _>>()?;
+
+        // counting each digest once per file keeps runs of equal chunks, like zeroed
+        // blocks, from multiplying the joined rows
+        let mut stmt = self.db.prepare(
+            "WITH counts AS (SELECT id, digest, COUNT(*) AS n FROM chunks GROUP BY id, digest) \
+             SELECT a.id, b.id, SUM(MIN(a.n, b.n)) FROM counts a \
+             JOIN counts b ON a.digest = b.digest AND a.id < b.id \
+             GROUP BY a.id, b.id",
+        )?;
+        let pairs: Vec<(i64, i64, i64)> = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+            .collect::<Result<_, _>>()?;
+
+        let mut partial = Vec::new();
+        for (a, b, shared) in pairs {
+            let (file_a, file_b) = match (files.get(&a), files.get(&b)) {
+                (Some(file_a), Some(file_b)) => (file_a, file_b),
+                _ => continue,
+            };
+            if file_a.2 == file_b.2 || file_a.0 != file_b.0 {
+                continue;
+            }
+            let chunks = file_a.4.max(file_b.4);
+            let percent = shared as f64 * 100.0 / chunks as f64;
+            if percent <= min_percent {
+                continue;
+            }
+            partial.push(PartialDuplicate {
+                a: file_a.1.clone(),
+                b: file_b.1.clone(),
+                shared_chunks: shared as usize,
+                chunks,
+                shared_bytes: (shared as u64 * file_a.0).min(file_a.3.min(file_b.3)),
+                percent,
+            });
+        }
+        partial.sort_by(|x, y| {
+            y.percent
+                .partial_cmp(&x.percent)
+                .unwrap_or(std::cmp::Ordering::Equal)
+                .then_with(|| (&x.a, &x.b).cmp(&(&y.a, &y.b)))
+        });
+        Ok(partial)
+    }
+}
+
+/// Splits all files of at least `min_size` bytes whose chunks aren't known yet, and
+/// stores the chunk digests in batches of `commit_batchsize` files. Returns the number
+/// of split files.
+pub fn update_chunk_index(
+    db_mutex: &Mutex<Database>,
+    min_size: u64,
+    chunk_size: usize,
+    commit_batchsize: usize,
+) -> Result<usize> {
+    let files = if let Ok(db) = db_mutex.lock() {
+        db.get_files_to_chunk(min_size, chunk_size)?
+    } else {
+        return Err(anyhow!("Unable to lock DB"));
+    };
+    log::info!("Files to split into chunks: {}", files.len());
+    let mut num_chunked = 0;
+    for batch in files.chunks(commit_batchsize.max(1)) {
+        let chunked: Vec<ChunkedFile> = batch
+            .par_iter()
+            .filter_map(|(id, path, digest)| match chunk_digests(path, chunk_size) {
+                Ok(chunks) => Some((*id, digest.clone(), chunks)),
+                Err(e) => {
+                    log::warn!("Unable to split {}: {}", path.to_string_lossy(), e);
+                    None
+                }
+            })
+            .collect();
+        num_chunked += chunked.len();
+        if let Ok(mut db) = db_mutex.lock() {
+            db.insert_chunks(&chunked, chunk_size)?;
+        } else {
+            return Err(anyhow!("Unable to lock DB"));
+        }
+    }
+    Ok(num_chunked)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filehashing::create_filedigest;
+    use std::fs;
+
+    #[test]
+    fn test_partial_duplicates() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let chunk_size = 1024;
+        let chunk = |byte: u8| vec![byte; chunk_size];
+        // four chunks each, the first two are the same
+        let a = [chunk(1), chunk(2), chunk(3), chunk(4)].concat();
+        let b = [chunk(1), chunk(2), chunk(5), chunk(6)].concat();
+        let c = [chunk(7), chunk(8)].concat();
+        let db = Database::new("test_partial_duplicates.sqlite", true)?;
+        let mut paths = Vec::new();
+        for (name, content) in [
+            ("a", &a),
+            ("b", &b),
+            ("c", &c),
+            ("small", &a[..10].to_vec()),
+        ]
+        .iter()
+        {
+            let path = dir.path().join(name);
+            fs::write(&path, content)?;
+            db.insert_filedigest(&create_filedigest(&path)?)?;
+            paths.push(path);
+        }
+        let db_mutex = Mutex::new(db);
+        assert_eq!(update_chunk_index(&db_mutex, 100, chunk_size, 2)?, 3);
+        // nothing changed
+        assert_eq!(update_chunk_index(&db_mutex, 100, chunk_size, 2)?, 0);
+
+        let db = db_mutex.into_inner().unwrap();
+        let partial = db.partial_duplicates(40.0)?;
+        assert_eq!(
+            partial,
+            [PartialDuplicate {
+                a: paths[0].clone(),
+                b: paths[1].clone(),
+                shared_chunks: 2,
+                chunks: 4,
+                shared_bytes: 2048,
+                percent: 50.0,
+            }]
+        );
+        assert!(db.partial_duplicates(50.0)?.is_empty());
+
+        // b changed, its chunks are outdated
+        db.db.execute(
+            "UPDATE file_digests SET digest = ?1 WHERE path = ?2",
+            params![vec![9u8; 8], paths[1].to_string_lossy()],
+        )?;
+        assert!(db.partial_duplicates(40.0)?.is_empty());
+        assert_eq!(db.prune_chunk_index(100)?, 4);
+        assert_eq!(db.prune_chunk_index(5000)?, 6);
+        Ok(())
+    }
+}
@@ -0,0 +1,269 @@
+use anyhow::{anyhow, Result};
+use rusqlite::params;
+use serde::Serialize;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::database::Database;
+
+/// Where copies of a file exist when comparing two databases.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Presence {
+    Both,
+    OnlyHere,
+    OnlyOther,
+}
+
+impl fmt::Display for Presence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Presence::Both => "both",
+            Presence::OnlyHere => "only_here",
+            Presence::OnlyOther => "only_other",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ComparedFile {
+    pub presence: Presence,
+    pub size: u64,
+    /// The file in this database, None for files only in the other one
+    pub path: Option<PathBuf>,
+    /// A copy in the other database. If there are several, the first path is used.
+    pub other_path: Option<PathBuf>,
+    /// Birth time (unix seconds) and owner uid of the file in this database, if they were
+    /// collected with --collect-metadata
+    pub created: Option<i64>,
+    pub owner: Option<u32>,
+}
+
+/// The files of two databases, matched by digest.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct CompareReport {
+    pub files: Vec<ComparedFile>,
+}
+
+impl CompareReport {
+    pub fn with_presence(&self, presence: Presence) -> impl Iterator<Item = &ComparedFile> {
+        self.files.iter().filter(move |f| f.presence == presence)
+    }
+
+    /// Number of files and their total size in bytes.
+    pub fn totals(&self, presence: Presence) -> (usize, u64) {
+        self.with_presence(presence)
+            .fold((0, 0), |(n, size), f| (n + 1, size + f.size))
+    }
+
+    /// One line per file: presence, size, path, other path, created, owner uid.
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("presence,size,path,other_path,created,owner_uid\n");
+        for file in &self.files {
+            csv.push_str(&format!(
+                "{},{},{},{},{},{}\n",
+                file.presence,
+                file.size,
+                csv_field(file.path.as_deref()),
+                csv_field(file.other_path.as_deref()),
+                optional_field(file.created),
+                optional_field(file.owner)
+            ));
+        }
+        csv
+    }
+}
+
+/// The path as a CSV field, quoted if it contains a separator, quote or line break.
+pub fn csv_field(path: Option<&Path>) -> String {
+    let path = path.map(|p| p.to_string_lossy()).unwrap_or_default();
+    if path.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", path.replace('"', "\"\""))
+    } else {
+        path.into_owned()
+    }
+}
+
+fn optional_field<T: ToString>(value: Option<T>) -> String {
+    value.map(|v| v.to_string()).unwrap_or_default()
+}
+
+impl fmt::Display for CompareReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sections = [
+            (Presence::Both, "In both databases"),
+            (Presence::OnlyHere, "Only in this database"),
+            (Presence::OnlyOther, "Only in the other database"),
+        ];
+        for (presence, title) in sections.iter() {
+            let (num_files, size) = self.totals(*presence);
+            writeln!(
+                f,
+                "{}: {} files, {:.2} GB",
+                title,
+                num_files,
+                size as f64 / (1024.0 * 1024.0 * 1024.0)
+            )?;
+            for file in self.with_presence(*presence) {
+                let path = file.path.as_ref().or(file.other_path.as_ref());
+                write!(f, "  {}", path.unwrap().to_string_lossy())?;
+                if let (Some(_), Some(other)) = (&file.path, &file.other_path) {
+                    write!(f, " = {}", other.to_string_lossy())?;
+                }
+                writeln!(f)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Database {
+    /// Compares the files of this database with the ones in the database at `other`.
+    /// The other database is attached, so the matching happens in SQLite instead of
+    /// loading both into memory.
+    pub fn compare_with(&self, other: &Path) -> Result<CompareReport> {
+        if !other.exists() {
+            return Err(anyhow!("{} does not exist", other.to_string_lossy()));
+        }
+        self.db.execute(
+            "ATTACH DATABASE ?1 AS other",
+            params![other.to_string_lossy()],
+        )?;
+        let report = self.compare_with_attached();
+        self.db.execute("DETACH DATABASE other", params![])?;
+        report
+    }
+
+    fn compare_with_attached(&self) -> Result<CompareReport> {
+        let has_digests: bool = self.db.query_row(
+            "SELECT EXISTS (SELECT 1 FROM other.sqlite_master
+                WHERE type = 'table' AND name = 'file_digests')",
+            params![],
+            |row| row.get(0),
+        )?;
+        if !has_digests {
+            return Err(anyhow!("The other database contains no Dupletti index"));
+        }
+        // There is no record of how digests were computed, but digests of different
+        // hash functions differ in length and could never match.
+        let mut stmt = self.db.prepare(
+            "SELECT length(digest) FROM main.file_digests WHERE digest IS NOT NULL
+             UNION SELECT length(digest) FROM other.file_digests WHERE digest IS NOT NULL",
+        )?;
+        let lengths: Result<Vec<i64>, _> = stmt.query_map([], |row| row.get(0))?.collect();
+        if lengths?.len() > 1 {
+            return Err(anyhow!(
+                "The databases contain digests of different lengths and can't be compared"
+            ));
+        }
+
+        let mut files = Vec::new();
+        let mut stmt = self.db.prepare(
+            "SELECT a.path, a.size, MIN(b.path), m.created, m.uid FROM main.file_digests a
+             LEFT JOIN other.file_digests b ON b.digest = a.digest
+             LEFT JOIN main.file_meta m ON m.id = a.id
+             WHERE a.digest IS NOT NULL
+             GROUP BY a.id ORDER BY a.path",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            let other_path: Option<String> = row.get(2)?;
+            Ok(ComparedFile {
+                presence: if other_path.is_some() {
+                    Presence::Both
+                } else {
+                    Presence::OnlyHere
+                },
+                size: row.get::<_, i64>(1)? as u64,
+                path: Some(PathBuf::from(row.get::<_, String>(0)?)),
+                other_path: other_path.map(PathBuf::from),
+                created: row.get(3)?,
+                owner: row.get(4)?,
+            })
+        })?;
+        for row in rows {
+            files.push(row?);
+        }
+        let mut stmt = self.db.prepare(
+            "SELECT b.path, b.size FROM other.file_digests b
+             WHERE b.digest IS NOT NULL AND NOT EXISTS
+                (SELECT 1 FROM main.file_digests a WHERE a.digest = b.digest)
+             ORDER BY b.path",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok(ComparedFile {
+                presence: Presence::OnlyOther,
+                size: row.get::<_, i64>(1)? as u64,
+                path: None,
+                other_path: Some(PathBuf::from(row.get::<_, String>(0)?)),
+                created: None,
+                owner: None,
+            })
+        })?;
+        for row in rows {
+            files.push(row?);
+        }
+        Ok(CompareReport { files })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::FileDigest;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_compare_with() -> Result<()> {
+        let dir = tempdir()?;
+        let laptop = Database::new(dir.path().join("laptop.sqlite"), true)?;
+        laptop.insert_filedigest(&FileDigest::new(1, "/laptop/a", vec![1; 8], 10))?;
+        laptop.insert_filedigest(&FileDigest::new(2, "/laptop/b", vec![2; 8], 20))?;
+        laptop.insert_filedigest(&FileDigest::new(3, "/laptop/copy of a", vec![1; 8], 10))?;
+        let nas_path = dir.path().join("nas.sqlite");
+        let nas = Database::new(&nas_path, true)?;
+        nas.insert_filedigest(&FileDigest::new(1, "/nas/a", vec![1; 8], 10))?;
+        nas.insert_filedigest(&FileDigest::new(2, "/nas/a, again", vec![1; 8], 10))?;
+        nas.insert_filedigest(&FileDigest::new(3, "/nas/c", vec![3; 8], 30))?;
+
+        let report = laptop.compare_with(&nas_path)?;
+        let both: Vec<(&Path, &Path)> = report
+            .with_presence(Presence::Both)
+            .map(|f| (f.path.as_deref().unwrap(), f.other_path.as_deref().unwrap()))
+            .collect();
+        assert_eq!(
+            both,
+            [
+                (Path::new("/laptop/a"), Path::new("/nas/a")),
+                (Path::new("/laptop/copy of a"), Path::new("/nas/a"))
+            ]
+        );
+        assert_eq!(report.totals(Presence::Both), (2, 20));
+        assert_eq!(report.totals(Presence::OnlyHere), (1, 20));
+        let only_other: Vec<&Path> = report
+            .with_presence(Presence::OnlyOther)
+            .map(|f| f.other_path.as_deref().unwrap())
+            .collect();
+        assert_eq!(only_other, [Path::new("/nas/c")]);
+
+        nas.db.execute(
+            "INSERT INTO file_meta (id, created, uid) VALUES (3, 1600000000, 1000)",
+            params![],
+        )?;
+        let csv = nas
+            .compare_with(&dir.path().join("laptop.sqlite"))?
+            .to_csv();
+        assert!(csv.starts_with("presence,size,path,other_path,created,owner_uid\n"));
+        assert!(csv.contains("both,10,\"/nas/a, again\",/laptop/a,,\n"));
+        assert!(csv.contains("only_here,30,/nas/c,,1600000000,1000\n"));
+        assert!(csv.contains("only_other,20,,/laptop/b,,\n"));
+
+        // the other database is detached again
+        assert!(laptop.compare_with(&nas_path).is_ok());
+        assert!(laptop
+            .compare_with(&dir.path().join("missing.sqlite"))
+            .is_err());
+        nas.insert_filedigest(&FileDigest::new(4, "/nas/d", vec![4; 32], 40))?;
+        assert!(laptop.compare_with(&nas_path).is_err());
+        Ok(())
+    }
+}
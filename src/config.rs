@@ -0,0 +1,159 @@
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::backup::BackupPolicy;
+use crate::filehashing::ScanOrder;
+use crate::similarities::KeepPolicy;
+
+/// The settings of a run after defaults are applied, as shown by `--show-config` and
+/// GET /api/config. None of the settings are secret, so nothing needs to be redacted.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct EffectiveConfig {
+    /// The file default options were read from, if any
+    pub config_file: Option<PathBuf>,
+    pub database_path: PathBuf,
+    pub scan: ScanConfig,
+    pub web: WebConfig,
+    pub videohash: VideoHashSettings,
+    pub backup: BackupPolicy,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ScanConfig {
+    /// Empty if no directory is scanned
+    pub paths: Vec<PathBuf>,
+    pub threads: usize,
+    /// --hash-threads and --video-threads, with 0 resolved to the number of CPUs
+    pub hash_threads: usize,
+    pub video_threads: usize,
+    pub commit_batchsize: usize,
+    pub commit_batch_bytes: usize,
+    pub hash_queue_size: usize,
+    pub clean_unfound: bool,
+    pub force: bool,
+    pub dry_run: bool,
+    pub cleanup_limits: CleanupLimits,
+    pub case_insensitive_paths: bool,
+    pub resume: bool,
+    /// Including the default extensions, unless they are disabled
+    pub skip_extensions: Vec<String>,
+    pub only_extensions: Vec<String>,
+    pub exclude_paths: Vec<String>,
+    pub clean_excluded: bool,
+    /// Bytes
+    pub min_size: u64,
+    pub clean_small: bool,
+    pub hydrate_placeholders: bool,
+    pub marker_file: String,
+    pub follow_symlinks: bool,
+    pub max_depth: Option<usize>,
+    pub use_gitignore: bool,
+    pub skip_hidden: bool,
+    pub one_file_system: bool,
+    pub prune_marked: bool,
+    pub probe_media: bool,
+    pub collect_metadata: bool,
+    pub burst_hash: bool,
+    pub scan_order: ScanOrder,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct WebConfig {
+    pub enabled: bool,
+    pub bind_address: String,
+    pub port: u16,
+    pub allow_preview: bool,
+    /// Only true if revealing was requested and the address is a loopback address
+    pub allow_reveal: bool,
+    pub max_body_size: usize,
+    pub max_group_size: usize,
+    pub keep: KeepPolicy,
+    pub added_after: Option<i64>,
+    pub include_dismissed: bool,
+    pub show_hardlinks: bool,
+    /// The first one is the one that is scanned
+    pub databases: Vec<DatabaseSpec>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct VideoHashSettings {
+    pub enabled: bool,
+    pub frame_size: u32,
+    pub min_duration: f64,
+    pub threshold: Option<u16>,
+}
+
+/// How much of the DB --clean-unfound may remove without --force. Scanning a drive that
+/// isn't mounted finds nothing, which would otherwise empty the whole DB.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct CleanupLimits {
+    pub max_percent: f64,
+    pub max_files: Option<usize>,
+}
+
+impl CleanupLimits {
+    /// Whether removing `num_removed` of `num_indexed` files is more than allowed.
+    pub fn exceeded_by(&self, num_removed: usize, num_indexed: usize) -> bool {
+        if num_removed == 0 {
+            return false;
+        }
+        let percent = num_removed as f64 * 100.0 / num_indexed.max(num_removed) as f64;
+        percent > self.max_percent || matches!(self.max_files, Some(max) if num_removed > max)
+    }
+}
+
+impl Default for CleanupLimits {
+    fn default() -> CleanupLimits {
+        CleanupLimits {
+            max_percent: 20.0,
+            max_files: None,
+        }
+    }
+}
+
+/// A database given with `--database [label=]path`. Without a label, the file name
+/// without extension is used.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DatabaseSpec {
+    /// The web interface serves the database below /<label>/ if there are several
+    pub label: String,
+    pub path: PathBuf,
+}
+
+impl DatabaseSpec {
+    pub fn new(path: impl Into<PathBuf>) -> Result<DatabaseSpec> {
+        let path = path.into();
+        let label = path
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        DatabaseSpec::with_label(&label, &path)
+    }
+
+    pub fn with_label(label: &str, path: &Path) -> Result<DatabaseSpec> {
+        let is_valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+        if label.is_empty() || !label.chars().all(is_valid) {
+            return Err(anyhow!(
+                "Invalid database label '{}', use letters, digits, - and _",
+                label
+            ));
+        }
+        Ok(DatabaseSpec {
+            label: label.to_string(),
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl FromStr for DatabaseSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<DatabaseSpec> {
+        match s.find('=') {
+            Some(i) => DatabaseSpec::with_label(&s[..i], Path::new(&s[i + 1..])),
+            None => DatabaseSpec::new(s),
+        }
+    }
+}
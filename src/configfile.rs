@@ -0,0 +1,86 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Looked for in the working directory, then in ~/.config/dupletti/
+pub const CONFIG_FILE_NAME: &str = "dupletti.toml";
+
+/// Defaults for the command line options, read from a TOML file. Options given on the
+/// command line take precedence, options missing from both keep their built-in default.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    /// Like --database, as path or label=path
+    pub database: Option<String>,
+    /// Scanned when Dupletti is run without --path or a command
+    pub paths: Option<Vec<PathBuf>>,
+    pub exclude_paths: Option<Vec<String>>,
+    pub threads: Option<usize>,
+    pub port: Option<u16>,
+    pub bind_address: Option<String>,
+    pub allow_preview: Option<bool>,
+    pub videohash: Option<bool>,
+}
+
+impl ConfigFile {
+    pub fn read(path: &Path) -> Result<ConfigFile> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Unable to read {}", path.to_string_lossy()))?;
+        toml::from_str(&text).with_context(|| format!("Unable to parse {}", path.to_string_lossy()))
+    }
+
+    /// Reads `explicit` if given, otherwise the first config file found in the usual
+    /// places. Returns the path of the file that was read.
+    pub fn find(explicit: Option<&Path>) -> Result<Option<(PathBuf, ConfigFile)>> {
+        if let Some(path) = explicit {
+            return Ok(Some((path.to_path_buf(), ConfigFile::read(path)?)));
+        }
+        let mut candidates = vec![PathBuf::from(CONFIG_FILE_NAME)];
+        if let Some(home) = std::env::var_os("HOME") {
+            candidates.push(
+                Path::new(&home)
+                    .join(".config")
+                    .join("dupletti")
+                    .join(CONFIG_FILE_NAME),
+            );
+        }
+        for path in candidates {
+            if path.is_file() {
+                let file = ConfigFile::read(&path)?;
+                return Ok(Some((path, file)));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_config_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join(CONFIG_FILE_NAME);
+        fs::write(
+            &path,
+            "database = \"photos=/data/photos.sqlite\"\n\
+             paths = [\"/data/photos\", \"/data/phone\"]\n\
+             port = 8080\n\
+             allow_preview = true\n",
+        )?;
+        let (found, file) = ConfigFile::find(Some(&path))?.unwrap();
+        assert_eq!(found, path);
+        assert_eq!(file.database.as_deref(), Some("photos=/data/photos.sqlite"));
+        assert_eq!(file.paths.unwrap().len(), 2);
+        assert_eq!((file.port, file.allow_preview), (Some(8080), Some(true)));
+        assert_eq!(file.threads, None);
+
+        // typos are reported instead of silently ignored
+        fs::write(&path, "prot = 8080\n")?;
+        assert!(ConfigFile::read(&path).is_err());
+        assert!(ConfigFile::read(&dir.path().join("missing.toml")).is_err());
+        Ok(())
+    }
+}
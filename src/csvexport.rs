@@ -0,0 +1,116 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use crate::compare::csv_field;
+use crate::database::Database;
+use crate::similarities::{get_list_of_similar_files, FileGroup};
+
+/// Writes one line per file of `groups`: group id, file id, path, size and the hex
+/// encoded digest.
+pub fn write_groups_csv<W: Write>(db: &Database, groups: &[FileGroup], mut out: W) -> Result<()> {
+    let digests: HashMap<i64, Vec<u8>> = db
+        .get_all_digest_rows()?
+        .into_iter()
+        .map(|(id, digest, _)| (id, digest))
+        .collect();
+    writeln!(out, "group_id,file_id,path,size,digest_hex")?;
+    for group in groups {
+        for file in &group.files {
+            writeln!(
+                out,
+                "{},{},{},{},{}",
+                group.group_id,
+                file.id,
+                csv_field(Some(&file.path)),
+                file.size,
+                digests.get(&file.id).map(hex::encode).unwrap_or_default()
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes all duplicate groups of `db` to `path` and returns their number.
+pub fn export_groups_csv(db: &Database, path: &Path) -> Result<usize> {
+    let groups = get_list_of_similar_files(db)?;
+    let mut out = std::io::BufWriter::new(fs::File::create(path)?);
+    write_groups_csv(db, &groups, &mut out)?;
+    out.flush()?;
+    Ok(groups.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::FileDigest;
+
+    #[test]
+    fn test_export_groups_csv() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = Database::new("test_export_groups_csv.sqlite", true)?;
+        let paths = [
+            "/tmp/nonexistent/plain",
+            "/tmp/nonexistent/a, b",
+            "/tmp/nonexistent/\"quoted\"",
+            "/tmp/nonexistent/two\nlines",
+        ];
+        for path in &paths {
+            db.insert_filedigest(&FileDigest::new(0, path, vec![0xab; 8], 7))?;
+        }
+        db.insert_filedigest(&FileDigest::new(
+            0,
+            "/tmp/nonexistent/single",
+            vec![2; 8],
+            7,
+        ))?;
+
+        let csv_path = dir.path().join("groups.csv");
+        assert_eq!(export_groups_csv(&db, &csv_path)?, 1);
+        let csv = fs::read_to_string(&csv_path)?;
+        let mut lines = csv.lines();
+        assert_eq!(lines.next(), Some("group_id,file_id,path,size,digest_hex"));
+        assert!(csv.contains(",/tmp/nonexistent/plain,7,abababababababab\n"));
+        assert!(csv.contains(",\"/tmp/nonexistent/a, b\",7,abababababababab\n"));
+        assert!(csv.contains(",\"/tmp/nonexistent/\"\"quoted\"\"\",7,abababababababab\n"));
+        assert!(csv.contains(",\"/tmp/nonexistent/two\nlines\",7,abababababababab\n"));
+        assert!(!csv.contains("single"));
+
+        // every path is read back from its quoted field
+        let mut read_back: Vec<String> = parse_csv(&csv)
+            .into_iter()
+            .skip(1)
+            .map(|fields| fields[2].clone())
+            .collect();
+        read_back.sort();
+        let mut expected: Vec<String> = paths.iter().map(|p| p.to_string()).collect();
+        expected.sort();
+        assert_eq!(read_back, expected);
+        Ok(())
+    }
+
+    /// Splits `csv` into records of fields, honouring quotes.
+    fn parse_csv(csv: &str) -> Vec<Vec<String>> {
+        let (mut records, mut fields, mut field) = (Vec::new(), Vec::new(), String::new());
+        let mut chars = csv.chars().peekable();
+        let mut quoted = false;
+        while let Some(c) = chars.next() {
+            match c {
+                '"' if quoted && chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' => quoted = !quoted,
+                ',' if !quoted => fields.push(std::mem::take(&mut field)),
+                '\n' if !quoted => {
+                    fields.push(std::mem::take(&mut field));
+                    records.push(std::mem::take(&mut fields));
+                }
+                _ => field.push(c),
+            }
+        }
+        records
+    }
+}
@@ -1,6 +1,15 @@
+use crate::config::CleanupLimits;
+use crate::exif::ExifInfo;
+use crate::filemeta::FileMeta;
+use crate::paths::PathCase;
 use anyhow::{anyhow, Context, Result};
-use rusqlite::{params, Connection};
+use rayon::prelude::*;
+use rusqlite::{params, Connection, OpenFlags};
+use std::fmt;
+use std::fs;
+use std::io;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct FileDigest {
@@ -8,6 +17,9 @@ pub struct FileDigest {
     pub path: PathBuf,
     pub digest: Vec<u8>,
     pub size: u64,
+    pub exif: ExifInfo,
+    /// Only read with --collect-metadata
+    pub meta: Option<FileMeta>,
 }
 
 impl FileDigest {
@@ -17,24 +29,157 @@ impl FileDigest {
             path: PathBuf::from(path),
             digest: digest,
             size: size,
+            exif: ExifInfo::default(),
+            meta: None,
         }
     }
 }
 
+/// Columns read by `filedigest_from_row`.
+const FILEDIGEST_COLUMNS: &str =
+    "id, path, digest, size, exif_capture_date, exif_camera_make, exif_camera_model";
+
+fn filedigest_from_row(row: &rusqlite::Row) -> rusqlite::Result<FileDigest> {
+    let path_string: String = row.get(1)?;
+    Ok(FileDigest {
+        id: row.get(0)?,
+        path: PathBuf::from(path_string),
+        digest: row.get(2)?,
+        size: row.get(3)?,
+        exif: ExifInfo {
+            capture_date: row.get(4)?,
+            camera_make: row.get(5)?,
+            camera_model: row.get(6)?,
+        },
+        meta: None,
+    })
+}
+
 pub struct Database {
     pub db: Connection,
 }
 
+/// Returned by `purge_missing` when it refuses to remove the missing files.
+#[derive(Debug)]
+pub struct PurgeRefused(pub String);
+
+impl fmt::Display for PurgeRefused {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for PurgeRefused {}
+
+fn is_missing(path: &Path) -> bool {
+    matches!(fs::metadata(path), Err(e) if e.kind() == io::ErrorKind::NotFound)
+}
+
+/// SQLite opens a database that only lives in memory for this name
+pub const IN_MEMORY_PATH: &str = ":memory:";
+
+/// How long a connection waits for another process to finish writing before it fails.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Tables with rows computed from the content of a file, by its id. They are outdated once
+/// the file changes.
+pub const CONTENT_TABLES: [&str; 7] = [
+    "video_hash",
+    "video_errors",
+    "media_metadata",
+    "image_hashes",
+    "thumbnails",
+    "chunked_files",
+    "chunks",
+];
+
+/// The other tables with rows about a file, by its id.
+const FILE_TABLES: [&str; 4] = [
+    "file_tags",
+    "protected_files",
+    "file_meta",
+    "pending_actions",
+];
+
 impl Database {
+    /// A database that is gone when it is dropped.
+    pub fn in_memory() -> Result<Database> {
+        Database::new(IN_MEMORY_PATH, false)
+    }
+
+    /// A read-only connection to a database that `new` has set up before.
+    pub fn open_reader<P: AsRef<Path>>(filepath: P) -> Result<Database> {
+        let flags = OpenFlags::SQLITE_OPEN_READ_ONLY
+            | OpenFlags::SQLITE_OPEN_NO_MUTEX
+            | OpenFlags::SQLITE_OPEN_URI;
+        let db = Database {
+            db: Connection::open_with_flags(filepath, flags)?,
+        };
+        db.db.busy_timeout(BUSY_TIMEOUT)?;
+        Ok(db)
+    }
+
+    /// Opens the database at `filepath`, creating it and its parent directories if needed.
     pub fn new<P: AsRef<Path>>(filepath: P, reset: bool) -> Result<Database> {
+        let filepath = filepath.as_ref();
+        if filepath.is_dir() {
+            return Err(anyhow!(
+                "{} is a directory, give the path of a database file like {}",
+                filepath.to_string_lossy(),
+                filepath.join("digests.sqlite").to_string_lossy()
+            ));
+        }
+        if let Some(parent) = filepath.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent).with_context(|| {
+                format!(
+                    "Unable to create the directory of {}",
+                    filepath.to_string_lossy()
+                )
+            })?;
+        }
         let db = Database {
             db: Connection::open(filepath)?,
         };
+        // In WAL mode, a scanning process can write while a serving one keeps reading
+        // from its snapshot. In-memory databases stay in their own journal mode.
+        db.db.busy_timeout(BUSY_TIMEOUT)?;
+        db.db.query_row("PRAGMA journal_mode = WAL", [], |row| {
+            row.get::<_, String>(0)
+        })?;
         if reset {
             db.db
                 .execute("DROP TABLE IF EXISTS file_digests", params![])?;
             db.db
                 .execute("DROP TABLE IF EXISTS video_hash", params![])?;
+            db.db
+                .execute("DROP TABLE IF EXISTS pending_files", params![])?;
+            db.db
+                .execute("DROP TABLE IF EXISTS video_errors", params![])?;
+            db.db
+                .execute("DROP TABLE IF EXISTS media_metadata", params![])?;
+            for table in [
+                "file_tags",
+                "dismissed_groups",
+                "protected_files",
+                "tombstones",
+                "group_notes",
+                "group_acks",
+                "scan_roots",
+                "excluded_digests",
+                "image_hashes",
+                "file_meta",
+                "skipped_files",
+                "thumbnails",
+                "chunked_files",
+                "chunks",
+                "jobs",
+                "pending_actions",
+            ]
+            .iter()
+            {
+                db.db
+                    .execute(&format!("DROP TABLE IF EXISTS {}", table), params![])?;
+            }
         }
         db.db
             .execute(
@@ -42,50 +187,402 @@ impl Database {
 					id    	INTEGER PRIMARY KEY,
 					path   	TEXT NOT NULL UNIQUE,
 					digest	BLOB,
-					size  	INTEGER     
+					size  	INTEGER,
+					first_seen	INTEGER,
+					exif_capture_date	TEXT,
+					exif_camera_make	TEXT,
+					exif_camera_model	TEXT,
+					root_id		INTEGER,
+					path_key	TEXT
 					)",
                 params![],
             )
             .context("Creating Database")?;
+        // finds the rows of moved files by their content
+        db.db
+            .execute(
+                "CREATE INDEX IF NOT EXISTS file_digests_digest ON file_digests (digest)",
+                params![],
+            )
+            .context("Creating Database")?;
 
         db.db
             .execute(
                 "CREATE TABLE IF NOT EXISTS video_hash (
 					id          INTEGER PRIMARY KEY,
-					histogram	BLOB   
+					histogram	BLOB,
+					frame_size	INTEGER NOT NULL DEFAULT 128,
+					duration	REAL
+					)",
+                params![],
+            )
+            .context("Creating Database")?;
+        // when files were first indexed is unknown for rows from older versions
+        db.add_column_if_missing("file_digests", "first_seen", "INTEGER")?;
+        db.add_column_if_missing("file_digests", "exif_capture_date", "TEXT")?;
+        db.add_column_if_missing("file_digests", "exif_camera_make", "TEXT")?;
+        db.add_column_if_missing("file_digests", "exif_camera_model", "TEXT")?;
+        // hashes from before the frame size was configurable were all computed at 128px
+        db.add_column_if_missing("video_hash", "frame_size", "INTEGER NOT NULL DEFAULT 128")?;
+        db.add_column_if_missing("video_hash", "duration", "REAL")?;
+        // the path as compared by `PathCase::key` when the file was last scanned, NULL for
+        // rows of older versions and renamed files until they are scanned again
+        db.add_column_if_missing("file_digests", "path_key", "TEXT")?;
+        db.db
+            .execute(
+                "CREATE UNIQUE INDEX IF NOT EXISTS file_digests_path_key \
+                 ON file_digests (path_key)",
+                params![],
+            )
+            .context("Creating Database")?;
+
+        db.db
+            .execute(
+                "CREATE TABLE IF NOT EXISTS video_errors (
+					id          INTEGER PRIMARY KEY,
+					message		TEXT NOT NULL
+					)",
+                params![],
+            )
+            .context("Creating Database")?;
+
+        db.db
+            .execute(
+                "CREATE TABLE IF NOT EXISTS media_metadata (
+					id          INTEGER PRIMARY KEY,
+					duration	REAL,
+					width		INTEGER,
+					height		INTEGER
+					)",
+                params![],
+            )
+            .context("Creating Database")?;
+
+        db.db
+            .execute(
+                "CREATE TABLE IF NOT EXISTS file_tags (
+					id		INTEGER NOT NULL,
+					tag		TEXT NOT NULL,
+					PRIMARY KEY (id, tag)
+					)",
+                params![],
+            )
+            .context("Creating Database")?;
+
+        db.db
+            .execute(
+                "CREATE TABLE IF NOT EXISTS dismissed_groups (
+					group_id	TEXT PRIMARY KEY
+					)",
+                params![],
+            )
+            .context("Creating Database")?;
+
+        db.db
+            .execute(
+                "CREATE TABLE IF NOT EXISTS group_notes (
+					group_id	TEXT PRIMARY KEY,
+					note		TEXT NOT NULL,
+					updated_at	INTEGER NOT NULL
+					)",
+                params![],
+            )
+            .context("Creating Database")?;
+
+        db.db
+            .execute(
+                "CREATE TABLE IF NOT EXISTS group_acks (
+					session		TEXT NOT NULL,
+					group_id	TEXT NOT NULL,
+					membership	TEXT NOT NULL,
+					acked_at	INTEGER NOT NULL,
+					PRIMARY KEY (session, group_id)
+					)",
+                params![],
+            )
+            .context("Creating Database")?;
+
+        db.db
+            .execute(
+                "CREATE TABLE IF NOT EXISTS scan_roots (
+					id			INTEGER PRIMARY KEY,
+					path		TEXT NOT NULL UNIQUE,
+					last_scan	INTEGER NOT NULL
+					)",
+                params![],
+            )
+            .context("Creating Database")?;
+
+        // dhash is NULL for photos that couldn't be decoded
+        db.db
+            .execute(
+                "CREATE TABLE IF NOT EXISTS image_hashes (
+					id		INTEGER PRIMARY KEY,
+					dhash	INTEGER
+					)",
+                params![],
+            )
+            .context("Creating Database")?;
+
+        // only filled with --collect-metadata, NULL where the platform lacks a field
+        db.db
+            .execute(
+                "CREATE TABLE IF NOT EXISTS file_meta (
+					id			INTEGER PRIMARY KEY,
+					created		INTEGER,
+					changed		INTEGER,
+					accessed	INTEGER,
+					uid			INTEGER,
+					mode		INTEGER,
+					dev			INTEGER,
+					inode		INTEGER,
+					links		INTEGER
+					)",
+                params![],
+            )
+            .context("Creating Database")?;
+        db.add_column_if_missing("file_meta", "dev", "INTEGER")?;
+        db.add_column_if_missing("file_meta", "inode", "INTEGER")?;
+        db.add_column_if_missing("file_meta", "links", "INTEGER")?;
+
+        db.db
+            .execute(
+                "CREATE TABLE IF NOT EXISTS excluded_digests (
+					digest		BLOB PRIMARY KEY,
+					source		TEXT,
+					added_at	INTEGER NOT NULL
+					)",
+                params![],
+            )
+            .context("Creating Database")?;
+
+        db.db
+            .execute(
+                "CREATE TABLE IF NOT EXISTS protected_files (
+					id		INTEGER PRIMARY KEY
+					)",
+                params![],
+            )
+            .context("Creating Database")?;
+
+        db.db
+            .execute(
+                "CREATE TABLE IF NOT EXISTS tombstones (
+					digest		BLOB NOT NULL,
+					path		TEXT NOT NULL,
+					deleted_at	INTEGER NOT NULL,
+					size		INTEGER
+					)",
+                params![],
+            )
+            .context("Creating Database")?;
+        db.db
+            .execute(
+                "CREATE INDEX IF NOT EXISTS tombstones_digest ON tombstones (digest)",
+                params![],
+            )
+            .context("Creating Database")?;
+
+        db.db
+            .execute(
+                "CREATE TABLE IF NOT EXISTS pending_files (
+					path   		TEXT PRIMARY KEY,
+					listed_at	INTEGER NOT NULL
+					)",
+                params![],
+            )
+            .context("Creating Database")?;
+        db.db
+            .execute(
+                "CREATE TABLE IF NOT EXISTS skipped_files (
+					path   		TEXT PRIMARY KEY,
+					reason		TEXT NOT NULL,
+					skipped_at	INTEGER NOT NULL
+					)",
+                params![],
+            )
+            .context("Creating Database")?;
+        // rendered images, only valid while the file still has the digest they were
+        // rendered from
+        db.db
+            .execute(
+                "CREATE TABLE IF NOT EXISTS thumbnails (
+					id		INTEGER NOT NULL,
+					kind	TEXT NOT NULL,
+					digest	BLOB NOT NULL,
+					data	BLOB NOT NULL,
+					PRIMARY KEY (id, kind)
+					)",
+                params![],
+            )
+            .context("Creating Database")?;
+        // only filled with --chunk-index, see `chunks`
+        db.db
+            .execute(
+                "CREATE TABLE IF NOT EXISTS chunked_files (
+					id			INTEGER PRIMARY KEY,
+					digest		BLOB NOT NULL,
+					chunk_size	INTEGER NOT NULL
+					)",
+                params![],
+            )
+            .context("Creating Database")?;
+        db.db
+            .execute(
+                "CREATE TABLE IF NOT EXISTS chunks (
+					id			INTEGER NOT NULL,
+					chunk_index	INTEGER NOT NULL,
+					digest		BLOB NOT NULL,
+					PRIMARY KEY (id, chunk_index)
+					)",
+                params![],
+            )
+            .context("Creating Database")?;
+        db.db
+            .execute(
+                "CREATE INDEX IF NOT EXISTS chunks_digest ON chunks (digest)",
+                params![],
+            )
+            .context("Creating Database")?;
+        // files marked for deletion during review, see `queue`
+        db.db
+            .execute(
+                "CREATE TABLE IF NOT EXISTS pending_actions (
+					id				INTEGER PRIMARY KEY,
+					action			TEXT NOT NULL,
+					requested_at	INTEGER NOT NULL
 					)",
                 params![],
             )
             .context("Creating Database")?;
+        // jobs started through /api/jobs, see `jobs`
+        db.db
+            .execute(
+                "CREATE TABLE IF NOT EXISTS jobs (
+					id				INTEGER PRIMARY KEY,
+					request			TEXT NOT NULL,
+					status			TEXT NOT NULL,
+					progress_done	INTEGER,
+					progress_total	INTEGER,
+					result			TEXT,
+					error			TEXT,
+					created_at		INTEGER NOT NULL,
+					started_at		INTEGER,
+					finished_at		INTEGER
+					)",
+                params![],
+            )
+            .context("Creating Database")?;
+        db.migrate_scan_roots().context("Creating Database")?;
 
         Ok(db)
     }
 
+    pub(crate) fn has_column(&self, table: &str, column: &str) -> Result<bool> {
+        let mut stmt = self.db.prepare(&format!("PRAGMA table_info({})", table))?;
+        let columns: Result<Vec<String>, _> = stmt.query_map([], |row| row.get(1))?.collect();
+        Ok(columns?.iter().any(|c| c == column))
+    }
+
+    /// Brings tables created by older versions up to date. Returns whether the column
+    /// was added.
+    pub(crate) fn add_column_if_missing(
+        &self,
+        table: &str,
+        column: &str,
+        definition: &str,
+    ) -> Result<bool> {
+        if self.has_column(table, column)? {
+            return Ok(false);
+        }
+        log::info!("Adding column {} to table {}", column, table);
+        self.db.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+            params![],
+        )?;
+        Ok(true)
+    }
+
     pub fn get_all_filedigests(&self) -> Result<Vec<FileDigest>> {
         let mut stmt = self
             .db
-            .prepare("SELECT id, path, digest, size FROM file_digests")?;
+            .prepare(&format!("SELECT {} FROM file_digests", FILEDIGEST_COLUMNS))?;
+        let rows: Result<Vec<_>, _> = stmt.query_map([], filedigest_from_row)?.collect();
+        Ok(rows?)
+    }
+
+    pub fn count_filedigests(&self) -> Result<usize> {
+        let count: i64 =
+            self.db
+                .query_row("SELECT COUNT(*) FROM file_digests", params![], |row| {
+                    row.get(0)
+                })?;
+        Ok(count as usize)
+    }
+
+    /// Whether nothing was ever scanned into the database, not even the file list of a
+    /// scan that is still running.
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(self.count_filedigests()? == 0 && self.count_pending_files()? == 0)
+    }
+
+    /// Paths of all indexed files, without loading digests.
+    pub fn get_all_paths(&self) -> Result<Vec<PathBuf>> {
+        let mut stmt = self.db.prepare("SELECT path FROM file_digests")?;
         let rows: Result<Vec<_>, _> = stmt
             .query_map([], |row| {
-                let path_string: String = row.get(1)?;
-                Ok(FileDigest {
-                    id: row.get(0)?,
-                    path: PathBuf::from(path_string),
-                    digest: row.get(2)?,
-                    size: row.get(3)?,
-                })
+                let path_string: String = row.get(0)?;
+                Ok(PathBuf::from(path_string))
             })?
-            .into_iter()
             .collect();
         Ok(rows?)
     }
 
+    /// (id, digest, size) of all indexed files, which is all that's needed for grouping.
+    pub fn get_all_digest_rows(&self) -> Result<Vec<(i64, Vec<u8>, u64)>> {
+        let mut stmt = self
+            .db
+            .prepare("SELECT id, digest, size FROM file_digests")?;
+        let rows: Result<Vec<_>, _> = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+            .collect();
+        Ok(rows?)
+    }
+
+    /// Calls `callback` for every indexed file without materializing the whole table.
+    pub fn for_each_filedigest<F>(&self, mut callback: F) -> Result<()>
+    where
+        F: FnMut(FileDigest) -> Result<()>,
+    {
+        let mut stmt = self
+            .db
+            .prepare(&format!("SELECT {} FROM file_digests", FILEDIGEST_COLUMNS))?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            callback(filedigest_from_row(row)?)?;
+        }
+        Ok(())
+    }
+
     pub fn insert_filedigest(&self, file: &FileDigest) -> Result<()> {
         // use INSERT OR IGNORE in case we're mistakenly trying to insert something twice
-        let path = file.path.to_string_lossy();
+        // paths are stored as text, a lossy conversion would store a file that doesn't exist
+        let path = file
+            .path
+            .to_str()
+            .ok_or_else(|| anyhow!("{:?} is not valid UTF-8 and can't be stored", file.path))?;
         let cnt = self.db.execute(
-            "INSERT OR IGNORE INTO file_digests (path, digest, size) VALUES (?1, ?2, ?3)",
-            params![path, file.digest, file.size],
+            "INSERT OR IGNORE INTO file_digests \
+             (path, digest, size, exif_capture_date, exif_camera_make, exif_camera_model) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                path,
+                file.digest,
+                file.size,
+                file.exif.capture_date,
+                file.exif.camera_make,
+                file.exif.camera_model
+            ],
         )?;
         if cnt == 0 {
             return Err(anyhow!("Unable to insert {}", path));
@@ -93,28 +590,117 @@ impl Database {
         Ok(())
     }
 
+    /// Replaces the digest and size of a file whose content changed since it was hashed,
+    /// and drops what was derived from the old content. Returns false if there is no such
+    /// file.
+    pub fn update_filedigest_digest(&self, file_id: i64, digest: &[u8], size: u64) -> Result<bool> {
+        let tx = self.db.unchecked_transaction()?;
+        let num_updated = tx.execute(
+            "UPDATE file_digests SET digest = ?1, size = ?2 WHERE id = ?3",
+            params![digest, size, file_id],
+        )?;
+        for table in CONTENT_TABLES.iter() {
+            tx.execute(
+                &format!("DELETE FROM {} WHERE id =(?1)", table),
+                params![file_id],
+            )?;
+        }
+        tx.commit()?;
+        Ok(num_updated > 0)
+    }
+
     pub fn lookup_filedigest(&self, file_id: i64) -> Result<FileDigest> {
         Ok(self.db.query_row(
-            "SELECT  id, path, digest, size FROM file_digests WHERE id =(?1)",
+            &format!(
+                "SELECT {} FROM file_digests WHERE id = ?1",
+                FILEDIGEST_COLUMNS
+            ),
             params![file_id],
-            |row| {
-                let path_string: String = row.get(1)?;
-                Ok(FileDigest {
-                    id: row.get(0)?,
-                    path: PathBuf::from(path_string),
-                    digest: row.get(2)?,
-                    size: row.get(3)?,
-                })
-            },
+            filedigest_from_row,
         )?)
     }
 
+    /// Removes all files that no longer exist on disk, optionally only those below `prefix`.
+    /// Returns the removed paths. Files that can't be looked up for other reasons are kept.
+    /// Like --clean-unfound, nothing is removed if `prefix`, or a scan root without one,
+    /// is empty while missing files are below it, since that is most likely a drive that
+    /// isn't mounted. Unless forced by passing no `limits`, the same goes for a root that
+    /// doesn't exist, and for removing more files than `limits` allow.
+    pub fn purge_missing(
+        &self,
+        prefix: Option<&Path>,
+        path_case: PathCase,
+        limits: Option<CleanupLimits>,
+    ) -> Result<Vec<PathBuf>> {
+        let mut candidates = Vec::new();
+        let mut stmt = self.db.prepare("SELECT id, path FROM file_digests")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let path = PathBuf::from(row.get::<_, String>(1)?);
+            if prefix.is_none_or(|p| path_case.starts_with(&path, p)) {
+                candidates.push((row.get::<_, i64>(0)?, path));
+            }
+        }
+        let num_candidates = candidates.len();
+        let missing: Vec<(i64, PathBuf)> = candidates
+            .into_par_iter()
+            .filter(|(_, path)| is_missing(path))
+            .collect();
+        let roots = match prefix {
+            Some(prefix) => vec![prefix.to_path_buf()],
+            None => self.get_scan_roots()?.into_iter().map(|r| r.path).collect(),
+        };
+        for root in roots.iter() {
+            let below_root = missing
+                .iter()
+                .filter(|(_, path)| path_case.starts_with(path, root))
+                .count();
+            if below_root == 0 {
+                continue;
+            }
+            let refused = match fs::read_dir(root).map(|mut entries| entries.next().is_none()) {
+                Ok(true) => "is empty",
+                Err(_) if limits.is_some() => "can't be read",
+                _ => continue,
+            };
+            return Err(PurgeRefused(format!(
+                "{} {}, but {} missing files are below it. Is the drive mounted? Refusing to \
+                 purge missing files",
+                root.to_string_lossy(),
+                refused,
+                below_root
+            ))
+            .into());
+        }
+        if matches!(limits, Some(l) if l.exceeded_by(missing.len(), num_candidates)) {
+            return Err(PurgeRefused(format!(
+                "Refusing to purge {} of {} indexed files that no longer exist, pass --force to \
+                 do it anyway",
+                missing.len(),
+                num_candidates
+            ))
+            .into());
+        }
+
+        let tx = self.db.unchecked_transaction()?;
+        for (id, path) in missing.iter() {
+            log::info!("Purging missing file {:?}", path);
+            self.delete_filedigest(*id)?;
+        }
+        tx.commit()?;
+        Ok(missing.into_iter().map(|(_, path)| path).collect())
+    }
+
     pub fn delete_filedigest(&self, file_id: i64) -> Result<usize> {
         let num_deleted = self
             .db
             .execute("DELETE FROM file_digests WHERE id =(?1)", params![file_id])?;
-        self.db
-            .execute("DELETE FROM video_hash WHERE id =(?1)", params![file_id])?;
+        for table in CONTENT_TABLES.iter().chain(FILE_TABLES.iter()) {
+            self.db.execute(
+                &format!("DELETE FROM {} WHERE id =(?1)", table),
+                params![file_id],
+            )?;
+        }
         Ok(num_deleted)
     }
 }
@@ -122,11 +708,34 @@ impl Database {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use rayon::prelude::*;
-    use std::fs;
     use std::io;
     use std::sync::mpsc;
 
+    #[test]
+    fn test_new_creates_parent_directories() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("nested").join("dbs").join("digests.sqlite");
+        Database::new(&path, false)?;
+        assert!(path.is_file());
+        let err = Database::new(dir.path(), false).err().unwrap();
+        assert!(err.to_string().contains("is a directory"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_empty() -> Result<()> {
+        let mut db = Database::new("test_is_empty.sqlite", true)?;
+        assert!(db.is_empty()?);
+        // a scan that is still listing files already counts
+        db.store_pending_files(&[PathBuf::from("/tmp/a")].iter().cloned().collect())?;
+        assert!(!db.is_empty()?);
+        db.store_pending_files(&Default::default())?;
+        assert!(db.is_empty()?);
+        db.insert_filedigest(&FileDigest::new(0, "/tmp/a", vec![1], 1))?;
+        assert!(!db.is_empty()?);
+        Ok(())
+    }
+
     #[test]
     fn test_insert_file() -> Result<()> {
         let db = Database::new("test1.sqlite", true)?;
@@ -154,6 +763,30 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_add_missing_frame_size_column() -> Result<()> {
+        let db = Database::new("test_add_missing_frame_size_column.sqlite", true)?;
+        db.db.execute("DROP TABLE video_hash", params![])?;
+        db.db.execute(
+            "CREATE TABLE video_hash (id INTEGER PRIMARY KEY, histogram BLOB)",
+            params![],
+        )?;
+        db.db.execute(
+            "INSERT INTO video_hash (id, histogram) VALUES (1, x'00')",
+            params![],
+        )?;
+        drop(db);
+
+        let db = Database::new("test_add_missing_frame_size_column.sqlite", false)?;
+        let frame_size: u32 =
+            db.db
+                .query_row("SELECT frame_size FROM video_hash", params![], |row| {
+                    row.get(0)
+                })?;
+        assert_eq!(frame_size, 128);
+        Ok(())
+    }
+
     /// This was used to update an existing DB.
     //#[test]
     #[allow(dead_code)]
@@ -213,6 +846,155 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_narrow_queries_agree_with_get_all_filedigests() -> Result<()> {
+        let db = Database::new("test_narrow_queries.sqlite", true)?;
+        db.insert_filedigest(&FileDigest::new(1, "/tmp/a", vec![0, 1, 2, 3], 1))?;
+        db.insert_filedigest(&FileDigest::new(2, "/tmp/b", vec![0, 1, 2, 3], 5))?;
+        db.insert_filedigest(&FileDigest::new(3, "/tmp/c", vec![4, 5, 6, 7], 9))?;
+        let all_files = db.get_all_filedigests()?;
+
+        let paths: Vec<_> = all_files.iter().map(|f| f.path.clone()).collect();
+        assert_eq!(db.get_all_paths()?, paths);
+
+        let digest_rows: Vec<_> = all_files
+            .iter()
+            .map(|f| (f.id, f.digest.clone(), f.size))
+            .collect();
+        assert_eq!(db.get_all_digest_rows()?, digest_rows);
+
+        let mut streamed = Vec::new();
+        db.for_each_filedigest(|f| {
+            streamed.push(f);
+            Ok(())
+        })?;
+        assert_eq!(streamed, all_files);
+        Ok(())
+    }
+
+    #[test]
+    fn test_purge_missing() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let existing = dir.path().join("sub/a");
+        fs::create_dir_all(dir.path().join("sub"))?;
+        fs::write(&existing, "x")?;
+        let db = Database::new("test_purge_missing.sqlite", true)?;
+        for (id, path) in [
+            (1, existing.clone()),
+            (2, dir.path().join("sub/b")),
+            (3, dir.path().join("other/c")),
+        ] {
+            db.insert_filedigest(&FileDigest::new(id, path.to_str().unwrap(), vec![0; 4], 1))?;
+            db.db.execute(
+                "INSERT INTO image_hashes (id, dhash) VALUES (?1, 0)",
+                params![id],
+            )?;
+            db.db.execute(
+                "INSERT INTO file_meta (id, created) VALUES (?1, 0)",
+                params![id],
+            )?;
+        }
+
+        let sub = dir.path().join("SUB");
+        let removed = db.purge_missing(Some(&sub), PathCase::Sensitive, None)?;
+        assert!(removed.is_empty());
+        let removed = db.purge_missing(Some(&sub), PathCase::Insensitive, None)?;
+        assert_eq!(removed, vec![dir.path().join("sub/b")]);
+
+        let removed = db.purge_missing(None, PathCase::Sensitive, None)?;
+        assert_eq!(removed, vec![dir.path().join("other/c")]);
+        assert_eq!(db.get_all_paths()?, vec![existing]);
+        let hashed: Vec<i64> = db
+            .db
+            .prepare("SELECT id FROM image_hashes")?
+            .query_map(params![], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        assert_eq!(hashed, vec![1]);
+        let with_meta: i64 =
+            db.db
+                .query_row("SELECT COUNT(*) FROM file_meta", params![], |row| {
+                    row.get(0)
+                })?;
+        assert_eq!(with_meta, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_purge_missing_guards() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = Database::new("test_purge_missing_guards.sqlite", true)?;
+        let library = dir.path().join("library");
+        let mount = dir.path().join("mount");
+        fs::create_dir_all(&library)?;
+        fs::create_dir_all(&mount)?;
+        for i in 0..10 {
+            let path = library.join(i.to_string());
+            if i < 8 {
+                fs::write(&path, "x")?;
+            }
+            db.insert_filedigest(&FileDigest::new(0, path.to_str().unwrap(), vec![i], 1))?;
+        }
+        db.insert_filedigest(&FileDigest::new(
+            0,
+            mount.join("a").to_str().unwrap(),
+            vec![10],
+            1,
+        ))?;
+        db.record_scan_root(&mount, PathCase::Sensitive)?;
+        let limits = Some(CleanupLimits::default());
+        let refused = |result: Result<Vec<PathBuf>>| result.unwrap_err().is::<PurgeRefused>();
+
+        // an empty root is refused even when forced
+        assert!(refused(db.purge_missing(
+            Some(&mount),
+            PathCase::Sensitive,
+            None
+        )));
+        assert!(refused(db.purge_missing(None, PathCase::Sensitive, None)));
+        // so is a root that doesn't exist, unless forced
+        let gone = dir.path().join("gone");
+        db.insert_filedigest(&FileDigest::new(
+            0,
+            gone.join("b").to_str().unwrap(),
+            vec![11],
+            1,
+        ))?;
+        assert!(refused(db.purge_missing(
+            Some(&gone),
+            PathCase::Sensitive,
+            limits
+        )));
+        assert_eq!(
+            db.purge_missing(Some(&gone), PathCase::Sensitive, None)?
+                .len(),
+            1
+        );
+
+        // 2 of 10 files are within the limits, 2 of the remaining 8 are not
+        let removed = db.purge_missing(Some(&library), PathCase::Sensitive, limits)?;
+        assert_eq!(removed.len(), 2);
+        fs::remove_file(library.join("0"))?;
+        fs::remove_file(library.join("1"))?;
+        assert!(refused(db.purge_missing(
+            Some(&library),
+            PathCase::Sensitive,
+            limits
+        )));
+        // files that can't be looked up aren't missing
+        fs::write(library.join("2"), "")?;
+        let unreadable = library.join("2").join("x");
+        db.insert_filedigest(&FileDigest::new(
+            0,
+            unreadable.to_str().unwrap(),
+            vec![12],
+            1,
+        ))?;
+        let removed = db.purge_missing(Some(&library), PathCase::Sensitive, None)?;
+        assert_eq!(removed, vec![library.join("0"), library.join("1")]);
+        assert_eq!(db.get_all_paths()?.len(), 8);
+        Ok(())
+    }
+
     #[test]
     fn test_insert_file_twice() -> Result<()> {
         let db = Database::new("test4.sqlite", true)?;
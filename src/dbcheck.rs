@@ -0,0 +1,201 @@
+use anyhow::Result;
+use rayon::prelude::*;
+use rusqlite::params;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::database::Database;
+use crate::paths::PathCase;
+
+/// Number of files whose existence is checked without --thorough
+const EXISTENCE_SAMPLE_SIZE: usize = 1000;
+
+/// Problems found by `Database::check_consistency`, counted per class.
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct ConsistencyReport {
+    /// Video hashes whose file is no longer in file_digests
+    pub orphaned_videohashes: usize,
+    /// Recorded video errors whose file is no longer in file_digests
+    pub orphaned_video_errors: usize,
+    /// Rows whose path is also stored in another row, e.g. with a different case
+    pub duplicate_paths: usize,
+    /// Rows without a digest
+    pub empty_digests: usize,
+    /// Video hashes computed at a different frame size than the majority
+    pub mixed_frame_sizes: usize,
+    /// Checked files that don't exist on disk anymore
+    pub missing_files: usize,
+    /// Number of files whose existence was checked
+    pub files_checked: usize,
+}
+
+impl ConsistencyReport {
+    pub fn has_problems(&self) -> bool {
+        self.orphaned_videohashes > 0
+            || self.orphaned_video_errors > 0
+            || self.duplicate_paths > 0
+            || self.empty_digests > 0
+            || self.mixed_frame_sizes > 0
+            || self.missing_files > 0
+    }
+}
+
+impl fmt::Display for ConsistencyReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} orphaned video hashes, {} orphaned video errors, {} duplicate paths, \
+             {} empty digests, {} video hashes with a different frame size, \
+             {} of {} checked files missing",
+            self.orphaned_videohashes,
+            self.orphaned_video_errors,
+            self.duplicate_paths,
+            self.empty_digests,
+            self.mixed_frame_sizes,
+            self.missing_files,
+            self.files_checked
+        )
+    }
+}
+
+impl Database {
+    fn count(&self, query: &str) -> Result<usize> {
+        let count: i64 = self.db.query_row(query, params![], |row| row.get(0))?;
+        Ok(count as usize)
+    }
+
+    /// Checks the DB for inconsistencies. Only files below `root` are checked for
+    /// existence, and only a random sample of them unless `thorough` is set.
+    pub fn check_consistency(
+        &self,
+        root: Option<&Path>,
+        path_case: PathCase,
+        thorough: bool,
+    ) -> Result<ConsistencyReport> {
+        let mut report = ConsistencyReport {
+            orphaned_videohashes: self.count(
+                "SELECT COUNT(*) FROM video_hash WHERE id NOT IN (SELECT id FROM file_digests)",
+            )?,
+            orphaned_video_errors: self.count(
+                "SELECT COUNT(*) FROM video_errors WHERE id NOT IN (SELECT id FROM file_digests)",
+            )?,
+            empty_digests: self.count(
+                "SELECT COUNT(*) FROM file_digests WHERE digest IS NULL OR length(digest) = 0",
+            )?,
+            mixed_frame_sizes: self.count(
+                "SELECT COALESCE(SUM(cnt) - MAX(cnt), 0) FROM \
+                 (SELECT COUNT(*) AS cnt FROM video_hash GROUP BY frame_size)",
+            )?,
+            ..Default::default()
+        };
+
+        let paths = self.get_all_paths()?;
+        let mut keys: HashMap<PathBuf, usize> = HashMap::new();
+        for path in paths.iter() {
+            *keys.entry(path_case.key(path)).or_insert(0) += 1;
+        }
+        report.duplicate_paths = keys.values().filter(|&&c| c > 1).sum();
+
+        let mut candidates: Vec<PathBuf> = paths
+            .into_iter()
+            .filter(|p| matches!(root, Some(r) if path_case.starts_with(p, r)))
+            .collect();
+        if !thorough && candidates.len() > EXISTENCE_SAMPLE_SIZE {
+            let step = candidates.len() / EXISTENCE_SAMPLE_SIZE;
+            candidates = candidates.into_iter().step_by(step).collect();
+            candidates.truncate(EXISTENCE_SAMPLE_SIZE);
+        }
+        report.files_checked = candidates.len();
+        report.missing_files = candidates
+            .par_iter()
+            .filter(|p| fs::metadata(p).is_err())
+            .count();
+        Ok(report)
+    }
+
+    /// Applies the repairs that can't lose information: removes video hashes and errors
+    /// of files that are no longer indexed. Returns the number of removed rows.
+    pub fn fix_orphans(&self) -> Result<usize> {
+        let tx = self.db.unchecked_transaction()?;
+        let mut num_removed = tx.execute(
+            "DELETE FROM video_hash WHERE id NOT IN (SELECT id FROM file_digests)",
+            params![],
+        )?;
+        num_removed += tx.execute(
+            "DELETE FROM video_errors WHERE id NOT IN (SELECT id FROM file_digests)",
+            params![],
+        )?;
+        tx.commit()?;
+        Ok(num_removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::FileDigest;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_consistent_database() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("a");
+        fs::write(&path, "a")?;
+        let db = Database::new("test_consistent_database.sqlite", true)?;
+        db.insert_filedigest(&FileDigest::new(0, &path.to_string_lossy(), vec![1; 8], 1))?;
+        let report = db.check_consistency(Some(dir.path()), PathCase::Sensitive, true)?;
+        assert!(!report.has_problems());
+        assert_eq!(report.files_checked, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_and_fix_inconsistencies() -> Result<()> {
+        let dir = tempdir()?;
+        let db = Database::new("test_check_and_fix_inconsistencies.sqlite", true)?;
+        db.db.execute(
+            "INSERT INTO file_digests (id, path, digest, size) VALUES \
+                (1, ?1, x'01010101', 1), \
+                (2, '/tmp/dupletti/Photo.jpg', x'02020202', 1), \
+                (3, '/tmp/dupletti/photo.JPG', x'02020202', 1), \
+                (4, '/tmp/dupletti/empty', x'', 1)",
+            params![dir.path().join("missing").to_string_lossy()],
+        )?;
+        db.db.execute(
+            "INSERT INTO video_hash (id, histogram, frame_size) VALUES \
+                (1, x'00', 128), (2, x'00', 128), (3, x'00', 64), (99, x'00', 128)",
+            params![],
+        )?;
+        db.db.execute(
+            "INSERT INTO video_errors (id, message) VALUES (98, 'gone')",
+            params![],
+        )?;
+
+        let report = db.check_consistency(Some(dir.path()), PathCase::Insensitive, false)?;
+        assert_eq!(
+            report,
+            ConsistencyReport {
+                orphaned_videohashes: 1,
+                orphaned_video_errors: 1,
+                duplicate_paths: 2,
+                empty_digests: 1,
+                mixed_frame_sizes: 1,
+                missing_files: 1,
+                files_checked: 1,
+            }
+        );
+        let report = db.check_consistency(None, PathCase::Sensitive, false)?;
+        assert_eq!(report.duplicate_paths, 0);
+        assert_eq!(report.files_checked, 0);
+
+        assert_eq!(db.fix_orphans()?, 2);
+        let report = db.check_consistency(None, PathCase::Sensitive, false)?;
+        assert_eq!(report.orphaned_videohashes, 0);
+        assert_eq!(report.orphaned_video_errors, 0);
+        assert_eq!(report.empty_digests, 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,298 @@
+use anyhow::{anyhow, Result};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::database::Database;
+
+/// What a process does with a database. Any number of processes can serve a database
+/// while another one scans it, but only one process may scan it at a time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LockRole {
+    Scanning,
+    Serving,
+}
+
+impl LockRole {
+    fn name(&self) -> &'static str {
+        match self {
+            LockRole::Scanning => "scanning",
+            LockRole::Serving => "serving",
+        }
+    }
+}
+
+/// The lock file of a role, e.g. "digests.sqlite.scanning.lock".
+fn lock_path(db_path: &Path, role: LockRole) -> PathBuf {
+    let mut name = db_path.as_os_str().to_owned();
+    name.push(format!(".{}.lock", role.name()));
+    PathBuf::from(name)
+}
+
+/// A file next to the lock file of a role that is locked while a process checks for and
+/// replaces a stale lock file. It is never removed, so every process locks the same file.
+fn guard_path(lock_path: &Path) -> PathBuf {
+    let mut name = lock_path.as_os_str().to_owned();
+    name.push(".guard");
+    PathBuf::from(name)
+}
+
+/// Creates the lock file with this process' id, or fails with `AlreadyExists`. The id is
+/// written to a temporary file that is then linked into place, so other processes never
+/// read a lock file without it.
+fn create_lock_file(path: &Path) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut file = tempfile::NamedTempFile::new_in(dir)?;
+    write!(file, "{}", std::process::id())?;
+    file.persist_noclobber(path).map_err(|e| e.error)?;
+    Ok(())
+}
+
+/// Whether a process with this id is still running. Without /proc, every process is
+/// assumed to be running, so a stale lock file has to be removed by hand.
+fn process_alive(pid: u32) -> bool {
+    let proc = Path::new("/proc");
+    !proc.is_dir() || proc.join(pid.to_string()).exists()
+}
+
+/// The id of the running process that holds the lock of `role`, if any.
+pub fn lock_holder(db_path: &Path, role: LockRole) -> Option<u32> {
+    let pid = fs::read_to_string(lock_path(db_path, role))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+    Some(pid).filter(|&pid| process_alive(pid))
+}
+
+/// A lock file next to the database that tells other processes what this one does with
+/// it. The file contains the process id and is removed when the lock is dropped, lock
+/// files of processes that are no longer running are ignored.
+pub struct DbLock {
+    path: PathBuf,
+    /// Only the process that created the file removes it again
+    owned: bool,
+}
+
+impl DbLock {
+    /// Fails if another process is scanning the database. Serving never fails, a
+    /// second server just doesn't take over the lock file of the first one.
+    pub fn acquire(db_path: &Path, role: LockRole) -> Result<DbLock> {
+        let path = lock_path(db_path, role);
+        // held until the lock file is ours or another process' lock is found, so that of
+        // two processes that both find a stale lock file only one replaces it
+        let guard = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(guard_path(&path))?;
+        guard.lock()?;
+        loop {
+            match create_lock_file(&path) {
+                Ok(()) => return Ok(DbLock { path, owned: true }),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(e.into()),
+            }
+            match lock_holder(db_path, role) {
+                Some(pid) if role == LockRole::Scanning => {
+                    return Err(anyhow!(
+                        "{} is already being scanned by process {}, only one scan can run at a \
+                         time. Remove {} if that process doesn't use it",
+                        db_path.to_string_lossy(),
+                        pid,
+                        path.to_string_lossy()
+                    ));
+                }
+                Some(pid) => {
+                    log::info!(
+                        "{} is also served by process {}",
+                        db_path.to_string_lossy(),
+                        pid
+                    );
+                    return Ok(DbLock { path, owned: false });
+                }
+                None => {
+                    log::warn!("Removing stale lock file {}", path.to_string_lossy());
+                    fs::remove_file(&path)?;
+                }
+            }
+        }
+    }
+}
+
+impl Drop for DbLock {
+    fn drop(&mut self) {
+        if self.owned {
+            if let Err(e) = fs::remove_file(&self.path) {
+                log::warn!("Unable to remove {}: {}", self.path.to_string_lossy(), e);
+            }
+        }
+    }
+}
+
+impl Database {
+    /// Changes whenever another connection, e.g. of another process, commits to the
+    /// database. Commits of this connection leave it unchanged.
+    pub fn data_version(&self) -> Result<i64> {
+        Ok(self
+            .db
+            .query_row("PRAGMA data_version", [], |row| row.get(0))?)
+    }
+}
+
+/// A state of the database. It changes with every commit, of this connection or any
+/// other, so what was computed from one state can be reused until then.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DbVersion {
+    data_version: i64,
+    total_changes: i64,
+}
+
+impl Database {
+    /// Rows changed through this connection since it was opened.
+    pub fn total_changes(&self) -> Result<i64> {
+        Ok(self
+            .db
+            .query_row("SELECT total_changes()", [], |row| row.get(0))?)
+    }
+
+    pub fn version(&self) -> Result<DbVersion> {
+        Ok(DbVersion {
+            data_version: self.data_version()?,
+            total_changes: self.total_changes()?,
+        })
+    }
+}
+
+/// Notices when the database was changed from the outside, so that what was computed
+/// from it can be thrown away.
+#[derive(Debug)]
+pub struct ChangeWatcher {
+    last_version: i64,
+}
+
+impl ChangeWatcher {
+    pub fn new(db: &Database) -> Result<ChangeWatcher> {
+        Ok(ChangeWatcher {
+            last_version: db.data_version()?,
+        })
+    }
+
+    /// Whether the database changed since the last call.
+    pub fn changed(&mut self, db: &Database) -> Result<bool> {
+        let version = db.data_version()?;
+        let changed = version != self.last_version;
+        self.last_version = version;
+        Ok(changed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::FileDigest;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_scanning_lock_is_exclusive() -> Result<()> {
+        let dir = tempdir()?;
+        let db_path = dir.path().join("digests.sqlite");
+        let scanning = DbLock::acquire(&db_path, LockRole::Scanning)?;
+        assert_eq!(
+            lock_holder(&db_path, LockRole::Scanning),
+            Some(std::process::id())
+        );
+        assert!(DbLock::acquire(&db_path, LockRole::Scanning).is_err());
+        // serving doesn't get in the way of scanning, and neither does a second server
+        let serving = DbLock::acquire(&db_path, LockRole::Serving)?;
+        let second = DbLock::acquire(&db_path, LockRole::Serving)?;
+        drop(second);
+        assert!(lock_holder(&db_path, LockRole::Serving).is_some());
+        drop(serving);
+        assert_eq!(lock_holder(&db_path, LockRole::Serving), None);
+        drop(scanning);
+        assert_eq!(lock_holder(&db_path, LockRole::Scanning), None);
+        DbLock::acquire(&db_path, LockRole::Scanning)?;
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_stale_lock_is_replaced() -> Result<()> {
+        let dir = tempdir()?;
+        let db_path = dir.path().join("digests.sqlite");
+        // larger than any pid linux hands out
+        fs::write(lock_path(&db_path, LockRole::Scanning), "4194305")?;
+        assert_eq!(lock_holder(&db_path, LockRole::Scanning), None);
+        let _lock = DbLock::acquire(&db_path, LockRole::Scanning)?;
+        assert_eq!(
+            lock_holder(&db_path, LockRole::Scanning),
+            Some(std::process::id())
+        );
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_concurrent_stale_lock_replacement() -> Result<()> {
+        let dir = tempdir()?;
+        let db_path = dir.path().join("digests.sqlite");
+        for _ in 0..20 {
+            fs::write(lock_path(&db_path, LockRole::Scanning), "4194305")?;
+            let locks: Vec<_> = (0..8)
+                .map(|_| {
+                    let db_path = db_path.clone();
+                    std::thread::spawn(move || DbLock::acquire(&db_path, LockRole::Scanning))
+                })
+                .collect::<Vec<_>>()
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect();
+            // every thread sees the stale file, but only one of them replaces it and the
+            // others find the new lock file complete
+            assert_eq!(locks.iter().filter(|lock| lock.is_ok()).count(), 1);
+            assert_eq!(
+                lock_holder(&db_path, LockRole::Scanning),
+                Some(std::process::id())
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_change_watcher() -> Result<()> {
+        let dir = tempdir()?;
+        let db_path = dir.path().join("digests.sqlite");
+        let served = Database::new(&db_path, false)?;
+        let scanner = Database::new(&db_path, false)?;
+        let mut watcher = ChangeWatcher::new(&served)?;
+        assert!(!watcher.changed(&served)?);
+        // the own connection's commits are not external changes
+        served.insert_filedigest(&FileDigest::new(0, "/tmp/a", vec![1], 1))?;
+        assert!(!watcher.changed(&served)?);
+        scanner.insert_filedigest(&FileDigest::new(0, "/tmp/b", vec![1], 1))?;
+        assert!(watcher.changed(&served)?);
+        assert!(!watcher.changed(&served)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_version() -> Result<()> {
+        let dir = tempdir()?;
+        let db_path = dir.path().join("digests.sqlite");
+        let served = Database::new(&db_path, false)?;
+        let scanner = Database::new(&db_path, false)?;
+        let version = served.version()?;
+        assert_eq!(served.version()?, version);
+        served.insert_filedigest(&FileDigest::new(0, "/tmp/a", vec![1], 1))?;
+        let version_after_own = served.version()?;
+        assert_ne!(version_after_own, version);
+        scanner.insert_filedigest(&FileDigest::new(0, "/tmp/b", vec![1], 1))?;
+        assert_ne!(served.version()?, version_after_own);
+        Ok(())
+    }
+}
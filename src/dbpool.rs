@@ -0,0 +1,158 @@
+use anyhow::{anyhow, Result};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::database::{Database, IN_MEMORY_PATH};
+
+/// Hands out connections to one database file. There is a single writer, behind the
+/// same mutex the rest of Dupletti locks, and any number of read-only connections. In
+/// WAL mode readers see the last commit and neither wait for the writer nor block it.
+pub struct DatabasePool {
+    path: PathBuf,
+    writer: Arc<Mutex<Database>>,
+    /// Idle read-only connections, reused by later readers
+    readers: Mutex<Vec<Database>>,
+}
+
+/// A read-only connection that goes back to its pool when dropped.
+pub enum Reader<'a> {
+    Pooled {
+        pool: &'a DatabasePool,
+        db: Option<Database>,
+    },
+    /// In-memory databases can't be opened a second time, their readers share the writer
+    Writer(MutexGuard<'a, Database>),
+}
+
+impl DatabasePool {
+    /// Opens the writer like `Database::new`, readers are opened when they are needed.
+    pub fn open<P: AsRef<Path>>(path: P, reset: bool) -> Result<DatabasePool> {
+        let db = Database::new(path.as_ref(), reset)?;
+        Ok(DatabasePool::with_writer(path, db))
+    }
+
+    /// A pool around an already opened database, which becomes the writer.
+    pub fn with_writer<P: AsRef<Path>>(path: P, db: Database) -> DatabasePool {
+        DatabasePool {
+            path: path.as_ref().to_path_buf(),
+            writer: Arc::new(Mutex::new(db)),
+            readers: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Writes are serialized, this waits until no one else writes.
+    pub fn writer(&self) -> Result<MutexGuard<'_, Database>> {
+        self.writer.lock().map_err(|_| anyhow!("Unable to lock DB"))
+    }
+
+    pub fn reader(&self) -> Result<Reader<'_>> {
+        if self.path.as_os_str() == IN_MEMORY_PATH {
+            return Ok(Reader::Writer(self.writer()?));
+        }
+        let idle = match self.readers.lock() {
+            Ok(mut readers) => readers.pop(),
+            Err(_) => return Err(anyhow!("Unable to lock DB readers")),
+        };
+        let db = match idle {
+            Some(db) => db,
+            None => Database::open_reader(&self.path)?,
+        };
+        Ok(Reader::Pooled {
+            pool: self,
+            db: Some(db),
+        })
+    }
+
+    /// The writer behind its mutex, for code that locks the database itself.
+    pub fn db_mutex(&self) -> Arc<Mutex<Database>> {
+        Arc::clone(&self.writer)
+    }
+}
+
+impl<'a> Deref for Reader<'a> {
+    type Target = Database;
+
+    fn deref(&self) -> &Database {
+        match self {
+            Reader::Pooled { db, .. } => db.as_ref().expect("reader is only taken on drop"),
+            Reader::Writer(db) => db,
+        }
+    }
+}
+
+impl<'a> Drop for Reader<'a> {
+    fn drop(&mut self) {
+        if let Reader::Pooled { pool, db } = self {
+            if let (Some(db), Ok(mut readers)) = (db.take(), pool.readers.lock()) {
+                readers.push(db);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::FileDigest;
+    use std::thread;
+
+    #[test]
+    fn test_readers_during_bulk_insert() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let pool = DatabasePool::open(dir.path().join("digests.sqlite"), true)?;
+        let (batches, batch_size) = (20, 500);
+        thread::scope(|scope| -> Result<()> {
+            let writer = scope.spawn(|| -> Result<()> {
+                for batch in 0..batches {
+                    let db = pool.writer()?;
+                    let tx = db.db.unchecked_transaction()?;
+                    for i in 0..batch_size {
+                        let path = format!("/tmp/nonexistent/{}/{}", batch, i);
+                        db.insert_filedigest(&FileDigest::new(0, &path, vec![1; 8], 1))?;
+                    }
+                    tx.commit()?;
+                }
+                Ok(())
+            });
+            let readers: Vec<_> = (0..4)
+                .map(|_| {
+                    scope.spawn(|| -> Result<usize> {
+                        let mut reads = 0;
+                        loop {
+                            let db = pool.reader()?;
+                            // every read sees whole batches only
+                            let count = db.count_filedigests()?;
+                            assert_eq!(count % batch_size, 0);
+                            assert_eq!(db.get_all_filedigests()?.len() % batch_size, 0);
+                            reads += 1;
+                            if count == batches * batch_size {
+                                return Ok(reads);
+                            }
+                        }
+                    })
+                })
+                .collect();
+            writer.join().unwrap()?;
+            for reader in readers {
+                assert!(reader.join().unwrap()? > 0);
+            }
+            Ok(())
+        })?;
+        // readers were given back and are reused
+        assert!(!pool.readers.lock().unwrap().is_empty());
+        let readonly = FileDigest::new(0, "/tmp/nonexistent/x", vec![1; 8], 1);
+        assert!(pool.reader()?.insert_filedigest(&readonly).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_in_memory_readers_share_the_writer() -> Result<()> {
+        let pool = DatabasePool::with_writer(IN_MEMORY_PATH, Database::in_memory()?);
+        pool.writer()?
+            .insert_filedigest(&FileDigest::new(0, "/tmp/a", vec![1; 8], 1))?;
+        assert_eq!(pool.reader()?.count_filedigests()?, 1);
+        assert_eq!(pool.db_mutex().lock().unwrap().count_filedigests()?, 1);
+        Ok(())
+    }
+}
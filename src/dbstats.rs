@@ -0,0 +1,163 @@
+use anyhow::Result;
+use rusqlite::params;
+use serde::Serialize;
+use std::fmt;
+use std::path::MAIN_SEPARATOR;
+
+use crate::database::Database;
+use crate::similarities;
+
+/// Indexed files with the same extension.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ExtensionCount {
+    /// Lowercase, without the dot. None for files without an extension
+    pub extension: Option<String>,
+    pub files: usize,
+    pub bytes: u64,
+}
+
+/// A summary of what is in the database, see `dupletti stats`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DatabaseStats {
+    pub files: usize,
+    pub bytes: u64,
+    pub distinct_digests: usize,
+    pub duplicate_groups: usize,
+    /// Bytes taken by all copies but one of each group
+    pub wasted_bytes: u64,
+    pub video_hashes: usize,
+    /// The most common extensions first
+    pub extensions: Vec<ExtensionCount>,
+}
+
+impl DatabaseStats {
+    pub fn of(db: &Database) -> Result<DatabaseStats> {
+        let (files, bytes, distinct_digests) = db.file_totals()?;
+        let (group_ids, wasted_bytes) = similarities::duplicate_totals(db)?;
+        Ok(DatabaseStats {
+            files,
+            bytes,
+            distinct_digests,
+            duplicate_groups: group_ids.len(),
+            wasted_bytes,
+            video_hashes: db.count_videohashes()?,
+            extensions: db.count_extensions()?,
+        })
+    }
+}
+
+impl fmt::Display for DatabaseStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Files:            {}", self.files)?;
+        writeln!(f, "Bytes:            {}", self.bytes)?;
+        writeln!(f, "Distinct digests: {}", self.distinct_digests)?;
+        writeln!(f, "Duplicate groups: {}", self.duplicate_groups)?;
+        writeln!(f, "Wasted bytes:     {}", self.wasted_bytes)?;
+        writeln!(f, "Video hashes:     {}", self.video_hashes)?;
+        if !self.extensions.is_empty() {
+            writeln!(f, "Extensions:")?;
+        }
+        for count in &self.extensions {
+            let extension = count.extension.as_deref().unwrap_or("(none)");
+            writeln!(
+                f,
+                "  {}: {} files, {} bytes",
+                extension, count.files, count.bytes
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Database {
+    /// Number of indexed files, their total size and the number of distinct digests.
+    pub fn file_totals(&self) -> Result<(usize, u64, usize)> {
+        let (files, bytes, digests): (i64, i64, i64) = self.db.query_row(
+            "SELECT COUNT(*), COALESCE(SUM(size), 0), COUNT(DISTINCT digest) FROM file_digests",
+            params![],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )?;
+        Ok((files as usize, bytes as u64, digests as usize))
+    }
+
+    /// Indexed files per extension, the most common first. Like `Path::extension`, the
+    /// extension is what follows the last dot of the file name, and names that only start
+    /// with a dot have none. Extensions that differ in case are counted together.
+    pub fn count_extensions(&self) -> Result<Vec<ExtensionCount>> {
+        // rtrim(x, replace(x, c, '')) cuts x after its last c, which leaves what follows
+        let mut stmt = self.db.prepare(
+            "WITH names AS ( \
+             SELECT replace(path, rtrim(path, replace(path, ?1, '')), '') AS name, size \
+             FROM file_digests) \
+             SELECT CASE WHEN instr(ltrim(name, '.'), '.') > 0 \
+             THEN lower(replace(name, rtrim(name, replace(name, '.', '')), '')) END AS extension, \
+             COUNT(*), SUM(size) \
+             FROM names GROUP BY extension ORDER BY COUNT(*) DESC, extension",
+        )?;
+        let counts: Result<Vec<ExtensionCount>, _> = stmt
+            .query_map(params![MAIN_SEPARATOR.to_string()], |row| {
+                let files: i64 = row.get(1)?;
+                let bytes: i64 = row.get(2)?;
+                Ok(ExtensionCount {
+                    extension: row.get(0)?,
+                    files: files as usize,
+                    bytes: bytes as u64,
+                })
+            })?
+            .collect();
+        Ok(counts?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::FileDigest;
+
+    #[test]
+    fn test_database_stats() -> Result<()> {
+        let db = Database::new("test_database_stats.sqlite", true)?;
+        let files: [(&str, u8, u64); 6] = [
+            ("/tmp/nonexistent/a.jpg", 1, 100),
+            ("/tmp/nonexistent/b.JPG", 1, 100),
+            ("/tmp/nonexistent/c.jpg", 1, 100),
+            ("/tmp/nonexistent/d.tar.gz", 2, 50),
+            ("/tmp/nonexistent/x.y/README", 3, 7),
+            ("/tmp/nonexistent/.bashrc", 4, 3),
+        ];
+        for (path, digest, size) in files.iter() {
+            db.insert_filedigest(&FileDigest::new(0, path, vec![*digest; 8], *size))?;
+        }
+        // a hashed video and a skipped one
+        let ids: Vec<i64> = db.get_all_filedigests()?.iter().map(|f| f.id).collect();
+        db.db.execute(
+            "INSERT INTO video_hash (id, histogram, frame_size) VALUES (?1, ?2, 32), (?3, NULL, 32)",
+            params![ids[3], vec![0u8; 64], ids[4]],
+        )?;
+
+        let stats = DatabaseStats::of(&db)?;
+        assert_eq!(
+            (stats.files, stats.bytes, stats.distinct_digests),
+            (6, 360, 4)
+        );
+        assert_eq!((stats.duplicate_groups, stats.wasted_bytes), (1, 200));
+        assert_eq!(stats.video_hashes, 1);
+        let extensions: Vec<(Option<&str>, usize, u64)> = stats
+            .extensions
+            .iter()
+            .map(|e| (e.extension.as_deref(), e.files, e.bytes))
+            .collect();
+        assert_eq!(
+            extensions,
+            [(Some("jpg"), 3, 300), (None, 2, 10), (Some("gz"), 1, 50)]
+        );
+        assert!(stats.to_string().contains("Wasted bytes:     200\n"));
+        let json = serde_json::to_value(&stats)?;
+        assert_eq!(json["extensions"][1]["extension"], serde_json::Value::Null);
+
+        let empty = DatabaseStats::of(&Database::in_memory()?)?;
+        assert_eq!((empty.files, empty.bytes), (0, 0));
+        assert!(empty.extensions.is_empty());
+        Ok(())
+    }
+}
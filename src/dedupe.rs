@@ -0,0 +1,560 @@
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Instant;
+
+use crate::database::Database;
+use crate::filemeta;
+use crate::similarities::{FileEntry, FileGroup, KeepPolicy};
+use crate::tombstones;
+use crate::verify::{self, Verdict, VERIFY_TIMEOUT};
+
+/// How the symlinks that replace duplicates point at the kept file.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkStyle {
+    /// Relative to the directory of the link, so moving a directory tree that contains
+    /// both keeps the link intact
+    Relative,
+    Absolute,
+}
+
+/// What --dedupe does with the duplicates of each group.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DedupeMode {
+    /// Replace each duplicate with a symlink to the kept file
+    Symlink(LinkStyle),
+    /// Replace each duplicate with a hardlink to the kept file, which keeps it a regular
+    /// file for tools that don't follow symlinks. Only works on the same file system
+    Hardlink,
+}
+
+impl FromStr for DedupeMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<DedupeMode> {
+        match s {
+            "symlink" | "symlink:relative" => Ok(DedupeMode::Symlink(LinkStyle::Relative)),
+            "symlink:absolute" => Ok(DedupeMode::Symlink(LinkStyle::Absolute)),
+            "hardlink" => Ok(DedupeMode::Hardlink),
+            _ => Err(anyhow!(
+                "Unknown dedupe mode {}, use symlink, symlink:relative, symlink:absolute or hardlink",
+                s
+            )),
+        }
+    }
+}
+
+/// What a dedupe run did.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct DedupeSummary {
+    /// Duplicates that were replaced
+    pub linked: usize,
+    /// Bytes freed by replacing them
+    pub reclaimed: u64,
+    /// Duplicates that were left alone, because they are protected, missing, already
+    /// links, or not identical to the kept file after all
+    pub skipped: usize,
+}
+
+impl fmt::Display for DedupeSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Replaced {} duplicates with links, freeing {} bytes",
+            self.linked, self.reclaimed
+        )?;
+        write!(f, "Skipped {} duplicates", self.skipped)
+    }
+}
+
+/// The path of `to` relative to the directory `from_dir`. Both must be absolute. Falls
+/// back to `to` itself if they have no common root, e.g. on different Windows drives.
+pub fn relative_path(from_dir: &Path, to: &Path) -> PathBuf {
+    let from: Vec<_> = from_dir.components().collect();
+    let to_components: Vec<_> = to.components().collect();
+    let common = from
+        .iter()
+        .zip(to_components.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+    if common == 0 {
+        return to.to_path_buf();
+    }
+    let mut relative = PathBuf::new();
+    for _ in common..from.len() {
+        relative.push("..");
+    }
+    for component in &to_components[common..] {
+        relative.push(component.as_os_str());
+    }
+    relative
+}
+
+/// Fails if this process can't create symlinks. On Windows that needs Developer Mode or
+/// administrator rights, so it is tried once before anything is changed.
+pub fn check_symlink_support() -> Result<()> {
+    #[cfg(windows)]
+    {
+        let probe =
+            std::env::temp_dir().join(format!("dupletti-symlink-probe-{}", std::process::id()));
+        let created = std::os::windows::fs::symlink_file("dupletti-probe-target", &probe);
+        let _ = fs::remove_file(&probe);
+        created.map_err(|e| {
+            anyhow!(
+                "Unable to create symlinks, this needs Developer Mode or administrator rights: {}",
+                e
+            )
+        })?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn create_symlink(target: &Path, link: &Path) -> Result<()> {
+    Ok(std::os::unix::fs::symlink(target, link)?)
+}
+
+#[cfg(windows)]
+fn create_symlink(target: &Path, link: &Path) -> Result<()> {
+    Ok(std::os::windows::fs::symlink_file(target, link)?)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn create_symlink(_target: &Path, _link: &Path) -> Result<()> {
+    Err(anyhow!("Symlinks are not supported on this platform"))
+}
+
+fn is_symlink(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_symlink())
+}
+
+/// Where the link that replaces `file` is created before it is renamed over it.
+fn link_temp_path(file: &Path) -> Result<PathBuf> {
+    let name = file
+        .file_name()
+        .ok_or_else(|| anyhow!("{} has no file name", file.to_string_lossy()))?;
+    let mut temp_name = OsString::from(".");
+    temp_name.push(name);
+    temp_name.push(".dupletti-link");
+    Ok(file.with_file_name(temp_name))
+}
+
+/// Renames the link at `temp` over `file`, or removes it if that fails.
+fn rename_link_over(temp: &Path, file: &Path) -> Result<()> {
+    if let Err(e) = fs::rename(temp, file) {
+        let _ = fs::remove_file(temp);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+/// Replaces `file` with a symlink to `target`. The link is created next to the file
+/// first and then renamed over it, so `file` is never missing, even if Dupletti is
+/// interrupted.
+pub fn replace_with_symlink(file: &Path, target: &Path) -> Result<()> {
+    let temp = link_temp_path(file)?;
+    if is_symlink(&temp) {
+        // left over from an interrupted run
+        fs::remove_file(&temp)?;
+    } else if temp.exists() {
+        return Err(anyhow!("{} is in the way", temp.to_string_lossy()));
+    }
+    create_symlink(target, &temp)?;
+    rename_link_over(&temp, file)
+}
+
+/// Whether both paths are known to lead to the same file.
+fn same_file(a: &Path, b: &Path) -> bool {
+    let identity = |path| {
+        let meta = filemeta::read_file_meta(path).ok()?;
+        Some((meta.device?, meta.inode?))
+    };
+    identity(a).is_some_and(|a| Some(a) == identity(b))
+}
+
+/// Replaces `file` with a hardlink to `target`, the same way `replace_with_symlink`
+/// does.
+pub fn replace_with_hardlink(file: &Path, target: &Path) -> Result<()> {
+    let temp = link_temp_path(file)?;
+    if same_file(&temp, target) {
+        // left over from an interrupted run
+        fs::remove_file(&temp)?;
+    } else if fs::symlink_metadata(&temp).is_ok() {
+        return Err(anyhow!("{} is in the way", temp.to_string_lossy()));
+    }
+    fs::hard_link(target, &temp)?;
+    rename_link_over(&temp, file)
+}
+
+/// Why a duplicate can't be replaced with a hardlink to `keeper`, besides the reasons of
+/// `refuse_reason`.
+fn refuse_hardlink_reason(keeper: &Path, duplicate: &FileEntry) -> Option<String> {
+    match fs::metadata(&duplicate.path) {
+        Ok(meta) if meta.len() != duplicate.size => {
+            return Some(format!(
+                "it changed since it was indexed, it has {} bytes instead of {}",
+                meta.len(),
+                duplicate.size
+            ))
+        }
+        Ok(_) => {}
+        Err(e) => return Some(e.to_string()),
+    }
+    if same_file(keeper, &duplicate.path) {
+        return Some("it is a hardlink of the kept file already".into());
+    }
+    let device = |path| filemeta::read_file_meta(path).ok()?.device;
+    match (device(keeper), device(&duplicate.path)) {
+        (Some(a), Some(b)) if a != b => Some("it is on another device than the kept file".into()),
+        _ => None,
+    }
+}
+
+/// Why a duplicate can't be replaced or deleted, None if it can.
+pub(crate) fn refuse_reason(keeper: &Path, duplicate: &FileEntry) -> Option<String> {
+    if duplicate.protected {
+        return Some("it is protected".into());
+    }
+    if duplicate.missing {
+        return Some("it doesn't exist".into());
+    }
+    if is_symlink(&duplicate.path) {
+        return Some("it is a link already".into());
+    }
+    // a bind mount or a hardlink reaches the kept file under a path of its own
+    if fs::canonicalize(&duplicate.path).ok().as_deref() == Some(keeper)
+        || same_file(keeper, &duplicate.path)
+    {
+        return Some("it is the kept file".into());
+    }
+    match verify::compare_files(keeper, &duplicate.path, Instant::now() + VERIFY_TIMEOUT) {
+        Ok(Verdict::Identical) => None,
+        Ok(verdict) => Some(format!("its content differs: {:?}", verdict)),
+        Err(e) => Some(e.to_string()),
+    }
+}
+
+/// Replaces every duplicate of `groups` that is byte by byte identical to the file kept
+/// by `keep_policy` with a link to it. Files replaced with symlinks are removed from the
+/// database and remembered as tombstones. Hardlinked files stay, and the file metadata
+/// of both is read again, so their group is known to take no extra space. Every
+/// replacement is appended to `replacement_log` with the time, the group and the link
+/// target, in the format of the --auto-delete log.
+pub fn dedupe_groups(
+    db: &Database,
+    groups: &[FileGroup],
+    keep_policy: KeepPolicy,
+    mode: DedupeMode,
+    mut replacement_log: Option<&mut dyn Write>,
+) -> Result<DedupeSummary> {
+    if let DedupeMode::Symlink(_) = mode {
+        check_symlink_support()?;
+    }
+    let mut summary = DedupeSummary::default();
+    for group in groups.iter().filter(|g| g.files.len() > 1) {
+        let keeper_index = keep_policy.keeper(&group.files);
+        let keeper = match fs::canonicalize(&group.files[keeper_index].path) {
+            Ok(keeper) => keeper,
+            Err(e) => {
+                log::warn!(
+                    "Not deduplicating group {}, its kept file can't be read: {}",
+                    group.group_id,
+                    e
+                );
+                summary.skipped += group.files.len() - 1;
+                continue;
+            }
+        };
+        for (i, duplicate) in group.files.iter().enumerate() {
+            if i == keeper_index {
+                continue;
+            }
+            let refusal = match mode {
+                DedupeMode::Hardlink => refuse_hardlink_reason(&keeper, duplicate),
+                DedupeMode::Symlink(_) => None,
+            };
+            if let Some(reason) = refusal.or_else(|| refuse_reason(&keeper, duplicate)) {
+                log::warn!(
+                    "Not replacing {}, {}",
+                    duplicate.path.to_string_lossy(),
+                    reason
+                );
+                summary.skipped += 1;
+                continue;
+            }
+            let target = match mode {
+                DedupeMode::Symlink(LinkStyle::Relative) => {
+                    let dir = duplicate.path.parent().unwrap_or_else(|| Path::new("."));
+                    relative_path(&fs::canonicalize(dir)?, &keeper)
+                }
+                _ => keeper.clone(),
+            };
+            let file = db.lookup_filedigest(duplicate.id)?;
+            let replaced = match mode {
+                DedupeMode::Symlink(_) => replace_with_symlink(&duplicate.path, &target),
+                DedupeMode::Hardlink => replace_with_hardlink(&duplicate.path, &target),
+            };
+            if let Err(e) = replaced {
+                log::warn!(
+                    "Unable to replace {}: {}",
+                    duplicate.path.to_string_lossy(),
+                    e
+                );
+                summary.skipped += 1;
+                continue;
+            }
+            log::info!(
+                "Replaced {} with a link to {}",
+                duplicate.path.to_string_lossy(),
+                target.to_string_lossy()
+            );
+            if let Some(replacement_log) = replacement_log.as_mut() {
+                let action = match mode {
+                    DedupeMode::Symlink(_) => "symlink",
+                    DedupeMode::Hardlink => "hardlink",
+                };
+                writeln!(
+                    replacement_log,
+                    "{}\t{}\t{}\t{}\t{}",
+                    tombstones::now()?,
+                    action,
+                    group.group_id,
+                    duplicate.path.to_string_lossy(),
+                    target.to_string_lossy()
+                )?;
+            }
+            if mode == DedupeMode::Hardlink {
+                for (id, path) in [
+                    (duplicate.id, &duplicate.path),
+                    (group.files[keeper_index].id, &keeper),
+                ] {
+                    db.set_file_meta(id, &filemeta::read_file_meta(path)?)?;
+                }
+            } else {
+                db.add_tombstone(&file)?;
+                db.delete_filedigest(duplicate.id)?;
+            }
+            summary.linked += 1;
+            summary.reclaimed += duplicate.size;
+        }
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filehashing::create_filedigest;
+    use crate::similarities;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_parse_dedupe_mode() {
+        assert_eq!(
+            "symlink".parse::<DedupeMode>().unwrap(),
+            DedupeMode::Symlink(LinkStyle::Relative)
+        );
+        assert_eq!(
+            "symlink:absolute".parse::<DedupeMode>().unwrap(),
+            DedupeMode::Symlink(LinkStyle::Absolute)
+        );
+        assert_eq!(
+            "hardlink".parse::<DedupeMode>().unwrap(),
+            DedupeMode::Hardlink
+        );
+        assert!("reflink".parse::<DedupeMode>().is_err());
+    }
+
+    #[test]
+    fn test_relative_path() {
+        let rel = |from: &str, to: &str| relative_path(Path::new(from), Path::new(to));
+        assert_eq!(rel("/a/b", "/a/b/c.jpg"), Path::new("c.jpg"));
+        assert_eq!(rel("/a/b", "/a/c/d.jpg"), Path::new("../c/d.jpg"));
+        assert_eq!(rel("/a/b/c", "/d.jpg"), Path::new("../../../d.jpg"));
+        assert_eq!(rel("/", "/a/b.jpg"), Path::new("a/b.jpg"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_replace_with_symlink() -> Result<()> {
+        let dir = tempdir()?;
+        let keeper = dir.path().join("keeper.jpg");
+        let copy = dir.path().join("sub").join("copy.jpg");
+        fs::create_dir(dir.path().join("sub"))?;
+        fs::write(&keeper, "content")?;
+        fs::write(&copy, "content")?;
+        // a link left over from an interrupted run is replaced as well
+        create_symlink(
+            Path::new("elsewhere"),
+            &dir.path().join("sub/.copy.jpg.dupletti-link"),
+        )?;
+
+        replace_with_symlink(&copy, Path::new("../keeper.jpg"))?;
+        assert!(is_symlink(&copy));
+        assert_eq!(fs::read_link(&copy)?, Path::new("../keeper.jpg"));
+        assert_eq!(fs::read_to_string(&copy)?, "content");
+        let names: Vec<_> = fs::read_dir(dir.path().join("sub"))?
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(names, ["copy.jpg"]);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_dedupe_groups() -> Result<()> {
+        let dir = tempdir()?;
+        let db = Database::new("test_dedupe_groups.sqlite", true)?;
+        fs::create_dir(dir.path().join("sub"))?;
+        let paths: Vec<PathBuf> = ["big.jpg", "sub/a.jpg", "sub/b.jpg"]
+            .iter()
+            .map(|n| dir.path().join(n))
+            .collect();
+        for path in paths.iter() {
+            fs::write(path, "the same content")?;
+            db.insert_filedigest(&create_filedigest(path)?)?;
+        }
+        // changed after indexing
+        fs::write(&paths[2], "the same CONTENT")?;
+
+        let groups = similarities::get_list_of_similar_files(&db)?;
+        let mut replacement_log = Vec::new();
+        let summary = dedupe_groups(
+            &db,
+            &groups,
+            KeepPolicy::First,
+            DedupeMode::Symlink(LinkStyle::Relative),
+            Some(&mut replacement_log),
+        )?;
+        assert_eq!(groups[0].files[0].path, paths[0]);
+        assert_eq!(
+            summary,
+            DedupeSummary {
+                linked: 1,
+                reclaimed: 16,
+                skipped: 1,
+            }
+        );
+        assert_eq!(fs::read_link(&paths[1])?, Path::new("../big.jpg"));
+        assert_eq!(fs::read_to_string(&paths[1])?, "the same content");
+        assert!(!is_symlink(&paths[2]));
+        assert_eq!(db.get_all_filedigests()?.len(), 2);
+        assert_eq!(db.get_tombstoned_paths()?, [paths[1].clone()]);
+        let replacement_log = String::from_utf8(replacement_log)?;
+        let fields: Vec<_> = replacement_log.trim_end().split('\t').collect();
+        assert_eq!(
+            fields[1..],
+            [
+                "symlink",
+                groups[0].group_id.as_str(),
+                &paths[1].to_string_lossy(),
+                "../big.jpg"
+            ]
+        );
+
+        // links are not replaced again
+        let groups = similarities::get_list_of_similar_files(&db)?;
+        let summary = dedupe_groups(
+            &db,
+            &groups,
+            KeepPolicy::First,
+            DedupeMode::Symlink(LinkStyle::Absolute),
+            None,
+        )?;
+        assert_eq!(summary.linked, 0);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_dedupe_groups_refuses_the_kept_inode() -> Result<()> {
+        let dir = tempdir()?;
+        let db = Database::new("test_dedupe_groups_refuses_the_kept_inode.sqlite", true)?;
+        let keeper = dir.path().join("a.jpg");
+        let other_name = dir.path().join("b.jpg");
+        fs::write(&keeper, "the same content")?;
+        // like a bind mount of the kept file's directory, the path doesn't canonicalize
+        // to the kept file but reaches its inode
+        fs::hard_link(&keeper, &other_name)?;
+        for path in [&keeper, &other_name] {
+            db.insert_filedigest(&create_filedigest(path)?)?;
+        }
+
+        let groups = similarities::get_list_of_similar_files(&db)?;
+        let summary = dedupe_groups(
+            &db,
+            &groups,
+            KeepPolicy::First,
+            DedupeMode::Symlink(LinkStyle::Relative),
+            None,
+        )?;
+        assert_eq!((summary.linked, summary.skipped), (0, 1));
+        assert!(!is_symlink(&other_name));
+        assert_eq!(fs::read_to_string(&keeper)?, "the same content");
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_dedupe_groups_with_hardlinks() -> Result<()> {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = tempdir()?;
+        let db = Database::new("test_dedupe_groups_with_hardlinks.sqlite", true)?;
+        let paths: Vec<PathBuf> = ["a.jpg", "b.jpg", "c.jpg", "d.jpg"]
+            .iter()
+            .map(|n| dir.path().join(n))
+            .collect();
+        for path in paths.iter() {
+            fs::write(path, "the same content")?;
+            db.insert_filedigest(&create_filedigest(path)?)?;
+        }
+        // changed after indexing
+        fs::write(&paths[3], "the same content, and more")?;
+        let (_, wasted) = similarities::duplicate_totals(&db)?;
+        assert_eq!(wasted, 3 * 16);
+
+        let groups = similarities::get_list_of_similar_files(&db)?;
+        let summary = dedupe_groups(&db, &groups, KeepPolicy::First, DedupeMode::Hardlink, None)?;
+        assert_eq!(
+            summary,
+            DedupeSummary {
+                linked: 2,
+                reclaimed: 32,
+                skipped: 1,
+            }
+        );
+        let inode = |path: &PathBuf| fs::metadata(path).unwrap().ino();
+        assert_eq!(inode(&paths[1]), inode(&paths[0]));
+        assert_eq!(inode(&paths[2]), inode(&paths[0]));
+        assert_ne!(inode(&paths[3]), inode(&paths[0]));
+        assert_eq!(fs::read_to_string(&paths[1])?, "the same content");
+        assert_eq!(fs::metadata(&paths[0])?.nlink(), 3);
+        let names: Vec<_> = fs::read_dir(dir.path())?
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(names.len(), 4);
+
+        // the files stay indexed, but only the changed one counts as wasted space
+        assert_eq!(db.get_all_filedigests()?.len(), 4);
+        assert!(db.get_tombstoned_paths()?.is_empty());
+        let (_, wasted) = similarities::duplicate_totals(&db)?;
+        assert_eq!(wasted, 16);
+        let groups = similarities::get_list_of_similar_files(&db)?;
+        let summary = dedupe_groups(&db, &groups, KeepPolicy::First, DedupeMode::Hardlink, None)?;
+        assert_eq!((summary.linked, summary.skipped), (0, 3));
+
+        fs::remove_file(&paths[3])?;
+        db.delete_filedigest(db.get_all_filedigests()?[3].id)?;
+        let groups = similarities::get_list_of_similar_files(&db)?;
+        assert!(groups[0].hardlinked);
+        Ok(())
+    }
+}
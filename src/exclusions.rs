@@ -0,0 +1,129 @@
+use anyhow::{anyhow, Result};
+use rusqlite::params;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::path::Path;
+
+use crate::database::Database;
+use crate::filehashing;
+use crate::tombstones::now;
+
+/// A digest whose files are never reported as duplicates, e.g. OS installers or stock
+/// assets that are duplicated everywhere by design.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ExcludedDigest {
+    /// hex encoded
+    pub digest: String,
+    /// The file the digest was taken from, if any
+    pub source: Option<String>,
+    /// unix seconds
+    pub added_at: i64,
+}
+
+/// Returns the digest of `arg` and the path it was read from. `arg` is hashed if it is
+/// an existing file, otherwise it has to be a hex encoded digest.
+pub fn parse_digest_or_path(arg: &str) -> Result<(Vec<u8>, Option<String>)> {
+    let path = Path::new(arg);
+    if path.is_file() {
+        let file = filehashing::create_filedigest(path)?;
+        return Ok((file.digest, Some(file.path.to_string_lossy().into_owned())));
+    }
+    match hex::decode(arg) {
+        Ok(digest) if digest.len() >= 4 => Ok((digest, None)),
+        _ => Err(anyhow!(
+            "{} is neither a file nor a hex encoded digest",
+            arg
+        )),
+    }
+}
+
+impl Database {
+    /// Returns false if the digest was already excluded.
+    pub fn exclude_digest(&self, digest: &[u8], source: Option<&str>) -> Result<bool> {
+        let num_inserted = self.db.execute(
+            "INSERT OR IGNORE INTO excluded_digests (digest, source, added_at) VALUES (?1, ?2, ?3)",
+            params![digest, source, now()?],
+        )?;
+        Ok(num_inserted > 0)
+    }
+
+    /// Returns false if the digest wasn't excluded.
+    pub fn remove_excluded_digest(&self, digest: &[u8]) -> Result<bool> {
+        let num_deleted = self.db.execute(
+            "DELETE FROM excluded_digests WHERE digest = ?1",
+            params![digest],
+        )?;
+        Ok(num_deleted > 0)
+    }
+
+    pub fn get_excluded_digests(&self) -> Result<Vec<ExcludedDigest>> {
+        let mut stmt = self
+            .db
+            .prepare("SELECT digest, source, added_at FROM excluded_digests ORDER BY added_at")?;
+        let rows: Result<Vec<_>, _> = stmt
+            .query_map([], |row| {
+                Ok(ExcludedDigest {
+                    digest: hex::encode(row.get::<_, Vec<u8>>(0)?),
+                    source: row.get(1)?,
+                    added_at: row.get(2)?,
+                })
+            })?
+            .collect();
+        Ok(rows?)
+    }
+
+    pub fn get_excluded_digest_set(&self) -> Result<HashSet<Vec<u8>>> {
+        let mut stmt = self.db.prepare("SELECT digest FROM excluded_digests")?;
+        let digests: Result<HashSet<Vec<u8>>, _> = stmt.query_map([], |row| row.get(0))?.collect();
+        Ok(digests?)
+    }
+
+    /// Number of indexed files that are not reported because their digest is excluded.
+    pub fn count_excluded_files(&self) -> Result<usize> {
+        let count: i64 = self.db.query_row(
+            "SELECT COUNT(*) FROM file_digests WHERE digest IN (SELECT digest FROM excluded_digests)",
+            params![],
+            |row| row.get(0),
+        )?;
+        Ok(count as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_excluded_digests() -> Result<()> {
+        let db = Database::new("test_excluded_digests.sqlite", true)?;
+        assert!(db.exclude_digest(&[1, 2, 3, 4], None)?);
+        assert!(!db.exclude_digest(&[1, 2, 3, 4], Some("/elsewhere"))?);
+        let excluded = db.get_excluded_digests()?;
+        assert_eq!(excluded.len(), 1);
+        assert_eq!(excluded[0].digest, "01020304");
+        assert_eq!(excluded[0].source, None);
+        assert!(db.remove_excluded_digest(&[1, 2, 3, 4])?);
+        assert!(!db.remove_excluded_digest(&[1, 2, 3, 4])?);
+        assert!(db.get_excluded_digest_set()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_digest_or_path() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("installer.iso");
+        fs::write(&path, "installer")?;
+        let (digest, source) = parse_digest_or_path(path.to_str().unwrap())?;
+        assert_eq!(digest, filehashing::create_filedigest(&path)?.digest);
+        assert_eq!(source.as_deref(), path.to_str());
+        assert_eq!(
+            parse_digest_or_path("0a0b0c0d")?,
+            (vec![10, 11, 12, 13], None)
+        );
+        assert!(parse_digest_or_path("not hex").is_err());
+        assert!(parse_digest_or_path("0a").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,202 @@
+use serde::Serialize;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+
+use crate::paths;
+use crate::rawpreview;
+use crate::tiff::Tiff;
+
+/// Image formats that carry EXIF metadata, in addition to camera RAW files.
+const EXIF_EXTENSIONS: [&str; 4] = ["jpg", "jpeg", "tif", "tiff"];
+
+const TAG_MAKE: u16 = 0x010f;
+const TAG_MODEL: u16 = 0x0110;
+const TAG_EXIF_IFD: u16 = 0x8769;
+const TAG_DATE_TIME_ORIGINAL: u16 = 0x9003;
+
+/// Only the start of a file is read, EXIF data is stored near the beginning.
+const MAX_EXIF_READ: u64 = 1024 * 1024;
+
+/// Capture metadata of an image, all fields are None if the file has no (readable) EXIF.
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct ExifInfo {
+    /// DateTimeOriginal as "YYYY-MM-DD HH:MM:SS"
+    pub capture_date: Option<String>,
+    pub camera_make: Option<String>,
+    pub camera_model: Option<String>,
+}
+
+impl ExifInfo {
+    pub fn is_present(&self) -> bool {
+        self.capture_date.is_some() || self.camera_make.is_some() || self.camera_model.is_some()
+    }
+}
+
+pub fn has_exif_extension(path: &Path) -> bool {
+    paths::has_extension(path, &EXIF_EXTENSIONS) || rawpreview::is_raw_file(path)
+}
+
+/// Reads the EXIF metadata of an image file. Files without EXIF, or where it can't be
+/// read, get an empty ExifInfo instead of an error.
+pub fn read_exif(path: &Path) -> ExifInfo {
+    if !has_exif_extension(path) {
+        return ExifInfo::default();
+    }
+    let mut data = vec![];
+    let read = fs::File::open(path).and_then(|f| f.take(MAX_EXIF_READ).read_to_end(&mut data));
+    if let Err(e) = read {
+        log::debug!("Unable to read EXIF of {}: {}", path.display(), e);
+        return ExifInfo::default();
+    }
+    parse_exif(&data).unwrap_or_default()
+}
+
+/// Parses EXIF from the start of a JPEG or TIFF based file.
+fn parse_exif(data: &[u8]) -> Option<ExifInfo> {
+    let tiff_data = if data.starts_with(&[0xff, 0xd8]) {
+        find_jpeg_exif(data)?
+    } else {
+        data
+    };
+    let tiff = Tiff::parse(tiff_data)?;
+    let ifd0 = tiff.first_ifd()? as usize;
+    let ascii = |ifd: usize, tag: u16| tiff.find_entry(ifd, tag).and_then(|e| tiff.ascii(e));
+    let capture_date = tiff
+        .find_entry(ifd0, TAG_EXIF_IFD)
+        .and_then(|e| tiff.values(e))
+        .and_then(|v| v.first().cloned())
+        .and_then(|exif_ifd| ascii(exif_ifd as usize, TAG_DATE_TIME_ORIGINAL))
+        .and_then(|d| normalize_date(&d));
+    Some(ExifInfo {
+        capture_date,
+        camera_make: ascii(ifd0, TAG_MAKE),
+        camera_model: ascii(ifd0, TAG_MODEL),
+    })
+}
+
+/// The TIFF block of the APP1 "Exif" segment of a JPEG file.
+fn find_jpeg_exif(data: &[u8]) -> Option<&[u8]> {
+    let mut pos = 2;
+    loop {
+        let marker = data.get(pos..pos + 2)?;
+        // start of scan: image data follows, there are no more metadata segments
+        if marker[0] != 0xff || marker[1] == 0xda {
+            return None;
+        }
+        let length = u16::from_be_bytes([*data.get(pos + 2)?, *data.get(pos + 3)?]) as usize;
+        let segment = data.get(pos + 4..pos + 2 + length)?;
+        if marker[1] == 0xe1 && segment.starts_with(b"Exif\0\0") {
+            return Some(&segment[6..]);
+        }
+        pos += 2 + length;
+    }
+}
+
+/// Turns EXIF's "YYYY:MM:DD HH:MM:SS" into "YYYY-MM-DD HH:MM:SS". Cameras without a set
+/// clock write zeros or blanks, those are treated as missing.
+fn normalize_date(date: &str) -> Option<String> {
+    let bytes = date.as_bytes();
+    let well_formed = bytes.len() == 19
+        && bytes.iter().enumerate().all(|(i, b)| match i {
+            4 | 7 => *b == b':',
+            10 => *b == b' ',
+            13 | 16 => *b == b':',
+            _ => b.is_ascii_digit(),
+        });
+    if !well_formed || date.starts_with("0000") {
+        return None;
+    }
+    Some(format!("{}-{}-{}", &date[0..4], &date[5..7], &date[8..]))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    /// A JPEG with an EXIF block that records a capture date, camera make and model.
+    pub fn jpeg_with_exif() -> Vec<u8> {
+        let make = b"Dupletti\0";
+        let model = b"Test Camera 1\0";
+        let date = b"2021:06:13 14:15:16\0";
+        // IFD0: 3 entries at offset 8, Exif IFD: 1 entry, then the strings
+        let ifd0 = 8u32;
+        let exif_ifd = ifd0 + 2 + 3 * 12 + 4;
+        let make_offset = exif_ifd + 2 + 12 + 4;
+        let model_offset = make_offset + make.len() as u32;
+        let date_offset = model_offset + model.len() as u32;
+
+        let mut tiff = b"II*\0".to_vec();
+        tiff.extend(&ifd0.to_le_bytes());
+        let entry = |tag: u16, field_type: u16, count: u32, value: u32| {
+            let mut e = tag.to_le_bytes().to_vec();
+            e.extend(&field_type.to_le_bytes());
+            e.extend(&count.to_le_bytes());
+            e.extend(&value.to_le_bytes());
+            e
+        };
+        tiff.extend(&3u16.to_le_bytes());
+        tiff.extend(entry(TAG_MAKE, 2, make.len() as u32, make_offset));
+        tiff.extend(entry(TAG_MODEL, 2, model.len() as u32, model_offset));
+        tiff.extend(entry(TAG_EXIF_IFD, 4, 1, exif_ifd));
+        tiff.extend(&0u32.to_le_bytes());
+        tiff.extend(&1u16.to_le_bytes());
+        tiff.extend(entry(
+            TAG_DATE_TIME_ORIGINAL,
+            2,
+            date.len() as u32,
+            date_offset,
+        ));
+        tiff.extend(&0u32.to_le_bytes());
+        tiff.extend(make.iter());
+        tiff.extend(model.iter());
+        tiff.extend(date.iter());
+
+        let mut app1 = b"Exif\0\0".to_vec();
+        app1.extend(tiff);
+        let mut jpeg = vec![0xff, 0xd8];
+        // an unrelated APP0 segment comes first
+        jpeg.extend(&[0xff, 0xe0, 0, 4, 0, 0]);
+        jpeg.extend(&[0xff, 0xe1]);
+        jpeg.extend(&(app1.len() as u16 + 2).to_be_bytes());
+        jpeg.extend(app1);
+        jpeg.extend(&[0xff, 0xda, 0, 2, 0xff, 0xd9]);
+        jpeg
+    }
+
+    #[test]
+    fn test_parse_exif() {
+        let exif = parse_exif(&jpeg_with_exif()).unwrap();
+        assert_eq!(exif.capture_date.as_deref(), Some("2021-06-13 14:15:16"));
+        assert_eq!(exif.camera_make.as_deref(), Some("Dupletti"));
+        assert_eq!(exif.camera_model.as_deref(), Some("Test Camera 1"));
+    }
+
+    #[test]
+    fn test_read_exif_of_broken_files() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("broken.jpg");
+        let mut data = jpeg_with_exif();
+        data.truncate(60);
+        fs::write(&path, data)?;
+        assert_eq!(read_exif(&path), ExifInfo::default());
+        fs::write(&path, b"not a jpeg")?;
+        assert_eq!(read_exif(&path), ExifInfo::default());
+        assert_eq!(
+            read_exif(&dir.path().join("missing.jpg")),
+            ExifInfo::default()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_normalize_date() {
+        assert_eq!(
+            normalize_date("2021:06:13 14:15:16").as_deref(),
+            Some("2021-06-13 14:15:16")
+        );
+        assert_eq!(normalize_date("0000:00:00 00:00:00"), None);
+        assert_eq!(normalize_date("    :  :     :  :  "), None);
+    }
+}
@@ -1,32 +1,385 @@
 use anyhow::{anyhow, Result};
 use blake2::{Blake2b, Digest};
 use rayon::prelude::*;
-use rusqlite::params;
+use rusqlite::{params, ErrorCode};
+use serde::Serialize;
 use std::fs;
 use std::io::{self, Read};
-use std::sync::{mpsc, Mutex};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::Mutex;
 
+use std::cmp::Reverse;
+use std::collections::hash_map::RandomState;
 use std::collections::HashSet;
+use std::hash::BuildHasher;
 use std::path::{Path, PathBuf};
-use std::time::Instant;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use super::database::{Database, FileDigest};
+use super::batcher::{self, BatchLimits, Batcher};
+use super::database::{self, Database, FileDigest};
+use super::exif;
+use super::filemeta;
+use super::paths::PathCase;
+use super::scanroots;
+use super::scanstats::{Phase, Progress, ScanStats};
+use super::throttle;
+
+/// The order in which files are hashed.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScanOrder {
+    /// Largest files first, so the biggest duplicates show up early
+    Size,
+    /// By path, which keeps reads from the same directory together
+    Name,
+    Random,
+}
+
+impl FromStr for ScanOrder {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<ScanOrder> {
+        match s {
+            "size" => Ok(ScanOrder::Size),
+            "name" => Ok(ScanOrder::Name),
+            "random" => Ok(ScanOrder::Random),
+            _ => Err(anyhow!(
+                "Unknown scan order {}, use size, name or random",
+                s
+            )),
+        }
+    }
+}
+
+/// A number of bytes, given like 4096, 500K, 10M or 2G. Suffixes are powers of 1024.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ByteSize {
+    pub bytes: u64,
+}
+
+impl FromStr for ByteSize {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<ByteSize> {
+        let invalid = || {
+            anyhow!(
+                "Unknown size {}, use a number of bytes like 4096, 500K or 10M",
+                s
+            )
+        };
+        let number = s.trim_end_matches(&['B', 'b'][..]);
+        let (number, shift) = match number.chars().last().map(|c| c.to_ascii_uppercase()) {
+            Some('K') => (&number[..number.len() - 1], 10),
+            Some('M') => (&number[..number.len() - 1], 20),
+            Some('G') => (&number[..number.len() - 1], 30),
+            Some('T') => (&number[..number.len() - 1], 40),
+            _ => (number, 0),
+        };
+        let number: u64 = number.parse().map_err(|_| invalid())?;
+        let bytes = number.checked_mul(1 << shift).ok_or_else(invalid)?;
+        Ok(ByteSize { bytes })
+    }
+}
+
+/// Files within the same power of 2^SIZE_TIER_BITS bytes belong to the same size tier.
+const SIZE_TIER_BITS: u32 = 4;
+
+fn size_tier(size: u64) -> u32 {
+    (64 - size.leading_zeros()) / SIZE_TIER_BITS
+}
+
+/// Orders `items` for processing, `key` gives the path and size of an item. Sizes are
+/// only compared by tier, so files of similar size keep their order, which is usually
+/// the directory order.
+pub fn order_for_scan<T, F>(mut items: Vec<T>, order: ScanOrder, key: F) -> Vec<T>
+where
+    F: Fn(&T) -> (&Path, u64),
+{
+    match order {
+        ScanOrder::Size => items.sort_by_key(|i| Reverse(size_tier(key(i).1))),
+        ScanOrder::Name => items.sort_by(|a, b| key(a).0.cmp(key(b).0)),
+        ScanOrder::Random => {
+            let state = RandomState::new();
+            items.sort_by_cached_key(|i| state.hash_one(key(i).0));
+        }
+    }
+    items
+}
+
+/// Orders paths for hashing, reading their size from disk if needed.
+pub fn order_paths<I>(paths: I, order: ScanOrder) -> Vec<PathBuf>
+where
+    I: IntoIterator<Item = PathBuf>,
+{
+    let sized: Vec<(PathBuf, u64)> = paths
+        .into_iter()
+        .map(|p| {
+            let size = match order {
+                ScanOrder::Size => fs::metadata(&p).map(|m| m.len()).unwrap_or(0),
+                _ => 0,
+            };
+            (p, size)
+        })
+        .collect();
+    order_for_scan(sized, order, |(p, size)| (p.as_path(), *size))
+        .into_iter()
+        .map(|(p, _)| p)
+        .collect()
+}
+
+fn is_constraint_violation(err: &rusqlite::Error) -> bool {
+    matches!(err, rusqlite::Error::SqliteFailure(e, _) if e.code == ErrorCode::ConstraintViolation)
+}
+
+/// An indexed file below one of `roots` with the content of `file` that is gone from disk, if
+/// `path` isn't indexed yet. Returns its id and old path.
+fn find_moved_file(
+    stmt: &mut rusqlite::Statement,
+    file: &FileDigest,
+    path: &str,
+    roots: &[PathBuf],
+    path_case: PathCase,
+) -> Result<Option<(i64, String)>> {
+    let candidates = stmt
+        .query_map(params![file.digest, file.size, path], |row| {
+            Ok((row.get(0)?, row.get::<_, String>(1)?))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(candidates.into_iter().find(|(_, old_path)| {
+        let old_path = Path::new(old_path);
+        roots
+            .iter()
+            .any(|root| path_case.starts_with(old_path, root))
+            && !old_path.exists()
+    }))
+}
 
 impl Database {
-    fn insert_many_filedigests(&mut self, files: &Vec<FileDigest>) -> Result<()> {
+    /// Inserts a batch of digests in one transaction. Paths that are already indexed get
+    /// their digest and size updated instead. Single rows that can't be written are logged
+    /// and counted, but don't abort the rest of the batch.
+    /// With `PathCase::Insensitive`, a path that is indexed with a different case updates
+    /// the existing row, which keeps its original casing.
+    /// New rows remember `scan_time` (unix seconds) as the time they were first seen.
+    /// With `moved_from`, a new path whose content matches an indexed file below one of
+    /// those roots which no longer exists takes over its row, so tags and history survive moves.
+    fn insert_many_filedigests(
+        &mut self,
+        files: &Vec<FileDigest>,
+        path_case: PathCase,
+        moved_from: &[PathBuf],
+        scan_time: i64,
+        stats: &mut ScanStats,
+    ) -> Result<()> {
         let tx = self.db.transaction()?;
-        let mut stmt = tx.prepare(
-            "INSERT OR IGNORE INTO file_digests (path, digest, size) VALUES (?1, ?2, ?3)",
+        let roots = scanroots::scan_root_ids(&tx)?;
+        let mut insert_stmt = tx.prepare(
+            "INSERT OR IGNORE INTO file_digests (path, digest, size, first_seen, \
+             exif_capture_date, exif_camera_make, exif_camera_model, root_id, path_key) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        )?;
+        let mut update_stmt = tx.prepare(
+            "UPDATE file_digests SET digest = ?2, size = ?3, \
+             exif_capture_date = ?4, exif_camera_make = ?5, exif_camera_model = ?6 \
+             WHERE path = ?1 AND (digest IS NOT ?2 OR size IS NOT ?3)",
         )?;
+        // rows without a key yet are only found by their ASCII case
+        let mut lookup_stmt = tx.prepare(
+            "SELECT path FROM file_digests WHERE path_key = ?1 \
+             UNION ALL SELECT path FROM file_digests \
+             WHERE path_key IS NULL AND path = ?2 COLLATE NOCASE",
+        )?;
+        let mut key_stmt = tx.prepare(
+            "UPDATE OR IGNORE file_digests SET path_key = ?2 WHERE path = ?1 AND path_key IS NOT ?2",
+        )?;
+        let mut pending_stmt = tx.prepare("DELETE FROM pending_files WHERE path = ?1")?;
+        let mut outdated_stmts = database::CONTENT_TABLES
+            .iter()
+            .map(|table| {
+                tx.prepare(&format!(
+                    "DELETE FROM {} WHERE id IN (SELECT id FROM file_digests WHERE path = ?1)",
+                    table
+                ))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut moved_stmt = tx.prepare(
+            "SELECT id, path FROM file_digests WHERE digest = ?1 AND size = ?2 \
+             AND NOT EXISTS (SELECT 1 FROM file_digests WHERE path = ?3) ORDER BY id",
+        )?;
+        let mut move_stmt = tx.prepare(
+            "UPDATE file_digests SET path = ?2, root_id = ?3, path_key = ?4 WHERE id = ?1",
+        )?;
+        let mut meta_stmt = tx.prepare(
+            "INSERT OR REPLACE INTO file_meta \
+             (id, created, changed, accessed, uid, mode, dev, inode, links) \
+             SELECT id, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9 FROM file_digests WHERE path = ?1",
+        )?;
+        let mut paths_in_batch = HashSet::new();
         for f in files {
-            // TODO: raise Error when _cnt == 0, because that means we re-inserted a path.
-            let path = f.path.to_string_lossy();
-            let cnt = stmt.execute(params![path, f.digest, f.size])?;
-            if cnt == 0 {
-                return Err(anyhow!("Unable to insert {}", path));
+            let mut path = f.path.to_string_lossy().into_owned();
+            pending_stmt.execute(params![path])?;
+            let key = path_case.key(&f.path).to_string_lossy().into_owned();
+            if !paths_in_batch.insert(key.clone()) {
+                log::debug!("{} is contained twice in the batch", path);
+                stats.batch_duplicates += 1;
+                continue;
+            }
+            if path_case == PathCase::Insensitive {
+                let mut rows = lookup_stmt.query(params![key, path])?;
+                if let Some(row) = rows.next()? {
+                    path = row.get(0)?;
+                }
+            }
+            let root_id = scanroots::root_of(&roots, Path::new(&path), path_case);
+            let moved = if moved_from.is_empty() {
+                None
+            } else {
+                find_moved_file(&mut moved_stmt, f, &path, moved_from, path_case)?
+            };
+            if let Some((id, old_path)) = moved {
+                move_stmt.execute(params![id, path, root_id, key])?;
+                log::info!("{} was moved to {}", old_path, path);
+                stats.files_moved += 1;
+            } else {
+                let exif = &f.exif;
+                let result = insert_stmt
+                    .execute(params![
+                        path,
+                        f.digest,
+                        f.size,
+                        scan_time,
+                        exif.capture_date,
+                        exif.camera_make,
+                        exif.camera_model,
+                        root_id,
+                        key
+                    ])
+                    .and_then(|cnt| match cnt {
+                        0 => update_stmt
+                            .execute(params![
+                                path,
+                                f.digest,
+                                f.size,
+                                exif.capture_date,
+                                exif.camera_make,
+                                exif.camera_model
+                            ])
+                            .map(|c| (0, c)),
+                        _ => Ok((cnt, 0)),
+                    });
+                match result {
+                    Ok((1, _)) => stats.files_inserted += 1,
+                    Ok((_, 1)) => {
+                        log::info!("Content of {} changed, updating digest", path);
+                        for stmt in outdated_stmts.iter_mut() {
+                            stmt.execute(params![path])?;
+                        }
+                        stats.files_updated += 1;
+                    }
+                    // already indexed with the same content
+                    Ok(_) => {}
+                    Err(e) if is_constraint_violation(&e) => {
+                        log::warn!("Unable to insert {}: {}", path, e);
+                        stats.insert_conflicts += 1;
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+                key_stmt.execute(params![path, key])?;
+            }
+            if let Some(meta) = &f.meta {
+                meta_stmt.execute(params![
+                    path,
+                    meta.created,
+                    meta.changed,
+                    meta.accessed,
+                    meta.uid,
+                    meta.mode,
+                    meta.device,
+                    meta.inode,
+                    meta.links
+                ])?;
+            }
+        }
+        meta_stmt.finalize()?;
+        moved_stmt.finalize()?;
+        move_stmt.finalize()?;
+        insert_stmt.finalize()?;
+        update_stmt.finalize()?;
+        lookup_stmt.finalize()?;
+        key_stmt.finalize()?;
+        pending_stmt.finalize()?;
+        for stmt in outdated_stmts {
+            stmt.finalize()?;
+        }
+        Ok(tx.commit()?)
+    }
+
+    /// Replaces the list of files that still need to be hashed, so an interrupted scan can
+    /// be resumed without listing the filesystem again.
+    pub fn store_pending_files(&mut self, files: &HashSet<PathBuf>) -> Result<()> {
+        let listed_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        let tx = self.db.transaction()?;
+        tx.execute("DELETE FROM pending_files", params![])?;
+        {
+            let mut stmt = tx
+                .prepare("INSERT OR IGNORE INTO pending_files (path, listed_at) VALUES (?1, ?2)")?;
+            for f in files {
+                stmt.execute(params![f.to_string_lossy(), listed_at])?;
+            }
+        }
+        Ok(tx.commit()?)
+    }
+
+    /// Adds files to the pending list of the running scan.
+    pub fn append_pending_files(&mut self, files: &HashSet<PathBuf>) -> Result<()> {
+        let listed_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        let tx = self.db.transaction()?;
+        {
+            let mut stmt = tx
+                .prepare("INSERT OR IGNORE INTO pending_files (path, listed_at) VALUES (?1, ?2)")?;
+            for f in files {
+                stmt.execute(params![f.to_string_lossy(), listed_at])?;
             }
         }
-        stmt.finalize()?;
+        Ok(tx.commit()?)
+    }
+
+    /// The files left over from an interrupted scan, and when they were listed.
+    pub fn get_pending_files(&self) -> Result<(HashSet<PathBuf>, Option<SystemTime>)> {
+        let mut stmt = self
+            .db
+            .prepare("SELECT path, listed_at FROM pending_files")?;
+        let mut files = HashSet::new();
+        let mut listed_at = None;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            files.insert(PathBuf::from(row.get::<_, String>(0)?));
+            let secs: i64 = row.get(1)?;
+            listed_at = Some(UNIX_EPOCH + Duration::from_secs(secs as u64));
+        }
+        Ok((files, listed_at))
+    }
+
+    pub fn count_pending_files(&self) -> Result<usize> {
+        let count: i64 =
+            self.db
+                .query_row("SELECT COUNT(*) FROM pending_files", params![], |row| {
+                    row.get(0)
+                })?;
+        Ok(count as usize)
+    }
+
+    /// Drops files that couldn't be hashed from the pending list, so resuming doesn't
+    /// retry them forever.
+    fn delete_pending_files(&mut self, files: &[PathBuf]) -> Result<()> {
+        let tx = self.db.transaction()?;
+        for f in files {
+            tx.execute(
+                "DELETE FROM pending_files WHERE path = ?1",
+                params![f.to_string_lossy()],
+            )?;
+        }
         Ok(tx.commit()?)
     }
 }
@@ -38,89 +391,191 @@ fn get_hash<D: Digest + Default>(filepath: &Path) -> io::Result<Vec<u8>> {
     let mut buffer = [0u8; BUFFER_SIZE];
 
     loop {
-        let n = reader.read(&mut buffer).unwrap();
-        sh.update(&buffer[..n]);
-        if n == 0 || n < BUFFER_SIZE {
+        // a short read, e.g. from a pipe or a network file system, isn't the end yet
+        let n = reader.read(&mut buffer)?;
+        if n == 0 {
             break;
         }
+        throttle::read(n);
+        sh.update(&buffer[..n]);
     }
 
     Ok(sh.finalize().to_vec())
 }
 
-fn _create_filedigest(path: &PathBuf) -> Result<FileDigest> {
+/// The digest of a file's content, as stored in the DB.
+pub fn file_digest(path: &Path) -> io::Result<Vec<u8>> {
+    get_hash::<Blake2b>(path)
+}
+
+fn _create_filedigest(path: &PathBuf, collect_metadata: bool) -> Result<FileDigest> {
     let digest = get_hash::<Blake2b>(&path)?;
     let s = fs::metadata(&path)?.len();
+    let meta = if collect_metadata {
+        Some(filemeta::read_file_meta(path)?)
+    } else {
+        None
+    };
     Ok(FileDigest {
         id: -1,
         path: path.to_path_buf(),
         digest: digest,
         size: s,
+        exif: exif::read_exif(path),
+        meta,
     })
 }
 
-pub fn process_filelist(
+/// Hashes a single file, for files that are changed outside of a scan.
+pub fn create_filedigest(path: &Path) -> Result<FileDigest> {
+    _create_filedigest(&path.to_path_buf(), false)
+}
+
+/// Hashes the files of `filelist` in parallel and commits them in batches. The list
+/// may also be a channel that is still being filled while hashing. With
+/// `collect_metadata`, the file system metadata of each file is stored as well.
+/// With `moved_from`, files that were moved below those roots keep their rows, see
+/// `insert_many_filedigests`.
+#[allow(clippy::too_many_arguments)]
+pub fn process_filelist<I>(
     db_mutex: &Mutex<Database>,
-    filelist: HashSet<PathBuf>,
-    commit_batchsize: usize,
-) -> Result<()> {
-    let (tx, rx) = mpsc::channel();
+    filelist: I,
+    batch_limits: BatchLimits,
+    path_case: PathCase,
+    collect_metadata: bool,
+    moved_from: &[PathBuf],
+    stats: &mut ScanStats,
+    progress: &Progress,
+) -> Result<()>
+where
+    I: IntoIterator<Item = PathBuf>,
+    I::IntoIter: Send + 'static,
+{
+    let scan_time = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+    let filelist = filelist.into_iter();
+    let (tx, rx) = batcher::queue(&batch_limits);
     rayon::spawn(move || {
         filelist
-            .par_iter()
-            .map(|path| _create_filedigest(path))
-            .try_for_each_with(tx, |tx, f| tx.send(f))
-            .expect("expected no send errors");
+            .par_bridge()
+            .map(|path| {
+                let digest = _create_filedigest(&path, collect_metadata);
+                (path, digest)
+            })
+            .try_for_each_with(tx, |tx, f| tx.send(f).map_err(Box::new))
+            // only fails once hashing stopped early, the files in flight are lost
+            .ok();
     });
 
-    let mut filedigests: Vec<FileDigest> = Vec::new();
-    let mut time_last_commit = Instant::now();
-    for digest in rx.iter() {
-        match digest {
-            Ok(fd) => filedigests.push(fd),
-            Err(err) => log::warn!("Error while processing filelist: {:?}", err),
-        };
-        if filedigests.len() < commit_batchsize {
+    let mut filedigests: Batcher<FileDigest> = Batcher::new(batch_limits);
+    let mut failed_paths: Vec<PathBuf> = Vec::new();
+    let phase = progress.phase(Phase::Hashing);
+    phase.start();
+    loop {
+        match rx.recv_timeout(filedigests.time_left()) {
+            Ok((path, digest)) => {
+                match digest {
+                    Ok(fd) => {
+                        phase.add_file(fd.size, false);
+                        filedigests.push(fd)
+                    }
+                    Err(err) => {
+                        log::warn!("Error while processing filelist: {:?}", err);
+                        stats.hash_errors += 1;
+                        phase.add_file(0, true);
+                        failed_paths.push(path);
+                    }
+                };
+                progress.update(Phase::Hashing);
+                if progress.stop_requested() {
+                    log::warn!("Hashing stopped, use --resume to hash the remaining files");
+                    break;
+                }
+            }
+            // no file was hashed in time, but the batch may be old enough to commit
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        if !filedigests.is_full() {
             continue;
         }
 
-        // Submitting batch
-        let dt = time_last_commit.elapsed().as_secs_f64();
-        time_last_commit = Instant::now();
-        let total_size_mb = filedigests.iter().map(|f| f.size).sum::<u64>() / (1024 * 1024);
-        let mps = total_size_mb as f64 / dt;
-        let fps = commit_batchsize as f64 / dt;
+        // Submitting batch, hashing goes on until the queue is full
+        phase.set_queued(rx.depth());
         log::debug!(
-            "Committing to DB (speed: {:3.2} MiB/s, {:3.2} files/s)",
-            mps,
-            fps
+            "Committing {} files, {} hashed files queued",
+            filedigests.items().len(),
+            rx.depth()
         );
         if let Ok(mut db) = db_mutex.lock() {
-            db.insert_many_filedigests(&filedigests)?;
+            db.insert_many_filedigests(
+                filedigests.items(),
+                path_case,
+                moved_from,
+                scan_time,
+                stats,
+            )?;
+            db.delete_pending_files(&failed_paths)?;
         } else {
             return Err(anyhow!("Unable to lock DB"));
         }
+        progress.report(Phase::Hashing);
         filedigests.clear();
+        failed_paths.clear();
     }
 
-    if filedigests.len() > 0 {
+    if !filedigests.is_empty() || !failed_paths.is_empty() {
         if let Ok(mut db) = db_mutex.lock() {
-            db.insert_many_filedigests(&filedigests)?;
+            db.insert_many_filedigests(
+                filedigests.items(),
+                path_case,
+                moved_from,
+                scan_time,
+                stats,
+            )?;
+            db.delete_pending_files(&failed_paths)?;
         } else {
             return Err(anyhow!("Unable to lock DB"));
         }
     }
+    progress.finish(Phase::Hashing);
     Ok(())
 }
 
+/// The size of the files at `paths` that can be read, in bytes.
+pub fn total_size<'a, I: IntoIterator<Item = &'a PathBuf>>(paths: I) -> u64 {
+    paths
+        .into_iter()
+        .filter_map(|p| fs::metadata(p).ok())
+        .map(|m| m.len())
+        .sum()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use crate::scanstats::{PhaseSnapshot, ProgressReporter};
     use std::fs::File;
     use std::io::prelude::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
     use tempfile::tempdir;
 
+    #[test]
+    fn test_parse_byte_size() {
+        let bytes = |s: &str| s.parse::<ByteSize>().map(|b| b.bytes).ok();
+        assert_eq!(bytes("4096"), Some(4096));
+        assert_eq!(bytes("500K"), Some(500 * 1024));
+        assert_eq!(bytes("10M"), Some(10 * 1024 * 1024));
+        assert_eq!(bytes("10mb"), Some(10 * 1024 * 1024));
+        assert_eq!(bytes("2G"), Some(2 << 30));
+        assert_eq!(bytes("1T"), Some(1 << 40));
+        assert_eq!(bytes("0"), Some(0));
+        for invalid in ["", "M", "1.5M", "10X", "-1", "99999999999T"].iter() {
+            assert_eq!(bytes(invalid), None, "{} should be rejected", invalid);
+        }
+    }
+
     #[test]
     fn test_blake2_hash() -> Result<()> {
         let target_digest = vec![
@@ -141,6 +596,40 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_get_hash_reads_to_the_end() -> Result<()> {
+        let dir = tempdir()?;
+        // reading a directory fails, which is an error and not a panic
+        assert!(get_hash::<Blake2b>(dir.path()).is_err());
+
+        // a pipe returns what was written so far, which is less than the buffer
+        let fifo = dir.path().join("fifo");
+        assert!(std::process::Command::new("mkfifo")
+            .arg(&fifo)
+            .status()?
+            .success());
+        let writer = {
+            let fifo = fifo.clone();
+            std::thread::spawn(move || -> io::Result<()> {
+                let mut pipe = fs::OpenOptions::new().write(true).open(&fifo)?;
+                for _ in 0..3 {
+                    pipe.write_all(b"Hello, world!")?;
+                    pipe.flush()?;
+                    std::thread::sleep(std::time::Duration::from_millis(20));
+                }
+                Ok(())
+            })
+        };
+        let digest = get_hash::<Blake2b>(&fifo)?;
+        writer.join().unwrap()?;
+        assert_eq!(
+            digest,
+            Blake2b::digest(&b"Hello, world!".repeat(3)).to_vec()
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_process_filelist_and_check_hash() -> Result<()> {
         let target_digest = vec![
@@ -163,13 +652,292 @@ mod tests {
         let filelist: HashSet<_> = vec![filepath.clone()].into_iter().collect();
         let db = Database::new("test_process_filelist_and_check_hash.sqlite", true)?;
         let db_mutex = Mutex::new(db);
-        process_filelist(&db_mutex, filelist, 16)?;
+        process_filelist(
+            &db_mutex,
+            filelist,
+            BatchLimits::with_items(16),
+            PathCase::Sensitive,
+            false,
+            &[],
+            &mut ScanStats::default(),
+            &Progress::default(),
+        )?;
 
         let inserted_files = db_mutex.lock().unwrap().get_all_filedigests()?;
         assert_eq!(inserted_files[0].digest, target_digest);
         Ok(())
     }
 
+    /// Keeps everything it is given, last one last.
+    #[derive(Default)]
+    struct RecordingReporter {
+        reports: Mutex<Vec<(Phase, PhaseSnapshot)>>,
+        finished: Mutex<Vec<(Phase, PhaseSnapshot)>>,
+    }
+
+    impl ProgressReporter for RecordingReporter {
+        fn report(&self, phase: Phase, snapshot: &PhaseSnapshot) {
+            self.reports.lock().unwrap().push((phase, snapshot.clone()));
+        }
+
+        fn finish(&self, phase: Phase, snapshot: &PhaseSnapshot) {
+            self.finished
+                .lock()
+                .unwrap()
+                .push((phase, snapshot.clone()));
+        }
+
+        fn interval(&self) -> Duration {
+            Duration::from_secs(3600)
+        }
+    }
+
+    #[test]
+    fn test_process_filelist_reports_progress() -> Result<()> {
+        let dir = tempdir()?;
+        let filelist: HashSet<PathBuf> = (1..=5)
+            .map(|i| {
+                let path = dir.path().join(i.to_string());
+                fs::write(&path, vec![0u8; i * 100]).unwrap();
+                path
+            })
+            .collect();
+        let reporter = Arc::new(RecordingReporter::default());
+        let progress = Progress::with_reporter(reporter.clone());
+        progress
+            .phase(Phase::Hashing)
+            .add_total(filelist.len(), total_size(&filelist));
+        let db_mutex = Mutex::new(Database::new(
+            "test_process_filelist_reports_progress.sqlite",
+            true,
+        )?);
+        process_filelist(
+            &db_mutex,
+            filelist,
+            BatchLimits::with_items(2),
+            PathCase::Sensitive,
+            false,
+            &[],
+            &mut ScanStats::default(),
+            &progress,
+        )?;
+
+        // the first file is shown right away, then only after each batch of 2 files
+        let reports = reporter.reports.lock().unwrap();
+        assert_eq!(reports.len(), 3);
+        assert_eq!(reports[0].1.files_done, 1);
+        assert_eq!(reports[2].1.files_done, 4);
+        let finished = reporter.finished.lock().unwrap();
+        let (phase, last) = &finished[0];
+        assert_eq!(*phase, Phase::Hashing);
+        assert_eq!((last.files_done, last.files_total), (5, 5));
+        assert_eq!((last.bytes_done, last.bytes_total), (1500, 1500));
+        assert_eq!(finished.len(), 1);
+        Ok(())
+    }
+
+    /// Asks to stop once the first file is reported.
+    struct StoppingReporter {
+        stop: Arc<AtomicBool>,
+    }
+
+    impl ProgressReporter for StoppingReporter {
+        fn report(&self, _phase: Phase, _snapshot: &PhaseSnapshot) {
+            self.stop.store(true, Ordering::SeqCst);
+        }
+
+        fn interval(&self) -> Duration {
+            Duration::from_secs(0)
+        }
+    }
+
+    #[test]
+    fn test_stopped_hashing_commits_partial_batch() -> Result<()> {
+        let dir = tempdir()?;
+        let filelist: HashSet<PathBuf> = (0..100)
+            .map(|i| {
+                let path = dir.path().join(i.to_string());
+                fs::write(&path, i.to_string()).unwrap();
+                path
+            })
+            .collect();
+        let mut db = Database::new("test_stopped_hashing_commits_partial_batch.sqlite", true)?;
+        db.store_pending_files(&filelist)?;
+        let db_mutex = Mutex::new(db);
+        let stop = Arc::new(AtomicBool::new(false));
+        let progress = Progress::with_reporter(Arc::new(StoppingReporter { stop: stop.clone() }))
+            .with_stop(stop);
+        process_filelist(
+            &db_mutex,
+            filelist,
+            BatchLimits::with_items(16),
+            PathCase::Sensitive,
+            false,
+            &[],
+            &mut ScanStats::default(),
+            &progress,
+        )?;
+
+        // the file hashed before stopping is committed, the rest is left for --resume
+        let db = db_mutex.lock().unwrap();
+        assert_eq!(db.count_filedigests()?, 1);
+        assert_eq!(db.count_pending_files()?, 99);
+        Ok(())
+    }
+
+    #[test]
+    fn test_aged_batch_commits_while_waiting() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("first");
+        fs::write(&path, "first")?;
+        let db = Database::new("test_aged_batch_commits_while_waiting.sqlite", true)?;
+        let db_mutex = Mutex::new(db);
+        let limits = BatchLimits {
+            max_interval: Duration::from_millis(20),
+            ..BatchLimits::with_items(100)
+        };
+        let (tx, filelist) = std::sync::mpsc::channel();
+        tx.send(path)?;
+        // while one worker waits for the next path, another one hashes the first
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(4).build()?;
+        std::thread::scope(|scope| -> Result<()> {
+            let hashing = scope.spawn(|| {
+                pool.install(|| {
+                    process_filelist(
+                        &db_mutex,
+                        filelist,
+                        limits,
+                        PathCase::Sensitive,
+                        false,
+                        &[],
+                        &mut ScanStats::default(),
+                        &Progress::default(),
+                    )
+                })
+            });
+            // the file is committed once its batch is old, although no other file follows
+            let committed = (0..500).any(|_| {
+                std::thread::sleep(Duration::from_millis(10));
+                db_mutex.lock().unwrap().count_filedigests().unwrap() == 1
+            });
+            drop(tx);
+            hashing.join().unwrap()?;
+            assert!(committed);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_order_for_scan() {
+        const MB: u64 = 1024 * 1024;
+        let files = vec![
+            ("/b/small", 10),
+            ("/a/large", 900 * MB),
+            ("/c/medium", 20 * MB),
+            ("/a/larger", 1000 * MB),
+            ("/b/tiny", 1),
+        ];
+        let ordered = |order| -> Vec<&str> {
+            order_for_scan(files.clone(), order, |(p, size)| (Path::new(*p), *size))
+                .into_iter()
+                .map(|(p, _)| p)
+                .collect()
+        };
+        // both large files are in the same tier and keep their order
+        assert_eq!(
+            ordered(ScanOrder::Size),
+            ["/a/large", "/a/larger", "/c/medium", "/b/small", "/b/tiny"]
+        );
+        assert_eq!(
+            ordered(ScanOrder::Name),
+            ["/a/large", "/a/larger", "/b/small", "/b/tiny", "/c/medium"]
+        );
+        let mut random = ordered(ScanOrder::Random);
+        random.sort_unstable();
+        assert_eq!(random, ordered(ScanOrder::Name));
+        assert!("sideways".parse::<ScanOrder>().is_err());
+    }
+
+    #[test]
+    fn test_order_paths_reads_sizes() -> Result<()> {
+        let dir = tempdir()?;
+        let (small, large) = (dir.path().join("a"), dir.path().join("b"));
+        fs::write(&small, "a")?;
+        fs::write(&large, vec![0u8; 100])?;
+        let paths = vec![small.clone(), large.clone()];
+        assert_eq!(order_paths(paths.clone(), ScanOrder::Size), [large, small]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_filelist_reads_exif() -> Result<()> {
+        let dir = tempdir()?;
+        let photo = dir.path().join("photo.jpg");
+        fs::write(&photo, crate::exif::tests::jpeg_with_exif())?;
+        // not an image, even though the content is
+        let renamed = dir.path().join("photo.bin");
+        fs::copy(&photo, &renamed)?;
+        let filelist: HashSet<_> = vec![photo.clone(), renamed].into_iter().collect();
+        let db = Database::new("test_process_filelist_reads_exif.sqlite", true)?;
+        let db_mutex = Mutex::new(db);
+        process_filelist(
+            &db_mutex,
+            filelist,
+            BatchLimits::with_items(16),
+            PathCase::Sensitive,
+            false,
+            &[],
+            &mut ScanStats::default(),
+            &Progress::default(),
+        )?;
+
+        let files = db_mutex.lock().unwrap().get_all_filedigests()?;
+        assert_eq!(files.len(), 2);
+        for f in files {
+            if f.path == photo {
+                assert_eq!(f.exif.capture_date.as_deref(), Some("2021-06-13 14:15:16"));
+                assert_eq!(f.exif.camera_make.as_deref(), Some("Dupletti"));
+                assert_eq!(f.exif.camera_model.as_deref(), Some("Test Camera 1"));
+            } else {
+                assert!(!f.exif.is_present());
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_filelist_consumes_pending_files() -> Result<()> {
+        let dir = tempdir()?;
+        let existing = dir.path().join("existing.txt");
+        fs::write(&existing, "content")?;
+        let filelist: HashSet<_> = vec![existing, dir.path().join("vanished.txt")]
+            .into_iter()
+            .collect();
+
+        let mut db = Database::new("test_process_filelist_pending.sqlite", true)?;
+        db.store_pending_files(&filelist)?;
+        let (pending, listed_at) = db.get_pending_files()?;
+        assert_eq!(pending, filelist);
+        assert!(listed_at.is_some());
+
+        let db_mutex = Mutex::new(db);
+        let mut stats = ScanStats::default();
+        process_filelist(
+            &db_mutex,
+            filelist,
+            BatchLimits::with_items(16),
+            PathCase::Sensitive,
+            false,
+            &[],
+            &mut stats,
+            &Progress::default(),
+        )?;
+        assert_eq!(stats.files_inserted, 1);
+        assert_eq!(stats.hash_errors, 1);
+        assert_eq!(db_mutex.lock().unwrap().count_pending_files()?, 0);
+        Ok(())
+    }
+
     #[test]
     fn test_insert_files_multithreaded() -> Result<()> {
         let dir = PathBuf::from(tempdir()?.path());
@@ -193,7 +961,16 @@ mod tests {
             File::create(path).expect("Failed to create temporary file");
         }
         filelist.insert(first_path);
-        process_filelist(&db_mutex, filelist.clone(), 16)?;
+        process_filelist(
+            &db_mutex,
+            filelist.clone(),
+            BatchLimits::with_items(16),
+            PathCase::Sensitive,
+            false,
+            &[],
+            &mut ScanStats::default(),
+            &Progress::default(),
+        )?;
 
         let db = db_mutex.lock().unwrap();
         let all_files = db.get_all_filedigests()?;
@@ -212,9 +989,178 @@ mod tests {
         testfiles.push(FileDigest::new(5, "/tmp/e", vec![0, 1, 2, 5], 1));
 
         let mut db = Database::new("test6.sqlite", true)?;
-        db.insert_many_filedigests(&testfiles)?;
+        db.record_scan_root(Path::new("/tmp"), PathCase::Sensitive)?;
+        let mut stats = ScanStats::default();
+        db.insert_many_filedigests(&testfiles, PathCase::Sensitive, &[], 0, &mut stats)?;
         let result = db.get_all_filedigests()?;
         assert_eq!(testfiles, result);
+        assert_eq!(stats.files_inserted, 5);
+        // new files belong to the scan root they are below
+        let root_ids: i64 = db.db.query_row(
+            "SELECT COUNT(*) FROM file_digests WHERE root_id = 1",
+            [],
+            |row| row.get(0),
+        )?;
+        assert_eq!(root_ids, 5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_insert_many_filedigests_with_duplicates_in_batch() -> Result<()> {
+        let testfiles = vec![
+            FileDigest::new(1, "/tmp/a", vec![0, 1, 2, 3], 1),
+            FileDigest::new(2, "/tmp/a", vec![0, 1, 2, 3], 1),
+            FileDigest::new(2, "/tmp/b", vec![0, 1, 2, 4], 1),
+        ];
+
+        let mut db = Database::new("test_insert_many_duplicates_in_batch.sqlite", true)?;
+        let mut stats = ScanStats::default();
+        db.insert_many_filedigests(&testfiles, PathCase::Sensitive, &[], 0, &mut stats)?;
+        let paths: Vec<_> = db
+            .get_all_filedigests()?
+            .into_iter()
+            .map(|f| f.path)
+            .collect();
+        assert_eq!(
+            paths,
+            vec![PathBuf::from("/tmp/a"), PathBuf::from("/tmp/b")]
+        );
+        assert_eq!(stats.files_inserted, 2);
+        assert_eq!(stats.batch_duplicates, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_filter_by_first_seen() -> Result<()> {
+        let mut db = Database::new("test_filter_by_first_seen.sqlite", true)?;
+        let last_week = crate::similarities::parse_date("2024-05-01")?;
+        let today = crate::similarities::parse_date("2024-05-08")?;
+        let mut stats = ScanStats::default();
+        let first_scan = vec![
+            FileDigest::new(0, "/tmp/old_a", vec![1; 8], 1),
+            FileDigest::new(0, "/tmp/old_b", vec![1; 8], 1),
+            FileDigest::new(0, "/tmp/old_c", vec![2; 8], 1),
+        ];
+        db.insert_many_filedigests(&first_scan, PathCase::Sensitive, &[], last_week, &mut stats)?;
+        let second_scan = vec![
+            FileDigest::new(0, "/tmp/new_c", vec![2; 8], 1),
+            FileDigest::new(0, "/tmp/new_d", vec![3; 8], 1),
+            FileDigest::new(0, "/tmp/new_e", vec![3; 8], 1),
+        ];
+        db.insert_many_filedigests(&second_scan, PathCase::Sensitive, &[], today, &mut stats)?;
+        // indexed before first_seen was recorded
+        db.db.execute(
+            "INSERT INTO file_digests (path, digest, size) VALUES \
+                ('/tmp/legacy_a', x'0404040404040404', 1), ('/tmp/legacy_b', x'0404040404040404', 1)",
+            params![],
+        )?;
+
+        let groups = crate::similarities::get_list_of_similar_files(&db)?;
+        assert_eq!(groups.len(), 4);
+        let ids = |groups: Vec<crate::similarities::FileGroup>| {
+            let mut ids: Vec<String> = groups.into_iter().map(|g| g.group_id).collect();
+            ids.sort();
+            ids
+        };
+        let recent = crate::similarities::filter_added_after(groups, today);
+        assert_eq!(
+            ids(recent),
+            ["0202020202020202", "0303030303030303", "0404040404040404"]
+        );
+        let groups = crate::similarities::get_list_of_similar_files(&db)?;
+        assert_eq!(
+            ids(crate::similarities::filter_added_after(groups, last_week)).len(),
+            4
+        );
+        // collapsed groups are checked by the members they don't list as well
+        let collapsed = crate::similarities::get_collapsed_list_of_similar_files(&db, Some(1))?;
+        let old_c = collapsed
+            .iter()
+            .find(|g| g.group_id == "0202020202020202")
+            .map(|g| g.files[0].path.clone());
+        assert_eq!(old_c, Some(PathBuf::from("/tmp/old_c")));
+        assert_eq!(
+            ids(crate::similarities::filter_added_after(collapsed, today)),
+            ["0202020202020202", "0303030303030303", "0404040404040404"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_insert_many_filedigests_case_insensitive() -> Result<()> {
+        let mut db = Database::new("test_insert_many_case_insensitive.sqlite", true)?;
+        db.insert_filedigest(&FileDigest::new(1, "/tmp/X/Photo.JPG", vec![0, 1, 2, 3], 1))?;
+        let testfiles = vec![
+            FileDigest::new(1, "/tmp/x/photo.jpg", vec![0, 1, 2, 4], 1),
+            FileDigest::new(2, "/tmp/x/PHOTO.jpg", vec![0, 1, 2, 4], 1),
+        ];
+        let mut stats = ScanStats::default();
+        db.insert_many_filedigests(&testfiles, PathCase::Insensitive, &[], 0, &mut stats)?;
+        assert_eq!(
+            db.get_all_filedigests()?,
+            vec![FileDigest::new(1, "/tmp/X/Photo.JPG", vec![0, 1, 2, 4], 1)]
+        );
+        assert_eq!(stats.files_updated, 1);
+        assert_eq!(stats.batch_duplicates, 1);
+
+        // keyed rows are found by their full lower case path, which is indexed
+        let testfiles = vec![FileDigest::new(2, "/tmp/Ärger.jpg", vec![1], 1)];
+        db.insert_many_filedigests(&testfiles, PathCase::Insensitive, &[], 0, &mut stats)?;
+        let testfiles = vec![FileDigest::new(2, "/tmp/äRGER.JPG", vec![2], 1)];
+        db.insert_many_filedigests(&testfiles, PathCase::Insensitive, &[], 0, &mut stats)?;
+        let paths: Vec<_> = db
+            .get_all_filedigests()?
+            .into_iter()
+            .map(|f| f.path)
+            .collect();
+        assert_eq!(
+            paths,
+            [
+                PathBuf::from("/tmp/X/Photo.JPG"),
+                PathBuf::from("/tmp/Ärger.jpg")
+            ]
+        );
+        assert_eq!(stats.files_updated, 2);
+        let plan: String = db.db.query_row(
+            "EXPLAIN QUERY PLAN SELECT path FROM file_digests WHERE path_key = ?1",
+            params!["/tmp/ärger.jpg"],
+            |row| row.get(3),
+        )?;
+        assert!(plan.contains("file_digests_path_key"), "{}", plan);
+        Ok(())
+    }
+
+    #[test]
+    fn test_insert_many_filedigests_updates_changed_files() -> Result<()> {
+        let mut db = Database::new("test_insert_many_updates_changed.sqlite", true)?;
+        db.insert_filedigest(&FileDigest::new(1, "/tmp/a", vec![0, 1, 2, 3], 1))?;
+        db.insert_filedigest(&FileDigest::new(2, "/tmp/b", vec![0, 1, 2, 4], 1))?;
+        for id in [1, 2] {
+            db.db.execute(
+                "INSERT INTO video_hash (id, histogram) VALUES (?1, x'00')",
+                params![id],
+            )?;
+        }
+
+        let testfiles = vec![
+            FileDigest::new(1, "/tmp/a", vec![9, 9, 9, 9], 2), // changed
+            FileDigest::new(2, "/tmp/b", vec![0, 1, 2, 4], 1), // unchanged
+            FileDigest::new(3, "/tmp/c", vec![0, 1, 2, 5], 1), // new
+        ];
+        let mut stats = ScanStats::default();
+        db.insert_many_filedigests(&testfiles, PathCase::Sensitive, &[], 0, &mut stats)?;
+
+        assert_eq!(db.get_all_filedigests()?, testfiles);
+        assert_eq!(stats.files_inserted, 1);
+        assert_eq!(stats.files_updated, 1);
+        assert_eq!(stats.insert_conflicts, 0);
+        // the video hash of the changed file is computed again
+        let hashed: Vec<i64> = db
+            .db
+            .prepare("SELECT id FROM video_hash")?
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        assert_eq!(hashed, [2]);
         Ok(())
     }
 }
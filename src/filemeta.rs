@@ -0,0 +1,229 @@
+use anyhow::{anyhow, Result};
+use rusqlite::{params, OptionalExtension};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::database::Database;
+
+/// Lists the users of the system, for showing owners by name.
+const PASSWD_PATH: &str = "/etc/passwd";
+
+/// File system metadata that helps telling the original of a duplicate from its copies.
+/// Only collected with --collect-metadata. Fields the platform doesn't provide are None.
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct FileMeta {
+    /// Birth time (unix seconds), not available on every file system
+    pub created: Option<i64>,
+    /// Last change of the inode (unix seconds)
+    pub changed: Option<i64>,
+    pub accessed: Option<i64>,
+    pub uid: Option<u32>,
+    /// Permission bits, e.g. 0o644
+    pub mode: Option<u32>,
+    /// Device and inode, see `FileIdentity`. Stored as signed, like SQLite does
+    pub device: Option<i64>,
+    pub inode: Option<i64>,
+    /// Number of hardlinks
+    pub links: Option<i64>,
+}
+
+/// Files with the same device and inode are the same file, reached through hardlinks or
+/// through different mounts of the same file system.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FileIdentity {
+    pub device: i64,
+    pub inode: i64,
+}
+
+fn unix_seconds(time: std::io::Result<SystemTime>) -> Option<i64> {
+    let time = time.ok()?;
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(d) => Some(d.as_secs() as i64),
+        Err(e) => Some(-(e.duration().as_secs() as i64)),
+    }
+}
+
+impl FileMeta {
+    #[cfg(unix)]
+    fn from_metadata(metadata: &fs::Metadata) -> FileMeta {
+        use std::os::unix::fs::MetadataExt;
+        FileMeta {
+            created: unix_seconds(metadata.created()),
+            changed: Some(metadata.ctime()),
+            accessed: unix_seconds(metadata.accessed()),
+            uid: Some(metadata.uid()),
+            mode: Some(metadata.mode() & 0o7777),
+            device: Some(metadata.dev() as i64),
+            inode: Some(metadata.ino() as i64),
+            links: Some(metadata.nlink() as i64),
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn from_metadata(metadata: &fs::Metadata) -> FileMeta {
+        FileMeta {
+            created: unix_seconds(metadata.created()),
+            changed: None,
+            accessed: unix_seconds(metadata.accessed()),
+            uid: None,
+            mode: None,
+            device: None,
+            inode: None,
+            links: None,
+        }
+    }
+
+    /// The permissions like `ls -l` shows them, e.g. "rw-r--r--".
+    pub fn permissions(&self) -> Option<String> {
+        let mode = self.mode?;
+        Some(
+            (0..9)
+                .map(|i| {
+                    if mode & (0o400 >> i) == 0 {
+                        '-'
+                    } else {
+                        ['r', 'w', 'x'][i % 3]
+                    }
+                })
+                .collect(),
+        )
+    }
+}
+
+pub fn read_file_meta(path: &Path) -> Result<FileMeta> {
+    Ok(FileMeta::from_metadata(&fs::metadata(path)?))
+}
+
+/// Name and uid of each user in passwd format.
+fn parse_passwd(passwd: &str) -> impl Iterator<Item = (&str, u32)> {
+    passwd.lines().filter_map(|line| {
+        let mut fields = line.split(':');
+        let name = fields.next()?;
+        let uid = fields.nth(1)?.parse().ok()?;
+        Some((name, uid))
+    })
+}
+
+pub fn user_name(uid: u32) -> Option<String> {
+    let passwd = fs::read_to_string(PASSWD_PATH).ok()?;
+    let name = parse_passwd(&passwd).find(|(_, u)| *u == uid)?.0;
+    Some(name.to_string())
+}
+
+/// The uid of a user given by name or as number.
+pub fn user_id(user: &str) -> Result<u32> {
+    if let Ok(uid) = user.parse() {
+        return Ok(uid);
+    }
+    let passwd = fs::read_to_string(PASSWD_PATH).unwrap_or_default();
+    let uid = parse_passwd(&passwd)
+        .find(|(name, _)| *name == user)
+        .map(|(_, uid)| uid);
+    uid.ok_or_else(|| anyhow!("Unknown user {}", user))
+}
+
+impl Database {
+    pub fn get_file_meta(&self, file_id: i64) -> Result<Option<FileMeta>> {
+        Ok(self
+            .db
+            .query_row(
+                "SELECT created, changed, accessed, uid, mode, dev, inode, links \
+                 FROM file_meta WHERE id = ?1",
+                params![file_id],
+                |row| {
+                    Ok(FileMeta {
+                        created: row.get(0)?,
+                        changed: row.get(1)?,
+                        accessed: row.get(2)?,
+                        uid: row.get(3)?,
+                        mode: row.get(4)?,
+                        device: row.get(5)?,
+                        inode: row.get(6)?,
+                        links: row.get(7)?,
+                    })
+                },
+            )
+            .optional()?)
+    }
+
+    /// Replaces the metadata of a file, e.g. after it was hardlinked by --dedupe.
+    pub fn set_file_meta(&self, file_id: i64, meta: &FileMeta) -> Result<()> {
+        self.db.execute(
+            "INSERT OR REPLACE INTO file_meta \
+             (id, created, changed, accessed, uid, mode, dev, inode, links) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![
+                file_id,
+                meta.created,
+                meta.changed,
+                meta.accessed,
+                meta.uid,
+                meta.mode,
+                meta.device,
+                meta.inode,
+                meta.links
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Identity and number of hardlinks of every file whose identity was collected.
+    pub fn get_file_identities(&self) -> Result<HashMap<i64, (FileIdentity, i64)>> {
+        let mut stmt = self.db.prepare(
+            "SELECT id, dev, inode, links FROM file_meta \
+             WHERE dev IS NOT NULL AND inode IS NOT NULL AND links IS NOT NULL",
+        )?;
+        let identities: Result<HashMap<_, _>, _> = stmt
+            .query_map([], |row| {
+                let identity = FileIdentity {
+                    device: row.get(1)?,
+                    inode: row.get(2)?,
+                };
+                Ok((row.get(0)?, (identity, row.get(3)?)))
+            })?
+            .collect();
+        Ok(identities?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    #[cfg(unix)]
+    fn test_read_file_meta() -> Result<()> {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+        let dir = tempdir()?;
+        let path = dir.path().join("original.jpg");
+        fs::write(&path, "original")?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640))?;
+        let meta = read_file_meta(&path)?;
+        assert_eq!(meta.mode, Some(0o640));
+        assert_eq!(meta.permissions().as_deref(), Some("rw-r-----"));
+        assert_eq!(meta.uid, Some(fs::metadata(dir.path())?.uid()));
+        assert!(meta.changed.is_some());
+        assert!(meta.accessed.is_some());
+        // file systems without birth times leave it out instead of failing
+        if let Ok(created) = fs::metadata(&path)?.created() {
+            assert_eq!(meta.created, unix_seconds(Ok(created)));
+        } else {
+            assert_eq!(meta.created, None);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_passwd() {
+        let passwd = "root:x:0:0:root:/root:/bin/bash\n\
+                      # comment\n\
+                      alice:x:1000:1000::/home/alice:/bin/sh\n";
+        let users: Vec<_> = parse_passwd(passwd).collect();
+        assert_eq!(users, [("root", 0), ("alice", 1000)]);
+        assert_eq!(user_id("1001").unwrap(), 1001);
+    }
+}
@@ -0,0 +1,172 @@
+use anyhow::{anyhow, Result};
+use rusqlite::{params, OptionalExtension};
+use std::path::Path;
+
+use crate::database::Database;
+use crate::stillpreview;
+use crate::videohash;
+
+/// Number of frames sampled from each video.
+pub const STRIP_FRAMES: usize = 5;
+
+/// Frames are scaled to this height, keeping the aspect ratio.
+const FRAME_HEIGHT: u32 = 120;
+
+/// The kind under which strips are cached in the thumbnails table.
+pub const STRIP_KIND: &str = "strip";
+
+/// Evenly spaced times to sample `n` frames at, each in the middle of its part of the
+/// video, so that neither black first frames nor end credits are picked.
+pub fn strip_times(duration: f64, n: usize) -> Vec<f64> {
+    (0..n)
+        .map(|i| duration * (i as f64 + 0.5) / n as f64)
+        .collect()
+}
+
+/// Puts RGB24 frames of width x height next to each other. Frames that couldn't be
+/// decoded are left black.
+fn composite(frames: &[Option<Vec<u8>>], width: u32, height: u32) -> Vec<u8> {
+    let row_len = width as usize * 3;
+    let mut strip = Vec::with_capacity(row_len * frames.len() * height as usize);
+    for y in 0..height as usize {
+        for frame in frames {
+            match frame {
+                Some(pixels) => strip.extend(&pixels[y * row_len..(y + 1) * row_len]),
+                None => strip.resize(strip.len() + row_len, 0),
+            }
+        }
+    }
+    strip
+}
+
+/// Samples `STRIP_FRAMES` frames of a video and puts them next to each other, as BMP
+/// like the previews of still images.
+pub fn render_strip(path: &Path) -> Result<Vec<u8>> {
+    let info = videohash::probe(path)?;
+    let (width, height) = match (info.width, info.height) {
+        (Some(w), Some(h)) if w > 0 && h > 0 => {
+            let w = (w as u64 * FRAME_HEIGHT as u64 / h as u64).max(1) as u32;
+            (w, FRAME_HEIGHT)
+        }
+        _ => return Err(anyhow!("No video found in {}", path.to_string_lossy())),
+    };
+    let duration = info
+        .duration
+        .ok_or_else(|| anyhow!("Unknown duration of {}", path.to_string_lossy()))?;
+    let times = strip_times(duration, STRIP_FRAMES);
+    let frames: Vec<Option<Vec<u8>>> = videohash::frames_at(path, &times, width, height)?
+        .into_iter()
+        .map(|f| f.map(|f| f.pixels))
+        .collect();
+    if frames.iter().all(Option::is_none) {
+        return Err(anyhow!("Unable to decode {}", path.to_string_lossy()));
+    }
+    let pixels = composite(&frames, width, height);
+    Ok(stillpreview::encode_bmp(
+        width * STRIP_FRAMES as u32,
+        height,
+        &pixels,
+    ))
+}
+
+impl Database {
+    /// A cached image of a file, None if there is none or the file changed since.
+    pub fn get_thumbnail(&self, file_id: i64, kind: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self
+            .db
+            .query_row(
+                "SELECT t.data FROM thumbnails t \
+                 JOIN file_digests f ON f.id = t.id AND f.digest = t.digest \
+                 WHERE t.id = ?1 AND t.kind = ?2",
+                params![file_id, kind],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    /// Caches an image rendered from the current content of a file.
+    pub fn store_thumbnail(&self, file_id: i64, kind: &str, data: &[u8]) -> Result<()> {
+        self.db.execute(
+            "INSERT OR REPLACE INTO thumbnails (id, kind, digest, data) \
+             SELECT id, ?2, digest, ?3 FROM file_digests WHERE id = ?1",
+            params![file_id, kind, data],
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::FileDigest;
+
+    #[test]
+    fn test_strip_times() {
+        assert_eq!(strip_times(10.0, 5), [1.0, 3.0, 5.0, 7.0, 9.0]);
+        assert!(strip_times(10.0, 0).is_empty());
+    }
+
+    #[test]
+    fn test_composite() {
+        // two frames of 2x2 pixels, the second one missing
+        let frame: Vec<u8> = (0..12).collect();
+        let strip = composite(&[Some(frame), None], 2, 2);
+        assert_eq!(
+            strip,
+            [
+                0, 1, 2, 3, 4, 5, 0, 0, 0, 0, 0, 0, //
+                6, 7, 8, 9, 10, 11, 0, 0, 0, 0, 0, 0
+            ]
+        );
+    }
+
+    #[test]
+    fn test_strips_are_cached_per_content() -> Result<()> {
+        let db = Database::new("test_strips_are_cached_per_content.sqlite", true)?;
+        db.insert_filedigest(&FileDigest::new(0, "/tmp/a.mp4", vec![1; 8], 10))?;
+        let id = db.get_all_filedigests()?[0].id;
+        assert_eq!(db.get_thumbnail(id, STRIP_KIND)?, None);
+        db.store_thumbnail(id, STRIP_KIND, b"strip")?;
+        assert_eq!(db.get_thumbnail(id, STRIP_KIND)?, Some(b"strip".to_vec()));
+        assert_eq!(db.get_thumbnail(id, "other")?, None);
+
+        // the file changed
+        db.db.execute(
+            "UPDATE file_digests SET digest = ?1 WHERE id = ?2",
+            params![vec![2u8; 8], id],
+        )?;
+        assert_eq!(db.get_thumbnail(id, STRIP_KIND)?, None);
+        db.delete_filedigest(id)?;
+        db.store_thumbnail(id, STRIP_KIND, b"strip")?;
+        assert_eq!(db.get_thumbnail(id, STRIP_KIND)?, None);
+        Ok(())
+    }
+
+    // needs the ffmpeg command line tool to create the clip
+    #[test]
+    #[ignore]
+    fn test_frames_at_requested_times() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let clip = dir.path().join("clip.mp4");
+        // 10 seconds with a key frame every second
+        let status = std::process::Command::new("ffmpeg")
+            .args(["-loglevel", "error", "-f", "lavfi", "-i"])
+            .arg("testsrc=duration=10:size=160x120:rate=10")
+            .args(["-g", "10", "-pix_fmt", "yuv420p"])
+            .arg(&clip)
+            .status()?;
+        assert!(status.success());
+
+        let times = strip_times(10.0, STRIP_FRAMES);
+        let frames = videohash::frames_at(&clip, &times, 160, 120)?;
+        assert_eq!(frames.len(), STRIP_FRAMES);
+        for (frame, requested) in frames.iter().zip(times.iter()) {
+            let frame = frame.as_ref().expect("frame was not decoded");
+            assert_eq!(frame.pixels.len(), 160 * 120 * 3);
+            let time = frame.time.expect("frame has no timestamp");
+            assert!(time <= *requested && requested - time <= 1.0);
+        }
+        assert!(!render_strip(&clip)?.is_empty());
+        Ok(())
+    }
+}
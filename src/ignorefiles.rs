@@ -0,0 +1,57 @@
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use std::path::Path;
+use std::rc::Rc;
+
+/// Files listing paths to leave out of scans with --use-gitignore, in the syntax of
+/// .gitignore. They apply to the directory they are in and everything below it.
+pub const IGNORE_FILE_NAMES: [&str; 2] = [".gitignore", ".duplettiignore"];
+
+/// The ignore files of a directory, followed by those of the directories above it up to
+/// the scan root. Patterns of deeper files take precedence, so they can re-include paths
+/// with `!`.
+pub struct IgnoreFiles {
+    matcher: Gitignore,
+    parent: Option<Rc<IgnoreFiles>>,
+}
+
+impl IgnoreFiles {
+    /// Adds the ignore files in `dir` to `parent`, which is returned as it is if there
+    /// are none.
+    pub fn read(dir: &Path, parent: Option<Rc<IgnoreFiles>>) -> Option<Rc<IgnoreFiles>> {
+        let files: Vec<_> = IGNORE_FILE_NAMES
+            .iter()
+            .map(|name| dir.join(name))
+            .filter(|f| f.is_file())
+            .collect();
+        if files.is_empty() {
+            return parent;
+        }
+        let mut builder = GitignoreBuilder::new(dir);
+        for file in &files {
+            // the valid patterns are still used
+            if let Some(e) = builder.add(file) {
+                log::warn!("Unable to read all of {:?}: {}", file, e);
+            }
+        }
+        match builder.build() {
+            Ok(matcher) => Some(Rc::new(IgnoreFiles { matcher, parent })),
+            Err(e) => {
+                log::warn!("Unable to use the ignore files in {:?}: {}", dir, e);
+                parent
+            }
+        }
+    }
+
+    /// Whether `path`, which is below the directory of the ignore files, is left out.
+    pub fn ignores(&self, path: &Path, is_dir: bool) -> bool {
+        match self.matcher.matched(path, is_dir) {
+            Match::Ignore(_) => true,
+            Match::Whitelist(_) => false,
+            Match::None => self
+                .parent
+                .as_ref()
+                .is_some_and(|p| p.ignores(path, is_dir)),
+        }
+    }
+}
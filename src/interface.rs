@@ -1,38 +1,245 @@
-use crate::database::Database;
-use crate::similarities;
+use crate::acks::{self, SeenFilter};
+use crate::backup::BackupPolicy;
+use crate::bursts;
+use crate::config::EffectiveConfig;
+use crate::database::{Database, PurgeRefused};
+use crate::dblock::{ChangeWatcher, DbVersion};
+use crate::dbpool::DatabasePool;
+use crate::filemeta::{self, FileMeta};
+use crate::framestrips;
+use crate::jobs::{Job, JobQueue, JobRequest};
+use crate::paths::PathCase;
+use crate::placeholders::SkippedFile;
+use crate::queue::{FileOutcome, Outcome, QueueReport, QueueTotals, QueuedAction, QueuedFile};
+use crate::rawpreview;
+use crate::shutdown;
+use crate::similarities::{self, ExistenceCache, GroupFilter, KeepPolicy};
+use crate::singleflight::SingleFlight;
+use crate::stale::{self, StaleAge};
+use crate::stillpreview::{self, StillFormat};
+use crate::tombstones;
+use crate::triage::{BulkAction, BulkCounts};
+use crate::verify;
 use crate::videohash;
 use anyhow::{anyhow, Result};
 use log;
 use ndarray::prelude::*;
-use rouille::{router, Response};
+use rouille::{router, Request, Response};
 use rusqlite::params;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::fs;
-use std::sync::{Arc, Mutex};
+use std::io::{BufWriter, Read, Write};
+use std::net::IpAddr;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 use tera::{Context as TeraContext, Tera};
 
+/// An error that should be reported to the client with a specific HTTP status.
+/// Every other error is reported as 500.
+#[derive(Debug, PartialEq)]
+pub struct ApiError {
+    pub status: u16,
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn new(status: u16, message: impl Into<String>) -> ApiError {
+        ApiError {
+            status,
+            message: message.into(),
+        }
+    }
+
+    pub fn bad_request(message: impl Into<String>) -> ApiError {
+        ApiError::new(400, message)
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    status: u16,
+    message: String,
+}
+
+#[derive(Serialize)]
+struct ErrorEnvelope {
+    error: ErrorBody,
+}
+
+/// Turns any error into the JSON error envelope, using the status of an `ApiError` if there is one.
+fn error_response(e: &anyhow::Error) -> Response {
+    let status = e.downcast_ref::<ApiError>().map_or(500, |e| e.status);
+    let envelope = ErrorEnvelope {
+        error: ErrorBody {
+            status,
+            message: e.to_string(),
+        },
+    };
+    Response::json(&envelope).with_status_code(status)
+}
+
+/// Most groups or files a single page lists.
+const MAX_PER_PAGE: usize = 1000;
+/// Most groups a single request names or changes.
+const MAX_LIST_LEN: usize = 10_000;
+
+/// Parses a page size query parameter, `default` if it isn't given.
+fn per_page_param(request: &Request, name: &str, default: usize) -> Result<usize> {
+    match request.get_param(name) {
+        None => Ok(default),
+        Some(value) => match value.parse::<usize>() {
+            Ok(n) if (1..=MAX_PER_PAGE).contains(&n) => Ok(n),
+            _ => Err(ApiError::bad_request(format!(
+                "{} must be a number from 1 to {}",
+                name, MAX_PER_PAGE
+            ))
+            .into()),
+        },
+    }
+}
+
+/// Parses --max-group-size, which is also the default page size of the web interface.
+pub fn parse_max_group_size(s: &str) -> Result<usize> {
+    match s.parse()? {
+        n if (1..=MAX_PER_PAGE).contains(&n) => Ok(n),
+        _ => Err(anyhow!(
+            "--max-group-size must be from 1 to {}",
+            MAX_PER_PAGE
+        )),
+    }
+}
+
+/// Refuses requests naming or changing more than `MAX_LIST_LEN` groups at once.
+fn check_list_len(what: &str, len: usize) -> Result<()> {
+    if len > MAX_LIST_LEN {
+        return Err(ApiError::bad_request(format!(
+            "{} lists {} groups, at most {} are allowed",
+            what, len, MAX_LIST_LEN
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+/// Rejects thresholds that can never change the clustering result.
+fn check_threshold(threshold: u16) -> Result<u16> {
+    let max_threshold = videohash::max_threshold();
+    if threshold > max_threshold {
+        return Err(ApiError::bad_request(format!(
+            "threshold {} out of range, valid range is 0 to {}",
+            threshold, max_threshold
+        ))
+        .into());
+    }
+    Ok(threshold)
+}
+
+/// Reads the request body, refusing anything larger than `max_body_size` bytes.
+fn read_body(request: &Request, max_body_size: usize) -> Result<Vec<u8>> {
+    let too_large = || ApiError::new(413, format!("request body exceeds {} bytes", max_body_size));
+    let content_length = request
+        .header("Content-Length")
+        .and_then(|l| l.parse::<usize>().ok());
+    if content_length.is_some_and(|l| l > max_body_size) {
+        return Err(too_large().into());
+    }
+    let mut body = Vec::new();
+    if let Some(data) = request.data() {
+        data.take(max_body_size as u64 + 1).read_to_end(&mut body)?;
+    }
+    if body.len() > max_body_size {
+        return Err(too_large().into());
+    }
+    Ok(body)
+}
+
 impl Database {
-    fn rename_file(&self, file_id: i64, new_path: String) -> Result<()> {
+    /// Points the file, and rows in other tables that refer to it by path, to `new_path`.
+    fn rename_file(&self, file_id: i64, old_path: &Path, new_path: &str) -> Result<()> {
         self.db.execute(
-            "UPDATE file_digests SET path = (?1) WHERE id =(?2)",
+            "UPDATE file_digests SET path = (?1), path_key = NULL WHERE id =(?2)",
             params![new_path, file_id],
         )?;
+        self.db.execute(
+            "UPDATE OR REPLACE pending_files SET path = ?1 WHERE path = ?2",
+            params![new_path, old_path.to_string_lossy()],
+        )?;
+        self.refresh_root_id(file_id)?;
         log::debug!("DB: renaming {} to {}", file_id, new_path);
         Ok(())
     }
 }
 
-pub fn show_results_in_console(result: &Vec<Vec<similarities::FileEntry>>) {
-    let mut total_size_saved = 0;
+/// Describes a list of groups that was cut short by --limit-results.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ResultsLimit {
+    /// Number of groups without the limit
+    pub num_groups: usize,
+    /// Bytes taken by all copies but one of all groups
+    pub wasted_total: u64,
+    /// Whether to report `wasted_total` instead of the total of the shown groups
+    pub full_totals: bool,
+}
+
+/// The summary of the console output.
+fn total_saved_line(saved: u64, num_shown: usize, limit: Option<&ResultsLimit>) -> String {
+    let gb = |bytes: u64| bytes as f64 / (1024.0 * 1024.0 * 1024.0);
+    match limit {
+        Some(limit) if limit.full_totals => format!(
+            "Total saved size: {:.2} GB in {} groups, the {} largest are shown",
+            gb(limit.wasted_total),
+            limit.num_groups,
+            num_shown
+        ),
+        Some(limit) => format!(
+            "Total saved size: {:.2} GB (only the {} largest of {} groups, pass --full-totals for all)",
+            gb(saved),
+            num_shown,
+            limit.num_groups
+        ),
+        None => format!("Total saved size: {:.2} GB", gb(saved)),
+    }
+}
+
+pub fn show_results_in_console(
+    result: &Vec<similarities::FileGroup>,
+    keep_policy: KeepPolicy,
+    limit: Option<&ResultsLimit>,
+) {
     let mut print_nl = false;
     for bag in result {
-        for (i, f) in bag.iter().enumerate() {
-            if i > 0 {
-                total_size_saved += f.size;
-            }
+        for f in bag.files.iter() {
             let s = f.size as f64 / (1024. * 1024. * 1024.);
             if s > 1.0 {
                 let p = f.path.to_string_lossy();
-                println!("{0:>4.2} GB: {1}", s, p);
+                let mut marks: Vec<String> = f.tags.iter().map(|t| format!("#{}", t)).collect();
+                if f.protected {
+                    marks.insert(0, "protected".to_string());
+                }
+                if bag.dismissed {
+                    marks.insert(0, "dismissed".to_string());
+                }
+                if marks.is_empty() {
+                    println!("{0:>4.2} GB: {1}", s, p);
+                } else {
+                    println!("{0:>4.2} GB: {1} [{2}]", s, p, marks.join(", "));
+                }
                 print_nl = true;
             }
         }
@@ -42,52 +249,378 @@ pub fn show_results_in_console(result: &Vec<Vec<similarities::FileEntry>>) {
         }
     }
 
-    let total_size_gb = total_size_saved as f64 / (1024.0 * 1024.0 * 1024.0);
-    println!("Total saved size: {:.2} GB", total_size_gb);
+    let reclaimable =
+        similarities::reclaimable_bytes(result.iter().map(|g| g.files.as_slice()), keep_policy);
+    println!(
+        "{}",
+        total_saved_line(reclaimable.total, result.len(), limit)
+    );
+}
+
+/// A rendered results page, with the ids of the groups that couldn't be rendered.
+#[derive(Debug)]
+pub struct RenderedResults {
+    pub html: String,
+    pub skipped_groups: Vec<String>,
 }
 
 pub fn render_results_to_html(
-    result: &Vec<Vec<similarities::FileEntry>>,
+    result: &[similarities::FileGroup],
     tera: &Tera,
     allow_preview: bool,
-) -> Result<String> {
+    keep_policy: KeepPolicy,
+    num_excluded: usize,
+) -> Result<RenderedResults> {
     log::debug!("rendering to HTML");
+    let (context, skipped_groups) = results_context(
+        result,
+        tera,
+        allow_preview,
+        keep_policy,
+        num_excluded,
+        ResultsView::List,
+    );
+    Ok(RenderedResults {
+        html: tera.render("results.html.tera", &context)?,
+        skipped_groups,
+    })
+}
+
+/// What a static report shows besides the groups, see `write_results_html`.
+#[derive(Debug, Serialize)]
+pub struct ReportInfo {
+    /// Unix seconds
+    pub generated_at: i64,
+    pub roots: Vec<String>,
+    /// Clusters of similar videos, empty if no videos were hashed
+    pub videos: Vec<videohash::ClusterReport>,
+    pub videohash_threshold: u16,
+}
+
+/// Renders the groups one by one, see `render_group`, so the page only needs them as HTML.
+fn results_context(
+    result: &[similarities::FileGroup],
+    tera: &Tera,
+    allow_preview: bool,
+    keep_policy: KeepPolicy,
+    num_excluded: usize,
+    view: ResultsView,
+) -> (TeraContext, Vec<String>) {
+    let reclaimable = similarities::groups_reclaimable(result, keep_policy);
+    let mut groups = Vec::new();
+    let mut skipped_groups = Vec::new();
+    for (group, bytes) in result.iter().zip(reclaimable.per_group.iter()) {
+        let (html, rendered) = render_group(tera, view, group, *bytes, allow_preview, false);
+        if !rendered {
+            skipped_groups.push(group.group_id.clone());
+        }
+        groups.push(html);
+    }
     let mut context = TeraContext::new();
-    context.insert("result", result);
+    context.insert("groups", &groups);
+    context.insert("skipped_groups", &skipped_groups);
+    context.insert("num_groups", &result.len());
+    context.insert("num_excluded", &num_excluded);
+    context.insert("reclaimable_total", &reclaimable.total);
     context.insert("allow_preview", &allow_preview);
-    let html = tera.render("results.html.tera", &context)?;
-    Ok(html)
+    (context, skipped_groups)
+}
+
+/// The HTML of a single group. If rendering fails, e.g. for a file with a date tera
+/// can't format, the group is replaced by a note naming the files that fail on their
+/// own, so a single bad group doesn't take down the whole page. Returns whether the
+/// group was rendered. Groups of a `static_report` link files directly and have no
+/// buttons.
+fn render_group(
+    tera: &Tera,
+    view: ResultsView,
+    group: &similarities::FileGroup,
+    group_reclaimable: u64,
+    allow_preview: bool,
+    static_report: bool,
+) -> (String, bool) {
+    let render = |group: &serde_json::Value| -> Result<String> {
+        let mut context = TeraContext::new();
+        context.insert("group", group);
+        context.insert("group_reclaimable", &group_reclaimable);
+        context.insert("allow_preview", &allow_preview);
+        context.insert("static_report", &static_report);
+        // the date filter panics for timestamps chrono can't represent
+        panic::catch_unwind(AssertUnwindSafe(|| {
+            tera.render(view.group_template(), &context)
+        }))
+        .map_err(|_| anyhow!("rendering panicked"))?
+        .map_err(anyhow::Error::from)
+    };
+    let value = serde_json::to_value(group).unwrap_or_default();
+    let error = match render(&value) {
+        Ok(html) => return (html, true),
+        Err(e) => e,
+    };
+    let bad_files: Vec<String> = group
+        .files
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| {
+            let mut single = value.clone();
+            single["files"] = serde_json::Value::Array(vec![value["files"][*i].clone()]);
+            render(&single).is_err()
+        })
+        .map(|(_, file)| file.id.to_string())
+        .collect();
+    log::error!(
+        "Unable to render group {} (bad files: {:?}): {:#}",
+        group.group_id,
+        bad_files,
+        error
+    );
+    let culprit = if bad_files.is_empty() {
+        String::new()
+    } else {
+        format!(" because of file {}", bad_files.join(", "))
+    };
+    let html = format!(
+        "    <ul class=\"group render_error\" data-group-id=\"{}\">\n        \
+         <li class=\"groupinfo\">This group couldn't be shown{}, see the log</li>\n    </ul>\n",
+        group.group_id, culprit
+    );
+    (html, false)
+}
+
+/// How the results page lays out the groups. Both views are rendered from the same
+/// context, the gallery shows thumbnails of images when previews are allowed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResultsView {
+    List,
+    Gallery,
+}
+
+/// Cookie that remembers the last view chosen with the `view` parameter.
+const VIEW_COOKIE: &str = "view";
+
+/// Cookie that tells browsers apart for remembering which groups they saw, see `acks`.
+const SESSION_COOKIE: &str = "session";
+
+/// How long the browser keeps cookies, a year.
+const COOKIE_MAX_AGE: u64 = 365 * 24 * 60 * 60;
+
+fn cookie(request: &Request, name: &str) -> Option<String> {
+    rouille::input::cookies(request)
+        .find(|(n, _)| *n == name)
+        .map(|(_, value)| value.to_string())
+}
+
+fn set_cookie(name: &str, value: &str) -> String {
+    format!(
+        "{}={}; Path=/; SameSite=Lax; Max-Age={}",
+        name, value, COOKIE_MAX_AGE
+    )
+}
+
+/// The session of the browser, and whether it is new and has to be set as cookie.
+fn session_param(request: &Request) -> (String, bool) {
+    match cookie(request, SESSION_COOKIE).filter(|s| !s.is_empty()) {
+        Some(session) => (session, false),
+        None => (hex::encode(rand::random::<[u8; 16]>()), true),
+    }
+}
+
+/// Parses the optional `only` parameter, see `acks::SeenFilter`.
+/// Only groups that were ignored for this many days, e.g. `?stale=180`.
+fn stale_param(request: &Request) -> Result<Option<StaleAge>> {
+    request
+        .get_param("stale")
+        .map(|stale| {
+            stale
+                .parse()
+                .map_err(|e: anyhow::Error| ApiError::bad_request(e.to_string()).into())
+        })
+        .transpose()
+}
+
+fn seen_param(request: &Request) -> Result<Option<SeenFilter>> {
+    request
+        .get_param("only")
+        .map(|only| {
+            only.parse()
+                .map_err(|e: anyhow::Error| ApiError::bad_request(e.to_string()).into())
+        })
+        .transpose()
+}
+
+impl ResultsView {
+    fn name(&self) -> &'static str {
+        match self {
+            ResultsView::List => "list",
+            ResultsView::Gallery => "gallery",
+        }
+    }
+
+    fn group_template(&self) -> &'static str {
+        match self {
+            ResultsView::List => "results_group.html.tera",
+            ResultsView::Gallery => "gallery_group.html.tera",
+        }
+    }
+}
+
+impl FromStr for ResultsView {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<ResultsView> {
+        match s {
+            "list" => Ok(ResultsView::List),
+            "gallery" => Ok(ResultsView::Gallery),
+            _ => Err(anyhow!("view must be list or gallery, not {}", s)),
+        }
+    }
+}
+
+/// The view asked for by the `view` parameter, and whether it was given. Without it,
+/// the view of the cookie is used, and the list otherwise.
+fn view_param(request: &Request) -> Result<(ResultsView, bool)> {
+    if let Some(view) = request.get_param("view") {
+        let view = view
+            .parse()
+            .map_err(|e: anyhow::Error| ApiError::bad_request(e.to_string()))?;
+        return Ok((view, true));
+    }
+    let view = cookie(request, VIEW_COOKIE)
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(ResultsView::List);
+    Ok((view, false))
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn render_results_view(
+    result: &[similarities::FileGroup],
+    tera: &Tera,
+    allow_preview: bool,
+    keep_policy: KeepPolicy,
+    num_excluded: usize,
+    view: ResultsView,
+    queued: Option<QueueTotals>,
+    videohash_threshold: Option<u16>,
+) -> Result<RenderedResults> {
+    let (mut context, skipped_groups) =
+        results_context(result, tera, allow_preview, keep_policy, num_excluded, view);
+    context.insert("view", view.name());
+    if let Some(queued) = queued {
+        context.insert("queued", &queued);
+    }
+    if let Some(threshold) = videohash_threshold {
+        context.insert("videohash_threshold", &threshold);
+    }
+    Ok(RenderedResults {
+        html: tera.render("results.html.tera", &context)?,
+        skipped_groups,
+    })
+}
+
+/// Writes the same HTML as `render_results_to_html`, but renders the groups one by one
+/// into `out`. The context and the rendered output then only ever hold a single group,
+/// instead of all of them, which matters for reports with tens of thousands of groups.
+/// The header is written before the groups are rendered, so groups that can't be rendered
+/// are only returned, not listed at the top.
+///
+/// With `report`, the page is a static report that works without the web interface: the
+/// styles are inline, files are linked with file:// URLs, and there are no buttons or
+/// scripts.
+pub fn write_results_html<W: Write>(
+    out: W,
+    result: &[similarities::FileGroup],
+    tera: &Tera,
+    allow_preview: bool,
+    keep_policy: KeepPolicy,
+    num_excluded: usize,
+    report: Option<&ReportInfo>,
+) -> Result<Vec<String>> {
+    log::debug!("streaming HTML for {} groups", result.len());
+    let mut out = BufWriter::new(out);
+    let reclaimable = similarities::groups_reclaimable(result, keep_policy);
+    let mut context = TeraContext::new();
+    context.insert("num_groups", &result.len());
+    context.insert("num_excluded", &num_excluded);
+    context.insert("reclaimable_total", &reclaimable.total);
+    context.insert("allow_preview", &allow_preview);
+    if let Some(info) = report {
+        context.insert("report", info);
+    }
+    tera.render_to("results_header.html.tera", &context, &mut out)?;
+    let mut skipped_groups = Vec::new();
+    for (group, bytes) in result.iter().zip(reclaimable.per_group.iter()) {
+        let (html, rendered) = render_group(
+            tera,
+            ResultsView::List,
+            group,
+            *bytes,
+            allow_preview,
+            report.is_some(),
+        );
+        if !rendered {
+            skipped_groups.push(group.group_id.clone());
+        }
+        out.write_all(html.as_bytes())?;
+    }
+    tera.render_to("results_footer.html.tera", &context, &mut out)?;
+    out.flush()?;
+    Ok(skipped_groups)
 }
 
 pub fn render_videohash_results_to_html(
-    result: Vec<Vec<&videohash::VideoHash>>,
+    result: Vec<videohash::VideoGroup<'_>>,
     tera: &Tera,
     allow_preview: bool,
+    keep_policy: KeepPolicy,
 ) -> Result<String> {
     log::debug!("rendering to HTML");
+    let reclaimable =
+        similarities::reclaimable_bytes(result.iter().map(|g| g.files.as_slice()), keep_policy);
     let mut context = TeraContext::new();
     context.insert("result", &result);
+    context.insert("reclaimable", &reclaimable);
     context.insert("allow_preview", &allow_preview);
     let html = tera.render("videohash.html.tera", &context)?;
     Ok(html)
 }
 
+/// Renames a file on disk and in the database. Refuses to if the file is gone or
+/// something already exists at `new_name`, which would be replaced. The database changes
+/// are only committed once the file was renamed, and the file is renamed back if the
+/// commit fails.
 fn rename_file(db: &Database, id: i64, new_name: String) -> Result<&str> {
     let file = db.lookup_filedigest(id)?;
-    let status = if file.path.exists() {
-        fs::rename(file.path, &new_name)?;
-        "success"
-    } else {
-        "does-not-exist"
-    };
-    db.rename_file(id, new_name)?;
-    Ok(status)
+    if fs::symlink_metadata(&file.path).is_err() {
+        return Err(ApiError::new(
+            404,
+            format!("{} does not exist", file.path.to_string_lossy()),
+        )
+        .into());
+    }
+    if fs::symlink_metadata(&new_name).is_ok() {
+        return Err(ApiError::new(409, format!("{} already exists", new_name)).into());
+    }
+    let tx = db.db.unchecked_transaction()?;
+    db.rename_file(id, &file.path, &new_name)?;
+    fs::rename(&file.path, &new_name)?;
+    if let Err(e) = tx.commit() {
+        fs::rename(&new_name, &file.path)?;
+        return Err(e.into());
+    }
+    Ok("success")
 }
 
-fn delete_file(db: &Database, id: i64) -> Result<&str> {
+/// Deletes a file from disk and the database, unless it is protected.
+pub(crate) fn delete_file(db: &Database, id: i64) -> Result<&str> {
     let file = db.lookup_filedigest(id)?;
+    if db.is_protected(id)? {
+        return Err(
+            ApiError::new(403, format!("{} is protected", file.path.to_string_lossy())).into(),
+        );
+    }
     let status = if file.path.exists() {
-        fs::remove_file(file.path)?;
+        fs::remove_file(&file.path)?;
+        db.add_tombstone(&file)?;
         "success"
     } else {
         "does-not-exist"
@@ -96,148 +629,1872 @@ fn delete_file(db: &Database, id: i64) -> Result<&str> {
     Ok(status)
 }
 
+/// How long the existence of files is cached when rendering results.
+const EXISTENCE_CACHE_TTL: Duration = Duration::from_secs(10);
+
+/// Parses the optional filter query parameters `path`, `min_size`, `type`, `cross_dirs`,
+/// `root`, `cross_roots`, `added_after`, `include_dismissed` and `show_hardlinks`.
+fn group_filter_param(request: &Request) -> Result<GroupFilter> {
+    let invalid = |e: anyhow::Error| anyhow::Error::from(ApiError::bad_request(e.to_string()));
+    Ok(GroupFilter {
+        path: request.get_param("path").filter(|p| !p.is_empty()),
+        min_size: request
+            .get_param("min_size")
+            .map(|m| m.parse().map_err(|_| anyhow!("Invalid min_size {}", m)))
+            .transpose()
+            .map_err(invalid)?,
+        file_type: request
+            .get_param("type")
+            .map(|t| t.parse())
+            .transpose()
+            .map_err(invalid)?,
+        cross_dirs: matches!(
+            request.get_param("cross_dirs").as_deref(),
+            Some("true") | Some("1")
+        ),
+        root: request.get_param("root").filter(|r| !r.is_empty()),
+        cross_roots: matches!(
+            request.get_param("cross_roots").as_deref(),
+            Some("true") | Some("1")
+        ),
+        added_after: request
+            .get_param("added_after")
+            .map(|d| similarities::parse_date(&d))
+            .transpose()
+            .map_err(invalid)?,
+        include_dismissed: matches!(
+            request.get_param("include_dismissed").as_deref(),
+            Some("true") | Some("1")
+        ),
+        show_hardlinks: matches!(
+            request.get_param("show_hardlinks").as_deref(),
+            Some("true") | Some("1")
+        ),
+    })
+}
+
+/// Groups computed for the results, by the maximum number of listed members and the
+/// limit of groups. Only a few of them are kept, see `SingleFlight`.
+pub type ResultsCache = SingleFlight<(Option<usize>, Option<usize>), Vec<similarities::FileGroup>>;
+
+/// With `limit`, only the groups with the most wasted space are looked up, before the
+/// filter is applied. The groups are computed once for each state of the database, and
+/// concurrent requests wait for the same computation.
+fn get_results_with_missing_marked(
+    pool: &DatabasePool,
+    results_cache: &ResultsCache,
+    existence_cache: &Mutex<ExistenceCache>,
+    max_group_size: Option<usize>,
+    limit: Option<usize>,
+    filter: &GroupFilter,
+) -> Result<Vec<similarities::FileGroup>> {
+    // versions are per connection, the cache goes by the writer's
+    let version = pool.writer()?.version()?;
+    let groups = results_cache.get((max_group_size, limit), version, || {
+        let db = pool.reader()?;
+        Ok(match limit {
+            Some(limit) => {
+                similarities::get_largest_similar_files(&db, max_group_size, limit)?.groups
+            }
+            None => similarities::get_collapsed_list_of_similar_files(&db, max_group_size)?,
+        })
+    })?;
+    let mut results = filter.apply(groups.as_ref().clone());
+    if let Ok(mut cache) = existence_cache.lock() {
+        cache.mark_missing(&mut results);
+    } else {
+        return Err(anyhow!("Unable to lock existence cache"));
+    }
+    Ok(results)
+}
+
+/// With `only`, the groups are filtered by what the session saw before. Either way, the
+/// listed groups count as seen afterwards. With `stale`, only stale groups are listed,
+/// the most wasted space first.
+#[allow(clippy::too_many_arguments)]
 fn handle_index_request(
-    db_mutex: &Mutex<Database>,
+    pool: &DatabasePool,
+    results_cache: &ResultsCache,
+    existence_cache: &Mutex<ExistenceCache>,
     tera: &Tera,
     allow_preview: bool,
+    keep_policy: KeepPolicy,
+    max_group_size: usize,
+    filter: &GroupFilter,
+    view: ResultsView,
+    only: Option<SeenFilter>,
+    stale: Option<StaleAge>,
+    session: &str,
+    videohash_threshold: Option<u16>,
+) -> Result<Response> {
+    let mut results = get_results_with_missing_marked(
+        pool,
+        results_cache,
+        existence_cache,
+        Some(max_group_size),
+        None,
+        filter,
+    )?;
+    let (num_excluded, queued) = {
+        let db = pool.reader()?;
+        if let Some(only) = only {
+            results = acks::filter_seen(results, &db.get_group_acks(session)?, only);
+        }
+        if let Some(age) = stale {
+            results = stale::retain_stale(results, &db.stale_groups(age, tombstones::now()?)?);
+        }
+        (db.count_excluded_files()?, db.queue_totals()?)
+    };
+    let db = pool.writer()?;
+    let before_acks = db.version()?;
+    db.ack_groups(session, &results)?;
+    // the groups don't depend on what was seen, so they stay current
+    results_cache.carry_over(before_acks, db.version()?)?;
+    drop(db);
+    let html = render_results_view(
+        &results,
+        tera,
+        allow_preview,
+        keep_policy,
+        num_excluded,
+        view,
+        Some(queued),
+        videohash_threshold,
+    )?
+    .html;
+    Ok(Response::html(html))
+}
+
+/// With `limit`, only the groups with the most wasted space are listed.
+fn handle_duplicates_api_request(
+    pool: &DatabasePool,
+    results_cache: &ResultsCache,
+    existence_cache: &Mutex<ExistenceCache>,
+    filter: &GroupFilter,
+    limit: Option<String>,
+) -> Result<Response> {
+    let limit = limit
+        .map(|l| l.parse())
+        .transpose()
+        .map_err(|_| ApiError::bad_request("limit must be a non-negative number"))?;
+    let results =
+        get_results_with_missing_marked(pool, results_cache, existence_cache, None, limit, filter)?;
+    Ok(Response::json(&results))
+}
+
+#[derive(Deserialize)]
+struct BulkRequest {
+    #[serde(flatten)]
+    filter: GroupFilter,
+    /// tag=<name>, dismiss or protect
+    action: String,
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct BulkResponse {
+    #[serde(flatten)]
+    counts: BulkCounts,
+    dry_run: bool,
+}
+
+/// Applies an action to all groups matching the same filters as the index page.
+fn handle_bulk_request(
+    db_mutex: &Mutex<Database>,
+    request: &Request,
+    max_body_size: usize,
 ) -> Result<Response> {
+    let body = read_body(request, max_body_size)?;
+    let bulk: BulkRequest = serde_json::from_slice(&body)
+        .map_err(|e| ApiError::bad_request(format!("invalid bulk request: {}", e)))?;
+    let action: BulkAction = bulk
+        .action
+        .parse()
+        .map_err(|e: anyhow::Error| ApiError::bad_request(e.to_string()))?;
     if let Ok(db) = db_mutex.lock() {
-        let results = similarities::get_list_of_similar_files(&db)?;
-        let html = render_results_to_html(&results, &tera, allow_preview).unwrap();
-        Ok(Response::html(html))
+        let groups = similarities::get_list_of_similar_files(&db)?;
+        if !bulk.dry_run {
+            let preview = db.apply_bulk_action(&groups, &bulk.filter, &action, true)?;
+            check_list_len("the bulk filter", preview.groups)?;
+        }
+        let counts = db.apply_bulk_action(&groups, &bulk.filter, &action, bulk.dry_run)?;
+        log::info!("Bulk {:?}: {:?}, dry run: {}", action, counts, bulk.dry_run);
+        Ok(Response::json(&BulkResponse {
+            counts,
+            dry_run: bulk.dry_run,
+        }))
     } else {
-        return Err(anyhow!("Unable to lock DB"));
+        Err(anyhow!("Unable to lock DB"))
     }
 }
 
-fn handle_preview_request(db_mutex: &Mutex<Database>, file_id: i64) -> Result<Response> {
+#[derive(Deserialize)]
+struct AckRequest {
+    group_ids: Vec<String>,
+    /// Defaults to the session cookie
+    #[serde(default)]
+    session: Option<String>,
+}
+
+#[derive(Serialize)]
+struct AckResponse {
+    acknowledged: usize,
+}
+
+/// Marks groups as seen with their current members, like listing them on the index page.
+fn handle_ack_request(
+    db_mutex: &Mutex<Database>,
+    request: &Request,
+    max_body_size: usize,
+) -> Result<Response> {
+    let body = read_body(request, max_body_size)?;
+    let ack: AckRequest = serde_json::from_slice(&body)
+        .map_err(|e| ApiError::bad_request(format!("invalid ack request: {}", e)))?;
+    let group_ids = ack.group_ids;
+    check_list_len("group_ids", group_ids.len())?;
+    let session = ack
+        .session
+        .or_else(|| cookie(request, SESSION_COOKIE))
+        .filter(|s| !s.is_empty())
+        .ok_or_else(|| ApiError::bad_request("no session given and no session cookie set"))?;
     if let Ok(db) = db_mutex.lock() {
-        let filepath = db.lookup_filedigest(file_id)?.path;
-        let extension = filepath.extension().and_then(|s| s.to_str()).unwrap_or("");
-        let file = fs::File::open(&filepath)?;
-        Ok(Response::from_file(rouille::extension_to_mime(extension), file).with_no_cache())
-    // files might be big, so don't cache them
+        let groups: Vec<similarities::FileGroup> = similarities::get_list_of_similar_files(&db)?
+            .into_iter()
+            .filter(|g| group_ids.contains(&g.group_id))
+            .collect();
+        db.ack_groups(&session, &groups)?;
+        Ok(Response::json(&AckResponse {
+            acknowledged: groups.len(),
+        }))
     } else {
-        return Err(anyhow!("Unable to lock DB"));
+        Err(anyhow!("Unable to lock DB"))
     }
 }
 
-pub struct VideoHashData {
-    pub hashes: Vec<videohash::VideoHash>,
-    pub distances: Array2<u16>,
+#[derive(Deserialize)]
+struct SwapRequest {
+    a: i64,
+    b: i64,
+    /// Instead of exchanging the paths, moves the content of `a` to the path of `b`.
+    /// Meant for similar videos, where the files differ.
+    #[serde(default)]
+    replace_content: bool,
+    /// The video hash threshold `a` and `b` have to be in one cluster at, for
+    /// `replace_content`
+    #[serde(default)]
+    threshold: Option<u16>,
 }
 
-impl VideoHashData {
-    pub fn new(db_mutex: &Mutex<Database>) -> Result<VideoHashData> {
-        let mut vhd = VideoHashData {
-            hashes: Vec::new(),
-            distances: Array::zeros((0, 0)),
-        };
-        vhd.refresh(db_mutex)?;
-        Ok(vhd)
-    }
+#[derive(Serialize)]
+struct SwapResponse {
+    status: &'static str,
+}
 
-    pub fn refresh(&mut self, db_mutex: &Mutex<Database>) -> Result<()> {
-        // We do everything within the DB-mutex so concurrent calls work w/o races.
-        if let Ok(db) = db_mutex.lock() {
-            self.hashes = db.get_all_files_with_videohash()?;
-            log::debug!("Num videohashs: {}", self.hashes.len());
-            self.distances = videohash::calculate_distances(&self.hashes);
-            log::debug!("Done with distance calculation");
-        } else {
-            return Err(anyhow!("Unable to lock DB"));
+/// Exchanges two exact duplicates, or replaces the content of one file with another.
+fn handle_swap_request(
+    db_mutex: &Mutex<Database>,
+    vhs_mutex: &Mutex<VideoHashState>,
+    request: &Request,
+    max_body_size: usize,
+    default_threshold: u16,
+) -> Result<Response> {
+    let body = read_body(request, max_body_size)?;
+    let swap: SwapRequest = serde_json::from_slice(&body)
+        .map_err(|e| ApiError::bad_request(format!("invalid swap request: {}", e)))?;
+    if swap.a == swap.b {
+        return Err(ApiError::bad_request("a and b must be different files").into());
+    }
+    if swap.replace_content {
+        let threshold = check_threshold(swap.threshold.unwrap_or(default_threshold))?;
+        let mut vhs = vhs_mutex
+            .lock()
+            .map_err(|_| anyhow!("Unable to lock video hashes"))?;
+        let vhd = vhs.data(db_mutex)?;
+        vhd.refresh(db_mutex)?;
+        if !vhd.same_cluster(swap.a, swap.b, threshold) {
+            return Err(ApiError::bad_request(format!(
+                "Files {} and {} are not similar videos at threshold {}",
+                swap.a, swap.b, threshold
+            ))
+            .into());
         }
-        Ok(())
     }
-
-    fn handle_request(&self, threshold: u16, tera: &Tera, allow_preview: bool) -> Result<Response> {
-        log::debug!("# Clustering with threshold {}", threshold);
-        let mut results = videohash::find_similar_files(&self.hashes, &self.distances, threshold);
-        // sort by filesize (maximum first)
-        let mut total_size_saved = 0;
-        for bag in results.iter() {
-            let mut max_size = 0;
-            for f in bag {
-                total_size_saved += f.size;
-                max_size = std::cmp::max(max_size, f.size);
+    if let Ok(db) = db_mutex.lock() {
+        let a = db.lookup_filedigest(swap.a)?;
+        let b = db.lookup_filedigest(swap.b)?;
+        for file in [&a, &b].iter() {
+            if db.is_protected(file.id)? {
+                return Err(ApiError::new(
+                    403,
+                    format!("{} is protected", file.path.to_string_lossy()),
+                )
+                .into());
+            }
+            if !file.path.exists() {
+                return Err(ApiError::new(
+                    404,
+                    format!("{} no longer exists", file.path.to_string_lossy()),
+                )
+                .into());
             }
-            total_size_saved -= max_size;
         }
-        let total_size_gb = total_size_saved as f64 / (1024.0 * 1024.0 * 1024.0);
-        log::info!("Max saved size by videohash: {:.2} GB", total_size_gb);
-        results.sort_unstable_by_key(|bag| bag.iter().map(|x| x.size).min());
-        results.reverse();
-        log::info!("# Clusters({}): {}", threshold, results.len());
-        let html = render_videohash_results_to_html(results, &tera, allow_preview)?;
-        Ok(Response::html(html))
+        if swap.replace_content {
+            db.replace_content(&a, &b)?;
+        } else {
+            if a.digest != b.digest {
+                return Err(ApiError::bad_request(
+                    "Only exact duplicates can be swapped, use replace_content for similar files",
+                )
+                .into());
+            }
+            db.swap_files(&a, &b)?;
+        }
+        Ok(Response::json(&SwapResponse { status: "success" }))
+    } else {
+        Err(anyhow!("Unable to lock DB"))
     }
 }
 
-fn handle_rename_request(
+#[derive(Deserialize, Default)]
+struct PurgeMissingRequest {
+    prefix: Option<PathBuf>,
+    /// Removes more files than the cleanup limits allow
+    #[serde(default)]
+    force: bool,
+}
+
+#[derive(Serialize)]
+struct PurgeMissingResponse {
+    removed: Vec<PathBuf>,
+}
+
+fn handle_purge_missing_request(
     db_mutex: &Mutex<Database>,
-    id: i64,
-    new_name: String,
+    request: &Request,
+    max_body_size: usize,
+    config: &EffectiveConfig,
 ) -> Result<Response> {
-    log::debug!("renaming {} to {}", id, new_name);
+    let body = read_body(request, max_body_size)?;
+    let purge: PurgeMissingRequest = if body.is_empty() {
+        PurgeMissingRequest::default()
+    } else {
+        serde_json::from_slice(&body)
+            .map_err(|e| ApiError::bad_request(format!("invalid purge request: {}", e)))?
+    };
     if let Ok(db) = db_mutex.lock() {
-        Ok(Response::text(rename_file(&db, id, new_name)?))
+        let prefix = purge.prefix.as_deref();
+        let path_case = prefix.map_or(PathCase::Sensitive, |p| PathCase::for_root(p, false));
+        db.backup(&config.backup, "purging missing files")?;
+        let limits = Some(config.scan.cleanup_limits).filter(|_| !purge.force);
+        let removed = db
+            .purge_missing(prefix, path_case, limits)
+            .map_err(|e| match e.downcast::<PurgeRefused>() {
+                Ok(refused) => ApiError::new(409, refused.0).into(),
+                Err(e) => e,
+            })?;
+        log::info!("Purged {} missing files", removed.len());
+        Ok(Response::json(&PurgeMissingResponse { removed }))
     } else {
-        return Err(anyhow!("Unable to lock DB"));
+        Err(anyhow!("Unable to lock DB"))
     }
 }
 
-fn handle_remove_request(db_mutex: &Mutex<Database>, id: i64) -> Result<Response> {
-    log::debug!("Deleting {}", id);
+fn handle_group_api_request(
+    pool: &DatabasePool,
+    vhs_mutex: &Mutex<VideoHashState>,
+    group_id: &str,
+    threshold: u16,
+) -> Result<Response> {
+    let not_found = || ApiError::new(404, format!("No group with id {}", group_id));
+    if group_id.starts_with('v') {
+        let threshold = check_threshold(threshold)?;
+        let mut vhs = vhs_mutex
+            .lock()
+            .map_err(|_| anyhow!("Unable to lock video hashes"))?;
+        let clusters = vhs.data(&pool.db_mutex())?.clusters(threshold);
+        let group = clusters
+            .iter()
+            .find(|g| g.group_id == group_id)
+            .ok_or_else(not_found)?;
+        return Ok(Response::json(group));
+    }
+    if !similarities::is_group_id(group_id) {
+        return Err(ApiError::bad_request(format!("Invalid group id {}", group_id)).into());
+    }
+    let group = similarities::get_group(&*pool.reader()?, group_id)?.ok_or_else(not_found)?;
+    Ok(Response::json(&group))
+}
+
+pub fn render_group_page_to_html(
+    group: &similarities::FileGroup,
+    page: usize,
+    page_size: usize,
+    tera: &Tera,
+    allow_preview: bool,
+) -> Result<String> {
+    // groups have at least two members
+    let num_pages = (group.member_count - 1) / page_size + 1;
+    let mut context = TeraContext::new();
+    context.insert("group", group);
+    context.insert("page", &page);
+    context.insert("num_pages", &num_pages);
+    context.insert("offset", &(page * page_size));
+    context.insert("page_size", &page_size);
+    context.insert("allow_preview", &allow_preview);
+    Ok(tera.render("group.html.tera", &context)?)
+}
+
+/// Lists all members of a duplicate group, `page_size` at a time.
+fn handle_group_page_request(
+    db: &Database,
+    group_id: &str,
+    page: usize,
+    page_size: usize,
+    tera: &Tera,
+    allow_preview: bool,
+) -> Result<Response> {
+    if !similarities::is_group_id(group_id) {
+        return Err(ApiError::bad_request(format!("Invalid group id {}", group_id)).into());
+    }
+    if !(1..=MAX_PER_PAGE).contains(&page_size) {
+        return Err(
+            ApiError::bad_request(format!("Page size must be from 1 to {}", MAX_PER_PAGE)).into(),
+        );
+    }
+    if page.checked_mul(page_size).is_none() {
+        return Err(ApiError::bad_request(format!("page {} out of range", page)).into());
+    }
+    let group = similarities::get_group_page(db, group_id, page, page_size)?
+        .ok_or_else(|| ApiError::new(404, format!("No group with id {}", group_id)))?;
+    let num_pages = (group.member_count - 1) / page_size + 1;
+    if page >= num_pages {
+        return Err(ApiError::bad_request(format!(
+            "page {} out of range, the group has {} pages",
+            page, num_pages
+        ))
+        .into());
+    }
+    let html = render_group_page_to_html(&group, page, page_size, tera, allow_preview)?;
+    Ok(Response::html(html))
+}
+
+#[derive(Deserialize)]
+struct GroupNoteRequest {
+    /// Empty or missing notes clear the note
+    #[serde(default)]
+    note: String,
+}
+
+/// Sets or clears the note of a duplicate group.
+fn handle_group_note_request(
+    db_mutex: &Mutex<Database>,
+    group_id: &str,
+    request: &Request,
+    max_body_size: usize,
+) -> Result<Response> {
+    if !similarities::is_group_id(group_id) {
+        return Err(ApiError::bad_request(format!("Invalid group id {}", group_id)).into());
+    }
+    let body = read_body(request, max_body_size)?;
+    let note: GroupNoteRequest = serde_json::from_slice(&body)
+        .map_err(|e| ApiError::bad_request(format!("invalid note: {}", e)))?;
     if let Ok(db) = db_mutex.lock() {
-        Ok(Response::text(delete_file(&db, id)?))
+        db.set_group_note(group_id, &note.note)?;
+        Ok(Response::json(&db.get_group_note(group_id)?))
     } else {
-        return Err(anyhow!("Unable to lock DB"));
+        Err(anyhow!("Unable to lock DB"))
     }
 }
 
-pub fn start_web_interface(
-    db_mutex: Arc<Mutex<Database>>,
-    bind_address: String,
-    port: u16,
-    allow_preview: bool,
-) -> ! {
-    if allow_preview && bind_address != "127.0.0.1" {
-        log::warn!("You seem to be binding to a public interface and use --allow_preview.");
+/// Reads all members of a duplicate group again and compares them byte by byte, to
+/// make sure they are still duplicates before deleting any of them. Members that
+/// aren't done after `timeout` are reported as cancelled.
+fn handle_verify_request(
+    pool: &DatabasePool,
+    group_id: &str,
+    timeout: Duration,
+) -> Result<Response> {
+    if !similarities::is_group_id(group_id) {
+        return Err(ApiError::bad_request(format!("Invalid group id {}", group_id)).into());
     }
+    let deadline = Instant::now() + timeout.min(verify::VERIFY_TIMEOUT);
+    // reading the files may take a while, the reader is only held for looking them up
+    let members = verify::group_members(&*pool.reader()?, group_id)?
+        .ok_or_else(|| ApiError::new(404, format!("No group with id {}", group_id)))?;
+    Ok(Response::json(&verify::GroupVerification {
+        group_id: group_id.to_string(),
+        members: verify::verify_members(&members, deadline),
+    }))
+}
 
-    let tera = Tera::new("templates/**/*.html.tera").unwrap();
-    let listen_address = format!("{}:{}", bind_address, port);
-    let vhd_mutex = Arc::new(Mutex::new(
-        VideoHashData::new(&Arc::clone(&db_mutex)).unwrap(),
-    ));
-    rouille::start_server(listen_address, move |request| {
-        let db_mutex = Arc::clone(&db_mutex);
-        let vhd_mutex = Arc::clone(&vhd_mutex);
-        let response = router!(request,
-            (GET) (/) => {handle_index_request(&db_mutex, &tera, allow_preview)},
-            (GET) (/preview/{file_id: i64}) => {handle_preview_request(&db_mutex, file_id)},
-            (GET) (/rename/{id: i64}/{new_name: String}) => {handle_rename_request(&db_mutex, id, new_name)},
-            (GET) (/remove/{id: i64}) => {handle_remove_request(&db_mutex, id)},
-            (GET) (/videohash/{threshold: u16}) => {
-                vhd_mutex.lock().unwrap().handle_request(threshold, &tera, allow_preview)},
-            (GET) (/refresh) => {
-                let mut vhd = vhd_mutex.lock().unwrap();
-                vhd.refresh(&db_mutex).unwrap();
-                vhd.handle_request(1, &tera, allow_preview)
-            },
-            _ => Ok(Response::text("Unknown Request").with_status_code(500))
-        );
-        response.unwrap_or_else(|e| Response::text(e.to_string()).with_status_code(500))
-    });
+/// Starts scans requested through the setup page. Only used when Dupletti was started
+/// without a --path, scans are then run by whoever receives the paths.
+pub struct ScanTrigger {
+    requests: Mutex<mpsc::Sender<PathBuf>>,
+    started: AtomicBool,
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::database::FileDigest;
-    use std::path::PathBuf;
+impl ScanTrigger {
+    pub fn new(requests: mpsc::Sender<PathBuf>) -> ScanTrigger {
+        ScanTrigger {
+            requests: Mutex::new(requests),
+            started: AtomicBool::new(false),
+        }
+    }
 
-    #[test]
+    fn start(&self, path: PathBuf) -> Result<()> {
+        match self.requests.lock() {
+            Ok(requests) => requests
+                .send(path)
+                .map_err(|_| anyhow!("The scanner is no longer running"))?,
+            Err(_) => return Err(anyhow!("Unable to lock scan requests")),
+        }
+        self.started.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+/// The setup page is shown instead of the results until a first scan was started.
+fn shows_setup(pool: &DatabasePool, scan_trigger: Option<&ScanTrigger>) -> Result<bool> {
+    match scan_trigger {
+        Some(trigger) if !trigger.started.load(Ordering::SeqCst) => pool.reader()?.is_empty(),
+        _ => Ok(false),
+    }
+}
+
+pub fn render_setup_page(tera: &Tera) -> Result<String> {
+    Ok(tera.render("setup.html.tera", &TeraContext::new())?)
+}
+
+#[derive(Deserialize)]
+struct ScanRequest {
+    path: PathBuf,
+}
+
+#[derive(Debug, Serialize)]
+struct ScanResponse {
+    status: &'static str,
+    path: PathBuf,
+}
+
+fn handle_scan_request(
+    scan_trigger: Option<&ScanTrigger>,
+    request: &Request,
+    max_body_size: usize,
+) -> Result<Response> {
+    let trigger = scan_trigger.ok_or_else(|| {
+        ApiError::new(
+            409,
+            "Scans can only be started here if Dupletti runs without --path",
+        )
+    })?;
+    let body = read_body(request, max_body_size)?;
+    let scan: ScanRequest = serde_json::from_slice(&body)
+        .map_err(|e| ApiError::bad_request(format!("invalid scan request: {}", e)))?;
+    if !scan.path.is_dir() {
+        return Err(ApiError::bad_request(format!(
+            "{} is not a directory",
+            scan.path.to_string_lossy()
+        ))
+        .into());
+    }
+    log::info!("Scan of {} requested", scan.path.to_string_lossy());
+    trigger.start(scan.path.clone())?;
+    Ok(Response::json(&ScanResponse {
+        status: "started",
+        path: scan.path,
+    }))
+}
+
+#[derive(Debug, Serialize)]
+struct ExcludeResponse {
+    status: &'static str,
+    /// The group that is no longer reported
+    group_id: String,
+}
+
+/// Jobs are only run for the database that scans use.
+fn job_queue(jobs: Option<&JobQueue>) -> Result<&JobQueue> {
+    jobs.ok_or_else(|| ApiError::new(404, "Jobs are only available for the first database").into())
+}
+
+/// Number of jobs listed by GET /api/jobs
+const LISTED_JOBS: usize = 100;
+
+#[derive(Debug, Serialize)]
+struct JobsResponse {
+    jobs: Vec<Job>,
+}
+
+fn handle_jobs_request(jobs: Option<&JobQueue>) -> Result<Response> {
+    Ok(Response::json(&JobsResponse {
+        jobs: job_queue(jobs)?.list(LISTED_JOBS)?,
+    }))
+}
+
+fn handle_job_submit_request(
+    jobs: Option<&JobQueue>,
+    request: &Request,
+    max_body_size: usize,
+) -> Result<Response> {
+    let jobs = job_queue(jobs)?;
+    let body = read_body(request, max_body_size)?;
+    let job: JobRequest = serde_json::from_slice(&body)
+        .map_err(|e| ApiError::bad_request(format!("invalid job request: {}", e)))?;
+    job.check()
+        .map_err(|e| ApiError::bad_request(e.to_string()))?;
+    Ok(Response::json(&jobs.submit(&job)?).with_status_code(202))
+}
+
+fn handle_job_request(jobs: Option<&JobQueue>, job_id: i64) -> Result<Response> {
+    match job_queue(jobs)?.get(job_id)? {
+        Some(job) => Ok(Response::json(&job)),
+        None => Err(ApiError::new(404, format!("No job with id {}", job_id)).into()),
+    }
+}
+
+/// Asks a job to stop. Running jobs stop after their current step, so the returned
+/// job may still be running.
+fn handle_job_cancel_request(jobs: Option<&JobQueue>, job_id: i64) -> Result<Response> {
+    let jobs = job_queue(jobs)?;
+    match jobs.get(job_id)? {
+        Some(job) if job.status.is_finished() => Err(ApiError::new(
+            409,
+            format!("Job {} is already {}", job_id, job.status.name()),
+        )
+        .into()),
+        Some(_) => Ok(Response::json(&jobs.cancel(job_id)?)),
+        None => Err(ApiError::new(404, format!("No job with id {}", job_id)).into()),
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct QueueResponse {
+    status: &'static str,
+    id: i64,
+    queued: QueueTotals,
+}
+
+/// Marks a file for deletion, or unmarks it. Protected files can't be queued.
+fn handle_queue_request(db_mutex: &Mutex<Database>, file_id: i64, queue: bool) -> Result<Response> {
+    if let Ok(db) = db_mutex.lock() {
+        let status = if queue {
+            let file = db.lookup_filedigest(file_id)?;
+            if db.is_protected(file_id)? {
+                return Err(ApiError::new(
+                    403,
+                    format!("{} is protected", file.path.to_string_lossy()),
+                )
+                .into());
+            }
+            if db.queue_action(file_id, QueuedAction::Delete)? {
+                "queued"
+            } else {
+                "already-queued"
+            }
+        } else if db.unqueue_action(file_id)? {
+            "unqueued"
+        } else {
+            "not-queued"
+        };
+        Ok(Response::json(&QueueResponse {
+            status,
+            id: file_id,
+            queued: db.queue_totals()?,
+        }))
+    } else {
+        Err(anyhow!("Unable to lock DB"))
+    }
+}
+
+pub fn render_queue_to_html(
+    files: &[QueuedFile],
+    totals: QueueTotals,
+    tera: &Tera,
+    allow_preview: bool,
+) -> Result<String> {
+    let mut context = TeraContext::new();
+    context.insert("files", files);
+    context.insert("totals", &totals);
+    context.insert("allow_preview", &allow_preview);
+    Ok(tera.render("queue.html.tera", &context)?)
+}
+
+fn handle_queue_page_request(db: &Database, tera: &Tera, allow_preview: bool) -> Result<Response> {
+    let html = render_queue_to_html(
+        &db.get_queued_files()?,
+        db.queue_totals()?,
+        tera,
+        allow_preview,
+    )?;
+    Ok(Response::html(html))
+}
+
+/// Runs all queued deletions through `delete_file`. Protected files, and files whose
+/// content has no other copy on disk, are refused and stay queued, as do files that
+/// couldn't be deleted. A dry run only reports what would happen.
+fn execute_queue(db: &Database, dry_run: bool) -> Result<QueueReport> {
+    let mut report = QueueReport {
+        dry_run,
+        files: Vec::new(),
+        freed_bytes: 0,
+    };
+    // a dry run deletes nothing, so the files it would delete are tracked here
+    let mut deleted = HashSet::new();
+    for queued in db.get_queued_files()? {
+        let file = db.lookup_filedigest(queued.id)?;
+        let mut error = None;
+        let outcome = if db.is_protected(file.id)? {
+            Outcome::Protected
+        } else if !file.path.exists() {
+            if !dry_run {
+                delete_file(db, file.id)?;
+            }
+            Outcome::DoesNotExist
+        } else if !db
+            .other_copies(&file)?
+            .iter()
+            .any(|(id, path)| !deleted.contains(id) && path.exists())
+        {
+            Outcome::LastCopy
+        } else if dry_run {
+            Outcome::WouldDelete
+        } else {
+            match delete_file(db, file.id) {
+                Ok(_) => Outcome::Deleted,
+                Err(e) => {
+                    error = Some(e.to_string());
+                    Outcome::Failed
+                }
+            }
+        };
+        if matches!(outcome, Outcome::Deleted | Outcome::WouldDelete) {
+            deleted.insert(file.id);
+            report.freed_bytes += file.size;
+        }
+        log::info!("{}: {:?}", file.path.to_string_lossy(), outcome);
+        report.files.push(FileOutcome {
+            id: file.id,
+            path: file.path,
+            outcome,
+            error,
+        });
+    }
+    Ok(report)
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ExecuteQueueRequest {
+    #[serde(default)]
+    dry_run: bool,
+}
+
+fn handle_execute_queue_request(
+    db_mutex: &Mutex<Database>,
+    request: &Request,
+    max_body_size: usize,
+    backup: &BackupPolicy,
+) -> Result<Response> {
+    let body = read_body(request, max_body_size)?;
+    let execute: ExecuteQueueRequest = if body.is_empty() {
+        ExecuteQueueRequest::default()
+    } else {
+        serde_json::from_slice(&body)
+            .map_err(|e| ApiError::bad_request(format!("invalid execute request: {}", e)))?
+    };
+    if let Ok(db) = db_mutex.lock() {
+        if !execute.dry_run {
+            db.backup(backup, "executing the deletion queue")?;
+        }
+        Ok(Response::json(&execute_queue(&db, execute.dry_run)?))
+    } else {
+        Err(anyhow!("Unable to lock DB"))
+    }
+}
+
+/// Adds the digest of a file to the exclusion list, which hides its whole group.
+fn handle_exclude_request(db_mutex: &Mutex<Database>, file_id: i64) -> Result<Response> {
+    if let Ok(db) = db_mutex.lock() {
+        let file = db.lookup_filedigest(file_id)?;
+        db.exclude_digest(&file.digest, Some(&file.path.to_string_lossy()))?;
+        Ok(Response::json(&ExcludeResponse {
+            status: "success",
+            group_id: similarities::group_id(&file.digest),
+        }))
+    } else {
+        Err(anyhow!("Unable to lock DB"))
+    }
+}
+
+pub fn render_redownloads_to_html(
+    files: &[similarities::FileEntry],
+    tera: &Tera,
+    allow_preview: bool,
+) -> Result<String> {
+    let mut context = TeraContext::new();
+    context.insert("files", files);
+    context.insert("allow_preview", &allow_preview);
+    Ok(tera.render("redownloads.html.tera", &context)?)
+}
+
+/// Lists files whose content was deleted before, see `Database::get_redownloads`.
+fn handle_redownloads_request(db: &Database, tera: &Tera, allow_preview: bool) -> Result<Response> {
+    let files = db.get_redownloads()?;
+    let html = render_redownloads_to_html(&files, tera, allow_preview)?;
+    Ok(Response::html(html))
+}
+
+pub fn render_skipped_to_html(files: &[SkippedFile], tera: &Tera) -> Result<String> {
+    let mut context = TeraContext::new();
+    context.insert("files", files);
+    Ok(tera.render("skipped.html.tera", &context)?)
+}
+
+/// Lists files that scans left out of hashing, like cloud placeholders.
+fn handle_skipped_request(db: &Database, tera: &Tera) -> Result<Response> {
+    let files = db.get_skipped_files()?;
+    Ok(Response::html(render_skipped_to_html(&files, tera)?))
+}
+
+pub fn render_bursts_to_html(
+    bursts: &[bursts::Burst<'_>],
+    window: i64,
+    threshold: u32,
+    tera: &Tera,
+    allow_preview: bool,
+) -> Result<String> {
+    let mut context = TeraContext::new();
+    context.insert("bursts", bursts);
+    context.insert("window", &window);
+    context.insert("threshold", &threshold);
+    context.insert("allow_preview", &allow_preview);
+    Ok(tera.render("bursts.html.tera", &context)?)
+}
+
+/// Reads the burst window and threshold from the query string.
+fn burst_params(request: &Request) -> (i64, u32) {
+    let window = request.get_param("window").and_then(|w| w.parse().ok());
+    let threshold = request.get_param("threshold").and_then(|t| t.parse().ok());
+    (
+        window.unwrap_or(bursts::DEFAULT_BURST_WINDOW),
+        threshold.unwrap_or(bursts::DEFAULT_BURST_THRESHOLD),
+    )
+}
+
+fn handle_bursts_request(
+    db: &Database,
+    request: &Request,
+    tera: &Tera,
+    allow_preview: bool,
+) -> Result<Response> {
+    let (window, threshold) = burst_params(request);
+    let photos = db.get_burst_photos()?;
+    let bursts = bursts::find_bursts(&photos, window, threshold);
+    if request.url().starts_with("/api/") {
+        return Ok(Response::json(&bursts));
+    }
+    let html = render_bursts_to_html(&bursts, window, threshold, tera, allow_preview)?;
+    Ok(Response::html(html))
+}
+
+/// Browsers can't display RAW photos, so their embedded JPEG preview is served instead.
+/// Files without a usable preview get a placeholder image.
+fn raw_preview_response(filepath: &Path) -> Response {
+    match rawpreview::extract_preview(filepath) {
+        Ok(jpeg) => Response::from_data("image/jpeg", jpeg),
+        Err(e) => {
+            log::info!("No preview for {}: {}", filepath.to_string_lossy(), e);
+            Response::from_data("image/svg+xml", rawpreview::PLACEHOLDER_SVG)
+        }
+    }
+}
+
+/// HEIF images are decoded through ffmpeg. Files that can't be decoded are recorded in the
+/// error table and shown as a placeholder.
+fn still_preview_response(pool: &DatabasePool, file_id: i64, filepath: &Path) -> Result<Response> {
+    match stillpreview::render_preview(filepath) {
+        Ok(bmp) => Ok(Response::from_data("image/bmp", bmp)),
+        Err(e) => {
+            log::info!("No preview for {}: {}", filepath.to_string_lossy(), e);
+            pool.writer()?
+                .record_video_errors(&[file_id], &e.to_string())?;
+            Ok(Response::from_data(
+                "image/svg+xml",
+                rawpreview::PLACEHOLDER_SVG,
+            ))
+        }
+    }
+}
+
+fn handle_preview_request(pool: &DatabasePool, file_id: i64) -> Result<Response> {
+    let filepath = pool.reader()?.lookup_filedigest(file_id)?.path;
+    let mut mime =
+        rouille::extension_to_mime(filepath.extension().and_then(|s| s.to_str()).unwrap_or(""));
+    if rawpreview::is_raw_file(&filepath) && filepath.exists() {
+        return Ok(raw_preview_response(&filepath));
+    }
+    match stillpreview::sniff_file(&filepath) {
+        Some(StillFormat::Heif) => return still_preview_response(pool, file_id, &filepath),
+        Some(StillFormat::WebP) => mime = "image/webp",
+        None => {}
+    }
+    let file = fs::File::open(&filepath).map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => ApiError::new(
+            404,
+            format!("{} no longer exists", filepath.to_string_lossy()),
+        )
+        .into(),
+        _ => anyhow::Error::from(e),
+    })?;
+    // files might be big, so don't cache them
+    Ok(Response::from_file(mime, file).with_no_cache())
+}
+
+/// Revealing files only makes sense if the browser runs on the same machine as the server.
+pub fn is_loopback(bind_address: &str) -> bool {
+    bind_address
+        .parse::<IpAddr>()
+        .map_or(bind_address == "localhost", |ip| ip.is_loopback())
+}
+
+/// `meta` is only stored for files hashed with --collect-metadata.
+pub fn render_reveal_page(
+    path: &Path,
+    file_id: i64,
+    tera: &Tera,
+    allow_reveal: bool,
+    meta: Option<&FileMeta>,
+) -> Result<String> {
+    let mut context = TeraContext::new();
+    context.insert("path", &path.to_string_lossy());
+    context.insert("file_id", &file_id);
+    context.insert("allow_reveal", &allow_reveal);
+    if let Some(meta) = meta {
+        context.insert("meta", meta);
+        let owner = meta
+            .uid
+            .map(|uid| filemeta::user_name(uid).unwrap_or_else(|| uid.to_string()));
+        context.insert("owner", &owner);
+        context.insert("permissions", &meta.permissions());
+    }
+    Ok(tera.render("reveal.html.tera", &context)?)
+}
+
+fn handle_reveal_request(
+    db: &Database,
+    file_id: i64,
+    tera: &Tera,
+    allow_reveal: bool,
+) -> Result<Response> {
+    let filepath = db.lookup_filedigest(file_id)?.path;
+    let meta = db.get_file_meta(file_id)?;
+    let html = render_reveal_page(&filepath, file_id, tera, allow_reveal, meta.as_ref())?;
+    Ok(Response::html(html))
+}
+
+#[derive(Serialize)]
+struct RevealResponse {
+    status: &'static str,
+}
+
+/// Opens the OS file manager at the given file.
+fn reveal_in_file_manager(path: &Path) -> Result<()> {
+    let mut command = if cfg!(target_os = "windows") {
+        let mut c = Command::new("explorer");
+        c.arg(format!("/select,{}", path.to_string_lossy()));
+        c
+    } else if cfg!(target_os = "macos") {
+        let mut c = Command::new("open");
+        c.arg("-R").arg(path);
+        c
+    } else {
+        // xdg-open can't select a file, so open the containing directory instead
+        let mut c = Command::new("xdg-open");
+        c.arg(path.parent().unwrap_or(path));
+        c
+    };
+    let mut child = command.spawn()?;
+    // the file manager may keep running, wait for it in the background so it is reaped
+    thread::spawn(move || child.wait());
+    Ok(())
+}
+
+fn handle_reveal_post_request(
+    pool: &DatabasePool,
+    file_id: i64,
+    allow_reveal: bool,
+) -> Result<Response> {
+    if !allow_reveal {
+        return Err(ApiError::new(
+            403,
+            "Revealing files is disabled, run with --allow-reveal on a loopback address",
+        )
+        .into());
+    }
+    let filepath = pool.reader()?.lookup_filedigest(file_id)?.path;
+    if !filepath.exists() {
+        return Err(ApiError::new(
+            404,
+            format!("{} no longer exists", filepath.to_string_lossy()),
+        )
+        .into());
+    }
+    reveal_in_file_manager(&filepath)?;
+    Ok(Response::json(&RevealResponse { status: "success" }))
+}
+
+pub struct VideoHashData {
+    pub hashes: Vec<videohash::VideoHash>,
+    pub distances: Array2<u16>,
+    pub min_duration: f64,
+    /// The state of the database the data was loaded from
+    version: Option<DbVersion>,
+}
+
+impl VideoHashData {
+    pub fn new(db_mutex: &Mutex<Database>, min_duration: f64) -> Result<VideoHashData> {
+        let mut vhd = VideoHashData {
+            hashes: Vec::new(),
+            distances: Array::zeros((0, 0)),
+            min_duration,
+            version: None,
+        };
+        vhd.refresh(db_mutex)?;
+        Ok(vhd)
+    }
+
+    /// Loads the hashes again, unless the database didn't change since. Concurrent
+    /// refreshes wait for the video hash lock, so all but the first are skipped.
+    pub fn refresh(&mut self, db_mutex: &Mutex<Database>) -> Result<()> {
+        // We do everything within the DB-mutex so concurrent calls work w/o races.
+        if let Ok(db) = db_mutex.lock() {
+            if self.version == Some(db.version()?) {
+                log::debug!("Video hashes are up to date");
+                return Ok(());
+            }
+            // Histograms of files without any decodable frame are all zeros. They would
+            // be close to each other, so keep them away from the distance computation.
+            let (hashes, empty): (Vec<_>, Vec<_>) = db
+                .get_all_files_with_videohash()?
+                .into_iter()
+                .partition(|h| h.histogram.iter().any(|&x| x != 0));
+            if !empty.is_empty() {
+                log::warn!("Ignoring {} videos with empty histograms", empty.len());
+                let ids: Vec<i64> = empty.iter().map(|h| h.id).collect();
+                db.record_video_errors(&ids, "No frames could be decoded")?;
+            }
+            self.hashes = hashes;
+            log::debug!("Num videohashs: {}", self.hashes.len());
+            videohash::warn_on_mixed_frame_sizes(&self.hashes);
+            self.distances = videohash::calculate_distances(&self.hashes);
+            log::debug!("Done with distance calculation");
+            self.version = Some(db.version()?);
+        } else {
+            return Err(anyhow!("Unable to lock DB"));
+        }
+        Ok(())
+    }
+
+    pub fn clusters(&self, threshold: u16) -> Vec<videohash::VideoGroup<'_>> {
+        videohash::find_similar_files(&self.hashes, &self.distances, threshold, self.min_duration)
+            .into_iter()
+            .map(videohash::VideoGroup::new)
+            .collect()
+    }
+
+    /// Whether the files are in one cluster at `threshold`.
+    fn same_cluster(&self, a: i64, b: i64, threshold: u16) -> bool {
+        self.clusters(threshold).iter().any(|cluster| {
+            [a, b]
+                .iter()
+                .all(|id| cluster.files.iter().any(|f| f.id == *id))
+        })
+    }
+
+    /// Clusters the files at each of `thresholds`. The distances are only computed once,
+    /// so this is cheap compared to loading the data.
+    pub fn sweep(&self, thresholds: &[u16], keep_policy: KeepPolicy) -> Vec<SweepPoint> {
+        thresholds
+            .iter()
+            .map(|&threshold| {
+                let clusters = self.clusters(threshold);
+                SweepPoint {
+                    threshold,
+                    clusters: clusters.len(),
+                    clustered_files: clusters.iter().map(|g| g.files.len()).sum(),
+                    reclaimable_bytes: similarities::reclaimable_bytes(
+                        clusters.iter().map(|g| g.files.as_slice()),
+                        keep_policy.clone(),
+                    )
+                    .total,
+                }
+            })
+            .collect()
+    }
+
+    fn handle_request(
+        &self,
+        threshold: u16,
+        tera: &Tera,
+        allow_preview: bool,
+        keep_policy: KeepPolicy,
+    ) -> Result<Response> {
+        log::debug!("# Clustering with threshold {}", threshold);
+        let mut results = self.clusters(threshold);
+        let reclaimable = similarities::reclaimable_bytes(
+            results.iter().map(|g| g.files.as_slice()),
+            keep_policy.clone(),
+        );
+        let total_size_gb = reclaimable.total as f64 / (1024.0 * 1024.0 * 1024.0);
+        log::info!("Max saved size by videohash: {:.2} GB", total_size_gb);
+        // sort by filesize (maximum first)
+        results.sort_unstable_by_key(|bag| bag.files.iter().map(|x| x.size).min());
+        results.reverse();
+        log::info!("# Clusters({}): {}", threshold, results.len());
+        let html = render_videohash_results_to_html(results, &tera, allow_preview, keep_policy)?;
+        Ok(Response::html(html))
+    }
+}
+
+/// The clustering at one threshold of a sweep.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SweepPoint {
+    pub threshold: u16,
+    pub clusters: usize,
+    pub clustered_files: usize,
+    pub reclaimable_bytes: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct SweepResponse {
+    points: Vec<SweepPoint>,
+}
+
+/// A sweep clusters the files at most this often
+const MAX_SWEEP_STEPS: usize = 50;
+
+/// The thresholds of a sweep from `from` to `to`, both included.
+fn sweep_thresholds(from: u16, to: u16, step: u16) -> Result<Vec<u16>> {
+    check_threshold(to)?;
+    if step == 0 || from > to {
+        return Err(ApiError::bad_request(format!(
+            "Can't sweep from {} to {} in steps of {}",
+            from, to, step
+        ))
+        .into());
+    }
+    let thresholds: Vec<u16> = (from..=to).step_by(step as usize).collect();
+    if thresholds.len() > MAX_SWEEP_STEPS {
+        return Err(ApiError::bad_request(format!(
+            "A sweep can have at most {} steps, this one has {}",
+            MAX_SWEEP_STEPS,
+            thresholds.len()
+        ))
+        .into());
+    }
+    Ok(thresholds)
+}
+
+/// The thresholds given by from, to and step, e.g. `?from=8&to=128&step=8`, the defaults.
+fn sweep_param(request: &Request) -> Result<Vec<u16>> {
+    let param = |name: &str, default: u16| match request.get_param(name) {
+        Some(value) => value
+            .parse()
+            .map_err(|_| ApiError::bad_request(format!("Invalid {} {}", name, value))),
+        None => Ok(default),
+    };
+    sweep_thresholds(param("from", 8)?, param("to", 128)?, param("step", 8)?)
+}
+
+fn handle_videohash_sweep_request(
+    db_mutex: &Mutex<Database>,
+    vhs_mutex: &Mutex<VideoHashState>,
+    thresholds: &[u16],
+    keep_policy: KeepPolicy,
+) -> Result<Response> {
+    let mut vhs = vhs_mutex
+        .lock()
+        .map_err(|_| anyhow!("Unable to lock video hashes"))?;
+    let points = vhs.data(db_mutex)?.sweep(thresholds, keep_policy);
+    Ok(Response::json(&SweepResponse { points }))
+}
+
+const VIDEOHASH_DISABLED: &str = "Video similarity is not enabled, run with --videohash";
+
+/// Video hash data is only loaded once video hashes were requested or exist, so the
+/// web interface also works without --videohash.
+pub struct VideoHashState {
+    data: Option<VideoHashData>,
+    enabled: bool,
+    min_duration: f64,
+}
+
+impl VideoHashState {
+    pub fn new(enabled: bool, min_duration: f64) -> VideoHashState {
+        VideoHashState {
+            data: None,
+            enabled,
+            min_duration,
+        }
+    }
+
+    /// Drops the loaded data, it is loaded again on next use.
+    fn invalidate(&mut self) {
+        self.data = None;
+    }
+
+    /// Loads the data on first use. Fails with 409 while video similarity is disabled
+    /// and no video hashes exist.
+    fn data(&mut self, db_mutex: &Mutex<Database>) -> Result<&mut VideoHashData> {
+        if self.data.is_none() {
+            let has_hashes = if let Ok(db) = db_mutex.lock() {
+                db.count_videohashes()? > 0
+            } else {
+                return Err(anyhow!("Unable to lock DB"));
+            };
+            if self.enabled || has_hashes {
+                self.data = Some(VideoHashData::new(db_mutex, self.min_duration)?);
+            }
+        }
+        self.data
+            .as_mut()
+            .ok_or_else(|| ApiError::new(409, VIDEOHASH_DISABLED).into())
+    }
+}
+
+fn handle_videohash_request(
+    db_mutex: &Mutex<Database>,
+    vhs_mutex: &Mutex<VideoHashState>,
+    threshold: u16,
+    refresh: bool,
+    tera: &Tera,
+    allow_preview: bool,
+    keep_policy: KeepPolicy,
+) -> Result<Response> {
+    let threshold = check_threshold(threshold)?;
+    let mut vhs = vhs_mutex
+        .lock()
+        .map_err(|_| anyhow!("Unable to lock video hashes"))?;
+    // freshly loaded data doesn't need a refresh
+    let refresh = refresh && vhs.data.is_some();
+    let vhd = match vhs.data(db_mutex) {
+        Ok(vhd) => vhd,
+        Err(e) => {
+            return match e.downcast_ref::<ApiError>() {
+                Some(api_error) if api_error.status == 409 => Ok(Response::html(format!(
+                    "<!DOCTYPE html><html><body><p>{}</p></body></html>",
+                    VIDEOHASH_DISABLED
+                ))),
+                _ => Err(e),
+            }
+        }
+    };
+    if refresh {
+        vhd.refresh(db_mutex)?;
+    }
+    vhd.handle_request(threshold, tera, allow_preview, keep_policy)
+}
+
+/// A video on the comparison page.
+#[derive(Debug, Serialize)]
+struct ComparedVideo {
+    id: i64,
+    path: PathBuf,
+    size: u64,
+    /// Only known for probed files
+    media: Option<videohash::MediaInfo>,
+}
+
+fn render_compare_to_html(
+    videos: &[ComparedVideo],
+    distance: Option<u16>,
+    tera: &Tera,
+) -> Result<String> {
+    let mut context = TeraContext::new();
+    context.insert("videos", videos);
+    context.insert("distance", &distance);
+    Ok(tera.render("compare.html.tera", &context)?)
+}
+
+/// Shows two videos with frame strips side by side, to judge whether they really are
+/// the same video. The distance of their histograms is shown if they have video hashes.
+fn handle_compare_request(
+    pool: &DatabasePool,
+    vhs_mutex: &Mutex<VideoHashState>,
+    file_ids: [i64; 2],
+    tera: &Tera,
+) -> Result<Response> {
+    let videos = {
+        let db = pool.reader()?;
+        let videos: Result<Vec<ComparedVideo>> = file_ids
+            .iter()
+            .map(|&id| {
+                let file = db.lookup_filedigest(id)?;
+                Ok(ComparedVideo {
+                    id,
+                    path: file.path,
+                    size: file.size,
+                    media: db.get_media_metadata(id)?,
+                })
+            })
+            .collect();
+        videos?
+    };
+    let mut vhs = vhs_mutex
+        .lock()
+        .map_err(|_| anyhow!("Unable to lock video hashes"))?;
+    // without video hashes, the strips are still worth a look
+    let distance = vhs.data(&pool.db_mutex()).ok().and_then(|vhd| {
+        let hash = |id: i64| vhd.hashes.iter().find(|h| h.id == id);
+        match (hash(file_ids[0]), hash(file_ids[1])) {
+            (Some(a), Some(b)) => videohash::distance(a, b),
+            _ => None,
+        }
+    });
+    let html = render_compare_to_html(&videos, distance, tera)?;
+    Ok(Response::html(html))
+}
+
+/// Frames sampled from a video, rendered on first request and then cached in the
+/// database. Videos that can't be decoded get a placeholder image.
+fn handle_strip_request(pool: &DatabasePool, file_id: i64) -> Result<Response> {
+    let filepath = {
+        let db = pool.reader()?;
+        if let Some(strip) = db.get_thumbnail(file_id, framestrips::STRIP_KIND)? {
+            return Ok(Response::from_data("image/bmp", strip));
+        }
+        db.lookup_filedigest(file_id)?.path
+    };
+    // decoding takes a while, so the DB isn't locked meanwhile
+    match framestrips::render_strip(&filepath) {
+        Ok(strip) => {
+            pool.writer()?
+                .store_thumbnail(file_id, framestrips::STRIP_KIND, &strip)?;
+            Ok(Response::from_data("image/bmp", strip))
+        }
+        Err(e) => {
+            log::info!("No frame strip for {}: {}", filepath.to_string_lossy(), e);
+            Ok(Response::from_data(
+                "image/svg+xml",
+                rawpreview::PLACEHOLDER_SVG,
+            ))
+        }
+    }
+}
+
+/// Calls `respond` with the file and the files similar to it. Fails with 409 if the file
+/// has no video hash.
+fn with_similar_files<F>(
+    db_mutex: &Mutex<Database>,
+    vhs_mutex: &Mutex<VideoHashState>,
+    file_id: i64,
+    threshold: u16,
+    respond: F,
+) -> Result<Response>
+where
+    F: FnOnce(&videohash::VideoHash, Vec<videohash::Neighbor<'_>>) -> Result<Response>,
+{
+    let threshold = check_threshold(threshold)?;
+    let mut vhs = vhs_mutex
+        .lock()
+        .map_err(|_| anyhow!("Unable to lock video hashes"))?;
+    let vhd = vhs.data(db_mutex)?;
+    let file = vhd.hashes.iter().find(|h| h.id == file_id).ok_or_else(|| {
+        ApiError::new(
+            409,
+            format!(
+                "File {} has no video hash, run with --videohash to compute it",
+                file_id
+            ),
+        )
+    })?;
+    respond(
+        file,
+        videohash::nearest_neighbors(file, &vhd.hashes, threshold),
+    )
+}
+
+fn handle_similar_to_api_request(
+    db_mutex: &Mutex<Database>,
+    vhs_mutex: &Mutex<VideoHashState>,
+    file_id: i64,
+    threshold: u16,
+) -> Result<Response> {
+    with_similar_files(db_mutex, vhs_mutex, file_id, threshold, |_, matches| {
+        Ok(Response::json(&matches))
+    })
+}
+
+pub fn render_similar_to_html(
+    file: &videohash::VideoHash,
+    matches: &[videohash::Neighbor<'_>],
+    threshold: u16,
+    tera: &Tera,
+    allow_preview: bool,
+) -> Result<String> {
+    let mut context = TeraContext::new();
+    context.insert("file", file);
+    context.insert("matches", matches);
+    context.insert("threshold", &threshold);
+    context.insert("allow_preview", &allow_preview);
+    Ok(tera.render("similar.html.tera", &context)?)
+}
+
+fn handle_similar_to_request(
+    db_mutex: &Mutex<Database>,
+    vhs_mutex: &Mutex<VideoHashState>,
+    file_id: i64,
+    threshold: u16,
+    tera: &Tera,
+    allow_preview: bool,
+) -> Result<Response> {
+    let result = with_similar_files(db_mutex, vhs_mutex, file_id, threshold, |file, matches| {
+        let html = render_similar_to_html(file, &matches, threshold, tera, allow_preview)?;
+        Ok(Response::html(html))
+    });
+    result.or_else(|e| match e.downcast_ref::<ApiError>() {
+        Some(api_error) if api_error.status == 409 => Ok(Response::html(format!(
+            "<!DOCTYPE html><html><body><p>{}</p></body></html>",
+            api_error.message
+        ))),
+        _ => Err(e),
+    })
+}
+
+/// State of a duplicate group after one of its files was renamed or removed, so the
+/// web interface can update the page in place.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct GroupUpdate {
+    pub status: String,
+    pub group_id: String,
+    /// True if the group has less than two members left
+    pub dissolved: bool,
+    pub files: Vec<similarities::FileEntry>,
+    pub reclaimable: u64,
+}
+
+fn group_update(
+    db: &Database,
+    group_id: String,
+    status: &str,
+    keep_policy: KeepPolicy,
+) -> Result<GroupUpdate> {
+    let files = similarities::get_group(db, &group_id)?.map_or(Vec::new(), |g| g.files);
+    let reclaimable = similarities::reclaimable_bytes(Some(files.as_slice()), keep_policy);
+    Ok(GroupUpdate {
+        status: status.to_string(),
+        group_id,
+        dissolved: files.is_empty(),
+        files,
+        reclaimable: reclaimable.total,
+    })
+}
+
+fn handle_rename_request(
+    db_mutex: &Mutex<Database>,
+    id: i64,
+    new_name: String,
+    keep_policy: KeepPolicy,
+) -> Result<Response> {
+    log::debug!("renaming {} to {}", id, new_name);
+    if let Ok(db) = db_mutex.lock() {
+        let group_id = similarities::group_id(&db.lookup_filedigest(id)?.digest);
+        let status = rename_file(&db, id, new_name)?;
+        Ok(Response::json(&group_update(
+            &db,
+            group_id,
+            status,
+            keep_policy,
+        )?))
+    } else {
+        return Err(anyhow!("Unable to lock DB"));
+    }
+}
+
+#[derive(Deserialize)]
+struct RenameRequest {
+    new_name: String,
+}
+
+fn handle_rename_post_request(
+    db_mutex: &Mutex<Database>,
+    id: i64,
+    request: &Request,
+    max_body_size: usize,
+    keep_policy: KeepPolicy,
+) -> Result<Response> {
+    let body = read_body(request, max_body_size)?;
+    let rename: RenameRequest = serde_json::from_slice(&body)
+        .map_err(|e| ApiError::bad_request(format!("invalid rename request: {}", e)))?;
+    if rename.new_name.is_empty() {
+        return Err(ApiError::bad_request("new_name must not be empty").into());
+    }
+    handle_rename_request(db_mutex, id, rename.new_name, keep_policy)
+}
+
+fn remove_and_update_group(db: &Database, id: i64, keep_policy: KeepPolicy) -> Result<GroupUpdate> {
+    let group_id = similarities::group_id(&db.lookup_filedigest(id)?.digest);
+    let status = delete_file(db, id)?;
+    group_update(db, group_id, status, keep_policy)
+}
+
+fn handle_remove_request(
+    db_mutex: &Mutex<Database>,
+    id: i64,
+    keep_policy: KeepPolicy,
+) -> Result<Response> {
+    log::debug!("Deleting {}", id);
+    if let Ok(db) = db_mutex.lock() {
+        Ok(Response::json(&remove_and_update_group(
+            &db,
+            id,
+            keep_policy,
+        )?))
+    } else {
+        return Err(anyhow!("Unable to lock DB"));
+    }
+}
+
+/// Loads the templates. Their links are relative to `base`, the path the database is
+/// served at.
+pub fn load_templates(base: &str) -> Result<Tera> {
+    let mut tera = Tera::new("templates/**/*.html.tera")?;
+    let base = tera::Value::String(base.to_string());
+    tera.register_function(
+        "base_url",
+        move |_: &HashMap<String, tera::Value>| -> tera::Result<tera::Value> { Ok(base.clone()) },
+    );
+    Ok(tera)
+}
+
+/// Settings of the web interface that are the same for all databases.
+pub struct WebSettings {
+    pub allow_preview: bool,
+    pub allow_reveal: bool,
+    pub max_body_size: usize,
+    pub keep_policy: KeepPolicy,
+    pub max_group_size: usize,
+    pub config: EffectiveConfig,
+    pub scan_trigger: Option<ScanTrigger>,
+    /// Runs the jobs posted to /api/jobs, for the database it was started with
+    pub jobs: Option<JobQueue>,
+    /// Linked from the results page, and used by the similar videos pages if none is
+    /// given. There is no link without --videohash or --videohash-threshold
+    pub videohash_threshold: Option<u16>,
+}
+
+/// A database served by the web interface, with everything that is cached for it.
+pub struct Site {
+    label: String,
+    /// Pages that only read use the pooled readers, so they don't wait for scans and jobs
+    pool: Arc<DatabasePool>,
+    /// The writer of `pool`
+    db_mutex: Arc<Mutex<Database>>,
+    vhs_mutex: Mutex<VideoHashState>,
+    results_cache: ResultsCache,
+    existence_cache: Mutex<ExistenceCache>,
+    changes: Mutex<ChangeWatcher>,
+    tera: Tera,
+}
+
+impl Site {
+    /// `base` is the path the site is served at, ending in a slash.
+    pub fn new(
+        label: String,
+        pool: Arc<DatabasePool>,
+        base: &str,
+        videohash: bool,
+        videohash_min_duration: f64,
+    ) -> Result<Site> {
+        let db_mutex = pool.db_mutex();
+        let mut vhs = VideoHashState::new(videohash, videohash_min_duration);
+        if let Err(e) = vhs.data(&db_mutex) {
+            log::info!("Video similarity not available for {}: {}", label, e);
+        }
+        let changes = if let Ok(db) = db_mutex.lock() {
+            ChangeWatcher::new(&db)?
+        } else {
+            return Err(anyhow!("Unable to lock DB"));
+        };
+        Ok(Site {
+            label,
+            pool,
+            db_mutex,
+            vhs_mutex: Mutex::new(vhs),
+            results_cache: ResultsCache::new(),
+            existence_cache: Mutex::new(ExistenceCache::new(EXISTENCE_CACHE_TTL)),
+            changes: Mutex::new(changes),
+            tera: load_templates(base)?,
+        })
+    }
+
+    /// Throws away the cached video hashes and file existence when another process,
+    /// e.g. a scan started by cron, committed to the database. Returns whether it did.
+    fn pick_up_external_changes(&self) -> Result<bool> {
+        let changed = if let Ok(db) = self.db_mutex.lock() {
+            let mut changes = self
+                .changes
+                .lock()
+                .map_err(|_| anyhow!("Unable to lock change watcher"))?;
+            changes.changed(&db)?
+        } else {
+            return Err(anyhow!("Unable to lock DB"));
+        };
+        if changed {
+            log::info!("{} was changed by another process", self.label);
+            self.vhs_mutex
+                .lock()
+                .map_err(|_| anyhow!("Unable to lock video hashes"))?
+                .invalidate();
+            self.existence_cache
+                .lock()
+                .map_err(|_| anyhow!("Unable to lock existence cache"))?
+                .clear();
+        }
+        Ok(changed)
+    }
+}
+
+/// Routes a request to the pages of a single database.
+fn route_site(request: &Request, site: &Site, settings: &WebSettings) -> Result<Response> {
+    site.pick_up_external_changes()?;
+    let pool = &*site.pool;
+    let db_mutex = &*site.db_mutex;
+    let vhs_mutex = &site.vhs_mutex;
+    let results_cache = &site.results_cache;
+    let existence_cache = &site.existence_cache;
+    let tera = &site.tera;
+    let allow_preview = settings.allow_preview;
+    let allow_reveal = settings.allow_reveal;
+    let max_body_size = settings.max_body_size;
+    let keep_policy = settings.keep_policy.clone();
+    let max_group_size = settings.max_group_size;
+    let scan_trigger = settings.scan_trigger.as_ref();
+    let jobs = settings
+        .jobs
+        .as_ref()
+        .filter(|jobs| Arc::ptr_eq(jobs.db_mutex(), &site.db_mutex));
+    let default_threshold = settings
+        .videohash_threshold
+        .unwrap_or(videohash::DEFAULT_THRESHOLD);
+    router!(request,
+        (GET) (/) => {
+            if shows_setup(pool, scan_trigger)? {
+                return Ok(Response::html(render_setup_page(tera)?));
+            }
+            let max_group_size = per_page_param(request, "max_group_size", max_group_size)?;
+            let (view, chosen) = view_param(request)?;
+            let only = seen_param(request)?;
+            let stale = stale_param(request)?;
+            let (session, new_session) = session_param(request);
+            let mut response = group_filter_param(request).and_then(|filter|
+                handle_index_request(pool, results_cache, existence_cache, tera, allow_preview, keep_policy.clone(), max_group_size, &filter, view, only, stale, &session, settings.videohash_threshold))?;
+            if chosen {
+                response = response.with_additional_header("Set-Cookie", set_cookie(VIEW_COOKIE, view.name()));
+            }
+            if new_session {
+                response = response.with_additional_header("Set-Cookie", set_cookie(SESSION_COOKIE, &session));
+            }
+            Ok(response)
+        },
+        (GET) (/group/{group_id: String}) => {
+            let page = request.get_param("page").map(|p| p.parse()).transpose().map_err(|_| ApiError::bad_request("page must be a non-negative number"))?.unwrap_or(0);
+            let page_size = per_page_param(request, "max_group_size", max_group_size)?;
+            handle_group_page_request(&*pool.reader()?, &group_id, page, page_size, tera, allow_preview)
+        },
+        (GET) (/api/duplicates) => {
+            group_filter_param(request).and_then(|filter|
+                handle_duplicates_api_request(pool, results_cache, existence_cache, &filter, request.get_param("limit")))
+        },
+        (GET) (/redownloads) => {handle_redownloads_request(&*pool.reader()?, tera, allow_preview)},
+        (GET) (/skipped) => {handle_skipped_request(&*pool.reader()?, tera)},
+        (GET) (/bursts) => {handle_bursts_request(&*pool.reader()?, request, tera, allow_preview)},
+        (GET) (/api/bursts) => {handle_bursts_request(&*pool.reader()?, request, tera, allow_preview)},
+        (GET) (/api/config) => {Ok(Response::json(&settings.config))},
+        (POST) (/purge_missing) => {handle_purge_missing_request(db_mutex, request, max_body_size, &settings.config)},
+        (POST) (/swap) => {handle_swap_request(db_mutex, vhs_mutex, request, max_body_size, default_threshold)},
+        (POST) (/group/{group_id: String}/note) => {handle_group_note_request(db_mutex, &group_id, request, max_body_size)},
+        (POST) (/group/{group_id: String}/verify) => {
+            let timeout = request.get_param("timeout").and_then(|t| t.parse().ok()).map_or(verify::VERIFY_TIMEOUT, Duration::from_secs);
+            handle_verify_request(pool, &group_id, timeout)
+        },
+        (POST) (/bulk) => {handle_bulk_request(db_mutex, request, max_body_size)},
+        (POST) (/ack) => {handle_ack_request(db_mutex, request, max_body_size)},
+        (POST) (/exclude/{file_id: i64}) => {handle_exclude_request(db_mutex, file_id)},
+        (GET) (/queue) => {handle_queue_page_request(&*pool.reader()?, tera, allow_preview)},
+        (POST) (/queue/execute) => {handle_execute_queue_request(db_mutex, request, max_body_size, &settings.config.backup)},
+        (POST) (/queue/{file_id: i64}/delete) => {handle_queue_request(db_mutex, file_id, true)},
+        (POST) (/queue/{file_id: i64}/unqueue) => {handle_queue_request(db_mutex, file_id, false)},
+        (POST) (/scan) => {handle_scan_request(scan_trigger, request, max_body_size)},
+        (GET) (/api/jobs) => {handle_jobs_request(jobs)},
+        (POST) (/api/jobs) => {handle_job_submit_request(jobs, request, max_body_size)},
+        (GET) (/api/jobs/{job_id: i64}) => {handle_job_request(jobs, job_id)},
+        (POST) (/api/jobs/{job_id: i64}/cancel) => {handle_job_cancel_request(jobs, job_id)},
+        (GET) (/api/group/{group_id: String}) => {
+            let threshold = request.get_param("threshold").and_then(|t| t.parse().ok()).unwrap_or(1);
+            handle_group_api_request(pool, vhs_mutex, &group_id, threshold)
+        },
+        (GET) (/api/similar_to/{file_id: i64}) => {
+            let threshold = request.get_param("threshold").and_then(|t| t.parse().ok()).unwrap_or(default_threshold);
+            handle_similar_to_api_request(db_mutex, vhs_mutex, file_id, threshold)
+        },
+        (GET) (/similar_to/{file_id: i64}) => {
+            let threshold = request.get_param("threshold").and_then(|t| t.parse().ok()).unwrap_or(default_threshold);
+            handle_similar_to_request(db_mutex, vhs_mutex, file_id, threshold, tera, allow_preview)
+        },
+        (GET) (/preview/{file_id: i64}) => {handle_preview_request(pool, file_id)},
+        (GET) (/strip/{file_id: i64}) => {handle_strip_request(pool, file_id)},
+        (GET) (/compare/{id_a: i64}/{id_b: i64}) => {handle_compare_request(pool, vhs_mutex, [id_a, id_b], tera)},
+        (GET) (/reveal/{file_id: i64}) => {handle_reveal_request(&*pool.reader()?, file_id, tera, allow_reveal)},
+        (POST) (/reveal/{file_id: i64}) => {handle_reveal_post_request(pool, file_id, allow_reveal)},
+        (GET) (/rename/{id: i64}/{new_name: String}) => {handle_rename_request(db_mutex, id, new_name, keep_policy.clone())},
+        (POST) (/rename/{id: i64}) => {handle_rename_post_request(db_mutex, id, request, max_body_size, keep_policy.clone())},
+        (GET) (/remove/{id: i64}) => {handle_remove_request(db_mutex, id, keep_policy.clone())},
+        (GET) (/videohash) => {
+            handle_videohash_request(db_mutex, vhs_mutex, default_threshold, false, tera, allow_preview, keep_policy.clone())},
+        (GET) (/videohash/{threshold: u16}) => {
+            handle_videohash_request(db_mutex, vhs_mutex, threshold, false, tera, allow_preview, keep_policy.clone())},
+        (GET) (/api/videohash/sweep) => {
+            sweep_param(request).and_then(|thresholds| handle_videohash_sweep_request(db_mutex, vhs_mutex, &thresholds, keep_policy.clone()))},
+        (GET) (/refresh) => {
+            handle_videohash_request(db_mutex, vhs_mutex, 1, true, tera, allow_preview, keep_policy.clone())},
+        _ => Err(ApiError::new(404, "Unknown Request").into())
+    )
+}
+
+/// A row of the database overview.
+#[derive(Serialize)]
+struct SiteSummary<'a> {
+    label: &'a str,
+    num_files: usize,
+    num_groups: usize,
+}
+
+fn render_databases_to_html(sites: &[Site], tera: &Tera) -> Result<String> {
+    let mut summaries = Vec::new();
+    for site in sites {
+        let db = site.pool.reader()?;
+        summaries.push(SiteSummary {
+            label: &site.label,
+            num_files: db.count_filedigests()?,
+            num_groups: similarities::get_list_of_similar_files(&db)?
+                .iter()
+                .filter(|g| !g.dismissed)
+                .count(),
+        });
+    }
+    let mut context = TeraContext::new();
+    context.insert("databases", &summaries);
+    Ok(tera.render("databases.html.tera", &context)?)
+}
+
+/// With several databases, each one is served below /<label>/ and / lists them.
+pub fn route_sites(
+    request: &Request,
+    sites: &[Site],
+    settings: &WebSettings,
+    tera: &Tera,
+) -> Result<Response> {
+    if request.url() == "/" {
+        return Ok(Response::html(render_databases_to_html(sites, tera)?));
+    }
+    let label = request.url()[1..]
+        .split('/')
+        .next()
+        .unwrap_or("")
+        .to_string();
+    let site = sites
+        .iter()
+        .find(|s| s.label == label)
+        .ok_or_else(|| ApiError::new(404, format!("Unknown database {}", label)))?;
+    if request.url() == format!("/{}", label) {
+        return Ok(Response::redirect_303(format!("/{}/", label)));
+    }
+    let request = request
+        .remove_prefix(&format!("/{}", label))
+        .ok_or_else(|| anyhow!("Request is not below /{}", label))?;
+    route_site(&request, site, settings)
+}
+
+/// Serves `databases`, given as (label, database) pairs. A single database is served
+/// at /, several ones below /<label>/. With a `scan_trigger`, an empty database shows a
+/// setup page that starts a scan of the first database. Returns once Dupletti is asked to
+/// stop and the requests in progress are answered.
+#[allow(clippy::too_many_arguments)]
+pub fn start_web_interface(
+    databases: Vec<(String, Arc<DatabasePool>)>,
+    bind_address: String,
+    port: u16,
+    allow_preview: bool,
+    allow_reveal: bool,
+    max_body_size: usize,
+    keep_policy: KeepPolicy,
+    max_group_size: usize,
+    videohash: bool,
+    videohash_min_duration: f64,
+    videohash_threshold: Option<u16>,
+    config: EffectiveConfig,
+    scan_trigger: Option<ScanTrigger>,
+    jobs: Option<JobQueue>,
+) -> Result<()> {
+    if allow_preview && bind_address != "127.0.0.1" {
+        log::warn!("You seem to be binding to a public interface and use --allow_preview.");
+    }
+    if allow_reveal && !is_loopback(&bind_address) {
+        log::warn!("--allow-reveal is ignored because you are not binding to a loopback address.");
+    }
+    let settings = WebSettings {
+        allow_preview,
+        allow_reveal: allow_reveal && is_loopback(&bind_address),
+        max_body_size,
+        keep_policy,
+        max_group_size,
+        config,
+        scan_trigger,
+        jobs,
+        videohash_threshold,
+    };
+
+    let single = databases.len() == 1;
+    let sites: Vec<Site> = databases
+        .into_iter()
+        .map(|(label, pool)| {
+            let base = if single {
+                "/".to_string()
+            } else {
+                format!("/{}/", label)
+            };
+            Site::new(label, pool, &base, videohash, videohash_min_duration)
+        })
+        .collect::<Result<_>>()?;
+    let tera = load_templates("/")?;
+    let listen_address = format!("{}:{}", bind_address, port);
+    let server = rouille::Server::new(listen_address, move |request| {
+        let response = if single {
+            route_site(request, &sites[0], &settings)
+        } else {
+            route_sites(request, &sites, &settings, &tera)
+        };
+        response.unwrap_or_else(|e| error_response(&e))
+    })
+    .map_err(|e| anyhow!("Unable to start the web interface: {}", e))?;
+    while !shutdown::requested() {
+        server.poll_timeout(Duration::from_millis(100));
+    }
+    log::info!("Stopping the web interface");
+    server.join();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::FileDigest;
+    use crate::urls;
+    use std::path::PathBuf;
+
+    #[test]
     fn test_rename_file() -> Result<()> {
         let db = Database::new("test3.sqlite", true)?;
         let file = FileDigest {
@@ -245,11 +2502,1647 @@ mod tests {
             path: PathBuf::from("/tmp/a"),
             digest: vec![0, 1, 2, 3],
             size: 1,
+            exif: Default::default(),
+            meta: None,
+        };
+        db.insert_filedigest(&file)?;
+        db.rename_file(1, Path::new("/tmp/a"), "/tmp/b")?;
+        let file = db.lookup_filedigest(1)?;
+        assert_eq!(file.path.to_string_lossy(), "/tmp/b");
+        Ok(())
+    }
+
+    #[test]
+    fn test_rename_file_updates_pending_files() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let old_path = dir.path().join("a");
+        let new_path = dir.path().join("b");
+        fs::write(&old_path, "a")?;
+        let mut db = Database::new("test_rename_file_updates_pending_files.sqlite", true)?;
+        db.insert_filedigest(&FileDigest::new(1, &old_path.to_string_lossy(), vec![1], 1))?;
+        db.store_pending_files(&[old_path.clone()].iter().cloned().collect())?;
+
+        let status = rename_file(&db, 1, new_path.to_string_lossy().into_owned())?;
+        assert_eq!(status, "success");
+        assert!(new_path.exists());
+        let (pending, _) = db.get_pending_files()?;
+        assert_eq!(pending.into_iter().collect::<Vec<_>>(), vec![new_path]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_rename_keeps_db_unchanged() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let old_path = dir.path().join("a");
+        fs::write(&old_path, "a")?;
+        let db = Database::new("test_failed_rename_keeps_db_unchanged.sqlite", true)?;
+        db.insert_filedigest(&FileDigest::new(1, &old_path.to_string_lossy(), vec![1], 1))?;
+
+        // renaming into a directory that doesn't exist fails, even when running as root
+        let target = dir.path().join("missing_dir").join("b");
+        assert!(rename_file(&db, 1, target.to_string_lossy().into_owned()).is_err());
+        assert!(old_path.exists());
+        assert_eq!(db.lookup_filedigest(1)?.path, old_path);
+
+        // an existing file is never replaced
+        let existing = dir.path().join("b");
+        fs::write(&existing, "b")?;
+        let rename = |to: &Path| rename_file(&db, 1, to.to_string_lossy().into_owned());
+        assert_eq!(status_of(rename(&existing)), 409);
+        assert_eq!(fs::read_to_string(&existing)?, "b");
+        // neither is a file renamed that is gone
+        fs::remove_file(&old_path)?;
+        assert_eq!(status_of(rename(&dir.path().join("c"))), 404);
+        assert_eq!(db.lookup_filedigest(1)?.path, old_path);
+        Ok(())
+    }
+
+    fn status_of(result: Result<impl std::fmt::Debug>) -> u16 {
+        let err = result.expect_err("expected a rejection");
+        error_response(&err).status_code
+    }
+
+    #[test]
+    fn test_queue_request() -> Result<()> {
+        let db = Database::new("test_queue_request.sqlite", true)?;
+        insert_group(&db, &["/tmp/nonexistent/a", "/tmp/nonexistent/b"], 1)?;
+        db.db
+            .execute("INSERT INTO protected_files (id) VALUES (2)", params![])?;
+        let db_mutex = Mutex::new(db);
+        let status = |response: Response| -> Result<serde_json::Value> {
+            let mut body = String::new();
+            response
+                .data
+                .into_reader_and_size()
+                .0
+                .read_to_string(&mut body)?;
+            let body: serde_json::Value = serde_json::from_str(&body)?;
+            Ok(serde_json::json!([body["status"], body["queued"]["files"]]))
+        };
+
+        let queued = status(handle_queue_request(&db_mutex, 1, true)?)?;
+        assert_eq!(queued, serde_json::json!(["queued", 1]));
+        let queued = status(handle_queue_request(&db_mutex, 1, true)?)?;
+        assert_eq!(queued, serde_json::json!(["already-queued", 1]));
+        assert_eq!(status_of(handle_queue_request(&db_mutex, 2, true)), 403);
+        {
+            let db = db_mutex.lock().unwrap();
+            let groups = similarities::get_list_of_similar_files(&db)?;
+            let queued: Vec<bool> = groups[0].files.iter().map(|f| f.queued).collect();
+            assert_eq!(queued, [true, false]);
+            assert_eq!(
+                db.queue_totals()?,
+                QueueTotals {
+                    files: 1,
+                    bytes: 10
+                }
+            );
+        }
+        let unqueued = status(handle_queue_request(&db_mutex, 1, false)?)?;
+        assert_eq!(unqueued, serde_json::json!(["unqueued", 0]));
+        let unqueued = status(handle_queue_request(&db_mutex, 1, false)?)?;
+        assert_eq!(unqueued, serde_json::json!(["not-queued", 0]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_execute_queue() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = Database::new("test_execute_queue.sqlite", true)?;
+        let mut paths = Vec::new();
+        // two copies, another two copies the first of which gets protected, and a file
+        // that is gone from disk
+        for (name, content) in [
+            ("a1", "a"),
+            ("a2", "a"),
+            ("p1", "p"),
+            ("p2", "p"),
+            ("m", "m"),
+        ]
+        .iter()
+        {
+            let path = dir.path().join(name);
+            fs::write(&path, content)?;
+            db.insert_filedigest(&crate::filehashing::create_filedigest(&path)?)?;
+            paths.push(path);
+        }
+        fs::remove_file(&paths[4])?;
+        for id in [1, 2, 3, 5].iter() {
+            assert!(db.queue_action(*id, QueuedAction::Delete)?);
+        }
+        db.db
+            .execute("INSERT INTO protected_files (id) VALUES (3)", params![])?;
+        let outcomes = |report: &QueueReport| -> Vec<Outcome> {
+            report.files.iter().map(|f| f.outcome).collect()
+        };
+
+        let report = execute_queue(&db, true)?;
+        assert_eq!(
+            outcomes(&report),
+            [
+                Outcome::WouldDelete,
+                Outcome::LastCopy,
+                Outcome::Protected,
+                Outcome::DoesNotExist
+            ]
+        );
+        assert_eq!(report.freed_bytes, 1);
+        assert_eq!(db.queue_totals()?.files, 4);
+        assert!(paths[0].exists());
+
+        let report = execute_queue(&db, false)?;
+        assert_eq!(
+            outcomes(&report),
+            [
+                Outcome::Deleted,
+                Outcome::LastCopy,
+                Outcome::Protected,
+                Outcome::DoesNotExist
+            ]
+        );
+        assert_eq!(report.freed_bytes, 1);
+        assert!(!paths[0].exists());
+        assert!(paths[1].exists() && paths[2].exists());
+        let left: Vec<i64> = db.get_queued_files()?.iter().map(|f| f.id).collect();
+        assert_eq!(left, [2, 3]);
+        assert_eq!(db.count_filedigests()?, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_bulk_request() -> Result<()> {
+        let db = Database::new("test_bulk_request.sqlite", true)?;
+        insert_group(&db, &["/tmp/nonexistent/a", "/tmp/nonexistent/b"], 1)?;
+        insert_group(&db, &["/tmp/nonexistent/c", "/tmp/elsewhere/d"], 2)?;
+        let pool = DatabasePool::with_writer("test_bulk_request.sqlite", db);
+        let db_mutex = pool.db_mutex();
+        let bulk = |body: &str| {
+            let request = Request::fake_http("POST", "/bulk", vec![], body.as_bytes().to_vec());
+            handle_bulk_request(&db_mutex, &request, 1024)
+        };
+        let counts = |response: Response| -> Result<serde_json::Value> {
+            let mut body = String::new();
+            response
+                .data
+                .into_reader_and_size()
+                .0
+                .read_to_string(&mut body)?;
+            Ok(serde_json::from_str(&body)?)
+        };
+
+        let response = bulk(r#"{"cross_dirs": true, "action": "protect", "dry_run": true}"#)?;
+        assert_eq!(
+            counts(response)?,
+            serde_json::json!({"groups": 1, "files": 2, "dry_run": true})
+        );
+        assert!(!db_mutex.lock().unwrap().is_protected(3)?);
+        bulk(r#"{"cross_dirs": true, "action": "protect"}"#)?;
+        assert_eq!(status_of(delete_file(&db_mutex.lock().unwrap(), 3)), 403);
+
+        bulk(r#"{"path": "/tmp/nonexistent/a", "action": "dismiss"}"#)?;
+        let cache = Mutex::new(ExistenceCache::new(EXISTENCE_CACHE_TTL));
+        let results_cache = ResultsCache::new();
+        let results = get_results_with_missing_marked(
+            &pool,
+            &results_cache,
+            &cache,
+            None,
+            None,
+            &GroupFilter::default(),
+        )?;
+        let ids: Vec<&str> = results.iter().map(|g| g.group_id.as_str()).collect();
+        assert_eq!(ids, ["0202020202020202"]);
+        let request = Request::fake_http("GET", "/?include_dismissed=1", vec![], vec![]);
+        let results = get_results_with_missing_marked(
+            &pool,
+            &results_cache,
+            &cache,
+            None,
+            None,
+            &group_filter_param(&request)?,
+        )?;
+        assert_eq!(results.len(), 2);
+        assert!(results.iter().any(|g| g.dismissed));
+
+        assert_eq!(status_of(bulk(r#"{"action": "delete"}"#)), 400);
+        assert_eq!(
+            status_of(bulk(r#"{"min_size": "big", "action": "dismiss"}"#)),
+            400
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_job_requests() -> Result<()> {
+        let db = Database::new("test_job_requests.sqlite", true)?;
+        let db_mutex = Arc::new(Mutex::new(db));
+        let run: crate::jobs::RunJob = Box::new(|_, _| Ok(serde_json::json!({})));
+        let queue = JobQueue::start(db_mutex, run)?;
+        let submit = |body: &str| {
+            let request = Request::fake_http("POST", "/api/jobs", vec![], body.as_bytes().to_vec());
+            handle_job_submit_request(Some(&queue), &request, 1024)
+        };
+
+        assert_eq!(status_of(submit(r#"{"kind": "format"}"#)), 400);
+        assert_eq!(
+            status_of(submit(r#"{"kind": "verify", "group_id": "xyz"}"#)),
+            400
+        );
+        assert_eq!(status_of(handle_jobs_request(None)), 404);
+        let response = submit(r#"{"kind": "verify", "group_id": "abcdabcdabcdabcd"}"#)?;
+        assert_eq!(response.status_code, 202);
+        let job = queue.list(1)?.remove(0);
+        assert_eq!(
+            job.request,
+            JobRequest::Verify {
+                group_id: "abcdabcdabcdabcd".into()
+            }
+        );
+
+        assert_eq!(status_of(handle_job_request(Some(&queue), job.id + 1)), 404);
+        assert_eq!(
+            status_of(handle_job_cancel_request(Some(&queue), job.id + 1)),
+            404
+        );
+        while !queue.get(job.id)?.unwrap().status.is_finished() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(
+            status_of(handle_job_cancel_request(Some(&queue), job.id)),
+            409
+        );
+        assert_eq!(handle_job_request(Some(&queue), job.id)?.status_code, 200);
+        Ok(())
+    }
+
+    #[test]
+    fn test_swap_request() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let paths: Vec<PathBuf> = ["a", "b", "c"].iter().map(|n| dir.path().join(n)).collect();
+        fs::write(&paths[0], "same")?;
+        fs::write(&paths[1], "same")?;
+        fs::write(&paths[2], "different")?;
+        let db = Database::new("test_swap_request.sqlite", true)?;
+        for path in paths.iter() {
+            db.insert_filedigest(&crate::filehashing::create_filedigest(path)?)?;
+        }
+        db.db
+            .execute("INSERT INTO protected_files (id) VALUES (3)", params![])?;
+        // c looks like a, not like b
+        db.db.execute(
+            "INSERT INTO video_hash (id, histogram) VALUES (1, x'ff00'), (2, x'00ff'), (3, x'fe01')",
+            params![],
+        )?;
+        let db_mutex = Mutex::new(db);
+        let vhs_mutex = Mutex::new(VideoHashState::new(true, 0.0));
+        let swap = |body: &str| {
+            let request = Request::fake_http(
+                "POST",
+                "/swap",
+                vec![("Content-Type".to_owned(), "application/json".to_owned())],
+                body.as_bytes().to_vec(),
+            );
+            handle_swap_request(&db_mutex, &vhs_mutex, &request, 1024, 8)
+        };
+
+        assert_eq!(status_of(swap(r#"{"a": 1, "b": 1}"#)), 400);
+        assert_eq!(status_of(swap(r#"{"a": 1, "b": 3}"#)), 403);
+        db_mutex
+            .lock()
+            .unwrap()
+            .db
+            .execute("DELETE FROM protected_files", params![])?;
+        assert_eq!(status_of(swap(r#"{"a": 1, "b": 3}"#)), 400);
+        assert_eq!(swap(r#"{"a": 1, "b": 2}"#)?.status_code, 200);
+        {
+            let db = db_mutex.lock().unwrap();
+            assert_eq!(db.lookup_filedigest(1)?.path, paths[1]);
+            assert_eq!(db.lookup_filedigest(2)?.path, paths[0]);
+        }
+
+        // content is only replaced between similar videos
+        let replace = r#"{"a": 3, "b": 2, "replace_content": true}"#;
+        assert_eq!(status_of(swap(replace)), 400);
+        let replace = r#"{"a": 3, "b": 1, "replace_content": true, "threshold": 1}"#;
+        assert_eq!(status_of(swap(replace)), 400);
+        let replace = r#"{"a": 3, "b": 1, "replace_content": true}"#;
+        assert_eq!(swap(replace)?.status_code, 200);
+        assert_eq!(fs::read_to_string(&paths[1])?, "different");
+        assert!(!paths[2].exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_group_filter_param() {
+        let request = Request::fake_http(
+            "GET",
+            "/?path=photos&min_size=10&type=image&cross_dirs=true&added_after=2024-05-08&root=backup",
+            vec![],
+            vec![],
+        );
+        let filter = group_filter_param(&request).unwrap();
+        assert_eq!(filter.path.as_deref(), Some("photos"));
+        assert_eq!(filter.min_size, Some(10));
+        assert_eq!(filter.file_type, Some(similarities::FileType::Image));
+        assert!(filter.cross_dirs);
+        assert!(filter.added_after.is_some());
+        assert_eq!(filter.root.as_deref(), Some("backup"));
+        assert!(!filter.cross_roots);
+        let request = Request::fake_http("GET", "/?type=document", vec![], vec![]);
+        assert_eq!(status_of(group_filter_param(&request)), 400);
+        let request = Request::fake_http("GET", "/?added_after=2024-02-31", vec![], vec![]);
+        assert_eq!(status_of(group_filter_param(&request)), 400);
+    }
+
+    #[test]
+    fn test_check_threshold() {
+        let max_threshold = videohash::max_distance(videohash::HISTOGRAM_LEN) + 1;
+        assert_eq!(check_threshold(0).unwrap(), 0);
+        assert_eq!(check_threshold(max_threshold).unwrap(), max_threshold);
+        assert_eq!(status_of(check_threshold(max_threshold + 1)), 400);
+        assert_eq!(status_of(check_threshold(u16::MAX)), 400);
+    }
+
+    #[test]
+    fn test_request_caps() {
+        let param = |query: &str| {
+            let request = Request::fake_http("GET", format!("/{}", query), vec![], vec![]);
+            per_page_param(&request, "max_group_size", 50)
         };
-        db.insert_filedigest(&file)?;
-        db.rename_file(1, "/tmp/b".to_string())?;
-        let file = db.lookup_filedigest(1)?;
-        assert_eq!(file.path.to_string_lossy(), "/tmp/b");
+        assert_eq!(param("").unwrap(), 50);
+        assert_eq!(param("?max_group_size=1").unwrap(), 1);
+        assert_eq!(param("?max_group_size=1000").unwrap(), MAX_PER_PAGE);
+        assert_eq!(status_of(param("?max_group_size=0")), 400);
+        assert_eq!(status_of(param("?max_group_size=1001")), 400);
+        assert_eq!(status_of(param("?max_group_size=many")), 400);
+        assert!(check_list_len("group_ids", MAX_LIST_LEN).is_ok());
+        assert_eq!(
+            status_of(check_list_len("group_ids", MAX_LIST_LEN + 1)),
+            400
+        );
+    }
+
+    #[test]
+    fn test_read_body_limit() {
+        let request = Request::fake_http("POST", "/rename/1", vec![], vec![b'a'; 16]);
+        assert_eq!(read_body(&request, 16).unwrap().len(), 16);
+
+        let request = Request::fake_http("POST", "/rename/1", vec![], vec![b'a'; 17]);
+        assert_eq!(status_of(read_body(&request, 16)), 413);
+
+        // a lying Content-Length header is rejected before reading anything
+        let headers = vec![("Content-Length".to_string(), "1000".to_string())];
+        let request = Request::fake_http("POST", "/rename/1", headers, vec![]);
+        assert_eq!(status_of(read_body(&request, 16)), 413);
+    }
+
+    #[test]
+    fn test_rename_post_validation() -> Result<()> {
+        let db_mutex = Mutex::new(Database::new("test_rename_post_validation.sqlite", true)?);
+        let request = Request::fake_http("POST", "/rename/1", vec![], b"not json".to_vec());
+        assert_eq!(
+            status_of(handle_rename_post_request(
+                &db_mutex,
+                1,
+                &request,
+                1024,
+                KeepPolicy::First
+            )),
+            400
+        );
+
+        let request =
+            Request::fake_http("POST", "/rename/1", vec![], br#"{"new_name": ""}"#.to_vec());
+        assert_eq!(
+            status_of(handle_rename_post_request(
+                &db_mutex,
+                1,
+                &request,
+                1024,
+                KeepPolicy::First
+            )),
+            400
+        );
+
+        let body = format!(r#"{{"new_name": "{}"}}"#, "a".repeat(2048)).into_bytes();
+        let request = Request::fake_http("POST", "/rename/1", vec![], body);
+        assert_eq!(
+            status_of(handle_rename_post_request(
+                &db_mutex,
+                1,
+                &request,
+                1024,
+                KeepPolicy::First
+            )),
+            413
+        );
+        Ok(())
+    }
+
+    fn insert_group(db: &Database, paths: &[&str], digest: u8) -> Result<()> {
+        for path in paths {
+            db.insert_filedigest(&FileDigest::new(0, path, vec![digest; 8], 10))?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_index_only_new_or_changed() -> Result<()> {
+        let db = Database::new("test_index_only_new_or_changed.sqlite", true)?;
+        insert_group(&db, &["/tmp/nonexistent/a", "/tmp/nonexistent/b"], 1)?;
+        insert_group(&db, &["/tmp/nonexistent/c", "/tmp/nonexistent/d"], 2)?;
+        let pool = DatabasePool::with_writer("test_index_only_new_or_changed.sqlite", db);
+        let db_mutex = pool.db_mutex();
+        let cache = Mutex::new(ExistenceCache::new(EXISTENCE_CACHE_TTL));
+        let results_cache = ResultsCache::new();
+        let tera = load_templates("/")?;
+        let render = |only: Option<SeenFilter>, session: &str| -> Result<String> {
+            let response = handle_index_request(
+                &pool,
+                &results_cache,
+                &cache,
+                &tera,
+                false,
+                KeepPolicy::First,
+                50,
+                &GroupFilter::default(),
+                ResultsView::List,
+                only,
+                None,
+                session,
+                None,
+            )?;
+            let mut body = String::new();
+            response
+                .data
+                .into_reader_and_size()
+                .0
+                .read_to_string(&mut body)?;
+            Ok(body)
+        };
+        let first = render(None, "browser")?;
+        assert!(first.contains("0101010101010101") && first.contains("0202020202020202"));
+        assert!(!render(Some(SeenFilter::New), "browser")?.contains("data-group-id"));
+
+        {
+            let db = db_mutex.lock().unwrap();
+            insert_group(&db, &["/tmp/nonexistent/e"], 2)?;
+            insert_group(&db, &["/tmp/nonexistent/f", "/tmp/nonexistent/g"], 3)?;
+        }
+        cache.lock().unwrap().clear();
+        let changed = render(Some(SeenFilter::Changed), "browser")?;
+        assert!(changed.contains("0202020202020202"));
+        assert!(!changed.contains("0101010101010101") && !changed.contains("0303030303030303"));
+        // listing the changed group acknowledged it, the new one is still new
+        assert!(!render(Some(SeenFilter::Changed), "browser")?.contains("data-group-id"));
+        let new = render(Some(SeenFilter::New), "browser")?;
+        assert!(new.contains("0303030303030303") && !new.contains("0202020202020202"));
+        // another browser saw nothing
+        let other = render(Some(SeenFilter::New), "other")?;
+        assert_eq!(other.matches("data-group-id").count(), 3);
+
+        let request = Request::fake_http("GET", "/?only=old", vec![], vec![]);
+        assert_eq!(status_of(seen_param(&request)), 400);
+        let request = Request::fake_http("GET", "/", vec![], vec![]);
+        let (session, new_session) = session_param(&request);
+        assert!(new_session && session.len() == 32);
+        let cookie = vec![("Cookie".to_string(), "session=abc".to_string())];
+        let request = Request::fake_http("GET", "/", cookie, vec![]);
+        assert_eq!(session_param(&request), ("abc".to_string(), false));
+        Ok(())
+    }
+
+    #[test]
+    fn test_ack_request() -> Result<()> {
+        let db = Database::new("test_ack_request.sqlite", true)?;
+        insert_group(&db, &["/tmp/nonexistent/a", "/tmp/nonexistent/b"], 1)?;
+        insert_group(&db, &["/tmp/nonexistent/c", "/tmp/nonexistent/d"], 2)?;
+        let db_mutex = Mutex::new(db);
+        let ack = |cookies: Vec<(String, String)>, body: &str| {
+            let request = Request::fake_http("POST", "/ack", cookies, body.as_bytes().to_vec());
+            handle_ack_request(&db_mutex, &request, 1024)
+        };
+        let cookie = vec![("Cookie".to_string(), "session=abc".to_string())];
+        ack(cookie, r#"{"group_ids": ["0101010101010101", "ffff"]}"#)?;
+        ack(
+            vec![],
+            r#"{"group_ids": ["0202020202020202"], "session": "def"}"#,
+        )?;
+        assert_eq!(status_of(ack(vec![], r#"{"group_ids": []}"#)), 400);
+        let too_many = serde_json::json!({
+            "group_ids": vec!["0101010101010101"; MAX_LIST_LEN + 1],
+            "session": "abc",
+        });
+        let request = Request::fake_http("POST", "/ack", vec![], too_many.to_string().into());
+        assert_eq!(
+            status_of(handle_ack_request(&db_mutex, &request, 1 << 20)),
+            400
+        );
+
+        let db = db_mutex.lock().unwrap();
+        let acked: Vec<String> = db.get_group_acks("abc")?.into_keys().collect();
+        assert_eq!(acked, ["0101010101010101"]);
+        assert_eq!(db.get_group_acks("def")?.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_duplicates_api_limit() -> Result<()> {
+        let db = Database::new("test_duplicates_api_limit.sqlite", true)?;
+        insert_group(&db, &["/tmp/nonexistent/a", "/tmp/nonexistent/b"], 1)?;
+        insert_group(
+            &db,
+            &[
+                "/tmp/nonexistent/c",
+                "/tmp/nonexistent/d",
+                "/tmp/nonexistent/e",
+            ],
+            2,
+        )?;
+        let pool = DatabasePool::with_writer("test_duplicates_api_limit.sqlite", db);
+        let cache = Mutex::new(ExistenceCache::new(EXISTENCE_CACHE_TTL));
+        let results_cache = ResultsCache::new();
+        let ids = |limit: Option<&str>| -> Result<Vec<String>> {
+            let response = handle_duplicates_api_request(
+                &pool,
+                &results_cache,
+                &cache,
+                &GroupFilter::default(),
+                limit.map(String::from),
+            )?;
+            let (mut reader, _) = response.data.into_reader_and_size();
+            let mut body = String::new();
+            reader.read_to_string(&mut body)?;
+            let groups: Vec<serde_json::Value> = serde_json::from_str(&body)?;
+            Ok(groups
+                .iter()
+                .map(|g| g["group_id"].as_str().unwrap().to_string())
+                .collect())
+        };
+        assert_eq!(ids(None)?.len(), 2);
+        // the group with three copies wastes more space
+        assert_eq!(ids(Some("1"))?, ["0202020202020202"]);
+        assert!(ids(Some("0"))?.is_empty());
+        assert_eq!(status_of(ids(Some("-1"))), 400);
+
+        let gb = 1024 * 1024 * 1024;
+        let limit = ResultsLimit {
+            num_groups: 100,
+            wasted_total: 50 * gb,
+            full_totals: false,
+        };
+        assert_eq!(
+            total_saved_line(2 * gb, 10, None),
+            "Total saved size: 2.00 GB"
+        );
+        assert_eq!(
+            total_saved_line(2 * gb, 10, Some(&limit)),
+            "Total saved size: 2.00 GB (only the 10 largest of 100 groups, pass --full-totals for all)"
+        );
+        let limit = ResultsLimit {
+            full_totals: true,
+            ..limit
+        };
+        assert_eq!(
+            total_saved_line(2 * gb, 10, Some(&limit)),
+            "Total saved size: 50.00 GB in 100 groups, the 10 largest are shown"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove_from_two_member_group() -> Result<()> {
+        let db = Database::new("test_remove_from_two_member_group.sqlite", true)?;
+        insert_group(&db, &["/tmp/nonexistent/a", "/tmp/nonexistent/b"], 1)?;
+        insert_group(&db, &["/tmp/nonexistent/c", "/tmp/nonexistent/d"], 2)?;
+
+        let update = remove_and_update_group(&db, 1, KeepPolicy::First)?;
+        assert_eq!(
+            update,
+            GroupUpdate {
+                status: "does-not-exist".to_string(),
+                group_id: "0101010101010101".to_string(),
+                dissolved: true,
+                files: vec![],
+                reclaimable: 0,
+            }
+        );
+        // the other group is untouched
+        assert_eq!(similarities::get_list_of_similar_files(&db)?.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove_from_three_member_group() -> Result<()> {
+        let db = Database::new("test_remove_from_three_member_group.sqlite", true)?;
+        insert_group(
+            &db,
+            &[
+                "/tmp/nonexistent/a",
+                "/tmp/nonexistent/b",
+                "/tmp/nonexistent/c",
+            ],
+            1,
+        )?;
+
+        let update = remove_and_update_group(&db, 2, KeepPolicy::First)?;
+        assert!(!update.dissolved);
+        assert_eq!(update.group_id, "0101010101010101");
+        let ids: Vec<_> = update.files.iter().map(|f| f.id).collect();
+        assert_eq!(ids, vec![1, 3]);
+        assert_eq!(update.reclaimable, 10);
+        Ok(())
+    }
+
+    #[test]
+    fn test_render_results_with_group_info() -> Result<()> {
+        let db = Database::new("test_render_results_with_group_info.sqlite", true)?;
+        insert_group(
+            &db,
+            &[
+                "/tmp/nonexistent/a",
+                "/tmp/nonexistent/b",
+                "/tmp/nonexistent/c",
+            ],
+            1,
+        )?;
+        let results = similarities::get_list_of_similar_files(&db)?;
+        let tera = load_templates("/")?;
+        let html = render_results_to_html(&results, &tera, false, KeepPolicy::First, 0)?.html;
+        assert!(html.contains(r#"data-group-id="0101010101010101""#));
+        assert!(html.contains(r#"<span class="group_reclaimable">20 B</span>"#));
+        assert!(!html.contains("first_seen"));
+
+        db.db.execute(
+            "UPDATE file_digests SET first_seen = ?1",
+            params![similarities::parse_date("2024-05-08")?],
+        )?;
+        let results = similarities::get_list_of_similar_files(&db)?;
+        let html = render_results_to_html(&results, &tera, false, KeepPolicy::First, 0)?.html;
+        assert!(html.contains(r#"<span class="first_seen">added 2024-05-08</span>"#));
+        Ok(())
+    }
+
+    #[test]
+    fn test_results_views() -> Result<()> {
+        let db = Database::new("test_results_views.sqlite", true)?;
+        insert_group(
+            &db,
+            &["/tmp/nonexistent/a.jpg", "/tmp/nonexistent/b.jpg"],
+            1,
+        )?;
+        insert_group(
+            &db,
+            &["/tmp/nonexistent/c.mp4", "/tmp/nonexistent/d.mp4"],
+            2,
+        )?;
+        let results = similarities::get_list_of_similar_files(&db)?;
+        assert!(results
+            .iter()
+            .flat_map(|g| g.files.iter())
+            .all(|f| f.is_image != f.is_video));
+        let tera = load_templates("/")?;
+        let render = |view| {
+            render_results_view(
+                &results,
+                &tera,
+                true,
+                KeepPolicy::First,
+                0,
+                view,
+                None,
+                None,
+            )
+            .map(|r| r.html)
+        };
+
+        let list = render(ResultsView::List)?;
+        assert!(list.contains(r#"<ul class="group" data-group-id="0101010101010101">"#));
+        assert!(list.contains(r#"List | <a href="?view=gallery">Gallery</a>"#));
+        assert!(!list.contains("thumbnail"));
+        assert!(!list.contains("Similar videos"));
+        let linked = render_results_view(
+            &results,
+            &tera,
+            true,
+            KeepPolicy::First,
+            0,
+            ResultsView::List,
+            None,
+            Some(40),
+        )?;
+        assert!(linked
+            .html
+            .contains(r#"<a href="videohash/40">Similar videos</a>"#));
+        let gallery = render(ResultsView::Gallery)?;
+        assert!(gallery.contains(r#"<ul class="group gallery" data-group-id="0101010101010101">"#));
+        assert!(gallery.contains(r#"<a href="?view=list">List</a> | Gallery"#));
+        assert_eq!(gallery.matches(r#"loading="lazy""#).count(), 2);
+        assert_eq!(
+            gallery
+                .matches(r#"<span class="thumbnail placeholder">video</span>"#)
+                .count(),
+            2
+        );
+        // both views list the same files with the same buttons
+        for html in [&list, &gallery] {
+            assert_eq!(html.matches(r#"class="rename_button""#).count(), 4);
+            assert!(html.contains("/tmp/nonexistent/d.mp4"));
+        }
+        // without previews, the gallery doesn't link images it can't show
+        let gallery = render_results_view(
+            &results,
+            &tera,
+            false,
+            KeepPolicy::First,
+            0,
+            ResultsView::Gallery,
+            None,
+            None,
+        )?
+        .html;
+        assert!(!gallery.contains("<img"));
+
+        let request = Request::fake_http("GET", "/?view=gallery", vec![], vec![]);
+        assert_eq!(view_param(&request)?, (ResultsView::Gallery, true));
+        let cookie = vec![("Cookie".to_string(), "view=gallery".to_string())];
+        let request = Request::fake_http("GET", "/", cookie.clone(), vec![]);
+        assert_eq!(view_param(&request)?, (ResultsView::Gallery, false));
+        let request = Request::fake_http("GET", "/?view=list", cookie, vec![]);
+        assert_eq!(view_param(&request)?, (ResultsView::List, true));
+        let request = Request::fake_http("GET", "/", vec![], vec![]);
+        assert_eq!(view_param(&request)?, (ResultsView::List, false));
+        let request = Request::fake_http("GET", "/?view=table", vec![], vec![]);
+        assert_eq!(status_of(view_param(&request)), 400);
+        Ok(())
+    }
+
+    #[test]
+    fn test_streamed_results_match_render() -> Result<()> {
+        let db = Database::new("test_streamed_results_match_render.sqlite", true)?;
+        insert_group(&db, &["/tmp/nonexistent/a", "/tmp/nonexistent/b"], 1)?;
+        insert_group(
+            &db,
+            &["/tmp/nonexistent/<c>", "/tmp/nonexistent/d", "/x/e"],
+            2,
+        )?;
+        db.set_group_note("0202020202020202", "keep the one in /x")?;
+        let results = similarities::get_list_of_similar_files(&db)?;
+        let tera = load_templates("/")?;
+        for allow_preview in [false, true] {
+            let html =
+                render_results_to_html(&results, &tera, allow_preview, KeepPolicy::First, 3)?.html;
+            let mut streamed = Vec::new();
+            write_results_html(
+                &mut streamed,
+                &results,
+                &tera,
+                allow_preview,
+                KeepPolicy::First,
+                3,
+                None,
+            )?;
+            assert_eq!(String::from_utf8(streamed)?, html);
+        }
+        let mut streamed = Vec::new();
+        write_results_html(&mut streamed, &[], &tera, false, KeepPolicy::First, 0, None)?;
+        assert_eq!(
+            String::from_utf8(streamed)?,
+            render_results_to_html(&[], &tera, false, KeepPolicy::First, 0)?.html
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_report_is_standalone() -> Result<()> {
+        let db = Database::new("test_report_is_standalone.sqlite", true)?;
+        insert_group(&db, &["/tmp/nonexistent/a b", "/tmp/nonexistent/<c>"], 1)?;
+        let results = similarities::get_list_of_similar_files(&db)?;
+        let tera = load_templates("/web/")?;
+        let video = |path: &str, size, distance| videohash::ClusteredVideo {
+            path: path.to_string(),
+            size,
+            file_url: urls::file_url(Path::new(path)),
+            distance,
+        };
+        let mut info = ReportInfo {
+            // 2021-06-01 12:00 UTC
+            generated_at: 1622548800,
+            roots: vec!["/tmp/<root>".to_string()],
+            videos: vec![videohash::ClusterReport {
+                group_id: "v1".to_string(),
+                files: vec![
+                    video("/tmp/nonexistent/x.mp4", 2000, 0),
+                    video("/tmp/nonexistent/y.mp4", 1000, 12),
+                ],
+            }],
+            videohash_threshold: 64,
+        };
+        let report = |info: &ReportInfo| -> Result<String> {
+            let mut html = Vec::new();
+            write_results_html(
+                &mut html,
+                &results,
+                &tera,
+                false,
+                KeepPolicy::First,
+                0,
+                Some(info),
+            )?;
+            Ok(String::from_utf8(html)?)
+        };
+        let html = report(&info)?;
+        assert!(html.contains("<title>Dupletti Report</title>"));
+        assert!(html.contains("<style>"));
+        assert!(html.contains("Generated 2021-06-01 12:00 from &#x2F;tmp&#x2F;&lt;root&gt;"));
+        assert!(html.contains(r#"href="file:///tmp/nonexistent/a%20b""#));
+        assert!(html.contains("&#x2F;nonexistent&#x2F;&lt;c&gt;"));
+        assert!(html.contains("Similar videos"));
+        assert!(html.contains(r#"href="file:///tmp/nonexistent/y.mp4""#));
+        assert!(html.contains("distance 12"));
+        for server_only in ["<base", "<script", "style.css", "reveal/", "remove_button"] {
+            assert!(!html.contains(server_only), "{}", server_only);
+        }
+
+        info.videos.clear();
+        let html = report(&info)?;
+        assert!(!html.contains("Similar videos"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_bad_group_is_replaced() -> Result<()> {
+        let db = Database::new("test_bad_group_is_replaced.sqlite", true)?;
+        insert_group(&db, &["/tmp/nonexistent/a", "/tmp/nonexistent/b"], 1)?;
+        insert_group(&db, &["/tmp/nonexistent/c", "/tmp/nonexistent/d"], 2)?;
+        insert_group(&db, &["/tmp/nonexistent/e", "/tmp/nonexistent/f"], 3)?;
+        // tera can't format this date
+        db.db.execute(
+            "UPDATE file_digests SET first_seen = ?1 WHERE path = '/tmp/nonexistent/d'",
+            params![i64::MAX],
+        )?;
+        let results = similarities::get_list_of_similar_files(&db)?;
+        let tera = load_templates("/")?;
+
+        let render = |view| {
+            render_results_view(
+                &results,
+                &tera,
+                false,
+                KeepPolicy::First,
+                0,
+                view,
+                None,
+                None,
+            )
+        };
+        let rendered = render(ResultsView::List)?;
+        assert_eq!(rendered.skipped_groups, ["0202020202020202"]);
+        let html = rendered.html;
+        assert!(html.contains(r#"data-group-id="0101010101010101""#));
+        assert!(html.contains(r#"data-group-id="0303030303030303""#));
+        assert!(
+            html.contains(r#"<ul class="group render_error" data-group-id="0202020202020202">"#)
+        );
+        assert!(html.contains("This group couldn't be shown because of file 4, see the log"));
+        assert!(html.contains("1 groups couldn't be shown, see the log: 0202020202020202"));
+        assert_eq!(html.matches(r#"class="fileentry"#).count(), 4);
+        // the gallery doesn't show dates
+        assert!(render(ResultsView::Gallery)?.skipped_groups.is_empty());
+
+        let mut streamed = Vec::new();
+        let skipped = write_results_html(
+            &mut streamed,
+            &results,
+            &tera,
+            false,
+            KeepPolicy::First,
+            0,
+            None,
+        )?;
+        assert_eq!(skipped, ["0202020202020202"]);
+        let streamed = String::from_utf8(streamed)?;
+        assert!(streamed.contains(r#"data-group-id="0303030303030303""#));
+        assert!(streamed.contains("because of file 4"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_reveal() -> Result<()> {
+        let db = Database::new("test_reveal.sqlite", true)?;
+        insert_group(&db, &["/tmp/nonexistent/<a & b>.mp4"], 1)?;
+        let pool = DatabasePool::with_writer("test_reveal.sqlite", db);
+        let tera = load_templates("/")?;
+
+        let html = render_reveal_page(Path::new("/tmp/<a & b>.mp4"), 1, &tera, false, None)?;
+        assert!(html.contains("&lt;a &amp; b&gt;.mp4</code>"));
+        assert!(!html.contains("<a & b>"));
+        assert!(!html.contains("reveal_button\">"));
+        assert!(!html.contains("file_meta"));
+        let meta = FileMeta {
+            created: None,
+            changed: Some(86400),
+            uid: Some(4321),
+            mode: Some(0o644),
+            ..Default::default()
+        };
+        let html = render_reveal_page(Path::new("/tmp/a.mp4"), 1, &tera, true, Some(&meta))?;
+        assert!(html.contains(r#"id="reveal_button""#));
+        assert!(html.contains(r#"href="similar_to/1""#));
+        assert!(html.contains("<dt>Changed</dt><dd>1970-01-02 00:00:00</dd>"));
+        assert!(!html.contains("<dt>Created</dt>"));
+        assert!(html.contains("<dt>Permissions</dt><dd>rw-r--r--</dd>"));
+
+        let response = handle_reveal_request(&*pool.reader()?, 1, &tera, false)?;
+        assert_eq!(response.status_code, 200);
+        assert_eq!(status_of(handle_reveal_post_request(&pool, 1, false)), 403);
+        assert_eq!(status_of(handle_reveal_post_request(&pool, 1, true)), 404);
+        Ok(())
+    }
+
+    #[test]
+    fn test_videohash_sweep() -> Result<()> {
+        let db = Database::new("test_videohash_sweep.sqlite", true)?;
+        db.db.execute(
+            "INSERT INTO file_digests (id, path, size) VALUES \
+                (1, '/tmp/a.mp4', 10), (2, '/tmp/b.mp4', 11), (3, '/tmp/c.wmv', 12), \
+                (4, '/tmp/d.avi', 13), (5, '/tmp/e.wmv', 15), (6, '/tmp/f.mp4', 16)",
+            params![],
+        )?;
+        db.db.execute(
+            "INSERT INTO video_hash (id, histogram) VALUES \
+            (1, x'ff00ff00'), (2, x'ff01ff00'), (3, x'000000a0'), \
+            (4, x'00ff00ff'), (5, x'000000a2'), (6, x'00000030')",
+            params![],
+        )?;
+        let db_mutex = Mutex::new(db);
+        let vhd = VideoHashData::new(&db_mutex, 0.0)?;
+        let thresholds = sweep_thresholds(0, 511, 16)?;
+        let points = vhd.sweep(&thresholds, KeepPolicy::Largest);
+        assert_eq!(points.len(), 32);
+        assert_eq!(points[0].clustered_files, 0);
+        assert_eq!(
+            points[1],
+            SweepPoint {
+                threshold: 16,
+                clusters: 2,
+                clustered_files: 4,
+                reclaimable_bytes: 10 + 12,
+            }
+        );
+        for pair in points.windows(2) {
+            assert!(pair[0].clustered_files <= pair[1].clustered_files);
+            assert!(pair[0].reclaimable_bytes <= pair[1].reclaimable_bytes);
+        }
+        assert!(points.last().unwrap().clustered_files > 4);
+
+        assert_eq!(sweep_thresholds(8, 128, 8)?.len(), 16);
+        assert_eq!(status_of(sweep_thresholds(0, 510, 10)), 400);
+        assert_eq!(status_of(sweep_thresholds(10, 5, 1)), 400);
+        assert_eq!(status_of(sweep_thresholds(0, 10, 0)), 400);
+        assert_eq!(status_of(sweep_thresholds(0, 1000, 100)), 400);
+        let vhs_mutex = Mutex::new(VideoHashState::new(true, 0.0));
+        let response =
+            handle_videohash_sweep_request(&db_mutex, &vhs_mutex, &[16], KeepPolicy::Largest)?;
+        assert_eq!(response.status_code, 200);
+        Ok(())
+    }
+
+    #[test]
+    fn test_refresh_skips_empty_histograms() -> Result<()> {
+        let db = Database::new("test_refresh_skips_empty_histograms.sqlite", true)?;
+        db.db.execute(
+            "INSERT INTO file_digests (id, path, size) VALUES \
+                (1, '/tmp/a.mp4', 10), (2, '/tmp/b.mp4', 11), (3, '/tmp/c.mp4', 12)",
+            params![],
+        )?;
+        db.db.execute(
+            "INSERT INTO video_hash (id, histogram) VALUES \
+            (1, x'ff000000'), (2, x'00000000'), (3, x'00000000')",
+            params![],
+        )?;
+        let db_mutex = Mutex::new(db);
+        let vhd = VideoHashData::new(&db_mutex, 0.0)?;
+        let ids: Vec<i64> = vhd.hashes.iter().map(|h| h.id).collect();
+        assert_eq!(ids, [1]);
+        assert_eq!(vhd.distances.shape(), [1, 1]);
+
+        let db = db_mutex.lock().unwrap();
+        let errors: Vec<i64> = db.get_video_errors()?.into_iter().map(|e| e.0).collect();
+        assert_eq!(errors, [2, 3]);
+        assert_eq!(db.get_all_files_with_videohash()?.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_videohash_disabled() -> Result<()> {
+        let db = Database::new("test_videohash_disabled.sqlite", true)?;
+        db.db.execute(
+            "INSERT INTO file_digests (id, path, size, digest) VALUES \
+                (1, '/tmp/a.mp4', 10, x'01'), (2, '/tmp/b.mp4', 11, x'02')",
+            params![],
+        )?;
+        let pool = DatabasePool::with_writer("test_videohash_disabled.sqlite", db);
+        let db_mutex = pool.db_mutex();
+        let vhs_mutex = Mutex::new(VideoHashState::new(false, 0.0));
+        let tera = load_templates("/")?;
+        let request = |refresh| {
+            handle_videohash_request(
+                &db_mutex,
+                &vhs_mutex,
+                1,
+                refresh,
+                &tera,
+                false,
+                KeepPolicy::First,
+            )
+        };
+
+        let response = request(false)?;
+        assert_eq!(response.status_code, 200);
+        let (mut reader, _) = response.data.into_reader_and_size();
+        let mut body = String::new();
+        reader.read_to_string(&mut body)?;
+        assert!(body.contains(VIDEOHASH_DISABLED));
+        assert!(request(true).is_ok());
+        let group_id = "v0000000000000000";
+        assert_eq!(
+            status_of(handle_group_api_request(&pool, &vhs_mutex, group_id, 1)),
+            409
+        );
+        assert!(vhs_mutex.lock().unwrap().data.is_none());
+
+        // becomes available once video hashes show up
+        db_mutex.lock().unwrap().db.execute(
+            "INSERT INTO video_hash (id, histogram) VALUES (1, x'ff00'), (2, x'ff00')",
+            params![],
+        )?;
+        let response = request(false)?;
+        assert_eq!(response.status_code, 200);
+        let (mut reader, _) = response.data.into_reader_and_size();
+        let mut body = String::new();
+        reader.read_to_string(&mut body)?;
+        assert!(!body.contains(VIDEOHASH_DISABLED));
+        let group_id = vhs_mutex.lock().unwrap().data(&db_mutex)?.clusters(1)[0]
+            .group_id
+            .clone();
+        let response = handle_group_api_request(&pool, &vhs_mutex, &group_id, 1)?;
+        assert_eq!(response.status_code, 200);
+        Ok(())
+    }
+
+    #[test]
+    fn test_similar_to() -> Result<()> {
+        let db = Database::new("test_similar_to.sqlite", true)?;
+        db.db.execute(
+            "INSERT INTO file_digests (id, path, size, digest) VALUES \
+                (1, '/tmp/a.mp4', 10, x'01'), (2, '/tmp/b.mp4', 11, x'02'), \
+                (3, '/tmp/c.mp4', 12, x'03'), (4, '/tmp/<d>.mp4', 13, x'04')",
+            params![],
+        )?;
+        db.db.execute(
+            "INSERT INTO video_hash (id, histogram) VALUES \
+            (1, x'ff00'), (2, x'f00f'), (4, x'fa05')",
+            params![],
+        )?;
+        let db_mutex = Mutex::new(db);
+        let vhs_mutex = Mutex::new(VideoHashState::new(true, 0.0));
+        let tera = load_templates("/")?;
+
+        let response = handle_similar_to_api_request(&db_mutex, &vhs_mutex, 1, 64)?;
+        let (mut reader, _) = response.data.into_reader_and_size();
+        let mut body = String::new();
+        reader.read_to_string(&mut body)?;
+        let matches: Vec<serde_json::Value> = serde_json::from_str(&body)?;
+        let ids: Vec<(i64, u64)> = matches
+            .iter()
+            .map(|m| (m["id"].as_i64().unwrap(), m["distance"].as_u64().unwrap()))
+            .collect();
+        assert_eq!(ids, [(4, 10), (2, 30)]);
+        assert_eq!(
+            status_of(handle_similar_to_api_request(&db_mutex, &vhs_mutex, 3, 64)),
+            409
+        );
+
+        let response = handle_similar_to_request(&db_mutex, &vhs_mutex, 1, 64, &tera, false)?;
+        let (mut reader, _) = response.data.into_reader_and_size();
+        let mut body = String::new();
+        reader.read_to_string(&mut body)?;
+        assert!(body.contains("&lt;d&gt;.mp4"));
+        let response = handle_similar_to_request(&db_mutex, &vhs_mutex, 3, 64, &tera, false)?;
+        let (mut reader, _) = response.data.into_reader_and_size();
+        let mut body = String::new();
+        reader.read_to_string(&mut body)?;
+        assert!(body.contains("--videohash"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_compare_videos() -> Result<()> {
+        let db = Database::new("test_compare_videos.sqlite", true)?;
+        db.db.execute(
+            "INSERT INTO file_digests (id, path, size, digest) VALUES \
+                (1, '/tmp/a.mp4', 10, x'01'), (2, '/tmp/<b>.mp4', 11, x'02'), \
+                (3, '/tmp/c.mp4', 12, x'03')",
+            params![],
+        )?;
+        db.db.execute(
+            "INSERT INTO video_hash (id, histogram) VALUES (1, x'ff00'), (2, x'f00f')",
+            params![],
+        )?;
+        db.db.execute(
+            "INSERT INTO media_metadata (id, duration, width, height) VALUES (1, 12.5, 640, 480)",
+            params![],
+        )?;
+        let pool = DatabasePool::with_writer("test_compare_videos.sqlite", db);
+        let db_mutex = pool.db_mutex();
+        let vhs_mutex = Mutex::new(VideoHashState::new(true, 0.0));
+        let tera = load_templates("/")?;
+        let compare = |ids: [i64; 2]| -> Result<String> {
+            let response = handle_compare_request(&pool, &vhs_mutex, ids, &tera)?;
+            let mut body = String::new();
+            response
+                .data
+                .into_reader_and_size()
+                .0
+                .read_to_string(&mut body)?;
+            Ok(body)
+        };
+
+        let body = compare([1, 2])?;
+        assert!(body.contains("Distance 30"));
+        assert!(body.contains("&lt;b&gt;.mp4"));
+        assert!(body.contains("640x480"));
+        assert!(body.contains(r#"src="strip/1""#) && body.contains(r#"src="strip/2""#));
+        assert!(compare([1, 3])?.contains("No comparable video hashes"));
+
+        // files that can't be decoded get a placeholder, which isn't cached
+        let response = handle_strip_request(&pool, 3)?;
+        assert_eq!(response.status_code, 200);
+        assert!(response
+            .headers
+            .iter()
+            .any(|(k, v)| k == "Content-Type" && v == "image/svg+xml"));
+        assert_eq!(
+            db_mutex
+                .lock()
+                .unwrap()
+                .get_thumbnail(3, framestrips::STRIP_KIND)?,
+            None
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_external_changes_invalidate_caches() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db_path = dir.path().join("digests.sqlite");
+        let a = dir.path().join("a.mp4");
+        let b = dir.path().join("b.mp4");
+        fs::write(&a, "same")?;
+        fs::write(&b, "same")?;
+        // stands in for a scan running in another process
+        let scanner = Database::new(&db_path, false)?;
+        scanner.db.execute(
+            "INSERT INTO file_digests (id, path, size, digest) VALUES \
+             (1, ?1, 4, x'01020304'), (2, ?2, 4, x'01020304')",
+            params![a.to_string_lossy(), b.to_string_lossy()],
+        )?;
+        scanner.db.execute(
+            "INSERT INTO video_hash (id, histogram) VALUES (1, x'ff00')",
+            params![],
+        )?;
+        let pool = Arc::new(DatabasePool::open(&db_path, false)?);
+        let served = pool.db_mutex();
+        let site = Site::new("digests".to_string(), pool, "/", true, 0.0)?;
+        let num_hashes = || {
+            site.vhs_mutex
+                .lock()
+                .unwrap()
+                .data(&served)
+                .unwrap()
+                .hashes
+                .len()
+        };
+        let missing = || -> Result<Vec<bool>> {
+            let groups = get_results_with_missing_marked(
+                &site.pool,
+                &site.results_cache,
+                &site.existence_cache,
+                None,
+                None,
+                &GroupFilter::default(),
+            )?;
+            Ok(groups[0].files.iter().map(|f| f.missing).collect())
+        };
+        assert_eq!(num_hashes(), 1);
+        assert_eq!(missing()?, [false, false]);
+        assert!(!site.pick_up_external_changes()?);
+
+        fs::remove_file(&b)?;
+        scanner.db.execute(
+            "INSERT INTO video_hash (id, histogram) VALUES (2, x'f00f')",
+            params![],
+        )?;
+        assert!(site.pick_up_external_changes()?);
+        assert!(site.vhs_mutex.lock().unwrap().data.is_none());
+        assert_eq!(num_hashes(), 2);
+        // without the change, the existence of b would still be cached
+        assert_eq!(missing()?, [false, true]);
+        assert!(!site.pick_up_external_changes()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_redownloads_are_flagged() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let original = dir.path().join("original.mp4");
+        fs::write(&original, "downloaded twice")?;
+        let db_mutex = Mutex::new(Database::new("test_redownloads_are_flagged.sqlite", true)?);
+        let index = |path: &Path| {
+            crate::filehashing::process_filelist(
+                &db_mutex,
+                vec![path.to_path_buf()],
+                crate::batcher::BatchLimits::with_items(16),
+                PathCase::Sensitive,
+                false,
+                &[],
+                &mut crate::ScanStats::default(),
+                &crate::Progress::default(),
+            )
+        };
+        index(&original)?;
+        {
+            let db = db_mutex.lock().unwrap();
+            let id = db.get_all_filedigests()?[0].id;
+            assert_eq!(delete_file(&db, id)?, "success");
+            assert!(db.get_redownloads()?.is_empty());
+            // deleted a while before the copy shows up again
+            db.db.execute(
+                "UPDATE tombstones SET deleted_at = deleted_at - 60",
+                params![],
+            )?;
+        }
+        let copy = dir.path().join("copy.mp4");
+        fs::write(&copy, "downloaded twice")?;
+        fs::write(dir.path().join("other.mp4"), "something else")?;
+        index(&copy)?;
+        index(&dir.path().join("other.mp4"))?;
+
+        let db = db_mutex.lock().unwrap();
+        let redownloads = db.get_redownloads()?;
+        assert_eq!(redownloads.len(), 1);
+        assert_eq!(redownloads[0].path, copy);
+        let tombstone = redownloads[0].previously_deleted.as_ref().unwrap();
+        assert_eq!(tombstone.path, original.to_string_lossy());
+
+        let tera = load_templates("/")?;
+        let html = render_redownloads_to_html(&redownloads, &tera, false)?;
+        assert!(html.contains("previously deleted from"));
+        assert!(html.contains("original.mp4"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_loopback() {
+        assert!(is_loopback("127.0.0.1"));
+        assert!(is_loopback("::1"));
+        assert!(is_loopback("localhost"));
+        assert!(!is_loopback("0.0.0.0"));
+        assert!(!is_loopback("192.168.1.10"));
+    }
+
+    #[test]
+    fn test_group_page() -> Result<()> {
+        let db = Database::new("test_group_page.sqlite", true)?;
+        let paths: Vec<String> = (0..120).map(|i| format!("/tmp/cache/{}", i)).collect();
+        let paths: Vec<&str> = paths.iter().map(|p| p.as_str()).collect();
+        insert_group(&db, &paths, 1)?;
+        let db_mutex = Mutex::new(db);
+        let tera = load_templates("/")?;
+
+        let results = {
+            let db = db_mutex.lock().unwrap();
+            similarities::get_collapsed_list_of_similar_files(&db, Some(50))?
+        };
+        let html = render_results_to_html(&results, &tera, false, KeepPolicy::First, 0)?.html;
+        assert_eq!(html.matches(r#"class="fileentry"#).count(), 50);
+        assert!(html.contains("120 files"));
+        assert!(html.contains(r#"href="group/0101010101010101""#));
+        assert!(html.contains(r#"<span class="group_reclaimable">1.16 KB</span>"#));
+
+        let group_id = "0101010101010101";
+        let response =
+            handle_group_page_request(&db_mutex.lock().unwrap(), group_id, 2, 50, &tera, false)?;
+        let (mut reader, _) = response.data.into_reader_and_size();
+        let mut body = String::new();
+        reader.read_to_string(&mut body)?;
+        assert_eq!(body.matches(r#"class="fileentry"#).count(), 20);
+        assert!(body.contains("/tmp/cache/100"));
+        assert!(body.contains("Page 3 of 3"));
+        assert!(body.contains("?page=1"));
+        assert!(!body.contains("?page=3"));
+        let page = |page: usize, page_size: usize| {
+            handle_group_page_request(
+                &db_mutex.lock().unwrap(),
+                group_id,
+                page,
+                page_size,
+                &tera,
+                false,
+            )
+        };
+        assert_eq!(status_of(page(3, 50)), 400);
+        assert_eq!(status_of(page(usize::MAX / 2, 50)), 400);
+        assert_eq!(status_of(page(0, 0)), 400);
+        assert_eq!(status_of(page(0, MAX_PER_PAGE + 1)), 400);
+        assert_eq!(page(119, 1)?.status_code, 200);
+
+        assert_eq!(
+            status_of(handle_group_page_request(
+                &db_mutex.lock().unwrap(),
+                "zz",
+                0,
+                50,
+                &tera,
+                false
+            )),
+            400
+        );
+        assert_eq!(
+            status_of(handle_group_page_request(
+                &db_mutex.lock().unwrap(),
+                "02",
+                0,
+                50,
+                &tera,
+                false
+            )),
+            400
+        );
+        assert_eq!(
+            status_of(handle_group_page_request(
+                &db_mutex.lock().unwrap(),
+                "0202020202020202",
+                0,
+                50,
+                &tera,
+                false
+            )),
+            404
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_raw_preview() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (raw, jpeg) = crate::rawpreview::tests::synthetic_raw(true);
+        let raw_path = dir.path().join("photo.NEF");
+        let broken_path = dir.path().join("broken.cr2");
+        fs::write(&raw_path, raw)?;
+        fs::write(&broken_path, "not a raw file")?;
+        let db = Database::new("test_raw_preview.sqlite", true)?;
+        insert_group(
+            &db,
+            &[raw_path.to_str().unwrap(), broken_path.to_str().unwrap()],
+            1,
+        )?;
+        let pool = DatabasePool::with_writer("test_raw_preview.sqlite", db);
+
+        let response = handle_preview_request(&pool, 1)?;
+        assert_eq!(response.status_code, 200);
+        assert!(response
+            .headers
+            .iter()
+            .any(|(k, v)| k == "Content-Type" && v == "image/jpeg"));
+        let (mut reader, _) = response.data.into_reader_and_size();
+        let mut body = Vec::new();
+        reader.read_to_end(&mut body)?;
+        assert_eq!(body, jpeg);
+
+        let response = handle_preview_request(&pool, 2)?;
+        assert_eq!(response.status_code, 200);
+        assert!(response
+            .headers
+            .iter()
+            .any(|(k, v)| k == "Content-Type" && v == "image/svg+xml"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_still_preview() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        // a WebP file with the wrong extension and a HEIC file ffmpeg can't decode
+        let webp_path = dir.path().join("scraped.jpg");
+        let heic_path = dir.path().join("IMG_0001.heic");
+        fs::write(&webp_path, b"RIFF\x24\0\0\0WEBPVP8L")?;
+        fs::write(&heic_path, b"\0\0\0\x18ftypheic\0\0\0\0broken")?;
+        let db = Database::new("test_still_preview.sqlite", true)?;
+        insert_group(
+            &db,
+            &[webp_path.to_str().unwrap(), heic_path.to_str().unwrap()],
+            1,
+        )?;
+        let pool = DatabasePool::with_writer("test_still_preview.sqlite", db);
+        let db_mutex = pool.db_mutex();
+
+        let content_type = |response: &Response| {
+            response
+                .headers
+                .iter()
+                .find(|(k, _)| k == "Content-Type")
+                .map(|(_, v)| v.to_string())
+        };
+        let response = handle_preview_request(&pool, 1)?;
+        assert_eq!(response.status_code, 200);
+        assert_eq!(content_type(&response).as_deref(), Some("image/webp"));
+
+        let response = handle_preview_request(&pool, 2)?;
+        assert_eq!(response.status_code, 200);
+        assert_eq!(content_type(&response).as_deref(), Some("image/svg+xml"));
+        let errors = db_mutex.lock().unwrap().get_video_errors()?;
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_bursts_page() -> Result<()> {
+        let db = Database::new("test_bursts_page.sqlite", true)?;
+        for id in 1..=3 {
+            let mut file = FileDigest::new(id, &format!("/photos/{}.jpg", id), vec![id as u8], 1);
+            file.exif.capture_date = Some(format!("2021-06-13 14:15:1{}", id));
+            db.insert_filedigest(&file)?;
+            db.db.execute(
+                "INSERT INTO image_hashes (id, dhash) VALUES (?1, ?2)",
+                params![id, if id == 3 { 0 } else { -1 }],
+            )?;
+        }
+        let db_mutex = Mutex::new(db);
+        let tera = load_templates("/")?;
+        let get = |url: &str| -> Result<String> {
+            let request = Request::fake_http("GET", url, vec![], vec![]);
+            let response = handle_bursts_request(&db_mutex.lock().unwrap(), &request, &tera, true)?;
+            let mut body = String::new();
+            response
+                .data
+                .into_reader_and_size()
+                .0
+                .read_to_string(&mut body)?;
+            Ok(body)
+        };
+        let html = get("/bursts")?;
+        assert!(html.contains("1 bursts"));
+        assert!(html.contains(r#"src="preview/1""#) && html.contains(r#"src="preview/2""#));
+        assert!(!html.contains("/photos/3.jpg"));
+        let json: serde_json::Value = serde_json::from_str(&get("/api/bursts?window=0")?)?;
+        assert_eq!(json, serde_json::json!([]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_exclude_request() -> Result<()> {
+        let db = Database::new("test_exclude_request.sqlite", true)?;
+        insert_group(&db, &["/tmp/nonexistent/a", "/tmp/nonexistent/b"], 1)?;
+        insert_group(&db, &["/tmp/nonexistent/c", "/tmp/nonexistent/d"], 2)?;
+        let pool = DatabasePool::with_writer("test_exclude_request.sqlite", db);
+        let db_mutex = pool.db_mutex();
+        handle_exclude_request(&db_mutex, 3)?;
+        let cache = Mutex::new(ExistenceCache::new(EXISTENCE_CACHE_TTL));
+        let results_cache = ResultsCache::new();
+        let results = get_results_with_missing_marked(
+            &pool,
+            &results_cache,
+            &cache,
+            None,
+            None,
+            &GroupFilter::default(),
+        )?;
+        let ids: Vec<&str> = results.iter().map(|g| g.group_id.as_str()).collect();
+        assert_eq!(ids, ["0101010101010101"]);
+        let html =
+            render_results_to_html(&results, &load_templates("/")?, false, KeepPolicy::First, 2)?
+                .html;
+        assert!(html.contains("2 files with excluded content not shown"));
+        assert!(handle_exclude_request(&db_mutex, 42).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_setup_page() -> Result<()> {
+        let db = Database::new("test_setup_page.sqlite", true)?;
+        let pool = DatabasePool::with_writer("test_setup_page.sqlite", db);
+        let db_mutex = pool.db_mutex();
+        let (requests, requested) = mpsc::channel();
+        let trigger = ScanTrigger::new(requests);
+        assert!(shows_setup(&pool, Some(&trigger))?);
+        assert!(!shows_setup(&pool, None)?);
+        assert!(render_setup_page(&load_templates("/")?)?.contains(r#"id="scan_form""#));
+
+        let scan = |trigger: Option<&ScanTrigger>, path: &Path| {
+            let body = serde_json::json!({ "path": path }).to_string();
+            let request = Request::fake_http("POST", "/scan", vec![], body.into_bytes());
+            handle_scan_request(trigger, &request, 1024)
+        };
+        let dir = tempfile::tempdir()?;
+        let file = dir.path().join("file.txt");
+        fs::write(&file, "")?;
+        assert_eq!(status_of(scan(Some(&trigger), &file)), 400);
+        assert_eq!(status_of(scan(Some(&trigger), &dir.path().join("x"))), 400);
+        assert_eq!(status_of(scan(None, dir.path())), 409);
+        assert!(shows_setup(&pool, Some(&trigger))?);
+        assert_eq!(scan(Some(&trigger), dir.path())?.status_code, 200);
+        assert_eq!(requested.try_recv()?, dir.path());
+        // the results page takes over while the scan is running
+        assert!(!shows_setup(&pool, Some(&trigger))?);
+
+        // as it does once the database has content
+        let trigger = ScanTrigger::new(mpsc::channel().0);
+        insert_group(&db_mutex.lock().unwrap(), &["/tmp/nonexistent/a"], 1)?;
+        assert!(!shows_setup(&pool, Some(&trigger))?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_group_note_request() -> Result<()> {
+        let db = Database::new("test_group_note_request.sqlite", true)?;
+        insert_group(&db, &["/tmp/nonexistent/a", "/tmp/nonexistent/b"], 1)?;
+        let db_mutex = Mutex::new(db);
+        let set_note = |group_id: &str, body: &str| {
+            let request = Request::fake_http(
+                "POST",
+                format!("/group/{}/note", group_id),
+                vec![],
+                body.as_bytes().to_vec(),
+            );
+            handle_group_note_request(&db_mutex, group_id, &request, 1024)
+        };
+
+        set_note(
+            "0101010101010101",
+            r#"{"note": "<b>master</b> on the left"}"#,
+        )?;
+        let group = similarities::get_group(&db_mutex.lock().unwrap(), "0101010101010101")?;
+        let html = render_group_page_to_html(&group.unwrap(), 0, 50, &load_templates("/")?, false)?;
+        assert!(html.contains("&lt;b&gt;master&lt;&#x2F;b&gt; on the left"));
+
+        set_note("0101010101010101", "{}")?;
+        assert_eq!(
+            db_mutex
+                .lock()
+                .unwrap()
+                .get_group_note("0101010101010101")?,
+            None
+        );
+        assert_eq!(status_of(set_note("xyz", r#"{"note": "x"}"#)), 400);
+        assert_eq!(status_of(set_note("0101010101010101", "note")), 400);
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_request() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = Database::new("test_verify_request.sqlite", true)?;
+        let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+        fs::write(&a, "abcdef")?;
+        fs::write(&b, "abcxyz")?;
+        insert_group(&db, &[a.to_str().unwrap(), b.to_str().unwrap()], 1)?;
+        let pool = DatabasePool::with_writer("test_verify_request.sqlite", db);
+
+        let response = handle_verify_request(&pool, "0101010101010101", Duration::from_secs(60))?;
+        let mut body = String::new();
+        response
+            .data
+            .into_reader_and_size()
+            .0
+            .read_to_string(&mut body)?;
+        let verification: serde_json::Value = serde_json::from_str(&body)?;
+        let members = verification["members"].as_array().unwrap();
+        assert_eq!(members.len(), 2);
+        // the digests in the test database are made up
+        assert_eq!(members[0]["digest_matches"], false);
+        assert_eq!(members[1]["verdict"]["verdict"], "differs_at");
+        assert_eq!(members[1]["verdict"]["offset"], 3);
+
+        let verify =
+            |group_id: &str| handle_verify_request(&pool, group_id, Duration::from_secs(60));
+        assert_eq!(status_of(verify("0202020202020202")), 404);
+        assert_eq!(status_of(verify("xyz")), 400);
         Ok(())
     }
+
+    #[test]
+    fn test_error_envelope() {
+        let err: anyhow::Error = ApiError::bad_request("nope").into();
+        let response = error_response(&err);
+        assert_eq!(response.status_code, 400);
+        let (mut reader, _) = response.data.into_reader_and_size();
+        let mut body = String::new();
+        reader.read_to_string(&mut body).unwrap();
+        assert_eq!(body, r#"{"error":{"status":400,"message":"nope"}}"#);
+
+        let response = error_response(&anyhow!("something broke"));
+        assert_eq!(response.status_code, 500);
+    }
 }
@@ -0,0 +1,684 @@
+use anyhow::{anyhow, Result};
+use rusqlite::params;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::Write;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::database::Database;
+use crate::dedupe::{self, DedupeMode, DedupeSummary};
+use crate::scanstats::{Phase, PhaseSnapshot, Progress, ProgressReporter};
+use crate::similarities::{self, KeepPolicy};
+use crate::tombstones::now;
+use crate::verify::{self, GroupVerification};
+
+/// What a job does, as posted to /api/jobs.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum JobRequest {
+    /// Scans a directory like --path. Unfound files are only removed with `clean_unfound`,
+    /// and never past the checks that --force skips
+    Scan {
+        path: PathBuf,
+        #[serde(default)]
+        clean_unfound: bool,
+    },
+    /// Computes the video hashes that are missing, like --videohash after a scan
+    Videohash,
+    /// Reads all members of a duplicate group again, like the "Verify group" button
+    Verify { group_id: String },
+    /// Replaces duplicates with links, like --dedupe
+    Dedupe {
+        #[serde(default = "default_dedupe_mode")]
+        mode: String,
+        /// The --keep policy of the web interface if not given
+        keep: Option<String>,
+        #[serde(default)]
+        include_dismissed: bool,
+    },
+}
+
+fn default_dedupe_mode() -> String {
+    "symlink".to_string()
+}
+
+impl JobRequest {
+    /// Rejects requests that would only fail once they run.
+    pub fn check(&self) -> Result<()> {
+        match self {
+            JobRequest::Scan { path, .. } if !path.is_dir() => {
+                Err(anyhow!("{} is not a directory", path.to_string_lossy()))
+            }
+            JobRequest::Verify { group_id } if !similarities::is_group_id(group_id) => {
+                Err(anyhow!("Invalid group id {}", group_id))
+            }
+            JobRequest::Dedupe { mode, keep, .. } => {
+                mode.parse::<DedupeMode>()?;
+                if let Some(keep) = keep {
+                    keep.parse::<KeepPolicy>()?;
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Done,
+    Failed,
+    Cancelled,
+}
+
+impl JobStatus {
+    pub fn is_finished(self) -> bool {
+        matches!(
+            self,
+            JobStatus::Done | JobStatus::Failed | JobStatus::Cancelled
+        )
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            JobStatus::Queued => "queued",
+            JobStatus::Running => "running",
+            JobStatus::Done => "done",
+            JobStatus::Failed => "failed",
+            JobStatus::Cancelled => "cancelled",
+        }
+    }
+}
+
+impl FromStr for JobStatus {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<JobStatus> {
+        match s {
+            "queued" => Ok(JobStatus::Queued),
+            "running" => Ok(JobStatus::Running),
+            "done" => Ok(JobStatus::Done),
+            "failed" => Ok(JobStatus::Failed),
+            "cancelled" => Ok(JobStatus::Cancelled),
+            _ => Err(anyhow!(
+                "Unknown job status {}, use queued, running, done, failed or cancelled",
+                s
+            )),
+        }
+    }
+}
+
+/// How far a running job got, in steps that depend on its kind.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct JobProgress {
+    pub done: usize,
+    pub total: usize,
+}
+
+/// A job as stored in the jobs table.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Job {
+    pub id: i64,
+    #[serde(flatten)]
+    pub request: JobRequest,
+    pub status: JobStatus,
+    pub progress: Option<JobProgress>,
+    /// A summary of what the job did, once it is done
+    pub result: Option<serde_json::Value>,
+    pub error: Option<String>,
+    /// unix seconds
+    pub created_at: i64,
+    pub started_at: Option<i64>,
+    pub finished_at: Option<i64>,
+}
+
+/// Returned by jobs that stop because they were cancelled.
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Shared between a running job and the web interface. Jobs check for cancellation
+/// between their steps, whatever a step is in the middle of is finished first.
+#[derive(Debug, Default)]
+pub struct JobContext {
+    cancelled: Arc<AtomicBool>,
+    progress: Mutex<Option<JobProgress>>,
+}
+
+impl JobContext {
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// Err(Cancelled) once the job was asked to stop.
+    pub fn check_cancelled(&self) -> Result<()> {
+        if self.cancelled.load(Ordering::SeqCst) {
+            return Err(Cancelled.into());
+        }
+        Ok(())
+    }
+
+    pub fn set_progress(&self, done: usize, total: usize) {
+        if let Ok(mut progress) = self.progress.lock() {
+            *progress = Some(JobProgress { done, total });
+        }
+    }
+
+    pub fn progress(&self) -> Option<JobProgress> {
+        self.progress.lock().ok().and_then(|p| *p)
+    }
+}
+
+/// Scans and video hashing report the files of their current phase as the job progress.
+impl ProgressReporter for JobContext {
+    fn report(&self, _phase: Phase, snapshot: &PhaseSnapshot) {
+        self.set_progress(snapshot.files_done, snapshot.files_total);
+    }
+
+    fn finish(&self, phase: Phase, snapshot: &PhaseSnapshot) {
+        self.report(phase, snapshot);
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::from_secs(1)
+    }
+}
+
+/// Progress for a scan or video hashing run by a job. The run stops after its current
+/// batch once the job is cancelled.
+pub fn scan_progress(context: &Arc<JobContext>) -> Progress {
+    Progress::with_reporter(context.clone()).with_stop(context.cancelled.clone())
+}
+
+impl Database {
+    fn insert_job(&self, request: &JobRequest) -> Result<i64> {
+        self.db.execute(
+            "INSERT INTO jobs (request, status, created_at) VALUES (?1, ?2, ?3)",
+            params![
+                serde_json::to_string(request)?,
+                JobStatus::Queued.name(),
+                now()?
+            ],
+        )?;
+        Ok(self.db.last_insert_rowid())
+    }
+
+    pub fn get_job(&self, id: i64) -> Result<Option<Job>> {
+        Ok(self.get_jobs(Some(id), 1)?.pop())
+    }
+
+    /// The newest `limit` jobs, or only the one with `id`.
+    pub fn get_jobs(&self, id: Option<i64>, limit: usize) -> Result<Vec<Job>> {
+        let mut stmt = self.db.prepare(
+            "SELECT id, request, status, progress_done, progress_total, result, error, \
+             created_at, started_at, finished_at FROM jobs \
+             WHERE ?1 IS NULL OR id = ?1 ORDER BY id DESC LIMIT ?2",
+        )?;
+        let rows: Vec<_> = stmt
+            .query_map(params![id, limit as i64], |row| {
+                let progress: Option<(i64, i64)> = match (row.get(3)?, row.get(4)?) {
+                    (Some(done), Some(total)) => Some((done, total)),
+                    _ => None,
+                };
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    progress,
+                    row.get::<_, Option<String>>(5)?,
+                    row.get::<_, Option<String>>(6)?,
+                    (row.get(7)?, row.get(8)?, row.get(9)?),
+                ))
+            })?
+            .collect::<Result<_, _>>()?;
+        rows.into_iter()
+            .map(
+                |(
+                    id,
+                    request,
+                    status,
+                    progress,
+                    result,
+                    error,
+                    (created_at, started_at, finished_at),
+                )| {
+                    Ok(Job {
+                        id,
+                        request: serde_json::from_str(&request)?,
+                        status: status.parse()?,
+                        progress: progress.map(|(done, total)| JobProgress {
+                            done: done as usize,
+                            total: total as usize,
+                        }),
+                        result: result.map(|r| serde_json::from_str(&r)).transpose()?,
+                        error,
+                        created_at,
+                        started_at,
+                        finished_at,
+                    })
+                },
+            )
+            .collect()
+    }
+
+    fn start_job(&self, id: i64) -> Result<()> {
+        self.db.execute(
+            "UPDATE jobs SET status = ?1, started_at = ?2 WHERE id = ?3",
+            params![JobStatus::Running.name(), now()?, id],
+        )?;
+        Ok(())
+    }
+
+    fn finish_job(
+        &self,
+        id: i64,
+        status: JobStatus,
+        progress: Option<JobProgress>,
+        result: Option<&serde_json::Value>,
+        error: Option<&str>,
+    ) -> Result<()> {
+        self.db.execute(
+            "UPDATE jobs SET status = ?1, progress_done = ?2, progress_total = ?3, \
+             result = ?4, error = ?5, finished_at = ?6 WHERE id = ?7",
+            params![
+                status.name(),
+                progress.map(|p| p.done as i64),
+                progress.map(|p| p.total as i64),
+                result.map(|r| r.to_string()),
+                error,
+                now()?,
+                id
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Jobs that were running when Dupletti stopped can't be continued, they are marked
+    /// as failed. Returns the ids of the jobs that are still queued, oldest first.
+    fn recover_jobs(&self) -> Result<Vec<i64>> {
+        self.db.execute(
+            "UPDATE jobs SET status = ?1, error = 'Interrupted by a restart', finished_at = ?2 \
+             WHERE status = ?3",
+            params![JobStatus::Failed.name(), now()?, JobStatus::Running.name()],
+        )?;
+        let mut stmt = self
+            .db
+            .prepare("SELECT id FROM jobs WHERE status = ?1 ORDER BY id")?;
+        let ids: Result<Vec<i64>, _> = stmt
+            .query_map(params![JobStatus::Queued.name()], |row| row.get(0))?
+            .collect();
+        Ok(ids?)
+    }
+}
+
+/// Runs a job and returns a summary of what it did.
+pub type RunJob = Box<dyn Fn(&JobRequest, &Arc<JobContext>) -> Result<serde_json::Value> + Send>;
+
+/// The job that is running, for cancelling it and showing its progress.
+type RunningJob = Arc<Mutex<Option<(i64, Arc<JobContext>)>>>;
+
+/// Runs jobs one after another on a single worker thread, in the order they were
+/// submitted.
+pub struct JobQueue {
+    db_mutex: Arc<Mutex<Database>>,
+    sender: Mutex<mpsc::Sender<i64>>,
+    running: RunningJob,
+}
+
+impl JobQueue {
+    /// Starts the worker, which also picks up the jobs left queued by a previous run.
+    pub fn start(db_mutex: Arc<Mutex<Database>>, run: RunJob) -> Result<JobQueue> {
+        let queued = if let Ok(db) = db_mutex.lock() {
+            db.recover_jobs()?
+        } else {
+            return Err(anyhow!("Unable to lock DB"));
+        };
+        let (sender, receiver) = mpsc::channel();
+        for id in queued {
+            sender.send(id)?;
+        }
+        let running: RunningJob = Arc::new(Mutex::new(None));
+        let worker_db = db_mutex.clone();
+        let worker_running = running.clone();
+        thread::spawn(move || {
+            for id in receiver {
+                if let Err(e) = run_job(&worker_db, &worker_running, &run, id) {
+                    log::error!("Unable to run job {}: {}", id, e);
+                }
+            }
+        });
+        Ok(JobQueue {
+            db_mutex,
+            sender: Mutex::new(sender),
+            running,
+        })
+    }
+
+    pub fn db_mutex(&self) -> &Arc<Mutex<Database>> {
+        &self.db_mutex
+    }
+
+    pub fn submit(&self, request: &JobRequest) -> Result<Job> {
+        let job = if let Ok(db) = self.db_mutex.lock() {
+            let id = db.insert_job(request)?;
+            db.get_job(id)?
+                .ok_or_else(|| anyhow!("Job {} went missing", id))?
+        } else {
+            return Err(anyhow!("Unable to lock DB"));
+        };
+        match self.sender.lock() {
+            Ok(sender) => sender
+                .send(job.id)
+                .map_err(|_| anyhow!("The job worker is no longer running"))?,
+            Err(_) => return Err(anyhow!("Unable to lock job queue")),
+        }
+        log::info!("Job {} queued: {:?}", job.id, job.request);
+        Ok(job)
+    }
+
+    /// The job with its current progress if it is running.
+    pub fn get(&self, id: i64) -> Result<Option<Job>> {
+        let job = if let Ok(db) = self.db_mutex.lock() {
+            db.get_job(id)?
+        } else {
+            return Err(anyhow!("Unable to lock DB"));
+        };
+        Ok(job.map(|job| self.with_progress(job)))
+    }
+
+    pub fn list(&self, limit: usize) -> Result<Vec<Job>> {
+        let jobs = if let Ok(db) = self.db_mutex.lock() {
+            db.get_jobs(None, limit)?
+        } else {
+            return Err(anyhow!("Unable to lock DB"));
+        };
+        Ok(jobs
+            .into_iter()
+            .map(|job| self.with_progress(job))
+            .collect())
+    }
+
+    fn with_progress(&self, mut job: Job) -> Job {
+        if let Ok(running) = self.running.lock() {
+            if let Some((id, context)) = running.as_ref() {
+                if *id == job.id {
+                    job.progress = context.progress();
+                }
+            }
+        }
+        job
+    }
+
+    /// Queued jobs are cancelled right away, running ones once they finish their current
+    /// step. Finished jobs are left as they are.
+    pub fn cancel(&self, id: i64) -> Result<Option<Job>> {
+        if let Ok(db) = self.db_mutex.lock() {
+            let job = match db.get_job(id)? {
+                Some(job) => job,
+                None => return Ok(None),
+            };
+            match job.status {
+                JobStatus::Queued => db.finish_job(id, JobStatus::Cancelled, None, None, None)?,
+                JobStatus::Running => {
+                    if let Ok(running) = self.running.lock() {
+                        if let Some((running_id, context)) = running.as_ref() {
+                            if *running_id == id {
+                                context.cancel();
+                            }
+                        }
+                    }
+                }
+                _ => {}
+            }
+        } else {
+            return Err(anyhow!("Unable to lock DB"));
+        }
+        self.get(id)
+    }
+}
+
+fn run_job(db_mutex: &Mutex<Database>, running: &RunningJob, run: &RunJob, id: i64) -> Result<()> {
+    let context = Arc::new(JobContext::default());
+    let request = if let Ok(db) = db_mutex.lock() {
+        match db.get_job(id)? {
+            // cancelled while it was queued
+            Some(job) if job.status == JobStatus::Queued => {
+                db.start_job(id)?;
+                job.request
+            }
+            _ => return Ok(()),
+        }
+    } else {
+        return Err(anyhow!("Unable to lock DB"));
+    };
+    *running
+        .lock()
+        .map_err(|_| anyhow!("Unable to lock running job"))? = Some((id, context.clone()));
+    log::info!("Job {} started", id);
+    let outcome = run(&request, &context);
+    *running
+        .lock()
+        .map_err(|_| anyhow!("Unable to lock running job"))? = None;
+    let progress = context.progress();
+    let (status, result, error) = match outcome {
+        Ok(result) => (JobStatus::Done, Some(result), None),
+        Err(e) if e.is::<Cancelled>() => (JobStatus::Cancelled, None, None),
+        Err(e) => (JobStatus::Failed, None, Some(e.to_string())),
+    };
+    log::info!("Job {} {}", id, status.name());
+    if let Ok(db) = db_mutex.lock() {
+        db.finish_job(id, status, progress, result.as_ref(), error.as_deref())
+    } else {
+        Err(anyhow!("Unable to lock DB"))
+    }
+}
+
+/// Runs a verify job.
+pub fn verify_group(
+    db_mutex: &Mutex<Database>,
+    group_id: &str,
+    context: &JobContext,
+) -> Result<GroupVerification> {
+    let members = if let Ok(db) = db_mutex.lock() {
+        verify::group_members(&db, group_id)?
+            .ok_or_else(|| anyhow!("No group with id {}", group_id))?
+    } else {
+        return Err(anyhow!("Unable to lock DB"));
+    };
+    context.set_progress(0, 1);
+    let verification = GroupVerification {
+        group_id: group_id.to_string(),
+        members: verify::verify_members(&members, Instant::now() + verify::VERIFY_TIMEOUT),
+    };
+    context.set_progress(1, 1);
+    Ok(verification)
+}
+
+/// Runs a dedupe job one group at a time, so it can be cancelled between groups.
+pub fn dedupe_groups(
+    db_mutex: &Mutex<Database>,
+    mode: DedupeMode,
+    keep_policy: KeepPolicy,
+    include_dismissed: bool,
+    mut replacement_log: Option<&mut dyn Write>,
+    context: &JobContext,
+) -> Result<DedupeSummary> {
+    let groups = if let Ok(db) = db_mutex.lock() {
+        let mut groups = similarities::get_list_of_similar_files(&db)?;
+        groups.retain(|g| (include_dismissed || !g.dismissed) && !g.hardlinked);
+        groups
+    } else {
+        return Err(anyhow!("Unable to lock DB"));
+    };
+    let mut summary = DedupeSummary::default();
+    for (i, group) in groups.iter().enumerate() {
+        context.set_progress(i, groups.len());
+        if let Err(e) = context.check_cancelled() {
+            log::info!("Dedupe cancelled: {}", summary);
+            return Err(e);
+        }
+        let done = if let Ok(db) = db_mutex.lock() {
+            dedupe::dedupe_groups(
+                &db,
+                std::slice::from_ref(group),
+                keep_policy.clone(),
+                mode,
+                replacement_log
+                    .as_mut()
+                    .map(|log| &mut **log as &mut dyn Write),
+            )?
+        } else {
+            return Err(anyhow!("Unable to lock DB"));
+        };
+        summary.linked += done.linked;
+        summary.reclaimed += done.reclaimed;
+        summary.skipped += done.skipped;
+    }
+    context.set_progress(groups.len(), groups.len());
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wait_until_finished(queue: &JobQueue, id: i64) -> Job {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            let job = queue.get(id).unwrap().unwrap();
+            if job.status.is_finished() || Instant::now() > deadline {
+                return job;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_job_lifecycle() -> Result<()> {
+        let db = Database::new("test_job_lifecycle.sqlite", true)?;
+        let db_mutex = Arc::new(Mutex::new(db));
+        // verify jobs count to ten, cancellable after each step, other kinds fail
+        let (started, wait_for_start) = mpsc::channel();
+        let started = Mutex::new(started);
+        let run: RunJob = Box::new(move |request, context| match request {
+            JobRequest::Verify { group_id } => {
+                context.set_progress(0, 10);
+                started.lock().unwrap().send(()).unwrap();
+                for i in 0..10 {
+                    context.set_progress(i, 10);
+                    context.check_cancelled()?;
+                    thread::sleep(Duration::from_millis(if group_id == "aaaaaaaaaaaaaaaa" {
+                        1
+                    } else {
+                        50
+                    }));
+                }
+                context.set_progress(10, 10);
+                Ok(serde_json::json!({ "counted": 10 }))
+            }
+            _ => Err(anyhow!("not supported")),
+        });
+        let queue = JobQueue::start(db_mutex.clone(), run)?;
+        let verify = |group_id: &str| JobRequest::Verify {
+            group_id: group_id.to_string(),
+        };
+
+        let job = queue.submit(&verify("aaaaaaaaaaaaaaaa"))?;
+        assert_eq!(job.status, JobStatus::Queued);
+        wait_for_start.recv()?;
+        let job = wait_until_finished(&queue, job.id);
+        assert_eq!(job.status, JobStatus::Done);
+        assert_eq!(job.result, Some(serde_json::json!({ "counted": 10 })));
+        assert_eq!(
+            job.progress,
+            Some(JobProgress {
+                done: 10,
+                total: 10
+            })
+        );
+        assert!(job.started_at.is_some() && job.finished_at.is_some());
+
+        // the second job waits for the first one and is cancelled before it starts
+        let slow = queue.submit(&verify("bbbbbbbbbbbbbbbb"))?;
+        let queued = queue.submit(&verify("cccccccccccccccc"))?;
+        wait_for_start.recv()?;
+        assert_eq!(
+            queue.cancel(queued.id)?.unwrap().status,
+            JobStatus::Cancelled
+        );
+        let running = queue.cancel(slow.id)?.unwrap();
+        assert_eq!(running.status, JobStatus::Running);
+        assert!(running.progress.is_some());
+        let slow = wait_until_finished(&queue, slow.id);
+        assert_eq!(slow.status, JobStatus::Cancelled);
+        assert!(slow.progress.unwrap().done < 10);
+
+        let failed = queue.submit(&JobRequest::Videohash)?;
+        let failed = wait_until_finished(&queue, failed.id);
+        assert_eq!(failed.status, JobStatus::Failed);
+        assert_eq!(failed.error.as_deref(), Some("not supported"));
+        // the cancelled job never ran
+        assert!(wait_for_start.try_recv().is_err());
+        assert_eq!(queue.get(queued.id)?.unwrap().started_at, None);
+
+        let ids: Vec<i64> = queue.list(10)?.iter().map(|j| j.id).collect();
+        assert_eq!(ids, [failed.id, queued.id, slow.id, job.id]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_jobs_survive_restarts() -> Result<()> {
+        let db = Database::new("test_jobs_survive_restarts.sqlite", true)?;
+        let running = db.insert_job(&JobRequest::Videohash)?;
+        db.start_job(running)?;
+        let queued = db.insert_job(&JobRequest::Videohash)?;
+        assert_eq!(db.recover_jobs()?, [queued]);
+        let job = db.get_job(running)?.unwrap();
+        assert_eq!(job.status, JobStatus::Failed);
+        assert_eq!(job.error.as_deref(), Some("Interrupted by a restart"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_job_request() {
+        let request: JobRequest = serde_json::from_str(r#"{"kind": "dedupe"}"#).unwrap();
+        assert_eq!(
+            request,
+            JobRequest::Dedupe {
+                mode: "symlink".into(),
+                keep: None,
+                include_dismissed: false
+            }
+        );
+        assert!(request.check().is_ok());
+        let request: JobRequest =
+            serde_json::from_str(r#"{"kind": "dedupe", "keep": "smallest"}"#).unwrap();
+        assert!(request.check().is_err());
+        assert!(serde_json::from_str::<JobRequest>(r#"{"kind": "format"}"#).is_err());
+        let request: JobRequest =
+            serde_json::from_str(r#"{"kind": "scan", "path": "/nonexistent"}"#).unwrap();
+        assert!(request.check().is_err());
+        let verify = |group_id: &str| JobRequest::Verify {
+            group_id: group_id.to_string(),
+        };
+        assert!(verify("0123456789abcdef").check().is_ok());
+        assert!(verify("").check().is_err());
+        assert!(verify("01234567").check().is_err());
+        assert!(verify("0123456789ABCDEF").check().is_err());
+    }
+}
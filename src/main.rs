@@ -1,10 +1,15 @@
-use anyhow::{anyhow, Result};
-use glob::glob;
+use anyhow::{anyhow, Context, Result};
 use log;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io;
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex};
+use std::rc::Rc;
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use structopt::clap::ArgMatches;
 use structopt::StructOpt;
 
 mod database;
@@ -22,6 +27,116 @@ pub use crate::filehashing::*;
 mod videohash;
 pub use crate::videohash::*;
 
+mod scanstats;
+pub use crate::scanstats::{Progress, ScanStats};
+
+mod paths;
+pub use crate::paths::{ExcludePatterns, PathCase};
+
+mod rawpreview;
+
+mod stillpreview;
+
+mod tiff;
+
+mod dbcheck;
+pub use crate::dbcheck::ConsistencyReport;
+
+mod exif;
+pub use crate::exif::ExifInfo;
+
+mod triage;
+pub use crate::triage::{BulkAction, BulkCounts};
+
+mod tombstones;
+pub use crate::tombstones::Tombstone;
+
+mod swap;
+
+mod notes;
+pub use crate::notes::GroupNote;
+
+mod acks;
+pub use crate::acks::SeenFilter;
+
+mod scanroots;
+pub use crate::scanroots::ScanRoot;
+
+mod compare;
+pub use crate::compare::CompareReport;
+
+mod exclusions;
+pub use crate::exclusions::ExcludedDigest;
+
+mod unionfind;
+
+mod bursts;
+
+mod config;
+pub use crate::config::{CleanupLimits, EffectiveConfig};
+
+mod filemeta;
+pub use crate::filemeta::FileMeta;
+
+mod batcher;
+pub use crate::batcher::BatchLimits;
+
+mod dblock;
+pub use crate::dblock::{DbLock, LockRole};
+
+mod placeholders;
+pub use crate::placeholders::SkippedFile;
+
+mod backup;
+pub use crate::backup::BackupPolicy;
+
+mod urls;
+
+mod framestrips;
+
+mod verify;
+
+mod dedupe;
+pub use crate::dedupe::{DedupeMode, DedupeSummary};
+
+mod notify;
+use crate::notify::{DuplicateTotals, Notifier, RunSummary};
+
+mod chunks;
+
+mod jobs;
+use crate::jobs::JobRequest;
+
+mod queue;
+
+mod dbpool;
+pub use crate::dbpool::DatabasePool;
+
+mod stale;
+use crate::stale::{StaleAge, StaleReport};
+
+mod singleflight;
+
+mod csvexport;
+
+mod configfile;
+use crate::configfile::ConfigFile;
+
+mod shutdown;
+
+mod ignorefiles;
+use crate::ignorefiles::IgnoreFiles;
+
+mod dbstats;
+use crate::dbstats::DatabaseStats;
+
+mod autodelete;
+
+mod throttle;
+
+/// Where the index is stored, relative to the working directory
+const DATABASE_PATH: &str = "./digests.sqlite";
+
 /// Search for duplicate files
 #[derive(StructOpt, Debug)]
 struct ProgramArguments {
@@ -33,17 +148,56 @@ struct ProgramArguments {
     #[structopt(short, long)]
     clean_unfound: bool,
 
-    /// Number of threads for parallel processing (1 = single-threaded)
-    #[structopt(short, long, default_value = "4")]
+    /// Clean unfound files even if the DB seems to belong to a different path, or if more
+    /// files would be removed than the --clean-unfound-max-* limits allow. Also lifts the
+    /// limits when purging missing files
+    #[structopt(long)]
+    force: bool,
+
+    /// Only log which files --clean-unfound would remove, and how many bytes they take,
+    /// or print which duplicates --auto-delete would delete, without removing them
+    #[structopt(long)]
+    dry_run: bool,
+
+    /// Refuse to clean unfound files if more than this percentage of the DB would be removed
+    #[structopt(long, default_value = "20")]
+    clean_unfound_max_percent: f64,
+
+    /// Refuse to clean unfound files if more than this many files would be removed
+    #[structopt(long)]
+    clean_unfound_max_files: Option<usize>,
+
+    /// Number of threads for parallel processing (1 = single-threaded, 0 = one per logical
+    /// CPU)
+    #[structopt(short, long, default_value = "4", global = true)]
     threads: usize,
 
-    /// The path to the file to read
-    #[structopt(short, long, parse(from_os_str), default_value = "")]
-    path: PathBuf,
+    /// Number of threads hashing files, 0 for one per logical CPU [default: --threads]
+    #[structopt(long, global = true)]
+    hash_threads: Option<usize>,
+
+    /// Number of threads decoding videos for --videohash, 0 for one per logical CPU. Each
+    /// one holds a decoder and its frames, so fewer use less memory [default: --threads]
+    #[structopt(long, global = true)]
+    video_threads: Option<usize>,
+
+    /// Read files at most this many MiB per second while hashing, shared by all threads,
+    /// e.g. to keep a NAS responsive. 0 doesn't limit reads
+    #[structopt(
+        long,
+        default_value = "0",
+        global = true,
+        parse(try_from_str = throttle::parse_limit)
+    )]
+    throttle: f64,
+
+    /// The directory to scan, repeat it to scan several directories in one run
+    #[structopt(short, long, parse(from_os_str), number_of_values = 1)]
+    path: Vec<PathBuf>,
 
     // The number of occurrences of the `v/verbose` flag
     /// Verbose mode (-v, -vv, -vvv, etc.)
-    #[structopt(short, long, parse(from_occurrences))]
+    #[structopt(short, long, parse(from_occurrences), global = true)]
     verbose: u8,
 
     /// Use web interface or not.
@@ -62,6 +216,16 @@ struct ProgramArguments {
     #[structopt(long, default_value = "1024")]
     commit_batchsize: usize,
 
+    /// Commit a batch early once the hashed files take about this many bytes of memory.
+    /// Counts paths, digests and video histograms, not the size of the files
+    #[structopt(long, default_value = "268435456")]
+    commit_batch_bytes: usize,
+
+    /// Hashed files that may wait for a database commit before hashing pauses. Keeps
+    /// memory bounded when the database is slow
+    #[structopt(long, default_value = "256")]
+    hash_queue_size: usize,
+
     /// Allows web interface to serve files through preview links.
     /// Otherwise file links will be local and use file:// , which
     /// is not the best UX. However, this opens up a potential
@@ -72,209 +236,4037 @@ struct ProgramArguments {
     #[structopt(long)]
     allow_preview: bool,
 
+    /// Allows the web interface to open the file manager at a file. Only has an effect
+    /// if the web interface is bound to a loopback address like 127.0.0.1.
+    #[structopt(long)]
+    allow_reveal: bool,
+
+    /// Hash photos with a capture date, to find bursts of near identical photos at /bursts
+    #[structopt(long)]
+    burst_hash: bool,
+
+    /// Split files of at least --chunk-index-min-size bytes into 4 MiB chunks and store
+    /// their digests, to find files that are partly the same with --partial
+    #[structopt(long)]
+    chunk_index: bool,
+
+    /// Files smaller than this many bytes are left out of the chunk index
+    #[structopt(long, default_value = "1073741824")]
+    chunk_index_min_size: u64,
+
+    /// List pairs of chunk indexed files that share more than this percentage of their
+    /// chunks, then exit [default: 50]
+    #[structopt(long, conflicts_with = "quick")]
+    partial: Option<Option<f64>>,
+
+    /// Remove the chunks of files that changed, are gone or are now below
+    /// --chunk-index-min-size
+    #[structopt(long)]
+    prune_chunks: bool,
+
+    /// List the groups whose files were all first seen more than --older-than ago and that
+    /// were never dismissed, the most wasted space first, then exit
+    #[structopt(long, conflicts_with = "quick")]
+    stale: bool,
+
+    /// How old groups have to be for --stale, in days like 180d or weeks like 26w
+    #[structopt(long, default_value = "180d")]
+    older_than: StaleAge,
+
     /// Enable similarity-search via color histograms
     #[structopt(long)]
     videohash: bool,
+
+    /// Width and height in pixels that video frames are scaled to before computing
+    /// histograms (16-512). Smaller is faster but less accurate
+    #[structopt(long, default_value = "128", parse(try_from_str = videohash::parse_frame_size))]
+    videohash_frame_size: u32,
+
+    /// Videos shorter than this many seconds are skipped by the similarity-search
+    #[structopt(long, default_value = "5")]
+    videohash_min_duration: f64,
+
+    /// Only compute the missing video hashes of the files in the DB, without scanning.
+    /// With --path, only those of the videos below it
+    #[structopt(long)]
+    videohash_only: bool,
+
+    /// Print the clusters of similar videos at this threshold instead of the duplicates
+    /// when not starting the web interface. Otherwise the similar videos page links to
+    /// this threshold [default: 64]
+    #[structopt(long)]
+    videohash_threshold: Option<u16>,
+
+    /// Read duration and resolution of all video and audio files, without computing
+    /// video hashes
+    #[structopt(long)]
+    probe_media: bool,
+
+    /// Store creation, change and access times, owner and permissions of hashed files, to
+    /// tell originals from copies
+    #[structopt(long)]
+    collect_metadata: bool,
+
+    /// Recompute video hashes that were computed with a different frame size
+    #[structopt(long, requires = "videohash")]
+    rehash_videos: bool,
+
+    /// Maximum size in bytes of request bodies accepted by the web interface
+    #[structopt(long, default_value = "65536")]
+    max_body_size: usize,
+
+    /// Remove all files from the DB that no longer exist on disk, optionally only those
+    /// below the given path prefix
+    #[structopt(long)]
+    purge_missing: Option<Option<String>>,
+
+    /// Forget deleted files after this many days. Files deleted through the web interface
+    /// are remembered to recognize when they are downloaded again
+    #[structopt(long)]
+    prune_tombstones: Option<u64>,
+
+    /// Don't back up the database before --reset-database, purging missing files and
+    /// forced cleanups
+    #[structopt(long)]
+    no_backup: bool,
+
+    /// Where to put database backups [default: next to the database]
+    #[structopt(long, parse(from_os_str))]
+    backup_dir: Option<PathBuf>,
+
+    /// Number of database backups to keep, older ones are removed
+    #[structopt(long, default_value = "5")]
+    keep_backups: usize,
+
+    /// Compare paths ignoring their case. This is detected automatically for the scan path
+    #[structopt(long)]
+    case_insensitive_paths: bool,
+
+    /// Continue hashing the files left over from an interrupted scan, without listing
+    /// the filesystem again
+    #[structopt(long)]
+    resume: bool,
+
+    /// Groups with more files are shown collapsed in the web interface, at most 1000
+    #[structopt(long, default_value = "50", parse(try_from_str = interface::parse_max_group_size))]
+    max_group_size: usize,
+
+    /// Only show groups with files that were first indexed on or after this date (YYYY-MM-DD)
+    #[structopt(long, parse(try_from_str = similarities::parse_date))]
+    added_after: Option<i64>,
+
+    /// Also list groups that were dismissed in the web interface
+    #[structopt(long)]
+    include_dismissed: bool,
+
+    /// Also list groups whose files are all hardlinks of one file, found with
+    /// --collect-metadata
+    #[structopt(long)]
+    show_hardlinks: bool,
+
+    /// Only show groups with a file below this scan root, given by its last path component
+    /// or its full path, when not starting the web interface
+    #[structopt(long)]
+    root: Option<String>,
+
+    /// Only show groups with files below more than one scan root when not starting the web
+    /// interface
+    #[structopt(long)]
+    cross_roots: bool,
+
+    /// Only print the groups with the most wasted space when not starting the web
+    /// interface. Much faster for large databases
+    #[structopt(long)]
+    limit_results: Option<usize>,
+
+    /// With --limit-results, report the saved size of all groups instead of only the
+    /// printed ones
+    #[structopt(long, requires = "limit-results")]
+    full_totals: bool,
+
+    /// Check the DB for inconsistencies and exit. Files below --path are checked for
+    /// existence. Exits with a non-zero code if problems remain
+    #[structopt(long)]
+    check_database: bool,
+
+    /// Check the existence of all files instead of a sample
+    #[structopt(long, requires = "check-database")]
+    thorough: bool,
+
+    /// Remove orphaned rows found by the check
+    #[structopt(long, requires = "check-database")]
+    fix: bool,
+
+    /// Hash all indexed files again and report those whose content changed, e.g. by bit
+    /// rot, or that no longer exist, then exit. Exits with a non-zero code if any are found
+    #[structopt(long)]
+    verify: bool,
+
+    /// Store the new digest of files whose content changed
+    #[structopt(long, requires = "verify")]
+    update_on_mismatch: bool,
+
+    /// Additional file extensions that are not hashed, separated by commas
+    #[structopt(long, use_delimiter = true)]
+    skip_extensions: Vec<String>,
+
+    /// Don't skip the default extensions (tmp, part, crdownload, log, ds_store)
+    #[structopt(long)]
+    no_default_skips: bool,
+
+    /// Files whose path matches this glob pattern are not hashed, e.g. **/node_modules/**
+    /// or *.iso. Can be given several times
+    #[structopt(long, number_of_values = 1)]
+    exclude_path: Vec<String>,
+
+    /// Also remove indexed files that match --exclude-path when cleaning unfound files.
+    /// They are kept by default
+    #[structopt(long, requires = "clean-unfound")]
+    clean_excluded: bool,
+
+    /// Only hash files with this extension, e.g. jpg. Can be given several times
+    #[structopt(long, number_of_values = 1)]
+    only_ext: Vec<String>,
+
+    /// Files smaller than this are not hashed, e.g. 4096, 500K or 10M
+    #[structopt(long, default_value = "0")]
+    min_size: ByteSize,
+
+    /// Also remove indexed files smaller than --min-size when cleaning unfound files. They
+    /// are kept by default
+    #[structopt(long, requires = "clean-unfound")]
+    clean_small: bool,
+
+    /// Also hash cloud placeholders (OneDrive, Dropbox, tiered storage), which downloads
+    /// them. They are skipped by default
+    #[structopt(long)]
+    hydrate_placeholders: bool,
+
+    /// Directories containing a file of this name are skipped with everything below them.
+    /// Use an empty name to disable
+    #[structopt(long, default_value = ".nodupes")]
+    marker_file: String,
+
+    /// Follow links to files and directories outside of --path while scanning. Links are
+    /// skipped by default
+    #[structopt(long)]
+    follow_symlinks: bool,
+
+    /// Only scan this many levels of directories, 1 means only the files directly in --path
+    #[structopt(long, parse(try_from_str = parse_max_depth))]
+    max_depth: Option<usize>,
+
+    /// Leave out the files and directories listed in .gitignore and .duplettiignore files
+    /// below --path. Those that are indexed already are kept
+    #[structopt(long)]
+    use_gitignore: bool,
+
+    /// Leave out files and directories whose name starts with a dot, like .git or .cache.
+    /// Those that are indexed already are kept
+    #[structopt(long)]
+    skip_hidden: bool,
+
+    /// Don't enter directories on other file systems than the --path they are below, like
+    /// du -x. Those that are indexed already are kept. Has no effect on Windows
+    #[structopt(long)]
+    one_file_system: bool,
+
+    /// Remove already indexed files below directories containing a marker file
+    #[structopt(long)]
+    prune_marked: bool,
+
+    /// The order in which files are hashed (size, name, random). Size hashes the largest
+    /// files first, name keeps files of the same directory together
+    #[structopt(long, default_value = "size")]
+    scan_order: ScanOrder,
+
+    /// Print the effective settings as JSON and exit
+    #[structopt(long)]
+    show_config: bool,
+
+    /// Scan --path into a temporary in-memory database, print the duplicates and exit.
+    /// Nothing is written to disk
+    #[structopt(
+        long,
+        conflicts_with_all = &[
+            "clean-unfound",
+            "force",
+            "videohash",
+            "burst-hash",
+            "chunk-index",
+            "prune-chunks",
+            "rehash-videos",
+            "resume",
+            "reset-database",
+            "prune-marked",
+            "purge-missing",
+            "prune-tombstones",
+            "check-database",
+            "exclude",
+            "unexclude",
+            "list-excluded",
+            "database",
+        ]
+    )]
+    quick: bool,
+
+    /// A database to use, as path or label=path. Can be given several times to serve all of
+    /// them in the web interface. Scanning and maintenance use the first one. Missing
+    /// directories are created [default: ./digests.sqlite]
+    #[structopt(
+        long = "database",
+        number_of_values = 1,
+        env = "DUPLETTI_DB",
+        global = true
+    )]
+    databases: Vec<config::DatabaseSpec>,
+
+    /// Read default options from this TOML file [default: dupletti.toml in the working
+    /// directory or ~/.config/dupletti/]
+    #[structopt(long, parse(from_os_str))]
+    config: Option<PathBuf>,
+
+    /// Print the duplicates as JSON instead of text when not starting the web interface
+    #[structopt(long, global = true)]
+    json: bool,
+
+    /// Write the duplicates to this file as an HTML report instead of starting the web
+    /// interface. Its links lead to the web interface at --bind-address and --port
+    #[structopt(long, parse(from_os_str), conflicts_with = "json")]
+    export_html: Option<PathBuf>,
+
+    /// Write the duplicates, and the similar videos if any were hashed, to this file as a
+    /// standalone HTML report instead of starting the web interface. Files are linked
+    /// directly, so the report works without Dupletti running
+    #[structopt(
+        long,
+        parse(from_os_str),
+        conflicts_with_all = &["json", "export-html", "quick"]
+    )]
+    output_report: Option<PathBuf>,
+
+    /// Also write the duplicates to this file as CSV, one line per file with its group id,
+    /// file id, path, size and hex encoded digest. Written once the scan is done
+    #[structopt(long, parse(from_os_str))]
+    export_csv: Option<PathBuf>,
+
+    /// Compare the database with another one by digest and exit. Lists the files in both,
+    /// only in this one and only in the other one
+    #[structopt(long, parse(from_os_str), conflicts_with = "quick")]
+    compare_with: Option<PathBuf>,
+
+    /// Print the comparison as CSV
+    #[structopt(long, requires = "compare-with", conflicts_with = "json")]
+    csv: bool,
+
+    /// Never report files with the same content as this file, or with this hex encoded
+    /// digest, then exit
+    #[structopt(long, number_of_values = 1)]
+    exclude: Vec<String>,
+
+    /// Report files with this hex encoded digest again, then exit
+    #[structopt(long, number_of_values = 1)]
+    unexclude: Vec<String>,
+
+    /// List the excluded digests and exit
+    #[structopt(long)]
+    list_excluded: bool,
+
+    /// Which file of a duplicate group is kept when computing reclaimable space (first,
+    /// first-alphabetical, shortest-path, longest-path, largest, exif, oldest, newest,
+    /// owner:<user>, in-dir:<dir>)
+    #[structopt(long, default_value = "largest")]
+    keep: similarities::KeepPolicy,
+
+    /// Replace the duplicates in the database with links to the file kept by --keep, then
+    /// exit. Each duplicate is compared byte by byte with the kept file first.
+    /// symlink:relative (the default for symlink), symlink:absolute or hardlink
+    #[structopt(long, conflicts_with = "quick")]
+    dedupe: Option<DedupeMode>,
+
+    /// Replace the duplicates in the database with hardlinks to the file kept by --keep,
+    /// like --dedupe hardlink. Files on other devices than the kept file are skipped
+    #[structopt(long, conflicts_with_all = &["quick", "dedupe"])]
+    hardlink_duplicates: bool,
+
+    /// Delete the duplicates in the database, keeping the file chosen by --keep, which has
+    /// to be given. Each duplicate is compared byte by byte with the kept file first, and
+    /// nothing of a group is deleted if its kept file is gone or changed. Everything done
+    /// is appended to --delete-log, then exit
+    #[structopt(long, conflicts_with_all = &["quick", "dedupe", "hardlink-duplicates"])]
+    auto_delete: bool,
+
+    /// Where --auto-delete and --dedupe record what they did [default: next to the database]
+    #[structopt(long, parse(from_os_str))]
+    delete_log: Option<PathBuf>,
+
+    /// Show a desktop notification with a summary when a scan or --dedupe completes
+    #[structopt(long)]
+    notify: bool,
+
+    /// POST the summary of each completed scan or --dedupe as JSON to this URL
+    #[structopt(long)]
+    notify_webhook: Option<String>,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
 }
 
-fn list_files_in_directory<P: AsRef<Path>>(directory: P) -> HashSet<PathBuf> {
-    let mut files = HashSet::new();
-    let globresult = glob(directory.as_ref().join("**/*").to_str().unwrap()).unwrap();
-    for entry in globresult {
-        if let Ok(path) = entry {
-            if path.is_file() {
-                files.insert(path);
-            }
-        }
-    }
-    return files;
+// The common tasks, as an alternative to combining the flags. Options that are given
+// before the command apply as well. Not a doc comment, structopt would show it as the
+// description of the whole program.
+#[derive(StructOpt, Debug, Clone, PartialEq)]
+enum Command {
+    /// Hash the files below the given directories into the database, then print the
+    /// duplicates
+    Scan {
+        #[structopt(parse(from_os_str), required = true)]
+        paths: Vec<PathBuf>,
+
+        /// Also compute video hashes to find similar videos
+        #[structopt(long)]
+        videohash: bool,
+
+        /// Start the web interface after scanning instead of printing the duplicates
+        #[structopt(long)]
+        serve: bool,
+    },
+    /// Print the duplicates in the database and exit
+    Report,
+    /// Start the web interface on the database as it is, without scanning
+    Serve,
+    /// Remove files that no longer exist on disk from the database and exit, optionally
+    /// only those below the given path prefix
+    Clean { prefix: Option<String> },
+    /// Print a summary of the database contents, like the number of files, duplicates and
+    /// files per extension, and exit
+    Stats,
 }
 
-fn get_file_digests(db_mutex: &Mutex<Database>) -> Result<Vec<FileDigest>> {
-    if let Ok(db) = db_mutex.lock() {
-        return Ok(db.get_all_filedigests()?);
-    } else {
-        return Err(anyhow!("Unable to lock DB"));
+impl ProgramArguments {
+    /// Takes the options that weren't given on the command line, as told by `matches`,
+    /// from `file`. Its scan paths are only used if no command is given.
+    fn apply_config_file(&mut self, file: &ConfigFile, matches: &ArgMatches) -> Result<()> {
+        let given = |name: &str| matches.occurrences_of(name) > 0;
+        if let Some(database) = &file.database {
+            // DUPLETTI_DB is given as well, clap doesn't count it as an occurrence
+            if self.databases.is_empty() {
+                self.databases = vec![database.parse()?];
+            }
+        }
+        if let Some(paths) = &file.paths {
+            if self.path.is_empty() && self.command.is_none() {
+                self.path = paths.clone();
+            }
+        }
+        if let (Some(exclude_paths), false) = (&file.exclude_paths, given("exclude-path")) {
+            self.exclude_path = exclude_paths.clone();
+        }
+        if let (Some(threads), false) = (file.threads, given("threads")) {
+            self.threads = threads;
+        }
+        if let (Some(port), false) = (file.port, given("port")) {
+            self.port = port;
+        }
+        if let (Some(bind_address), false) = (&file.bind_address, given("bind-address")) {
+            self.bind_address = bind_address.clone();
+        }
+        if let (Some(allow_preview), false) = (file.allow_preview, given("allow-preview")) {
+            self.allow_preview = allow_preview;
+        }
+        if let (Some(videohash), false) = (file.videohash, given("videohash")) {
+            self.videohash = videohash;
+        }
+        Ok(())
     }
-}
 
-fn remove_outdated_files(
-    db_mutex: &Mutex<Database>,
-    current_filelist: &HashSet<PathBuf>,
-) -> Result<()> {
-    let files_in_db = get_file_digests(&db_mutex)?;
-    for f in files_in_db {
-        if !current_filelist.contains(&f.path) {
-            println!("Removing {:?}", f.path);
-            if let Ok(db) = db_mutex.lock() {
-                db.delete_filedigest(f.id)?;
-            } else {
-                return Err(anyhow!("Unable to lock DB"));
+    /// Sets the flags that `command` stands for.
+    fn apply_command(&mut self) -> Result<()> {
+        let command = match &self.command {
+            Some(command) => command.clone(),
+            None => return Ok(()),
+        };
+        let name = match command {
+            Command::Scan { .. } => "scan",
+            Command::Report => "report",
+            Command::Serve => "serve",
+            Command::Clean { .. } => "clean",
+            Command::Stats => "stats",
+        };
+        if !self.path.is_empty() && name != "scan" {
+            return Err(anyhow!(
+                "{} doesn't scan, use `dupletti scan <path>` to scan",
+                name
+            ));
+        }
+        if name == "serve" && (self.reset_database || self.resume) {
+            return Err(anyhow!(
+                "serve only shows the database, it can't be used with --reset-database or --resume"
+            ));
+        }
+        match command {
+            Command::Scan {
+                paths,
+                videohash,
+                serve,
+            } => {
+                self.path.extend(paths);
+                self.videohash |= videohash;
+                self.no_web = !serve;
             }
+            Command::Report => self.no_web = true,
+            Command::Serve => self.no_web = false,
+            Command::Clean { prefix } => {
+                self.purge_missing = Some(prefix);
+                self.no_web = true;
+            }
+            Command::Stats => self.no_web = true,
         }
+        Ok(())
     }
-    Ok(())
-}
 
-fn filter_out_files_already_in_database(
-    db_mutex: &Mutex<Database>,
-    current_filelist: HashSet<PathBuf>,
-) -> Result<HashSet<PathBuf>> {
-    let files_in_db = get_file_digests(&db_mutex)?;
-    let filepaths_in_db: HashSet<_> = files_in_db.iter().map(|f| &f.path).collect();
-    let mut result = HashSet::<PathBuf>::new();
-    for f in current_filelist {
-        if !filepaths_in_db.contains(&f) {
-            result.insert(f);
+    /// Fails if a flag lacks the flags it depends on, where clap can't tell.
+    fn check_dependencies(&self, matches: &ArgMatches) -> Result<()> {
+        if self.dry_run && !self.clean_unfound && !self.auto_delete {
+            return Err(anyhow!("--dry-run needs --clean-unfound or --auto-delete"));
         }
+        if self.force && !self.clean_unfound && self.purge_missing.is_none() {
+            return Err(anyhow!(
+                "--force needs --clean-unfound, --purge-missing or clean"
+            ));
+        }
+        if self.delete_log.is_some() && !self.auto_delete && self.dedupe_mode().is_none() {
+            return Err(anyhow!("--delete-log needs --auto-delete or --dedupe"));
+        }
+        if self.auto_delete && matches.occurrences_of("keep") == 0 {
+            return Err(anyhow!(
+                "--auto-delete needs --keep, the default only applies to reclaimable space"
+            ));
+        }
+        Ok(())
     }
-    Ok(result)
-}
 
-fn update_database<P: AsRef<Path>>(
-    db_mutex: &Mutex<Database>,
-    path: P,
-    commit_batchsize: usize,
-    clean_unfound: bool,
-    update_videohash: bool,
-) -> Result<()> {
-    log::info!("creating file list");
-    let complete_filelist = list_files_in_directory(path);
-    log::info!("Number of found files: {:?}", complete_filelist.len());
-
-    if clean_unfound {
-        log::info!("Removing outdated files");
-        remove_outdated_files(&db_mutex, &complete_filelist)?;
-    }
-    let filelist = filter_out_files_already_in_database(&db_mutex, complete_filelist)?;
-    log::info!("Number of not already indexed files: {:?}", filelist.len());
-    log::info!("Hashing");
-    filehashing::process_filelist(&db_mutex, filelist, commit_batchsize)?;
-    log::info!("hashing done");
-    if update_videohash {
-        log::info!("Creating video hashes");
-        videohash::update_hashes(&db_mutex, commit_batchsize)?;
-        log::info!("video hashes done");
+    /// How duplicates are replaced, see --dedupe and --hardlink-duplicates.
+    fn dedupe_mode(&self) -> Option<DedupeMode> {
+        if self.hardlink_duplicates {
+            Some(DedupeMode::Hardlink)
+        } else {
+            self.dedupe
+        }
     }
-    Ok(())
-}
 
-fn main() -> Result<()> {
-    let args = Arc::new(ProgramArguments::from_args());
+    /// Whether only the web interface is started, without a thread for scanning.
+    fn serve_only(&self) -> bool {
+        self.command == Some(Command::Serve)
+    }
 
-    let _verbosity = match args.verbose {
-        0 => "warn",
-        1 => "info",
-        _ => "debug",
-    };
+    /// The number of threads hashing files, see `thread_count`.
+    fn hash_threads(&self) -> usize {
+        thread_count(self.hash_threads.unwrap_or(self.threads))
+    }
 
-    if args.verbose < 2 {
-        log::warn!("Verbosity is fixed at 'debug' during development");
+    /// The number of threads decoding videos, see `thread_count`.
+    fn video_threads(&self) -> usize {
+        thread_count(self.video_threads.unwrap_or(self.threads))
     }
 
-    env_logger::init_from_env(
-        env_logger::Env::default().filter_or(env_logger::DEFAULT_FILTER_ENV, "debug"),
-    );
-    //env_logger::init();
-    //log::set_max_level(log::LevelFilter::Debug);
+    /// Whether the web interface is started once the scan is done, rather than the
+    /// results being written to a file or the console.
+    fn serves_web(&self) -> bool {
+        !self.no_web && self.export_html.is_none() && self.output_report.is_none()
+    }
+}
 
-    // We can only call this function once, so here is a sensible place.
-    rayon::ThreadPoolBuilder::new()
-        .num_threads(args.threads)
-        .build_global()?;
+/// Number of paths that can be queued up between listing, filtering and hashing
+const LISTING_CHANNEL_SIZE: usize = 4096;
 
-    log::debug!("cmd args: {:?}", args);
+/// Maximum number of listed paths that are checked against the DB at once
+const FILTER_BATCHSIZE: usize = 1024;
 
-    let db = Database::new("./digests.sqlite", args.reset_database)?;
-    let db_mutex = Arc::new(Mutex::new(db));
-    let db_mutex2 = db_mutex.clone();
-    let args2 = args.clone();
-    let handle = thread::spawn(move || {
-        let args = Arc::clone(&args2);
-        let db_mutex = Arc::clone(&db_mutex2);
-        if !args.path.as_os_str().is_empty() {
-            update_database(
-                &db_mutex,
-                &args.path,
-                args.commit_batchsize,
-                args.clean_unfound,
-                args.videohash,
-            )
-            .unwrap();
+/// Walks `roots` and calls `visit` for every file found. Links are left out unless
+/// `follow_symlinks` is set. Then links that lead out of the roots are followed, and
+/// each directory is walked once so that link cycles end. Links into the roots are still
+/// left out, their targets are listed under their own paths. Directories that contain
+/// `marker_file` are not entered, nor are those deeper than `max_depth`, where files
+/// directly in a root are at depth 1. With `use_ignore_files`, paths matched by the
+/// .gitignore and .duplettiignore files found on the way are left out, and with
+/// `skip_hidden` those whose name starts with a dot. The roots are walked either way.
+/// With `one_file_system`, directories on another device than their root are not
+/// entered. Paths that aren't valid UTF-8 are always left out. Stops early once `visit`
+/// returns false.
+#[allow(clippy::too_many_arguments)]
+fn walk_directories<P: AsRef<Path>, F: FnMut(PathBuf) -> bool>(
+    roots: &[P],
+    marker_file: &str,
+    follow_symlinks: bool,
+    max_depth: Option<usize>,
+    use_ignore_files: bool,
+    skip_hidden: bool,
+    one_file_system: bool,
+    visit: &mut F,
+) -> UnwalkedDirs {
+    let canonical_roots: Vec<PathBuf> = roots
+        .iter()
+        .filter_map(|r| fs::canonicalize(r).ok())
+        .collect();
+    let mut walked_dirs = HashSet::new();
+    let mut unwalked = UnwalkedDirs::default();
+    // each directory keeps the device of its root, to compare its subdirectories with
+    type PendingDir = (PathBuf, usize, Option<Rc<IgnoreFiles>>, Option<u64>);
+    let mut pending_dirs: Vec<PendingDir> = roots
+        .iter()
+        .rev()
+        .map(|r| {
+            let device = if one_file_system {
+                paths::device_id(r.as_ref())
+            } else {
+                None
+            };
+            (r.as_ref().into(), 1, None, device)
+        })
+        .collect();
+    while let Some((dir, depth, ignore_files, root_device)) = pending_dirs.pop() {
+        if follow_symlinks {
+            if let Ok(canonical) = fs::canonicalize(&dir) {
+                if !walked_dirs.insert(canonical) {
+                    log::debug!("Skipping {:?}, it was walked already", dir);
+                    continue;
+                }
+            }
         }
-    });
-
-    if !args.no_web {
-        interface::start_web_interface(
-            db_mutex,
-            args.bind_address.clone(),
-            args.port,
-            args.allow_preview,
-        );
-    } else {
-        if let Ok(db) = db_mutex.lock() {
-            let results = similarities::get_list_of_similar_files(&db)?;
-            interface::show_results_in_console(&results);
+        if !marker_file.is_empty() && dir.join(marker_file).is_file() {
+            log::info!("Skipping {:?}, it contains {}", dir, marker_file);
+            unwalked.marked.push(dir);
+            continue;
+        }
+        let ignore_files = if use_ignore_files {
+            IgnoreFiles::read(&dir, ignore_files)
         } else {
-            return Err(anyhow!("Unable to lock DB"));
+            None
+        };
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                log::warn!("Unable to list {:?}: {}", dir, e);
+                continue;
+            }
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            // the DB stores paths as text, such files couldn't be found again
+            if path.to_str().is_none() {
+                log::warn!(
+                    "Skipping {:?}, paths that aren't valid UTF-8 can't be indexed",
+                    path
+                );
+                continue;
+            }
+            if skip_hidden && paths::is_hidden(&entry.file_name()) {
+                log::debug!("Skipping {:?}, it is hidden", path);
+                unwalked.hidden.push(path);
+                continue;
+            }
+            if entry.file_type().is_ok_and(|t| t.is_symlink()) {
+                // this also keeps the links left by --dedupe from being listed again
+                if !follow_symlinks {
+                    log::debug!("Skipping link {:?}", path);
+                    continue;
+                }
+                match fs::canonicalize(&path) {
+                    Ok(target) if canonical_roots.iter().any(|r| target.starts_with(r)) => {
+                        log::debug!("Skipping link {:?}, {:?} is listed already", path, target);
+                        continue;
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        log::debug!("Skipping broken link {:?}: {}", path, e);
+                        continue;
+                    }
+                }
+            }
+            let is_dir = path.is_dir();
+            if ignore_files
+                .as_ref()
+                .is_some_and(|i| i.ignores(&path, is_dir))
+            {
+                log::debug!("Skipping {:?}, it is ignored", path);
+                unwalked.ignored.push(path);
+                continue;
+            }
+            if is_dir {
+                if paths::crosses_device(root_device, paths::device_id(&path)) {
+                    log::info!("Skipping {:?}, it is on another file system", path);
+                    unwalked.other_device.push(path);
+                } else if max_depth.is_none_or(|max| depth < max) {
+                    pending_dirs.push((path, depth + 1, ignore_files.clone(), root_device));
+                } else {
+                    log::debug!("Skipping {:?}, it is deeper than --max-depth", path);
+                    unwalked.too_deep.push(path);
+                }
+            } else if path.is_file() && !visit(path) {
+                return unwalked;
+            }
         }
     }
-    log::debug!("exiting");
-    handle.join().unwrap();
-    Ok(())
+    unwalked
 }
 
-#[cfg(test)]
-mod tests {
+/// `threads` as given with --threads and the like, where 0 means one per logical CPU.
+fn thread_count(threads: usize) -> usize {
+    if threads > 0 {
+        return threads;
+    }
+    thread::available_parallelism().map_or_else(
+        |e| {
+            log::warn!("Unable to count the CPUs, using a single thread: {}", e);
+            1
+        },
+        |n| n.get(),
+    )
+}
+
+fn parse_max_depth(s: &str) -> Result<usize> {
+    match s.parse()? {
+        0 => Err(anyhow!(
+            "--max-depth must be at least 1, the files directly in --path"
+        )),
+        depth => Ok(depth),
+    }
+}
+
+/// The directories a walk left out on purpose. Their files are kept in the DB.
+#[derive(Debug, Default)]
+struct UnwalkedDirs {
+    /// The outermost directories containing the marker file
+    marked: Vec<PathBuf>,
+    /// The outermost directories below --max-depth
+    too_deep: Vec<PathBuf>,
+    /// The files and outermost directories matched by an ignore file
+    ignored: Vec<PathBuf>,
+    /// The hidden files and outermost hidden directories
+    hidden: Vec<PathBuf>,
+    /// The outermost directories on other file systems than their root
+    other_device: Vec<PathBuf>,
+}
+
+/// Temporary and bookkeeping files that are never interesting as duplicates
+const DEFAULT_SKIP_EXTENSIONS: [&str; 5] = ["tmp", "part", "crdownload", "log", "ds_store"];
+
+/// The extensions that are left out of scans, in the lower case form without a leading
+/// dot that `paths::has_extension` expects.
+fn skipped_extensions(additional: &[String], no_default_skips: bool) -> Vec<String> {
+    let defaults: &[&str] = if no_default_skips {
+        &[]
+    } else {
+        &DEFAULT_SKIP_EXTENSIONS
+    };
+    defaults
+        .iter()
+        .map(|e| e.to_string())
+        .chain(
+            additional
+                .iter()
+                .map(|e| e.trim().trim_start_matches('.').to_lowercase()),
+        )
+        .filter(|e| !e.is_empty())
+        .collect()
+}
+
+fn skip_files_by_extension(
+    files: HashSet<PathBuf>,
+    extensions: &[String],
+    stats: &mut ScanStats,
+) -> HashSet<PathBuf> {
+    let num_files = files.len();
+    let files: HashSet<PathBuf> = files
+        .into_iter()
+        .filter(|f| !paths::has_extension(f, extensions))
+        .collect();
+    stats.files_skipped += num_files - files.len();
+    files
+}
+
+/// Files that are listed but not hashed on purpose, because they don't have one of the
+/// --only-ext extensions, match --exclude-path or are smaller than --min-size. Those
+/// that are indexed already stay in the DB, unless the matching --clean-* flag is given.
+/// There is none for --only-ext, its files are neither looked for nor cleaned.
+#[derive(Debug, Clone)]
+struct ListingFilter {
+    /// Lower case and without the dot, empty to hash all extensions
+    only_extensions: Vec<String>,
+    exclude: ExcludePatterns,
+    clean_excluded: bool,
+    min_size: u64,
+    clean_small: bool,
+}
+
+/// Files as sorted by `ListingFilter::apply`.
+#[derive(Debug, Default)]
+struct Filtered {
+    hashed: HashSet<PathBuf>,
+    /// Left out, but kept in the DB if indexed
+    kept: Vec<PathBuf>,
+    /// Left out and removed from the DB when cleaning unfound files
+    dropped: Vec<PathBuf>,
+}
+
+impl ListingFilter {
+    fn new(args: &ProgramArguments) -> Result<ListingFilter> {
+        Ok(ListingFilter {
+            only_extensions: args
+                .only_ext
+                .iter()
+                .map(|e| e.trim_start_matches('.').to_lowercase())
+                .collect(),
+            exclude: ExcludePatterns::new(&args.exclude_path)?,
+            clean_excluded: args.clean_excluded,
+            min_size: args.min_size.bytes,
+            clean_small: args.clean_small,
+        })
+    }
+
+    /// Leaves nothing out.
+    #[cfg(test)]
+    fn none() -> ListingFilter {
+        ListingFilter {
+            only_extensions: Vec::new(),
+            exclude: ExcludePatterns::none(),
+            clean_excluded: false,
+            min_size: 0,
+            clean_small: false,
+        }
+    }
+
+    /// Whether `path` has one of the --only-ext extensions, or any if none are given.
+    fn includes(&self, path: &Path) -> bool {
+        self.only_extensions.is_empty() || paths::has_extension(path, &self.only_extensions)
+    }
+
+    fn apply(&self, files: HashSet<PathBuf>, stats: &mut ScanStats) -> Filtered {
+        let mut filtered = Filtered::default();
+        for f in files {
+            let clean = if !self.includes(&f) {
+                stats.files_not_included += 1;
+                false
+            } else if self.exclude.is_excluded(&f) {
+                stats.files_excluded += 1;
+                self.clean_excluded
+            } else if self.min_size > 0 && fs::metadata(&f).map_or(0, |m| m.len()) < self.min_size {
+                stats.files_too_small += 1;
+                self.clean_small
+            } else {
+                filtered.hashed.insert(f);
+                continue;
+            };
+            if clean {
+                filtered.dropped.push(f);
+            } else {
+                filtered.kept.push(f);
+            }
+        }
+        filtered
+    }
+}
+
+/// Leaves out files that would be downloaded when they are read, and remembers them
+/// for the skipped files page.
+fn skip_placeholders(
+    db_mutex: &Mutex<Database>,
+    files: HashSet<PathBuf>,
+    stats: &mut ScanStats,
+) -> Result<HashSet<PathBuf>> {
+    let (skipped, files): (HashSet<PathBuf>, HashSet<PathBuf>) = files
+        .into_iter()
+        .partition(|f| placeholders::is_placeholder(f));
+    if skipped.is_empty() {
+        return Ok(files);
+    }
+    stats.placeholders_skipped += skipped.len();
+    if let Ok(mut db) = db_mutex.lock() {
+        db.record_skipped_files(&skipped, placeholders::PLACEHOLDER_REASON)?;
+    } else {
+        return Err(anyhow!("Unable to lock DB"));
+    }
+    Ok(files)
+}
+
+fn get_file_paths(db_mutex: &Mutex<Database>) -> Result<Vec<PathBuf>> {
+    if let Ok(db) = db_mutex.lock() {
+        db.get_all_paths()
+    } else {
+        Err(anyhow!("Unable to lock DB"))
+    }
+}
+
+/// What clean_unfound does with an indexed file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Disposition {
+    /// Still there, kept
+    Found,
+    /// Below a directory that was not listed on purpose, or without one of the
+    /// --only-ext extensions, kept
+    Excluded,
+    /// Still there, but left out of scans with a --clean-* flag. Only the row is removed
+    Dropped,
+    /// Deleted through Dupletti, which remembered it already. Only the row is removed
+    AlreadyDeleted,
+    /// Gone without Dupletti knowing, the row is removed and the file remembered
+    Missing,
+    /// Outside the scanned roots, or still on disk though the scan didn't list it, kept
+    Unlisted,
+}
+
+/// What clean_unfound decides by, all as keys returned by `path_case.key`.
+#[derive(Debug, Default)]
+struct CleanupKeys {
+    roots: Vec<PathBuf>,
+    current: HashSet<PathBuf>,
+    dropped: HashSet<PathBuf>,
+    excluded_dirs: HashSet<PathBuf>,
+    tombstoned: HashSet<PathBuf>,
+}
+
+/// Decides what happens to the indexed file at `path`. `gone` tells whether a file that
+/// wasn't listed is really gone from the disk.
+fn cleanup_disposition(
+    path: &Path,
+    keys: &CleanupKeys,
+    filter: &ListingFilter,
+    path_case: PathCase,
+    gone: impl Fn(&Path) -> bool,
+) -> Disposition {
+    let key = path_case.key(path);
+    if keys.current.contains(&key) {
+        Disposition::Found
+    } else if keys.dropped.contains(&key) {
+        Disposition::Dropped
+    } else if key.ancestors().any(|a| keys.excluded_dirs.contains(a)) || !filter.includes(path) {
+        Disposition::Excluded
+    } else if keys.tombstoned.contains(&key) {
+        Disposition::AlreadyDeleted
+    } else if keys.roots.iter().any(|root| key.starts_with(root)) && gone(path) {
+        Disposition::Missing
+    } else {
+        Disposition::Unlisted
+    }
+}
+
+/// Whether nothing is at `path` anymore, not even a link. Files that can't be looked up
+/// for other reasons are not gone.
+fn is_gone(path: &Path) -> bool {
+    matches!(fs::symlink_metadata(path), Err(e) if e.kind() == io::ErrorKind::NotFound)
+}
+
+/// Number of indexed files per disposition after clean_unfound.
+#[derive(Debug, Default, PartialEq)]
+struct CleanupSummary {
+    found: usize,
+    excluded: usize,
+    dropped: usize,
+    already_deleted: usize,
+    missing: usize,
+    unlisted: usize,
+    /// Size of the dropped, already deleted and missing files
+    removed_bytes: u64,
+    /// Nothing was removed, see --dry-run
+    dry_run: bool,
+}
+
+impl fmt::Display for CleanupSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} files found, {} in excluded directories, {} left out of scans, {} deleted \
+             before, {} missing, {} kept outside the scan or still on disk, {} bytes {}",
+            self.found,
+            self.excluded,
+            self.dropped,
+            self.already_deleted,
+            self.missing,
+            self.unlisted,
+            self.removed_bytes,
+            if self.dry_run {
+                "would be removed"
+            } else {
+                "removed"
+            }
+        )
+    }
+}
+
+/// The directories directly below `roots` that contain `paths`, with the number of paths
+/// in each, the most affected first. Files directly in a root count for the root itself.
+fn affected_directories<'a>(
+    roots: &[PathBuf],
+    paths: impl IntoIterator<Item = &'a Path>,
+) -> Vec<(PathBuf, usize)> {
+    let mut counts = HashMap::new();
+    for path in paths {
+        let below_root = roots.iter().find_map(|root| {
+            let components: Vec<_> = path.strip_prefix(root).ok()?.components().collect();
+            Some((root, components))
+        });
+        let dir = match below_root {
+            Some((root, components)) if components.len() > 1 => root.join(components[0]),
+            Some((root, _)) => root.to_path_buf(),
+            None => path.parent().unwrap_or(path).to_path_buf(),
+        };
+        *counts.entry(dir).or_insert(0) += 1;
+    }
+    let mut dirs: Vec<_> = counts.into_iter().collect();
+    dirs.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    dirs
+}
+
+/// Number of affected directories listed when a cleanup is refused.
+const MAX_LISTED_DIRECTORIES: usize = 10;
+
+/// Removes indexed files that weren't found anymore, and those in `dropped`, which were
+/// found but are left out of scans now. Files below `excluded_dirs` were not listed on
+/// purpose and are kept, as are files outside of `roots` and those that are still on
+/// disk. Missing files are remembered like deleted ones, unless they were deleted
+/// through Dupletti already. If more files would be removed than
+/// `limits` allow, nothing is removed unless `force` is given. Forced removals of that
+/// many files are backed up first. With `dry_run` the files are only logged and counted.
+#[allow(clippy::too_many_arguments)]
+fn remove_outdated_files(
+    db_mutex: &Mutex<Database>,
+    roots: &[PathBuf],
+    current_filelist: &HashSet<PathBuf>,
+    dropped: &HashSet<PathBuf>,
+    excluded_dirs: &[PathBuf],
+    filter: &ListingFilter,
+    path_case: PathCase,
+    limits: CleanupLimits,
+    force: bool,
+    dry_run: bool,
+    backup: &BackupPolicy,
+) -> Result<CleanupSummary> {
+    let mut keys = CleanupKeys {
+        roots: roots.iter().map(|p| path_case.key(p)).collect(),
+        current: current_filelist.iter().map(|p| path_case.key(p)).collect(),
+        dropped: dropped.iter().map(|p| path_case.key(p)).collect(),
+        excluded_dirs: excluded_dirs.iter().map(|p| path_case.key(p)).collect(),
+        tombstoned: HashSet::new(),
+    };
+    let mut summary = CleanupSummary {
+        dry_run,
+        ..Default::default()
+    };
+    if let Ok(db) = db_mutex.lock() {
+        keys.tombstoned = db
+            .get_tombstoned_paths()?
+            .iter()
+            .map(|p| path_case.key(p))
+            .collect();
+        let mut outdated = Vec::new();
+        db.for_each_filedigest(|f| {
+            let disposition = cleanup_disposition(&f.path, &keys, filter, path_case, is_gone);
+            match disposition {
+                Disposition::Found => summary.found += 1,
+                Disposition::Excluded => summary.excluded += 1,
+                Disposition::Dropped => summary.dropped += 1,
+                Disposition::AlreadyDeleted => summary.already_deleted += 1,
+                Disposition::Missing => summary.missing += 1,
+                Disposition::Unlisted => summary.unlisted += 1,
+            }
+            if matches!(
+                disposition,
+                Disposition::Dropped | Disposition::AlreadyDeleted | Disposition::Missing
+            ) {
+                summary.removed_bytes += f.size;
+                outdated.push((f, disposition));
+            }
+            Ok(())
+        })?;
+        let num_indexed = summary.found
+            + summary.excluded
+            + summary.dropped
+            + summary.already_deleted
+            + summary.missing
+            + summary.unlisted;
+        let exceeded = limits.exceeded_by(outdated.len(), num_indexed);
+        if dry_run {
+            for (f, _) in &outdated {
+                log::info!("Would remove {:?}", f.path);
+            }
+            if exceeded && !force {
+                log::warn!(
+                    "Removing {} of {} indexed files would be refused without --force",
+                    outdated.len(),
+                    num_indexed
+                );
+            }
+            return Ok(summary);
+        }
+        if exceeded && !force {
+            let dirs = affected_directories(roots, outdated.iter().map(|(f, _)| f.path.as_path()));
+            let listed: Vec<String> = dirs
+                .iter()
+                .take(MAX_LISTED_DIRECTORIES)
+                .map(|(dir, n)| format!("{} ({} files)", dir.to_string_lossy(), n))
+                .collect();
+            return Err(anyhow!(
+                "Refusing to remove {} of {} indexed files that were not found or are left out, \
+                 pass --force to do it anyway. Affected: {}",
+                outdated.len(),
+                num_indexed,
+                listed.join(", ")
+            ));
+        }
+        if exceeded {
+            db.backup(
+                backup,
+                &format!("removing {} unfound files", outdated.len()),
+            )?;
+        }
+        for (f, disposition) in outdated {
+            log::info!("Removing {:?}", f.path);
+            if disposition == Disposition::Missing {
+                db.add_tombstone(&f)?;
+            }
+            db.delete_filedigest(f.id)?;
+        }
+    } else {
+        return Err(anyhow!("Unable to lock DB"));
+    }
+    Ok(summary)
+}
+
+/// Removes all indexed files below `dirs`. Returns the number of removed files.
+fn remove_files_below(
+    db_mutex: &Mutex<Database>,
+    dirs: &[PathBuf],
+    path_case: PathCase,
+) -> Result<usize> {
+    if let Ok(db) = db_mutex.lock() {
+        let mut ids = Vec::new();
+        db.for_each_filedigest(|f| {
+            if dirs.iter().any(|d| path_case.starts_with(&f.path, d)) {
+                log::info!("Pruning {:?}", f.path);
+                ids.push(f.id);
+            }
+            Ok(())
+        })?;
+        for id in ids.iter() {
+            db.delete_filedigest(*id)?;
+        }
+        Ok(ids.len())
+    } else {
+        Err(anyhow!("Unable to lock DB"))
+    }
+}
+
+/// The keys of all indexed paths, as compared by `path_case`.
+fn get_indexed_path_keys(
+    db_mutex: &Mutex<Database>,
+    path_case: PathCase,
+) -> Result<HashSet<PathBuf>> {
+    Ok(get_file_paths(db_mutex)?
+        .iter()
+        .map(|p| path_case.key(p))
+        .collect())
+}
+
+fn filter_out_files_already_in_database(
+    indexed_keys: &HashSet<PathBuf>,
+    current_filelist: HashSet<PathBuf>,
+    path_case: PathCase,
+) -> HashSet<PathBuf> {
+    current_filelist
+        .into_iter()
+        .filter(|f| !indexed_keys.contains(&path_case.key(f)))
+        .collect()
+}
+
+/// What the filter stage saw of the file listing.
+#[derive(Debug, Default)]
+struct Listing {
+    /// All listed files that weren't skipped, only collected when needed for clean_unfound
+    files: HashSet<PathBuf>,
+    /// Listed files that are removed from the DB, see `ListingFilter`
+    dropped: HashSet<PathBuf>,
+    num_found: usize,
+    num_new: usize,
+    stats: ScanStats,
+}
+
+/// The filter stage of a scan: takes listed paths from `paths` in batches, leaves out
+/// skipped extensions, files left out by `filter`, files that are already indexed and, unless `hydrate_placeholders`
+/// is set, cloud placeholders, and passes the rest on to
+/// `new_files` after adding them to the pending list. Each batch is passed on in
+/// `scan_order`.
+#[allow(clippy::too_many_arguments)]
+fn filter_listed_files(
+    db_mutex: &Mutex<Database>,
+    paths: mpsc::Receiver<PathBuf>,
+    new_files: mpsc::SyncSender<PathBuf>,
+    skip_extensions: &[String],
+    filter: &ListingFilter,
+    hydrate_placeholders: bool,
+    path_case: PathCase,
+    collect_files: bool,
+    scan_order: ScanOrder,
+    progress: &Progress,
+) -> Result<Listing> {
+    let indexed_keys = get_indexed_path_keys(db_mutex, path_case)?;
+    let mut listing = Listing::default();
+    // wait for the next path, then take everything else that is already queued up
+    while let Ok(path) = paths.recv() {
+        let mut batch: HashSet<PathBuf> = paths.try_iter().take(FILTER_BATCHSIZE - 1).collect();
+        batch.insert(path);
+        listing.num_found += batch.len();
+        let batch = skip_files_by_extension(batch, skip_extensions, &mut listing.stats);
+        let filtered = filter.apply(batch, &mut listing.stats);
+        let batch = filtered.hashed;
+        if collect_files {
+            listing.files.extend(batch.iter().cloned());
+            listing.files.extend(filtered.kept);
+            listing.dropped.extend(filtered.dropped);
+        }
+        let batch = filter_out_files_already_in_database(&indexed_keys, batch, path_case);
+        let batch = if hydrate_placeholders {
+            batch
+        } else {
+            skip_placeholders(db_mutex, batch, &mut listing.stats)?
+        };
+        if batch.is_empty() {
+            continue;
+        }
+        if let Ok(mut db) = db_mutex.lock() {
+            db.append_pending_files(&batch)?;
+            db.forget_skipped_files(&batch)?;
+        } else {
+            return Err(anyhow!("Unable to lock DB"));
+        }
+        listing.num_new += batch.len();
+        progress
+            .phase(scanstats::Phase::Hashing)
+            .add_total(batch.len(), filehashing::total_size(&batch));
+        log::debug!(
+            "Listed {} files so far, {} of them new",
+            listing.num_found,
+            listing.num_new
+        );
+        for f in filehashing::order_paths(batch, scan_order) {
+            if new_files.send(f).is_err() {
+                // hashing stopped early, its error is reported instead
+                return Ok(listing);
+            }
+        }
+    }
+    Ok(listing)
+}
+
+/// Pending file lists older than this are likely to be out of date when resuming.
+const PENDING_FILES_STALE_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
+
+fn load_pending_files(db_mutex: &Mutex<Database>) -> Result<HashSet<PathBuf>> {
+    let (filelist, listed_at) = if let Ok(db) = db_mutex.lock() {
+        db.get_pending_files()?
+    } else {
+        return Err(anyhow!("Unable to lock DB"));
+    };
+    if let Some(age) = listed_at.and_then(|t| t.elapsed().ok()) {
+        if age > PENDING_FILES_STALE_AFTER {
+            log::warn!(
+                "The pending file list was created {} hours ago, files may have changed since",
+                age.as_secs() / 3600
+            );
+        }
+    }
+    Ok(filelist)
+}
+
+/// An empty scan root with indexed files below it is most likely a drive that isn't
+/// mounted, cleaning unfound files would remove all of them.
+fn check_root_is_mounted(db: &Database, root: &Path, path_case: PathCase) -> Result<()> {
+    if fs::read_dir(root)?.next().is_some() {
+        return Ok(());
+    }
+    let paths = db.get_all_paths()?;
+    let share = scanroots::RootShare::new(paths.iter().map(|p| p.as_path()), root, path_case);
+    if share.below_root > 0 {
+        return Err(anyhow!(
+            "{} is empty, but {} indexed files are below it. Is the drive mounted? Refusing to \
+             clean unfound files",
+            root.to_string_lossy(),
+            share.below_root
+        ));
+    }
+    Ok(())
+}
+
+/// How `update_database` scans, mostly set by the options of the same names.
+#[derive(Debug, Clone)]
+struct ScanOptions {
+    batch_limits: BatchLimits,
+    /// Removes indexed files that weren't found, see `remove_outdated_files`
+    clean_unfound: bool,
+    /// Video hashes are only computed if given
+    videohash_config: Option<videohash::VideoHashConfig>,
+    case_insensitive_paths: bool,
+    /// Hashes the pending files of an interrupted scan instead of listing the roots
+    resume: bool,
+    skip_extensions: Vec<String>,
+    filter: ListingFilter,
+    hydrate_placeholders: bool,
+    marker_file: String,
+    follow_symlinks: bool,
+    max_depth: Option<usize>,
+    use_ignore_files: bool,
+    skip_hidden: bool,
+    one_file_system: bool,
+    prune_marked: bool,
+    probe_media: bool,
+    collect_metadata: bool,
+    scan_order: ScanOrder,
+    force: bool,
+    dry_run: bool,
+    cleanup_limits: CleanupLimits,
+    backup: BackupPolicy,
+}
+
+impl ScanOptions {
+    fn new(
+        args: &ProgramArguments,
+        batch_limits: BatchLimits,
+        videohash_config: Option<videohash::VideoHashConfig>,
+    ) -> Result<ScanOptions> {
+        Ok(ScanOptions {
+            batch_limits,
+            clean_unfound: args.clean_unfound,
+            videohash_config,
+            case_insensitive_paths: args.case_insensitive_paths,
+            resume: args.resume,
+            skip_extensions: skipped_extensions(&args.skip_extensions, args.no_default_skips),
+            filter: ListingFilter::new(args)?,
+            hydrate_placeholders: args.hydrate_placeholders,
+            marker_file: args.marker_file.clone(),
+            follow_symlinks: args.follow_symlinks,
+            max_depth: args.max_depth,
+            use_ignore_files: args.use_gitignore,
+            skip_hidden: args.skip_hidden,
+            one_file_system: args.one_file_system,
+            prune_marked: args.prune_marked,
+            probe_media: args.probe_media,
+            collect_metadata: args.collect_metadata,
+            scan_order: args.scan_order,
+            force: args.force,
+            dry_run: args.dry_run,
+            cleanup_limits: cleanup_limits(args),
+            backup: backup_policy(args),
+        })
+    }
+}
+
+/// Scans `roots` into the DB. Roots below another one are scanned once as part of it,
+/// and with `clean_unfound` only files found in none of them are removed. The scan
+/// reports to `progress` and stops early, keeping what was hashed, when it asks to.
+fn update_database<P: AsRef<Path>>(
+    db_mutex: &Mutex<Database>,
+    roots: &[P],
+    options: &ScanOptions,
+    progress: &Progress,
+) -> Result<ScanStats> {
+    let mut stats = ScanStats::default();
+    let roots: Vec<PathBuf> = roots.iter().map(|r| r.as_ref().to_path_buf()).collect();
+    // paths are compared the same way for all roots, a single case-insensitive one decides
+    let path_case = if roots
+        .iter()
+        .any(|r| PathCase::for_root(r, options.case_insensitive_paths) == PathCase::Insensitive)
+    {
+        PathCase::Insensitive
+    } else {
+        PathCase::Sensitive
+    };
+    log::info!("Comparing paths as {:?}", path_case);
+    let roots = scanroots::outermost_roots(&roots, path_case);
+    if let Ok(db) = db_mutex.lock() {
+        for root in roots.iter() {
+            if let Some(warning) = db.check_scan_root(root, path_case)? {
+                if options.clean_unfound && !options.force {
+                    return Err(anyhow!(
+                        "{}. Refusing to clean unfound files, pass --force to do it anyway",
+                        warning
+                    ));
+                }
+                log::warn!("{}", warning);
+            }
+            db.record_scan_root(root, path_case)?;
+        }
+        let roots: Vec<PathBuf> = db.get_scan_roots()?.into_iter().map(|r| r.path).collect();
+        for (a, b) in scanroots::overlapping_roots(&roots) {
+            log::warn!(
+                "{} and {} contain the same files under different paths, e.g. through a \
+                 symlink or a bind mount. With --collect-metadata, they aren't reported as \
+                 duplicates of each other",
+                a.to_string_lossy(),
+                b.to_string_lossy()
+            );
+        }
+    } else {
+        return Err(anyhow!("Unable to lock DB"));
+    }
+    if options.resume {
+        log::info!("Resuming interrupted scan");
+        let filelist = filehashing::order_paths(load_pending_files(db_mutex)?, options.scan_order);
+        log::info!("Number of not already indexed files: {:?}", filelist.len());
+        log::info!("Hashing");
+        progress
+            .phase(scanstats::Phase::Hashing)
+            .add_total(filelist.len(), filehashing::total_size(&filelist));
+        filehashing::process_filelist(
+            db_mutex,
+            filelist,
+            options.batch_limits,
+            path_case,
+            options.collect_metadata,
+            &[],
+            &mut stats,
+            progress,
+        )?;
+        if progress.stop_requested() {
+            return Ok(stats);
+        }
+    } else {
+        log::info!("Listing and hashing files");
+        // the pending list is filled while listing
+        if let Ok(mut db) = db_mutex.lock() {
+            if options.clean_unfound {
+                for root in roots.iter() {
+                    check_root_is_mounted(&db, root, path_case)?;
+                }
+            }
+            db.store_pending_files(&HashSet::new())?;
+        } else {
+            return Err(anyhow!("Unable to lock DB"));
+        }
+        let roots = &roots;
+        let (unwalked, listing) = thread::scope(|s| -> Result<_> {
+            let (path_tx, path_rx) = mpsc::sync_channel(LISTING_CHANNEL_SIZE);
+            let (new_tx, new_rx) = mpsc::sync_channel(LISTING_CHANNEL_SIZE);
+            let walker = s.spawn(move || {
+                let mut visit = |f| path_tx.send(f).is_ok();
+                walk_directories(
+                    roots,
+                    &options.marker_file,
+                    options.follow_symlinks,
+                    options.max_depth,
+                    options.use_ignore_files,
+                    options.skip_hidden,
+                    options.one_file_system,
+                    &mut visit,
+                )
+            });
+            let filter = s.spawn(move || {
+                filter_listed_files(
+                    db_mutex,
+                    path_rx,
+                    new_tx,
+                    &options.skip_extensions,
+                    &options.filter,
+                    options.hydrate_placeholders,
+                    path_case,
+                    options.clean_unfound,
+                    options.scan_order,
+                    progress,
+                )
+            });
+            filehashing::process_filelist(
+                db_mutex,
+                new_rx,
+                options.batch_limits,
+                path_case,
+                options.collect_metadata,
+                // files that were moved take over the rows the cleanup would remove
+                if options.clean_unfound { roots } else { &[] },
+                &mut stats,
+                progress,
+            )?;
+            let unwalked = walker.join().expect("directory walker panicked");
+            let listing = filter.join().expect("filter stage panicked")?;
+            Ok((unwalked, listing))
+        })?;
+        // the listing is incomplete, so nothing is cleaned up
+        if progress.stop_requested() {
+            return Ok(stats);
+        }
+        stats.files_skipped += listing.stats.files_skipped;
+        stats.files_not_included += listing.stats.files_not_included;
+        stats.files_excluded += listing.stats.files_excluded;
+        stats.files_too_small += listing.stats.files_too_small;
+        stats.placeholders_skipped += listing.stats.placeholders_skipped;
+        if stats.placeholders_skipped > 0 {
+            log::warn!(
+                "{} cloud placeholders were not hashed, since reading them downloads them. \
+                 Pass --hydrate-placeholders to hash them anyway",
+                stats.placeholders_skipped
+            );
+        }
+        stats.marked_dirs_skipped += unwalked.marked.len();
+        log::info!(
+            "Number of found files: {:?}, {} new, {} skipped, {} of other extensions, {} \
+             excluded, {} too small, {} marked directories skipped",
+            listing.num_found
+                - listing.stats.files_skipped
+                - listing.stats.files_not_included
+                - listing.stats.files_excluded
+                - listing.stats.files_too_small,
+            listing.num_new,
+            stats.files_skipped,
+            stats.files_not_included,
+            stats.files_excluded,
+            stats.files_too_small,
+            stats.marked_dirs_skipped
+        );
+
+        if options.prune_marked {
+            let num_pruned = remove_files_below(db_mutex, &unwalked.marked, path_case)?;
+            log::info!("Pruned {} files below marked directories", num_pruned);
+        }
+        if options.clean_unfound {
+            log::info!("Removing outdated files");
+            let summary = remove_outdated_files(
+                db_mutex,
+                roots,
+                &listing.files,
+                &listing.dropped,
+                &[
+                    unwalked.marked,
+                    unwalked.too_deep,
+                    unwalked.ignored,
+                    unwalked.hidden,
+                    unwalked.other_device,
+                ]
+                .concat(),
+                &options.filter,
+                path_case,
+                options.cleanup_limits,
+                options.force,
+                options.dry_run,
+                &options.backup,
+            )?;
+            if options.dry_run {
+                // asked for, so shown without -v as well
+                log::warn!("Cleanup dry run: {}", summary);
+            } else {
+                log::info!("Cleanup: {}", summary);
+            }
+        }
+    }
+    log::info!("hashing done");
+    if options.probe_media {
+        log::info!("Probing media files");
+        let num_probed = videohash::probe_media(db_mutex, options.batch_limits.max_items)?;
+        log::info!("{} media files probed", num_probed);
+    }
+    if let Some(config) = options.videohash_config {
+        log::info!("Creating video hashes");
+        videohash::update_hashes(
+            &db_mutex,
+            options.batch_limits,
+            &config,
+            &[],
+            &mut stats,
+            progress,
+        )?;
+        log::info!("video hashes done");
+    }
+    log::info!("Scan summary: {}", stats);
+    log::info!("Progress: {}", progress);
+    Ok(stats)
+}
+
+/// Hashes --path into an in-memory database and returns the duplicates, see --quick.
+fn quick_scan(args: &ProgramArguments) -> Result<Vec<FileGroup>> {
+    if args.path.is_empty() {
+        return Err(anyhow!("--quick needs a --path to scan"));
+    }
+    let db_mutex = Mutex::new(Database::in_memory()?);
+    let options = ScanOptions {
+        clean_unfound: false,
+        resume: false,
+        prune_marked: false,
+        probe_media: false,
+        collect_metadata: false,
+        force: false,
+        dry_run: false,
+        cleanup_limits: CleanupLimits::default(),
+        backup: BackupPolicy::disabled(),
+        ..ScanOptions::new(args, batch_limits(args)?, None)?
+    };
+    update_database(&db_mutex, &args.path, &options, &Progress::default())?;
+    let filter = GroupFilter {
+        added_after: args.added_after,
+        ..Default::default()
+    };
+    let db = db_mutex
+        .into_inner()
+        .map_err(|_| anyhow!("Unable to lock DB"))?;
+    Ok(filter.apply(similarities::get_list_of_similar_files(&db)?))
+}
+
+/// Handles --exclude, --unexclude and --list-excluded.
+fn manage_exclusions(db: &Database, args: &ProgramArguments) -> Result<()> {
+    for arg in &args.exclude {
+        let (digest, source) = exclusions::parse_digest_or_path(arg)?;
+        if db.exclude_digest(&digest, source.as_deref())? {
+            println!("Excluded {}", hex::encode(&digest));
+        } else {
+            println!("{} was already excluded", hex::encode(&digest));
+        }
+    }
+    for arg in &args.unexclude {
+        let digest = hex::decode(arg).map_err(|_| anyhow!("{} is not a hex digest", arg))?;
+        if !db.remove_excluded_digest(&digest)? {
+            return Err(anyhow!("{} is not excluded", arg));
+        }
+        println!("Removed {} from the exclusion list", arg);
+    }
+    if args.list_excluded {
+        for excluded in db.get_excluded_digests()? {
+            match excluded.source {
+                Some(source) => println!("{} {}", excluded.digest, source),
+                None => println!("{}", excluded.digest),
+            }
+        }
+        println!(
+            "{} indexed files are not reported",
+            db.count_excluded_files()?
+        );
+    }
+    Ok(())
+}
+
+fn export_csv(db_mutex: &Mutex<Database>, path: &Path) -> Result<()> {
+    if let Ok(db) = db_mutex.lock() {
+        let num_groups = csvexport::export_groups_csv(&db, path)?;
+        log::info!("Wrote {} groups to {}", num_groups, path.to_string_lossy());
+        Ok(())
+    } else {
+        Err(anyhow!("Unable to lock DB"))
+    }
+}
+
+/// Prints the duplicates when the web interface isn't used.
+fn print_results(
+    groups: &Vec<FileGroup>,
+    args: &ProgramArguments,
+    num_excluded: usize,
+    limit: Option<&ResultsLimit>,
+) -> Result<()> {
+    if let Some(path) = &args.export_html {
+        let tera =
+            interface::load_templates(&format!("http://{}:{}/", args.bind_address, args.port))?;
+        let file = fs::File::create(path)?;
+        let skipped_groups = interface::write_results_html(
+            file,
+            groups,
+            &tera,
+            args.allow_preview,
+            args.keep.clone(),
+            num_excluded,
+            None,
+        )?;
+        if !skipped_groups.is_empty() {
+            log::warn!(
+                "{} groups couldn't be written and were replaced by a note: {}",
+                skipped_groups.len(),
+                skipped_groups.join(", ")
+            );
+        }
+        log::info!(
+            "Wrote {} groups to {}",
+            groups.len(),
+            path.to_string_lossy()
+        );
+    } else if args.json {
+        println!("{}", serde_json::to_string_pretty(groups)?);
+    } else {
+        interface::show_results_in_console(groups, args.keep.clone(), limit);
+    }
+    Ok(())
+}
+
+/// The duplicates to report once the scan is done, as chosen by the options that filter
+/// and limit them.
+fn filtered_results(
+    db: &Database,
+    args: &ProgramArguments,
+) -> Result<(Vec<FileGroup>, Option<ResultsLimit>)> {
+    let filter = GroupFilter {
+        added_after: args.added_after,
+        include_dismissed: args.include_dismissed,
+        show_hardlinks: args.show_hardlinks,
+        root: args.root.clone(),
+        cross_roots: args.cross_roots,
+        ..Default::default()
+    };
+    let (groups, limit) = match args.limit_results {
+        Some(limit) => {
+            let largest = similarities::get_largest_similar_files(db, None, limit)?;
+            let limit = ResultsLimit {
+                num_groups: largest.num_groups,
+                wasted_total: largest.wasted_total,
+                full_totals: args.full_totals,
+            };
+            (largest.groups, Some(limit))
+        }
+        None => (similarities::get_list_of_similar_files(db)?, None),
+    };
+    Ok((filter.apply(groups), limit))
+}
+
+/// Writes the --output-report. Similar videos are clustered at --videohash-threshold, or
+/// the default one, and left out if no videos were hashed. Returns the number of groups of
+/// duplicates.
+fn write_report(db_mutex: &Mutex<Database>, path: &Path, args: &ProgramArguments) -> Result<usize> {
+    let threshold = args
+        .videohash_threshold
+        .unwrap_or(videohash::DEFAULT_THRESHOLD);
+    let has_videohashes = if let Ok(db) = db_mutex.lock() {
+        db.count_videohashes()? > 0
+    } else {
+        return Err(anyhow!("Unable to lock DB"));
+    };
+    let mut videos = Vec::new();
+    if has_videohashes {
+        let vhd = interface::VideoHashData::new(db_mutex, args.videohash_min_duration)?;
+        videos = vhd
+            .clusters(threshold)
+            .iter()
+            .map(videohash::ClusterReport::new)
+            .collect();
+        videos.sort_by_key(|c| std::cmp::Reverse(c.files[0].size));
+    }
+    let (groups, roots, num_excluded) = if let Ok(db) = db_mutex.lock() {
+        let (groups, _) = filtered_results(&db, args)?;
+        let roots: Vec<PathBuf> = if args.path.is_empty() {
+            db.get_scan_roots()?.into_iter().map(|r| r.path).collect()
+        } else {
+            args.path
+                .iter()
+                .map(|p| fs::canonicalize(p).unwrap_or_else(|_| p.clone()))
+                .collect()
+        };
+        (groups, roots, db.count_excluded_files()?)
+    } else {
+        return Err(anyhow!("Unable to lock DB"));
+    };
+    let info = interface::ReportInfo {
+        generated_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64,
+        roots: roots
+            .iter()
+            .map(|r| r.to_string_lossy().into_owned())
+            .collect(),
+        videos,
+        videohash_threshold: threshold,
+    };
+    let tera = interface::load_templates("/")?;
+    let skipped_groups = interface::write_results_html(
+        fs::File::create(path)?,
+        &groups,
+        &tera,
+        false,
+        args.keep.clone(),
+        num_excluded,
+        Some(&info),
+    )?;
+    if !skipped_groups.is_empty() {
+        log::warn!(
+            "{} groups couldn't be written and were replaced by a note: {}",
+            skipped_groups.len(),
+            skipped_groups.join(", ")
+        );
+    }
+    log::info!(
+        "Wrote {} groups and {} clusters of similar videos to {}",
+        groups.len(),
+        info.videos.len(),
+        path.to_string_lossy()
+    );
+    Ok(groups.len())
+}
+
+/// Prints the clusters of similar videos at `threshold`, those with the largest videos
+/// first.
+fn print_video_clusters(
+    db_mutex: &Mutex<Database>,
+    threshold: u16,
+    min_duration: f64,
+    json: bool,
+) -> Result<()> {
+    let vhd = interface::VideoHashData::new(db_mutex, min_duration)?;
+    let mut clusters: Vec<videohash::ClusterReport> = vhd
+        .clusters(threshold)
+        .iter()
+        .map(videohash::ClusterReport::new)
+        .collect();
+    clusters.sort_by_key(|c| std::cmp::Reverse(c.files[0].size));
+    if json {
+        println!("{}", serde_json::to_string_pretty(&clusters)?);
+    } else {
+        for cluster in &clusters {
+            println!("{}", cluster);
+        }
+        println!(
+            "{} clusters of similar videos at threshold {}",
+            clusters.len(),
+            threshold
+        );
+    }
+    Ok(())
+}
+
+/// The databases given with --database, or the default one.
+fn database_specs(args: &ProgramArguments) -> Vec<config::DatabaseSpec> {
+    if args.databases.is_empty() {
+        vec![config::DatabaseSpec::new(DATABASE_PATH).unwrap()]
+    } else {
+        args.databases.clone()
+    }
+}
+
+/// The web interface tells databases apart by their label, so labels must be unique.
+fn check_database_labels(databases: &[config::DatabaseSpec]) -> Result<()> {
+    let mut labels = HashSet::new();
+    for spec in databases {
+        if !labels.insert(&spec.label) {
+            return Err(anyhow!(
+                "Database label {} is used more than once, use label=path to tell them apart",
+                spec.label
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn batch_limits(args: &ProgramArguments) -> Result<BatchLimits> {
+    BatchLimits::new(args.commit_batchsize, args.commit_batch_bytes)?
+        .with_queue_size(args.hash_queue_size)
+}
+
+fn backup_policy(args: &ProgramArguments) -> BackupPolicy {
+    BackupPolicy {
+        enabled: !args.no_backup,
+        dir: args.backup_dir.clone(),
+        keep: args.keep_backups,
+    }
+}
+
+fn video_hash_config(args: &ProgramArguments) -> videohash::VideoHashConfig {
+    videohash::VideoHashConfig {
+        frame_size: args.videohash_frame_size,
+        min_duration: args.videohash_min_duration,
+        order: args.scan_order,
+        threads: args.video_threads(),
+    }
+}
+
+/// Scans `roots` with the settings given on the command line.
+fn scan_roots(
+    db_mutex: &Mutex<Database>,
+    roots: &[PathBuf],
+    args: &ProgramArguments,
+    batch_limits: BatchLimits,
+    videohash_config: Option<videohash::VideoHashConfig>,
+) -> Result<ScanStats> {
+    update_database(
+        db_mutex,
+        roots,
+        &ScanOptions::new(args, batch_limits, videohash_config)?,
+        &Progress::default(),
+    )
+}
+
+/// Runs the jobs posted to /api/jobs with the settings given on the command line. Scans
+/// only clean up unfound files when the job asks for it.
+fn job_runner(
+    args: Arc<ProgramArguments>,
+    db_mutex: Arc<Mutex<Database>>,
+    db_path: PathBuf,
+    batch_limits: BatchLimits,
+    videohash_config: Option<videohash::VideoHashConfig>,
+) -> jobs::RunJob {
+    Box::new(move |request, context| match request {
+        JobRequest::Scan {
+            path,
+            clean_unfound,
+        } => {
+            let _lock = lock_for_scanning(&db_path)?;
+            // what is listed and hashed is set on the command line, nothing is forced
+            let options = ScanOptions {
+                clean_unfound: *clean_unfound,
+                resume: false,
+                prune_marked: false,
+                force: false,
+                dry_run: false,
+                ..ScanOptions::new(&args, batch_limits, videohash_config)?
+            };
+            let roots = std::slice::from_ref(path);
+            let progress = jobs::scan_progress(context);
+            let stats = update_database(&db_mutex, roots, &options, &progress)?;
+            // a cancelled scan keeps the files it hashed
+            context.check_cancelled()?;
+            Ok(serde_json::to_value(&stats)?)
+        }
+        JobRequest::Videohash => {
+            let _lock = lock_for_scanning(&db_path)?;
+            let mut stats = ScanStats::default();
+            videohash::update_hashes(
+                &db_mutex,
+                batch_limits,
+                &video_hash_config(&args),
+                &[],
+                &mut stats,
+                &jobs::scan_progress(context),
+            )?;
+            context.check_cancelled()?;
+            Ok(serde_json::to_value(&stats)?)
+        }
+        JobRequest::Verify { group_id } => Ok(serde_json::to_value(&jobs::verify_group(
+            &db_mutex, group_id, context,
+        )?)?),
+        JobRequest::Dedupe {
+            mode,
+            keep,
+            include_dismissed,
+        } => {
+            let mode = mode.parse()?;
+            let keep = match keep {
+                Some(keep) => keep.parse()?,
+                None => args.keep.clone(),
+            };
+            if let Ok(db) = db_mutex.lock() {
+                db.backup(&backup_policy(&args), "deduplicating")?;
+            } else {
+                return Err(anyhow!("Unable to lock DB"));
+            }
+            // jobs of each served database are logged next to it
+            let mut log_file = open_delete_log(None, &db_path)?;
+            let summary = jobs::dedupe_groups(
+                &db_mutex,
+                mode,
+                keep,
+                *include_dismissed,
+                Some(&mut log_file),
+                context,
+            )?;
+            Ok(serde_json::to_value(&summary)?)
+        }
+    })
+}
+
+/// Opens `log_path` for appending, by default the log next to the database at `db_path`.
+fn open_delete_log(log_path: Option<&Path>, db_path: &Path) -> Result<fs::File> {
+    let log_path = match log_path {
+        Some(path) => path.to_path_buf(),
+        None => db_path.with_extension("deletions.log"),
+    };
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&log_path)
+        .with_context(|| format!("Opening {}", log_path.to_string_lossy()))
+}
+
+fn cleanup_limits(args: &ProgramArguments) -> CleanupLimits {
+    CleanupLimits {
+        max_percent: args.clean_unfound_max_percent,
+        max_files: args.clean_unfound_max_files,
+    }
+}
+
+fn effective_config(args: &ProgramArguments) -> EffectiveConfig {
+    let databases = database_specs(args);
+    EffectiveConfig {
+        config_file: args.config.clone(),
+        database_path: if args.quick {
+            PathBuf::from(database::IN_MEMORY_PATH)
+        } else {
+            databases[0].path.clone()
+        },
+        scan: config::ScanConfig {
+            paths: args.path.clone(),
+            threads: args.threads,
+            hash_threads: args.hash_threads(),
+            video_threads: args.video_threads(),
+            commit_batchsize: args.commit_batchsize,
+            commit_batch_bytes: args.commit_batch_bytes,
+            hash_queue_size: args.hash_queue_size,
+            clean_unfound: args.clean_unfound,
+            force: args.force,
+            dry_run: args.dry_run,
+            cleanup_limits: cleanup_limits(args),
+            case_insensitive_paths: args.case_insensitive_paths,
+            resume: args.resume,
+            skip_extensions: skipped_extensions(&args.skip_extensions, args.no_default_skips),
+            only_extensions: args.only_ext.clone(),
+            exclude_paths: args.exclude_path.clone(),
+            clean_excluded: args.clean_excluded,
+            min_size: args.min_size.bytes,
+            clean_small: args.clean_small,
+            hydrate_placeholders: args.hydrate_placeholders,
+            marker_file: args.marker_file.clone(),
+            follow_symlinks: args.follow_symlinks,
+            max_depth: args.max_depth,
+            use_gitignore: args.use_gitignore,
+            skip_hidden: args.skip_hidden,
+            one_file_system: args.one_file_system,
+            prune_marked: args.prune_marked,
+            probe_media: args.probe_media,
+            collect_metadata: args.collect_metadata,
+            burst_hash: args.burst_hash,
+            scan_order: args.scan_order,
+        },
+        web: config::WebConfig {
+            enabled: !args.no_web,
+            bind_address: args.bind_address.clone(),
+            port: args.port,
+            allow_preview: args.allow_preview,
+            allow_reveal: args.allow_reveal && interface::is_loopback(&args.bind_address),
+            max_body_size: args.max_body_size,
+            max_group_size: args.max_group_size,
+            keep: args.keep.clone(),
+            added_after: args.added_after,
+            include_dismissed: args.include_dismissed,
+            show_hardlinks: args.show_hardlinks,
+            databases,
+        },
+        videohash: config::VideoHashSettings {
+            enabled: args.videohash,
+            frame_size: args.videohash_frame_size,
+            min_duration: args.videohash_min_duration,
+            threshold: args.videohash_threshold,
+        },
+        backup: backup_policy(args),
+    }
+}
+
+/// Makes sure that no other process scans the database at the same time.
+fn lock_for_scanning(db_path: &Path) -> Result<DbLock> {
+    let lock = DbLock::acquire(db_path, LockRole::Scanning)?;
+    if let Some(pid) = dblock::lock_holder(db_path, LockRole::Serving) {
+        if pid != std::process::id() {
+            log::info!(
+                "{} is served by process {}, which picks up the changes of this scan",
+                db_path.to_string_lossy(),
+                pid
+            );
+        }
+    }
+    Ok(lock)
+}
+
+/// The level logged with `verbose` times -v.
+fn log_level(verbose: u8) -> log::LevelFilter {
+    match verbose {
+        0 => log::LevelFilter::Warn,
+        1 => log::LevelFilter::Info,
+        2 => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    }
+}
+
+/// Exit code of a run that reported duplicates without starting the web interface, see
+/// `exit_code`.
+const EXIT_DUPLICATES_FOUND: i32 = 3;
+
+/// How a run that didn't fail ended.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Outcome {
+    Done,
+    /// The duplicates were printed or written to a file, this many groups of them
+    Duplicates(usize),
+    /// --check-database or --verify found problems
+    Problems,
+    /// Asked to stop, see `shutdown`
+    Stopped,
+}
+
+/// The exit code of a run: 0 if it succeeded, 1 if it failed or found problems and 3 if
+/// it reported duplicates, so scripts can tell whether there are any. Runs that were
+/// stopped exit with `shutdown::EXIT_CODE`.
+fn exit_code(result: &Result<Outcome>) -> i32 {
+    match result {
+        Ok(Outcome::Duplicates(groups)) if *groups > 0 => EXIT_DUPLICATES_FOUND,
+        Ok(Outcome::Problems) => 1,
+        Ok(Outcome::Stopped) => shutdown::EXIT_CODE,
+        Ok(_) => 0,
+        Err(_) => 1,
+    }
+}
+
+/// The outcome of a check that found problems or none.
+fn checked(has_problems: bool) -> Outcome {
+    if has_problems {
+        Outcome::Problems
+    } else {
+        Outcome::Done
+    }
+}
+
+fn main() {
+    let result = run();
+    if let Err(e) = &result {
+        // the way Rust reports errors returned from main
+        eprintln!("Error: {:?}", e);
+    }
+    std::process::exit(exit_code(&result));
+}
+
+fn run() -> Result<Outcome> {
+    let matches = ProgramArguments::clap().get_matches();
+    let mut args = ProgramArguments::from_clap(&matches);
+    if let Some((path, file)) = ConfigFile::find(args.config.as_deref())? {
+        args.apply_config_file(&file, &matches)?;
+        // shown by --show-config
+        args.config = Some(path);
+    }
+    args.apply_command()?;
+    args.check_dependencies(&matches)?;
+    let args = Arc::new(args);
+
+    // RUST_LOG is parsed last, so it overrides -v
+    env_logger::Builder::new()
+        .filter_level(log_level(args.verbose))
+        .parse_env(env_logger::Env::default())
+        .init();
+    shutdown::install_handler()?;
+
+    // We can only call this function once, so here is a sensible place. Video hashing
+    // uses a pool of its own, see --video-threads.
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(args.hash_threads())
+        .build_global()?;
+    if args.throttle > 0.0 {
+        throttle::set_limit(args.throttle);
+        log::info!("Reading files at most at {} MiB/s", args.throttle);
+    }
+
+    log::debug!("cmd args: {:?}", args);
+
+    let config = effective_config(&args);
+    if args.show_config {
+        println!("{}", serde_json::to_string_pretty(&config)?);
+        return Ok(Outcome::Done);
+    }
+    let batch_limits = batch_limits(&args)?;
+    for warning in batch_limits.warnings() {
+        log::warn!("{}", warning);
+    }
+    if let Some(warning) = args
+        .videohash_threshold
+        .and_then(videohash::threshold_warning)
+    {
+        log::warn!("{}", warning);
+    }
+
+    if args.quick {
+        let groups = quick_scan(&args)?;
+        print_results(&groups, &args, 0, None)?;
+        return Ok(Outcome::Duplicates(groups.len()));
+    }
+
+    let databases = database_specs(&args);
+    check_database_labels(&databases)?;
+    if args.reset_database && databases[0].path.exists() {
+        Database::new(&databases[0].path, false)?.backup(&config.backup, "--reset-database")?;
+    }
+    if args.serve_only() && !databases[0].path.exists() {
+        log::warn!(
+            "{} does not exist, serving an empty database",
+            databases[0].path.to_string_lossy()
+        );
+    }
+    let db = Database::new(&databases[0].path, args.reset_database)?;
+    if !args.exclude.is_empty() || !args.unexclude.is_empty() || args.list_excluded {
+        manage_exclusions(&db, &args)?;
+        return Ok(Outcome::Done);
+    }
+    if let Some(other) = &args.compare_with {
+        let report = db.compare_with(other)?;
+        if args.json {
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        } else if args.csv {
+            print!("{}", report.to_csv());
+        } else {
+            print!("{}", report);
+        }
+        return Ok(Outcome::Done);
+    }
+    if let Some(min_percent) = args.partial {
+        let partial = db.partial_duplicates(min_percent.unwrap_or(50.0))?;
+        if args.json {
+            println!("{}", serde_json::to_string_pretty(&partial)?);
+        } else {
+            for pair in partial {
+                println!("{}", pair);
+            }
+        }
+        return Ok(Outcome::Done);
+    }
+    if args.stale {
+        let report = StaleReport::new(
+            db.stale_groups(args.older_than, tombstones::now()?)?,
+            args.older_than,
+        );
+        if args.json {
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        } else {
+            print!("{}", report);
+        }
+        return Ok(Outcome::Done);
+    }
+    if let Some(mode) = args.dedupe_mode() {
+        let mut groups = similarities::get_list_of_similar_files(&db)?;
+        if !args.include_dismissed {
+            groups.retain(|g| !g.dismissed);
+        }
+        // linking hardlinks to each other frees nothing
+        groups.retain(|g| !g.hardlinked);
+        db.backup(&config.backup, "deduplicating")?;
+        let mut log_file = open_delete_log(args.delete_log.as_deref(), &databases[0].path)?;
+        let summary =
+            dedupe::dedupe_groups(&db, &groups, args.keep.clone(), mode, Some(&mut log_file))?;
+        println!("{}", summary);
+        if let Some(notifier) = Notifier::new(args.notify, args.notify_webhook.clone()) {
+            let after = DuplicateTotals::of(&db).unwrap_or_default();
+            notifier.send(&RunSummary::of_dedupe(&summary, &after));
+        }
+        return Ok(Outcome::Done);
+    }
+    if args.auto_delete {
+        let mut groups = similarities::get_list_of_similar_files(&db)?;
+        if !args.include_dismissed {
+            groups.retain(|g| !g.dismissed);
+        }
+        // deleting a hardlink frees nothing
+        groups.retain(|g| !g.hardlinked);
+        let report = if args.dry_run {
+            autodelete::auto_delete(&db, &groups, &args.keep, true, None)?
+        } else {
+            db.backup(&config.backup, "deleting duplicates")?;
+            let mut log_file = open_delete_log(args.delete_log.as_deref(), &databases[0].path)?;
+            autodelete::auto_delete(&db, &groups, &args.keep, false, Some(&mut log_file))?
+        };
+        if args.json {
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        } else {
+            if report.dry_run {
+                for file in &report.files {
+                    println!("{}", file);
+                }
+            }
+            println!("{}", report);
+        }
+        return Ok(Outcome::Done);
+    }
+    if args.check_database {
+        // with several roots, all indexed files are checked
+        let root = match args.path.as_slice() {
+            [root] => Some(root.as_path()),
+            _ => None,
+        };
+        let path_case = root.map_or(PathCase::Sensitive, |r| {
+            PathCase::for_root(r, args.case_insensitive_paths)
+        });
+        let mut report = db.check_consistency(root, path_case, args.thorough)?;
+        println!("{}", report);
+        for root in db.get_scan_roots()? {
+            println!("Scanned {}", root.path.to_string_lossy());
+        }
+        if args.fix {
+            println!("Removed {} orphaned rows", db.fix_orphans()?);
+            report.orphaned_videohashes = 0;
+            report.orphaned_video_errors = 0;
+        }
+        return Ok(checked(report.has_problems()));
+    }
+    if args.verify {
+        if args.update_on_mismatch {
+            db.backup(&config.backup, "updating changed digests")?;
+        }
+        let db_mutex = Mutex::new(db);
+        let report =
+            verify::verify_digests(&db_mutex, args.update_on_mismatch, &Progress::default())?;
+        if args.json {
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        } else {
+            println!("{}", report);
+        }
+        if report.incomplete {
+            return Ok(Outcome::Stopped);
+        }
+        return Ok(checked(report.has_problems()));
+    }
+    if args.command == Some(Command::Stats) {
+        let stats = DatabaseStats::of(&db)?;
+        if args.json {
+            println!("{}", serde_json::to_string_pretty(&stats)?);
+        } else {
+            print!("{}", stats);
+        }
+        return Ok(Outcome::Done);
+    }
+    if let Some(prefix) = &args.purge_missing {
+        let prefix = prefix.as_ref().map(Path::new);
+        let path_case = prefix.map_or(PathCase::Sensitive, |p| {
+            PathCase::for_root(p, args.case_insensitive_paths)
+        });
+        db.backup(&config.backup, "purging missing files")?;
+        let limits = Some(config.scan.cleanup_limits).filter(|_| !args.force);
+        let removed = db.purge_missing(prefix, path_case, limits)?;
+        log::info!("Purged {} missing files", removed.len());
+        if let Some(Command::Clean { .. }) = args.command {
+            println!("Removed {} files that no longer exist", removed.len());
+            return Ok(Outcome::Done);
+        }
+    }
+    if let Some(days) = args.prune_tombstones {
+        let num_pruned = db.prune_tombstones(Duration::from_secs(days * 24 * 60 * 60))?;
+        log::info!(
+            "Pruned {} deleted files, {} remembered",
+            num_pruned,
+            db.count_tombstones()?
+        );
+    }
+    if args.prune_chunks {
+        let num_pruned = db.prune_chunk_index(args.chunk_index_min_size)?;
+        log::info!("Pruned {} chunks", num_pruned);
+    }
+    if args.rehash_videos {
+        let num_deleted = db.delete_videohashes_with_other_frame_size(args.videohash_frame_size)?;
+        log::info!("{} video hashes will be recomputed", num_deleted);
+    }
+    if args.videohash_only {
+        let _lock = lock_for_scanning(&databases[0].path)?;
+        let db_mutex = Mutex::new(db);
+        let mut stats = ScanStats::default();
+        let progress = Progress::default();
+        videohash::update_hashes(
+            &db_mutex,
+            batch_limits,
+            &video_hash_config(&args),
+            &args.path,
+            &mut stats,
+            &progress,
+        )?;
+        let hashed = progress
+            .phase(scanstats::Phase::Videohash)
+            .snapshot(std::time::Instant::now());
+        println!("Video hashes: {}", hashed);
+        if stats.videohash_conflicts > 0 {
+            log::warn!("{} video hash conflicts", stats.videohash_conflicts);
+        }
+        if shutdown::requested() {
+            return Ok(Outcome::Stopped);
+        }
+        return Ok(Outcome::Done);
+    }
+    let num_pending = db.count_pending_files()?;
+    if num_pending > 0 && !args.resume {
+        log::warn!(
+            "A previous scan was interrupted with {} files left to hash, use --resume to continue it",
+            num_pending
+        );
+    }
+    let videohash_config = if args.videohash {
+        Some(video_hash_config(&args))
+    } else {
+        None
+    };
+    if args.serve_only() && databases[0].path.exists() && db.is_empty()? {
+        log::warn!(
+            "{} is empty, scan a directory with `dupletti scan <directory>` first",
+            databases[0].path.to_string_lossy()
+        );
+    }
+    let first_run = args.path.is_empty()
+        && !args.resume
+        && !args.serve_only()
+        && databases.len() == 1
+        && db.is_empty()?;
+    if first_run {
+        println!(
+            "{} is empty. Scan a directory with `dupletti --path <directory>`{}",
+            databases[0].path.to_string_lossy(),
+            if !args.serves_web() {
+                String::new()
+            } else {
+                format!(
+                    ", or choose one at http://{}:{}/",
+                    args.bind_address, args.port
+                )
+            }
+        );
+    }
+    let serve_web = args.serves_web();
+    let db_path = databases[0].path.clone();
+    let scan_lock = if !args.path.is_empty() || args.resume {
+        Some(lock_for_scanning(&db_path)?)
+    } else {
+        None
+    };
+    // paths to scan that are chosen on the setup page of the web interface
+    let (scan_requests, requested_scans) = mpsc::channel::<PathBuf>();
+    let pool = Arc::new(DatabasePool::with_writer(&db_path, db));
+    let db_mutex = pool.db_mutex();
+    let db_mutex2 = db_mutex.clone();
+    let args2 = args.clone();
+    let scanner = move || {
+        let args = Arc::clone(&args2);
+        let db_mutex = Arc::clone(&db_mutex2);
+        let notifier = Notifier::new(args.notify, args.notify_webhook.clone());
+        let scan = |roots: &[PathBuf]| {
+            let before = notifier
+                .as_ref()
+                .map(|_| DuplicateTotals::of_locked(&db_mutex));
+            let stats =
+                scan_roots(&db_mutex, roots, &args, batch_limits, videohash_config).unwrap();
+            if let (Some(notifier), Some(before)) = (&notifier, before) {
+                let after = DuplicateTotals::of_locked(&db_mutex);
+                notifier.send(&RunSummary::of_scan(&stats, &before, &after));
+            }
+        };
+        if scan_lock.is_some() {
+            scan(&args.path);
+        }
+        if shutdown::requested() {
+            return;
+        }
+        if args.burst_hash {
+            let num_hashed = bursts::update_image_hashes(&db_mutex, args.commit_batchsize).unwrap();
+            log::info!("{} photos hashed for burst detection", num_hashed);
+        }
+        if args.chunk_index {
+            let num_chunked = chunks::update_chunk_index(
+                &db_mutex,
+                args.chunk_index_min_size,
+                chunks::CHUNK_SIZE,
+                args.commit_batchsize,
+            )
+            .unwrap();
+            log::info!("{} files split into chunks", num_chunked);
+        }
+        drop(scan_lock);
+        // without the web interface it is written once this thread is done
+        if let (true, Some(path)) = (serve_web, &args.export_csv) {
+            if let Err(e) = export_csv(&db_mutex, path) {
+                log::error!("Unable to write {}: {}", path.to_string_lossy(), e);
+            }
+        }
+        // ends once the web interface drops its sender, right away if it has none
+        for path in requested_scans {
+            match lock_for_scanning(&db_path) {
+                Ok(_lock) => scan(&[path]),
+                Err(e) => log::error!("Unable to scan {}: {}", path.to_string_lossy(), e),
+            }
+        }
+    };
+    let handle = if args.serve_only() {
+        None
+    } else {
+        Some(thread::spawn(scanner))
+    };
+
+    if serve_web {
+        let _serving = databases
+            .iter()
+            .map(|spec| DbLock::acquire(&spec.path, LockRole::Serving))
+            .collect::<Result<Vec<_>>>()?;
+        let mut served = vec![(databases[0].label.clone(), pool)];
+        for spec in &databases[1..] {
+            let pool = DatabasePool::open(&spec.path, false)?;
+            served.push((spec.label.clone(), Arc::new(pool)));
+        }
+        let scan_trigger = if first_run {
+            Some(interface::ScanTrigger::new(scan_requests))
+        } else {
+            None
+        };
+        let runner = job_runner(
+            args.clone(),
+            db_mutex.clone(),
+            databases[0].path.clone(),
+            batch_limits,
+            videohash_config,
+        );
+        let jobs = jobs::JobQueue::start(db_mutex.clone(), runner)?;
+        interface::start_web_interface(
+            served,
+            args.bind_address.clone(),
+            args.port,
+            args.allow_preview,
+            args.allow_reveal,
+            args.max_body_size,
+            args.keep.clone(),
+            args.max_group_size,
+            args.videohash,
+            args.videohash_min_duration,
+            args.videohash_threshold.or(if args.videohash {
+                Some(videohash::DEFAULT_THRESHOLD)
+            } else {
+                None
+            }),
+            config,
+            scan_trigger,
+            Some(jobs),
+        )?;
+        // only returns once asked to stop, after the scan saved what it hashed
+        if let Some(handle) = handle {
+            handle.join().unwrap();
+        }
+        Ok(Outcome::Stopped)
+    } else {
+        drop(scan_requests);
+        // the duplicates are printed once the scan is done
+        if let Some(handle) = handle {
+            handle.join().unwrap();
+        }
+        if shutdown::requested() {
+            return Ok(Outcome::Stopped);
+        }
+        if let Some(path) = &args.export_csv {
+            export_csv(&db_mutex, path)?;
+        }
+        let outcome = if let Some(path) = &args.output_report {
+            Outcome::Duplicates(write_report(&db_mutex, path, &args)?)
+        } else if let Some(threshold) = args.videohash_threshold {
+            // identical videos have identical histograms, they are part of the clusters
+            print_video_clusters(&db_mutex, threshold, args.videohash_min_duration, args.json)?;
+            Outcome::Done
+        } else if let Ok(db) = db_mutex.lock() {
+            let (results, limit) = filtered_results(&db, &args)?;
+            print_results(&results, &args, db.count_excluded_files()?, limit.as_ref())?;
+            Outcome::Duplicates(results.len())
+        } else {
+            return Err(anyhow!("Unable to lock DB"));
+        };
+        log::debug!("exiting");
+        Ok(outcome)
+    }
+}
+
+#[cfg(test)]
+mod tests {
     use super::*;
     use rusqlite::params;
-    use std::fs;
+    use std::io::Read;
     use tempfile::tempdir;
 
+    fn list_files_in_directory<P: AsRef<Path>>(directory: P) -> HashSet<PathBuf> {
+        let mut files = HashSet::new();
+        walk_directories(
+            &[directory],
+            "",
+            false,
+            None,
+            false,
+            false,
+            false,
+            &mut |f| {
+                files.insert(f);
+                true
+            },
+        );
+        files
+    }
+
+    #[test]
+    fn test_filter_out_files_already_in_database() -> Result<()> {
+        let mut testfiles = Vec::new();
+        testfiles.push(FileDigest::new(1, "/tmp/a", vec![0, 1, 2, 3], 1));
+        testfiles.push(FileDigest::new(2, "/tmp/b", vec![0, 1, 2, 3], 1));
+        testfiles.push(FileDigest::new(3, "/tmp/c", vec![0, 1, 2, 4], 1));
+
+        let db = Database::new("test.sqlite", true)?;
+        for f in testfiles.iter() {
+            db.insert_filedigest(&f)?;
+        }
+
+        testfiles.push(FileDigest::new(4, "/tmp/d", vec![0, 1, 2, 4], 1));
+        testfiles.push(FileDigest::new(5, "/tmp/e", vec![0, 1, 2, 5], 1));
+
+        let all_files: HashSet<_> = testfiles.iter().map(|f| f.path.clone()).collect();
+        let db_mutex = Mutex::new(db);
+        let indexed_keys = get_indexed_path_keys(&db_mutex, PathCase::Sensitive)?;
+        let new_files =
+            filter_out_files_already_in_database(&indexed_keys, all_files, PathCase::Sensitive);
+        let target_files: HashSet<_> = testfiles[3..].iter().map(|f| f.path.clone()).collect();
+        assert_eq!(new_files, target_files);
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove_outdated_files() -> Result<()> {
+        let db_mutex = Mutex::new(Database::new("test.sqlite", true)?);
+
+        db_mutex.lock().unwrap().db.execute(
+            "INSERT INTO file_digests (id, path, digest, size) VALUES \
+                (1, '/tmp/a', x'aaaaaaaa', 2), 
+                (2, '/tmp/b', x'aaaaaaaa', 2), 
+                (3, '/tmp/c', x'aaaaaaab', 1), 
+                (4, '/tmp/d', x'aaaaaaab', 3), 
+                (5, '/tmp/e', x'aaaaaaac', 1)",
+            params![],
+        )?;
+        let mut testfiles = db_mutex.lock().unwrap().get_all_filedigests()?;
+
+        testfiles.remove(3);
+        let remaining_files: HashSet<_> = testfiles.iter().map(|f| f.path.clone()).collect();
+
+        remove_outdated_files(
+            &db_mutex,
+            &["/tmp".into()],
+            &remaining_files,
+            &HashSet::new(),
+            &[],
+            &ListingFilter::none(),
+            PathCase::Sensitive,
+            CleanupLimits::default(),
+            true,
+            false,
+            &BackupPolicy::disabled(),
+        )?;
+        let new_files = db_mutex.lock().unwrap().get_all_filedigests()?;
+        assert_eq!(new_files, testfiles);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cleanup_limits() {
+        let limits = CleanupLimits::default();
+        assert!(!limits.exceeded_by(0, 0));
+        assert!(!limits.exceeded_by(20, 100));
+        assert!(limits.exceeded_by(21, 100));
+        // an empty listing removes everything
+        assert!(limits.exceeded_by(5, 5));
+        let limits = CleanupLimits {
+            max_percent: 100.0,
+            max_files: Some(10),
+        };
+        assert!(!limits.exceeded_by(10, 10));
+        assert!(limits.exceeded_by(11, 1000));
+    }
+
+    #[test]
+    fn test_affected_directories() {
+        let paths: Vec<PathBuf> = ["/nas/a/1", "/nas/a/sub/2", "/nas/b/3", "/nas/4", "/other/5"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        let roots = [PathBuf::from("/nas")];
+        let dirs = affected_directories(&roots, paths.iter().map(|p| p.as_path()));
+        assert_eq!(
+            dirs,
+            [
+                (PathBuf::from("/nas/a"), 2),
+                (PathBuf::from("/nas"), 1),
+                (PathBuf::from("/nas/b"), 1),
+                (PathBuf::from("/other"), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_refused_cleanup_keeps_db_unchanged() -> Result<()> {
+        let db = Database::new("test_refused_cleanup_keeps_db_unchanged.sqlite", true)?;
+        for (i, path) in ["/nas/a/1", "/nas/a/2", "/nas/b/3", "/nas/4"]
+            .iter()
+            .enumerate()
+        {
+            db.insert_filedigest(&FileDigest::new(0, path, vec![i as u8], 1))?;
+        }
+        let db_mutex = Mutex::new(db);
+        let current: HashSet<_> = [PathBuf::from("/nas/4")].iter().cloned().collect();
+        let root = Path::new("/nas");
+        let err = remove_outdated_files(
+            &db_mutex,
+            &[root.to_path_buf()],
+            &current,
+            &HashSet::new(),
+            &[],
+            &ListingFilter::none(),
+            PathCase::Sensitive,
+            CleanupLimits::default(),
+            false,
+            false,
+            &BackupPolicy::disabled(),
+        )
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("/nas/a (2 files), /nas/b (1 files)"));
+        let db = db_mutex.lock().unwrap();
+        assert_eq!(db.get_all_paths()?.len(), 4);
+        assert_eq!(db.count_tombstones()?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_dry_run_cleanup_keeps_db_unchanged() -> Result<()> {
+        let db = Database::new("test_dry_run_cleanup_keeps_db_unchanged.sqlite", true)?;
+        for (i, path) in ["/nas/a/1", "/nas/a/2", "/nas/b/3", "/nas/4"]
+            .iter()
+            .enumerate()
+        {
+            db.insert_filedigest(&FileDigest::new(0, path, vec![i as u8], 10 + i as u64))?;
+        }
+        let db_mutex = Mutex::new(db);
+        let current: HashSet<_> = [PathBuf::from("/nas/4")].iter().cloned().collect();
+        // more than the limits allow, which a dry run only warns about
+        let summary = remove_outdated_files(
+            &db_mutex,
+            &[PathBuf::from("/nas")],
+            &current,
+            &HashSet::new(),
+            &[],
+            &ListingFilter::none(),
+            PathCase::Sensitive,
+            CleanupLimits::default(),
+            false,
+            true,
+            &BackupPolicy::disabled(),
+        )?;
+        assert_eq!((summary.missing, summary.removed_bytes), (3, 33));
+        assert!(summary.to_string().ends_with("33 bytes would be removed"));
+        let db = db_mutex.lock().unwrap();
+        assert_eq!(db.get_all_paths()?.len(), 4);
+        assert_eq!(db.count_tombstones()?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_forced_cleanup_is_backed_up() -> Result<()> {
+        let dir = tempdir()?;
+        let db = Database::new(dir.path().join("digests.sqlite"), true)?;
+        for (i, path) in ["/nas/1", "/nas/2", "/nas/3"].iter().enumerate() {
+            db.insert_filedigest(&FileDigest::new(0, path, vec![i as u8], 1))?;
+        }
+        let db_mutex = Mutex::new(db);
+        let current: HashSet<_> = [PathBuf::from("/nas/1")].iter().cloned().collect();
+        let backups = dir.path().join("backups");
+        let policy = BackupPolicy {
+            dir: Some(backups.clone()),
+            ..BackupPolicy::default()
+        };
+        let cleanup = |limits: CleanupLimits| {
+            remove_outdated_files(
+                &db_mutex,
+                &["/nas".into()],
+                &current,
+                &HashSet::new(),
+                &[],
+                &ListingFilter::none(),
+                PathCase::Sensitive,
+                limits,
+                true,
+                false,
+                &policy,
+            )
+        };
+
+        // within the limits, nothing is backed up
+        cleanup(CleanupLimits {
+            max_percent: 100.0,
+            max_files: None,
+        })?;
+        assert!(!backups.exists());
+
+        db_mutex
+            .lock()
+            .unwrap()
+            .insert_filedigest(&FileDigest::new(0, "/nas/4", vec![4], 1))?;
+        cleanup(CleanupLimits::default())?;
+        let backup: Vec<PathBuf> = fs::read_dir(&backups)?.map(|e| e.unwrap().path()).collect();
+        assert_eq!(backup.len(), 1);
+        assert_eq!(
+            Database::new(&backup[0], false)?.get_all_paths()?,
+            [PathBuf::from("/nas/1"), PathBuf::from("/nas/4")]
+        );
+        assert_eq!(db_mutex.lock().unwrap().get_all_paths()?.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_empty_scan_root_is_not_cleaned() -> Result<()> {
+        let dir = tempdir()?;
+        let db = Database::new("test_empty_scan_root_is_not_cleaned.sqlite", true)?;
+        let indexed = dir.path().join("photo.jpg");
+        db.insert_filedigest(&FileDigest::new(0, &indexed.to_string_lossy(), vec![1], 1))?;
+        let db_mutex = Mutex::new(db);
+        let scan = || {
+            update_database(
+                &db_mutex,
+                &[dir.path()],
+                &ScanOptions {
+                    force: true,
+                    ..clean_scan()
+                },
+                &Progress::default(),
+            )
+        };
+        // not even --force cleans an empty mount point
+        assert!(scan().is_err());
+        assert_eq!(db_mutex.lock().unwrap().get_all_paths()?, [indexed]);
+        fs::write(dir.path().join("other.jpg"), "other")?;
+        scan()?;
+        assert_eq!(
+            db_mutex.lock().unwrap().get_all_paths()?,
+            [dir.path().join("other.jpg")]
+        );
+        Ok(())
+    }
+
+    /// A --clean-unfound scan with nothing else enabled, committing every 16 files.
+    fn clean_scan() -> ScanOptions {
+        ScanOptions {
+            batch_limits: BatchLimits::with_items(16),
+            clean_unfound: true,
+            videohash_config: None,
+            case_insensitive_paths: false,
+            resume: false,
+            skip_extensions: Vec::new(),
+            filter: ListingFilter::none(),
+            hydrate_placeholders: false,
+            marker_file: ".nodupes".to_string(),
+            follow_symlinks: false,
+            max_depth: None,
+            use_ignore_files: false,
+            skip_hidden: false,
+            one_file_system: false,
+            prune_marked: false,
+            probe_media: false,
+            collect_metadata: false,
+            scan_order: ScanOrder::Name,
+            force: false,
+            dry_run: false,
+            cleanup_limits: CleanupLimits::default(),
+            backup: BackupPolicy::disabled(),
+        }
+    }
+
+    /// Scans `root` with --clean-unfound, forced if `filter` cleans left out files.
+    fn scan_with_filter(
+        db_mutex: &Mutex<Database>,
+        root: &Path,
+        filter: &ListingFilter,
+    ) -> Result<ScanStats> {
+        update_database(
+            db_mutex,
+            &[root],
+            &ScanOptions {
+                filter: filter.clone(),
+                // cleaning most of these small libraries needs --force
+                force: filter.clean_excluded || filter.clean_small,
+                ..clean_scan()
+            },
+            &Progress::default(),
+        )
+    }
+
     #[test]
-    fn test_filter_out_files_already_in_database() -> Result<()> {
-        let mut testfiles = Vec::new();
-        testfiles.push(FileDigest::new(1, "/tmp/a", vec![0, 1, 2, 3], 1));
-        testfiles.push(FileDigest::new(2, "/tmp/b", vec![0, 1, 2, 3], 1));
-        testfiles.push(FileDigest::new(3, "/tmp/c", vec![0, 1, 2, 4], 1));
+    fn test_exclude_paths() -> Result<()> {
+        let dir = tempdir()?;
+        let thumbnails = dir.path().join("2020").join(".thumbnails").join("large");
+        fs::create_dir_all(&thumbnails)?;
+        fs::write(thumbnails.join("a.jpg"), "thumbnail")?;
+        fs::write(dir.path().join("a.jpg"), "photo")?;
+        fs::write(dir.path().join("disk.iso"), "image")?;
+        let db_mutex = Mutex::new(Database::new("test_exclude_paths.sqlite", true)?);
+        let paths = || db_mutex.lock().unwrap().get_all_paths().unwrap().len();
+        scan_with_filter(&db_mutex, dir.path(), &ListingFilter::none())?;
+        assert_eq!(paths(), 3);
 
-        let db = Database::new("test.sqlite", true)?;
-        for f in testfiles.iter() {
-            db.insert_filedigest(&f)?;
+        // new files below excluded directories aren't hashed, indexed ones are kept
+        fs::write(thumbnails.join("b.jpg"), "another thumbnail")?;
+        let mut filter = ListingFilter::none();
+        filter.exclude = ExcludePatterns::new(&["**/.thumbnails/**", "*.iso"])?;
+        let stats = scan_with_filter(&db_mutex, dir.path(), &filter)?;
+        assert_eq!((stats.files_excluded, stats.files_inserted), (3, 0));
+        assert_eq!(paths(), 3);
+
+        filter.clean_excluded = true;
+        scan_with_filter(&db_mutex, dir.path(), &filter)?;
+        let db = db_mutex.lock().unwrap();
+        assert_eq!(db.get_all_paths()?, [dir.path().join("a.jpg")]);
+        // they still exist, so they aren't remembered as deleted
+        assert!(db.get_tombstoned_paths()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_min_size() -> Result<()> {
+        let dir = tempdir()?;
+        fs::write(dir.path().join("small.txt"), "small")?;
+        fs::write(dir.path().join("large.mp4"), vec![1; 2048])?;
+        let db_mutex = Mutex::new(Database::new("test_min_size.sqlite", true)?);
+        let mut filter = ListingFilter::none();
+        filter.min_size = "1K".parse::<ByteSize>()?.bytes;
+        let stats = scan_with_filter(&db_mutex, dir.path(), &filter)?;
+        assert_eq!((stats.files_too_small, stats.files_inserted), (1, 1));
+        let paths = || db_mutex.lock().unwrap().get_all_paths().unwrap();
+        assert_eq!(paths(), [dir.path().join("large.mp4")]);
+
+        // files indexed before the threshold was raised stay until --clean-small
+        scan_with_filter(&db_mutex, dir.path(), &ListingFilter::none())?;
+        filter.min_size = 4096;
+        scan_with_filter(&db_mutex, dir.path(), &filter)?;
+        assert_eq!(paths().len(), 2);
+        filter.clean_small = true;
+        scan_with_filter(&db_mutex, dir.path(), &filter)?;
+        assert!(paths().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_log_level() {
+        assert_eq!(log_level(0), log::LevelFilter::Warn);
+        assert_eq!(log_level(1), log::LevelFilter::Info);
+        assert_eq!(log_level(2), log::LevelFilter::Debug);
+        assert_eq!(log_level(3), log::LevelFilter::Trace);
+        assert_eq!(log_level(7), log::LevelFilter::Trace);
+    }
+
+    #[test]
+    fn test_only_extensions() -> Result<()> {
+        let dir = tempdir()?;
+        for name in &["a.JPG", "b.jpeg", "c.Mp4", "d.txt", "jpg", "e.jpg.txt"] {
+            fs::write(dir.path().join(name), name)?;
         }
+        let db_mutex = Mutex::new(Database::new("test_only_extensions.sqlite", true)?);
+        let names = || {
+            let mut names: Vec<String> = db_mutex
+                .lock()
+                .unwrap()
+                .get_all_paths()
+                .unwrap()
+                .iter()
+                .map(|p| p.file_name().unwrap().to_string_lossy().into_owned())
+                .collect();
+            names.sort();
+            names
+        };
+        scan_with_filter(&db_mutex, dir.path(), &ListingFilter::none())?;
+        assert_eq!(names().len(), 6);
 
-        testfiles.push(FileDigest::new(4, "/tmp/d", vec![0, 1, 2, 4], 1));
-        testfiles.push(FileDigest::new(5, "/tmp/e", vec![0, 1, 2, 5], 1));
+        let mut filter = ListingFilter::none();
+        filter.only_extensions = vec!["jpg".into(), "jpeg".into(), "mp4".into()];
+        fs::write(dir.path().join("f.JPEG"), "f")?;
+        fs::write(dir.path().join("g.txt"), "g")?;
+        let stats = scan_with_filter(&db_mutex, dir.path(), &filter)?;
+        assert_eq!((stats.files_not_included, stats.files_inserted), (4, 1));
+        assert!(names().contains(&"f.JPEG".to_string()));
+        assert!(!names().contains(&"g.txt".to_string()));
 
-        let all_files: HashSet<_> = testfiles.iter().map(|f| f.path.clone()).collect();
+        // files of other extensions are not cleaned, even when they are gone
+        fs::remove_file(dir.path().join("d.txt"))?;
+        fs::remove_file(dir.path().join("jpg"))?;
+        fs::remove_file(dir.path().join("a.JPG"))?;
+        update_database(
+            &db_mutex,
+            &[dir.path()],
+            &ScanOptions {
+                filter: filter.clone(),
+                force: true,
+                ..clean_scan()
+            },
+            &Progress::default(),
+        )?;
+        assert_eq!(
+            names(),
+            ["b.jpeg", "c.Mp4", "d.txt", "e.jpg.txt", "f.JPEG", "jpg"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_depth() -> Result<()> {
+        let dir = tempdir()?;
+        let nested = dir.path().join("a").join("b");
+        fs::create_dir_all(&nested)?;
+        fs::write(dir.path().join("top.txt"), "top")?;
+        fs::write(dir.path().join("a").join("middle.txt"), "middle")?;
+        fs::write(nested.join("bottom.txt"), "bottom")?;
+        let db_mutex = Mutex::new(Database::new("test_max_depth.sqlite", true)?);
+        let scan = |max_depth| {
+            update_database(
+                &db_mutex,
+                &[dir.path()],
+                &ScanOptions {
+                    max_depth,
+                    ..clean_scan()
+                },
+                &Progress::default(),
+            )
+        };
+        let names = || {
+            let mut names: Vec<String> = db_mutex
+                .lock()
+                .unwrap()
+                .get_all_paths()
+                .unwrap()
+                .iter()
+                .map(|p| p.file_name().unwrap().to_string_lossy().into_owned())
+                .collect();
+            names.sort();
+            names
+        };
+        scan(Some(1))?;
+        assert_eq!(names(), ["top.txt"]);
+        scan(Some(2))?;
+        assert_eq!(names(), ["middle.txt", "top.txt"]);
+
+        // files indexed below the limit by an earlier scan aren't cleaned as unfound
+        scan(None)?;
+        assert_eq!(names().len(), 3);
+        scan(Some(1))?;
+        assert_eq!(names().len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_ignore_files() -> Result<()> {
+        let dir = tempdir()?;
+        let root = dir.path();
+        for d in ["build", "src/cache", "photos"].iter() {
+            fs::create_dir_all(root.join(d))?;
+        }
+        fs::write(root.join(".gitignore"), "build/\n*.o\n")?;
+        fs::write(root.join("src").join(".duplettiignore"), "cache\n!keep.o\n")?;
+        for f in [
+            "build/out.bin",
+            "main.o",
+            "src/lib.o",
+            "src/keep.o",
+            "src/cache/blob",
+            "src/lib.rs",
+            "photos/a.jpg",
+        ]
+        .iter()
+        {
+            fs::write(root.join(f), f)?;
+        }
+        let listed = |use_ignore_files| {
+            let mut listed = HashSet::new();
+            walk_directories(
+                &[root],
+                "",
+                false,
+                None,
+                use_ignore_files,
+                false,
+                false,
+                &mut |f| {
+                    listed.insert(f.strip_prefix(root).unwrap().to_path_buf());
+                    true
+                },
+            );
+            listed
+        };
+        // off by default, the ignore files are listed as well
+        assert_eq!(listed(false).len(), 9);
+        let expected: HashSet<PathBuf> = [
+            ".gitignore",
+            "src/.duplettiignore",
+            "src/keep.o",
+            "src/lib.rs",
+            "photos/a.jpg",
+        ]
+        .iter()
+        .map(PathBuf::from)
+        .collect();
+        assert_eq!(listed(true), expected);
+
+        let db_mutex = Mutex::new(Database::new("test_ignore_files.sqlite", true)?);
+        let scan = |use_ignore_files| {
+            update_database(
+                &db_mutex,
+                &[root],
+                &ScanOptions {
+                    use_ignore_files,
+                    ..clean_scan()
+                },
+                &Progress::default(),
+            )
+        };
+        let indexed = || -> HashSet<PathBuf> {
+            let db = db_mutex.lock().unwrap();
+            db.get_all_paths()
+                .unwrap()
+                .iter()
+                .map(|p| p.strip_prefix(root).unwrap().to_path_buf())
+                .collect()
+        };
+        scan(true)?;
+        assert_eq!(indexed(), expected);
+
+        // files indexed before are kept, like those below marked directories
+        scan(false)?;
+        assert_eq!(indexed().len(), 9);
+        scan(true)?;
+        assert_eq!(indexed().len(), 9);
+        Ok(())
+    }
+
+    #[test]
+    fn test_skip_hidden() -> Result<()> {
+        // a relative root that is hidden itself, like ./.tmpXXXX, is still scanned
+        let dir = tempfile::tempdir_in(".")?;
+        let name = dir.path().file_name().unwrap();
+        assert!(paths::is_hidden(name));
+        let root = &Path::new(".").join(name);
+        for d in [".git/objects/ab", ".cache", "photos/.thumbs"].iter() {
+            fs::create_dir_all(root.join(d))?;
+        }
+        for f in [
+            ".git/objects/ab/cdef",
+            ".cache/blob",
+            ".hidden.txt",
+            "photos/a.jpg",
+            "photos/.thumbs/a.jpg",
+        ]
+        .iter()
+        {
+            fs::write(root.join(f), f)?;
+        }
+        let db_mutex = Mutex::new(Database::new("test_skip_hidden.sqlite", true)?);
+        let scan = |skip_hidden| {
+            update_database(
+                &db_mutex,
+                &[root],
+                &ScanOptions {
+                    skip_hidden,
+                    ..clean_scan()
+                },
+                &Progress::default(),
+            )
+        };
+        let indexed = || -> Vec<PathBuf> {
+            let db = db_mutex.lock().unwrap();
+            let mut paths = db.get_all_paths().unwrap();
+            paths.sort();
+            paths
+        };
+        scan(true)?;
+        assert_eq!(indexed(), [root.join("photos/a.jpg")]);
+
+        // files indexed before are kept
+        scan(false)?;
+        assert_eq!(indexed().len(), 5);
+        scan(true)?;
+        assert_eq!(indexed().len(), 5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan_several_roots() -> Result<()> {
+        let (a, b) = (tempdir()?, tempdir()?);
+        let photos = a.path().join("photos");
+        fs::create_dir(&photos)?;
+        fs::write(photos.join("x.jpg"), "same")?;
+        fs::write(b.path().join("x.jpg"), "same")?;
+        fs::write(a.path().join("only.txt"), "only")?;
+        let db = Database::new("test_scan_several_roots.sqlite", true)?;
         let db_mutex = Mutex::new(db);
-        let new_files = filter_out_files_already_in_database(&db_mutex, all_files)?;
-        let target_files: HashSet<_> = testfiles[3..].iter().map(|f| f.path.clone()).collect();
-        assert_eq!(new_files, target_files);
+        // photos is part of a already
+        let roots = [a.path(), photos.as_path(), b.path()];
+        let scan = || update_database(&db_mutex, &roots, &clean_scan(), &Progress::default());
+        let stats = scan()?;
+        assert_eq!(stats.batch_duplicates, 0);
+        let mut expected = vec![
+            a.path().join("only.txt"),
+            photos.join("x.jpg"),
+            b.path().join("x.jpg"),
+        ];
+        expected.sort();
+        let paths = |db_mutex: &Mutex<Database>| -> Result<Vec<PathBuf>> {
+            let mut paths = db_mutex.lock().unwrap().get_all_paths()?;
+            paths.sort();
+            Ok(paths)
+        };
+        assert_eq!(paths(&db_mutex)?, expected);
+        let groups = similarities::get_list_of_similar_files(&db_mutex.lock().unwrap())?;
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].files.len(), 2);
+
+        // nothing is cleaned for being outside one of the roots, and moves between roots
+        // keep their rows
+        fs::rename(a.path().join("only.txt"), b.path().join("only.txt"))?;
+        let stats = scan()?;
+        assert_eq!(stats.files_moved, 1);
+        expected.retain(|p| *p != a.path().join("only.txt"));
+        expected.push(b.path().join("only.txt"));
+        expected.sort();
+        assert_eq!(paths(&db_mutex)?, expected);
         Ok(())
     }
 
     #[test]
-    fn test_remove_outdated_files() -> Result<()> {
-        let db_mutex = Mutex::new(Database::new("test.sqlite", true)?);
+    fn test_cleanup_disposition() {
+        let set =
+            |paths: &[&str]| -> HashSet<PathBuf> { paths.iter().map(PathBuf::from).collect() };
+        let keys = CleanupKeys {
+            roots: vec!["/a".into(), "/b".into(), "/marked".into()],
+            current: set(&["/a/found", "/b/Found"]),
+            dropped: set(&["/a/small"]),
+            excluded_dirs: set(&["/marked"]),
+            tombstoned: set(&["/a/deleted"]),
+        };
+        let disposition = |path: &str, path_case| {
+            cleanup_disposition(
+                Path::new(path),
+                &keys,
+                &ListingFilter::none(),
+                path_case,
+                |p| p != Path::new("/a/still-there"),
+            )
+        };
+
+        assert_eq!(
+            disposition("/a/found", PathCase::Sensitive),
+            Disposition::Found
+        );
+        assert_eq!(
+            disposition("/marked/x", PathCase::Sensitive),
+            Disposition::Excluded
+        );
+        assert_eq!(
+            disposition("/a/small", PathCase::Sensitive),
+            Disposition::Dropped
+        );
+        assert_eq!(
+            disposition("/a/deleted", PathCase::Sensitive),
+            Disposition::AlreadyDeleted
+        );
+        assert_eq!(
+            disposition("/a/gone", PathCase::Sensitive),
+            Disposition::Missing
+        );
+        // found wins over excluded, in case the marker was added while scanning
+        assert_eq!(
+            disposition("/b/Found", PathCase::Sensitive),
+            Disposition::Found
+        );
+        assert_eq!(
+            disposition("/b/found", PathCase::Sensitive),
+            Disposition::Missing
+        );
+        // only files below a scanned root that are really gone count as missing
+        assert_eq!(
+            disposition("/elsewhere/gone", PathCase::Sensitive),
+            Disposition::Unlisted
+        );
+        assert_eq!(
+            disposition("/a/still-there", PathCase::Sensitive),
+            Disposition::Unlisted
+        );
+    }
 
+    #[test]
+    fn test_remove_outdated_files_remembers_missing_files() -> Result<()> {
+        let db = Database::new("test_remove_outdated_files_tombstones.sqlite", true)?;
+        for (path, digest) in [("/tmp/found", 1), ("/tmp/deleted", 2), ("/tmp/gone", 3)].iter() {
+            db.insert_filedigest(&FileDigest::new(0, path, vec![*digest], 1))?;
+        }
+        // deleted through the web interface, and indexed again before it went away
+        db.add_tombstone(&FileDigest::new(0, "/tmp/deleted", vec![2], 1))?;
+        let db_mutex = Mutex::new(db);
+        let current: HashSet<_> = [PathBuf::from("/tmp/found")].iter().cloned().collect();
+
+        let summary = remove_outdated_files(
+            &db_mutex,
+            &["/tmp".into()],
+            &current,
+            &HashSet::new(),
+            &[],
+            &ListingFilter::none(),
+            PathCase::Sensitive,
+            CleanupLimits::default(),
+            true,
+            false,
+            &BackupPolicy::disabled(),
+        )?;
+        assert_eq!(
+            summary,
+            CleanupSummary {
+                found: 1,
+                excluded: 0,
+                dropped: 0,
+                already_deleted: 1,
+                missing: 1,
+                unlisted: 0,
+                removed_bytes: 2,
+                dry_run: false,
+            }
+        );
+        let db = db_mutex.lock().unwrap();
+        assert_eq!(db.get_all_paths()?, [PathBuf::from("/tmp/found")]);
+        let mut tombstoned = db.get_tombstoned_paths()?;
+        tombstoned.sort();
+        assert_eq!(
+            tombstoned,
+            [PathBuf::from("/tmp/deleted"), PathBuf::from("/tmp/gone")]
+        );
+        assert_eq!(db.count_tombstones()?, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_moved_files_keep_their_rows() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path();
+        fs::create_dir(root.join("old"))?;
+        fs::write(root.join("old/photo.jpg"), "moved")?;
+        fs::write(root.join("old/other.jpg"), "stays")?;
+        let db_mutex = Mutex::new(Database::new(
+            "test_moved_files_keep_their_rows.sqlite",
+            true,
+        )?);
+        let scan = || update_database(&db_mutex, &[root], &clean_scan(), &Progress::default());
+        scan()?;
+        let id_of = |path: &Path| -> Result<i64> {
+            let db = db_mutex.lock().unwrap();
+            let files = db.get_all_filedigests()?;
+            Ok(files.iter().find(|f| f.path == path).unwrap().id)
+        };
+        let id = id_of(&root.join("old/photo.jpg"))?;
         db_mutex.lock().unwrap().db.execute(
-            "INSERT INTO file_digests (id, path, digest, size) VALUES \
-                (1, '/tmp/a', x'aaaaaaaa', 2), 
-                (2, '/tmp/b', x'aaaaaaaa', 2), 
-                (3, '/tmp/c', x'aaaaaaab', 1), 
-                (4, '/tmp/d', x'aaaaaaab', 3), 
-                (5, '/tmp/e', x'aaaaaaac', 1)",
-            params![],
+            "INSERT INTO file_tags (id, tag) VALUES (?1, 'keep')",
+            params![id],
         )?;
-        let mut testfiles = get_file_digests(&db_mutex)?;
 
-        testfiles.remove(3);
-        let remaining_files: HashSet<_> = testfiles.iter().map(|f| f.path.clone()).collect();
+        fs::create_dir(root.join("new"))?;
+        fs::rename(root.join("old/photo.jpg"), root.join("new/photo.jpg"))?;
+        // a copy of a file that still exists is not a move
+        fs::copy(root.join("old/other.jpg"), root.join("new/other.jpg"))?;
+        let stats = scan()?;
+        assert_eq!(stats.files_moved, 1);
+        assert_eq!(stats.files_inserted, 1);
+        assert_eq!(id_of(&root.join("new/photo.jpg"))?, id);
+        let db = db_mutex.lock().unwrap();
+        assert_eq!(db.get_tags(id)?, ["keep"]);
+        assert_eq!(db.get_all_filedigests()?.len(), 3);
+        assert_eq!(db.count_tombstones()?, 0);
+        Ok(())
+    }
 
-        remove_outdated_files(&db_mutex, &remaining_files)?;
-        let new_files = get_file_digests(&db_mutex)?;
-        assert_eq!(new_files, testfiles);
+    #[test]
+    fn test_case_insensitive_paths_are_not_indexed_twice() -> Result<()> {
+        let db = Database::new("test_case_insensitive_paths.sqlite", true)?;
+        db.insert_filedigest(&FileDigest::new(1, "/tmp/X/Photo.JPG", vec![0, 1, 2, 3], 1))?;
+        let db_mutex = Mutex::new(db);
+        let filelist: HashSet<_> = [PathBuf::from("/tmp/x/photo.jpg")]
+            .iter()
+            .cloned()
+            .collect();
+
+        let new_files = filter_out_files_already_in_database(
+            &get_indexed_path_keys(&db_mutex, PathCase::Sensitive)?,
+            filelist.clone(),
+            PathCase::Sensitive,
+        );
+        assert_eq!(new_files.len(), 1);
+        let new_files = filter_out_files_already_in_database(
+            &get_indexed_path_keys(&db_mutex, PathCase::Insensitive)?,
+            filelist.clone(),
+            PathCase::Insensitive,
+        );
+        assert!(new_files.is_empty());
+
+        // clean_unfound must not remove the file just because of the different case
+        remove_outdated_files(
+            &db_mutex,
+            &["/tmp".into()],
+            &filelist,
+            &HashSet::new(),
+            &[],
+            &ListingFilter::none(),
+            PathCase::Insensitive,
+            CleanupLimits::default(),
+            true,
+            false,
+            &BackupPolicy::disabled(),
+        )?;
+        let paths = db_mutex.lock().unwrap().get_all_paths()?;
+        assert_eq!(paths, vec![PathBuf::from("/tmp/X/Photo.JPG")]);
+        remove_outdated_files(
+            &db_mutex,
+            &["/tmp".into()],
+            &filelist,
+            &HashSet::new(),
+            &[],
+            &ListingFilter::none(),
+            PathCase::Sensitive,
+            CleanupLimits::default(),
+            true,
+            false,
+            &BackupPolicy::disabled(),
+        )?;
+        assert!(db_mutex.lock().unwrap().get_all_paths()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_resume_hashes_only_pending_files() -> Result<()> {
+        let dir = tempdir()?;
+        let pending: HashSet<_> = [dir.path().join("a"), dir.path().join("b")]
+            .iter()
+            .cloned()
+            .collect();
+        for path in pending.iter() {
+            fs::write(path, "pending")?;
+        }
+        fs::write(dir.path().join("c"), "not listed before the interruption")?;
+
+        let mut db = Database::new("test_resume.sqlite", true)?;
+        db.store_pending_files(&pending)?;
+        let db_mutex = Mutex::new(db);
+        let stats = update_database(
+            &db_mutex,
+            &[dir.path()],
+            &ScanOptions {
+                clean_unfound: false,
+                resume: true,
+                ..clean_scan()
+            },
+            &Progress::default(),
+        )?;
+        assert_eq!(stats.files_inserted, 2);
+
+        let db = db_mutex.lock().unwrap();
+        let indexed: HashSet<_> = db.get_all_paths()?.into_iter().collect();
+        assert_eq!(indexed, pending);
+        assert_eq!(db.count_pending_files()?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_marked_directories_are_skipped() -> Result<()> {
+        let dir = tempdir()?;
+        let root = dir.path();
+        for path in [
+            "a/file",
+            "marked/.nodupes",
+            "marked/x",
+            "marked/sub/.nodupes",
+            "marked/sub/y",
+            "other/sub/.nodupes",
+            "other/sub/z",
+            "other/kept",
+        ]
+        .iter()
+        {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::write(&path, path.to_string_lossy().as_bytes())?;
+        }
+        let db = Database::new("test_marked_directories_are_skipped.sqlite", true)?;
+        // indexed before the directory was marked
+        let old_file = root.join("marked/x");
+        db.insert_filedigest(&FileDigest::new(0, &old_file.to_string_lossy(), vec![1], 1))?;
+        let db_mutex = Mutex::new(db);
+        let scan = |prune_marked: bool| {
+            update_database(
+                &db_mutex,
+                &[root],
+                &ScanOptions {
+                    prune_marked,
+                    ..clean_scan()
+                },
+                &Progress::default(),
+            )
+        };
+
+        let stats = scan(false)?;
+        assert_eq!(stats.marked_dirs_skipped, 2);
+        let indexed = || -> HashSet<PathBuf> {
+            db_mutex
+                .lock()
+                .unwrap()
+                .get_all_paths()
+                .unwrap()
+                .into_iter()
+                .collect()
+        };
+        let expected: HashSet<PathBuf> = [
+            old_file.clone(),
+            root.join("a/file"),
+            root.join("other/kept"),
+        ]
+        .iter()
+        .cloned()
+        .collect();
+        // clean_unfound keeps the file below the marked directory
+        assert_eq!(indexed(), expected);
+
+        scan(true)?;
+        assert!(!indexed().contains(&old_file));
+        assert_eq!(indexed().len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_walk_directory_skips_file_links() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::write(dir.path().join("file"), "content")?;
+        std::os::unix::fs::symlink("file", dir.path().join("link"))?;
+        let mut listed = Vec::new();
+        walk_directories(
+            &[dir.path()],
+            "",
+            false,
+            None,
+            false,
+            false,
+            false,
+            &mut |f| {
+                listed.push(f);
+                true
+            },
+        );
+        assert_eq!(listed, [dir.path().join("file")]);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_walk_directories_through_symlinks() -> Result<()> {
+        use std::os::unix::fs::symlink;
+        let (dir, outside) = (tempdir()?, tempdir()?);
+        let root = dir.path();
+        fs::create_dir(root.join("sub"))?;
+        fs::write(root.join("sub").join("a.jpg"), "a")?;
+        fs::write(outside.path().join("b.jpg"), "b")?;
+        // a cycle inside the root, a link to a file in it and a way out and back in
+        symlink(root, root.join("sub").join("loop"))?;
+        symlink(root.join("sub").join("a.jpg"), root.join("a-link.jpg"))?;
+        symlink(outside.path(), root.join("external"))?;
+        symlink(root, outside.path().join("back"))?;
+        symlink(outside.path(), outside.path().join("self"))?;
+
+        let walk = |follow_symlinks| {
+            let mut listed = Vec::new();
+            walk_directories(
+                &[root],
+                "",
+                follow_symlinks,
+                None,
+                false,
+                false,
+                false,
+                &mut |f| {
+                    listed.push(f);
+                    true
+                },
+            );
+            listed.sort();
+            listed
+        };
+        assert_eq!(walk(false), [root.join("sub").join("a.jpg")]);
+        assert_eq!(
+            walk(true),
+            [
+                root.join("external").join("b.jpg"),
+                root.join("sub").join("a.jpg")
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_streaming_scan_matches_two_pass_scan() -> Result<()> {
+        let dir = tempdir()?;
+        let root = dir.path();
+        for path in [
+            "a.mp4",
+            "b.tmp",
+            "known",
+            "sub/c.jpg",
+            "sub/d.jpg",
+            "sub/deeper/e",
+            "marked/.nodupes",
+            "marked/f",
+        ]
+        .iter()
+        {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::write(&path, path.to_string_lossy().as_bytes())?;
+        }
+        let skip_extensions = skipped_extensions(&[], false);
+        let new_db = |name: &str| -> Result<Mutex<Database>> {
+            let db = Database::new(name, true)?;
+            let known = root.join("known").to_string_lossy().into_owned();
+            db.insert_filedigest(&FileDigest::new(0, &known, vec![1], 1))?;
+            Ok(Mutex::new(db))
+        };
+
+        // list everything first, then filter, then hash
+        let two_pass_db = new_db("test_streaming_scan_two_pass.sqlite")?;
+        let mut two_pass_stats = ScanStats::default();
+        let mut listed = HashSet::new();
+        let marked_dirs = walk_directories(
+            &[root],
+            ".nodupes",
+            false,
+            None,
+            false,
+            false,
+            false,
+            &mut |f| {
+                listed.insert(f);
+                true
+            },
+        );
+        two_pass_stats.marked_dirs_skipped += marked_dirs.marked.len();
+        let listed = skip_files_by_extension(listed, &skip_extensions, &mut two_pass_stats);
+        let indexed_keys = get_indexed_path_keys(&two_pass_db, PathCase::Sensitive)?;
+        let filelist =
+            filter_out_files_already_in_database(&indexed_keys, listed, PathCase::Sensitive);
+        filehashing::process_filelist(
+            &two_pass_db,
+            filelist,
+            BatchLimits::with_items(2),
+            PathCase::Sensitive,
+            false,
+            &[],
+            &mut two_pass_stats,
+            &Progress::default(),
+        )?;
+
+        let streaming_db = new_db("test_streaming_scan_streaming.sqlite")?;
+        let streaming_stats = update_database(
+            &streaming_db,
+            &[root],
+            &ScanOptions {
+                batch_limits: BatchLimits::with_items(2),
+                skip_extensions: skip_extensions.clone(),
+                ..clean_scan()
+            },
+            &Progress::default(),
+        )?;
+
+        assert_eq!(streaming_stats, two_pass_stats);
+        assert_eq!(streaming_stats.files_inserted, 4);
+        let digests = |db_mutex: &Mutex<Database>| -> Result<Vec<(PathBuf, Vec<u8>)>> {
+            let mut digests: Vec<_> = db_mutex
+                .lock()
+                .unwrap()
+                .get_all_filedigests()?
+                .into_iter()
+                .map(|f| (f.path, f.digest))
+                .collect();
+            digests.sort();
+            Ok(digests)
+        };
+        assert_eq!(digests(&streaming_db)?, digests(&two_pass_db)?);
+        assert_eq!(streaming_db.lock().unwrap().count_pending_files()?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_skip_files_by_extension() -> Result<()> {
+        let dir = tempdir()?;
+        for name in ["a.mp4", "b.TMP", "c.part", ".DS_Store", "d.bak", "e.log"].iter() {
+            fs::write(dir.path().join(name), name)?;
+        }
+        let names = |extensions: &[String]| {
+            let mut stats = ScanStats::default();
+            let files = skip_files_by_extension(
+                list_files_in_directory(dir.path()),
+                extensions,
+                &mut stats,
+            );
+            let mut names: Vec<String> = files
+                .iter()
+                .map(|f| f.file_name().unwrap().to_string_lossy().into_owned())
+                .collect();
+            names.sort();
+            (names, stats.files_skipped)
+        };
+
+        let defaults = skipped_extensions(&[], false);
+        assert_eq!(
+            names(&defaults),
+            (vec!["a.mp4".to_string(), "d.bak".to_string()], 4)
+        );
+        let with_bak = skipped_extensions(&[".BAK".to_string()], false);
+        assert_eq!(names(&with_bak), (vec!["a.mp4".to_string()], 5));
+        let only_bak = skipped_extensions(&["bak".to_string()], true);
+        assert_eq!(names(&only_bak).1, 1);
+        assert!(skipped_extensions(&[], true).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_placeholders_are_skipped() -> Result<()> {
+        let dir = tempdir()?;
+        fs::write(dir.path().join("local"), "downloaded")?;
+        // a sparse file has no data blocks, like a placeholder
+        let placeholder = dir.path().join("placeholder");
+        fs::File::create(&placeholder)?.set_len(1 << 20)?;
+        if !placeholders::is_placeholder(&placeholder) {
+            // the file system allocated blocks anyway
+            return Ok(());
+        }
+        let db_mutex = Mutex::new(Database::new("test_placeholders_are_skipped.sqlite", true)?);
+        let scan = |hydrate_placeholders| {
+            update_database(
+                &db_mutex,
+                &[dir.path()],
+                &ScanOptions {
+                    hydrate_placeholders,
+                    ..clean_scan()
+                },
+                &Progress::default(),
+            )
+        };
+        let stats = scan(false)?;
+        assert_eq!((stats.files_inserted, stats.placeholders_skipped), (1, 1));
+        {
+            let db = db_mutex.lock().unwrap();
+            assert_eq!(db.get_all_paths()?, [dir.path().join("local")]);
+            let skipped = db.get_skipped_files()?;
+            assert_eq!(skipped.len(), 1);
+            assert_eq!(skipped[0].path, placeholder);
+        }
+        let stats = scan(true)?;
+        assert_eq!((stats.files_inserted, stats.placeholders_skipped), (1, 0));
+        let db = db_mutex.lock().unwrap();
+        assert_eq!(db.get_all_paths()?.len(), 2);
+        assert!(db.get_skipped_files()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_quick_scan() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path();
+        fs::create_dir_all(root.join("sub"))?;
+        fs::write(root.join("a.jpg"), "same")?;
+        fs::write(root.join("sub").join("b.jpg"), "same")?;
+        fs::write(root.join("c.jpg"), "different")?;
+        fs::write(root.join("d.tmp"), "same")?;
+        let before = list_files_in_directory(root);
+        let args =
+            ProgramArguments::from_iter(&["dupletti", "--quick", "--path", root.to_str().unwrap()]);
+        let groups = quick_scan(&args)?;
+        assert_eq!(groups.len(), 1);
+        let mut paths: Vec<&Path> = groups[0].files.iter().map(|f| f.path.as_path()).collect();
+        paths.sort();
+        assert_eq!(paths, [root.join("a.jpg"), root.join("sub").join("b.jpg")]);
+        // nothing was written next to the scanned files
+        assert_eq!(list_files_in_directory(root), before);
+        assert_eq!(effective_config(&args).database_path, Path::new(":memory:"));
+
+        for flag in ["--clean-unfound", "--videohash", "--resume"].iter() {
+            let args = ProgramArguments::from_iter_safe(&["dupletti", "--quick", flag]);
+            assert!(args.is_err(), "{} should be rejected", flag);
+        }
+        let args = ProgramArguments::from_iter(&["dupletti", "--quick"]);
+        assert!(quick_scan(&args).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan_jobs() -> Result<()> {
+        let dir = tempdir()?;
+        let root = dir.path().join("photos");
+        fs::create_dir(&root)?;
+        fs::write(root.join("a.jpg"), "same")?;
+        fs::write(root.join("b.jpg"), "same")?;
+        let gone = root.join("gone.jpg");
+        let db_path = dir.path().join("digests.sqlite");
+        let db = Database::new(&db_path, true)?;
+        db.insert_filedigest(&FileDigest::new(0, &gone.to_string_lossy(), vec![1], 1))?;
+        let db_mutex = Arc::new(Mutex::new(db));
+        // the command line cleans up and forces it, jobs do neither on their own
+        let args =
+            ProgramArguments::from_iter(&["dupletti", "--clean-unfound", "--force", "--no-backup"]);
+        let run = job_runner(
+            Arc::new(args),
+            db_mutex.clone(),
+            db_path,
+            BatchLimits::with_items(16),
+            None,
+        );
+        let scan = |clean_unfound| JobRequest::Scan {
+            path: root.clone(),
+            clean_unfound,
+        };
+        let paths = || db_mutex.lock().unwrap().get_all_paths().unwrap();
+
+        // a cancelled scan stops before cleaning up
+        let cancelled = Arc::new(jobs::JobContext::default());
+        cancelled.cancel();
+        let result = run(&scan(true), &cancelled);
+        assert!(result.unwrap_err().is::<jobs::Cancelled>());
+        assert!(paths().contains(&gone));
+
+        let context = Arc::new(jobs::JobContext::default());
+        run(&scan(false), &context)?;
+        assert_eq!(paths().len(), 3);
+        let progress = context.progress().unwrap();
+        // the cancelled scan may have hashed some files already
+        assert!(progress.total > 0 && progress.done == progress.total);
+        // removing one of three files is past the limits that only --force skips
+        assert!(run(&scan(true), &context).is_err());
+        assert!(paths().contains(&gone));
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_file_precedence() -> Result<()> {
+        let file = ConfigFile {
+            database: Some("photos=/data/photos.sqlite".into()),
+            paths: Some(vec!["/data/photos".into()]),
+            threads: Some(8),
+            port: Some(8080),
+            allow_preview: Some(true),
+            ..Default::default()
+        };
+        let parse = |argv: &[&str]| -> Result<ProgramArguments> {
+            let matches = ProgramArguments::clap().get_matches_from_safe(argv)?;
+            let mut args = ProgramArguments::from_clap(&matches);
+            args.apply_config_file(&file, &matches)?;
+            args.apply_command()?;
+            Ok(args)
+        };
+        // the command line wins over the file, which wins over the built-in defaults
+        let args = parse(&["dupletti", "--port", "9000", "--database", "a.sqlite"])?;
+        assert_eq!((args.port, args.threads), (9000, 8));
+        assert_eq!(args.databases[0].path, Path::new("a.sqlite"));
+        assert_eq!(args.path, [Path::new("/data/photos")]);
+        assert!(args.allow_preview);
+        assert_eq!(args.bind_address, "127.0.0.1");
+        let args = parse(&["dupletti", "report", "--threads", "2"])?;
+        assert_eq!((args.port, args.threads), (8080, 2));
+        assert_eq!(args.databases[0].label, "photos");
+        // commands other than scan don't pick up the scan paths
+        assert!(args.path.is_empty());
+        assert_eq!(parse(&["dupletti", "scan", "/x"])?.path, [Path::new("/x")]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_exit_code() {
+        assert_eq!(exit_code(&Ok(Outcome::Done)), 0);
+        assert_eq!(exit_code(&Ok(Outcome::Duplicates(0))), 0);
+        assert_eq!(
+            exit_code(&Ok(Outcome::Duplicates(2))),
+            EXIT_DUPLICATES_FOUND
+        );
+        assert_eq!(exit_code(&Ok(Outcome::Problems)), 1);
+        assert_eq!(exit_code(&Ok(Outcome::Stopped)), shutdown::EXIT_CODE);
+        assert_eq!(exit_code(&Err(anyhow!("Unable to lock DB"))), 1);
+        // distinct from the failures and from runs that were stopped
+        assert!(![0, 1, shutdown::EXIT_CODE].contains(&EXIT_DUPLICATES_FOUND));
+    }
+
+    #[test]
+    fn test_thread_counts() -> Result<()> {
+        let cpus = thread::available_parallelism()?.get();
+        assert_eq!((thread_count(3), thread_count(0)), (3, cpus));
+
+        let parse = |argv: &[&str]| ProgramArguments::from_iter_safe(argv);
+        let args = parse(&["dupletti"])?;
+        assert_eq!((args.hash_threads(), args.video_threads()), (4, 4));
+        let args = parse(&["dupletti", "--threads", "0", "--video-threads", "1"])?;
+        assert_eq!((args.hash_threads(), args.video_threads()), (cpus, 1));
+        let args = parse(&["dupletti", "scan", "/x", "--hash-threads", "0", "-t", "2"])?;
+        assert_eq!((args.hash_threads(), args.video_threads()), (cpus, 2));
+        Ok(())
+    }
+
+    #[test]
+    fn test_commands() -> Result<()> {
+        let parse = |argv: &[&str]| -> Result<ProgramArguments> {
+            let mut args = ProgramArguments::from_iter_safe(argv)?;
+            args.apply_command()?;
+            Ok(args)
+        };
+        let args = parse(&[
+            "dupletti",
+            "--database",
+            "a.sqlite",
+            "scan",
+            "/data",
+            "/backup",
+            "--videohash",
+            "--threads",
+            "2",
+        ])?;
+        assert_eq!(args.path, [Path::new("/data"), Path::new("/backup")]);
+        assert!(args.videohash && args.no_web);
+        assert_eq!(args.threads, 2);
+        assert_eq!(args.databases[0].path, Path::new("a.sqlite"));
+        assert!(!parse(&["dupletti", "scan", "/data", "--serve"])?.no_web);
+        assert!(parse(&["dupletti", "scan"]).is_err());
+
+        let args = parse(&["dupletti", "report", "--json", "--database", "b.sqlite"])?;
+        assert!(args.no_web && args.json && args.path.is_empty());
+        assert_eq!(args.databases[0].path, Path::new("b.sqlite"));
+        assert!(!parse(&["dupletti", "--no-web", "serve"])?.no_web);
+        assert!(parse(&["dupletti", "--path", "/data", "serve"]).is_err());
+        assert!(parse(&["dupletti", "serve"])?.serve_only());
+        assert!(!parse(&["dupletti", "scan", "/data", "--serve"])?.serve_only());
+        assert!(!parse(&["dupletti"])?.serve_only());
+        // serving an emptied database or scanning in serve mode makes no sense
+        assert!(parse(&["dupletti", "--reset-database", "serve"]).is_err());
+        assert!(parse(&["dupletti", "--resume", "serve"]).is_err());
+        assert!(parse(&["dupletti", "--reset-database", "report"]).is_ok());
+
+        let args = parse(&["dupletti", "clean", "/data/old"])?;
+        assert_eq!(args.purge_missing, Some(Some("/data/old".to_string())));
+        assert_eq!(parse(&["dupletti", "clean"])?.purge_missing, Some(None));
+        assert!(parse(&["dupletti", "stats", "--json"])?.json);
+
+        let check = |argv: &[&str]| -> Result<()> {
+            let matches = ProgramArguments::clap().get_matches_from_safe(argv)?;
+            ProgramArguments::from_clap(&matches).check_dependencies(&matches)
+        };
+        assert!(check(&["dupletti", "--auto-delete", "--keep", "shortest-path"]).is_ok());
+        // deleting by the default policy for reclaimable space would be a surprise
+        assert!(check(&["dupletti", "--auto-delete"]).is_err());
+        assert!(check(&["dupletti", "--auto-delete", "--keep", "newest", "--dry-run"]).is_ok());
+        assert!(check(&["dupletti", "-c", "--dry-run"]).is_ok());
+        assert!(check(&["dupletti", "--dry-run"]).is_err());
+        assert!(check(&["dupletti", "--delete-log", "a.log"]).is_err());
+        assert!(check(&["dupletti", "--dedupe", "symlink", "--delete-log", "a.log"]).is_ok());
+        assert!(check(&["dupletti", "--purge-missing", "--force"]).is_ok());
+        assert!(check(&["dupletti", "--force"]).is_err());
+        assert!(parse(&["dupletti", "--max-group-size", "0"]).is_err());
+        assert!(parse(&["dupletti", "--max-group-size", "1001"]).is_err());
+
+        let args = parse(&["dupletti", "--hardlink-duplicates"])?;
+        assert_eq!(args.dedupe_mode(), Some(DedupeMode::Hardlink));
+        assert!(parse(&["dupletti", "--hardlink-duplicates", "--dedupe", "symlink"]).is_err());
+        assert!(parse(&["dupletti", "--path", "/data", "stats"]).is_err());
+
+        // the flags work as before
+        let args = parse(&["dupletti", "--path", "/data", "--no-web"])?;
+        assert_eq!((args.path.len(), args.no_web), (1, true));
+        Ok(())
+    }
+
+    #[test]
+    fn test_effective_config() -> Result<()> {
+        let args = ProgramArguments::from_iter(&[
+            "dupletti",
+            "--port",
+            "8080",
+            "--scan-order",
+            "name",
+            "--skip-extensions",
+            "bak",
+            "--allow-reveal",
+            "--bind-address",
+            "0.0.0.0",
+        ]);
+        let config = effective_config(&args);
+        assert_eq!(config.web.port, 8080);
+        assert_eq!(config.scan.scan_order, ScanOrder::Name);
+        assert!(config.scan.paths.is_empty());
+        // not effective on a public address
+        assert!(!config.web.allow_reveal);
+
+        let json: serde_json::Value = serde_json::to_value(&config)?;
+        assert_eq!(json["web"]["port"], 8080);
+        assert_eq!(json["scan"]["scan_order"], "name");
+        assert_eq!(json["web"]["keep"], "largest");
+        assert_eq!(json["database_path"], DATABASE_PATH);
+        let skipped = json["scan"]["skip_extensions"].as_array().unwrap();
+        assert!(skipped.contains(&"bak".into()) && skipped.contains(&"tmp".into()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_serve_several_databases() -> Result<()> {
+        let dir = tempdir()?;
+        let photos = dir.path().join("photos.sqlite");
+        let backup = dir.path().join("other.sqlite");
+        let args = ProgramArguments::from_iter(&[
+            "dupletti",
+            "--database",
+            photos.to_str().unwrap(),
+            "--database",
+            &format!("backup={}", backup.to_str().unwrap()),
+        ]);
+        let config = effective_config(&args);
+        let labels: Vec<&str> = config
+            .web
+            .databases
+            .iter()
+            .map(|d| d.label.as_str())
+            .collect();
+        assert_eq!(labels, ["photos", "backup"]);
+        assert_eq!(config.database_path, photos);
+
+        let mut sites = Vec::new();
+        for (i, spec) in config.web.databases.iter().enumerate() {
+            let db = Database::new(&spec.path, true)?;
+            let digest = vec![i as u8; 8];
+            db.insert_filedigest(&FileDigest::new(1, &format!("/{}/a", i), digest.clone(), 1))?;
+            db.insert_filedigest(&FileDigest::new(2, &format!("/{}/b", i), digest, 1))?;
+            let base = format!("/{}/", spec.label);
+            sites.push(interface::Site::new(
+                spec.label.clone(),
+                Arc::new(DatabasePool::with_writer(&spec.path, db)),
+                &base,
+                false,
+                5.0,
+            )?);
+        }
+        let settings = interface::WebSettings {
+            allow_preview: false,
+            allow_reveal: false,
+            max_body_size: 1024,
+            keep_policy: args.keep,
+            max_group_size: 50,
+            config: config.clone(),
+            scan_trigger: None,
+            jobs: None,
+            videohash_threshold: None,
+        };
+        let tera = interface::load_templates("/")?;
+        let get = |url: &str| -> Result<(u16, String)> {
+            let request = rouille::Request::fake_http("GET", url, vec![], vec![]);
+            let response = interface::route_sites(&request, &sites, &settings, &tera)?;
+            let mut body = String::new();
+            response
+                .data
+                .into_reader_and_size()
+                .0
+                .read_to_string(&mut body)?;
+            Ok((response.status_code, body))
+        };
+
+        let (_, photos_body) = get("/photos/api/duplicates")?;
+        assert!(photos_body.contains("/0/a") && !photos_body.contains("/1/a"));
+        let (_, backup_body) = get("/backup/api/duplicates")?;
+        assert!(backup_body.contains("/1/a") && !backup_body.contains("/0/a"));
+        let (status, page) = get("/backup/")?;
+        assert_eq!(status, 200);
+        assert!(page.contains(r#"<base href="/backup/">"#));
+        let (_, index) = get("/")?;
+        assert!(index.contains(r#"href="photos/""#) && index.contains(r#"href="backup/""#));
+        let request = rouille::Request::fake_http("GET", "/missing/", vec![], vec![]);
+        let err = interface::route_sites(&request, &sites, &settings, &tera).unwrap_err();
+        assert_eq!(err.downcast_ref::<ApiError>().unwrap().status, 404);
+
+        let args = ProgramArguments::from_iter(&[
+            "dupletti",
+            "--database",
+            "a/digests.sqlite",
+            "--database",
+            "b/digests.sqlite",
+        ]);
+        assert!(check_database_labels(&database_specs(&args)).is_err());
+        assert!(ProgramArguments::from_iter_safe(&["dupletti", "--database", "a b=x"]).is_err());
         Ok(())
     }
 
@@ -300,4 +4292,54 @@ mod tests {
         assert_eq!(filelist, all_files);
         Ok(())
     }
+
+    #[test]
+    fn test_list_files_with_glob_characters() -> Result<()> {
+        let dir = tempdir()?;
+        // the root is never read as a pattern
+        let root = dir.path().join("a[1]");
+        fs::create_dir_all(root.join("[live]"))?;
+        fs::create_dir(dir.path().join("a1"))?;
+        fs::write(dir.path().join("a1").join("x.mp3"), "x")?;
+        let filelist: HashSet<_> = [root.join("*.mp3"), root.join("[live]").join("?.mp3")]
+            .iter()
+            .cloned()
+            .collect();
+        for path in &filelist {
+            fs::write(path, "song")?;
+        }
+        assert_eq!(list_files_in_directory(&root), filelist);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_non_utf8_paths_are_skipped() -> Result<()> {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = tempdir()?;
+        let invalid = dir.path().join(OsStr::from_bytes(b"caf\xe9.txt"));
+        fs::write(&invalid, "latin-1")?;
+        fs::write(dir.path().join("café.txt"), "utf-8")?;
+        assert_eq!(
+            list_files_in_directory(dir.path()),
+            [dir.path().join("café.txt")].iter().cloned().collect()
+        );
+
+        let db_mutex = Mutex::new(Database::new("test_non_utf8_paths.sqlite", true)?);
+        update_database(
+            &db_mutex,
+            &[dir.path()],
+            &clean_scan(),
+            &Progress::default(),
+        )?;
+        let db = db_mutex.lock().unwrap();
+        assert_eq!(db.get_all_paths()?, [dir.path().join("café.txt")]);
+        // nor can it be stored any other way
+        let mut file = FileDigest::new(0, "", vec![1; 8], 7);
+        file.path = invalid;
+        assert!(db.insert_filedigest(&file).is_err());
+        Ok(())
+    }
 }
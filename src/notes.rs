@@ -0,0 +1,110 @@
+use anyhow::Result;
+use rusqlite::{params, OptionalExtension};
+use serde::Serialize;
+use std::collections::HashMap;
+
+use crate::database::Database;
+use crate::tombstones::now;
+
+/// A note left on a duplicate group. Notes are keyed by the group id, which only depends
+/// on the content of the files, so they survive rescans.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct GroupNote {
+    pub note: String,
+    /// unix seconds
+    pub updated_at: i64,
+}
+
+impl Database {
+    /// Sets the note of a group. Empty notes remove it.
+    pub fn set_group_note(&self, group_id: &str, note: &str) -> Result<()> {
+        if note.trim().is_empty() {
+            self.db.execute(
+                "DELETE FROM group_notes WHERE group_id = ?1",
+                params![group_id],
+            )?;
+        } else {
+            self.db.execute(
+                "INSERT OR REPLACE INTO group_notes (group_id, note, updated_at) VALUES (?1, ?2, ?3)",
+                params![group_id, note, now()?],
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn get_group_note(&self, group_id: &str) -> Result<Option<GroupNote>> {
+        Ok(self
+            .db
+            .query_row(
+                "SELECT note, updated_at FROM group_notes WHERE group_id = ?1",
+                params![group_id],
+                |row| {
+                    Ok(GroupNote {
+                        note: row.get(0)?,
+                        updated_at: row.get(1)?,
+                    })
+                },
+            )
+            .optional()?)
+    }
+
+    pub fn get_group_notes(&self) -> Result<HashMap<String, GroupNote>> {
+        let mut stmt = self
+            .db
+            .prepare("SELECT group_id, note, updated_at FROM group_notes")?;
+        let notes: Result<HashMap<String, GroupNote>, _> = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get(0)?,
+                    GroupNote {
+                        note: row.get(1)?,
+                        updated_at: row.get(2)?,
+                    },
+                ))
+            })?
+            .collect();
+        Ok(notes?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::FileDigest;
+    use crate::similarities::{self, get_list_of_similar_files};
+
+    #[test]
+    fn test_group_notes() -> Result<()> {
+        let db = Database::new("test_group_notes.sqlite", true)?;
+        for (id, digest) in [(1, 1), (2, 1), (3, 2), (4, 2)].iter() {
+            db.insert_filedigest(&FileDigest::new(
+                *id,
+                &format!("/tmp/nonexistent/{}", id),
+                vec![*digest; 8],
+                1,
+            ))?;
+        }
+        let noted = similarities::group_id(&[2; 8]);
+        db.set_group_note(&noted, "left copy is the master")?;
+
+        let notes: Vec<(String, Option<String>)> = get_list_of_similar_files(&db)?
+            .into_iter()
+            .map(|g| (g.group_id, g.note.map(|n| n.note)))
+            .collect();
+        assert_eq!(notes.len(), 2);
+        for (group_id, note) in notes {
+            if group_id == noted {
+                assert_eq!(note.as_deref(), Some("left copy is the master"));
+            } else {
+                assert_eq!(note, None);
+            }
+        }
+        let group = similarities::get_group(&db, &noted)?.unwrap();
+        assert_eq!(group.note.unwrap().note, "left copy is the master");
+
+        db.set_group_note(&noted, " ")?;
+        assert_eq!(db.get_group_note(&noted)?, None);
+        assert!(db.get_group_notes()?.is_empty());
+        Ok(())
+    }
+}
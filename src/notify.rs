@@ -0,0 +1,324 @@
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::database::Database;
+use crate::dedupe::DedupeSummary;
+use crate::scanstats::ScanStats;
+use crate::similarities;
+
+/// How long a webhook may take to answer before it is retried, and then given up on.
+pub const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Webhooks are tried this often, so a single network hiccup doesn't lose the summary.
+const WEBHOOK_ATTEMPTS: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RunKind {
+    Scan,
+    Dedupe,
+}
+
+/// What is sent when a scan or dedupe run completes.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RunSummary {
+    pub run: RunKind,
+    /// Files that were added or whose content changed
+    pub files_hashed: usize,
+    /// Files that couldn't be hashed, or duplicates that couldn't be replaced
+    pub errors: usize,
+    pub duplicate_groups: usize,
+    /// Groups that didn't exist before the run
+    pub new_duplicate_groups: usize,
+    /// Bytes taken by all copies but one
+    pub reclaimable_bytes: u64,
+    /// Bytes freed by replacing duplicates with links
+    pub freed_bytes: u64,
+}
+
+/// Duplicate groups at one point in time, to tell which groups a run found.
+#[derive(Debug, Default)]
+pub struct DuplicateTotals {
+    group_ids: HashSet<String>,
+    reclaimable: u64,
+}
+
+impl DuplicateTotals {
+    pub fn of(db: &Database) -> Result<DuplicateTotals> {
+        let (group_ids, reclaimable) = similarities::duplicate_totals(db)?;
+        Ok(DuplicateTotals {
+            group_ids,
+            reclaimable,
+        })
+    }
+
+    /// Like `of`, but never fails, since notifying must not stop a scan.
+    pub fn of_locked(db_mutex: &Mutex<Database>) -> DuplicateTotals {
+        let totals = match db_mutex.lock() {
+            Ok(db) => DuplicateTotals::of(&db),
+            Err(_) => Err(anyhow!("Unable to lock DB")),
+        };
+        totals.unwrap_or_else(|e| {
+            log::warn!("Unable to count duplicates for the notification: {}", e);
+            DuplicateTotals::default()
+        })
+    }
+}
+
+impl RunSummary {
+    pub fn of_scan(
+        stats: &ScanStats,
+        before: &DuplicateTotals,
+        after: &DuplicateTotals,
+    ) -> RunSummary {
+        RunSummary {
+            run: RunKind::Scan,
+            files_hashed: stats.files_inserted + stats.files_updated,
+            errors: stats.hash_errors,
+            duplicate_groups: after.group_ids.len(),
+            new_duplicate_groups: after.group_ids.difference(&before.group_ids).count(),
+            reclaimable_bytes: after.reclaimable,
+            freed_bytes: 0,
+        }
+    }
+
+    pub fn of_dedupe(summary: &DedupeSummary, after: &DuplicateTotals) -> RunSummary {
+        RunSummary {
+            run: RunKind::Dedupe,
+            files_hashed: 0,
+            errors: summary.skipped,
+            duplicate_groups: after.group_ids.len(),
+            new_duplicate_groups: 0,
+            reclaimable_bytes: after.reclaimable,
+            freed_bytes: summary.reclaimed,
+        }
+    }
+
+    fn title(&self) -> &'static str {
+        match self.run {
+            RunKind::Scan => "Dupletti scan finished",
+            RunKind::Dedupe => "Dupletti dedupe finished",
+        }
+    }
+}
+
+impl fmt::Display for RunSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.run {
+            RunKind::Scan => write!(
+                f,
+                "{} files hashed, {} errors, {} new duplicate groups. ",
+                self.files_hashed, self.errors, self.new_duplicate_groups
+            )?,
+            RunKind::Dedupe => write!(
+                f,
+                "{} bytes freed, {} duplicates skipped. ",
+                self.freed_bytes, self.errors
+            )?,
+        }
+        write!(
+            f,
+            "{} duplicate groups, {} bytes reclaimable",
+            self.duplicate_groups, self.reclaimable_bytes
+        )
+    }
+}
+
+/// Sends run summaries to the desktop (--notify) and to a webhook (--notify-webhook).
+#[derive(Debug, Clone)]
+pub struct Notifier {
+    pub desktop: bool,
+    pub webhook: Option<String>,
+    pub timeout: Duration,
+}
+
+impl Notifier {
+    /// None if neither kind of notification is asked for.
+    pub fn new(desktop: bool, webhook: Option<String>) -> Option<Notifier> {
+        if !desktop && webhook.is_none() {
+            return None;
+        }
+        Some(Notifier {
+            desktop,
+            webhook,
+            timeout: WEBHOOK_TIMEOUT,
+        })
+    }
+
+    /// Sends the summary everywhere it is asked for. Failures are only logged.
+    pub fn send(&self, summary: &RunSummary) {
+        if self.desktop {
+            if let Err(e) = show_desktop_notification(summary.title(), &summary.to_string()) {
+                log::warn!("Unable to show a desktop notification: {}", e);
+            }
+        }
+        if let Some(url) = &self.webhook {
+            if let Err(e) = post_json(url, summary, self.timeout, WEBHOOK_ATTEMPTS) {
+                log::warn!("Unable to notify {}: {}", url, e);
+            }
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn show_desktop_notification(title: &str, body: &str) -> Result<()> {
+    notify_rust::Notification::new()
+        .summary(title)
+        .body(body)
+        .show()?;
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn show_desktop_notification(title: &str, _body: &str) -> Result<()> {
+    log::debug!("Desktop notifications aren't supported here: {}", title);
+    Ok(())
+}
+
+/// POSTs `payload` as JSON, trying up to `attempts` times. Each attempt gives up after
+/// `timeout`.
+fn post_json<T: Serialize>(
+    url: &str,
+    payload: &T,
+    timeout: Duration,
+    attempts: usize,
+) -> Result<()> {
+    let body = serde_json::to_string(payload)?;
+    let agent = ureq::AgentBuilder::new().timeout(timeout).build();
+    let mut result = Err(anyhow!("No attempt made"));
+    for attempt in 1..=attempts {
+        result = agent
+            .post(url)
+            .set("Content-Type", "application/json")
+            .send_string(&body)
+            .map(|_| ())
+            .map_err(anyhow::Error::from);
+        match &result {
+            Ok(()) => break,
+            Err(e) => log::debug!("Attempt {} to notify {} failed: {}", attempt, url, e),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Instant;
+
+    fn summary() -> RunSummary {
+        RunSummary {
+            run: RunKind::Scan,
+            files_hashed: 120,
+            errors: 2,
+            duplicate_groups: 34,
+            new_duplicate_groups: 5,
+            reclaimable_bytes: 1_000_000,
+            freed_bytes: 0,
+        }
+    }
+
+    /// Answers each connection with the next of `statuses`, None leaves it hanging.
+    /// Returns the address and the received bodies.
+    fn test_server(statuses: Vec<Option<u16>>) -> (String, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let mut bodies = Vec::new();
+            for status in statuses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let line = line.trim_end().to_ascii_lowercase();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some(length) = line.strip_prefix("content-length:") {
+                        content_length = length.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+                bodies.push(String::from_utf8(body).unwrap());
+                match status {
+                    Some(status) => write!(
+                        &stream,
+                        "HTTP/1.1 {} X\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                        status
+                    )
+                    .unwrap(),
+                    None => thread::sleep(Duration::from_millis(500)),
+                }
+            }
+            bodies
+        });
+        (url, handle)
+    }
+
+    #[test]
+    fn test_payload() {
+        assert_eq!(
+            serde_json::to_value(summary()).unwrap(),
+            serde_json::json!({
+                "run": "scan",
+                "files_hashed": 120,
+                "errors": 2,
+                "duplicate_groups": 34,
+                "new_duplicate_groups": 5,
+                "reclaimable_bytes": 1_000_000,
+                "freed_bytes": 0,
+            })
+        );
+        assert_eq!(
+            summary().to_string(),
+            "120 files hashed, 2 errors, 5 new duplicate groups. 34 duplicate groups, 1000000 bytes reclaimable"
+        );
+    }
+
+    #[test]
+    fn test_webhook_is_retried_once() -> Result<()> {
+        let (url, server) = test_server(vec![Some(500), Some(200)]);
+        post_json(&url, &summary(), Duration::from_secs(5), WEBHOOK_ATTEMPTS)?;
+        let bodies = server.join().unwrap();
+        assert_eq!(bodies.len(), 2);
+        assert_eq!(bodies[1], serde_json::to_string(&summary())?);
+
+        let (url, server) = test_server(vec![Some(500), Some(500)]);
+        assert!(post_json(&url, &summary(), Duration::from_secs(5), WEBHOOK_ATTEMPTS).is_err());
+        assert_eq!(server.join().unwrap().len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_webhook_timeout() {
+        let (url, server) = test_server(vec![None, None]);
+        let start = Instant::now();
+        let result = post_json(
+            &url,
+            &summary(),
+            Duration::from_millis(100),
+            WEBHOOK_ATTEMPTS,
+        );
+        assert!(result.is_err());
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(server.join().unwrap().len(), 2);
+
+        // failures are only logged
+        let notifier = Notifier {
+            desktop: false,
+            webhook: Some("http://127.0.0.1:1/hook".into()),
+            timeout: Duration::from_millis(100),
+        };
+        notifier.send(&summary());
+    }
+}
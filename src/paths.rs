@@ -0,0 +1,231 @@
+use anyhow::{anyhow, Result};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// How paths are compared. On case-insensitive filesystems "Photo.JPG" and "photo.jpg"
+/// are the same file, so comparisons have to ignore the case, while the original
+/// casing is kept for display and filesystem operations.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PathCase {
+    Sensitive,
+    Insensitive,
+}
+
+impl PathCase {
+    /// Uses `Insensitive` if forced, otherwise probes the filesystem of `root`.
+    pub fn for_root(root: &Path, force_insensitive: bool) -> PathCase {
+        if force_insensitive || probe_case_insensitive(root) {
+            PathCase::Insensitive
+        } else {
+            PathCase::Sensitive
+        }
+    }
+
+    /// The key under which a path is compared.
+    pub fn key(&self, path: &Path) -> PathBuf {
+        match self {
+            PathCase::Sensitive => path.to_path_buf(),
+            PathCase::Insensitive => PathBuf::from(path.to_string_lossy().to_lowercase()),
+        }
+    }
+
+    pub fn starts_with(&self, path: &Path, prefix: &Path) -> bool {
+        self.key(path).starts_with(self.key(prefix))
+    }
+}
+
+/// The lower case extension of a file. Dotfiles without another dot, like ".DS_Store",
+/// count as an extension of their own.
+pub fn extension_of(path: &Path) -> Option<String> {
+    let ext = match path.extension() {
+        Some(ext) => ext.to_string_lossy(),
+        None => path.file_name()?.to_str()?.strip_prefix('.')?.into(),
+    };
+    Some(ext.to_lowercase())
+}
+
+/// Whether the extension of `path` is one of `extensions`, which must be lower case.
+pub fn has_extension<S: AsRef<str>>(path: &Path, extensions: &[S]) -> bool {
+    match extension_of(path) {
+        Some(ext) => extensions.iter().any(|e| e.as_ref() == ext),
+        None => false,
+    }
+}
+
+/// Whether a directory entry called `name` is hidden by the Unix convention of a leading
+/// dot, like .git or .cache. Only entries are checked, so the "." of a relative path
+/// like ./photos never counts.
+pub fn is_hidden(name: &OsStr) -> bool {
+    name.as_encoded_bytes().first() == Some(&b'.')
+}
+
+/// The device a path is on, see --one-file-system. None where it can't be told, on
+/// Windows or for paths that can't be read.
+pub fn device_id(path: &Path) -> Option<u64> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        fs::metadata(path).ok().map(|m| m.dev())
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        None
+    }
+}
+
+/// Whether a directory on `device` is on another file system than its root on
+/// `root_device`. Bind mounts of the same device are on the same one. Directories whose
+/// device can't be told are entered.
+pub fn crosses_device(root_device: Option<u64>, device: Option<u64>) -> bool {
+    match (root_device, device) {
+        (Some(root), Some(device)) => root != device,
+        _ => false,
+    }
+}
+
+/// Glob patterns of files that are left out of scans, see --exclude-path. Patterns are
+/// matched against the whole path, and `*` matches across directories too, so
+/// `**/node_modules/**` leaves out everything below any node_modules directory and
+/// `*.iso` all ISO images.
+#[derive(Debug, Clone)]
+pub struct ExcludePatterns {
+    set: GlobSet,
+}
+
+impl ExcludePatterns {
+    pub fn new<S: AsRef<str>>(patterns: &[S]) -> Result<ExcludePatterns> {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in patterns {
+            let pattern = pattern.as_ref();
+            let glob = Glob::new(pattern)
+                .map_err(|e| anyhow!("Invalid --exclude-path pattern {}: {}", pattern, e))?;
+            builder.add(glob);
+        }
+        Ok(ExcludePatterns {
+            set: builder.build()?,
+        })
+    }
+
+    /// Excludes nothing.
+    #[cfg(test)]
+    pub fn none() -> ExcludePatterns {
+        ExcludePatterns {
+            set: GlobSet::empty(),
+        }
+    }
+
+    pub fn is_excluded(&self, path: &Path) -> bool {
+        !self.set.is_empty() && self.set.is_match(path)
+    }
+}
+
+/// Checks whether the filesystem `root` lives on ignores the case of file names, by
+/// creating a file with an upper case name and looking it up in lower case.
+pub fn probe_case_insensitive(root: &Path) -> bool {
+    let probe = tempfile::Builder::new()
+        .prefix(".DUPLETTI-CASE-PROBE")
+        .tempfile_in(root);
+    match probe {
+        Ok(file) => {
+            let name = file
+                .path()
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .to_lowercase();
+            fs::metadata(root.join(name)).is_ok()
+        }
+        Err(e) => {
+            log::debug!("Unable to probe case sensitivity of {:?}: {}", root, e);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_path_case_key() {
+        let upper = Path::new("/Media/ÄRGER/Photo.JPG");
+        let lower = Path::new("/media/ärger/photo.jpg");
+        assert_ne!(
+            PathCase::Sensitive.key(upper),
+            PathCase::Sensitive.key(lower)
+        );
+        assert_eq!(
+            PathCase::Insensitive.key(upper),
+            PathCase::Insensitive.key(lower)
+        );
+        assert!(PathCase::Insensitive.starts_with(upper, Path::new("/media/ärger")));
+        assert!(!PathCase::Sensitive.starts_with(upper, Path::new("/media/ärger")));
+        assert!(!PathCase::Insensitive.starts_with(upper, Path::new("/media/ärg")));
+    }
+
+    #[test]
+    fn test_crosses_device() -> Result<()> {
+        assert!(crosses_device(Some(1), Some(2)));
+        assert!(!crosses_device(Some(1), Some(1)));
+        // the root or the directory couldn't be read, or --one-file-system is off
+        assert!(!crosses_device(None, Some(2)));
+        assert!(!crosses_device(Some(1), None));
+
+        let dir = tempdir()?;
+        fs::create_dir(dir.path().join("sub"))?;
+        let root = device_id(dir.path());
+        assert_eq!(root.is_some(), cfg!(unix));
+        assert!(!crosses_device(root, device_id(&dir.path().join("sub"))));
+        assert_eq!(device_id(&dir.path().join("missing")), None);
+        #[cfg(target_os = "linux")]
+        assert!(crosses_device(
+            device_id(Path::new("/")),
+            device_id(Path::new("/proc"))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_has_extension() {
+        let videos = ["mp4", "mkv"];
+        assert!(has_extension(Path::new("/tmp/a.MP4"), &videos));
+        assert!(has_extension(Path::new("/tmp/b.tar.mkv"), &videos));
+        assert!(!has_extension(Path::new("/tmp/mp4"), &videos));
+        assert!(!has_extension(Path::new("/tmp/c.mp4.part"), &videos));
+        assert!(has_extension(Path::new("/tmp/.DS_Store"), &["ds_store"]));
+        assert_eq!(
+            extension_of(Path::new("/tmp/.hidden.TXT")).as_deref(),
+            Some("txt")
+        );
+    }
+
+    #[test]
+    fn test_exclude_patterns() -> Result<()> {
+        let exclude = ExcludePatterns::new(&["**/.thumbnails/**", "**/node_modules/**", "*.iso"])?;
+        assert!(exclude.is_excluded(Path::new("/media/.thumbnails/a.jpg")));
+        assert!(exclude.is_excluded(Path::new("/media/2020/.thumbnails/large/a.jpg")));
+        assert!(exclude.is_excluded(Path::new("/src/app/node_modules/x/index.js")));
+        assert!(exclude.is_excluded(Path::new("/media/backup/disk.iso")));
+        assert!(!exclude.is_excluded(Path::new("/media/thumbnails/a.jpg")));
+        assert!(!exclude.is_excluded(Path::new("/media/disk.iso.txt")));
+        assert!(!ExcludePatterns::none().is_excluded(Path::new("/media/disk.iso")));
+        assert!(ExcludePatterns::new(&["[a-"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_probe_case_insensitive() -> std::io::Result<()> {
+        // the tests run on case-sensitive filesystems
+        let dir = tempdir()?;
+        assert!(!probe_case_insensitive(dir.path()));
+        assert_eq!(PathCase::for_root(dir.path(), false), PathCase::Sensitive);
+        assert_eq!(PathCase::for_root(dir.path(), true), PathCase::Insensitive);
+        // unwritable or missing roots are treated as case-sensitive
+        assert!(!probe_case_insensitive(&dir.path().join("missing")));
+        Ok(())
+    }
+}
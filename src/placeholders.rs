@@ -0,0 +1,178 @@
+use anyhow::Result;
+use rusqlite::params;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::database::Database;
+
+/// Files of cloud sync clients (OneDrive, Dropbox, iCloud) and tiered NAS storage that
+/// only exist as placeholders are downloaded in full when they are read. Hashing them
+/// would pull the whole cloud folder, so they are skipped unless --hydrate-placeholders
+/// is given.
+pub trait PlaceholderDetection {
+    fn is_placeholder(&self, metadata: &fs::Metadata) -> bool;
+}
+
+/// Smaller files can be stored inside the inode without any data blocks.
+const MIN_PLACEHOLDER_SIZE: u64 = 4096;
+
+/// Unix has no placeholder attribute, but placeholders don't have any data blocks
+/// allocated, although they report their full size.
+#[cfg(unix)]
+pub struct UnixBlocks;
+
+#[cfg(unix)]
+impl PlaceholderDetection for UnixBlocks {
+    fn is_placeholder(&self, metadata: &fs::Metadata) -> bool {
+        use std::os::unix::fs::MetadataExt;
+        has_no_data_blocks(metadata.len(), metadata.blocks())
+    }
+}
+
+fn has_no_data_blocks(len: u64, blocks: u64) -> bool {
+    len >= MIN_PLACEHOLDER_SIZE && blocks == 0
+}
+
+/// Windows marks placeholders with attributes, set by the cloud files API.
+#[cfg(windows)]
+pub struct WindowsAttributes;
+
+#[cfg(windows)]
+impl PlaceholderDetection for WindowsAttributes {
+    fn is_placeholder(&self, metadata: &fs::Metadata) -> bool {
+        use std::os::windows::fs::MetadataExt;
+        const FILE_ATTRIBUTE_OFFLINE: u32 = 0x1000;
+        const FILE_ATTRIBUTE_RECALL_ON_OPEN: u32 = 0x40000;
+        const FILE_ATTRIBUTE_RECALL_ON_DATA_ACCESS: u32 = 0x400000;
+        metadata.file_attributes()
+            & (FILE_ATTRIBUTE_OFFLINE
+                | FILE_ATTRIBUTE_RECALL_ON_OPEN
+                | FILE_ATTRIBUTE_RECALL_ON_DATA_ACCESS)
+            != 0
+    }
+}
+
+/// Platforms without a way to tell never report placeholders.
+#[cfg(not(any(unix, windows)))]
+pub struct NoDetection;
+
+#[cfg(not(any(unix, windows)))]
+impl PlaceholderDetection for NoDetection {
+    fn is_placeholder(&self, _: &fs::Metadata) -> bool {
+        false
+    }
+}
+
+pub fn platform_detection() -> &'static dyn PlaceholderDetection {
+    #[cfg(unix)]
+    return &UnixBlocks;
+    #[cfg(windows)]
+    return &WindowsAttributes;
+    #[cfg(not(any(unix, windows)))]
+    return &NoDetection;
+}
+
+/// Whether reading the file would download it first. Files that can't be inspected are
+/// left to the hashing, which reports them as errors.
+pub fn is_placeholder(path: &Path) -> bool {
+    fs::metadata(path).is_ok_and(|m| platform_detection().is_placeholder(&m))
+}
+
+/// A file that was listed, but left out of hashing.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SkippedFile {
+    pub path: PathBuf,
+    pub reason: String,
+    /// Unix seconds of the last scan that skipped the file
+    pub skipped_at: i64,
+}
+
+pub const PLACEHOLDER_REASON: &str = "cloud placeholder";
+
+impl Database {
+    pub fn record_skipped_files(&mut self, files: &HashSet<PathBuf>, reason: &str) -> Result<()> {
+        let skipped_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        let tx = self.db.transaction()?;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT OR REPLACE INTO skipped_files (path, reason, skipped_at) VALUES (?1, ?2, ?3)",
+            )?;
+            for f in files {
+                stmt.execute(params![f.to_string_lossy(), reason, skipped_at])?;
+            }
+        }
+        Ok(tx.commit()?)
+    }
+
+    /// Files that are hashed after all, e.g. because they were downloaded in the
+    /// meantime, are no longer skipped.
+    pub fn forget_skipped_files(&mut self, files: &HashSet<PathBuf>) -> Result<()> {
+        let tx = self.db.transaction()?;
+        {
+            let mut stmt = tx.prepare("DELETE FROM skipped_files WHERE path = ?1")?;
+            for f in files {
+                stmt.execute(params![f.to_string_lossy()])?;
+            }
+        }
+        Ok(tx.commit()?)
+    }
+
+    pub fn get_skipped_files(&self) -> Result<Vec<SkippedFile>> {
+        let mut stmt = self
+            .db
+            .prepare("SELECT path, reason, skipped_at FROM skipped_files ORDER BY path")?;
+        let files: Result<Vec<SkippedFile>, _> = stmt
+            .query_map([], |row| {
+                Ok(SkippedFile {
+                    path: PathBuf::from(row.get::<_, String>(0)?),
+                    reason: row.get(1)?,
+                    skipped_at: row.get(2)?,
+                })
+            })?
+            .collect();
+        Ok(files?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unix_blocks_heuristic() {
+        assert!(has_no_data_blocks(1 << 30, 0));
+        assert!(!has_no_data_blocks(1 << 30, 8));
+        // empty files and files stored inline have no blocks either
+        assert!(!has_no_data_blocks(0, 0));
+        assert!(!has_no_data_blocks(100, 0));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_written_files_are_no_placeholders() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("a");
+        fs::write(&path, vec![1; 1 << 16])?;
+        assert!(!is_placeholder(&path));
+        assert!(!is_placeholder(&dir.path().join("missing")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_skipped_files() -> Result<()> {
+        let mut db = Database::new("test_skipped_files.sqlite", true)?;
+        let files: HashSet<PathBuf> = ["/cloud/a", "/cloud/b"].iter().map(PathBuf::from).collect();
+        db.record_skipped_files(&files, PLACEHOLDER_REASON)?;
+        db.record_skipped_files(&files, PLACEHOLDER_REASON)?;
+        let skipped = db.get_skipped_files()?;
+        assert_eq!(skipped.len(), 2);
+        assert_eq!(skipped[0].path, Path::new("/cloud/a"));
+        assert_eq!(skipped[0].reason, PLACEHOLDER_REASON);
+        db.forget_skipped_files(&[PathBuf::from("/cloud/a")].iter().cloned().collect())?;
+        assert_eq!(db.get_skipped_files()?[0].path, Path::new("/cloud/b"));
+        Ok(())
+    }
+}
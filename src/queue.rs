@@ -0,0 +1,162 @@
+use anyhow::{anyhow, Result};
+use rusqlite::params;
+use serde::Serialize;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::database::{Database, FileDigest};
+use crate::tombstones::now;
+
+/// What is done with a queued file once the queue is executed.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QueuedAction {
+    Delete,
+}
+
+impl QueuedAction {
+    fn name(self) -> &'static str {
+        match self {
+            QueuedAction::Delete => "delete",
+        }
+    }
+}
+
+impl FromStr for QueuedAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<QueuedAction> {
+        match s {
+            "delete" => Ok(QueuedAction::Delete),
+            _ => Err(anyhow!("Unknown queued action {}, use delete", s)),
+        }
+    }
+}
+
+/// A file marked for an action during review.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct QueuedFile {
+    pub id: i64,
+    pub path: PathBuf,
+    pub size: u64,
+    pub action: QueuedAction,
+    /// unix seconds
+    pub requested_at: i64,
+}
+
+/// Number and size of the queued files.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct QueueTotals {
+    pub files: usize,
+    pub bytes: u64,
+}
+
+/// What executing the queue did with a file.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Outcome {
+    Deleted,
+    /// Only with a dry run
+    WouldDelete,
+    /// Already gone, it is removed from the DB
+    DoesNotExist,
+    Protected,
+    /// No other copy of the content exists on disk
+    LastCopy,
+    Failed,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FileOutcome {
+    pub id: i64,
+    pub path: PathBuf,
+    pub outcome: Outcome,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct QueueReport {
+    pub dry_run: bool,
+    pub files: Vec<FileOutcome>,
+    /// Bytes freed, or that would be freed with a dry run
+    pub freed_bytes: u64,
+}
+
+impl Database {
+    /// Returns false if the file was queued already.
+    pub fn queue_action(&self, file_id: i64, action: QueuedAction) -> Result<bool> {
+        let num_inserted = self.db.execute(
+            "INSERT OR IGNORE INTO pending_actions (id, action, requested_at) VALUES (?1, ?2, ?3)",
+            params![file_id, action.name(), now()?],
+        )?;
+        Ok(num_inserted > 0)
+    }
+
+    /// Returns false if the file wasn't queued.
+    pub fn unqueue_action(&self, file_id: i64) -> Result<bool> {
+        let num_deleted = self.db.execute(
+            "DELETE FROM pending_actions WHERE id = ?1",
+            params![file_id],
+        )?;
+        Ok(num_deleted > 0)
+    }
+
+    /// All queued files, in the order they were queued.
+    pub fn get_queued_files(&self) -> Result<Vec<QueuedFile>> {
+        let mut stmt = self.db.prepare(
+            "SELECT f.id, f.path, f.size, q.action, q.requested_at FROM pending_actions q \
+             JOIN file_digests f ON f.id = q.id ORDER BY q.requested_at, q.id",
+        )?;
+        let rows: Vec<(i64, String, u64, String, i64)> = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                ))
+            })?
+            .collect::<Result<_, _>>()?;
+        rows.into_iter()
+            .map(|(id, path, size, action, requested_at)| {
+                Ok(QueuedFile {
+                    id,
+                    path: PathBuf::from(path),
+                    size,
+                    action: action.parse()?,
+                    requested_at,
+                })
+            })
+            .collect()
+    }
+
+    pub fn queue_totals(&self) -> Result<QueueTotals> {
+        let (files, bytes): (i64, i64) = self.db.query_row(
+            "SELECT COUNT(*), COALESCE(SUM(f.size), 0) FROM pending_actions q \
+             JOIN file_digests f ON f.id = q.id",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        Ok(QueueTotals {
+            files: files as usize,
+            bytes: bytes as u64,
+        })
+    }
+
+    /// Other indexed files with the same content.
+    pub fn other_copies(&self, file: &FileDigest) -> Result<Vec<(i64, PathBuf)>> {
+        let mut stmt = self
+            .db
+            .prepare("SELECT id, path FROM file_digests WHERE digest = ?1 AND id != ?2")?;
+        let copies: Result<Vec<(i64, String)>, _> = stmt
+            .query_map(params![file.digest, file.id], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?
+            .collect();
+        Ok(copies?
+            .into_iter()
+            .map(|(id, path)| (id, PathBuf::from(path)))
+            .collect())
+    }
+}
@@ -0,0 +1,183 @@
+use anyhow::{anyhow, Result};
+use std::fs;
+use std::path::Path;
+
+use crate::paths;
+use crate::tiff::Tiff;
+
+/// Camera RAW formats that are TIFF containers with embedded JPEG previews.
+const RAW_EXTENSIONS: [&str; 8] = ["cr2", "nef", "nrw", "arw", "srf", "dng", "pef", "orf"];
+
+const TAG_COMPRESSION: u16 = 0x0103;
+const TAG_STRIP_OFFSETS: u16 = 0x0111;
+const TAG_STRIP_BYTE_COUNTS: u16 = 0x0117;
+const TAG_SUB_IFDS: u16 = 0x014a;
+const TAG_JPEG_OFFSET: u16 = 0x0201;
+const TAG_JPEG_LENGTH: u16 = 0x0202;
+const TAG_EXIF_IFD: u16 = 0x8769;
+
+/// Compression values for (old-style) JPEG image data
+const COMPRESSION_JPEG: [u32; 2] = [6, 7];
+
+/// Maximum number of IFDs that are visited, guards against loops in corrupt files
+const MAX_IFDS: usize = 64;
+
+/// Shown instead of previews that can't be extracted.
+pub const PLACEHOLDER_SVG: &str = r##"<svg xmlns="http://www.w3.org/2000/svg" width="160" height="120" viewBox="0 0 160 120">
+<rect width="160" height="120" fill="#ddd"/>
+<text x="80" y="64" font-family="sans-serif" font-size="14" text-anchor="middle" fill="#666">no preview</text>
+</svg>"##;
+
+pub fn is_raw_file(path: &Path) -> bool {
+    paths::has_extension(path, &RAW_EXTENSIONS)
+}
+
+/// Reads the largest embedded JPEG preview of a RAW file.
+pub fn extract_preview(path: &Path) -> Result<Vec<u8>> {
+    let data = fs::read(path)?;
+    Ok(find_largest_jpeg(&data)?.to_vec())
+}
+
+/// Walks all IFDs of a TIFF-based file and returns the largest embedded JPEG.
+fn find_largest_jpeg(data: &[u8]) -> Result<&[u8]> {
+    let tiff = Tiff::parse(data).ok_or_else(|| anyhow!("Not a TIFF based RAW file"))?;
+    let mut best: Option<&[u8]> = None;
+    let mut pending = vec![tiff.first_ifd().unwrap_or(0)];
+    let mut visited = 0;
+    while let Some(ifd) = pending.pop() {
+        if ifd == 0 || visited >= MAX_IFDS {
+            continue;
+        }
+        visited += 1;
+        let ifd = ifd as usize;
+        let num_entries = match tiff.u16_at(ifd) {
+            Some(n) => n as usize,
+            None => continue,
+        };
+        let mut compression = None;
+        let (mut strip_offsets, mut strip_counts) = (None, None);
+        let (mut jpeg_offset, mut jpeg_length) = (None, None);
+        for i in 0..num_entries {
+            let entry = ifd + 2 + i * 12;
+            let first_value = || tiff.values(entry).and_then(|v| v.first().cloned());
+            match tiff.u16_at(entry) {
+                Some(TAG_COMPRESSION) => compression = first_value(),
+                Some(TAG_STRIP_OFFSETS) => strip_offsets = first_value(),
+                Some(TAG_STRIP_BYTE_COUNTS) => strip_counts = first_value(),
+                Some(TAG_JPEG_OFFSET) => jpeg_offset = first_value(),
+                Some(TAG_JPEG_LENGTH) => jpeg_length = first_value(),
+                Some(TAG_SUB_IFDS) | Some(TAG_EXIF_IFD) => {
+                    pending.extend(tiff.values(entry).unwrap_or_default())
+                }
+                Some(_) => {}
+                None => break,
+            }
+        }
+        let mut candidates = vec![];
+        if let (Some(offset), Some(length)) = (jpeg_offset, jpeg_length) {
+            candidates.push((offset, length));
+        }
+        if let (Some(c), Some(offset), Some(length)) = (compression, strip_offsets, strip_counts) {
+            if COMPRESSION_JPEG.contains(&c) {
+                candidates.push((offset, length));
+            }
+        }
+        for (offset, length) in candidates {
+            if let Some(jpeg) = tiff.jpeg(offset, length) {
+                if !matches!(best, Some(b) if b.len() >= jpeg.len()) {
+                    best = Some(jpeg);
+                }
+            }
+        }
+        if let Some(next) = tiff.u32_at(ifd + 2 + num_entries * 12) {
+            pending.push(next);
+        }
+    }
+    best.ok_or_else(|| anyhow!("No embedded JPEG preview found"))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// A minimal TIFF with two IFDs. IFD0 holds a small JPEG "thumbnail" and IFD1 a
+    /// larger one, both as JPEGInterchangeFormat entries.
+    pub fn synthetic_raw(little_endian: bool) -> (Vec<u8>, Vec<u8>) {
+        let small = vec![0xff, 0xd8, 1, 2, 0xff, 0xd9];
+        let large = vec![0xff, 0xd8, 3, 4, 5, 6, 7, 8, 0xff, 0xd9];
+        let u16b = |v: u16| {
+            if little_endian {
+                v.to_le_bytes().to_vec()
+            } else {
+                v.to_be_bytes().to_vec()
+            }
+        };
+        let u32b = |v: u32| {
+            if little_endian {
+                v.to_le_bytes().to_vec()
+            } else {
+                v.to_be_bytes().to_vec()
+            }
+        };
+        let entry = |tag: u16, value: u32| {
+            let mut e = u16b(tag);
+            e.extend(u16b(4));
+            e.extend(u32b(1));
+            e.extend(u32b(value));
+            e
+        };
+        // header (8) + 2 IFDs with 2 entries each (2 + 24 + 4 = 30 bytes each)
+        let ifd0 = 8;
+        let ifd1 = ifd0 + 30;
+        let small_offset = ifd1 + 30;
+        let large_offset = small_offset + small.len() as u32;
+
+        let mut data = if little_endian {
+            b"II*\0".to_vec()
+        } else {
+            b"MM\0*".to_vec()
+        };
+        data.extend(u32b(ifd0));
+        for (offset, length, next) in [
+            (small_offset, small.len() as u32, ifd1),
+            (large_offset, large.len() as u32, 0),
+        ]
+        .iter()
+        {
+            data.extend(u16b(2));
+            data.extend(entry(TAG_JPEG_OFFSET, *offset));
+            data.extend(entry(TAG_JPEG_LENGTH, *length));
+            data.extend(u32b(*next));
+        }
+        data.extend(&small);
+        data.extend(&large);
+        (data, large)
+    }
+
+    #[test]
+    fn test_find_largest_jpeg() -> Result<()> {
+        for &little_endian in [true, false].iter() {
+            let (data, large) = synthetic_raw(little_endian);
+            assert_eq!(find_largest_jpeg(&data)?, large.as_slice());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_largest_jpeg_rejects_invalid_files() {
+        assert!(find_largest_jpeg(b"not a tiff").is_err());
+        // a valid header pointing past the end of the file
+        assert!(find_largest_jpeg(b"II*\0\xff\x00\x00\x00").is_err());
+        // the JPEG length points past the end of the file
+        let (mut data, _) = synthetic_raw(true);
+        data.truncate(data.len() - 5);
+        assert_eq!(find_largest_jpeg(&data).unwrap().len(), 6);
+    }
+
+    #[test]
+    fn test_is_raw_file() {
+        assert!(is_raw_file(Path::new("/tmp/a.NEF")));
+        assert!(is_raw_file(Path::new("/tmp/a.cr2")));
+        assert!(!is_raw_file(Path::new("/tmp/a.jpg")));
+    }
+}
@@ -0,0 +1,456 @@
+use anyhow::Result;
+use rusqlite::{params, Connection};
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::database::Database;
+use crate::paths::PathCase;
+use crate::tombstones::now;
+
+/// Databases with fewer files aren't checked, a new library starts out small.
+const MIN_INDEXED_FILES: usize = 100;
+/// A scan root is suspicious if less than this share of the indexed files are below it.
+const MIN_SHARE_PERCENT: usize = 5;
+
+/// A directory that was scanned into the DB.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ScanRoot {
+    /// Referenced by the root_id of the files below it
+    pub id: i64,
+    pub path: PathBuf,
+    /// unix seconds
+    pub last_scan: i64,
+}
+
+/// How many of the indexed files are below a scan root.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RootShare {
+    pub indexed: usize,
+    pub below_root: usize,
+}
+
+impl RootShare {
+    pub fn new<'a>(
+        indexed: impl IntoIterator<Item = &'a Path>,
+        root: &Path,
+        path_case: PathCase,
+    ) -> RootShare {
+        let mut share = RootShare {
+            indexed: 0,
+            below_root: 0,
+        };
+        for path in indexed {
+            share.indexed += 1;
+            if path_case.starts_with(path, root) {
+                share.below_root += 1;
+            }
+        }
+        share
+    }
+
+    /// Whether the DB most likely belongs to a different library than the scan root.
+    pub fn looks_mismatched(&self) -> bool {
+        self.indexed >= MIN_INDEXED_FILES
+            && self.below_root * 100 < self.indexed * MIN_SHARE_PERCENT
+    }
+}
+
+/// The name a scan root is selected by in filters: its last component, or the whole
+/// path for roots like "/".
+pub fn root_label(root: &Path) -> String {
+    root.file_name()
+        .unwrap_or(root.as_os_str())
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// The id of the innermost of `roots` that `path` is below.
+pub fn root_of(roots: &[(i64, PathBuf)], path: &Path, path_case: PathCase) -> Option<i64> {
+    roots
+        .iter()
+        .filter(|(_, root)| path_case.starts_with(path, root))
+        .max_by_key(|(_, root)| root.components().count())
+        .map(|(id, _)| *id)
+}
+
+/// Whether two paths are the same directory, e.g. because one is a bind mount of the
+/// other.
+#[cfg(unix)]
+fn same_directory(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (fs::metadata(a), fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn same_directory(_a: &Path, _b: &Path) -> bool {
+    false
+}
+
+/// Pairs of scan roots that contain the same files under different paths, because one
+/// of them is reached through a symlink or both are mounts of the same directory.
+/// Roots nested by their paths are fine, their files are indexed once.
+pub fn overlapping_roots(roots: &[PathBuf]) -> Vec<(PathBuf, PathBuf)> {
+    let canonical: Vec<Option<PathBuf>> = roots.iter().map(|r| fs::canonicalize(r).ok()).collect();
+    let mut overlapping = Vec::new();
+    for i in 0..roots.len() {
+        for j in i + 1..roots.len() {
+            let (a, b) = (&roots[i], &roots[j]);
+            if a.starts_with(b) || b.starts_with(a) {
+                continue;
+            }
+            let nested = match (&canonical[i], &canonical[j]) {
+                (Some(a), Some(b)) => a.starts_with(b) || b.starts_with(a),
+                _ => false,
+            };
+            if nested || same_directory(a, b) {
+                overlapping.push((a.clone(), b.clone()));
+            }
+        }
+    }
+    overlapping
+}
+
+/// The roots that aren't below another of `roots`, in their given order. Scanning a root
+/// that is nested in another one would list its files twice.
+pub fn outermost_roots(roots: &[PathBuf], path_case: PathCase) -> Vec<PathBuf> {
+    let mut outermost: Vec<PathBuf> = Vec::new();
+    for (i, root) in roots.iter().enumerate() {
+        let nested = roots.iter().enumerate().any(|(j, other)| {
+            let inside = path_case.starts_with(root, other);
+            // of two equal roots, the first one is kept
+            inside && (!path_case.starts_with(other, root) || j < i)
+        });
+        if nested {
+            log::info!(
+                "{} is part of another --path already",
+                root.to_string_lossy()
+            );
+        } else {
+            outermost.push(root.clone());
+        }
+    }
+    outermost
+}
+
+/// Ids and paths of all scan roots. Takes a connection, so it also works inside of
+/// transactions.
+pub fn scan_root_ids(db: &Connection) -> Result<Vec<(i64, PathBuf)>> {
+    let mut stmt = db.prepare("SELECT id, path FROM scan_roots")?;
+    let roots: Result<Vec<_>, _> = stmt
+        .query_map([], |row| {
+            let path: String = row.get(1)?;
+            Ok((row.get(0)?, PathBuf::from(path)))
+        })?
+        .collect();
+    Ok(roots?)
+}
+
+impl Database {
+    /// Remembers that `root` was scanned. A new root takes over the files below it
+    /// from the roots it is nested in.
+    pub fn record_scan_root(&self, root: &Path, path_case: PathCase) -> Result<()> {
+        let root = root.to_string_lossy();
+        let now = now()?;
+        self.db.execute(
+            "UPDATE scan_roots SET last_scan = ?2 WHERE path = ?1",
+            params![root, now],
+        )?;
+        let inserted = self.db.execute(
+            "INSERT OR IGNORE INTO scan_roots (path, last_scan) VALUES (?1, ?2)",
+            params![root, now],
+        )?;
+        if inserted > 0 {
+            self.assign_root_ids(path_case)?;
+        }
+        Ok(())
+    }
+
+    /// All directories scanned into the DB, the most recent first.
+    pub fn get_scan_roots(&self) -> Result<Vec<ScanRoot>> {
+        let mut stmt = self
+            .db
+            .prepare("SELECT id, path, last_scan FROM scan_roots ORDER BY last_scan DESC, path")?;
+        let roots: Result<Vec<ScanRoot>, _> = stmt
+            .query_map([], |row| {
+                let path: String = row.get(1)?;
+                Ok(ScanRoot {
+                    id: row.get(0)?,
+                    path: PathBuf::from(path),
+                    last_scan: row.get(2)?,
+                })
+            })?
+            .collect();
+        Ok(roots?)
+    }
+
+    /// Sets the root_id of every file to the innermost scan root it is below. Returns
+    /// the number of changed files.
+    pub fn assign_root_ids(&self, path_case: PathCase) -> Result<usize> {
+        let roots = scan_root_ids(&self.db)?;
+        let tx = self.db.unchecked_transaction()?;
+        let mut changed = Vec::new();
+        {
+            let mut stmt = tx.prepare("SELECT id, path, root_id FROM file_digests")?;
+            let mut rows = stmt.query([])?;
+            while let Some(row) = rows.next()? {
+                let path: String = row.get(1)?;
+                let root_id: Option<i64> = row.get(2)?;
+                let new_root_id = root_of(&roots, Path::new(&path), path_case);
+                if new_root_id != root_id {
+                    changed.push((row.get::<_, i64>(0)?, new_root_id));
+                }
+            }
+            let mut update = tx.prepare("UPDATE file_digests SET root_id = ?2 WHERE id = ?1")?;
+            for (id, root_id) in changed.iter() {
+                update.execute(params![id, root_id])?;
+            }
+        }
+        tx.commit()?;
+        log::info!("Assigned {} files to their scan roots", changed.len());
+        Ok(changed.len())
+    }
+
+    /// Updates the root_id of a file whose path was changed outside of a scan.
+    pub fn refresh_root_id(&self, file_id: i64) -> Result<()> {
+        let path: String = self.db.query_row(
+            "SELECT path FROM file_digests WHERE id = ?1",
+            params![file_id],
+            |row| row.get(0),
+        )?;
+        let root_id = root_of(
+            &scan_root_ids(&self.db)?,
+            Path::new(&path),
+            PathCase::Sensitive,
+        );
+        self.db.execute(
+            "UPDATE file_digests SET root_id = ?2 WHERE id = ?1",
+            params![file_id, root_id],
+        )?;
+        Ok(())
+    }
+
+    /// Scan roots of older versions were only identified by their path. Gives them ids,
+    /// and assigns the files to them.
+    pub(crate) fn migrate_scan_roots(&self) -> Result<()> {
+        if !self.has_column("scan_roots", "id")? {
+            log::info!("Adding ids to the scan roots");
+            self.db.execute_batch(
+                "BEGIN;
+                 ALTER TABLE scan_roots RENAME TO scan_roots_old;
+                 CREATE TABLE scan_roots (
+                    id          INTEGER PRIMARY KEY,
+                    path        TEXT NOT NULL UNIQUE,
+                    last_scan   INTEGER NOT NULL
+                 );
+                 INSERT INTO scan_roots (path, last_scan)
+                    SELECT path, last_scan FROM scan_roots_old ORDER BY last_scan, path;
+                 DROP TABLE scan_roots_old;
+                 COMMIT;",
+            )?;
+        }
+        if self.add_column_if_missing("file_digests", "root_id", "INTEGER")? {
+            self.assign_root_ids(PathCase::Sensitive)?;
+        }
+        Ok(())
+    }
+
+    /// Returns a warning if only few of the indexed files are below `root`.
+    pub fn check_scan_root(&self, root: &Path, path_case: PathCase) -> Result<Option<String>> {
+        let paths = self.get_all_paths()?;
+        let share = RootShare::new(paths.iter().map(|p| p.as_path()), root, path_case);
+        if !share.looks_mismatched() {
+            return Ok(None);
+        }
+        let known_roots: Vec<String> = self
+            .get_scan_roots()?
+            .iter()
+            .map(|r| r.path.to_string_lossy().into_owned())
+            .collect();
+        Ok(Some(format!(
+            "Only {} of {} indexed files are below {}, the database might belong to a different \
+             library. Previously scanned: {}",
+            share.below_root,
+            share.indexed,
+            root.to_string_lossy(),
+            if known_roots.is_empty() {
+                "unknown".to_string()
+            } else {
+                known_roots.join(", ")
+            }
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::FileDigest;
+
+    #[test]
+    #[cfg(unix)]
+    fn test_overlapping_roots() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let library = dir.path().join("library");
+        fs::create_dir_all(library.join("share"))?;
+        fs::create_dir(dir.path().join("other"))?;
+        std::os::unix::fs::symlink(library.join("share"), dir.path().join("share"))?;
+
+        let roots = [library.clone(), dir.path().join("share")];
+        assert_eq!(
+            overlapping_roots(&roots),
+            [(roots[0].clone(), roots[1].clone())]
+        );
+        assert!(overlapping_roots(&[library.clone(), library.join("share")]).is_empty());
+        assert!(overlapping_roots(&[library, dir.path().join("other")]).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_outermost_roots() {
+        let roots: Vec<PathBuf> = ["/data/photos", "/data", "/music", "/Data", "/data/"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        assert_eq!(
+            outermost_roots(&roots, PathCase::Sensitive),
+            [PathBuf::from("/data"), "/music".into(), "/Data".into()]
+        );
+        assert_eq!(
+            outermost_roots(&roots, PathCase::Insensitive),
+            [PathBuf::from("/data"), "/music".into()]
+        );
+    }
+
+    #[test]
+    fn test_root_share() {
+        let paths: Vec<PathBuf> = (0..200)
+            .map(|i| PathBuf::from(format!("/photos/{}.jpg", i)))
+            .chain((0..5).map(|i| PathBuf::from(format!("/Videos/{}.mp4", i))))
+            .collect();
+        let share = |root: &str, path_case| {
+            RootShare::new(
+                paths.iter().map(|p| p.as_path()),
+                Path::new(root),
+                path_case,
+            )
+        };
+        assert_eq!(
+            share("/videos", PathCase::Sensitive),
+            RootShare {
+                indexed: 205,
+                below_root: 0
+            }
+        );
+        assert!(share("/videos", PathCase::Sensitive).looks_mismatched());
+        assert!(share("/Videos", PathCase::Sensitive).looks_mismatched());
+        assert!(!share("/photos", PathCase::Sensitive).looks_mismatched());
+        assert!(!share("/", PathCase::Sensitive).looks_mismatched());
+        // 5 of 205 files are below 5%, 11 of 205 just above
+        assert!(RootShare {
+            indexed: 205,
+            below_root: 5
+        }
+        .looks_mismatched());
+        assert!(!RootShare {
+            indexed: 205,
+            below_root: 11
+        }
+        .looks_mismatched());
+        // small databases are never suspicious
+        let few = RootShare::new(
+            paths.iter().take(50).map(|p| p.as_path()),
+            Path::new("/x"),
+            PathCase::Sensitive,
+        );
+        assert!(!few.looks_mismatched());
+    }
+
+    #[test]
+    fn test_check_scan_root() -> Result<()> {
+        let db = Database::new("test_check_scan_root.sqlite", true)?;
+        for id in 0..MIN_INDEXED_FILES as i64 {
+            db.insert_filedigest(&FileDigest::new(
+                id + 1,
+                &format!("/library/a/{}", id),
+                vec![1],
+                1,
+            ))?;
+        }
+        db.record_scan_root(Path::new("/library/a"), PathCase::Sensitive)?;
+        assert_eq!(
+            db.check_scan_root(Path::new("/library"), PathCase::Sensitive)?,
+            None
+        );
+        let warning = db
+            .check_scan_root(Path::new("/other"), PathCase::Sensitive)?
+            .unwrap();
+        assert!(warning.contains("Only 0 of 100 indexed files"));
+        assert!(warning.contains("/library/a"));
+        assert_eq!(db.get_scan_roots()?.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_root_ids_are_backfilled() -> Result<()> {
+        let name = "test_root_ids_are_backfilled.sqlite";
+        let db = Database::new(name, true)?;
+        // the tables as older versions created them
+        db.db.execute_batch(
+            "DROP TABLE scan_roots;
+             CREATE TABLE scan_roots (path TEXT PRIMARY KEY, last_scan INTEGER NOT NULL);
+             INSERT INTO scan_roots VALUES ('/library', 1), ('/backup', 2), ('/library/raw', 3);
+             DROP TABLE file_digests;
+             CREATE TABLE file_digests (id INTEGER PRIMARY KEY, path TEXT NOT NULL UNIQUE, \
+                digest BLOB, size INTEGER);
+             INSERT INTO file_digests (id, path) VALUES (1, '/library/a.jpg'), \
+                (2, '/library/raw/a.cr2'), (3, '/backup/a.jpg'), (4, '/elsewhere/a.jpg'), \
+                (5, '/library-old/a.jpg');",
+        )?;
+        drop(db);
+
+        let db = Database::new(name, false)?;
+        let roots: Vec<(String, i64)> = db
+            .get_scan_roots()?
+            .iter()
+            .map(|r| (root_label(&r.path), r.id))
+            .collect();
+        assert_eq!(
+            roots,
+            [
+                ("raw".to_string(), 3),
+                ("backup".to_string(), 2),
+                ("library".to_string(), 1)
+            ]
+        );
+        let root_ids = |db: &Database| -> Result<Vec<Option<i64>>> {
+            let mut stmt = db
+                .db
+                .prepare("SELECT root_id FROM file_digests ORDER BY id")?;
+            let ids: Result<Vec<_>, _> = stmt.query_map([], |row| row.get(0))?.collect();
+            Ok(ids?)
+        };
+        // the innermost root wins, and prefixes only match whole components
+        assert_eq!(root_ids(&db)?, [Some(1), Some(3), Some(2), None, None]);
+
+        db.record_scan_root(Path::new("/elsewhere"), PathCase::Sensitive)?;
+        assert_eq!(root_ids(&db)?, [Some(1), Some(3), Some(2), Some(4), None]);
+        // scanning a known root again changes nothing
+        db.record_scan_root(Path::new("/library"), PathCase::Sensitive)?;
+        let library = db.get_scan_roots()?;
+        assert_eq!(
+            library
+                .iter()
+                .find(|r| r.path == Path::new("/library"))
+                .unwrap()
+                .id,
+            1
+        );
+        assert_eq!(root_ids(&db)?, [Some(1), Some(3), Some(2), Some(4), None]);
+        assert_eq!(root_label(Path::new("/")), "/");
+        Ok(())
+    }
+}
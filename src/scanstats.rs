@@ -0,0 +1,468 @@
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::shutdown;
+use crate::throttle;
+
+/// Counters collected over one run of update_database.
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct ScanStats {
+    /// Files left out of the scan because of their extension
+    pub files_skipped: usize,
+    /// Files left out of the scan because their extension isn't one of --only-ext
+    pub files_not_included: usize,
+    /// Files left out of the scan because they match --exclude-path
+    pub files_excluded: usize,
+    /// Files left out of the scan because they are smaller than --min-size
+    pub files_too_small: usize,
+    /// Cloud placeholders that were left out, since reading them downloads them
+    pub placeholders_skipped: usize,
+    /// Directories that were left out because they contain a marker file
+    pub marked_dirs_skipped: usize,
+    /// Files newly added to the DB
+    pub files_inserted: usize,
+    /// Already indexed files whose digest or size changed
+    pub files_updated: usize,
+    /// Missing files that were found again at a new path
+    pub files_moved: usize,
+    /// Files that could not be read or hashed
+    pub hash_errors: usize,
+    /// Paths that showed up more than once in the same commit batch
+    pub batch_duplicates: usize,
+    /// Rows that could not be written, e.g. because they were already indexed
+    pub insert_conflicts: usize,
+    /// Video hashes that could not be written because the file was already hashed
+    pub videohash_conflicts: usize,
+}
+
+impl fmt::Display for ScanStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} files skipped, {} of other extensions, {} excluded, {} too small, {} placeholders skipped, {} marked directories skipped, {} files inserted, {} updated, {} moved, {} hash errors, {} duplicate paths in batch, \
+             {} insert conflicts, {} video hash conflicts",
+            self.files_skipped,
+            self.files_not_included,
+            self.files_excluded,
+            self.files_too_small,
+            self.placeholders_skipped,
+            self.marked_dirs_skipped,
+            self.files_inserted,
+            self.files_updated,
+            self.files_moved,
+            self.hash_errors,
+            self.batch_duplicates,
+            self.insert_conflicts,
+            self.videohash_conflicts
+        )
+    }
+}
+
+/// Rates and ETAs are computed over the progress of this long period, so they follow
+/// changes in speed, e.g. from large to small files.
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// The phases of update_database that process files one by one.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase {
+    Hashing,
+    Videohash,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Phase::Hashing => write!(f, "Hashing"),
+            Phase::Videohash => write!(f, "Video hashing"),
+        }
+    }
+}
+
+/// Progress of one phase. Updated from the threads that collect results, read from
+/// anywhere.
+#[derive(Debug, Default)]
+pub struct PhaseProgress {
+    files_done: AtomicUsize,
+    bytes_done: AtomicU64,
+    errors: AtomicUsize,
+    /// Grow while files are still being listed
+    files_total: AtomicUsize,
+    bytes_total: AtomicU64,
+    /// Processed files waiting for the database writer, see `batcher::queue`
+    queued: AtomicUsize,
+    started: Mutex<Option<Instant>>,
+    /// (time, files_done, bytes_done) recorded by `sample`, oldest first
+    samples: Mutex<VecDeque<Sample>>,
+}
+
+impl PhaseProgress {
+    pub fn start(&self) {
+        if let Ok(mut started) = self.started.lock() {
+            started.get_or_insert_with(Instant::now);
+        }
+    }
+
+    pub fn add_total(&self, files: usize, bytes: u64) {
+        self.files_total.fetch_add(files, Ordering::Relaxed);
+        self.bytes_total.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Counts a processed file. Files that failed count as done as well.
+    pub fn add_file(&self, bytes: u64, failed: bool) {
+        self.files_done.fetch_add(1, Ordering::Relaxed);
+        self.bytes_done.fetch_add(bytes, Ordering::Relaxed);
+        if failed {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn set_queued(&self, files: usize) {
+        self.queued.store(files, Ordering::Relaxed);
+    }
+
+    /// Records the current number of processed files for the rolling rate.
+    pub fn sample(&self, now: Instant) {
+        if let Ok(mut samples) = self.samples.lock() {
+            samples.push_back((
+                now,
+                self.files_done.load(Ordering::Relaxed),
+                self.bytes_done.load(Ordering::Relaxed),
+            ));
+            // keep one sample older than the window, so the window is always covered
+            while samples.len() > 2 && now.duration_since(samples[1].0) >= RATE_WINDOW {
+                samples.pop_front();
+            }
+        }
+    }
+
+    pub fn snapshot(&self, now: Instant) -> PhaseSnapshot {
+        let files_done = self.files_done.load(Ordering::Relaxed);
+        let files_total = self.files_total.load(Ordering::Relaxed);
+        let rates = self
+            .samples
+            .lock()
+            .ok()
+            .and_then(|s| rolling_rate(s.front(), s.back()));
+        let files_per_sec = rates.map(|(files, _)| files);
+        PhaseSnapshot {
+            files_done,
+            files_total,
+            bytes_done: self.bytes_done.load(Ordering::Relaxed),
+            bytes_total: self.bytes_total.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            queued: self.queued.load(Ordering::Relaxed),
+            elapsed: self
+                .started
+                .lock()
+                .ok()
+                .and_then(|s| s.map(|s| now.duration_since(s))),
+            files_per_sec,
+            bytes_per_sec: rates.map(|(_, bytes)| bytes),
+            eta: files_per_sec.and_then(|r| eta(files_total.saturating_sub(files_done), r)),
+        }
+    }
+}
+
+type Sample = (Instant, usize, u64);
+
+/// Files and bytes per second between two samples.
+fn rolling_rate(first: Option<&Sample>, last: Option<&Sample>) -> Option<(f64, f64)> {
+    let (first, last) = (first?, last?);
+    let dt = last.0.duration_since(first.0).as_secs_f64();
+    if dt <= 0.0 {
+        return None;
+    }
+    Some((
+        (last.1 - first.1) as f64 / dt,
+        (last.2 - first.2) as f64 / dt,
+    ))
+}
+
+fn eta(files_remaining: usize, files_per_sec: f64) -> Option<Duration> {
+    if files_remaining == 0 {
+        Some(Duration::from_secs(0))
+    } else if files_per_sec > 0.0 {
+        Some(Duration::from_secs_f64(
+            files_remaining as f64 / files_per_sec,
+        ))
+    } else {
+        None
+    }
+}
+
+/// A consistent view of the progress of a phase.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PhaseSnapshot {
+    pub files_done: usize,
+    pub files_total: usize,
+    pub bytes_done: u64,
+    pub bytes_total: u64,
+    pub errors: usize,
+    pub queued: usize,
+    /// None if the phase didn't start
+    pub elapsed: Option<Duration>,
+    /// Over the last minute
+    pub files_per_sec: Option<f64>,
+    pub bytes_per_sec: Option<f64>,
+    pub eta: Option<Duration>,
+}
+
+impl fmt::Display for PhaseSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} files, {:.1} of {:.1} MiB, {} errors",
+            self.files_done,
+            self.files_total,
+            self.bytes_done as f64 / MIB,
+            self.bytes_total as f64 / MIB,
+            self.errors
+        )?;
+        if self.queued > 0 {
+            write!(f, ", {} queued", self.queued)?;
+        }
+        if let Some(rate) = self.files_per_sec {
+            write!(f, ", {:.1} files/s", rate)?;
+        }
+        if let Some(rate) = self.bytes_per_sec {
+            write!(f, ", {:.1} MiB/s", rate / MIB)?;
+            if let Some(limit) = throttle::limit() {
+                write!(f, " (throttled to {:.1})", limit)?;
+            }
+        }
+        if let Some(eta) = self.eta {
+            write!(f, ", {}s left", eta.as_secs())?;
+        } else if let Some(elapsed) = self.elapsed {
+            write!(f, ", {}s elapsed", elapsed.as_secs())?;
+        }
+        Ok(())
+    }
+}
+
+const MIB: f64 = 1024.0 * 1024.0;
+
+/// Where the progress of a phase is shown. Behind a trait so that tests can record it.
+pub trait ProgressReporter: Send + Sync {
+    fn report(&self, phase: Phase, snapshot: &PhaseSnapshot);
+
+    /// Called once the phase is done, with its last progress.
+    fn finish(&self, _phase: Phase, _snapshot: &PhaseSnapshot) {}
+
+    /// How often the progress is shown while files are processed, in addition to after
+    /// each committed batch.
+    fn interval(&self) -> Duration;
+}
+
+/// Logs a line at info level, for output that goes to a file or the journal.
+pub struct LogReporter;
+
+impl ProgressReporter for LogReporter {
+    fn report(&self, phase: Phase, snapshot: &PhaseSnapshot) {
+        log::info!("{}: {}", phase, snapshot);
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::from_secs(30)
+    }
+}
+
+/// Keeps rewriting a single line on stderr.
+pub struct TerminalReporter;
+
+impl ProgressReporter for TerminalReporter {
+    fn report(&self, phase: Phase, snapshot: &PhaseSnapshot) {
+        // clears what is left of a longer previous line
+        eprint!("\r{}: {}\x1b[K", phase, snapshot);
+        let _ = std::io::stderr().flush();
+    }
+
+    fn finish(&self, phase: Phase, snapshot: &PhaseSnapshot) {
+        eprintln!("\r{}: {}\x1b[K", phase, snapshot);
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::from_millis(250)
+    }
+}
+
+/// Progress of all phases of one run of update_database, shared between the threads
+/// that work on it.
+pub struct Progress {
+    hashing: PhaseProgress,
+    videohash: PhaseProgress,
+    reporter: Arc<dyn ProgressReporter>,
+    last_report: Mutex<Option<Instant>>,
+    /// Set to stop this run only, Ctrl-C stops all of them
+    stop: Arc<AtomicBool>,
+}
+
+impl Default for Progress {
+    /// Shows the progress on the terminal if stderr is one, logs it otherwise.
+    fn default() -> Progress {
+        if std::io::stderr().is_terminal() {
+            Progress::with_reporter(Arc::new(TerminalReporter))
+        } else {
+            Progress::with_reporter(Arc::new(LogReporter))
+        }
+    }
+}
+
+impl fmt::Debug for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Progress")
+            .field("hashing", &self.hashing)
+            .field("videohash", &self.videohash)
+            .finish()
+    }
+}
+
+impl Progress {
+    pub fn with_reporter(reporter: Arc<dyn ProgressReporter>) -> Progress {
+        Progress {
+            hashing: PhaseProgress::default(),
+            videohash: PhaseProgress::default(),
+            reporter,
+            last_report: Mutex::new(None),
+            stop: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Stops the run once `stop` is set.
+    pub fn with_stop(self, stop: Arc<AtomicBool>) -> Progress {
+        Progress { stop, ..self }
+    }
+
+    /// Whether the phases should save what they have and return, see `shutdown`.
+    pub fn stop_requested(&self) -> bool {
+        self.stop.load(Ordering::SeqCst) || shutdown::requested()
+    }
+
+    pub fn phase(&self, phase: Phase) -> &PhaseProgress {
+        match phase {
+            Phase::Hashing => &self.hashing,
+            Phase::Videohash => &self.videohash,
+        }
+    }
+
+    /// Shows the progress of `phase`, called after each committed batch.
+    pub fn report(&self, phase: Phase) {
+        let progress = self.phase(phase);
+        let now = Instant::now();
+        progress.sample(now);
+        if let Ok(mut last_report) = self.last_report.lock() {
+            *last_report = Some(now);
+        }
+        self.reporter.report(phase, &progress.snapshot(now));
+    }
+
+    /// Shows the progress of `phase` if the reporter's interval has passed, called after
+    /// each processed file.
+    pub fn update(&self, phase: Phase) {
+        let now = Instant::now();
+        let due = match self.last_report.lock() {
+            Ok(last_report) => {
+                last_report.is_none_or(|t| now.duration_since(t) >= self.reporter.interval())
+            }
+            Err(_) => false,
+        };
+        if due {
+            self.report(phase);
+        }
+    }
+
+    /// Shows the last progress of `phase` once it is done.
+    pub fn finish(&self, phase: Phase) {
+        let progress = self.phase(phase);
+        let now = Instant::now();
+        progress.sample(now);
+        self.reporter.finish(phase, &progress.snapshot(now));
+    }
+}
+
+impl fmt::Display for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let now = Instant::now();
+        let mut first = true;
+        for &phase in [Phase::Hashing, Phase::Videohash].iter() {
+            let snapshot = self.phase(phase).snapshot(now);
+            if snapshot.elapsed.is_none() {
+                continue;
+            }
+            if !first {
+                write!(f, "; ")?;
+            }
+            first = false;
+            write!(
+                f,
+                "{}: {} files, {:.1} MiB, {} errors in {}s",
+                phase,
+                snapshot.files_done,
+                snapshot.bytes_done as f64 / MIB,
+                snapshot.errors,
+                snapshot.elapsed.unwrap_or_default().as_secs()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rolling_eta() {
+        let progress = PhaseProgress::default();
+        let t0 = Instant::now();
+        progress.start();
+        progress.add_total(8200, 8200 * 1024);
+        progress.sample(t0);
+        let run = |from: u64, to: u64, files_per_sec: usize, failed: bool| {
+            for t in (from + 10..=to).step_by(10) {
+                for _ in 0..files_per_sec * 10 {
+                    progress.add_file(1024, failed);
+                }
+                progress.sample(t0 + Duration::from_secs(t));
+            }
+        };
+
+        // slow at first: 10 files/s for two minutes
+        run(0, 60, 10, false);
+        let snapshot = progress.snapshot(t0 + Duration::from_secs(60));
+        assert_eq!(snapshot.files_per_sec, Some(10.0));
+        assert_eq!(snapshot.eta, Some(Duration::from_secs(760)));
+        run(60, 120, 10, false);
+
+        // then 100 files/s, the slow part drops out of the window after a minute
+        run(120, 180, 100, true);
+        let snapshot = progress.snapshot(t0 + Duration::from_secs(180));
+        assert_eq!(snapshot.files_per_sec, Some(100.0));
+        assert_eq!(snapshot.bytes_per_sec, Some(100.0 * 1024.0));
+        assert_eq!(snapshot.eta, Some(Duration::from_secs(10)));
+        assert_eq!(snapshot.files_done, 7200);
+        assert_eq!(snapshot.bytes_done, 7200 * 1024);
+        assert_eq!(snapshot.errors, 6000);
+        assert!(snapshot.elapsed.is_some());
+    }
+
+    #[test]
+    fn test_eta_without_progress() {
+        let progress = PhaseProgress::default();
+        let t0 = Instant::now();
+        assert_eq!(progress.snapshot(t0).eta, None);
+        progress.add_total(10, 0);
+        progress.sample(t0);
+        progress.sample(t0 + Duration::from_secs(5));
+        let snapshot = progress.snapshot(t0 + Duration::from_secs(5));
+        assert_eq!(snapshot.files_per_sec, Some(0.0));
+        assert_eq!(snapshot.eta, None);
+        assert_eq!(snapshot.elapsed, None);
+        assert_eq!(eta(0, 0.0), Some(Duration::from_secs(0)));
+    }
+}
@@ -0,0 +1,25 @@
+use anyhow::Result;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Exit code of a run that was stopped, the one shells use for processes ended by SIGINT.
+pub const EXIT_CODE: i32 = 130;
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Asks long running work to stop on Ctrl-C or SIGTERM instead of ending the process.
+/// Hashing saves the files hashed so far, so that --resume goes on from there, and the
+/// web interface stops taking requests. A second Ctrl-C quits right away.
+pub fn install_handler() -> Result<()> {
+    ctrlc::set_handler(|| {
+        if REQUESTED.swap(true, Ordering::SeqCst) {
+            std::process::exit(EXIT_CODE);
+        }
+        eprintln!("Stopping once the hashed files are saved, press Ctrl-C again to quit now");
+    })?;
+    Ok(())
+}
+
+/// Whether Dupletti was asked to stop.
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
@@ -1,95 +1,999 @@
-use anyhow::Result;
-use serde::Serialize;
-use std::collections::HashMap;
-use std::collections::HashSet;
+use anyhow::{anyhow, Result};
+use rayon::prelude::*;
+use rusqlite::params;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
-use std::path::PathBuf;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 
-pub use crate::database::{Database, FileDigest};
+pub use crate::database::Database;
+use crate::exif::{self, ExifInfo};
+use crate::filemeta::{self, FileIdentity};
+use crate::notes::GroupNote;
+use crate::paths;
+use crate::scanroots;
+use crate::tombstones::Tombstone;
+use crate::urls;
+use crate::videohash;
 
-#[derive(Debug, PartialEq, Serialize)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct FileEntry {
     pub id: i64,
     pub path: PathBuf,
     pub size: u64,
+    /// Set by ExistenceCache::mark_missing if the file was removed from disk
+    pub missing: bool,
+    /// When the file was first indexed (unix seconds), unknown for files indexed by
+    /// older versions
+    pub first_seen: Option<i64>,
+    /// Capture metadata, only set for images
+    pub exif: ExifInfo,
+    /// Set if a file with the same content was deleted before this one was indexed
+    pub previously_deleted: Option<Tombstone>,
+    /// Protected files are never deleted, see `BulkAction::Protect`
+    pub protected: bool,
+    /// Marked for deletion, see `queue`
+    pub queued: bool,
+    pub tags: Vec<String>,
+    /// Birth time (unix seconds) and owner uid, only known with --collect-metadata
+    pub created: Option<i64>,
+    pub owner: Option<u32>,
+    /// The innermost scan root the file is below, see `scanroots::root_of`
+    pub root_id: Option<i64>,
+    pub root: Option<PathBuf>,
+    /// Going by the extension, so the results don't have to read every file
+    pub is_image: bool,
+    pub is_video: bool,
+    /// Links for the templates, see `urls`
+    pub file_url: String,
+    pub preview_url: String,
+    pub reveal_url: String,
+}
+
+/// A set of files with identical content.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FileGroup {
+    /// Stable identifier derived from the shared digest, see `group_id`.
+    pub group_id: String,
+    pub files: Vec<FileEntry>,
+    /// Number of files in the group. Larger than `files.len()` if the group was
+    /// collapsed to its first members.
+    pub member_count: usize,
+    pub note: Option<GroupNote>,
+    /// Dismissed groups are only listed if a filter asks for them
+    pub dismissed: bool,
+    /// All members are hardlinks of one file, so removing them frees no space. Only
+    /// known for lists of groups, and only listed if a filter asks for them
+    pub hardlinked: bool,
+    /// When the newest of the members a collapsed group doesn't list was first seen, see
+    /// `filter_added_after`. None if all members are listed.
+    #[serde(skip)]
+    pub unlisted_added: Option<i64>,
+}
+
+/// Anything that can be a member of a group of duplicates.
+pub trait GroupMember {
+    fn size(&self) -> u64;
+    fn path(&self) -> &Path;
+
+    /// Missing files are neither kept nor reclaimable.
+    fn is_missing(&self) -> bool {
+        false
+    }
+
+    /// Whether the file still has its EXIF metadata.
+    fn has_exif(&self) -> bool {
+        false
+    }
+
+    /// Protected files are kept no matter which file the policy would keep.
+    fn is_protected(&self) -> bool {
+        false
+    }
+
+    /// When the file was created on disk (unix seconds), if known.
+    fn created(&self) -> Option<i64> {
+        None
+    }
+
+    /// The uid of the owner, if known.
+    fn owner(&self) -> Option<u32> {
+        None
+    }
+}
+
+impl GroupMember for FileEntry {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn is_missing(&self) -> bool {
+        self.missing
+    }
+
+    fn has_exif(&self) -> bool {
+        self.exif.is_present()
+    }
+
+    fn is_protected(&self) -> bool {
+        self.protected
+    }
+
+    fn created(&self) -> Option<i64> {
+        self.created
+    }
+
+    fn owner(&self) -> Option<u32> {
+        self.owner
+    }
+}
+
+impl<T: GroupMember> GroupMember for &T {
+    fn size(&self) -> u64 {
+        (*self).size()
+    }
+
+    fn path(&self) -> &Path {
+        (*self).path()
+    }
+
+    fn is_missing(&self) -> bool {
+        (*self).is_missing()
+    }
+
+    fn has_exif(&self) -> bool {
+        (*self).has_exif()
+    }
+
+    fn is_protected(&self) -> bool {
+        (*self).is_protected()
+    }
+
+    fn created(&self) -> Option<i64> {
+        (*self).created()
+    }
+
+    fn owner(&self) -> Option<u32> {
+        (*self).owner()
+    }
+}
+
+/// Decides which member of a group is kept, all others count as reclaimable.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeepPolicy {
+    /// Keep the first member of the group
+    First,
+    /// Keep the member whose path sorts first
+    FirstAlphabetical,
+    /// Keep the member with the shortest path, e.g. over copies in nested backup folders
+    ShortestPath,
+    LongestPath,
+    /// Keep the largest member of the group
+    Largest,
+    /// Keep the first member that still has its EXIF metadata, e.g. over a copy that was
+    /// stripped when sending it through a messenger
+    Exif,
+    /// Keep the member that was created first. Members without a known creation time
+    /// are only kept if none has one.
+    Oldest,
+    /// Keep the member that was created last, like `Oldest`
+    Newest,
+    /// Keep the first member owned by the user with this uid
+    Owner(u32),
+    /// Keep the first member below this directory
+    InDir(PathBuf),
+}
+
+impl KeepPolicy {
+    /// Index of the member that should be kept. `files` must not be empty.
+    pub fn keeper<T: GroupMember>(&self, files: &[T]) -> usize {
+        match self {
+            KeepPolicy::First => 0,
+            KeepPolicy::FirstAlphabetical => files
+                .iter()
+                .enumerate()
+                .min_by(|(_, a), (_, b)| a.path().cmp(b.path()))
+                .map_or(0, |(i, _)| i),
+            KeepPolicy::ShortestPath => files
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, f)| f.path().as_os_str().len())
+                .map_or(0, |(i, _)| i),
+            KeepPolicy::LongestPath => files
+                .iter()
+                .enumerate()
+                .max_by_key(|(i, f)| (f.path().as_os_str().len(), std::cmp::Reverse(*i)))
+                .map_or(0, |(i, _)| i),
+            KeepPolicy::Largest => files
+                .iter()
+                .enumerate()
+                .max_by_key(|(i, f)| (f.size(), std::cmp::Reverse(*i)))
+                .map_or(0, |(i, _)| i),
+            KeepPolicy::Exif => files.iter().position(|f| f.has_exif()).unwrap_or(0),
+            KeepPolicy::Oldest => files
+                .iter()
+                .enumerate()
+                .filter_map(|(i, f)| Some((f.created()?, i)))
+                .min()
+                .map_or(0, |(_, i)| i),
+            KeepPolicy::Newest => files
+                .iter()
+                .enumerate()
+                .filter_map(|(i, f)| Some((f.created()?, std::cmp::Reverse(i))))
+                .max()
+                .map_or(0, |(_, i)| i.0),
+            KeepPolicy::Owner(_) | KeepPolicy::InDir(_) => {
+                files.iter().position(|f| self.prefers(f)).unwrap_or(0)
+            }
+        }
+    }
+
+    /// Whether the policy picks a member of `files` for what it is rather than falling
+    /// back to the first one, e.g. because no member is below the directory of `InDir`, or
+    /// no member has the creation time `Oldest` goes by, which needs --collect-metadata.
+    pub fn finds_keeper<T: GroupMember>(&self, files: &[T]) -> bool {
+        match self {
+            KeepPolicy::Owner(_) | KeepPolicy::InDir(_) => files.iter().any(|f| self.prefers(f)),
+            KeepPolicy::Exif => files.iter().any(|f| f.has_exif()),
+            KeepPolicy::Oldest | KeepPolicy::Newest => files.iter().any(|f| f.created().is_some()),
+            _ => !files.is_empty(),
+        }
+    }
+
+    fn prefers<T: GroupMember>(&self, file: &T) -> bool {
+        match self {
+            KeepPolicy::Owner(uid) => file.owner() == Some(*uid),
+            KeepPolicy::InDir(dir) => file.path().starts_with(dir),
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for KeepPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<KeepPolicy> {
+        match s {
+            "first" => Ok(KeepPolicy::First),
+            "first-alphabetical" => Ok(KeepPolicy::FirstAlphabetical),
+            "shortest-path" => Ok(KeepPolicy::ShortestPath),
+            "longest-path" => Ok(KeepPolicy::LongestPath),
+            "largest" => Ok(KeepPolicy::Largest),
+            "exif" => Ok(KeepPolicy::Exif),
+            "oldest" => Ok(KeepPolicy::Oldest),
+            "newest" => Ok(KeepPolicy::Newest),
+            _ => {
+                if let Some(user) = s.strip_prefix("owner:") {
+                    Ok(KeepPolicy::Owner(filemeta::user_id(user)?))
+                } else if let Some(dir) = s.strip_prefix("in-dir:").filter(|d| !d.is_empty()) {
+                    Ok(KeepPolicy::InDir(PathBuf::from(dir)))
+                } else {
+                    Err(anyhow!(
+                        "Unknown keep policy {}, use first, first-alphabetical, shortest-path, \
+                         longest-path, largest, exif, oldest, newest, owner:<user> or in-dir:<dir>",
+                        s
+                    ))
+                }
+            }
+        }
+    }
+}
+
+/// Bytes that could be freed by deleting everything but the keeper of each group.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Reclaimable {
+    pub per_group: Vec<u64>,
+    pub total: u64,
+}
+
+pub fn reclaimable_bytes<'a, T, I>(groups: I, policy: KeepPolicy) -> Reclaimable
+where
+    T: GroupMember + 'a,
+    I: IntoIterator<Item = &'a [T]>,
+{
+    let per_group: Vec<u64> = groups
+        .into_iter()
+        .map(|files| {
+            let present: Vec<&T> = files.iter().filter(|f| !f.is_missing()).collect();
+            if present.is_empty() {
+                return 0;
+            }
+            let deletable = present.iter().filter(|f| !f.is_protected());
+            if present.iter().any(|f| f.is_protected()) {
+                // a protected file is kept anyway, all others can go
+                return deletable.map(|f| f.size()).sum();
+            }
+            let keeper = policy.keeper(&present);
+            let total: u64 = deletable.map(|f| f.size()).sum();
+            total - present[keeper].size()
+        })
+        .collect();
+    let total = per_group.iter().sum();
+    Reclaimable { per_group, total }
 }
 
 #[derive(Debug)]
 struct FileDigestBag {
     id_list: Vec<i64>,
     digest: Vec<u8>,
+    /// Size of each file, they all have the same content
+    size: u64,
+    /// See `FileGroup::hardlinked`
+    hardlinked: bool,
+    /// Members that are hardlinks of an earlier member, they take no space of their own
+    hardlinks: usize,
+}
+
+impl FileDigestBag {
+    /// Bytes taken by all copies but one.
+    fn wasted_size(&self) -> u64 {
+        self.size * (self.id_list.len() - 1 - self.hardlinks) as u64
+    }
+}
+
+/// Number of digest bytes that make up a group id.
+pub const GROUP_ID_BYTES: usize = 8;
+
+/// The id of the group of files sharing `digest`. It only depends on the file contents,
+/// so it stays the same across rescans.
+pub fn group_id(digest: &[u8]) -> String {
+    hex::encode(&digest[..std::cmp::min(GROUP_ID_BYTES, digest.len())])
+}
+
+/// Whether `s` has the form of the ids returned by `group_id`.
+pub fn is_group_id(s: &str) -> bool {
+    s.len() == GROUP_ID_BYTES * 2 && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// SQL condition on `digest` that selects the group with the given id, with its two
+/// parameters. It is a range, so the digest index can be used.
+fn group_digest_range(group_id: &str) -> Result<(&'static str, Vec<u8>, Vec<u8>)> {
+    if !is_group_id(group_id) {
+        return Err(anyhow!("Invalid group id {}", group_id));
+    }
+    let start = hex::decode(group_id)?;
+    let mut end = start.clone();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return Ok(("digest >= ?1 AND digest < ?2", start, end));
+        }
+    }
+    // the last possible id, its range extends to the end
+    Ok(("digest >= ?1 AND digest >= ?2", start.clone(), start))
 }
 
-fn find_similarities(files: Vec<FileDigest>) -> HashSet<Vec<i64>> {
+fn find_similarities(files: Vec<(i64, Vec<u8>, u64)>) -> Vec<FileDigestBag> {
     let mut map = HashMap::new();
-    for file in files {
+    for (id, digest, size) in files {
         // Looking up 4bytes instead of 1byte reduces this function's time
         // for 30k files from >1m to <1s
         // file-digests are >4 bytes, so the unwrap should always work
-        let lookup_value: [u8; 4] = file.digest[0..4].try_into().unwrap();
+        let lookup_value: [u8; 4] = digest[0..4].try_into().unwrap();
         let candidate_bags = map
             .entry(lookup_value)
             .or_insert(Vec::<FileDigestBag>::new());
         let mut is_inserted = false;
         for bag in candidate_bags.iter_mut() {
-            if file.digest == bag.digest {
-                bag.id_list.push(file.id);
+            if digest == bag.digest {
+                bag.id_list.push(id);
                 is_inserted = true;
             }
         }
         if !is_inserted {
             candidate_bags.push(FileDigestBag {
-                id_list: vec![file.id],
-                digest: file.digest,
+                id_list: vec![id],
+                digest,
+                size,
+                hardlinked: false,
+                hardlinks: 0,
             })
         }
     }
-    let mut result = HashSet::new();
+    let mut result = Vec::new();
     for (_, candidate_bags) in map {
         for mut bag in candidate_bags {
             if bag.id_list.len() > 1 {
                 bag.id_list.sort_unstable(); // guarantee order of result
-                result.insert(bag.id_list);
+                result.push(bag);
             }
         }
     }
     result
 }
 
-fn into_resultbag(db: &Database, similar_files: &HashSet<Vec<i64>>) -> Result<Vec<Vec<FileEntry>>> {
+/// Merges members that are the same file seen through different mounts, like a bind
+/// mount of a directory that is scanned as well. They share device and inode but have a
+/// single link, so they can't be hardlinks. Bags left with one member are dropped, and
+/// bags whose members are all hardlinks of one file are marked.
+fn merge_same_files(
+    bags: Vec<FileDigestBag>,
+    identities: &HashMap<i64, (FileIdentity, i64)>,
+) -> Vec<FileDigestBag> {
+    bags.into_iter()
+        .filter_map(|mut bag| {
+            let mut seen = HashSet::new();
+            bag.id_list.retain(|id| match identities.get(id) {
+                Some((identity, 1)) => seen.insert(*identity),
+                _ => true,
+            });
+            if bag.id_list.len() < 2 {
+                return None;
+            }
+            let identity_of = |id: &i64| identities.get(id).map(|(identity, _)| identity);
+            let first = identity_of(&bag.id_list[0]);
+            bag.hardlinked =
+                first.is_some() && bag.id_list.iter().all(|id| identity_of(id) == first);
+            let mut linked = HashSet::new();
+            bag.hardlinks = bag
+                .id_list
+                .iter()
+                .filter_map(identity_of)
+                .filter(|identity| !linked.insert(*identity))
+                .count();
+            Some(bag)
+        })
+        .collect()
+}
+
+/// Keeps the `limit` bags with the most wasted space. Ties are broken by digest, so the
+/// selection doesn't depend on the order of the bags.
+fn largest_bags(mut bags: Vec<FileDigestBag>, limit: usize) -> Vec<FileDigestBag> {
+    if limit < bags.len() {
+        bags.select_nth_unstable_by(limit, |a, b| {
+            b.wasted_size()
+                .cmp(&a.wasted_size())
+                .then_with(|| a.digest.cmp(&b.digest))
+        });
+        bags.truncate(limit);
+    }
+    bags
+}
+
+/// Looks up the members of each bag. With `max_members`, larger groups are collapsed to
+/// their first members, so huge groups don't need to be loaded completely.
+fn into_resultbag(
+    db: &Database,
+    similar_files: &[FileDigestBag],
+    max_members: Option<usize>,
+) -> Result<Vec<FileGroup>> {
+    let mut notes = db.get_group_notes()?;
+    let dismissed = db.get_dismissed_groups()?;
     let mut bags = Vec::new();
-    for id_list in similar_files {
-        let files: Vec<FileEntry> = id_list
+    for bag in similar_files {
+        let files: Vec<FileEntry> = bag
+            .id_list
             .iter()
-            .map(|id| {
-                let f = db.lookup_filedigest(*id)?;
-                Ok(FileEntry {
-                    id: f.id,
-                    path: f.path,
-                    size: f.size,
-                })
-            })
+            .take(max_members.unwrap_or(usize::MAX))
+            .map(|id| db.lookup_file_entry(*id))
             .collect::<Result<Vec<_>>>()?;
-        bags.push(files);
+        let group_id = group_id(&bag.digest);
+        bags.push(FileGroup {
+            note: notes.remove(&group_id),
+            dismissed: dismissed.contains(&group_id),
+            hardlinked: bag.hardlinked,
+            unlisted_added: db.newest_first_seen(&bag.id_list[files.len()..])?,
+            group_id,
+            files,
+            member_count: bag.id_list.len(),
+        });
     }
 
-    bags.sort_unstable_by_key(|k| -(k[0].size as i64));
+    bags.sort_unstable_by_key(|k| -(k.files[0].size as i64));
     Ok(bags)
 }
 
-pub fn get_list_of_similar_files(db: &Database) -> Result<Vec<Vec<FileEntry>>> {
-    let files = db.get_all_filedigests()?;
-    log::info!("looking for similarities between {} files", files.len());
-    let similar_files = find_similarities(files);
+/// Like `reclaimable_bytes`, but also counts the members collapsed groups don't list.
+/// Members of a group have the same content, so they all have the size of the listed ones.
+pub fn groups_reclaimable(groups: &[FileGroup], policy: KeepPolicy) -> Reclaimable {
+    let mut reclaimable = reclaimable_bytes(groups.iter().map(|g| g.files.as_slice()), policy);
+    for (group, bytes) in groups.iter().zip(reclaimable.per_group.iter_mut()) {
+        let hidden = (group.member_count - group.files.len()) as u64;
+        *bytes += hidden * group.files.first().map_or(0, |f| f.size);
+    }
+    reclaimable.total = reclaimable.per_group.iter().sum();
+    reclaimable
+}
+
+/// Columns read by `file_entry_from_row`.
+const FILE_ENTRY_COLUMNS: &str =
+    "id, path, size, first_seen, exif_capture_date, exif_camera_make, exif_camera_model, \
+     (SELECT t.path FROM tombstones t \
+      WHERE t.digest = file_digests.digest AND t.deleted_at < file_digests.first_seen \
+      ORDER BY t.deleted_at DESC LIMIT 1), \
+     (SELECT MAX(t.deleted_at) FROM tombstones t \
+      WHERE t.digest = file_digests.digest AND t.deleted_at < file_digests.first_seen), \
+     EXISTS (SELECT 1 FROM protected_files p WHERE p.id = file_digests.id), \
+     (SELECT group_concat(t.tag, char(31)) FROM file_tags t WHERE t.id = file_digests.id), \
+     (SELECT m.created FROM file_meta m WHERE m.id = file_digests.id), \
+     (SELECT m.uid FROM file_meta m WHERE m.id = file_digests.id), \
+     root_id, (SELECT r.path FROM scan_roots r WHERE r.id = file_digests.root_id), \
+     EXISTS (SELECT 1 FROM pending_actions q WHERE q.id = file_digests.id)";
+
+/// Separates the tags read by `FILE_ENTRY_COLUMNS`.
+const TAG_SEPARATOR: char = '\u{1f}';
+
+fn file_entry_from_row(row: &rusqlite::Row) -> rusqlite::Result<FileEntry> {
+    let id = row.get(0)?;
+    let path_string: String = row.get(1)?;
+    Ok(FileEntry {
+        id,
+        path: PathBuf::from(&path_string),
+        size: row.get(2)?,
+        missing: false,
+        first_seen: row.get(3)?,
+        exif: ExifInfo {
+            capture_date: row.get(4)?,
+            camera_make: row.get(5)?,
+            camera_model: row.get(6)?,
+        },
+        previously_deleted: match (row.get(7)?, row.get(8)?) {
+            (Some(path), Some(deleted_at)) => Some(Tombstone { path, deleted_at }),
+            _ => None,
+        },
+        protected: row.get(9)?,
+        tags: {
+            let tags: Option<String> = row.get(10)?;
+            let mut tags: Vec<String> = tags
+                .iter()
+                .flat_map(|t| t.split(TAG_SEPARATOR))
+                .map(String::from)
+                .collect();
+            tags.sort();
+            tags
+        },
+        created: row.get(11)?,
+        owner: row.get(12)?,
+        root_id: row.get(13)?,
+        root: row.get::<_, Option<String>>(14)?.map(PathBuf::from),
+        queued: row.get(15)?,
+        is_image: FileType::Image.matches(Path::new(&path_string)),
+        is_video: FileType::Video.matches(Path::new(&path_string)),
+        file_url: urls::file_url(Path::new(&path_string)),
+        preview_url: urls::preview_url(id),
+        reveal_url: urls::reveal_url(id),
+    })
+}
+
+impl Database {
+    fn lookup_file_entry(&self, file_id: i64) -> Result<FileEntry> {
+        Ok(self.db.query_row(
+            &format!(
+                "SELECT {} FROM file_digests WHERE id = ?1",
+                FILE_ENTRY_COLUMNS
+            ),
+            params![file_id],
+            file_entry_from_row,
+        )?)
+    }
+
+    /// Current members of the exact-duplicate group with the given id, skipping the first
+    /// `offset` ones and returning at most `limit`.
+    fn get_group_members(
+        &self,
+        group_id: &str,
+        offset: usize,
+        limit: Option<usize>,
+    ) -> Result<Vec<FileEntry>> {
+        let (range, start, end) = group_digest_range(group_id)?;
+        let mut stmt = self.db.prepare(&format!(
+            "SELECT {} FROM file_digests WHERE {} ORDER BY id LIMIT ?3 OFFSET ?4",
+            FILE_ENTRY_COLUMNS, range
+        ))?;
+        let limit = limit.map_or(-1, |l| l as i64);
+        let files: Result<Vec<_>, _> = stmt
+            .query_map(
+                params![start, end, limit, offset as i64],
+                file_entry_from_row,
+            )?
+            .collect();
+        Ok(files?)
+    }
+
+    /// Files that were indexed after a file with the same content was deleted, newest
+    /// first.
+    pub fn get_redownloads(&self) -> Result<Vec<FileEntry>> {
+        let mut stmt = self.db.prepare(&format!(
+            "SELECT {} FROM file_digests WHERE EXISTS \
+             (SELECT 1 FROM tombstones t \
+              WHERE t.digest = file_digests.digest AND t.deleted_at < file_digests.first_seen) \
+             ORDER BY first_seen DESC, id",
+            FILE_ENTRY_COLUMNS
+        ))?;
+        let files: Result<Vec<_>, _> = stmt.query_map([], file_entry_from_row)?.collect();
+        Ok(files?)
+    }
+
+    /// The newest first_seen of the files, None without files. Files from before it was
+    /// recorded count as added now.
+    fn newest_first_seen(&self, ids: &[i64]) -> Result<Option<i64>> {
+        let mut stmt = self
+            .db
+            .prepare_cached("SELECT first_seen FROM file_digests WHERE id = ?1")?;
+        let mut newest = None;
+        for id in ids {
+            let first_seen: Option<i64> = stmt.query_row(params![id], |row| row.get(0))?;
+            newest = newest.max(Some(first_seen.unwrap_or(i64::MAX)));
+        }
+        Ok(newest)
+    }
+
+    fn count_group_members(&self, group_id: &str) -> Result<usize> {
+        let (range, start, end) = group_digest_range(group_id)?;
+        let count: i64 = self.db.query_row(
+            &format!("SELECT COUNT(*) FROM file_digests WHERE {}", range),
+            params![start, end],
+            |row| row.get(0),
+        )?;
+        Ok(count as usize)
+    }
+}
+
+/// Looks up a single duplicate group by its id. Returns None if the group no longer has
+/// more than one member.
+pub fn get_group(db: &Database, group_id: &str) -> Result<Option<FileGroup>> {
+    let files = db.get_group_members(group_id, 0, None)?;
+    if files.len() < 2 {
+        return Ok(None);
+    }
+    Ok(Some(FileGroup {
+        group_id: group_id.to_string(),
+        member_count: files.len(),
+        files,
+        note: db.get_group_note(group_id)?,
+        dismissed: db.is_dismissed(group_id)?,
+        hardlinked: false,
+        unlisted_added: None,
+    }))
+}
+
+/// One page of the members of a duplicate group, for groups too large to show at once.
+/// `files` holds the members of the requested page, `member_count` all of them.
+pub fn get_group_page(
+    db: &Database,
+    group_id: &str,
+    page: usize,
+    page_size: usize,
+) -> Result<Option<FileGroup>> {
+    let member_count = db.count_group_members(group_id)?;
+    if member_count < 2 {
+        return Ok(None);
+    }
+    let files = db.get_group_members(group_id, page * page_size, Some(page_size))?;
+    Ok(Some(FileGroup {
+        group_id: group_id.to_string(),
+        files,
+        member_count,
+        note: db.get_group_note(group_id)?,
+        dismissed: db.is_dismissed(group_id)?,
+        hardlinked: false,
+        unlisted_added: None,
+    }))
+}
+
+/// Parses a YYYY-MM-DD date into unix seconds at midnight UTC.
+pub fn parse_date(s: &str) -> Result<i64> {
+    let parts: Vec<&str> = s.split('-').collect();
+    let invalid = || anyhow!("Invalid date {}, expected YYYY-MM-DD", s);
+    if parts.len() != 3 {
+        return Err(invalid());
+    }
+    let year: i64 = parts[0].parse().map_err(|_| invalid())?;
+    let month: i64 = parts[1].parse().map_err(|_| invalid())?;
+    let day: i64 = parts[2].parse().map_err(|_| invalid())?;
+    let leap_year = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days_in_month = match month {
+        2 if leap_year => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    };
+    if !(1..=12).contains(&month) || !(1..=days_in_month).contains(&day) {
+        return Err(invalid());
+    }
+    // days since 1970-01-01 in the proleptic Gregorian calendar
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    Ok(days * 24 * 60 * 60)
+}
+
+/// Keeps the groups with at least one file first seen at or after `after` (unix seconds).
+/// Files from before first_seen was recorded might be new, so they count as well.
+/// Collapsed groups are checked by all their members.
+pub fn filter_added_after(groups: Vec<FileGroup>, after: i64) -> Vec<FileGroup> {
+    groups
+        .into_iter()
+        .filter(|g| added_since(g, after))
+        .collect()
+}
+
+fn added_since(group: &FileGroup, after: i64) -> bool {
+    matches!(group.unlisted_added, Some(t) if t >= after)
+        || group
+            .files
+            .iter()
+            .any(|f| !matches!(f.first_seen, Some(t) if t < after))
+}
+
+/// Image formats besides the ones that carry EXIF metadata
+const IMAGE_EXTENSIONS: [&str; 6] = ["png", "gif", "bmp", "webp", "heic", "heif"];
+
+/// Kinds of files that groups can be filtered by, going by the file extension.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileType {
+    Video,
+    Image,
+    Audio,
+}
+
+impl FileType {
+    pub fn matches(&self, path: &Path) -> bool {
+        match self {
+            FileType::Video => paths::has_extension(path, &videohash::VIDEO_EXTENSIONS),
+            FileType::Audio => paths::has_extension(path, &videohash::AUDIO_EXTENSIONS),
+            FileType::Image => {
+                paths::has_extension(path, &IMAGE_EXTENSIONS) || exif::has_exif_extension(path)
+            }
+        }
+    }
+}
+
+impl FromStr for FileType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<FileType> {
+        match s {
+            "video" => Ok(FileType::Video),
+            "image" => Ok(FileType::Image),
+            "audio" => Ok(FileType::Audio),
+            _ => Err(anyhow!(
+                "Unknown file type {}, use video, image or audio",
+                s
+            )),
+        }
+    }
+}
+
+fn deserialize_date<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<i64>, D::Error> {
+    let date: Option<String> = Option::deserialize(deserializer)?;
+    date.map(|d| parse_date(&d).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+/// Which groups are listed, shared by the index page and bulk operations. A group
+/// matches if at least one of its files matches the file criteria.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct GroupFilter {
+    /// Only files whose path contains this
+    pub path: Option<String>,
+    /// Only files of at least this many bytes
+    pub min_size: Option<u64>,
+    #[serde(rename = "type")]
+    pub file_type: Option<FileType>,
+    /// Only groups with files in more than one directory
+    pub cross_dirs: bool,
+    /// Only files below the scan root with this label or path, see `scanroots::root_label`
+    pub root: Option<String>,
+    /// Only groups with files below more than one scan root
+    pub cross_roots: bool,
+    /// See `filter_added_after`, given as YYYY-MM-DD
+    #[serde(deserialize_with = "deserialize_date")]
+    pub added_after: Option<i64>,
+    /// Also list groups that were dismissed
+    pub include_dismissed: bool,
+    /// Also list groups whose members are all hardlinks of one file
+    pub show_hardlinks: bool,
+}
+
+impl GroupFilter {
+    pub fn matches_file(&self, file: &FileEntry) -> bool {
+        if let Some(p) = &self.path {
+            if !file.path.to_string_lossy().contains(p.as_str()) {
+                return false;
+            }
+        }
+        if let Some(min_size) = self.min_size {
+            if file.size < min_size {
+                return false;
+            }
+        }
+        if let Some(label) = &self.root {
+            let below = file.root.as_deref().is_some_and(|root| {
+                scanroots::root_label(root) == *label || root == Path::new(label)
+            });
+            if !below {
+                return false;
+            }
+        }
+        match self.file_type {
+            Some(t) => t.matches(&file.path),
+            None => true,
+        }
+    }
+
+    pub fn matches_group(&self, group: &FileGroup) -> bool {
+        if group.dismissed && !self.include_dismissed {
+            return false;
+        }
+        if group.hardlinked && !self.show_hardlinks {
+            return false;
+        }
+        if !group.files.iter().any(|f| self.matches_file(f)) {
+            return false;
+        }
+        if self.cross_dirs {
+            let mut dirs: Vec<Option<&Path>> =
+                group.files.iter().map(|f| f.path.parent()).collect();
+            dirs.sort();
+            dirs.dedup();
+            if dirs.len() < 2 {
+                return false;
+            }
+        }
+        if self.cross_roots {
+            let roots: HashSet<i64> = group.files.iter().filter_map(|f| f.root_id).collect();
+            if roots.len() < 2 {
+                return false;
+            }
+        }
+        match self.added_after {
+            Some(after) => added_since(group, after),
+            None => true,
+        }
+    }
+
+    pub fn apply(&self, groups: Vec<FileGroup>) -> Vec<FileGroup> {
+        groups
+            .into_iter()
+            .filter(|g| self.matches_group(g))
+            .collect()
+    }
+}
+
+/// Remembers for a few seconds whether files exist, so that rendering a page doesn't
+/// need to touch every file on disk each time.
+pub struct ExistenceCache {
+    ttl: Duration,
+    entries: HashMap<PathBuf, (Instant, bool)>,
+}
+
+impl ExistenceCache {
+    pub fn new(ttl: Duration) -> ExistenceCache {
+        ExistenceCache {
+            ttl,
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Forgets all files, e.g. after another process changed the database.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Sets `missing` on every file of the groups that no longer exists on disk.
+    pub fn mark_missing(&mut self, groups: &mut [FileGroup]) {
+        let now = Instant::now();
+        let ttl = self.ttl;
+        self.entries
+            .retain(|_, (checked_at, _)| now.duration_since(*checked_at) < ttl);
+
+        let unchecked: Vec<PathBuf> = groups
+            .iter()
+            .flat_map(|g| g.files.iter())
+            .filter(|f| !self.entries.contains_key(&f.path))
+            .map(|f| f.path.clone())
+            .collect();
+        let checked: Vec<(PathBuf, bool)> = unchecked
+            .into_par_iter()
+            .map(|path| {
+                let exists = fs::metadata(&path).is_ok();
+                (path, exists)
+            })
+            .collect();
+        for (path, exists) in checked {
+            self.entries.insert(path, (now, exists));
+        }
+
+        for f in groups.iter_mut().flat_map(|g| g.files.iter_mut()) {
+            f.missing = !self.entries[&f.path].1;
+        }
+    }
+}
+
+pub fn get_list_of_similar_files(db: &Database) -> Result<Vec<FileGroup>> {
+    get_collapsed_list_of_similar_files(db, None)
+}
+
+/// Groups with more than `max_members` files only list their first members.
+pub fn get_collapsed_list_of_similar_files(
+    db: &Database,
+    max_members: Option<usize>,
+) -> Result<Vec<FileGroup>> {
+    let similar_files = find_duplicate_bags(db)?;
     log::info!("creating result bags");
-    let results = into_resultbag(&db, &similar_files)?;
+    let results = into_resultbag(&db, &similar_files, max_members)?;
     Ok(results)
 }
 
+/// The ids of all groups and the bytes taken by all copies but one, without looking up
+/// the files of each group.
+pub fn duplicate_totals(db: &Database) -> Result<(HashSet<String>, u64)> {
+    let bags = find_duplicate_bags(db)?;
+    let wasted = bags.iter().map(|b| b.wasted_size()).sum();
+    Ok((bags.iter().map(|b| group_id(&b.digest)).collect(), wasted))
+}
+
+/// The groups with the most wasted space, and how many there are in total.
+#[derive(Debug)]
+pub struct LargestGroups {
+    /// Ordered like the full list of groups
+    pub groups: Vec<FileGroup>,
+    pub num_groups: usize,
+    /// Bytes taken by all copies but one of every group, not only the listed ones
+    pub wasted_total: u64,
+}
+
+/// Like `get_collapsed_list_of_similar_files`, but only looks up the members of the
+/// `limit` groups with the most wasted space, which is much faster for large databases.
+pub fn get_largest_similar_files(
+    db: &Database,
+    max_members: Option<usize>,
+    limit: usize,
+) -> Result<LargestGroups> {
+    let similar_files = find_duplicate_bags(db)?;
+    let num_groups = similar_files.len();
+    let wasted_total = similar_files.iter().map(|b| b.wasted_size()).sum();
+    let similar_files = largest_bags(similar_files, limit);
+    log::info!(
+        "creating result bags for {} of {} groups",
+        similar_files.len(),
+        num_groups
+    );
+    Ok(LargestGroups {
+        groups: into_resultbag(db, &similar_files, max_members)?,
+        num_groups,
+        wasted_total,
+    })
+}
+
+/// Groups the files that are not excluded by digest, without looking up their members.
+fn find_duplicate_bags(db: &Database) -> Result<Vec<FileDigestBag>> {
+    let mut files = db.get_all_digest_rows()?;
+    let excluded = db.get_excluded_digest_set()?;
+    if !excluded.is_empty() {
+        let num_files = files.len();
+        files.retain(|(_, digest, _)| !excluded.contains(digest));
+        log::info!(
+            "{} files with an excluded digest are not reported",
+            num_files - files.len()
+        );
+    }
+    log::info!("looking for similarities between {} files", files.len());
+    Ok(merge_same_files(
+        find_similarities(files),
+        &db.get_file_identities()?,
+    ))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use rusqlite::params;
+    use crate::database::FileDigest;
+    use std::collections::HashSet;
 
     impl FileEntry {
         fn new(id: i64, path: &str, size: u64) -> FileEntry {
@@ -97,10 +1001,30 @@ mod tests {
                 id: id,
                 path: PathBuf::from(path),
                 size: size,
+                missing: false,
+                first_seen: None,
+                exif: ExifInfo::default(),
+                previously_deleted: None,
+                protected: false,
+                queued: false,
+                tags: Vec::new(),
+                created: None,
+                owner: None,
+                root_id: None,
+                root: None,
+                is_image: false,
+                is_video: false,
+                file_url: urls::file_url(Path::new(path)),
+                preview_url: urls::preview_url(id),
+                reveal_url: urls::reveal_url(id),
             }
         }
     }
 
+    fn id_lists(bags: Vec<FileDigestBag>) -> HashSet<Vec<i64>> {
+        bags.into_iter().map(|b| b.id_list).collect()
+    }
+
     #[test]
     fn test_resultbag() -> Result<()> {
         let db = Database::new("test.sqlite", true)?;
@@ -112,38 +1036,596 @@ mod tests {
                 (7, '/tmp/g', x'aaaaaaad', 4)",
             params![],
         )?;
-        let testfiles = db.get_all_filedigests()?;
+        let testfiles = db.get_all_digest_rows()?;
         let similar_files = find_similarities(testfiles);
-        let results = into_resultbag(&db, &similar_files)?;
+        let results = into_resultbag(&db, &similar_files, None)?;
 
         // TODO: this relies on the DB to retrieve filedigests in the order they were inserted
         let target = vec![
+            FileGroup {
+                group_id: "aaaaaaac".to_string(),
+                files: vec![
+                    FileEntry::new(4, "/tmp/e", 3),
+                    FileEntry::new(6, "/tmp/f", 3),
+                ],
+                member_count: 2,
+                note: None,
+                dismissed: false,
+                hardlinked: false,
+                unlisted_added: None,
+            },
+            FileGroup {
+                group_id: "aaaaaaaa".to_string(),
+                files: vec![
+                    FileEntry::new(1, "/tmp/a", 2),
+                    FileEntry::new(2, "/tmp/b", 2),
+                ],
+                member_count: 2,
+                note: None,
+                dismissed: false,
+                hardlinked: false,
+                unlisted_added: None,
+            },
+            FileGroup {
+                group_id: "aaaaaaab".to_string(),
+                files: vec![
+                    FileEntry::new(3, "/tmp/d", 1),
+                    FileEntry::new(5, "/tmp/c", 1),
+                ],
+                member_count: 2,
+                note: None,
+                dismissed: false,
+                hardlinked: false,
+                unlisted_added: None,
+            },
+        ];
+        assert_eq!(results, target);
+        Ok(())
+    }
+
+    #[test]
+    fn test_group_id_is_stable() -> Result<()> {
+        let digest_a = vec![0xaa; 64];
+        let digest_b = vec![0xbb; 64];
+        assert_eq!(group_id(&digest_a), "aaaaaaaaaaaaaaaa");
+
+        // same content indexed in a different order (and thus with different ids)
+        let db1 = Database::new("test_group_id_is_stable1.sqlite", true)?;
+        let db2 = Database::new("test_group_id_is_stable2.sqlite", true)?;
+        for (db, order) in [
+            (&db1, ["/a", "/b", "/c", "/d"]),
+            (&db2, ["/d", "/c", "/b", "/a"]),
+        ] {
+            for path in order.iter() {
+                let digest = if *path < "/c" { &digest_a } else { &digest_b };
+                db.insert_filedigest(&FileDigest::new(0, path, digest.clone(), 1))?;
+            }
+        }
+        let ids = |db: &Database| -> Result<HashSet<String>> {
+            Ok(get_list_of_similar_files(db)?
+                .into_iter()
+                .map(|g| g.group_id)
+                .collect())
+        };
+        assert_eq!(ids(&db1)?, ids(&db2)?);
+
+        let group = get_group(&db1, &group_id(&digest_a))?.unwrap();
+        let paths: Vec<_> = group.files.iter().map(|f| f.path.clone()).collect();
+        assert_eq!(paths, vec![PathBuf::from("/a"), PathBuf::from("/b")]);
+
+        // once a group is down to one member, it's gone
+        db1.delete_filedigest(group.files[0].id)?;
+        assert_eq!(get_group(&db1, &group_id(&digest_a))?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_group_id_must_be_complete() -> Result<()> {
+        let db = Database::new("test_group_id_must_be_complete.sqlite", true)?;
+        for (path, digest) in [("/a", 0xaa), ("/b", 0xaa), ("/c", 0xff), ("/d", 0xff)] {
+            db.insert_filedigest(&FileDigest::new(0, path, vec![digest; 64], 1))?;
+        }
+        assert!(is_group_id("aaaaaaaaaaaaaaaa"));
+        for prefix in ["", "aa", "aaaaaaaaaaaaaaaaaa", "AAAAAAAAAAAAAAAA"] {
+            assert!(!is_group_id(prefix));
+            assert!(get_group(&db, prefix).is_err());
+        }
+        assert_eq!(get_group(&db, "aaaaaaaaaaaaaaab")?, None);
+        // the last id has no upper bound
+        let group = get_group(&db, "ffffffffffffffff")?.unwrap();
+        assert_eq!(group.member_count, 2);
+        assert_eq!(
+            get_group_page(&db, "ffffffffffffffff", 0, 1)?
+                .unwrap()
+                .member_count,
+            2
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_reclaimable_bytes() {
+        let groups = [
             vec![
-                FileEntry::new(4, "/tmp/e", 3),
-                FileEntry::new(6, "/tmp/f", 3),
+                FileEntry::new(1, "/tmp/a", 10),
+                FileEntry::new(2, "/tmp/b", 30),
+                FileEntry::new(3, "/tmp/c", 20),
             ],
             vec![
-                FileEntry::new(1, "/tmp/a", 2),
-                FileEntry::new(2, "/tmp/b", 2),
+                FileEntry::new(4, "/tmp/d", 5),
+                FileEntry::new(5, "/tmp/e", 5),
             ],
-            vec![
-                FileEntry::new(3, "/tmp/d", 1),
-                FileEntry::new(5, "/tmp/c", 1),
+        ];
+        let slices = || groups.iter().map(|g| g.as_slice());
+
+        let first = reclaimable_bytes(slices(), KeepPolicy::First);
+        assert_eq!(first.per_group, vec![50, 5]);
+        assert_eq!(first.total, 55);
+
+        let largest = reclaimable_bytes(slices(), KeepPolicy::Largest);
+        assert_eq!(largest.per_group, vec![30, 5]);
+        assert_eq!(largest.total, 35);
+
+        // works on groups of references, too
+        let refs: Vec<Vec<&FileEntry>> = groups.iter().map(|g| g.iter().collect()).collect();
+        let from_refs = reclaimable_bytes(refs.iter().map(|g| g.as_slice()), KeepPolicy::Largest);
+        assert_eq!(from_refs, largest);
+    }
+
+    #[test]
+    fn test_reclaimable_bytes_keeps_protected_files() {
+        let mut files = vec![
+            FileEntry::new(1, "/tmp/a", 10),
+            FileEntry::new(2, "/tmp/b", 10),
+            FileEntry::new(3, "/tmp/c", 10),
+        ];
+        files[1].protected = true;
+        let reclaimable = reclaimable_bytes(std::iter::once(files.as_slice()), KeepPolicy::First);
+        assert_eq!(reclaimable.total, 20);
+        files[0].protected = true;
+        let reclaimable = reclaimable_bytes(std::iter::once(files.as_slice()), KeepPolicy::First);
+        assert_eq!(reclaimable.total, 10);
+    }
+
+    #[test]
+    fn test_excluded_digests_are_not_reported() -> Result<()> {
+        let db = Database::new("test_excluded_digests_are_not_reported.sqlite", true)?;
+        for (id, digest) in [(1, 1), (2, 1), (3, 2), (4, 2), (5, 2)].iter() {
+            db.insert_filedigest(&FileDigest::new(
+                *id,
+                &format!("/tmp/nonexistent/{}", id),
+                vec![*digest; 8],
+                1,
+            ))?;
+        }
+        db.exclude_digest(&[2; 8], None)?;
+        let groups = get_list_of_similar_files(&db)?;
+        let ids: Vec<&str> = groups.iter().map(|g| g.group_id.as_str()).collect();
+        assert_eq!(ids, [group_id(&[1; 8])]);
+        assert_eq!(db.count_excluded_files()?, 3);
+        db.remove_excluded_digest(&[2; 8])?;
+        assert_eq!(get_list_of_similar_files(&db)?.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_largest_similar_files() -> Result<()> {
+        let db = Database::new("test_largest_similar_files.sqlite", true)?;
+        // (digest, number of copies, size): 10, 12, 100 and 2 bytes wasted
+        let groups = [(1, 2, 10), (2, 5, 3), (3, 2, 100), (4, 3, 1)];
+        let mut id = 0;
+        for (digest, copies, size) in groups.iter() {
+            for _ in 0..*copies {
+                id += 1;
+                let path = format!("/tmp/nonexistent/{}", id);
+                db.insert_filedigest(&FileDigest::new(id, &path, vec![*digest; 8], *size))?;
+            }
+        }
+        db.insert_filedigest(&FileDigest::new(100, "/tmp/unique", vec![5; 8], 1000))?;
+
+        let full = get_list_of_similar_files(&db)?;
+        let wasted = |g: &FileGroup| g.files[0].size * (g.member_count as u64 - 1);
+        let mut by_wasted: Vec<&FileGroup> = full.iter().collect();
+        by_wasted.sort_by_key(|g| std::cmp::Reverse(wasted(g)));
+        for limit in 0..=5 {
+            let largest = get_largest_similar_files(&db, None, limit)?;
+            assert_eq!(largest.num_groups, 4);
+            assert_eq!(largest.wasted_total, 124);
+            // the same groups as the top of the full list, in the same order
+            let top: HashSet<&str> = by_wasted
+                .iter()
+                .take(limit)
+                .map(|g| g.group_id.as_str())
+                .collect();
+            let expected: Vec<&FileGroup> = full
+                .iter()
+                .filter(|g| top.contains(g.group_id.as_str()))
+                .collect();
+            let groups: Vec<&FileGroup> = largest.groups.iter().collect();
+            assert_eq!(groups, expected);
+        }
+        let largest = get_largest_similar_files(&db, None, 2)?;
+        let ids: Vec<&str> = largest.groups.iter().map(|g| g.group_id.as_str()).collect();
+        assert_eq!(ids, [group_id(&[3; 8]), group_id(&[2; 8])]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_root_filters() -> Result<()> {
+        let db = Database::new("test_root_filters.sqlite", true)?;
+        let files = [
+            ("/photos/a.jpg", 1),
+            ("/photos/b.jpg", 1),
+            ("/photos/c.jpg", 2),
+            ("/mnt/backup/c.jpg", 2),
+            ("/mnt/backup/d.jpg", 3),
+            ("/mnt/backup/e.jpg", 3),
+        ];
+        for (path, digest) in files.iter() {
+            db.insert_filedigest(&FileDigest::new(0, path, vec![*digest; 8], 1))?;
+        }
+        db.record_scan_root(Path::new("/photos"), paths::PathCase::Sensitive)?;
+        db.record_scan_root(Path::new("/mnt/backup"), paths::PathCase::Sensitive)?;
+        let ids = |filter: GroupFilter| -> Result<Vec<String>> {
+            Ok(filter
+                .apply(get_list_of_similar_files(&db)?)
+                .into_iter()
+                .map(|g| g.group_id)
+                .collect())
+        };
+        let mut below_backup = ids(serde_json::from_str(r#"{"root": "backup"}"#)?)?;
+        below_backup.sort();
+        assert_eq!(below_backup, [group_id(&[2; 8]), group_id(&[3; 8])]);
+        let by_path = ids(serde_json::from_str(r#"{"root": "/photos"}"#)?)?;
+        assert_eq!(by_path.len(), 2);
+        assert!(ids(serde_json::from_str(r#"{"root": "mnt"}"#)?)?.is_empty());
+        let cross_roots = ids(serde_json::from_str(r#"{"cross_roots": true}"#)?)?;
+        assert_eq!(cross_roots, [group_id(&[2; 8])]);
+
+        let group = get_group(&db, &group_id(&[2; 8]))?.unwrap();
+        assert_eq!(group.files[1].root, Some(PathBuf::from("/mnt/backup")));
+        assert_eq!(group.files[1].root_id, Some(2));
+        Ok(())
+    }
+
+    #[test]
+    fn test_dismissed_protected_and_tags() -> Result<()> {
+        let db = Database::new("test_dismissed_protected_and_tags.sqlite", true)?;
+        for (id, digest) in [(1, 1), (2, 1), (3, 2), (4, 2)].iter() {
+            db.insert_filedigest(&FileDigest::new(
+                *id,
+                &format!("/tmp/nonexistent/{}", id),
+                vec![*digest; 8],
+                1,
+            ))?;
+        }
+        let groups = get_list_of_similar_files(&db)?;
+        let filter: GroupFilter = serde_json::from_str(r#"{"path": "/tmp/nonexistent/1"}"#)?;
+        db.apply_bulk_action(&groups, &filter, &"dismiss".parse()?, false)?;
+        let filter: GroupFilter = serde_json::from_str(r#"{"path": "/tmp/nonexistent/3"}"#)?;
+        db.apply_bulk_action(&groups, &filter, &"protect".parse()?, false)?;
+        db.apply_bulk_action(&groups, &filter, &"tag=master".parse()?, false)?;
+        db.apply_bulk_action(&groups, &filter, &"tag=edited".parse()?, false)?;
+
+        let ids = |filter: &GroupFilter| -> Result<Vec<String>> {
+            Ok(filter
+                .apply(get_list_of_similar_files(&db)?)
+                .into_iter()
+                .map(|g| g.group_id)
+                .collect())
+        };
+        assert_eq!(ids(&GroupFilter::default())?, [group_id(&[2; 8])]);
+        let include_dismissed = GroupFilter {
+            include_dismissed: true,
+            ..Default::default()
+        };
+        assert_eq!(ids(&include_dismissed)?.len(), 2);
+
+        let group = get_group(&db, &group_id(&[2; 8]))?.unwrap();
+        assert!(!group.dismissed);
+        assert!(group.files[0].protected);
+        assert_eq!(group.files[0].tags, ["edited", "master"]);
+        assert!(!group.files[1].protected);
+        assert!(group.files[1].tags.is_empty());
+        assert!(get_group(&db, &group_id(&[1; 8]))?.unwrap().dismissed);
+        Ok(())
+    }
+
+    #[test]
+    fn test_collapsed_groups() -> Result<()> {
+        let db = Database::new("test_collapsed_groups.sqlite", true)?;
+        let tx = db.db.unchecked_transaction()?;
+        for i in 0..120 {
+            let path = format!("/tmp/cache/{}", i);
+            db.insert_filedigest(&FileDigest::new(0, &path, vec![1; 8], 10))?;
+        }
+        db.insert_filedigest(&FileDigest::new(0, "/tmp/x", vec![2; 8], 20))?;
+        db.insert_filedigest(&FileDigest::new(0, "/tmp/y", vec![2; 8], 20))?;
+        tx.commit()?;
+
+        let groups = get_collapsed_list_of_similar_files(&db, Some(50))?;
+        let big = groups
+            .iter()
+            .find(|g| g.group_id == group_id(&[1; 8]))
+            .unwrap();
+        assert_eq!(big.files.len(), 50);
+        assert_eq!(big.member_count, 120);
+        let small = groups
+            .iter()
+            .find(|g| g.group_id == group_id(&[2; 8]))
+            .unwrap();
+        assert_eq!(small.files.len(), 2);
+        assert_eq!(small.member_count, 2);
+
+        // wasted bytes include the members that aren't listed
+        let reclaimable = groups_reclaimable(&groups, KeepPolicy::First);
+        assert_eq!(reclaimable.total, 119 * 10 + 20);
+
+        let page = get_group_page(&db, &big.group_id, 2, 50)?.unwrap();
+        assert_eq!(page.member_count, 120);
+        let paths: Vec<_> = page.files.iter().map(|f| f.path.clone()).collect();
+        assert_eq!(paths.len(), 20);
+        assert_eq!(paths[0], PathBuf::from("/tmp/cache/100"));
+        assert!(get_group_page(&db, &big.group_id, 3, 50)?
+            .unwrap()
+            .files
+            .is_empty());
+        assert_eq!(get_group_page(&db, &group_id(&[3; 8]), 0, 50)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_date() -> Result<()> {
+        assert_eq!(parse_date("1970-01-01")?, 0);
+        assert_eq!(parse_date("2000-03-01")?, 951868800);
+        assert_eq!(parse_date("2024-02-29")?, 1709164800);
+        assert!(parse_date("2024-13-01").is_err());
+        assert!(parse_date("2024-02-31").is_err());
+        assert!(parse_date("2023-02-29").is_err());
+        assert!(parse_date("1900-02-29").is_err());
+        assert!(parse_date("2000-02-29").is_ok());
+        assert!(parse_date("2024-04-31").is_err());
+        assert!(parse_date("2024-12-31").is_ok());
+        assert!(parse_date("yesterday").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_mark_missing() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let present_a = dir.path().join("a");
+        let present_b = dir.path().join("b");
+        fs::write(&present_a, "x")?;
+        fs::write(&present_b, "x")?;
+        let missing = dir.path().join("c");
+
+        let mut groups = vec![FileGroup {
+            group_id: "00".to_string(),
+            files: vec![
+                FileEntry::new(1, present_a.to_str().unwrap(), 10),
+                FileEntry::new(2, missing.to_str().unwrap(), 10),
+                FileEntry::new(3, present_b.to_str().unwrap(), 10),
             ],
+            member_count: 3,
+            note: None,
+            dismissed: false,
+            hardlinked: false,
+            unlisted_added: None,
+        }];
+        let mut cache = ExistenceCache::new(Duration::from_secs(60));
+        cache.mark_missing(&mut groups);
+        let flags: Vec<_> = groups[0].files.iter().map(|f| f.missing).collect();
+        assert_eq!(flags, vec![false, true, false]);
+
+        // the missing file is neither kept nor counted as reclaimable
+        let reclaimable = |groups: &[FileGroup]| {
+            reclaimable_bytes(groups.iter().map(|g| g.files.as_slice()), KeepPolicy::First).total
+        };
+        assert_eq!(reclaimable(&groups), 10);
+
+        // results are cached until they expire
+        fs::remove_file(&present_a)?;
+        cache.mark_missing(&mut groups);
+        assert!(!groups[0].files[0].missing);
+        let mut cache = ExistenceCache::new(Duration::from_secs(0));
+        cache.mark_missing(&mut groups);
+        assert!(groups[0].files[0].missing);
+        assert_eq!(reclaimable(&groups), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_keep_policy_from_str() {
+        assert_eq!("first".parse::<KeepPolicy>().unwrap(), KeepPolicy::First);
+        assert_eq!(
+            "largest".parse::<KeepPolicy>().unwrap(),
+            KeepPolicy::Largest
+        );
+        assert_eq!("exif".parse::<KeepPolicy>().unwrap(), KeepPolicy::Exif);
+        assert_eq!("oldest".parse::<KeepPolicy>().unwrap(), KeepPolicy::Oldest);
+        assert_eq!(
+            "owner:1000".parse::<KeepPolicy>().unwrap(),
+            KeepPolicy::Owner(1000)
+        );
+        assert_eq!(
+            "first-alphabetical".parse::<KeepPolicy>().unwrap(),
+            KeepPolicy::FirstAlphabetical
+        );
+        assert_eq!("newest".parse::<KeepPolicy>().unwrap(), KeepPolicy::Newest);
+        assert_eq!(
+            "in-dir:/data/archive".parse::<KeepPolicy>().unwrap(),
+            KeepPolicy::InDir(PathBuf::from("/data/archive"))
+        );
+        assert!("smallest".parse::<KeepPolicy>().is_err());
+        assert!("owner:".parse::<KeepPolicy>().is_err());
+        assert!("in-dir:".parse::<KeepPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_group_filter() -> Result<()> {
+        let group = |id: &str, files: Vec<FileEntry>| FileGroup {
+            group_id: id.to_string(),
+            member_count: files.len(),
+            files,
+            note: None,
+            dismissed: false,
+            hardlinked: false,
+            unlisted_added: None,
+        };
+        let groups = || {
+            vec![
+                group(
+                    "same_dir",
+                    vec![
+                        FileEntry::new(1, "/media/a/x.mp4", 100),
+                        FileEntry::new(2, "/media/a/y.mp4", 100),
+                    ],
+                ),
+                group(
+                    "cross_dirs",
+                    vec![
+                        FileEntry::new(3, "/media/a/z.jpg", 10),
+                        FileEntry::new(4, "/backup/z.JPG", 10),
+                    ],
+                ),
+            ]
+        };
+        let ids = |filter: GroupFilter| -> Vec<String> {
+            filter
+                .apply(groups())
+                .into_iter()
+                .map(|g| g.group_id)
+                .collect()
+        };
+        assert_eq!(ids(GroupFilter::default()).len(), 2);
+        let by_path = GroupFilter {
+            path: Some("backup".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(ids(by_path), ["cross_dirs"]);
+        let by_size = GroupFilter {
+            min_size: Some(50),
+            ..Default::default()
+        };
+        assert_eq!(ids(by_size), ["same_dir"]);
+        let images_across_dirs: GroupFilter =
+            serde_json::from_str(r#"{"type": "image", "cross_dirs": true}"#)?;
+        assert_eq!(ids(images_across_dirs), ["cross_dirs"]);
+        let videos_across_dirs = GroupFilter {
+            file_type: Some(FileType::Video),
+            cross_dirs: true,
+            ..Default::default()
+        };
+        assert!(ids(videos_across_dirs).is_empty());
+        let filter: GroupFilter = serde_json::from_str(r#"{"added_after": "2024-05-08"}"#)?;
+        assert_eq!(filter.added_after, Some(parse_date("2024-05-08")?));
+        assert!(serde_json::from_str::<GroupFilter>(r#"{"added_after": "May"}"#).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_keep_policy_exif() -> Result<()> {
+        let db = Database::new("test_keep_policy_exif.sqlite", true)?;
+        let mut original = FileDigest::new(0, "/tmp/b.jpg", vec![1; 8], 10);
+        original.exif.capture_date = Some("2021-06-13 14:15:16".to_string());
+        db.insert_filedigest(&FileDigest::new(0, "/tmp/a.jpg", vec![1; 8], 10))?;
+        db.insert_filedigest(&original)?;
+        let groups = get_list_of_similar_files(&db)?;
+        assert_eq!(groups[0].files[1].exif, original.exif);
+        assert_eq!(KeepPolicy::Exif.keeper(&groups[0].files), 1);
+
+        // without any EXIF, the first file is kept
+        let files = [
+            FileEntry::new(1, "/tmp/a", 1),
+            FileEntry::new(2, "/tmp/b", 2),
         ];
-        assert_eq!(results, target);
+        assert_eq!(KeepPolicy::Exif.keeper(&files), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_keep_policy_metadata() -> Result<()> {
+        let db = Database::new("test_keep_policy_metadata.sqlite", true)?;
+        for path in ["/tmp/copy", "/tmp/original", "/tmp/unknown", "/tmp/newer"].iter() {
+            db.insert_filedigest(&FileDigest::new(0, path, vec![1; 8], 10))?;
+        }
+        // the copy has no birth time, the last file was never collected
+        db.db.execute(
+            "INSERT INTO file_meta (id, created, uid) VALUES \
+             (1, NULL, 0), (2, 100, 1000), (4, 200, 1000)",
+            params![],
+        )?;
+        let groups = get_list_of_similar_files(&db)?;
+        let files = &groups[0].files;
+        assert_eq!(files[1].created, Some(100));
+        assert_eq!(files[2].owner, None);
+        assert_eq!(KeepPolicy::Oldest.keeper(files), 1);
+        assert_eq!(KeepPolicy::Owner(1000).keeper(files), 1);
+        assert_eq!(KeepPolicy::Owner(0).keeper(files), 0);
+        // unknown owners fall back to the first file
+        assert_eq!(KeepPolicy::Owner(1234).keeper(files), 0);
+
+        let files = [
+            FileEntry::new(1, "/tmp/a", 1),
+            FileEntry::new(2, "/tmp/b", 2),
+        ];
+        assert_eq!(KeepPolicy::Oldest.keeper(&files), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_same_files_through_other_mounts() -> Result<()> {
+        let db = Database::new("test_same_files_through_other_mounts.sqlite", true)?;
+        let files = [
+            // a bind mount of the library, and a real copy
+            ("/srv/media/a.jpg", 1),
+            ("/export/share/a.jpg", 1),
+            ("/backup/a.jpg", 1),
+            // only the bind mount
+            ("/srv/media/b.jpg", 2),
+            ("/export/share/b.jpg", 2),
+            // hardlinks
+            ("/srv/media/c.jpg", 3),
+            ("/srv/media/c-link.jpg", 3),
+        ];
+        for (path, content) in files.iter() {
+            db.insert_filedigest(&FileDigest::new(0, path, vec![*content; 8], 10))?;
+        }
+        db.db.execute(
+            "INSERT INTO file_meta (id, dev, inode, links) VALUES \
+             (1, 5, 100, 1), (2, 5, 100, 1), (3, 6, 100, 1), \
+             (4, 5, 200, 1), (5, 5, 200, 1), (6, 5, 300, 2), (7, 5, 300, 2)",
+            params![],
+        )?;
+        let groups = get_list_of_similar_files(&db)?;
+        let mut members: Vec<(Vec<i64>, bool)> = groups
+            .iter()
+            .map(|g| (g.files.iter().map(|f| f.id).collect(), g.hardlinked))
+            .collect();
+        members.sort();
+        assert_eq!(members, [(vec![1, 3], false), (vec![6, 7], true)]);
+        // hardlinks take no extra space
+        assert_eq!(duplicate_totals(&db)?.1, 10);
+
+        assert_eq!(GroupFilter::default().apply(groups).len(), 1);
+        let groups = get_list_of_similar_files(&db)?;
+        let show_hardlinks = GroupFilter {
+            show_hardlinks: true,
+            ..Default::default()
+        };
+        assert_eq!(show_hardlinks.apply(groups).len(), 2);
         Ok(())
     }
 
     #[test]
     fn test_find_similarities() {
         let mut testfiles = Vec::new();
-        testfiles.push(FileDigest::new(1, "/tmp/a", vec![0, 1, 2, 3], 1));
-        testfiles.push(FileDigest::new(2, "/tmp/b", vec![0, 1, 2, 3], 1));
-        testfiles.push(FileDigest::new(3, "/tmp/c", vec![0, 1, 2, 4], 1));
-        testfiles.push(FileDigest::new(4, "/tmp/d", vec![0, 1, 2, 4], 1));
-        testfiles.push(FileDigest::new(5, "/tmp/e", vec![0, 1, 2, 5], 2));
-        let list_of_similar_files = find_similarities(testfiles);
+        testfiles.push((1, vec![0, 1, 2, 3], 1));
+        testfiles.push((2, vec![0, 1, 2, 3], 1));
+        testfiles.push((3, vec![0, 1, 2, 4], 1));
+        testfiles.push((4, vec![0, 1, 2, 4], 1));
+        testfiles.push((5, vec![0, 1, 2, 5], 2));
+        let list_of_similar_files = id_lists(find_similarities(testfiles));
 
         let mut target_sim_list = HashSet::new();
         target_sim_list.insert(vec![1, 2]);
@@ -166,13 +1648,7 @@ mod tests {
             // Use a very small range for the digest, so we get lots of collisions
             //let digest: Vec<u8> = (0..digest_len).map(|_| rng.gen::<u8>()).collect();
             let digest: Vec<u8> = (0..digest_len).map(|_| rng.gen_range(0..5)).collect();
-            let path = PathBuf::from(format!("/tmp/a{}", i));
-            files.push(FileDigest {
-                id: i,
-                path: path,
-                digest: digest,
-                size: 42,
-            });
+            files.push((i, digest, 42));
         }
         let t0 = Instant::now();
         let _list_of_similar_files = find_similarities(files);
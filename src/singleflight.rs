@@ -0,0 +1,241 @@
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+use crate::dblock::DbVersion;
+
+/// Number of values kept, beyond that the least recently used ones are dropped
+const MAX_VALUES: usize = 8;
+
+enum Slot<T> {
+    Computing,
+    Ready {
+        version: DbVersion,
+        value: Arc<T>,
+        /// When the value was last used, as a count of uses
+        used: u64,
+    },
+}
+
+/// Computes a value once per key and database version, and shares it between callers.
+/// Callers that ask while it is being computed wait for that computation instead of
+/// starting their own, so several tabs loading a large database at once cost one
+/// computation. A failed computation isn't kept, the next caller tries again. Only the
+/// values of the latest version are kept, at most `MAX_VALUES` of them, since the keys
+/// may come from requests.
+pub struct SingleFlight<K, T> {
+    slots: Mutex<HashMap<K, Slot<T>>>,
+    computed: Condvar,
+    uses: AtomicU64,
+}
+
+/// Gives up the slot of a computation that failed or panicked, and wakes the waiting
+/// callers either way.
+struct Computation<'a, K: Eq + Hash + Clone, T> {
+    flight: &'a SingleFlight<K, T>,
+    key: K,
+    done: bool,
+}
+
+impl<'a, K: Eq + Hash + Clone, T> Drop for Computation<'a, K, T> {
+    fn drop(&mut self) {
+        if !self.done {
+            if let Ok(mut slots) = self.flight.slots.lock() {
+                slots.remove(&self.key);
+            }
+        }
+        self.flight.computed.notify_all();
+    }
+}
+
+impl<K: Eq + Hash + Clone, T> SingleFlight<K, T> {
+    pub fn new() -> SingleFlight<K, T> {
+        SingleFlight {
+            slots: Mutex::new(HashMap::new()),
+            computed: Condvar::new(),
+            uses: AtomicU64::new(0),
+        }
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, HashMap<K, Slot<T>>>> {
+        self.slots
+            .lock()
+            .map_err(|_| anyhow!("Unable to lock computed results"))
+    }
+
+    /// The value of `key` computed at `version`, computed with `compute` unless another
+    /// caller already did or is doing so.
+    pub fn get<F: FnOnce() -> Result<T>>(
+        &self,
+        key: K,
+        version: DbVersion,
+        compute: F,
+    ) -> Result<Arc<T>> {
+        let mut slots = self.lock()?;
+        loop {
+            match slots.get_mut(&key) {
+                Some(Slot::Ready {
+                    version: v,
+                    value,
+                    used,
+                }) if *v == version => {
+                    *used = self.uses.fetch_add(1, Ordering::Relaxed);
+                    return Ok(Arc::clone(value));
+                }
+                Some(Slot::Computing) => {
+                    slots = self
+                        .computed
+                        .wait(slots)
+                        .map_err(|_| anyhow!("Unable to lock computed results"))?;
+                }
+                _ => break,
+            }
+        }
+        slots.insert(key.clone(), Slot::Computing);
+        drop(slots);
+
+        let mut computation = Computation {
+            flight: self,
+            key,
+            done: false,
+        };
+        let value = Arc::new(compute()?);
+        let mut slots = self.lock()?;
+        slots.insert(
+            computation.key.clone(),
+            Slot::Ready {
+                version,
+                value: Arc::clone(&value),
+                used: self.uses.fetch_add(1, Ordering::Relaxed),
+            },
+        );
+        evict(&mut slots, version);
+        computation.done = true;
+        Ok(value)
+    }
+
+    /// Keeps the values computed at `from` for `to`, after changes that don't affect
+    /// them.
+    pub fn carry_over(&self, from: DbVersion, to: DbVersion) -> Result<()> {
+        for slot in self.lock()?.values_mut() {
+            if let Slot::Ready { version, .. } = slot {
+                if *version == from {
+                    *version = to;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Drops the values of other versions than `version`, and the least recently used ones
+/// beyond `MAX_VALUES`.
+fn evict<K: Eq + Hash + Clone, T>(slots: &mut HashMap<K, Slot<T>>, version: DbVersion) {
+    slots.retain(|_, slot| match slot {
+        Slot::Ready { version: v, .. } => *v == version,
+        Slot::Computing => true,
+    });
+    let mut ready: Vec<(u64, K)> = slots
+        .iter()
+        .filter_map(|(key, slot)| match slot {
+            Slot::Ready { used, .. } => Some((*used, key.clone())),
+            Slot::Computing => None,
+        })
+        .collect();
+    if ready.len() > MAX_VALUES {
+        ready.sort_by_key(|(used, _)| *used);
+        for (_, key) in ready.iter().take(ready.len() - MAX_VALUES) {
+            slots.remove(key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{Database, FileDigest};
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Barrier;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_concurrent_callers_share_one_computation() -> Result<()> {
+        let db = Database::in_memory()?;
+        let version = db.version()?;
+        let flight = SingleFlight::new();
+        let computations = AtomicUsize::new(0);
+        let start = Barrier::new(8);
+        let compute = || {
+            computations.fetch_add(1, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(100));
+            Ok(42)
+        };
+        thread::scope(|scope| {
+            let callers: Vec<_> = (0..8)
+                .map(|_| {
+                    scope.spawn(|| {
+                        start.wait();
+                        flight.get("groups", version, compute)
+                    })
+                })
+                .collect();
+            for caller in callers {
+                assert_eq!(*caller.join().unwrap().unwrap(), 42);
+            }
+        });
+        assert_eq!(computations.load(Ordering::SeqCst), 1);
+
+        // cached until the database changes
+        assert_eq!(*flight.get("groups", version, compute)?, 42);
+        assert_eq!(computations.load(Ordering::SeqCst), 1);
+        db.insert_filedigest(&FileDigest::new(0, "/tmp/a", vec![1], 1))?;
+        let changed = db.version()?;
+        flight.get("groups", changed, compute)?;
+        assert_eq!(computations.load(Ordering::SeqCst), 2);
+        // other keys are computed on their own
+        flight.get("largest", changed, compute)?;
+        assert_eq!(computations.load(Ordering::SeqCst), 3);
+
+        db.insert_filedigest(&FileDigest::new(0, "/tmp/b", vec![1], 1))?;
+        flight.carry_over(changed, db.version()?)?;
+        flight.get("groups", db.version()?, compute)?;
+        assert_eq!(computations.load(Ordering::SeqCst), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_values_are_bounded() -> Result<()> {
+        let db = Database::in_memory()?;
+        let version = db.version()?;
+        let flight = SingleFlight::new();
+        let computations = AtomicUsize::new(0);
+        let compute = || Ok(computations.fetch_add(1, Ordering::SeqCst));
+        for key in 0..MAX_VALUES {
+            flight.get(key, version, compute)?;
+        }
+        // the first key is used again, so the second one is the least recently used
+        flight.get(0, version, compute)?;
+        flight.get(MAX_VALUES, version, compute)?;
+        assert_eq!(flight.lock()?.len(), MAX_VALUES);
+        assert_eq!(*flight.get(0, version, compute)?, 0);
+        assert_eq!(*flight.get(1, version, compute)?, MAX_VALUES + 1);
+
+        // a new version drops the values of the old one
+        db.insert_filedigest(&FileDigest::new(0, "/tmp/a", vec![1], 1))?;
+        flight.get(0, db.version()?, compute)?;
+        assert_eq!(flight.lock()?.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_failures_are_not_kept() -> Result<()> {
+        let version = Database::in_memory()?.version()?;
+        let flight = SingleFlight::new();
+        assert!(flight.get((), version, || Err(anyhow!("failed"))).is_err());
+        assert_eq!(*flight.get((), version, || Ok(1))?, 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,213 @@
+use anyhow::{anyhow, Result};
+use rusqlite::params;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::database::Database;
+use crate::similarities::{FileGroup, GROUP_ID_BYTES};
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// How long a group has to be around to count as stale, given in days like 180d or 26w.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StaleAge {
+    pub days: u64,
+}
+
+impl FromStr for StaleAge {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<StaleAge> {
+        let invalid = || {
+            anyhow!(
+                "Unknown age {}, use a number of days like 180d or weeks like 26w",
+                s
+            )
+        };
+        let (number, factor) = match s.strip_suffix('w') {
+            Some(weeks) => (weeks, 7),
+            None => (s.strip_suffix('d').unwrap_or(s), 1),
+        };
+        let number: u64 = number.parse().map_err(|_| invalid())?;
+        Ok(StaleAge {
+            days: number * factor,
+        })
+    }
+}
+
+/// A duplicate group whose files were all first seen before the cutoff.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StaleGroup {
+    pub group_id: String,
+    pub files: usize,
+    /// Of each file
+    pub size: u64,
+    /// Bytes freed by keeping a single copy
+    pub wasted_bytes: u64,
+    /// Days since the second copy was first seen, i.e. since the space could have been
+    /// reclaimed
+    pub ignored_days: u64,
+}
+
+/// The stale groups with how much space they waste in total.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StaleReport {
+    pub older_than_days: u64,
+    pub groups: Vec<StaleGroup>,
+    pub wasted_bytes: u64,
+    pub longest_ignored_days: u64,
+}
+
+impl StaleReport {
+    pub fn new(groups: Vec<StaleGroup>, age: StaleAge) -> StaleReport {
+        StaleReport {
+            older_than_days: age.days,
+            wasted_bytes: groups.iter().map(|g| g.wasted_bytes).sum(),
+            longest_ignored_days: groups.iter().map(|g| g.ignored_days).max().unwrap_or(0),
+            groups,
+        }
+    }
+}
+
+impl fmt::Display for StaleReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for group in &self.groups {
+            writeln!(
+                f,
+                "{}: {} files of {} bytes, {} bytes reclaimable for {} days",
+                group.group_id, group.files, group.size, group.wasted_bytes, group.ignored_days
+            )?;
+        }
+        writeln!(
+            f,
+            "{} groups older than {} days, {} bytes reclaimable but ignored, the oldest for {} days",
+            self.groups.len(),
+            self.older_than_days,
+            self.wasted_bytes,
+            self.longest_ignored_days
+        )
+    }
+}
+
+impl Database {
+    /// Groups whose files were all first seen more than `age` before `now` (unix seconds)
+    /// and that were never dismissed, the most wasted space first. Files from before
+    /// first_seen was recorded might be new, so groups with such files are left out, as
+    /// are groups with excluded content.
+    pub fn stale_groups(&self, age: StaleAge, now: i64) -> Result<Vec<StaleGroup>> {
+        let cutoff = now - age.days as i64 * SECONDS_PER_DAY;
+        let mut stmt = self.db.prepare(
+            "SELECT lower(hex(substr(d.digest, 1, ?2))), COUNT(*), MAX(d.size), \
+             (SELECT f.first_seen FROM file_digests f WHERE f.digest = d.digest \
+              ORDER BY f.first_seen LIMIT 1 OFFSET 1) \
+             FROM file_digests d \
+             WHERE d.digest NOT IN (SELECT digest FROM excluded_digests) \
+             GROUP BY d.digest \
+             HAVING COUNT(*) > 1 AND COUNT(d.first_seen) = COUNT(*) AND MAX(d.first_seen) < ?1 \
+             AND lower(hex(substr(d.digest, 1, ?2))) NOT IN (SELECT group_id FROM dismissed_groups) \
+             ORDER BY (COUNT(*) - 1) * MAX(d.size) DESC, d.digest",
+        )?;
+        let groups: Result<Vec<StaleGroup>, _> = stmt
+            .query_map(params![cutoff, GROUP_ID_BYTES as i64], |row| {
+                let files: i64 = row.get(1)?;
+                let size: i64 = row.get(2)?;
+                let duplicate_since: i64 = row.get(3)?;
+                Ok(StaleGroup {
+                    group_id: row.get(0)?,
+                    files: files as usize,
+                    size: size as u64,
+                    wasted_bytes: (files - 1) as u64 * size as u64,
+                    ignored_days: ((now - duplicate_since).max(0) / SECONDS_PER_DAY) as u64,
+                })
+            })?
+            .collect();
+        Ok(groups?)
+    }
+}
+
+/// Keeps the groups that are stale, in the order of `stale`.
+pub fn retain_stale(groups: Vec<FileGroup>, stale: &[StaleGroup]) -> Vec<FileGroup> {
+    let order: HashMap<&str, usize> = stale
+        .iter()
+        .enumerate()
+        .map(|(i, g)| (g.group_id.as_str(), i))
+        .collect();
+    let mut groups: Vec<(usize, FileGroup)> = groups
+        .into_iter()
+        .filter_map(|g| order.get(g.group_id.as_str()).map(|&i| (i, g)))
+        .collect();
+    groups.sort_by_key(|(i, _)| *i);
+    groups.into_iter().map(|(_, g)| g).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::FileDigest;
+    use crate::similarities::get_list_of_similar_files;
+
+    #[test]
+    fn test_stale_groups() -> Result<()> {
+        let db = Database::new("test_stale_groups.sqlite", true)?;
+        let now = 1_000 * SECONDS_PER_DAY;
+        // digest, size, days ago each file was first seen
+        let groups: [(u8, u64, &[Option<i64>]); 6] = [
+            (1, 10, &[Some(400), Some(300)]),
+            (2, 100, &[Some(400), Some(200), Some(190)]),
+            (3, 1000, &[Some(400), Some(10)]),
+            (4, 1000, &[Some(400), None]),
+            (5, 1000, &[Some(400), Some(400)]),
+            (6, 1000, &[Some(400), Some(400)]),
+        ];
+        for (digest, size, seen) in groups.iter() {
+            for (i, days_ago) in seen.iter().enumerate() {
+                let path = format!("/tmp/nonexistent/{}/{}", digest, i);
+                db.insert_filedigest(&FileDigest::new(0, &path, vec![*digest; 8], *size))?;
+                db.db.execute(
+                    "UPDATE file_digests SET first_seen = ?1 WHERE path = ?2",
+                    params![days_ago.map(|d| now - d * SECONDS_PER_DAY), path],
+                )?;
+            }
+        }
+        db.db.execute(
+            "INSERT INTO dismissed_groups (group_id) VALUES ('0505050505050505')",
+            params![],
+        )?;
+        db.exclude_digest(&[6; 8], None)?;
+
+        let stale = db.stale_groups("180d".parse()?, now)?;
+        let summary: Vec<(&str, u64, u64)> = stale
+            .iter()
+            .map(|g| (g.group_id.as_str(), g.wasted_bytes, g.ignored_days))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("0202020202020202", 200, 200),
+                ("0101010101010101", 10, 300)
+            ]
+        );
+        let report = StaleReport::new(stale.clone(), "180d".parse()?);
+        assert_eq!(
+            (report.wasted_bytes, report.longest_ignored_days),
+            (210, 300)
+        );
+        assert_eq!(db.stale_groups("195d".parse()?, now)?.len(), 1);
+        assert_eq!(db.stale_groups("52w".parse()?, now)?.len(), 0);
+
+        let listed = retain_stale(get_list_of_similar_files(&db)?, &stale);
+        let ids: Vec<&str> = listed.iter().map(|g| g.group_id.as_str()).collect();
+        assert_eq!(ids, ["0202020202020202", "0101010101010101"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_stale_age() {
+        assert_eq!("180d".parse::<StaleAge>().unwrap().days, 180);
+        assert_eq!("26w".parse::<StaleAge>().unwrap().days, 182);
+        assert_eq!("30".parse::<StaleAge>().unwrap().days, 30);
+        assert!("6m".parse::<StaleAge>().is_err());
+    }
+}
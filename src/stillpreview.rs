@@ -0,0 +1,115 @@
+use anyhow::Result;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+
+use crate::videohash;
+
+/// Previews are scaled to fit into a square of this many pixels.
+pub const PREVIEW_SIZE: u32 = 512;
+
+/// Still image formats that are recognized by their content instead of their extension,
+/// since phones happily export HEIC files named ".jpg".
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StillFormat {
+    /// Browsers can show these directly
+    WebP,
+    /// HEIC/HEIF, which most browsers can't show, so they are decoded through ffmpeg
+    Heif,
+}
+
+/// Brands of the ISO base media "ftyp" box that mark HEIF images rather than videos.
+const HEIF_BRANDS: [&[u8; 4]; 8] = [
+    b"heic", b"heix", b"heim", b"heis", b"hevc", b"hevx", b"mif1", b"msf1",
+];
+
+/// Identifies WebP and HEIF files from their first bytes.
+pub fn sniff(data: &[u8]) -> Option<StillFormat> {
+    if data.len() >= 12 && &data[0..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        return Some(StillFormat::WebP);
+    }
+    if data.len() >= 12 && &data[4..8] == b"ftyp" && HEIF_BRANDS.iter().any(|b| &data[8..12] == *b)
+    {
+        return Some(StillFormat::Heif);
+    }
+    None
+}
+
+pub fn sniff_file(path: &Path) -> Option<StillFormat> {
+    let mut header = Vec::with_capacity(12);
+    fs::File::open(path)
+        .and_then(|f| f.take(12).read_to_end(&mut header))
+        .ok()?;
+    sniff(&header)
+}
+
+/// Decodes a still image through ffmpeg and encodes it as BMP, which every browser can show.
+pub fn render_preview(path: &Path) -> Result<Vec<u8>> {
+    let (width, height, pixels) = videohash::decode_still(path, PREVIEW_SIZE)?;
+    Ok(encode_bmp(width, height, &pixels))
+}
+
+/// Encodes RGB24 pixels as an uncompressed 24 bit BMP.
+pub fn encode_bmp(width: u32, height: u32, rgb: &[u8]) -> Vec<u8> {
+    const HEADER_LEN: u32 = 14 + 40;
+    // BMP rows are stored bottom up, in BGR order and padded to multiples of 4 bytes
+    let row_len = (width * 3 + 3) & !3;
+    let image_len = row_len * height;
+    let mut data = Vec::with_capacity((HEADER_LEN + image_len) as usize);
+    data.extend(b"BM");
+    data.extend(&(HEADER_LEN + image_len).to_le_bytes());
+    data.extend(&0u32.to_le_bytes());
+    data.extend(&HEADER_LEN.to_le_bytes());
+    data.extend(&40u32.to_le_bytes());
+    data.extend(&(width as i32).to_le_bytes());
+    data.extend(&(height as i32).to_le_bytes());
+    data.extend(&1u16.to_le_bytes());
+    data.extend(&24u16.to_le_bytes());
+    data.extend(&0u32.to_le_bytes()); // no compression
+    data.extend(&image_len.to_le_bytes());
+    data.extend(&[0u8; 16]); // resolution and palette sizes
+    if width > 0 {
+        for row in rgb.chunks(width as usize * 3).rev() {
+            let start = data.len();
+            data.extend(row.chunks(3).flat_map(|p| p.iter().rev()));
+            data.resize(start + row_len as usize, 0);
+        }
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sniff() {
+        assert_eq!(sniff(b"RIFF\x24\0\0\0WEBPVP8L"), Some(StillFormat::WebP));
+        assert_eq!(
+            sniff(b"\0\0\0\x18ftypheic\0\0\0\0"),
+            Some(StillFormat::Heif)
+        );
+        assert_eq!(
+            sniff(b"\0\0\0\x1cftypmif1\0\0\0\0"),
+            Some(StillFormat::Heif)
+        );
+        // an ordinary MP4 video
+        assert_eq!(sniff(b"\0\0\0\x20ftypisom\0\0\x02\0"), None);
+        assert_eq!(sniff(b"RIFF\x24\0\0\0WAVEfmt "), None);
+        assert_eq!(sniff(b"RIFF"), None);
+    }
+
+    #[test]
+    fn test_encode_bmp() {
+        // 2x2 pixels: red, green in the top row and blue, white in the bottom row
+        let rgb = [255, 0, 0, 0, 255, 0, 0, 0, 255, 255, 255, 255];
+        let bmp = encode_bmp(2, 2, &rgb);
+        assert_eq!(&bmp[0..2], b"BM");
+        assert_eq!(bmp.len(), 54 + 2 * 8);
+        assert_eq!(u32::from_le_bytes([bmp[2], bmp[3], bmp[4], bmp[5]]), 70);
+        assert_eq!(
+            &bmp[54..],
+            &[255, 0, 0, 255, 255, 255, 0, 0, 0, 0, 255, 0, 255, 0, 0, 0][..]
+        );
+    }
+}
@@ -0,0 +1,212 @@
+use anyhow::{anyhow, Result};
+use rusqlite::params;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::database::{Database, FileDigest};
+use crate::filehashing;
+
+/// Appended to the name a file has while it is being moved around.
+const TEMP_SUFFIX: &str = ".dupletti-swap";
+
+/// A name next to `path` that is used while moving files.
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(TEMP_SUFFIX);
+    path.with_file_name(name)
+}
+
+/// Exchanges the files at `a` and `b` through a temporary name. Completed steps are
+/// undone if a later one fails.
+fn swap_on_disk<F>(a: &Path, b: &Path, mut rename: F) -> Result<()>
+where
+    F: FnMut(&Path, &Path) -> io::Result<()>,
+{
+    let tmp = temp_path(a);
+    if tmp.exists() {
+        return Err(anyhow!("{} is in the way", tmp.to_string_lossy()));
+    }
+    rename(a, &tmp)?;
+    if let Err(e) = rename(b, a) {
+        if let Err(undo) = rename(&tmp, a) {
+            log::error!("Unable to move {:?} back to {:?}: {}", tmp, a, undo);
+        }
+        return Err(e.into());
+    }
+    if let Err(e) = rename(&tmp, b) {
+        for (from, to) in [(a, b), (tmp.as_path(), a)].iter() {
+            if let Err(undo) = rename(from, to) {
+                log::error!("Unable to move {:?} back to {:?}: {}", from, to, undo);
+            }
+        }
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+impl Database {
+    /// Exchanges the paths of two files, on disk and in the DB. Meant for exact
+    /// duplicates, where the better copy sits at the worse path.
+    pub fn swap_files(&self, a: &FileDigest, b: &FileDigest) -> Result<()> {
+        self.swap_files_with(a, b, |from, to| fs::rename(from, to))
+    }
+
+    fn swap_files_with<F>(&self, a: &FileDigest, b: &FileDigest, rename: F) -> Result<()>
+    where
+        F: FnMut(&Path, &Path) -> io::Result<()>,
+    {
+        let tx = self.db.unchecked_transaction()?;
+        // paths are unique, so one of them needs a temporary name
+        let set_path = "UPDATE file_digests SET path = ?1, path_key = NULL WHERE id = ?2";
+        self.db.execute(
+            set_path,
+            params![temp_path(&a.path).to_string_lossy(), a.id],
+        )?;
+        self.db
+            .execute(set_path, params![a.path.to_string_lossy(), b.id])?;
+        self.db
+            .execute(set_path, params![b.path.to_string_lossy(), a.id])?;
+        self.refresh_root_id(a.id)?;
+        self.refresh_root_id(b.id)?;
+        swap_on_disk(&a.path, &b.path, rename)?;
+        if let Err(e) = tx.commit() {
+            swap_on_disk(&a.path, &b.path, |from, to| fs::rename(from, to))?;
+            return Err(e.into());
+        }
+        log::info!("Swapped {:?} and {:?}", a.path, b.path);
+        Ok(())
+    }
+
+    /// Moves the content of `keep` to the path of `replace`, whose own content is deleted.
+    /// The result is hashed again and stays indexed under the id of `keep`.
+    pub fn replace_content(&self, keep: &FileDigest, replace: &FileDigest) -> Result<()> {
+        let tmp = temp_path(&replace.path);
+        if tmp.exists() {
+            return Err(anyhow!("{} is in the way", tmp.to_string_lossy()));
+        }
+        fs::copy(&keep.path, &tmp)?;
+        let result = filehashing::create_filedigest(&tmp).and_then(|copy| {
+            let tx = self.db.unchecked_transaction()?;
+            self.add_tombstone(replace)?;
+            self.delete_filedigest(replace.id)?;
+            self.db.execute(
+                "UPDATE file_digests SET path = ?1, path_key = NULL, digest = ?2, size = ?3, \
+                 exif_capture_date = ?4, exif_camera_make = ?5, exif_camera_model = ?6 \
+                 WHERE id = ?7",
+                params![
+                    replace.path.to_string_lossy(),
+                    copy.digest,
+                    copy.size,
+                    copy.exif.capture_date,
+                    copy.exif.camera_make,
+                    copy.exif.camera_model,
+                    keep.id
+                ],
+            )?;
+            self.refresh_root_id(keep.id)?;
+            fs::rename(&tmp, &replace.path)?;
+            Ok(tx.commit()?)
+        });
+        if let Err(e) = result {
+            if tmp.exists() {
+                fs::remove_file(&tmp)?;
+            }
+            return Err(e);
+        }
+        log::info!("Replaced {:?} with {:?}", replace.path, keep.path);
+        // the database already points to the new copy, so this is only left over
+        if let Err(e) = fs::remove_file(&keep.path) {
+            log::warn!("Unable to remove {:?} after replacing: {}", keep.path, e);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn indexed_files(db: &Database, paths: &[&Path]) -> Result<Vec<FileDigest>> {
+        for path in paths {
+            db.insert_filedigest(&filehashing::create_filedigest(path)?)?;
+        }
+        let mut files = db.get_all_filedigests()?;
+        files.sort_by_key(|f| f.id);
+        Ok(files)
+    }
+
+    #[test]
+    fn test_swap_files() -> Result<()> {
+        let dir = tempdir()?;
+        let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+        fs::write(&a, "a")?;
+        fs::write(&b, "b")?;
+        let db = Database::new("test_swap_files.sqlite", true)?;
+        let files = indexed_files(&db, &[&a, &b])?;
+
+        db.swap_files(&files[0], &files[1])?;
+        assert_eq!(fs::read_to_string(&a)?, "b");
+        assert_eq!(fs::read_to_string(&b)?, "a");
+        assert_eq!(db.lookup_filedigest(files[0].id)?.path, b);
+        assert_eq!(db.lookup_filedigest(files[1].id)?.path, a);
+        assert!(!temp_path(&a).exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_swap_files_rolls_back_when_the_last_rename_fails() -> Result<()> {
+        let dir = tempdir()?;
+        let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+        fs::write(&a, "a")?;
+        fs::write(&b, "b")?;
+        let db = Database::new("test_swap_files_rollback.sqlite", true)?;
+        let files = indexed_files(&db, &[&a, &b])?;
+
+        let mut num_renames = 0;
+        let result = db.swap_files_with(&files[0], &files[1], |from, to| {
+            num_renames += 1;
+            if num_renames == 3 {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "injected failure",
+                ));
+            }
+            fs::rename(from, to)
+        });
+        assert!(result.is_err());
+        // three steps, and two to undo the first ones
+        assert_eq!(num_renames, 5);
+        assert_eq!(fs::read_to_string(&a)?, "a");
+        assert_eq!(fs::read_to_string(&b)?, "b");
+        assert!(!temp_path(&a).exists());
+        assert_eq!(db.lookup_filedigest(files[0].id)?.path, a);
+        assert_eq!(db.lookup_filedigest(files[1].id)?.path, b);
+        Ok(())
+    }
+
+    #[test]
+    fn test_replace_content() -> Result<()> {
+        let dir = tempdir()?;
+        let keep = dir.path().join("download/better.mp4");
+        let replace = dir.path().join("library/worse.mp4");
+        fs::create_dir_all(keep.parent().unwrap())?;
+        fs::create_dir_all(replace.parent().unwrap())?;
+        fs::write(&keep, "high quality")?;
+        fs::write(&replace, "low quality")?;
+        let db = Database::new("test_replace_content.sqlite", true)?;
+        let files = indexed_files(&db, &[&keep, &replace])?;
+
+        db.replace_content(&files[0], &files[1])?;
+        assert!(!keep.exists());
+        assert_eq!(fs::read_to_string(&replace)?, "high quality");
+        let remaining = db.get_all_filedigests()?;
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].id, files[0].id);
+        assert_eq!(remaining[0].path, replace);
+        assert_eq!(remaining[0].digest, files[0].digest);
+        assert_eq!(db.count_tombstones()?, 1);
+        Ok(())
+    }
+}
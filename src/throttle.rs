@@ -0,0 +1,145 @@
+use anyhow::{anyhow, Result};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const MIB: f64 = 1024.0 * 1024.0;
+
+/// Limits the bytes per second shared by several threads. Up to a second worth of bytes
+/// can be taken at once after a pause, beyond that callers wait until the bytes they took
+/// are paid off.
+pub struct TokenBucket {
+    bytes_per_sec: f64,
+    capacity: f64,
+    /// Bytes that can be taken without waiting as of the instant, negative while callers
+    /// wait
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    pub fn new(bytes_per_sec: f64) -> TokenBucket {
+        TokenBucket {
+            bytes_per_sec,
+            capacity: bytes_per_sec,
+            state: Mutex::new((bytes_per_sec, Instant::now())),
+        }
+    }
+
+    /// Takes `bytes` at `now` and returns how long to wait before using them.
+    fn reserve(&self, bytes: usize, now: Instant) -> Duration {
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            // the state is always consistent
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let (tokens, last) = *state;
+        let refilled = now.saturating_duration_since(last).as_secs_f64() * self.bytes_per_sec;
+        let tokens = (tokens + refilled).min(self.capacity) - bytes as f64;
+        *state = (tokens, now.max(last));
+        if tokens < 0.0 {
+            Duration::from_secs_f64(-tokens / self.bytes_per_sec)
+        } else {
+            Duration::ZERO
+        }
+    }
+
+    /// Waits until `bytes` can be used.
+    pub fn acquire(&self, bytes: usize) {
+        let wait = self.reserve(bytes, Instant::now());
+        if !wait.is_zero() {
+            thread::sleep(wait);
+        }
+    }
+}
+
+static LIMITER: OnceLock<TokenBucket> = OnceLock::new();
+
+/// Limits the reads of all following scans to `mib_per_sec`, see --throttle. 0 doesn't
+/// limit them. Only the first call has an effect.
+pub fn set_limit(mib_per_sec: f64) {
+    if mib_per_sec > 0.0 {
+        let _ = LIMITER.set(TokenBucket::new(mib_per_sec * MIB));
+    }
+}
+
+/// Parses --throttle, in MiB per second.
+pub fn parse_limit(s: &str) -> Result<f64> {
+    let limit: f64 = s.parse()?;
+    if limit.is_finite() && limit >= 0.0 {
+        Ok(limit)
+    } else {
+        Err(anyhow!("--throttle must be a number of MiB/s, or 0"))
+    }
+}
+
+/// The limit set with `set_limit`, in MiB per second.
+pub fn limit() -> Option<f64> {
+    LIMITER.get().map(|bucket| bucket.bytes_per_sec / MIB)
+}
+
+/// Waits until `bytes` that were just read can be used, if reads are limited.
+pub fn read(bytes: usize) {
+    if let Some(bucket) = LIMITER.get() {
+        bucket.acquire(bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_steady_rate() {
+        let bucket = TokenBucket::new(1000.0);
+        let start = Instant::now();
+        // the first second is covered by the full bucket
+        assert_eq!(bucket.reserve(1000, start), Duration::ZERO);
+        // reading 100 bytes every 50ms asks for twice the rate, so the wait grows
+        let mut waited = Duration::ZERO;
+        let mut now = start;
+        for _ in 0..100 {
+            now += Duration::from_millis(50);
+            waited = bucket.reserve(100, now);
+        }
+        // 10000 bytes after the first 1000 take 10s at 1000 bytes/s, 5s of which passed
+        assert!((waited.as_secs_f64() - 5.0).abs() < 1e-6, "{:?}", waited);
+
+        // callers that wait as told get exactly the rate
+        let bucket = TokenBucket::new(1000.0);
+        let mut now = start + Duration::from_secs(10);
+        let mut bytes = 0;
+        while now < start + Duration::from_secs(20) {
+            now += bucket.reserve(250, now);
+            bytes += 250;
+        }
+        assert_eq!(bytes, 1000 + 10 * 1000);
+    }
+
+    #[test]
+    fn test_parse_limit() {
+        assert_eq!(parse_limit("0").unwrap(), 0.0);
+        assert_eq!(parse_limit("2.5").unwrap(), 2.5);
+        assert!(parse_limit("-1").is_err());
+        assert!(parse_limit("inf").is_err());
+        assert!(parse_limit("fast").is_err());
+    }
+
+    #[test]
+    fn test_burst_is_capped() {
+        let bucket = TokenBucket::new(1000.0);
+        let start = Instant::now();
+        assert_eq!(bucket.reserve(600, start), Duration::ZERO);
+        // a long pause only refills a second worth of bytes
+        let later = start + Duration::from_secs(60);
+        assert_eq!(bucket.reserve(1000, later), Duration::ZERO);
+        assert_eq!(bucket.reserve(500, later), Duration::from_millis(500));
+        // a single read larger than the bucket is allowed, but paid off
+        let bucket = TokenBucket::new(1000.0);
+        assert_eq!(bucket.reserve(3000, start), Duration::from_secs(2));
+        // instants out of order, from threads that raced for the lock, don't refill
+        assert_eq!(
+            bucket.reserve(0, start - Duration::from_secs(1)),
+            Duration::from_secs(2)
+        );
+    }
+}
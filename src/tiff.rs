@@ -0,0 +1,107 @@
+use std::convert::TryInto;
+
+/// Read-only view of a TIFF structure, as used by camera RAW files and EXIF blocks.
+/// All accessors return None for offsets outside of the data.
+pub struct Tiff<'a> {
+    pub data: &'a [u8],
+    little_endian: bool,
+}
+
+impl<'a> Tiff<'a> {
+    /// Checks the byte order mark and magic number of the header.
+    pub fn parse(data: &'a [u8]) -> Option<Tiff<'a>> {
+        let little_endian = match data.get(0..4) {
+            Some(b"II*\0") => true,
+            Some(b"MM\0*") => false,
+            _ => return None,
+        };
+        Some(Tiff {
+            data,
+            little_endian,
+        })
+    }
+
+    /// Offset of the first IFD.
+    pub fn first_ifd(&self) -> Option<u32> {
+        self.u32_at(4)
+    }
+
+    pub fn u16_at(&self, offset: usize) -> Option<u16> {
+        let bytes: [u8; 2] = self.data.get(offset..offset + 2)?.try_into().ok()?;
+        Some(if self.little_endian {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        })
+    }
+
+    pub fn u32_at(&self, offset: usize) -> Option<u32> {
+        let bytes: [u8; 4] = self.data.get(offset..offset + 4)?.try_into().ok()?;
+        Some(if self.little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        })
+    }
+
+    /// Offset of the entry with the given tag in the IFD at `ifd`.
+    pub fn find_entry(&self, ifd: usize, tag: u16) -> Option<usize> {
+        let num_entries = self.u16_at(ifd)? as usize;
+        (0..num_entries)
+            .map(|i| ifd + 2 + i * 12)
+            .find(|&entry| self.u16_at(entry) == Some(tag))
+    }
+
+    /// All values of a SHORT or LONG entry, which are stored inline if they fit in 4 bytes.
+    pub fn values(&self, entry: usize) -> Option<Vec<u32>> {
+        let field_type = self.u16_at(entry + 2)?;
+        let count = self.u32_at(entry + 4)? as usize;
+        let size = match field_type {
+            3 => 2,
+            4 | 13 => 4,
+            _ => return None,
+        };
+        let start = if count * size <= 4 {
+            entry + 8
+        } else {
+            self.u32_at(entry + 8)? as usize
+        };
+        (0..count)
+            .map(|i| match size {
+                2 => self.u16_at(start + i * 2).map(u32::from),
+                _ => self.u32_at(start + i * 4),
+            })
+            .collect()
+    }
+
+    /// The value of an ASCII entry, up to the first NUL and without surrounding whitespace.
+    pub fn ascii(&self, entry: usize) -> Option<String> {
+        if self.u16_at(entry + 2)? != 2 {
+            return None;
+        }
+        let count = self.u32_at(entry + 4)? as usize;
+        let start = if count <= 4 {
+            entry + 8
+        } else {
+            self.u32_at(entry + 8)? as usize
+        };
+        let bytes = self.data.get(start..start.checked_add(count)?)?;
+        let bytes = bytes.split(|&b| b == 0).next().unwrap_or_default();
+        let value = std::str::from_utf8(bytes).ok()?.trim();
+        if value.is_empty() {
+            None
+        } else {
+            Some(value.to_string())
+        }
+    }
+
+    pub fn jpeg(&self, offset: u32, length: u32) -> Option<&'a [u8]> {
+        let start = offset as usize;
+        let jpeg = self.data.get(start..start.checked_add(length as usize)?)?;
+        if jpeg.starts_with(&[0xff, 0xd8]) {
+            Some(jpeg)
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,78 @@
+use anyhow::Result;
+use rusqlite::params;
+use serde::Serialize;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::database::{Database, FileDigest};
+
+/// A file that was deleted through Dupletti. Files indexed later with the same digest
+/// are likely downloaded again.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Tombstone {
+    pub path: String,
+    /// unix seconds
+    pub deleted_at: i64,
+}
+
+pub(crate) fn now() -> Result<i64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64)
+}
+
+impl Database {
+    /// Remembers the digest of a file that is about to be deleted.
+    pub fn add_tombstone(&self, file: &FileDigest) -> Result<()> {
+        self.db.execute(
+            "INSERT INTO tombstones (digest, path, deleted_at, size) VALUES (?1, ?2, ?3, ?4)",
+            params![file.digest, file.path.to_string_lossy(), now()?, file.size],
+        )?;
+        Ok(())
+    }
+
+    /// Forgets files deleted more than `max_age` ago. Returns the number of removed
+    /// tombstones.
+    pub fn prune_tombstones(&self, max_age: Duration) -> Result<usize> {
+        let cutoff = now()? - max_age.as_secs() as i64;
+        Ok(self.db.execute(
+            "DELETE FROM tombstones WHERE deleted_at < ?1",
+            params![cutoff],
+        )?)
+    }
+
+    /// Paths of all remembered deleted files.
+    pub fn get_tombstoned_paths(&self) -> Result<Vec<PathBuf>> {
+        let mut stmt = self.db.prepare("SELECT DISTINCT path FROM tombstones")?;
+        let paths: Result<Vec<String>, _> = stmt.query_map([], |row| row.get(0))?.collect();
+        Ok(paths?.into_iter().map(PathBuf::from).collect())
+    }
+
+    pub fn count_tombstones(&self) -> Result<usize> {
+        let count: i64 =
+            self.db
+                .query_row("SELECT COUNT(*) FROM tombstones", params![], |row| {
+                    row.get(0)
+                })?;
+        Ok(count as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prune_tombstones() -> Result<()> {
+        let db = Database::new("test_prune_tombstones.sqlite", true)?;
+        db.add_tombstone(&FileDigest::new(1, "/tmp/a", vec![1], 1))?;
+        db.add_tombstone(&FileDigest::new(2, "/tmp/b", vec![2], 1))?;
+        db.db.execute(
+            "UPDATE tombstones SET deleted_at = deleted_at - 10 * 86400 WHERE path = '/tmp/a'",
+            params![],
+        )?;
+
+        assert_eq!(db.prune_tombstones(Duration::from_secs(30 * 86400))?, 0);
+        assert_eq!(db.prune_tombstones(Duration::from_secs(5 * 86400))?, 1);
+        assert_eq!(db.count_tombstones()?, 1);
+        Ok(())
+    }
+}
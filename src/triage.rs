@@ -0,0 +1,233 @@
+use anyhow::{anyhow, Result};
+use rusqlite::params;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::str::FromStr;
+
+use crate::database::Database;
+use crate::similarities::{FileGroup, GroupFilter};
+
+/// What a bulk operation does with the matching groups and files.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BulkAction {
+    /// Adds a tag to every matching file
+    Tag(String),
+    /// Hides matching groups from the results
+    Dismiss,
+    /// Prevents matching files from being removed through the web interface
+    Protect,
+}
+
+impl FromStr for BulkAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<BulkAction> {
+        match s {
+            "dismiss" => Ok(BulkAction::Dismiss),
+            "protect" => Ok(BulkAction::Protect),
+            _ => match s.strip_prefix("tag=") {
+                Some(tag) if !tag.is_empty() => Ok(BulkAction::Tag(tag.to_string())),
+                _ => Err(anyhow!(
+                    "Unknown action {}, use tag=<name>, dismiss or protect",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+/// Number of groups and files a bulk operation applies to.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct BulkCounts {
+    pub groups: usize,
+    pub files: usize,
+}
+
+/// The groups matching `filter`, with the files the action applies to. Dismissing
+/// affects whole groups, tags and protection only the matching files.
+fn bulk_targets<'a>(
+    groups: &'a [FileGroup],
+    filter: &GroupFilter,
+    action: &BulkAction,
+) -> Vec<(&'a FileGroup, Vec<i64>)> {
+    groups
+        .iter()
+        .filter(|g| filter.matches_group(g))
+        .map(|g| {
+            let ids = g
+                .files
+                .iter()
+                .filter(|f| *action == BulkAction::Dismiss || filter.matches_file(f))
+                .map(|f| f.id)
+                .collect();
+            (g, ids)
+        })
+        .collect()
+}
+
+impl Database {
+    /// Applies `action` to all groups matching `filter` in one transaction. With
+    /// `dry_run`, only counts what would be affected.
+    pub fn apply_bulk_action(
+        &self,
+        groups: &[FileGroup],
+        filter: &GroupFilter,
+        action: &BulkAction,
+        dry_run: bool,
+    ) -> Result<BulkCounts> {
+        let targets = bulk_targets(groups, filter, action);
+        let counts = BulkCounts {
+            groups: targets.len(),
+            files: targets.iter().map(|(_, ids)| ids.len()).sum(),
+        };
+        if dry_run {
+            return Ok(counts);
+        }
+        let tx = self.db.unchecked_transaction()?;
+        for (group, ids) in targets.iter() {
+            match action {
+                BulkAction::Dismiss => {
+                    tx.execute(
+                        "INSERT OR IGNORE INTO dismissed_groups (group_id) VALUES (?1)",
+                        params![group.group_id],
+                    )?;
+                }
+                BulkAction::Tag(tag) => {
+                    for id in ids {
+                        tx.execute(
+                            "INSERT OR IGNORE INTO file_tags (id, tag) VALUES (?1, ?2)",
+                            params![id, tag],
+                        )?;
+                    }
+                }
+                BulkAction::Protect => {
+                    for id in ids {
+                        tx.execute(
+                            "INSERT OR IGNORE INTO protected_files (id) VALUES (?1)",
+                            params![id],
+                        )?;
+                    }
+                }
+            }
+        }
+        tx.commit()?;
+        Ok(counts)
+    }
+
+    pub fn get_dismissed_groups(&self) -> Result<HashSet<String>> {
+        let mut stmt = self.db.prepare("SELECT group_id FROM dismissed_groups")?;
+        let ids: Result<HashSet<String>, _> = stmt.query_map([], |row| row.get(0))?.collect();
+        Ok(ids?)
+    }
+
+    pub fn is_dismissed(&self, group_id: &str) -> Result<bool> {
+        let count: i64 = self.db.query_row(
+            "SELECT COUNT(*) FROM dismissed_groups WHERE group_id = ?1",
+            params![group_id],
+            |row| row.get(0),
+        )?;
+        Ok(count > 0)
+    }
+
+    pub fn is_protected(&self, file_id: i64) -> Result<bool> {
+        let count: i64 = self.db.query_row(
+            "SELECT COUNT(*) FROM protected_files WHERE id = ?1",
+            params![file_id],
+            |row| row.get(0),
+        )?;
+        Ok(count > 0)
+    }
+
+    pub fn get_tags(&self, file_id: i64) -> Result<Vec<String>> {
+        let mut stmt = self
+            .db
+            .prepare("SELECT tag FROM file_tags WHERE id = ?1 ORDER BY tag")?;
+        let tags: Result<Vec<String>, _> = stmt
+            .query_map(params![file_id], |row| row.get(0))?
+            .collect();
+        Ok(tags?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::FileDigest;
+    use crate::similarities;
+
+    #[test]
+    fn test_bulk_action_from_str() {
+        assert_eq!(
+            "tag=keep".parse::<BulkAction>().unwrap(),
+            BulkAction::Tag("keep".to_string())
+        );
+        assert_eq!(
+            "dismiss".parse::<BulkAction>().unwrap(),
+            BulkAction::Dismiss
+        );
+        assert!("tag=".parse::<BulkAction>().is_err());
+        assert!("delete".parse::<BulkAction>().is_err());
+    }
+
+    #[test]
+    fn test_bulk_tag_matching_files() -> Result<()> {
+        let db = Database::new("test_bulk_tag_matching_files.sqlite", true)?;
+        for (path, digest) in [
+            ("/media/photos/a.jpg", 1),
+            ("/backup/photos/a.jpg", 1),
+            ("/media/videos/b.mp4", 2),
+            ("/backup/videos/b.mp4", 2),
+        ]
+        .iter()
+        {
+            db.insert_filedigest(&FileDigest::new(0, path, vec![*digest; 8], 10))?;
+        }
+        let groups = similarities::get_list_of_similar_files(&db)?;
+        let filter = GroupFilter {
+            path: Some("/backup/".to_string()),
+            file_type: Some(similarities::FileType::Image),
+            ..Default::default()
+        };
+        let action = BulkAction::Tag("backup".to_string());
+
+        let expected = BulkCounts {
+            groups: 1,
+            files: 1,
+        };
+        assert_eq!(
+            db.apply_bulk_action(&groups, &filter, &action, true)?,
+            expected
+        );
+        assert!(db.get_tags(2)?.is_empty());
+        assert_eq!(
+            db.apply_bulk_action(&groups, &filter, &action, false)?,
+            expected
+        );
+        let tags: Vec<Vec<String>> = (1..=4).map(|id| db.get_tags(id).unwrap()).collect();
+        assert_eq!(
+            tags,
+            vec![vec![], vec!["backup".to_string()], vec![], vec![]]
+        );
+
+        let counts = db.apply_bulk_action(&groups, &filter, &BulkAction::Dismiss, false)?;
+        assert_eq!(
+            counts,
+            BulkCounts {
+                groups: 1,
+                files: 2
+            }
+        );
+        let dismissed = db.get_dismissed_groups()?;
+        assert_eq!(dismissed.len(), 1);
+        assert!(dismissed.contains("0101010101010101"));
+
+        let videos = GroupFilter {
+            file_type: Some(similarities::FileType::Video),
+            ..Default::default()
+        };
+        db.apply_bulk_action(&groups, &videos, &BulkAction::Protect, false)?;
+        assert!(!db.is_protected(1)?);
+        assert!(db.is_protected(3)? && db.is_protected(4)?);
+        Ok(())
+    }
+}
@@ -0,0 +1,191 @@
+/// Disjoint sets over the indices 0..n, for clustering files that are linked by some
+/// pairwise predicate.
+pub struct UnionFind {
+    parent: Vec<usize>,
+    /// Number of members, only up to date for roots
+    size: Vec<usize>,
+}
+
+impl UnionFind {
+    pub fn new(n: usize) -> UnionFind {
+        UnionFind {
+            parent: (0..n).collect(),
+            size: vec![1; n],
+        }
+    }
+
+    pub fn find(&mut self, y: usize) -> usize {
+        let parent = &mut self.parent;
+        let mut x = y;
+        while parent[x] != x {
+            let tmp = x;
+            x = parent[x];
+            parent[tmp] = parent[parent[x]];
+        }
+        x
+    }
+
+    /// Hangs the smaller set below the larger one, which keeps the trees flat.
+    pub fn union(&mut self, x: usize, y: usize) {
+        let x_root = self.find(x);
+        let y_root = self.find(y);
+        if x_root == y_root {
+            return;
+        }
+        let (small, large) = if self.size[x_root] < self.size[y_root] {
+            (x_root, y_root)
+        } else {
+            (y_root, x_root)
+        };
+        self.parent[small] = large;
+        self.size[large] += self.size[small];
+    }
+
+    /// The sets with more than one member, as lists of indices in ascending order. The
+    /// sets are ordered by their smallest index.
+    pub fn clusters(mut self) -> Vec<Vec<usize>> {
+        let n = self.parent.len();
+        // position of the cluster of each root in `clusters`, in order of first appearance
+        let mut position = vec![usize::MAX; n];
+        let mut clusters: Vec<Vec<usize>> = Vec::new();
+        for i in 0..n {
+            let root = self.find(i);
+            if position[root] == usize::MAX {
+                position[root] = clusters.len();
+                clusters.push(Vec::new());
+            }
+            clusters[position[root]].push(i);
+        }
+        clusters.retain(|c| c.len() > 1);
+        clusters
+    }
+}
+
+/// Clusters `items` by the transitive closure of `related`, which is called with the
+/// indices of every pair `i < j` once. Only clusters with more than one member are
+/// returned, ordered like `UnionFind::clusters`.
+pub fn cluster_by<T, F: Fn(usize, usize) -> bool>(items: &[T], related: F) -> Vec<Vec<&T>> {
+    let mut sets = UnionFind::new(items.len());
+    for i in 0..items.len() {
+        for j in i + 1..items.len() {
+            if related(i, j) {
+                sets.union(i, j);
+            }
+        }
+    }
+    sets.clusters()
+        .into_iter()
+        .map(|c| c.into_iter().map(|i| &items[i]).collect())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::seq::SliceRandom;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn test_union_find() {
+        let mut sets = UnionFind::new(6);
+        sets.union(0, 2);
+        sets.union(4, 2);
+        sets.union(1, 5);
+        assert_eq!(sets.find(0), sets.find(4));
+        assert_ne!(sets.find(0), sets.find(1));
+        assert_eq!(sets.clusters(), [vec![0, 2, 4], vec![1, 5]]);
+    }
+
+    /// Random edges between `n` items, as an adjacency matrix.
+    fn random_graph(rng: &mut StdRng, n: usize, edges: usize) -> Vec<Vec<bool>> {
+        let mut related = vec![vec![false; n]; n];
+        for _ in 0..edges {
+            let (i, j) = (rng.gen_range(0..n), rng.gen_range(0..n));
+            related[i][j] = true;
+            related[j][i] = true;
+        }
+        related
+    }
+
+    /// The cluster index of every item, or None if it isn't clustered.
+    fn membership(clusters: &[Vec<&usize>], n: usize) -> Vec<Option<usize>> {
+        let mut member = vec![None; n];
+        for (c, cluster) in clusters.iter().enumerate() {
+            for &&i in cluster.iter() {
+                assert!(member[i].is_none(), "{} is in two clusters", i);
+                member[i] = Some(c);
+            }
+        }
+        member
+    }
+
+    #[test]
+    fn test_clusters_are_connected_components() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for round in 0..50 {
+            let n = rng.gen_range(1..40);
+            let related = random_graph(&mut rng, n, round);
+            let items: Vec<usize> = (0..n).collect();
+            let clusters = cluster_by(&items, |i, j| related[i][j]);
+            let member = membership(&clusters, n);
+            for (i, row) in related.iter().enumerate() {
+                for (j, &edge) in row.iter().enumerate() {
+                    if edge && i != j {
+                        assert!(member[i].is_some() && member[i] == member[j]);
+                    }
+                }
+            }
+            // every cluster is connected: walking the edges from its first member
+            // reaches all of it
+            for cluster in clusters.iter() {
+                let mut reached = vec![*cluster[0]];
+                let mut next = 0;
+                while next < reached.len() {
+                    let i = reached[next];
+                    next += 1;
+                    for (j, &edge) in related[i].iter().enumerate() {
+                        if edge && !reached.contains(&j) {
+                            reached.push(j);
+                        }
+                    }
+                }
+                reached.sort_unstable();
+                assert_eq!(reached, cluster.iter().map(|&&i| i).collect::<Vec<_>>());
+            }
+            // deterministic order
+            let firsts: Vec<usize> = clusters.iter().map(|c| *c[0]).collect();
+            assert!(firsts.windows(2).all(|w| w[0] < w[1]));
+        }
+    }
+
+    #[test]
+    fn test_clusters_ignore_item_order() {
+        let mut rng = StdRng::seed_from_u64(2);
+        for round in 0..50 {
+            let n = rng.gen_range(1..40);
+            let related = random_graph(&mut rng, n, round);
+            let items: Vec<usize> = (0..n).collect();
+            let mut shuffled = items.clone();
+            shuffled.shuffle(&mut rng);
+
+            let normalize = |clusters: Vec<Vec<&usize>>| {
+                let mut clusters: Vec<Vec<usize>> = clusters
+                    .into_iter()
+                    .map(|c| {
+                        let mut c: Vec<usize> = c.into_iter().copied().collect();
+                        c.sort_unstable();
+                        c
+                    })
+                    .collect();
+                clusters.sort();
+                clusters
+            };
+            let clusters = normalize(cluster_by(&items, |i, j| related[i][j]));
+            let shuffled_clusters = normalize(cluster_by(&shuffled, |i, j| {
+                related[shuffled[i]][shuffled[j]]
+            }));
+            assert_eq!(clusters, shuffled_clusters);
+        }
+    }
+}
@@ -0,0 +1,117 @@
+use std::path::Path;
+
+/// Bytes that may appear unescaped in a path of a URL: RFC 3986 unreserved characters
+/// and the separators. `&` and `'` are encoded as well, so the URLs can be put into
+/// HTML attributes verbatim.
+fn is_unescaped(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"-._~/:@!$()*+,;=".contains(&b)
+}
+
+/// Percent-encodes a path, byte by byte, so that e.g. `#`, `%`, `?` and spaces are
+/// not taken for URL syntax and non-ASCII characters are encoded as UTF-8.
+pub fn percent_encode_path(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len());
+    for &b in bytes {
+        if is_unescaped(b) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    encoded
+}
+
+/// A file URL as of RFC 8089. Windows paths use forward slashes and get the drive
+/// letter after a leading slash (`file:///C:/...`), UNC paths keep their server as
+/// the host (`file://server/share/...`).
+fn file_url_of(path: &[u8], windows: bool) -> String {
+    if !windows {
+        return format!("file://{}", percent_encode_path(path));
+    }
+    let path: Vec<u8> = path
+        .iter()
+        .map(|&b| if b == b'\\' { b'/' } else { b })
+        .collect();
+    if let Some(unc) = path.strip_prefix(b"//") {
+        return format!("file://{}", percent_encode_path(unc));
+    }
+    let has_drive = matches!(path.as_slice(), [letter, b':', ..] if letter.is_ascii_alphabetic());
+    let separator = if has_drive { "/" } else { "" };
+    format!("file://{}{}", separator, percent_encode_path(&path))
+}
+
+/// The file URL of a local path, for opening files directly from the browser.
+pub fn file_url(path: &Path) -> String {
+    // non-UTF-8 names are kept byte by byte where the OS allows them
+    #[cfg(unix)]
+    let bytes = std::os::unix::ffi::OsStrExt::as_bytes(path.as_os_str()).to_vec();
+    #[cfg(not(unix))]
+    let bytes = path.to_string_lossy().into_owned().into_bytes();
+    file_url_of(&bytes, cfg!(windows))
+}
+
+/// The link to the preview page of an indexed file.
+pub fn preview_url(id: i64) -> String {
+    format!("preview/{}", id)
+}
+
+/// The link that reveals an indexed file in the local file manager.
+pub fn reveal_url(id: i64) -> String {
+    format!("reveal/{}", id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_urls() {
+        assert_eq!(
+            file_url_of("/home/a b/c.jpg".as_bytes(), false),
+            "file:///home/a%20b/c.jpg"
+        );
+        assert_eq!(
+            file_url_of("/tmp/#1.jpg".as_bytes(), false),
+            "file:///tmp/%231.jpg"
+        );
+        assert_eq!(
+            file_url_of("/tmp/100%.jpg".as_bytes(), false),
+            "file:///tmp/100%25.jpg"
+        );
+        assert_eq!(
+            file_url_of("/tmp/a?b.jpg".as_bytes(), false),
+            "file:///tmp/a%3Fb.jpg"
+        );
+        assert_eq!(
+            file_url_of("/tmp/\"&'.jpg".as_bytes(), false),
+            "file:///tmp/%22%26%27.jpg"
+        );
+        assert_eq!(
+            file_url_of("/写真/東京.jpg".as_bytes(), false),
+            "file:///%E5%86%99%E7%9C%9F/%E6%9D%B1%E4%BA%AC.jpg"
+        );
+        // backslashes are part of names outside of Windows
+        assert_eq!(
+            file_url_of("/tmp/a\\b".as_bytes(), false),
+            "file:///tmp/a%5Cb"
+        );
+        assert_eq!(
+            file_url_of("C:\\Users\\Jo Doe\\#1.jpg".as_bytes(), true),
+            "file:///C:/Users/Jo%20Doe/%231.jpg"
+        );
+        assert_eq!(
+            file_url_of("\\\\nas\\photos\\a.jpg".as_bytes(), true),
+            "file://nas/photos/a.jpg"
+        );
+        assert_eq!(file_url(Path::new("/tmp/a b")), "file:///tmp/a%20b");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_non_utf8_file_url() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+        let path = Path::new(OsStr::from_bytes(b"/tmp/caf\xe9.jpg"));
+        assert_eq!(file_url(path), "file:///tmp/caf%E9.jpg");
+    }
+}
@@ -0,0 +1,448 @@
+use anyhow::{anyhow, Result};
+use blake2::{Blake2b, Digest};
+use rayon::prelude::*;
+use serde::Serialize;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::database::{Database, FileDigest};
+use crate::filehashing;
+use crate::scanstats::{Phase, Progress};
+use crate::similarities;
+
+/// Files are read in chunks of this size, so verifying large files needs little memory.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Verifying stops after this long, the remaining members are reported as cancelled.
+pub const VERIFY_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// The outcome of reading a member again.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "verdict", rename_all = "snake_case")]
+pub enum Verdict {
+    /// Same bytes as the first member
+    Identical,
+    /// The first differing byte, or the end of the shorter file
+    DiffersAt { offset: u64 },
+    /// The file couldn't be read
+    Unreadable { error: String },
+    /// The timeout was reached before the file was read completely
+    Cancelled,
+}
+
+/// A member of a group after reading it again.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MemberCheck {
+    pub id: i64,
+    pub path: PathBuf,
+    /// Whether the recomputed digest is the one in the database, None if the file
+    /// couldn't be read completely
+    pub digest_matches: Option<bool>,
+    /// Compared with the first member, None for the first member itself
+    pub verdict: Option<Verdict>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct GroupVerification {
+    pub group_id: String,
+    pub members: Vec<MemberCheck>,
+}
+
+/// A file whose content changed since it was hashed, see --verify.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DigestMismatch {
+    pub id: i64,
+    pub path: PathBuf,
+    /// Hex encoded
+    pub stored_digest: String,
+    pub current_digest: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UnreadableFile {
+    pub path: PathBuf,
+    pub error: String,
+}
+
+/// The outcome of hashing all indexed files again.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct DigestReport {
+    /// Files that were read completely, whether their digest matched or not
+    pub checked: usize,
+    pub mismatched: Vec<DigestMismatch>,
+    /// Indexed files that no longer exist
+    pub missing: Vec<PathBuf>,
+    pub unreadable: Vec<UnreadableFile>,
+    /// Mismatched files whose digest was replaced, see --update-on-mismatch
+    pub updated: usize,
+    /// Stopped with Ctrl-C before all files were read
+    pub incomplete: bool,
+}
+
+impl DigestReport {
+    pub fn has_problems(&self) -> bool {
+        !self.mismatched.is_empty() || !self.missing.is_empty() || !self.unreadable.is_empty()
+    }
+}
+
+impl fmt::Display for DigestReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for file in &self.mismatched {
+            writeln!(f, "Changed: {}", file.path.to_string_lossy())?;
+        }
+        for path in &self.missing {
+            writeln!(f, "Missing: {}", path.to_string_lossy())?;
+        }
+        for file in &self.unreadable {
+            writeln!(
+                f,
+                "Unreadable: {}: {}",
+                file.path.to_string_lossy(),
+                file.error
+            )?;
+        }
+        write!(
+            f,
+            "{} files checked, {} changed, {} missing, {} unreadable",
+            self.checked,
+            self.mismatched.len(),
+            self.missing.len(),
+            self.unreadable.len()
+        )?;
+        if self.updated > 0 {
+            write!(f, ", {} digests updated", self.updated)?;
+        }
+        if self.incomplete {
+            write!(f, ", stopped before all files were checked")?;
+        }
+        Ok(())
+    }
+}
+
+/// Number of hashed files that can wait for the comparison with the DB
+const VERIFY_QUEUE_SIZE: usize = 1024;
+
+/// Hashes all indexed files that still exist again, in parallel like scans do, and
+/// reports those whose digest changed. With `update_on_mismatch`, their stored digest
+/// and size are replaced by the current ones, see `Database::update_filedigest_digest`.
+pub fn verify_digests(
+    db_mutex: &Mutex<Database>,
+    update_on_mismatch: bool,
+    progress: &Progress,
+) -> Result<DigestReport> {
+    let files = if let Ok(db) = db_mutex.lock() {
+        db.get_all_filedigests()?
+    } else {
+        return Err(anyhow!("Unable to lock DB"));
+    };
+    let mut report = DigestReport::default();
+    let (existing, missing): (Vec<FileDigest>, Vec<FileDigest>) =
+        files.into_iter().partition(|f| f.path.exists());
+    report.missing = missing.into_iter().map(|f| f.path).collect();
+
+    let phase = progress.phase(Phase::Hashing);
+    phase.start();
+    phase.add_total(existing.len(), existing.iter().map(|f| f.size).sum());
+    let (tx, rx) = mpsc::sync_channel(VERIFY_QUEUE_SIZE);
+    rayon::spawn(move || {
+        existing
+            .into_par_iter()
+            .map(|file| {
+                let current = fs::metadata(&file.path)
+                    .and_then(|meta| Ok((filehashing::file_digest(&file.path)?, meta.len())));
+                (file, current)
+            })
+            .try_for_each_with(tx, |tx, f| tx.send(f).map_err(|_| ()))
+            // only fails once verifying stopped early
+            .ok();
+    });
+    for (file, current) in rx {
+        match current {
+            Ok((digest, size)) => {
+                phase.add_file(file.size, false);
+                report.checked += 1;
+                if digest != file.digest {
+                    if update_on_mismatch {
+                        if let Ok(db) = db_mutex.lock() {
+                            db.update_filedigest_digest(file.id, &digest, size)?;
+                        } else {
+                            return Err(anyhow!("Unable to lock DB"));
+                        }
+                        report.updated += 1;
+                    }
+                    report.mismatched.push(DigestMismatch {
+                        id: file.id,
+                        path: file.path,
+                        stored_digest: hex::encode(&file.digest),
+                        current_digest: hex::encode(&digest),
+                    });
+                }
+            }
+            Err(e) => {
+                phase.add_file(0, true);
+                report.unreadable.push(UnreadableFile {
+                    path: file.path,
+                    error: e.to_string(),
+                });
+            }
+        }
+        progress.update(Phase::Hashing);
+        if progress.stop_requested() {
+            report.incomplete = true;
+            break;
+        }
+    }
+    progress.finish(Phase::Hashing);
+    report.mismatched.sort_by(|a, b| a.path.cmp(&b.path));
+    report.missing.sort();
+    report.unreadable.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(report)
+}
+
+/// Reads the next chunk, retrying short reads so both files advance in step.
+pub(crate) fn read_chunk(file: &mut File, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match file.read(&mut buffer[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+/// The blake2 digest like scans compute it, or None if `deadline` passed first.
+fn digest_until(path: &Path, deadline: Instant) -> io::Result<Option<Vec<u8>>> {
+    let mut file = File::open(path)?;
+    let mut hasher = Blake2b::default();
+    let mut buffer = vec![0u8; CHUNK_SIZE];
+    loop {
+        if Instant::now() >= deadline {
+            return Ok(None);
+        }
+        let n = read_chunk(&mut file, &mut buffer)?;
+        if n == 0 {
+            return Ok(Some(hasher.finalize().to_vec()));
+        }
+        hasher.update(&buffer[..n]);
+    }
+}
+
+/// Compares two files byte by byte, stopping at the first difference.
+pub fn compare_files(a: &Path, b: &Path, deadline: Instant) -> io::Result<Verdict> {
+    let (mut a, mut b) = (File::open(a)?, File::open(b)?);
+    let (mut buffer_a, mut buffer_b) = (vec![0u8; CHUNK_SIZE], vec![0u8; CHUNK_SIZE]);
+    let mut offset = 0u64;
+    loop {
+        if Instant::now() >= deadline {
+            return Ok(Verdict::Cancelled);
+        }
+        let n_a = read_chunk(&mut a, &mut buffer_a)?;
+        let n_b = read_chunk(&mut b, &mut buffer_b)?;
+        let n = n_a.min(n_b);
+        if let Some(i) = (0..n).find(|&i| buffer_a[i] != buffer_b[i]) {
+            return Ok(Verdict::DiffersAt {
+                offset: offset + i as u64,
+            });
+        }
+        if n_a != n_b {
+            return Ok(Verdict::DiffersAt {
+                offset: offset + n as u64,
+            });
+        }
+        if n == 0 {
+            return Ok(Verdict::Identical);
+        }
+        offset += n as u64;
+    }
+}
+
+/// The members of a group with their stored digests, or None if there is no such
+/// group. Only this needs the database, reading the files doesn't.
+pub fn group_members(db: &Database, group_id: &str) -> Result<Option<Vec<FileDigest>>> {
+    let group = match similarities::get_group(db, group_id)? {
+        Some(group) => group,
+        None => return Ok(None),
+    };
+    let members: Result<Vec<FileDigest>> = group
+        .files
+        .iter()
+        .map(|f| db.lookup_filedigest(f.id))
+        .collect();
+    Ok(Some(members?))
+}
+
+/// Reads all members again, recomputes their digests and compares each member with
+/// the first one.
+pub fn verify_members(members: &[FileDigest], deadline: Instant) -> Vec<MemberCheck> {
+    members
+        .iter()
+        .enumerate()
+        .map(|(i, member)| {
+            let digest_matches = match digest_until(&member.path, deadline) {
+                Ok(digest) => digest.map(|d| d == member.digest),
+                Err(_) => None,
+            };
+            let verdict = if i == 0 {
+                None
+            } else {
+                Some(
+                    match compare_files(&members[0].path, &member.path, deadline) {
+                        Ok(verdict) => verdict,
+                        Err(e) => Verdict::Unreadable {
+                            error: e.to_string(),
+                        },
+                    },
+                )
+            };
+            MemberCheck {
+                id: member.id,
+                path: member.path.clone(),
+                digest_matches,
+                verdict,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filehashing::create_filedigest;
+    use rusqlite::params;
+    use tempfile::tempdir;
+
+    fn far_deadline() -> Instant {
+        Instant::now() + VERIFY_TIMEOUT
+    }
+
+    #[test]
+    fn test_compare_files() -> Result<()> {
+        let dir = tempdir()?;
+        let path = |name: &str| dir.path().join(name);
+        let content: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(path("a"), &content)?;
+        fs::write(path("b"), &content)?;
+        let mut changed = content.clone();
+        changed[150_000] ^= 1;
+        fs::write(path("c"), &changed)?;
+        fs::write(path("d"), &content[..100_000])?;
+
+        assert_eq!(
+            compare_files(&path("a"), &path("b"), far_deadline())?,
+            Verdict::Identical
+        );
+        assert_eq!(
+            compare_files(&path("a"), &path("c"), far_deadline())?,
+            Verdict::DiffersAt { offset: 150_000 }
+        );
+        assert_eq!(
+            compare_files(&path("a"), &path("d"), far_deadline())?,
+            Verdict::DiffersAt { offset: 100_000 }
+        );
+        assert_eq!(
+            compare_files(&path("a"), &path("b"), Instant::now())?,
+            Verdict::Cancelled
+        );
+        assert!(compare_files(&path("a"), &path("missing"), far_deadline()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_group() -> Result<()> {
+        let dir = tempdir()?;
+        let db = Database::new("test_verify_group.sqlite", true)?;
+        let paths: Vec<PathBuf> = ["a", "b", "c", "d"]
+            .iter()
+            .map(|n| dir.path().join(n))
+            .collect();
+        for path in paths.iter() {
+            fs::write(path, "the same content")?;
+            db.insert_filedigest(&create_filedigest(path)?)?;
+        }
+        // changed after indexing, and removed
+        fs::write(&paths[2], "the same CONTENT")?;
+        fs::remove_file(&paths[3])?;
+        let group_id = hex::encode(&db.get_all_filedigests()?[0].digest[..8]);
+
+        let members = group_members(&db, &group_id)?.unwrap();
+        let checks: Vec<(Option<bool>, Option<Verdict>)> = verify_members(&members, far_deadline())
+            .into_iter()
+            .map(|m| (m.digest_matches, m.verdict))
+            .collect();
+        assert_eq!(checks[0], (Some(true), None));
+        assert_eq!(checks[1], (Some(true), Some(Verdict::Identical)));
+        assert_eq!(
+            checks[2],
+            (Some(false), Some(Verdict::DiffersAt { offset: 9 }))
+        );
+        assert_eq!(checks[3].0, None);
+        assert!(matches!(checks[3].1, Some(Verdict::Unreadable { .. })));
+
+        db.insert_filedigest(&FileDigest::new(0, "/tmp/single", vec![7; 8], 1))?;
+        assert!(group_members(&db, "0707070707070707")?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_digests() -> Result<()> {
+        let dir = tempdir()?;
+        let db = Database::new("test_verify_digests.sqlite", true)?;
+        let paths: Vec<PathBuf> = ["a", "b", "c"].iter().map(|n| dir.path().join(n)).collect();
+        for path in paths.iter() {
+            fs::write(path, "some content")?;
+            db.insert_filedigest(&create_filedigest(path)?)?;
+        }
+        // a flipped bit, same size
+        fs::write(&paths[1], "some contenu")?;
+        fs::remove_file(&paths[2])?;
+        db.db.execute(
+            "INSERT INTO image_hashes (id, dhash) VALUES (1, 0), (2, 0)",
+            params![],
+        )?;
+        let db_mutex = Mutex::new(db);
+
+        let report = verify_digests(&db_mutex, false, &Progress::default())?;
+        assert_eq!(report.checked, 1 + 1);
+        assert_eq!(report.mismatched.len(), 1);
+        let mismatch = &report.mismatched[0];
+        assert_eq!(mismatch.path, paths[1]);
+        assert_ne!(mismatch.stored_digest, mismatch.current_digest);
+        assert_eq!(report.missing, [paths[2].clone()]);
+        assert!(report.unreadable.is_empty() && report.has_problems());
+        assert!(report
+            .to_string()
+            .ends_with("2 files checked, 1 changed, 1 missing, 0 unreadable"));
+        let json: serde_json::Value = serde_json::to_value(&report)?;
+        assert_eq!(
+            json["mismatched"][0]["current_digest"],
+            mismatch.current_digest
+        );
+
+        // the stored digest is only replaced when asked to
+        let report = verify_digests(&db_mutex, true, &Progress::default())?;
+        assert_eq!((report.mismatched.len(), report.updated), (1, 1));
+        let stored = db_mutex.lock().unwrap().lookup_filedigest(mismatch.id)?;
+        assert_eq!(hex::encode(&stored.digest), mismatch.current_digest);
+        let report = verify_digests(&db_mutex, false, &Progress::default())?;
+        assert!(report.mismatched.is_empty());
+
+        // a grown file gets its size, and what was derived from the old content is gone
+        fs::write(&paths[1], "some longer content")?;
+        let report = verify_digests(&db_mutex, true, &Progress::default())?;
+        assert_eq!(report.updated, 1);
+        let db = db_mutex.lock().unwrap();
+        assert_eq!(db.lookup_filedigest(mismatch.id)?.size, 19);
+        let hashed: Vec<i64> = db
+            .db
+            .prepare("SELECT id FROM image_hashes")?
+            .query_map(params![], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        assert_eq!(hashed, [1]);
+        Ok(())
+    }
+}
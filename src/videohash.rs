@@ -1,51 +1,295 @@
+use crate::batcher::{self, BatchLimits, Batcher};
 use crate::database::Database;
+use crate::filehashing::{self, ScanOrder};
+use crate::paths;
+use crate::scanstats::{Phase, Progress, ScanStats};
+use crate::similarities;
+use crate::throttle;
+use crate::unionfind;
+use crate::urls;
 use anyhow::{anyhow, Result};
+use blake2::{Blake2b, Digest};
 use ffmpeg_next as ffmpeg;
 use log;
 use ndarray::prelude::*;
 use rayon::prelude::*;
 use rusqlite::params;
+use serde::ser::{SerializeStruct, Serializer};
 use serde::Serialize;
-use std::collections::HashMap;
-use std::sync::{mpsc, Mutex};
-use std::time::Instant;
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::fmt;
+use std::path::{Path, PathBuf, MAIN_SEPARATOR};
+use std::sync::Mutex;
 
 const NUM_BUCKETS_SHIFT: usize = 6;
 const NUM_BUCKETS: usize = 256 >> NUM_BUCKETS_SHIFT;
+pub const HISTOGRAM_LEN: usize = NUM_BUCKETS * NUM_BUCKETS * NUM_BUCKETS;
 
-#[derive(Debug, PartialEq, Serialize)]
+/// Files that are considered videos
+pub const VIDEO_EXTENSIONS: [&str; 5] = ["mp4", "avi", "mkv", "wmv", "flv"];
+/// Files that are probed for metadata in addition to videos
+pub const AUDIO_EXTENSIONS: [&str; 6] = ["mp3", "flac", "m4a", "ogg", "opus", "wav"];
+
+pub const DEFAULT_FRAME_SIZE: u32 = 128;
+const MIN_FRAME_SIZE: u32 = 16;
+const MAX_FRAME_SIZE: u32 = 512;
+
+/// Settings used when computing video hashes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VideoHashConfig {
+    /// Frames are scaled to frame_size x frame_size pixels before computing histograms
+    pub frame_size: u32,
+    /// Videos shorter than this many seconds are skipped
+    pub min_duration: f64,
+    /// In which order videos are hashed
+    pub order: ScanOrder,
+    /// Number of videos decoded at once
+    pub threads: usize,
+}
+
+/// (id, path, size, duration) of a video that still needs to be hashed. The duration is
+/// known if the video was probed already.
+type UnhashedVideo = (i64, String, u64, Option<f64>);
+
+/// Stream parameters of a video or audio file, read without decoding it. Files that
+/// couldn't be probed have all fields set to None.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize)]
+pub struct MediaInfo {
+    /// in seconds
+    pub duration: Option<f64>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+
+/// Very short clips like intros have degenerate histograms that cluster with everything.
+/// Videos of unknown length are never considered too short.
+pub fn is_too_short(duration: Option<f64>, min_duration: f64) -> bool {
+    matches!(duration, Some(d) if d < min_duration)
+}
+
+/// Parses and validates the `--videohash-frame-size` argument.
+pub fn parse_frame_size(s: &str) -> Result<u32> {
+    let size: u32 = s.parse()?;
+    if !(MIN_FRAME_SIZE..=MAX_FRAME_SIZE).contains(&size) {
+        return Err(anyhow!(
+            "Frame size must be between {} and {}, got {}",
+            MIN_FRAME_SIZE,
+            MAX_FRAME_SIZE,
+            size
+        ));
+    }
+    Ok(size)
+}
+
+#[derive(Debug, PartialEq)]
 pub struct VideoHash {
     pub id: i64,
     pub path: String,
     pub histogram: Vec<u8>,
-    pub size: u64, // We need size only for logging purposes
+    pub size: u64,             // We need size only for logging purposes
+    pub digest: Vec<u8>,       // file digest, used to identify clusters
+    pub frame_size: u32,       // histograms are only comparable at the same frame size
+    pub duration: Option<f64>, // in seconds, unknown for hashes of older versions
+}
+
+/// Serialized with a `file_url` for opening the video in a local player, see
+/// `urls::file_url`. It isn't stored, as there are many more hashes than rendered ones.
+impl Serialize for VideoHash {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("VideoHash", 5)?;
+        state.serialize_field("id", &self.id)?;
+        state.serialize_field("path", &self.path)?;
+        state.serialize_field("file_url", &urls::file_url(Path::new(&self.path)))?;
+        state.serialize_field("histogram", &self.histogram)?;
+        state.serialize_field("size", &self.size)?;
+        state.end()
+    }
+}
+
+impl similarities::GroupMember for VideoHash {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn path(&self) -> &std::path::Path {
+        std::path::Path::new(&self.path)
+    }
+}
+
+/// A cluster of similar videos.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct VideoGroup<'a> {
+    pub group_id: String,
+    pub files: Vec<&'a VideoHash>,
+}
+
+impl<'a> VideoGroup<'a> {
+    pub fn new(files: Vec<&'a VideoHash>) -> VideoGroup<'a> {
+        VideoGroup {
+            group_id: cluster_id(&files),
+            files,
+        }
+    }
+}
+
+/// Used by the similar videos pages if no threshold is given, see --videohash-threshold.
+pub const DEFAULT_THRESHOLD: u16 = 64;
+
+/// The smallest threshold that clusters all videos of the same frame size. Larger ones
+/// give the same clusters, as `find_similar_files` compares strictly.
+pub fn max_threshold() -> u16 {
+    max_distance(HISTOGRAM_LEN) + 1
+}
+
+/// Explains why `threshold` doesn't change the clustering, if it can't.
+pub fn threshold_warning(threshold: u16) -> Option<String> {
+    if threshold == 0 {
+        Some("A video hash threshold of 0 clusters no videos at all".to_string())
+    } else if threshold > max_threshold() {
+        Some(format!(
+            "Video hash thresholds above {} cluster all videos of the same frame size, like {} does",
+            max_threshold(),
+            max_threshold()
+        ))
+    } else {
+        None
+    }
+}
+
+/// A cluster as printed on the console, see --videohash-threshold.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ClusterReport {
+    pub group_id: String,
+    pub files: Vec<ClusteredVideo>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ClusteredVideo {
+    pub path: String,
+    pub size: u64,
+    pub file_url: String,
+    /// L1 distance of the histogram to the one of the first video of the cluster
+    pub distance: u16,
+}
+
+impl ClusterReport {
+    /// The largest video comes first and is the one the others are compared to.
+    pub fn new(group: &VideoGroup) -> ClusterReport {
+        let mut files = group.files.clone();
+        files.sort_by(|a, b| {
+            Reverse(a.size)
+                .cmp(&Reverse(b.size))
+                .then(a.path.cmp(&b.path))
+        });
+        let first = files[0];
+        ClusterReport {
+            group_id: group.group_id.clone(),
+            files: files
+                .iter()
+                .map(|f| ClusteredVideo {
+                    path: f.path.clone(),
+                    size: f.size,
+                    file_url: urls::file_url(Path::new(&f.path)),
+                    distance: l1_distance(&first.histogram, &f.histogram),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl fmt::Display for ClusterReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}: {} videos", self.group_id, self.files.len())?;
+        for file in &self.files {
+            writeln!(
+                f,
+                "  {} ({} bytes, distance {})",
+                file.path, file.size, file.distance
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Stable id of a cluster, derived from the sorted digests of its members. Unlike exact
+/// groups, the id changes whenever the membership changes. Prefixed with "v" so the ids
+/// can't be confused with the ids of exact-duplicate groups.
+pub fn cluster_id(files: &[&VideoHash]) -> String {
+    let mut digests: Vec<&Vec<u8>> = files.iter().map(|f| &f.digest).collect();
+    digests.sort_unstable();
+    let mut hasher = Blake2b::new();
+    for d in digests {
+        hasher.update(d);
+    }
+    format!("v{}", similarities::group_id(&hasher.finalize()))
 }
 
 impl Database {
-    fn get_files_without_videohash(&self) -> Result<Vec<(i64, String, u64)>> {
+    /// Videos that haven't been hashed yet, optionally only those below `prefix`. Videos
+    /// that were skipped for being too short are included again once `min_duration` no
+    /// longer excludes them. The prefix is compared case-sensitively, and as a whole
+    /// directory name, so /data/videos doesn't include /data/videos2.
+    fn get_files_without_videohash(
+        &self,
+        min_duration: f64,
+        prefix: Option<&Path>,
+    ) -> Result<Vec<UnhashedVideo>> {
+        // substr instead of LIKE, which would treat _ and % in the prefix as wildcards
         let mut stmt = self.db.prepare(
-            "SELECT id, path, size, lower(substr(path, -3)) as ext FROM file_digests \
-             WHERE id NOT IN (SELECT id FROM video_hash) \
-             AND ext IN ('mp4', 'avi', 'mkv', 'wmv', 'avi', 'flv')",
+            "SELECT f.id, f.path, f.size, m.duration FROM file_digests f \
+             LEFT JOIN media_metadata m ON m.id = f.id \
+             WHERE f.id NOT IN (SELECT id FROM video_hash \
+                WHERE histogram IS NOT NULL OR duration < ?1) \
+             AND (?2 IS NULL OR f.path = ?2 OR substr(f.path, 1, length(?3)) = ?3)",
         )?;
-        let ids: Result<Vec<_>, _> = stmt
-            .query_map([], |row| {
+        let prefix = prefix.map(|p| p.to_string_lossy().into_owned());
+        let dir = prefix.as_ref().map(|p| {
+            if p.ends_with(MAIN_SEPARATOR) {
+                p.clone()
+            } else {
+                format!("{}{}", p, MAIN_SEPARATOR)
+            }
+        });
+        let ids: Result<Vec<UnhashedVideo>, _> = stmt
+            .query_map(params![min_duration, prefix, dir], |row| {
                 let path_string: String = row.get(1)?;
-                Ok((row.get(0)?, path_string, row.get(2)?))
+                Ok((row.get(0)?, path_string, row.get(2)?, row.get(3)?))
             })?
-            .into_iter()
             .collect();
-        Ok(ids?)
+        Ok(ids?
+            .into_iter()
+            .filter(|(_, path, _, _)| paths::has_extension(Path::new(path), &VIDEO_EXTENSIONS))
+            .collect())
     }
 
-    fn insert_many_videohashes(&mut self, hashes: &Vec<VideoHash>) -> Result<()> {
+    /// Skipped videos have an empty histogram and are stored without one, so they are
+    /// not hashed again on every update. Only those rows get replaced; ids that already
+    /// have a histogram are logged and counted as conflicts, without losing the rest of
+    /// the batch.
+    fn insert_many_videohashes(
+        &mut self,
+        hashes: &Vec<VideoHash>,
+        stats: &mut ScanStats,
+    ) -> Result<()> {
         let tx = self.db.transaction()?;
-        let mut stmt =
-            tx.prepare("INSERT OR IGNORE INTO video_hash (id, histogram) VALUES (?1, ?2)")?;
+        let mut stmt = tx.prepare(
+            "INSERT INTO video_hash (id, histogram, frame_size, duration) \
+             VALUES (?1, ?2, ?3, ?4) \
+             ON CONFLICT(id) DO UPDATE SET histogram = excluded.histogram, \
+                frame_size = excluded.frame_size, duration = excluded.duration \
+             WHERE video_hash.histogram IS NULL",
+        )?;
         for h in hashes {
-            let cnt = stmt.execute(params![h.id, h.histogram])?;
+            let histogram = if h.histogram.is_empty() {
+                None
+            } else {
+                Some(&h.histogram)
+            };
+            let cnt = stmt.execute(params![h.id, histogram, h.frame_size, h.duration])?;
             if cnt == 0 {
-                return Err(anyhow!("Unable to insert {}", h.id));
+                log::warn!("Unable to insert video hash {}: already hashed", h.id);
+                stats.videohash_conflicts += 1;
             }
         }
         stmt.finalize()?;
@@ -54,24 +298,117 @@ impl Database {
 
     pub fn get_all_files_with_videohash(&self) -> Result<Vec<VideoHash>> {
         let mut stmt = self.db.prepare(
-            "SELECT f.id, f.path, f.size, h.histogram \
+            "SELECT f.id, f.path, f.size, h.histogram, f.digest, h.frame_size, h.duration \
              FROM file_digests f, video_hash h \
-             WHERE f.id == h.id",
+             WHERE f.id == h.id AND h.histogram IS NOT NULL",
         )?;
         let files: Result<Vec<_>, _> = stmt
             .query_map([], |row| {
                 let path_string: String = row.get(1)?;
+                let digest: Option<Vec<u8>> = row.get(4)?;
                 Ok(VideoHash {
                     id: row.get(0)?,
                     path: path_string,
                     size: row.get(2)?,
                     histogram: row.get(3)?,
+                    digest: digest.unwrap_or_default(),
+                    frame_size: row.get(5)?,
+                    duration: row.get(6)?,
                 })
             })?
             .into_iter()
             .collect();
         Ok(files?)
     }
+
+    pub fn count_videohashes(&self) -> Result<usize> {
+        let count: i64 = self.db.query_row(
+            "SELECT COUNT(*) FROM video_hash WHERE histogram IS NOT NULL",
+            params![],
+            |row| row.get(0),
+        )?;
+        Ok(count as usize)
+    }
+
+    /// Removes the hashes of the given files, so they are attempted again by the next
+    /// update, and records why in the video_errors table.
+    pub fn record_video_errors(&self, ids: &[i64], message: &str) -> Result<()> {
+        let tx = self.db.unchecked_transaction()?;
+        for id in ids {
+            tx.execute("DELETE FROM video_hash WHERE id = ?1", params![id])?;
+            tx.execute(
+                "INSERT OR REPLACE INTO video_errors (id, message) VALUES (?1, ?2)",
+                params![id, message],
+            )?;
+        }
+        Ok(tx.commit()?)
+    }
+
+    pub fn get_video_errors(&self) -> Result<Vec<(i64, String)>> {
+        let mut stmt = self
+            .db
+            .prepare("SELECT id, message FROM video_errors ORDER BY id")?;
+        let rows: Result<Vec<_>, _> = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect();
+        Ok(rows?)
+    }
+
+    /// Deletes all video hashes that weren't computed at `frame_size`, so they get
+    /// recomputed by the next update. Returns the number of deleted hashes.
+    /// Video and audio files that haven't been probed yet.
+    fn get_files_without_media_metadata(&self) -> Result<Vec<(i64, String)>> {
+        let mut stmt = self.db.prepare(
+            "SELECT id, path FROM file_digests WHERE id NOT IN (SELECT id FROM media_metadata)",
+        )?;
+        let files: Result<Vec<(i64, String)>, _> = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect();
+        Ok(files?
+            .into_iter()
+            .filter(|(_, path)| {
+                let path = Path::new(path);
+                paths::has_extension(path, &VIDEO_EXTENSIONS)
+                    || paths::has_extension(path, &AUDIO_EXTENSIONS)
+            })
+            .collect())
+    }
+
+    fn insert_many_media_metadata(&mut self, infos: &[(i64, MediaInfo)]) -> Result<()> {
+        let tx = self.db.transaction()?;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT OR REPLACE INTO media_metadata (id, duration, width, height) \
+                 VALUES (?1, ?2, ?3, ?4)",
+            )?;
+            for (id, info) in infos {
+                stmt.execute(params![id, info.duration, info.width, info.height])?;
+            }
+        }
+        Ok(tx.commit()?)
+    }
+
+    pub fn get_media_metadata(&self, file_id: i64) -> Result<Option<MediaInfo>> {
+        let mut stmt = self
+            .db
+            .prepare("SELECT duration, width, height FROM media_metadata WHERE id = ?1")?;
+        let mut rows = stmt.query(params![file_id])?;
+        match rows.next()? {
+            Some(row) => Ok(Some(MediaInfo {
+                duration: row.get(0)?,
+                width: row.get(1)?,
+                height: row.get(2)?,
+            })),
+            None => Ok(None),
+        }
+    }
+
+    pub fn delete_videohashes_with_other_frame_size(&self, frame_size: u32) -> Result<usize> {
+        Ok(self.db.execute(
+            "DELETE FROM video_hash WHERE frame_size != ?1",
+            params![frame_size],
+        )?)
+    }
 }
 
 struct Video {
@@ -79,6 +416,8 @@ struct Video {
     ictx: ffmpeg::format::context::Input,
     scaler: ffmpeg::software::scaling::Context,
     video_stream_index: usize,
+    duration: Option<f64>, // in seconds
+    time_base: f64,        // seconds per timestamp unit of the stream
 }
 
 impl Video {
@@ -95,6 +434,8 @@ impl Video {
                 .best(ffmpeg::media::Type::Video)
                 .ok_or(anyhow!("No video stream found"))?;
             let video_stream_index = input.index();
+            let duration = stream_duration(&ictx, &input);
+            let time_base = f64::from(input.time_base());
 
             //let decoder = input.codec().decoder().video()?;
             let context_decoder = ffmpeg::codec::context::Context::from_parameters(input.parameters())?;
@@ -117,6 +458,8 @@ impl Video {
                 ictx,
                 scaler,
                 video_stream_index,
+                duration,
+                time_base,
             })
         }()
         .map_err(|e| anyhow!("Unable to open {}: {}", filepath.to_string_lossy(), e))
@@ -128,45 +471,165 @@ impl Video {
         self.decoder.send_packet(packet)?;
         self.decoder.receive_frame(&mut decoded)?;
         self.scaler.run(&decoded, &mut rgb_frame)?;
-        return Ok(rgb_frame.data(0).to_vec());
+        // rows can be padded for alignment, which matters for arbitrary frame sizes
+        let row_len = rgb_frame.width() as usize * 3;
+        let pixels = rgb_frame
+            .data(0)
+            .chunks(rgb_frame.stride(0))
+            .take(rgb_frame.height() as usize)
+            .flat_map(|row| &row[..row_len])
+            .cloned()
+            .collect();
+        return Ok(pixels);
     }
-}
-
-impl Iterator for Video {
-    type Item = Vec<u8>;
 
-    fn next(&mut self) -> Option<Vec<u8>> {
+    /// Decodes the next key frame of the video stream, with the time it is shown at in
+    /// seconds if the container tells.
+    fn next_key_frame(&mut self) -> Option<(Option<f64>, Vec<u8>)> {
         loop {
-            let next_packet = self.ictx.packets().next();
-            if next_packet.is_none() {
-                return None;
-            }
-
-            let (stream, packet) = next_packet.unwrap();
-            if stream.index() != self.video_stream_index {
-                continue;
-            }
-
+            let (stream, packet) = self.ictx.packets().next()?;
+            throttle::read(packet.size());
             // Only consider key frames, don't even decode rest (saves a lot of compute)
-            if !packet.is_key() {
+            if stream.index() != self.video_stream_index || !packet.is_key() {
                 continue;
             }
-            let frame = self._decode_frame(&packet);
-            if frame.is_ok() {
-                return Some(frame.unwrap());
+            let time = packet.pts().map(|pts| pts as f64 * self.time_base);
+            if let Ok(frame) = self._decode_frame(&packet) {
+                return Some((time, frame));
             }
         }
     }
+
+    /// Jumps to the last key frame at or before `seconds`, which is the next one decoded.
+    fn seek(&mut self, seconds: f64) -> Result<()> {
+        // without a stream index, ffmpeg seeks in AV_TIME_BASE units
+        let timestamp = (seconds * 1_000_000.0) as i64;
+        self.ictx.seek(timestamp, ..timestamp)?;
+        self.decoder.flush();
+        Ok(())
+    }
+}
+
+/// A key frame decoded by `frames_at`.
+pub struct SampledFrame {
+    /// When the frame is shown in seconds, at or before the requested time
+    pub time: Option<f64>,
+    /// RGB24 pixels
+    pub pixels: Vec<u8>,
+}
+
+/// Decodes the key frames shown at `times` (in seconds), scaled to width x height.
+/// Frames that can't be decoded are None.
+pub fn frames_at(
+    path: &Path,
+    times: &[f64],
+    width: u32,
+    height: u32,
+) -> Result<Vec<Option<SampledFrame>>> {
+    let mut video = Video::new(path, width, height)?;
+    let mut frames = Vec::with_capacity(times.len());
+    for &time in times {
+        let frame = match video.seek(time) {
+            Ok(()) => video.next_key_frame(),
+            Err(e) => {
+                log::debug!(
+                    "Unable to seek to {}s in {}: {}",
+                    time,
+                    path.to_string_lossy(),
+                    e
+                );
+                None
+            }
+        };
+        frames.push(frame.map(|(time, pixels)| SampledFrame { time, pixels }));
+    }
+    Ok(frames)
+}
+
+/// Duration of `stream` in seconds, falling back to the duration of the container.
+fn stream_duration(
+    ictx: &ffmpeg::format::context::Input,
+    stream: &ffmpeg::format::stream::Stream,
+) -> Option<f64> {
+    if stream.duration() > 0 {
+        Some(stream.duration() as f64 * f64::from(stream.time_base()))
+    } else if ictx.duration() > 0 {
+        // container duration is in AV_TIME_BASE units
+        Some(ictx.duration() as f64 / 1_000_000.0)
+    } else {
+        None
+    }
+}
+
+/// Reads duration and resolution from the stream headers, without decoding any frames.
+pub fn probe(path: &Path) -> Result<MediaInfo> {
+    ffmpeg::init()?;
+    let ictx = ffmpeg::format::input(&path)?;
+    let streams = ictx.streams();
+    if let Some(video) = streams.best(ffmpeg::media::Type::Video) {
+        let decoder = ffmpeg::codec::context::Context::from_parameters(video.parameters())?
+            .decoder()
+            .video()?;
+        Ok(MediaInfo {
+            duration: stream_duration(&ictx, &video),
+            width: Some(decoder.width()),
+            height: Some(decoder.height()),
+        })
+    } else if let Some(audio) = streams.best(ffmpeg::media::Type::Audio) {
+        Ok(MediaInfo {
+            duration: stream_duration(&ictx, &audio),
+            ..Default::default()
+        })
+    } else {
+        Err(anyhow!("No video or audio stream found"))
+    }
+}
+
+/// Decodes the first frame of a still image, scaled to fit into max_size x max_size pixels.
+/// Returns width, height and the RGB24 pixels.
+pub fn decode_still(path: &Path, max_size: u32) -> Result<(u32, u32, Vec<u8>)> {
+    let info = probe(path)?;
+    let (width, height) = match (info.width, info.height) {
+        (Some(w), Some(h)) if w > 0 && h > 0 => fit_size(w, h, max_size),
+        _ => return Err(anyhow!("No image found in {}", path.to_string_lossy())),
+    };
+    Ok((width, height, decode_still_scaled(path, width, height)?))
+}
+
+/// Decodes the first frame of a still image, scaled to exactly width x height pixels.
+pub fn decode_still_scaled(path: &Path, width: u32, height: u32) -> Result<Vec<u8>> {
+    Video::new(path, width, height)?
+        .next()
+        .ok_or_else(|| anyhow!("Unable to decode {}", path.to_string_lossy()))
+}
+
+/// Scales width x height down to fit into max_size x max_size, keeping the aspect ratio.
+fn fit_size(width: u32, height: u32, max_size: u32) -> (u32, u32) {
+    let larger = width.max(height);
+    if larger <= max_size {
+        return (width, height);
+    }
+    let scale = |v: u32| ((v as u64 * max_size as u64 / larger as u64) as u32).max(1);
+    (scale(width), scale(height))
 }
 
-fn calculate_color_histogram(path: impl Into<std::path::PathBuf> + Clone) -> Result<Vec<u8>> {
-    const VIDEO_WIDTH: u32 = 128;
-    const VIDEO_HEIGHT: u32 = 128;
+impl Iterator for Video {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        self.next_key_frame().map(|(_, frame)| frame)
+    }
+}
+
+/// Normalized color histogram over RGB24 frames of frame_size x frame_size pixels.
+fn histogram_from_frames(
+    frames: impl Iterator<Item = Vec<u8>>,
+    frame_size: u32,
+) -> Result<Vec<u8>> {
     let mut histogram = Array::<u64, _>::zeros((NUM_BUCKETS, NUM_BUCKETS, NUM_BUCKETS));
-    let video = Video::new(path, VIDEO_HEIGHT, VIDEO_WIDTH)?;
     let mut num_pixel: u64 = 0;
-    let pixel_per_frame: usize = (VIDEO_HEIGHT * VIDEO_WIDTH) as usize;
-    for v in video {
+    let pixel_per_frame: usize = (frame_size * frame_size) as usize;
+    for v in frames {
         for i in 0..pixel_per_frame {
             let idx = i * 3;
             let r: usize = (v[idx + 0] >> NUM_BUCKETS_SHIFT).into();
@@ -177,6 +640,10 @@ fn calculate_color_histogram(path: impl Into<std::path::PathBuf> + Clone) -> Res
         }
     }
 
+    if num_pixel == 0 {
+        return Err(anyhow!("No frames could be decoded"));
+    }
+
     // We bin the counts into different bins
     let n = num_pixel as f64;
     let max = u8::MAX as f64;
@@ -186,80 +653,198 @@ fn calculate_color_histogram(path: impl Into<std::path::PathBuf> + Clone) -> Res
     Ok(flat_histogram.to_vec())
 }
 
+/// `known_duration` comes from an earlier probe, videos that are known to be too short
+/// aren't opened at all.
 fn _create_hash(
     id: i64,
     path: impl Into<std::path::PathBuf> + Clone,
     size: u64,
+    known_duration: Option<f64>,
+    config: &VideoHashConfig,
 ) -> Result<VideoHash> {
-    let h = calculate_color_histogram(path)?;
+    let (duration, h) = if is_too_short(known_duration, config.min_duration) {
+        (known_duration, Vec::new())
+    } else {
+        let video = Video::new(path, config.frame_size, config.frame_size)?;
+        let duration = known_duration.or(video.duration);
+        if is_too_short(duration, config.min_duration) {
+            (duration, Vec::new())
+        } else {
+            (duration, histogram_from_frames(video, config.frame_size)?)
+        }
+    };
+    if h.is_empty() {
+        log::debug!("Skipping video {} of {:?}s", id, duration);
+    }
     Ok(VideoHash {
         id: id,
         histogram: h,
         size: size,
         path: String::new(),
+        digest: Vec::new(),
+        frame_size: config.frame_size,
+        duration,
     })
 }
 
-fn get_files_without_videohash(db_mutex: &Mutex<Database>) -> Result<Vec<(i64, String, u64)>> {
+/// The unhashed videos below any of `prefixes`, or all of them if none are given.
+fn get_files_without_videohash(
+    db_mutex: &Mutex<Database>,
+    min_duration: f64,
+    prefixes: &[PathBuf],
+) -> Result<Vec<UnhashedVideo>> {
     if let Ok(db) = db_mutex.lock() {
-        return Ok(db.get_files_without_videohash()?);
+        if prefixes.is_empty() {
+            return db.get_files_without_videohash(min_duration, None);
+        }
+        let mut files = Vec::new();
+        let mut ids = HashSet::new();
+        for prefix in prefixes {
+            // nested prefixes list the same videos
+            for file in db.get_files_without_videohash(min_duration, Some(prefix))? {
+                if ids.insert(file.0) {
+                    files.push(file);
+                }
+            }
+        }
+        Ok(files)
     } else {
-        return Err(anyhow!("Unable to lock DB"));
+        Err(anyhow!("Unable to lock DB"))
     }
 }
 
-pub fn update_hashes(db_mutex: &Mutex<Database>, commit_batchsize: usize) -> Result<()> {
-    let filelist = get_files_without_videohash(db_mutex)?;
+/// Hashes the videos in the DB that don't have a video hash yet, only those below
+/// `prefixes` if any are given.
+pub fn update_hashes(
+    db_mutex: &Mutex<Database>,
+    batch_limits: BatchLimits,
+    config: &VideoHashConfig,
+    prefixes: &[PathBuf],
+    stats: &mut ScanStats,
+    progress: &Progress,
+) -> Result<()> {
+    let filelist = get_files_without_videohash(db_mutex, config.min_duration, prefixes)?;
     log::info!("Files to process: {:?}", filelist.len());
-    let (tx, rx) = mpsc::channel();
-    rayon::spawn(move || {
+    let phase = progress.phase(Phase::Videohash);
+    phase.start();
+    phase.add_total(filelist.len(), filelist.iter().map(|x| x.2).sum());
+    let filelist = filehashing::order_for_scan(filelist, config.order, |x| (Path::new(&x.1), x.2));
+    // decoders need much more memory than file hashing, so they have threads of their own
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(config.threads)
+        .thread_name(|i| format!("videohash-{}", i))
+        .build()?;
+    let (tx, rx) = batcher::queue(&batch_limits);
+    let config = *config;
+    pool.spawn(move || {
+        // par_bridge hands out the files in order, unlike par_iter which splits the list
         filelist
-            .par_iter()
-            .map(|x| _create_hash(x.0, &x.1, x.2))
+            .into_iter()
+            .par_bridge()
+            .map(|x| _create_hash(x.0, &x.1, x.2, x.3, &config))
             .try_for_each_with(tx, |tx, f| tx.send(f))
-            .expect("expected no send errors");
+            // only fails once hashing stopped early, the videos in flight are lost
+            .ok();
     });
 
-    let mut hashes: Vec<VideoHash> = Vec::new();
-    let mut time_last_commit = Instant::now();
-    for hist in rx.iter() {
+    let mut hashes: Batcher<VideoHash> = Batcher::new(batch_limits);
+    let mut num_skipped = 0;
+    while let Some(hist) = rx.recv() {
         match hist {
-            Ok(h) => hashes.push(h),
-            Err(err) => log::warn!("Error while processing filelist: {:?}", err),
+            Ok(h) => {
+                if h.histogram.is_empty() {
+                    num_skipped += 1;
+                }
+                phase.add_file(h.size, false);
+                hashes.push(h)
+            }
+            Err(err) => {
+                log::warn!("Error while processing filelist: {:?}", err);
+                phase.add_file(0, true);
+            }
         };
-        if hashes.len() < commit_batchsize {
+        progress.update(Phase::Videohash);
+        if progress.stop_requested() {
+            log::warn!("Video hashing stopped, the remaining videos are hashed on the next scan");
+            break;
+        }
+        if !hashes.is_full() {
             continue;
         }
 
-        // Submitting batch
-        let dt = time_last_commit.elapsed().as_secs_f64();
-        time_last_commit = Instant::now();
-        let total_size_mb = hashes.iter().map(|f| f.size).sum::<u64>() / (1024 * 1024);
-        let mps = total_size_mb as f64 / dt;
-        let fps = commit_batchsize as f64 / dt;
+        // Submitting batch, hashing goes on until the queue is full
+        phase.set_queued(rx.depth());
         log::debug!(
-            "Committing to DB (speed: {:3.2} MiB/s, {:3.2} files/s)",
-            mps,
-            fps
+            "Committing {} video hashes, {} queued",
+            hashes.items().len(),
+            rx.depth()
         );
         if let Ok(mut db) = db_mutex.lock() {
-            db.insert_many_videohashes(&hashes)?;
+            db.insert_many_videohashes(hashes.items(), stats)?;
         } else {
             return Err(anyhow!("Unable to lock DB"));
         }
+        progress.report(Phase::Videohash);
         hashes.clear();
     }
 
-    if hashes.len() > 0 {
+    if !hashes.is_empty() {
         if let Ok(mut db) = db_mutex.lock() {
-            db.insert_many_videohashes(&hashes)?;
+            db.insert_many_videohashes(hashes.items(), stats)?;
         } else {
             return Err(anyhow!("Unable to lock DB"));
         }
     }
+    progress.finish(Phase::Videohash);
+    log::info!(
+        "Skipped {} videos shorter than {}s",
+        num_skipped,
+        config.min_duration
+    );
     Ok(())
 }
 
+/// Stores duration and resolution of all video and audio files that weren't probed
+/// yet, independent of video hashing. Returns the number of probed files.
+pub fn probe_media(db_mutex: &Mutex<Database>, commit_batchsize: usize) -> Result<usize> {
+    probe_media_with(db_mutex, commit_batchsize, probe)
+}
+
+fn probe_media_with<F>(
+    db_mutex: &Mutex<Database>,
+    commit_batchsize: usize,
+    probe: F,
+) -> Result<usize>
+where
+    F: Fn(&Path) -> Result<MediaInfo> + Sync,
+{
+    let filelist = if let Ok(db) = db_mutex.lock() {
+        db.get_files_without_media_metadata()?
+    } else {
+        return Err(anyhow!("Unable to lock DB"));
+    };
+    log::info!("Files to probe: {:?}", filelist.len());
+    for batch in filelist.chunks(commit_batchsize.max(1)) {
+        let infos: Vec<(i64, MediaInfo)> = batch
+            .par_iter()
+            .map(|(id, path)| {
+                // failed probes are stored as well, so they aren't retried on every run
+                let info = probe(Path::new(path)).unwrap_or_else(|e| {
+                    log::debug!("Unable to probe {}: {}", path, e);
+                    MediaInfo::default()
+                });
+                (*id, info)
+            })
+            .collect();
+        if let Ok(mut db) = db_mutex.lock() {
+            db.insert_many_media_metadata(&infos)?;
+        } else {
+            return Err(anyhow!("Unable to lock DB"));
+        }
+    }
+    Ok(filelist.len())
+}
+
 fn l1_distance(a: &Vec<u8>, b: &Vec<u8>) -> u16 {
     let mut dist = 0;
     for i in 0..a.len() {
@@ -268,6 +853,13 @@ fn l1_distance(a: &Vec<u8>, b: &Vec<u8>) -> u16 {
     dist as u16
 }
 
+/// Largest L1 distance two histograms of the given length can have.
+/// Histograms are normalized so their buckets sum up to at most u8::MAX.
+pub fn max_distance(histogram_len: usize) -> u16 {
+    let max = u8::MAX as usize;
+    std::cmp::min(histogram_len * max, 2 * max) as u16
+}
+
 pub fn calculate_distances(files: &Vec<VideoHash>) -> Array2<u16> {
     let mut dist: Array2<u16> = Array::zeros((files.len(), files.len()));
     for (i, a) in files.iter().enumerate() {
@@ -284,59 +876,76 @@ pub fn calculate_distances(files: &Vec<VideoHash>) -> Array2<u16> {
     dist
 }
 
-pub fn find_similar_files<'a, 'b>(
-    files: &'a Vec<VideoHash>,
-    dist: &'b Array2<u16>,
-    threshold: u16,
-) -> Vec<Vec<&'a VideoHash>> {
-    // datastructures and functions for Union-Find
-    let mut parent = Vec::with_capacity(files.len());
-    fn _find(y: usize, parent: &mut Vec<usize>) -> usize {
-        let mut x = y;
-        while parent[x] != x {
-            let tmp = x;
-            x = parent[x];
-            parent[tmp] = parent[parent[x]];
-        }
-        return x;
-    }
-    fn _union(x: usize, y: usize, parent: &mut Vec<usize>) {
-        let x_root = _find(x, parent);
-        let y_root = _find(y, parent);
-
-        if x_root == y_root {
-            return;
-        }
+/// A file found close to the one a search started from.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Neighbor<'a> {
+    #[serde(flatten)]
+    pub file: &'a VideoHash,
+    pub distance: u16,
+}
 
-        // TODO: no union by size/rank
-        parent[x_root] = y_root;
+/// The distance between the histograms of two videos, None if they can't be compared.
+pub fn distance(a: &VideoHash, b: &VideoHash) -> Option<u16> {
+    if a.frame_size != b.frame_size || a.histogram.is_empty() || b.histogram.is_empty() {
+        return None;
     }
+    Some(l1_distance(&a.histogram, &b.histogram))
+}
 
-    // files[i] is stored at parent[i]
-    for i in 0..files.len() {
-        parent.push(i);
-    }
-    for i in 0..files.len() {
-        if files[i].histogram.iter().all(|&x| x == 0) {
-            continue;
-        }
-        for j in i..files.len() {
-            if dist[[i, j]] < threshold {
-                _union(i, j, &mut parent);
-            }
-        }
-    }
+/// The files whose histograms are closer than `threshold` to the one of `file`, closest
+/// first. Unlike find_similar_files, this only needs a single pass over `files`.
+pub fn nearest_neighbors<'a>(
+    file: &VideoHash,
+    files: &'a [VideoHash],
+    threshold: u16,
+) -> Vec<Neighbor<'a>> {
+    let mut neighbors: Vec<Neighbor<'a>> = files
+        .iter()
+        .filter(|f| f.id != file.id && f.frame_size == file.frame_size)
+        .map(|f| Neighbor {
+            file: f,
+            distance: l1_distance(&file.histogram, &f.histogram),
+        })
+        .filter(|n| n.distance < threshold)
+        .collect();
+    neighbors.sort_by_key(|n| (n.distance, n.file.id));
+    neighbors
+}
 
-    let mut filebags = HashMap::new();
-    for (idx, f) in files.iter().enumerate() {
-        let parent_idx = _find(idx, &mut parent);
-        let bag = filebags
-            .entry(parent_idx)
-            .or_insert(Vec::<&VideoHash>::new());
-        bag.push(f);
+/// Logs a warning if the hashes were computed at different frame sizes, since those
+/// can't be compared with each other.
+pub fn warn_on_mixed_frame_sizes(files: &[VideoHash]) {
+    let mut sizes: Vec<u32> = files.iter().map(|f| f.frame_size).collect();
+    sizes.sort_unstable();
+    sizes.dedup();
+    if sizes.len() > 1 {
+        log::warn!(
+            "Video hashes were computed at different frame sizes {:?}, only hashes of the same \
+             size are compared. Use --rehash-videos to recompute them",
+            sizes
+        );
     }
+}
 
-    filebags.into_values().filter(|x| x.len() > 1).collect()
+/// Clusters files whose histograms are closer than `threshold`. Files shorter than
+/// `min_duration` are left out, which matters for hashes from before short videos
+/// were skipped during hashing.
+pub fn find_similar_files<'a, 'b>(
+    files: &'a Vec<VideoHash>,
+    dist: &'b Array2<u16>,
+    threshold: u16,
+    min_duration: f64,
+) -> Vec<Vec<&'a VideoHash>> {
+    let too_short: Vec<bool> = files
+        .iter()
+        .map(|f| is_too_short(f.duration, min_duration))
+        .collect();
+    unionfind::cluster_by(files, |i, j| {
+        !too_short[i]
+            && !too_short[j]
+            && files[i].frame_size == files[j].frame_size
+            && dist[[i, j]] < threshold
+    })
 }
 
 #[cfg(test)]
@@ -347,7 +956,8 @@ mod tests {
     // only used during development
     //#[test]
     fn _test_color_() -> Result<()> {
-        let h = calculate_color_histogram("/media/scratch/vid1_720p.mp4")?;
+        let video = Video::new("/media/scratch/vid1_720p.mp4", 128, 128)?;
+        let h = histogram_from_frames(video, 128)?;
         //println!("Histogram shape: {:?}, sum: {}", h.shape(), h.sum());
         println!("Histogram: {:?}", h);
         Ok(())
@@ -368,12 +978,52 @@ mod tests {
             params![],
         )?;
 
-        let files = db.get_files_without_videohash()?;
+        let files = db.get_files_without_videohash(0.0, None)?;
         let ids: Vec<i64> = files.into_iter().map(|x| x.0).collect();
         assert_eq!(ids, [1, 4]);
         Ok(())
     }
 
+    #[test]
+    fn test_get_files_without_videohash_below_prefix() -> Result<()> {
+        let db = Database::new("test_get_files_without_videohash_below_prefix.sqlite", true)?;
+        db.db.execute(
+            "INSERT INTO file_digests (id, path, size) VALUES \
+                (1, '/data/videos/a.mp4', 1), (2, '/data/videos/sub/b.mkv', 1), \
+                (3, '/data/videos2/c.mp4', 1), (4, '/data/vid_os/d.mp4', 1), \
+                (5, '/data/Videos/e.mp4', 1), (6, '/data/videos/f.jpg', 1), \
+                (7, '/data/videos/g.avi', 1)",
+            params![],
+        )?;
+        db.db.execute(
+            "INSERT INTO video_hash (id, histogram) VALUES (7, x'01')",
+            params![],
+        )?;
+        let ids = |prefix: &str| -> Result<Vec<i64>> {
+            let files = db.get_files_without_videohash(0.0, Some(Path::new(prefix)))?;
+            Ok(files.into_iter().map(|x| x.0).collect())
+        };
+        // neither siblings with the same start, wildcards nor other cases match
+        assert_eq!(ids("/data/videos")?, [1, 2]);
+        assert_eq!(ids("/data/videos/")?, [1, 2]);
+        assert_eq!(ids("/data/videos/sub")?, [2]);
+        assert_eq!(ids("/data/vid_os")?, [4]);
+        assert_eq!(ids("/data/videos/a.mp4")?, [1]);
+        assert!(ids("/data/vid")?.is_empty());
+        assert_eq!(db.get_files_without_videohash(0.0, None)?.len(), 5);
+
+        // nested prefixes list each video once
+        let db_mutex = Mutex::new(db);
+        let prefixes = [
+            PathBuf::from("/data/videos"),
+            PathBuf::from("/data/videos/sub"),
+        ];
+        let files = get_files_without_videohash(&db_mutex, 0.0, &prefixes)?;
+        let ids: Vec<i64> = files.into_iter().map(|x| x.0).collect();
+        assert_eq!(ids, [1, 2]);
+        Ok(())
+    }
+
     #[test]
     fn test_get_all_files_with_videohash() -> Result<()> {
         let db = Database::new("test_get_all_files_with_videohash.sqlite", true)?;
@@ -399,12 +1049,18 @@ mod tests {
             path: "/tmp/c.wmv".to_string(),
             size: 12,
             histogram: vec![170, 170, 170, 170],
+            digest: Vec::new(),
+            frame_size: DEFAULT_FRAME_SIZE,
+            duration: None,
         });
         target_list.push(VideoHash {
             id: 4,
             path: "/tmp/d.avi".to_string(),
             size: 13,
             histogram: vec![170, 170, 170, 171],
+            digest: Vec::new(),
+            frame_size: DEFAULT_FRAME_SIZE,
+            duration: None,
         });
         assert_eq!(files, target_list);
         Ok(())
@@ -430,13 +1086,399 @@ mod tests {
         let files = db.get_all_files_with_videohash()?;
         let threshold = 128;
         let dist = calculate_distances(&files);
-        let similar_files = find_similar_files(&files, &dist, threshold);
+        let similar_files = find_similar_files(&files, &dist, threshold, 0.0);
         let res: HashSet<Vec<i64>> = similar_files
             .iter()
             .map(|b| b.iter().map(|x| x.id).collect())
             .collect();
         let expected = HashSet::from([vec![3, 5], vec![1, 2]]);
         assert_eq!(res, expected);
+
+        let mut reports: Vec<ClusterReport> = similar_files
+            .into_iter()
+            .map(|g| ClusterReport::new(&VideoGroup::new(g)))
+            .collect();
+        reports.sort_by_key(|r| r.files[0].size);
+        let summary: Vec<Vec<(&str, u64, u16)>> = reports
+            .iter()
+            .map(|r| {
+                r.files
+                    .iter()
+                    .map(|f| (f.path.as_str(), f.size, f.distance))
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                vec![("/tmp/b.mp4", 11, 0), ("/tmp/a.mp4", 10, 1)],
+                vec![("tmp/e.wmv", 15, 0), ("/tmp/c.wmv", 12, 2)],
+            ]
+        );
+        assert!(reports[0]
+            .to_string()
+            .ends_with("  /tmp/a.mp4 (10 bytes, distance 1)\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_threshold_warning() {
+        assert!(threshold_warning(0).is_some());
+        assert!(threshold_warning(DEFAULT_THRESHOLD).is_none());
+        assert!(threshold_warning(max_threshold()).is_none());
+        assert!(threshold_warning(max_threshold() + 1).is_some());
+    }
+
+    // only used during development, run with --ignored --nocapture to compare the
+    // clustering with a plain union-find loop
+    #[test]
+    #[ignore]
+    fn bench_find_similar_files() {
+        use crate::unionfind::UnionFind;
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+        use std::time::Instant;
+
+        let mut rng = StdRng::seed_from_u64(1);
+        let files: Vec<VideoHash> = (0..3000)
+            .map(|id| VideoHash {
+                id,
+                path: format!("/tmp/{}.mp4", id),
+                histogram: (0..64).map(|_| rng.gen_range(0..4)).collect(),
+                size: 1,
+                digest: Vec::new(),
+                frame_size: DEFAULT_FRAME_SIZE,
+                duration: None,
+            })
+            .collect();
+        let dist = calculate_distances(&files);
+        let threshold = 70;
+
+        let start = Instant::now();
+        let clusters = find_similar_files(&files, &dist, threshold, 0.0);
+        let clustered = start.elapsed();
+
+        let start = Instant::now();
+        let mut sets = UnionFind::new(files.len());
+        for i in 0..files.len() {
+            for j in i + 1..files.len() {
+                if files[i].frame_size == files[j].frame_size && dist[[i, j]] < threshold {
+                    sets.union(i, j);
+                }
+            }
+        }
+        let baseline = sets.clusters();
+        let inline = start.elapsed();
+
+        assert_eq!(clusters.len(), baseline.len());
+        println!(
+            "{} clusters, cluster_by: {:?}, inline loop: {:?}",
+            clusters.len(),
+            clustered,
+            inline
+        );
+    }
+
+    #[test]
+    fn test_mixed_frame_sizes_are_not_compared() -> Result<()> {
+        let db = Database::new("test_mixed_frame_sizes.sqlite", true)?;
+        db.db.execute(
+            "INSERT INTO file_digests (id, path, size) VALUES \
+                (1, '/tmp/a.mp4', 10), (2, '/tmp/b.mp4', 11), (3, '/tmp/c.mp4', 12)",
+            params![],
+        )?;
+        db.db.execute(
+            "INSERT INTO video_hash (id, histogram, frame_size) VALUES \
+            (1, x'ff00ff00', 128), (2, x'ff00ff00', 64), (3, x'ff01ff00', 128)",
+            params![],
+        )?;
+        let files = db.get_all_files_with_videohash()?;
+        let dist = calculate_distances(&files);
+        let ids: Vec<Vec<i64>> = find_similar_files(&files, &dist, 128, 0.0)
+            .iter()
+            .map(|b| b.iter().map(|x| x.id).collect())
+            .collect();
+        assert_eq!(ids, vec![vec![1, 3]]);
+
+        assert_eq!(db.delete_videohashes_with_other_frame_size(128)?, 1);
+        let ids: Vec<i64> = db
+            .get_files_without_videohash(0.0, None)?
+            .iter()
+            .map(|x| x.0)
+            .collect();
+        assert_eq!(ids, [2]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_serialized_file_url() -> Result<()> {
+        let video = VideoHash {
+            id: 1,
+            path: "/videos/a #1.mp4".to_string(),
+            histogram: vec![1, 2],
+            size: 3,
+            digest: vec![4; 8],
+            frame_size: DEFAULT_FRAME_SIZE,
+            duration: None,
+        };
+        let json: serde_json::Value = serde_json::to_value(&video)?;
+        assert_eq!(json["file_url"], "file:///videos/a%20%231.mp4");
+        assert_eq!(json["path"], "/videos/a #1.mp4");
+        assert!(json.get("digest").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_nearest_neighbors() {
+        let hash = |id: i64, histogram: Vec<u8>, frame_size: u32| VideoHash {
+            id,
+            path: format!("/tmp/{}.mp4", id),
+            size: 1,
+            histogram,
+            digest: Vec::new(),
+            frame_size,
+            duration: None,
+        };
+        let files = vec![
+            hash(1, vec![255, 0, 0, 0], 128),
+            hash(2, vec![200, 55, 0, 0], 128),
+            hash(3, vec![250, 5, 0, 0], 128),
+            hash(4, vec![0, 0, 255, 0], 128),
+            hash(5, vec![255, 0, 0, 0], 64),
+        ];
+        let neighbors = |threshold| -> Vec<(i64, u16)> {
+            nearest_neighbors(&files[0], &files, threshold)
+                .iter()
+                .map(|n| (n.file.id, n.distance))
+                .collect()
+        };
+        assert_eq!(neighbors(128), [(3, 10), (2, 110)]);
+        // the threshold is exclusive, like for clustering
+        assert_eq!(neighbors(110), [(3, 10)]);
+        assert_eq!(neighbors(511), [(3, 10), (2, 110), (4, 510)]);
+        assert!(neighbors(0).is_empty());
+    }
+
+    #[test]
+    fn test_short_videos_are_skipped() -> Result<()> {
+        let mut db = Database::new("test_short_videos_are_skipped.sqlite", true)?;
+        db.db.execute(
+            "INSERT INTO file_digests (id, path, size) VALUES \
+                (1, '/tmp/intro.mp4', 10), (2, '/tmp/movie.mp4', 11), \
+                (3, '/tmp/copy.mp4', 12), (4, '/tmp/legacy.mp4', 13)",
+            params![],
+        )?;
+        let video = |id: i64, histogram: Vec<u8>, duration: f64| VideoHash {
+            id,
+            path: String::new(),
+            histogram,
+            size: 1,
+            digest: Vec::new(),
+            frame_size: DEFAULT_FRAME_SIZE,
+            duration: Some(duration),
+        };
+        db.insert_many_videohashes(
+            &vec![
+                video(1, Vec::new(), 1.0),
+                video(2, vec![255, 0, 0, 0], 30.0),
+                video(3, vec![255, 0, 0, 0], 1.5),
+            ],
+            &mut ScanStats::default(),
+        )?;
+        db.db.execute(
+            "INSERT INTO video_hash (id, histogram) VALUES (4, x'ff000000')",
+            params![],
+        )?;
+
+        // the skipped 1s clip is neither hashed again nor clustered
+        let ids: Vec<i64> = db
+            .get_files_without_videohash(5.0, None)?
+            .iter()
+            .map(|x| x.0)
+            .collect();
+        assert!(ids.is_empty());
+        let files = db.get_all_files_with_videohash()?;
+        assert!(files.iter().all(|f| f.id != 1));
+
+        // a clip hashed before the limit existed is left out of the clusters,
+        // while legacy rows of unknown length are kept
+        let dist = calculate_distances(&files);
+        let ids: Vec<Vec<i64>> = find_similar_files(&files, &dist, 1, 5.0)
+            .iter()
+            .map(|b| b.iter().map(|x| x.id).collect())
+            .collect();
+        assert_eq!(ids, vec![vec![2, 4]]);
+
+        // lowering the limit makes the skipped clip eligible again
+        let ids: Vec<i64> = db
+            .get_files_without_videohash(0.5, None)?
+            .iter()
+            .map(|x| x.0)
+            .collect();
+        assert_eq!(ids, [1]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_probe_media_leaves_histograms_untouched() -> Result<()> {
+        let db = Database::new("test_probe_media.sqlite", true)?;
+        db.db.execute(
+            "INSERT INTO file_digests (id, path, size) VALUES \
+                (1, '/tmp/a.mp4', 1), (2, '/tmp/b.jpg', 1), \
+                (3, '/tmp/c.flac', 1), (4, '/tmp/broken.mkv', 1)",
+            params![],
+        )?;
+        let db_mutex = Mutex::new(db);
+        let fake_probe = |path: &Path| match path.to_str() {
+            Some("/tmp/a.mp4") => Ok(MediaInfo {
+                duration: Some(2.0),
+                width: Some(640),
+                height: Some(480),
+            }),
+            Some("/tmp/c.flac") => Ok(MediaInfo {
+                duration: Some(180.0),
+                ..Default::default()
+            }),
+            _ => Err(anyhow!("unreadable")),
+        };
+        assert_eq!(probe_media_with(&db_mutex, 2, fake_probe)?, 3);
+        // everything was probed, including the file that failed
+        assert_eq!(probe_media_with(&db_mutex, 2, fake_probe)?, 0);
+
+        let db = db_mutex.lock().unwrap();
+        assert_eq!(db.get_media_metadata(1)?.unwrap().width, Some(640));
+        assert_eq!(db.get_media_metadata(2)?, None);
+        assert_eq!(db.get_media_metadata(3)?.unwrap().duration, Some(180.0));
+        assert_eq!(db.get_media_metadata(4)?, Some(MediaInfo::default()));
+        let num_hashes: i64 =
+            db.db
+                .query_row("SELECT COUNT(*) FROM video_hash", params![], |r| r.get(0))?;
+        assert_eq!(num_hashes, 0);
+
+        // hashing reuses the probed duration and skips the short video without opening it
+        let files = db.get_files_without_videohash(0.0, None)?;
+        assert_eq!(files[0], (1, "/tmp/a.mp4".to_string(), 1, Some(2.0)));
+        let config = VideoHashConfig {
+            frame_size: DEFAULT_FRAME_SIZE,
+            min_duration: 5.0,
+            order: ScanOrder::Size,
+            threads: 1,
+        };
+        let hash = _create_hash(1, "/tmp/a.mp4", 1, Some(2.0), &config)?;
+        assert!(hash.histogram.is_empty());
+        assert_eq!(hash.duration, Some(2.0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_insert_many_videohashes_conflicts() -> Result<()> {
+        let mut db = Database::new("test_insert_many_videohashes_conflicts.sqlite", true)?;
+        db.db.execute(
+            "INSERT INTO video_hash (id, histogram) VALUES (2, x'01020304'), (3, NULL)",
+            params![],
+        )?;
+        let video = |id: i64| VideoHash {
+            id,
+            path: String::new(),
+            histogram: vec![id as u8; 4],
+            size: 1,
+            digest: Vec::new(),
+            frame_size: DEFAULT_FRAME_SIZE,
+            duration: Some(10.0),
+        };
+        let mut stats = ScanStats::default();
+        db.insert_many_videohashes(&vec![video(1), video(2), video(3), video(4)], &mut stats)?;
+        assert_eq!(stats.videohash_conflicts, 1);
+
+        let mut stmt = db
+            .db
+            .prepare("SELECT id, histogram FROM video_hash ORDER BY id")?;
+        let rows: Result<Vec<(i64, Vec<u8>)>, _> = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect();
+        // the conflicting row keeps its histogram, the skipped one gets replaced
+        assert_eq!(
+            rows?,
+            vec![
+                (1, vec![1; 4]),
+                (2, vec![1, 2, 3, 4]),
+                (3, vec![3; 4]),
+                (4, vec![4; 4])
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_too_short() {
+        assert!(is_too_short(Some(1.0), 5.0));
+        assert!(!is_too_short(Some(30.0), 5.0));
+        assert!(!is_too_short(Some(5.0), 5.0));
+        assert!(!is_too_short(None, 5.0));
+    }
+
+    #[test]
+    fn test_histogram_from_frames_with_different_sizes() -> Result<()> {
+        for &size in [16, 37, 128].iter() {
+            let pixels = (size * size) as usize;
+            let frames = (0..3u8).map(|k| {
+                (0..pixels * 3)
+                    .map(|i| (i as u8).wrapping_mul(k + 1))
+                    .collect::<Vec<u8>>()
+            });
+            let h = histogram_from_frames(frames, size)?;
+            assert_eq!(h.len(), HISTOGRAM_LEN);
+            // normalized to u8::MAX, up to rounding down in every bucket
+            let total: usize = h.iter().map(|&x| x as usize).sum();
+            assert!(total <= u8::MAX as usize && total > u8::MAX as usize - HISTOGRAM_LEN);
+        }
         Ok(())
     }
+
+    #[test]
+    fn test_histogram_without_frames() {
+        let err = histogram_from_frames(std::iter::empty(), 16).unwrap_err();
+        assert_eq!(err.to_string(), "No frames could be decoded");
+    }
+
+    #[test]
+    fn test_parse_frame_size() {
+        assert_eq!(parse_frame_size("128").unwrap(), 128);
+        assert_eq!(parse_frame_size("16").unwrap(), 16);
+        assert_eq!(parse_frame_size("512").unwrap(), 512);
+        assert!(parse_frame_size("15").is_err());
+        assert!(parse_frame_size("513").is_err());
+        assert!(parse_frame_size("abc").is_err());
+    }
+
+    #[test]
+    fn test_fit_size() {
+        assert_eq!(fit_size(4032, 3024, 512), (512, 384));
+        assert_eq!(fit_size(3024, 4032, 512), (384, 512));
+        assert_eq!(fit_size(300, 200, 512), (300, 200));
+        assert_eq!(fit_size(5000, 2, 512), (512, 1));
+    }
+
+    #[test]
+    fn test_cluster_id() {
+        let video = |id: i64, digest: u8| VideoHash {
+            id,
+            path: format!("/tmp/{}.mp4", id),
+            histogram: vec![0; 4],
+            size: 1,
+            digest: vec![digest; 64],
+            frame_size: DEFAULT_FRAME_SIZE,
+            duration: None,
+        };
+        let (a, b, c) = (video(1, 1), video(2, 2), video(3, 3));
+
+        // independent of member order and of the row ids
+        let id = cluster_id(&[&a, &b]);
+        assert!(id.starts_with('v'));
+        assert_eq!(id, cluster_id(&[&b, &a]));
+        assert_eq!(id, cluster_id(&[&video(7, 2), &video(8, 1)]));
+
+        // but changes with the membership
+        assert_ne!(id, cluster_id(&[&a, &b, &c]));
+        assert_ne!(id, cluster_id(&[&a, &c]));
+    }
 }